@@ -0,0 +1,74 @@
+use std::fmt;
+use std::fs;
+use std::io::Error;
+
+/// Set of CPU cores that a thread may run on
+#[derive(Clone, Debug, PartialEq)]
+pub struct CpuSet {
+    cpus: Vec<usize>,
+}
+
+impl fmt::Display for CpuSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let cpus: Vec<String> = self.cpus.iter().map(|cpu| cpu.to_string()).collect();
+        write!(f, "{}", cpus.join(","))
+    }
+}
+
+impl CpuSet {
+    /// Parse a CPU set given as a CPU list such as "2", "0-3" or "0-3,8,10-11" (the Linux cpulist format),
+    /// or as "node:N" for all the CPUs of NUMA node N.
+    pub fn parse(spec: &str) -> Result<CpuSet, String> {
+        let list = match spec.trim().strip_prefix("node:") {
+            Some(node) => {
+                let node: usize = node.parse().map_err(|_| format!("invalid NUMA node '{}'", node))?;
+                let path = format!("/sys/devices/system/node/node{}/cpulist", node);
+                fs::read_to_string(&path).map_err(|error| format!("cannot read {}: {}", path, error))?
+            }
+            None => { spec.to_string() }
+        };
+
+        let mut cpus = Vec::new();
+        for part in list.trim().split(',').filter(|p| !p.is_empty()) {
+            let (first, last) = match part.split_once('-') {
+                Some((first, last)) => { (first, last) }
+                None => { (part, part) }
+            };
+            let first: usize = first.trim().parse().map_err(|_| format!("invalid CPU '{}'", first))?;
+            let last: usize = last.trim().parse().map_err(|_| format!("invalid CPU '{}'", last))?;
+            if first > last {
+                return Err(format!("invalid CPU range '{}'", part));
+            }
+            cpus.extend(first..=last);
+        }
+        if cpus.is_empty() {
+            return Err(String::from("empty CPU set"));
+        }
+        cpus.sort_unstable();
+        cpus.dedup();
+        Ok(CpuSet { cpus })
+    }
+
+    /// Pin the calling thread to this set of CPUs
+    #[cfg(target_os = "linux")]
+    pub fn pin_current_thread(&self) -> Result<(), Error> {
+        // The CPU_* helpers and sched_setaffinity are plain libc calls on a stack-allocated set
+        unsafe {
+            let mut set: libc::cpu_set_t = std::mem::zeroed();
+            libc::CPU_ZERO(&mut set);
+            for cpu in &self.cpus {
+                libc::CPU_SET(*cpu, &mut set);
+            }
+            if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+                return Err(Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    /// Pin the calling thread to this set of CPUs
+    #[cfg(not(target_os = "linux"))]
+    pub fn pin_current_thread(&self) -> Result<(), Error> {
+        Err(Error::new(std::io::ErrorKind::Unsupported, "CPU affinity is only supported on Linux"))
+    }
+}
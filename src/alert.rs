@@ -0,0 +1,71 @@
+use std::fmt;
+
+/// Max number of alert rules, since every connection remembers which of them fired in a bit mask
+pub const MAX_ALERT_RULES: usize = 64;
+/// Minimum number of data packets before the retransmission rate of a connection is checked,
+/// since a single retransmission on a short connection is not a problem
+pub const RETRANSMIT_RATE_MIN_PACKETS: u32 = 20;
+
+/// Metric of a connection that an alert rule checks
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AlertMetric {
+    /// Percent of the data packets (of both flows) that carried no new bytes
+    RetransmitPercent,
+    /// Time between the SYN and the SYN/ACK, in milliseconds
+    HandshakeRttMs,
+    /// Packets (of both flows) that advertised a zero receive window
+    ZeroWindows,
+    /// Time lost (of both flows) to small writes that waited for a delayed ACK, in milliseconds
+    NagleStallMs,
+}
+
+impl AlertMetric {
+    fn name(&self) -> &'static str {
+        match self {
+            AlertMetric::RetransmitPercent => { "retransmit-pct" }
+            AlertMetric::HandshakeRttMs => { "handshake-rtt-ms" }
+            AlertMetric::ZeroWindows => { "zero-windows" }
+            AlertMetric::NagleStallMs => { "nagle-stall-ms" }
+        }
+    }
+}
+
+/// Fires once per connection, when its metric exceeds the threshold
+#[derive(Clone, Debug, PartialEq)]
+pub struct AlertRule {
+    pub metric: AlertMetric,
+    pub threshold: f64,
+}
+
+impl AlertRule {
+    /// Parse a rule given as "METRIC>THRESHOLD", such as "retransmit-pct>5", "handshake-rtt-ms>200" or "zero-windows>10"
+    pub fn parse(arg: &str) -> Result<Self, String> {
+        let (metric, threshold) = arg.split_once('>').ok_or("expected METRIC>THRESHOLD")?;
+        let metric = [AlertMetric::RetransmitPercent, AlertMetric::HandshakeRttMs, AlertMetric::ZeroWindows, AlertMetric::NagleStallMs]
+            .into_iter()
+            .find(|known| known.name() == metric.trim())
+            .ok_or_else(|| format!("unknown metric \"{}\", expected retransmit-pct, handshake-rtt-ms, zero-windows or nagle-stall-ms", metric.trim()))?;
+        let threshold = threshold.trim().parse::<f64>().ok().filter(|threshold| threshold.is_finite())
+            .ok_or("invalid threshold")?;
+        Ok(AlertRule { metric, threshold })
+    }
+}
+
+impl fmt::Display for AlertRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}>{}", self.metric.name(), self.threshold)
+    }
+}
+
+/// A rule that fired on a connection, with the value of its metric at that time
+#[derive(Clone, Debug, PartialEq)]
+pub struct Alert {
+    pub rule: AlertRule,
+    pub value: f64,
+}
+
+impl fmt::Display for Alert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (value {:.1})", self.rule, self.value)
+    }
+}
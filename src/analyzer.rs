@@ -0,0 +1,2514 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+use crate::conn::PacketDir;
+use crate::json::JsonValue;
+use crate::keylog::{CLIENT_RANDOM_LEN, KeyLog};
+use crate::tags::ConnTags;
+use crate::tls_decrypt::{cipher_suite_name, RecordCipher, MASTER_SECRET_LEN};
+
+/// Max bytes of each flow that are fed to analyzers by default, since protocols are identified by their first messages
+pub const ANALYZE_MAX_BYTES: usize = 65536;
+/// Max length of a TLS server name, as of a DNS name. Longer ones are ignored.
+pub(crate) const MAX_SERVER_NAME_LEN: usize = 255;
+/// Max distinct command verbs to count per connection, beyond which they are counted as "OTHER"
+const MAX_COMMAND_VERBS: usize = 32;
+/// Max protocol details to keep per connection, since some (like file names) may repeat many times
+const MAX_METADATA_ENTRIES: usize = 64;
+/// Max HTTP transactions to keep per connection, after which the HTTP analyzer stops
+const MAX_HTTP_TRANSACTIONS: usize = 100;
+/// Max DNS queries to keep per connection, after which the DNS analyzer stops
+const MAX_DNS_QUERIES: usize = 100;
+
+/// What is known about the application protocol of a connection
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AppInfo {
+    /// Label by the well-known server port, such as "http" or "tls"
+    pub port_label: Option<&'static str>,
+    /// Label by the content, set by analyzers that recognize a protocol
+    pub content_label: Option<&'static str>,
+    /// TLS server name (SNI) requested by the client
+    pub server_name: Option<String>,
+    /// Name that the client resolved to the server address, from a DNS answer over UDP seen before the connection
+    pub resolved_name: Option<String>,
+    /// ALPN protocols offered by the client, in order
+    pub alpn_offered: Vec<String>,
+    /// ALPN protocol selected by the server, if seen (TLS 1.3 sends it encrypted)
+    pub alpn_selected: Option<String>,
+    /// Random of the TLS ClientHello, that identifies the session in a key log
+    pub client_random: Option<[u8; CLIENT_RANDOM_LEN]>,
+    /// TLS version of the session secrets found in the key log, if a key log was given and has them
+    pub tls_keys: Option<&'static str>,
+    /// Kind of proxy tunnel that carries the connection, such as "http-connect" or "socks5"
+    pub tunnel: Option<&'static str>,
+    /// Host and port requested from the proxy, as given by the client
+    pub tunnel_target: Option<String>,
+    /// Protocol details found by analyzers, such as ("smb_dialect", "3.1.1"), in the order found.
+    /// A key may appear more than once with different values.
+    pub metadata: Vec<(&'static str, String)>,
+    /// Number of client commands by verb, for request/response protocols such as Redis
+    pub commands: BTreeMap<String, u64>,
+    /// Requests and responses of HTTP/1.x, in the order of the requests
+    pub http_transactions: Vec<HttpTransaction>,
+    /// Questions of DNS over TCP, with their response codes, in the order of the queries
+    pub dns_queries: Vec<DnsQuery>,
+    /// Labels attached by analyzers, rules and consumers, carried to all the flow exports
+    pub tags: ConnTags,
+}
+
+impl AppInfo {
+    /// The negotiated ALPN protocol: the one selected by the server, or else the only one offered by the client
+    pub fn alpn(&self) -> Option<&str> {
+        match (&self.alpn_selected, self.alpn_offered.as_slice()) {
+            (Some(selected), _) => { Some(selected) }
+            (None, [offered]) => { Some(offered) }
+            _ => { None }
+        }
+    }
+
+    /// Best known application label. ALPN is the most specific, since a port or a TLS handshake alone
+    /// does not tell which protocol runs inside.
+    pub fn label(&self) -> Option<String> {
+        if let Some(alpn) = self.alpn() {
+            let label = match alpn {
+                "h2" => { "http2" }
+                "h3" => { "http3" }
+                other => { other }
+            };
+            return Some(label.to_string());
+        }
+        self.content_label.or(self.port_label).map(String::from)
+    }
+
+    /// Add a protocol detail, unless it is already known or there are too many
+    pub fn add_metadata(&mut self, key: &'static str, value: String) {
+        if self.metadata.len() < MAX_METADATA_ENTRIES && !self.metadata.iter().any(|(k, v)| *k == key && *v == value) {
+            self.metadata.push((key, value));
+        }
+    }
+
+    /// Protocol details as a JSON object, with the values of every key in an array
+    pub fn metadata_json(&self) -> JsonValue {
+        metadata_to_json(&self.metadata)
+    }
+
+    /// Count a client command
+    pub fn count_command(&mut self, verb: &str) {
+        let verb = if self.commands.len() < MAX_COMMAND_VERBS || self.commands.contains_key(verb) { verb } else { "OTHER" };
+        *self.commands.entry(verb.to_string()).or_default() += 1;
+    }
+
+    /// Commands by verb, from the most common
+    pub fn top_commands(&self) -> Vec<(String, u64)> {
+        let mut commands: Vec<(String, u64)> = self.commands.iter().map(|(verb, count)| (verb.clone(), *count)).collect();
+        commands.sort_by(|(verb_a, count_a), (verb_b, count_b)| count_b.cmp(count_a).then(verb_a.cmp(verb_b)));
+        commands
+    }
+
+    /// Kind and target of the proxy tunnel, such as "socks5 example.com:443", if any
+    pub fn tunnel_description(&self) -> Option<String> {
+        let tunnel = self.tunnel?;
+        Some(format!("{} {}", tunnel, self.tunnel_target.as_deref().unwrap_or("unknown")))
+    }
+}
+
+/// A request of an HTTP/1.x connection, and its response if seen
+#[derive(Clone, Debug, PartialEq)]
+pub struct HttpTransaction {
+    pub method: String,
+    /// Request target as sent, usually a path and a query, or an absolute URL through a proxy
+    pub path: String,
+    /// Host header of the request, if any
+    pub host: Option<String>,
+    /// Status of the final response, if seen, since interim (1xx) responses are skipped except for 101
+    pub status: Option<u16>,
+    /// Content-Length of the request and of the response, if given
+    pub request_length: Option<u64>,
+    pub response_length: Option<u64>,
+}
+
+impl HttpTransaction {
+    pub fn to_json(&self) -> JsonValue {
+        JsonValue::object(vec![
+            ("method", self.method.as_str().into()),
+            ("path", self.path.as_str().into()),
+            ("host", self.host.as_deref().into()),
+            ("status", self.status.into()),
+            ("request_length", self.request_length.into()),
+            ("response_length", self.response_length.into()),
+        ])
+    }
+}
+
+/// Method, host and path, and the status if answered, as in "GET example.com/index.html 200"
+impl fmt::Display for HttpTransaction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}{}", self.method, self.host.as_deref().unwrap_or_default(), self.path)?;
+        match self.status {
+            None => { write!(f, " (no response)") }
+            Some(status) => { write!(f, " {}", status) }
+        }
+    }
+}
+
+/// A question of a DNS connection, and the response code of its answer if seen
+#[derive(Clone, Debug, PartialEq)]
+pub struct DnsQuery {
+    /// Transaction ID, that matches the response with the query
+    pub id: u16,
+    pub name: String,
+    /// Type of the records asked for, such as 1 for A
+    pub qtype: u16,
+    /// Response code, such as 3 for NXDOMAIN, and the number of answer records, if the response was seen
+    pub rcode: Option<u8>,
+    pub answers: Option<u16>,
+}
+
+impl DnsQuery {
+    /// Mnemonic of the record type, such as "AAAA", or "TYPE99" for types without one (RFC 3597)
+    pub fn type_name(&self) -> String {
+        dns_type_name(self.qtype).map(String::from).unwrap_or_else(|| format!("TYPE{}", self.qtype))
+    }
+
+    /// Mnemonic of the response code, such as "NXDOMAIN", if the response was seen
+    pub fn rcode_name(&self) -> Option<String> {
+        self.rcode.map(|rcode| dns_rcode_name(rcode).map(String::from).unwrap_or_else(|| format!("RCODE{}", rcode)))
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        JsonValue::object(vec![
+            ("id", self.id.into()),
+            ("name", self.name.as_str().into()),
+            ("type", self.type_name().into()),
+            ("rcode", self.rcode_name().into()),
+            ("answers", self.answers.into()),
+        ])
+    }
+}
+
+/// Type, name and response code, as in "AAAA example.com NXDOMAIN"
+impl fmt::Display for DnsQuery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {}", self.type_name(), self.name, self.rcode_name().as_deref().unwrap_or("(no response)"))
+    }
+}
+
+/// Identifies an application protocol from the first bytes of both flows of a connection
+pub trait Analyzer: Send {
+    /// Feed the next contiguous bytes of the flow sent by the given direction, in stream order.
+    /// Return false when the analyzer does not need more bytes of any flow.
+    fn feed(&mut self, dir: &PacketDir, data: &[u8], app: &mut AppInfo) -> bool;
+
+    /// Stream offset up to which the analyzer wants the bytes of each flow
+    fn max_bytes(&self) -> usize {
+        ANALYZE_MAX_BYTES
+    }
+
+    /// Copy of the analyzer with its current state, since connections are cloned
+    fn clone_box(&self) -> Box<dyn Analyzer>;
+}
+
+impl Clone for Box<dyn Analyzer> {
+    fn clone(&self) -> Self {
+        self.clone_box()
+    }
+}
+
+/// Analyzers by name, in the order they are fed, with a function that creates one for a new connection
+const ANALYZERS: &[(&str, AnalyzerFactory)] = &[
+    ("tls", |config| Box::new(TlsAnalyzer::new(config.clone()))),
+    ("proxy", |config| Box::new(ProxyAnalyzer::new(config.clone()))),
+    ("smb", |_| Box::new(SmbAnalyzer::new())),
+    ("postgresql", |_| Box::new(HandshakeAnalyzer::new(parse_postgresql_startup))),
+    ("mysql", |_| Box::new(HandshakeAnalyzer::new(parse_mysql_handshake))),
+    ("rdp", |_| Box::new(HandshakeAnalyzer::new(parse_rdp_negotiation))),
+    ("vnc", |_| Box::new(HandshakeAnalyzer::new(parse_vnc_handshake))),
+    ("cache", |_| Box::new(CacheAnalyzer::new())),
+    ("http", |_| Box::new(HttpAnalyzer::new())),
+    ("dns", |_| Box::new(DnsAnalyzer::new())),
+];
+
+type AnalyzerFactory = fn(&Arc<AnalyzerConfig>) -> Box<dyn Analyzer>;
+
+/// Settings of one analyzer
+#[derive(Clone, Debug, PartialEq)]
+pub struct AnalyzerSettings {
+    pub enabled: bool,
+    /// Stream offset up to which the analyzer is fed, instead of its own default
+    pub max_bytes: Option<usize>,
+    /// Max metadata entries and commands the analyzer may record per connection, after which it stops
+    pub max_records: Option<usize>,
+}
+
+/// Which analyzers run on new connections, and their limits
+#[derive(Clone, Debug, PartialEq)]
+pub struct AnalyzerConfig {
+    /// Settings by the index in [ANALYZERS]
+    settings: Vec<AnalyzerSettings>,
+    /// Secrets of TLS sessions, whose decrypted application data is fed to a fresh set of analyzers
+    pub(crate) key_log: Option<Arc<KeyLog>>,
+}
+
+impl Default for AnalyzerConfig {
+    fn default() -> Self {
+        let settings = AnalyzerSettings { enabled: true, max_bytes: None, max_records: None };
+        Self { settings: vec![settings; ANALYZERS.len()], key_log: None }
+    }
+}
+
+impl AnalyzerConfig {
+    /// All analyzers enabled without limits, and then the rules applied in order
+    pub fn new(rules: &[AnalyzerRule]) -> Self {
+        let mut config = AnalyzerConfig::default();
+        for rule in rules {
+            for (index, settings) in config.settings.iter_mut().enumerate() {
+                if rule.name != "all" && rule.name != ANALYZERS[index].0 {
+                    continue;
+                }
+                if let Some(enabled) = rule.enabled {
+                    settings.enabled = enabled;
+                }
+                if rule.max_bytes.is_some() {
+                    settings.max_bytes = rule.max_bytes;
+                }
+                if rule.max_records.is_some() {
+                    settings.max_records = rule.max_records;
+                }
+            }
+        }
+        config
+    }
+
+    /// Analyzers for a new connection, or for the stream tunneled through a proxy
+    pub fn create_analyzers(config: &Arc<AnalyzerConfig>) -> Vec<Box<dyn Analyzer>> {
+        config.settings.iter().zip(ANALYZERS).filter(|(settings, _)| settings.enabled).map(|(settings, (_, create))| {
+            let analyzer = create(config);
+            if settings.max_bytes.is_none() && settings.max_records.is_none() {
+                return analyzer;
+            }
+            Box::new(LimitedAnalyzer::new(analyzer, settings)) as Box<dyn Analyzer>
+        }).collect()
+    }
+}
+
+impl fmt::Display for AnalyzerConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut first = true;
+        for (settings, (name, _)) in self.settings.iter().zip(ANALYZERS).filter(|(settings, _)| settings.enabled) {
+            write!(f, "{}{}", if first { "" } else { ", " }, name)?;
+            if let Some(max_bytes) = settings.max_bytes {
+                write!(f, " max-bytes={}", max_bytes)?;
+            }
+            if let Some(max_records) = settings.max_records {
+                write!(f, " max-records={}", max_records)?;
+            }
+            first = false;
+        }
+        if first {
+            write!(f, "none")?;
+        }
+        Ok(())
+    }
+}
+
+/// One --analyzer option, given as "NAME:SETTING[,SETTING...]" where NAME is an analyzer or "all",
+/// and a SETTING is "on", "off", "max-bytes=N" or "max-records=N"
+#[derive(Clone, Debug)]
+pub struct AnalyzerRule {
+    name: &'static str,
+    enabled: Option<bool>,
+    max_bytes: Option<usize>,
+    max_records: Option<usize>,
+}
+
+impl AnalyzerRule {
+    pub fn parse(arg: &str) -> Result<Self, String> {
+        let (name, settings) = arg.split_once(':').ok_or("expected NAME:SETTING")?;
+        let name = ANALYZERS.iter().map(|(name, _)| *name).chain(["all"]).find(|known| *known == name.trim())
+            .ok_or_else(|| format!("unknown analyzer \"{}\", expected one of {} or all", name,
+                                   ANALYZERS.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(", ")))?;
+        let mut rule = AnalyzerRule { name, enabled: None, max_bytes: None, max_records: None };
+        for setting in settings.split(',').map(str::trim) {
+            let number = |value: &str| value.parse::<usize>().map_err(|_| format!("invalid number in \"{}\"", setting));
+            match setting.split_once('=') {
+                None if setting == "on" => { rule.enabled = Some(true) }
+                None if setting == "off" => { rule.enabled = Some(false) }
+                Some(("max-bytes", value)) => { rule.max_bytes = Some(number(value)?) }
+                Some(("max-records", value)) => { rule.max_records = Some(number(value)?) }
+                _ => { return Err(format!("unknown setting \"{}\", expected on, off, max-bytes=N or max-records=N", setting)); }
+            }
+        }
+        Ok(rule)
+    }
+}
+
+/// Applies the configured limits to an analyzer: it is fed only up to the max bytes of each flow,
+/// and it is stopped once it recorded the max number of metadata entries, commands, HTTP transactions and DNS queries
+#[derive(Clone)]
+struct LimitedAnalyzer {
+    inner: Box<dyn Analyzer>,
+    max_bytes: usize,
+    max_records: usize,
+    /// Bytes fed so far per flow, indexed by [flow_index]
+    fed: [usize; 2],
+    /// Metadata entries, commands, HTTP transactions and DNS queries recorded so far
+    records: usize,
+}
+
+impl LimitedAnalyzer {
+    fn new(inner: Box<dyn Analyzer>, settings: &AnalyzerSettings) -> Self {
+        let max_bytes = settings.max_bytes.unwrap_or_else(|| inner.max_bytes());
+        Self { inner, max_bytes, max_records: settings.max_records.unwrap_or(usize::MAX), fed: [0, 0], records: 0 }
+    }
+
+    fn record_count(app: &AppInfo) -> usize {
+        app.metadata.len() + app.commands.values().sum::<u64>() as usize + app.http_transactions.len() + app.dns_queries.len()
+    }
+}
+
+impl Analyzer for LimitedAnalyzer {
+    fn feed(&mut self, dir: &PacketDir, data: &[u8], app: &mut AppInfo) -> bool {
+        let index = flow_index(dir);
+        let data = &data[..data.len().min(self.max_bytes.saturating_sub(self.fed[index]))];
+        if !data.is_empty() {
+            self.fed[index] += data.len();
+            let records_before = LimitedAnalyzer::record_count(app);
+            if !self.inner.feed(dir, data, app) {
+                return false;
+            }
+            self.records += LimitedAnalyzer::record_count(app).saturating_sub(records_before);
+        }
+        self.records < self.max_records && self.fed.iter().any(|fed| *fed < self.max_bytes)
+    }
+
+    fn max_bytes(&self) -> usize {
+        self.max_bytes
+    }
+
+    fn clone_box(&self) -> Box<dyn Analyzer> {
+        Box::new(self.clone())
+    }
+}
+
+/// Protocol details as a JSON object, with the values of every key in an array
+pub fn metadata_to_json(metadata: &[(&'static str, String)]) -> JsonValue {
+    let mut keys: Vec<&'static str> = Vec::new();
+    for (key, _) in metadata {
+        if !keys.contains(key) {
+            keys.push(key);
+        }
+    }
+    JsonValue::object(keys.into_iter().map(|key| {
+        let values = metadata.iter().filter(|(k, _)| *k == key).map(|(_, v)| v.as_str().into()).collect::<Vec<JsonValue>>();
+        (key, values.into())
+    }).collect())
+}
+
+/// Label of a well-known server port
+pub fn port_label(port: u16) -> Option<&'static str> {
+    match port {
+        22 => { Some("ssh") }
+        25 | 587 => { Some("smtp") }
+        53 => { Some("dns") }
+        80 => { Some("http") }
+        443 | 8443 => { Some("tls") }
+        445 => { Some("smb") }
+        1080 => { Some("socks") }
+        3128 | 8080 => { Some("http-proxy") }
+        3306 => { Some("mysql") }
+        3389 => { Some("rdp") }
+        5432 => { Some("postgresql") }
+        5900 => { Some("vnc") }
+        6379 => { Some("redis") }
+        11211 => { Some("memcached") }
+        _ => { None }
+    }
+}
+
+/// Max bytes of a flow to collect while looking for a complete TLS hello message
+const TLS_HELLO_MAX_BYTES: usize = 16384;
+/// Max length of a TLS record with its header: the max ciphertext of TLS 1.2 is 2^14 + 2048 bytes
+const TLS_RECORD_MAX_LEN: usize = 5 + 16384 + 2048;
+
+/// Extracts the server name and ALPN from the TLS ClientHello and ServerHello. With the session's TLS 1.2
+/// master secret in the key log, it also decrypts the records and analyzes the application data.
+#[derive(Clone)]
+struct TlsAnalyzer {
+    /// Bytes collected so far per flow, indexed by [flow_index]
+    buffers: [Vec<u8>; 2],
+    /// Whether the flow's hello was found, or the flow is not TLS
+    done: [bool; 2],
+    /// Key log with the session secrets, and which analyzers to create for the decrypted stream
+    config: Arc<AnalyzerConfig>,
+    /// Decryption of the session, once its ClientHello was found in the key log
+    decryption: Option<Box<TlsDecryption>>,
+}
+
+/// Result of looking for a TLS hello message at the start of a flow
+enum TlsHello {
+    NeedMore,
+    NotTls,
+    Client { server_name: Option<String>, alpn: Vec<String>, client_random: [u8; CLIENT_RANDOM_LEN] },
+    Server { alpn: Option<String>, server_random: [u8; CLIENT_RANDOM_LEN], cipher_suite: u16 },
+}
+
+impl TlsAnalyzer {
+    fn new(config: Arc<AnalyzerConfig>) -> Self {
+        Self { buffers: [Vec::new(), Vec::new()], done: [false, false], config, decryption: None }
+    }
+}
+
+impl Analyzer for TlsAnalyzer {
+    fn feed(&mut self, dir: &PacketDir, data: &[u8], app: &mut AppInfo) -> bool {
+        let index = flow_index(dir);
+        if self.done[index] {
+            if let Some(decryption) = &mut self.decryption {
+                decryption.records[index].extend_from_slice(data);
+                if !decryption.decrypt(index, app) {
+                    self.decryption = None;
+                }
+            }
+        } else {
+            let buffer = &mut self.buffers[index];
+            let kept = data.len().min(TLS_HELLO_MAX_BYTES.saturating_sub(buffer.len()));
+            buffer.extend_from_slice(&data[..kept]);
+            let mut hello_found = true;
+            match parse_tls_hello(buffer) {
+                TlsHello::NeedMore => {
+                    self.done[index] = buffer.len() >= TLS_HELLO_MAX_BYTES;
+                    hello_found = false;
+                }
+                TlsHello::NotTls => {
+                    self.done[index] = true;
+                    hello_found = false;
+                }
+                TlsHello::Client { server_name, alpn, client_random } => {
+                    app.server_name = server_name;
+                    app.client_random = Some(client_random);
+                    app.alpn_offered = alpn;
+                    app.content_label = Some("tls");
+                    self.done[index] = true;
+                    let master_secret = self.config.key_log.as_ref().and_then(|key_log| key_log.master_secret(&client_random));
+                    // The ServerHello is needed to expand the keys, so it must come after
+                    if let Some(master_secret) = master_secret.filter(|secret| secret.len() == MASTER_SECRET_LEN && !self.done[1 - index]) {
+                        self.decryption = Some(Box::new(TlsDecryption::new(master_secret, client_random, index, &self.config)));
+                    }
+                }
+                TlsHello::Server { alpn, server_random, cipher_suite } => {
+                    app.alpn_selected = alpn;
+                    self.done[index] = true;
+                    if let Some(decryption) = &mut self.decryption {
+                        if !decryption.start(index, &server_random, cipher_suite, app) {
+                            self.decryption = None;
+                        }
+                    }
+                }
+            }
+            if self.done[index] {
+                let mut stream = std::mem::take(&mut self.buffers[index]);
+                match &mut self.decryption {
+                    // The records of the flow are decrypted from its start, where the hello is still plaintext
+                    Some(decryption) if hello_found => {
+                        stream.extend_from_slice(&data[kept..]);
+                        decryption.records[index] = stream;
+                        let client_index = decryption.client_index;
+                        if !decryption.decrypt(client_index, app) || !decryption.decrypt(1 - client_index, app) {
+                            self.decryption = None;
+                        }
+                    }
+                    _ => { self.decryption = None }
+                }
+            }
+        }
+        self.decryption.is_some() || !(self.done[0] && self.done[1])
+    }
+
+    fn max_bytes(&self) -> usize {
+        if self.decryption.is_some() { usize::MAX } else { ANALYZE_MAX_BYTES }
+    }
+
+    fn clone_box(&self) -> Box<dyn Analyzer> {
+        Box::new(self.clone())
+    }
+}
+
+/// Decrypts the records of both flows of a TLS 1.2 session with its master secret, and feeds the application data
+/// to a fresh set of analyzers
+#[derive(Clone)]
+struct TlsDecryption {
+    master_secret: Vec<u8>,
+    client_random: [u8; CLIENT_RANDOM_LEN],
+    /// Flow index of the client, that sent the ClientHello
+    client_index: usize,
+    /// Record ciphers per flow, once the ServerHello selected a supported cipher suite
+    ciphers: Option<[RecordCipher; 2]>,
+    /// Name of the selected cipher suite
+    cipher_suite: &'static str,
+    /// Whether the flow sent its ChangeCipherSpec, after which its records are encrypted
+    encrypted: [bool; 2],
+    /// Bytes of each flow from the first record not decrypted yet
+    records: [Vec<u8>; 2],
+    /// Whether a record was decrypted, which proves the secret
+    decrypted: bool,
+    /// Analyzers of the decrypted application data
+    inner: Vec<Box<dyn Analyzer>>,
+}
+
+impl TlsDecryption {
+    fn new(master_secret: &[u8], client_random: [u8; CLIENT_RANDOM_LEN], client_index: usize, config: &Arc<AnalyzerConfig>) -> Self {
+        Self {
+            master_secret: master_secret.to_vec(),
+            client_random,
+            client_index,
+            ciphers: None,
+            cipher_suite: "",
+            encrypted: [false, false],
+            records: [Vec::new(), Vec::new()],
+            decrypted: false,
+            inner: AnalyzerConfig::create_analyzers(config),
+        }
+    }
+
+    /// Expand the keys once the ServerHello was found. Return false if the cipher suite is not supported.
+    fn start(&mut self, server_index: usize, server_random: &[u8; CLIENT_RANDOM_LEN], cipher_suite: u16, app: &mut AppInfo) -> bool {
+        if server_index == self.client_index {
+            return false;
+        }
+        match cipher_suite_name(cipher_suite) {
+            None => {
+                app.add_metadata("tls_decryption", format!("unsupported cipher suite 0x{:04x}", cipher_suite));
+                false
+            }
+            Some(name) => {
+                let (client, server) = RecordCipher::from_master_secret(&self.master_secret, &self.client_random, server_random);
+                self.ciphers = Some(if self.client_index == 0 { [client, server] } else { [server, client] });
+                self.cipher_suite = name;
+                true
+            }
+        }
+    }
+
+    /// Decrypt the complete records of a flow, and feed the application data to the inner analyzers.
+    /// Return false when a record fails authentication, or the inner analyzers do not need more.
+    fn decrypt(&mut self, index: usize, app: &mut AppInfo) -> bool {
+        let ciphers = match &mut self.ciphers {
+            None => { return self.records[index].len() <= TLS_RECORD_MAX_LEN; }
+            Some(ciphers) => { ciphers }
+        };
+        let dir = index_dir(index);
+        let mut records = ByteReader::new(&self.records[index]);
+        let mut consumed = 0;
+        while let (Some(content_type), Some(version), Some(len)) = (records.u8(), records.u16(), records.u16()) {
+            let fragment = match records.take(len as usize) {
+                None => { break; }
+                Some(fragment) => { fragment }
+            };
+            consumed = records.pos;
+            // Only the records after the ChangeCipherSpec are encrypted, while the handshake before is plaintext
+            if content_type == 20 {
+                self.encrypted[index] = true;
+                continue;
+            }
+            if !self.encrypted[index] {
+                continue;
+            }
+            let plaintext = match ciphers[index].open(content_type, version, fragment) {
+                None => {
+                    app.add_metadata("tls_decryption", String::from("bad record MAC"));
+                    return false;
+                }
+                Some(plaintext) => { plaintext }
+            };
+            if !self.decrypted {
+                app.add_metadata("tls_decryption", self.cipher_suite.to_string());
+                self.decrypted = true;
+            }
+            if content_type == 23 && !ProxyAnalyzer::feed_inner(&mut self.inner, &dir, &plaintext, app) {
+                return false;
+            }
+        }
+        self.records[index].drain(..consumed);
+        self.records[index].len() <= TLS_RECORD_MAX_LEN
+    }
+}
+
+/// Max bytes of a flow to collect while looking for a complete proxy handshake
+const PROXY_HANDSHAKE_MAX_BYTES: usize = 4096;
+
+/// Detects HTTP CONNECT and SOCKS handshakes, records the requested target,
+/// and identifies the tunneled stream with a fresh set of analyzers
+#[derive(Clone)]
+struct ProxyAnalyzer {
+    /// Bytes collected so far per flow, indexed by [flow_index], until the handshake is complete
+    buffers: [Vec<u8>; 2],
+    /// Analyzers of the tunneled stream, once the tunnel is established
+    inner: Option<Vec<Box<dyn Analyzer>>>,
+    /// Which analyzers to create for the tunneled stream
+    config: Arc<AnalyzerConfig>,
+}
+
+/// Result of looking for a proxy handshake at the start of both flows
+enum ProxyHandshake {
+    NeedMore,
+    NotProxy,
+    /// The proxy refused the request, so nothing is tunneled
+    Refused { kind: &'static str, target: String },
+    /// The tunnel is established, and the tunneled stream starts after the handshake bytes of each flow
+    Tunnel { kind: &'static str, target: String, client_len: usize, server_len: usize },
+}
+
+impl ProxyAnalyzer {
+    fn new(config: Arc<AnalyzerConfig>) -> Self {
+        Self { buffers: [Vec::new(), Vec::new()], inner: None, config }
+    }
+
+    /// Feed the tunneled bytes to the inner analyzers, and return whether any of them needs more
+    fn feed_inner(inner: &mut Vec<Box<dyn Analyzer>>, dir: &PacketDir, data: &[u8], app: &mut AppInfo) -> bool {
+        if !data.is_empty() {
+            inner.retain_mut(|analyzer| analyzer.feed(dir, data, app));
+        }
+        !inner.is_empty()
+    }
+}
+
+impl Analyzer for ProxyAnalyzer {
+    fn feed(&mut self, dir: &PacketDir, data: &[u8], app: &mut AppInfo) -> bool {
+        if let Some(inner) = &mut self.inner {
+            return ProxyAnalyzer::feed_inner(inner, dir, data, app);
+        }
+        let index = flow_index(dir);
+        let room = PROXY_HANDSHAKE_MAX_BYTES.saturating_sub(self.buffers[index].len());
+        self.buffers[index].extend_from_slice(&data[..data.len().min(room)]);
+        // The client is whichever flow starts with a valid request
+        let (client, server) = (index_dir(index), index_dir(1 - index));
+        let mut handshake = parse_proxy_handshake(&self.buffers[index], &self.buffers[1 - index]);
+        let mut client_dir = client.clone();
+        let mut server_dir = server.clone();
+        if matches!(handshake, ProxyHandshake::NotProxy) {
+            handshake = parse_proxy_handshake(&self.buffers[1 - index], &self.buffers[index]);
+            client_dir = server;
+            server_dir = client;
+        }
+        match handshake {
+            ProxyHandshake::NeedMore => { self.buffers[index].len() < PROXY_HANDSHAKE_MAX_BYTES }
+            ProxyHandshake::NotProxy => { false }
+            ProxyHandshake::Refused { kind, target } => {
+                app.tunnel = Some(kind);
+                app.tunnel_target = Some(target);
+                false
+            }
+            ProxyHandshake::Tunnel { kind, target, client_len, server_len } => {
+                app.tunnel = Some(kind);
+                app.tunnel_target = Some(target);
+                app.content_label = Some(kind);
+                let mut inner = AnalyzerConfig::create_analyzers(&self.config);
+                let buffers = std::mem::take(&mut self.buffers);
+                let client_data = &buffers[flow_index(&client_dir)][client_len..];
+                let server_data = &buffers[flow_index(&server_dir)][server_len..];
+                ProxyAnalyzer::feed_inner(&mut inner, &client_dir, client_data, app);
+                let more = ProxyAnalyzer::feed_inner(&mut inner, &server_dir, server_data, app);
+                self.inner = Some(inner);
+                more
+            }
+        }
+    }
+
+    fn clone_box(&self) -> Box<dyn Analyzer> {
+        Box::new(self.clone())
+    }
+}
+
+/// Look for a proxy handshake, given the bytes of the flow that may be the client, and of the other flow
+fn parse_proxy_handshake(client: &[u8], server: &[u8]) -> ProxyHandshake {
+    match client.first() {
+        None => { ProxyHandshake::NeedMore }
+        Some(b'C') => { parse_http_connect(client, server) }
+        Some(4) => { parse_socks4(client, server).unwrap_or(ProxyHandshake::NeedMore) }
+        Some(5) => { parse_socks5(client, server).unwrap_or(ProxyHandshake::NeedMore) }
+        _ => { ProxyHandshake::NotProxy }
+    }
+}
+
+/// HTTP CONNECT request and the response headers, where a 2xx status establishes the tunnel
+fn parse_http_connect(client: &[u8], server: &[u8]) -> ProxyHandshake {
+    const METHOD: &[u8] = b"CONNECT ";
+    if !client.starts_with(&METHOD[..client.len().min(METHOD.len())]) {
+        return ProxyHandshake::NotProxy;
+    }
+    let request_len = match find_headers_end(client) {
+        None => { return ProxyHandshake::NeedMore; }
+        Some(len) => { len }
+    };
+    let request_line = String::from_utf8_lossy(&client[..request_len]);
+    let mut request_words = request_line.lines().next().unwrap_or_default().split(' ');
+    let target = match (request_words.next(), request_words.next(), request_words.next()) {
+        (Some("CONNECT"), Some(target), Some(version)) if version.starts_with("HTTP/") => { target.to_string() }
+        _ => { return ProxyHandshake::NotProxy; }
+    };
+    let response_len = match find_headers_end(server) {
+        None => { return ProxyHandshake::NeedMore; }
+        Some(len) => { len }
+    };
+    let status_line = String::from_utf8_lossy(&server[..response_len]);
+    let status = status_line.lines().next().unwrap_or_default().split(' ').nth(1).unwrap_or_default().to_string();
+    let kind = "http-connect";
+    if status.len() == 3 && status.starts_with('2') {
+        ProxyHandshake::Tunnel { kind, target, client_len: request_len, server_len: response_len }
+    } else {
+        ProxyHandshake::Refused { kind, target }
+    }
+}
+
+/// Length of the HTTP headers, up to and including the empty line, if complete
+fn find_headers_end(data: &[u8]) -> Option<usize> {
+    data.windows(4).position(|window| window == b"\r\n\r\n").map(|pos| pos + 4)
+}
+
+/// SOCKS4 (and 4a, with a host name) CONNECT request and the reply, or None if more bytes are needed
+fn parse_socks4(client: &[u8], server: &[u8]) -> Option<ProxyHandshake> {
+    let mut request = ByteReader::new(client);
+    let (_, command, port) = (request.u8()?, request.u8()?, request.u16()?);
+    let ip = Ipv4Addr::from(<[u8; 4]>::try_from(request.take(4)?).ok()?);
+    if command != 1 {
+        return Some(ProxyHandshake::NotProxy);
+    }
+    request.take_until_zero()?;
+    let octets = ip.octets();
+    let (kind, host) = if octets[..3] == [0, 0, 0] && octets[3] != 0 {
+        ("socks4a", String::from_utf8_lossy(request.take_until_zero()?).into_owned())
+    } else {
+        ("socks4", ip.to_string())
+    };
+    let target = format!("{}:{}", host, port);
+    let mut reply = ByteReader::new(server);
+    let (version, status) = (reply.u8()?, reply.u8()?);
+    reply.take(6)?;
+    if version != 0 {
+        return Some(ProxyHandshake::NotProxy);
+    }
+    Some(match status {
+        0x5a => { ProxyHandshake::Tunnel { kind, target, client_len: request.pos, server_len: reply.pos } }
+        _ => { ProxyHandshake::Refused { kind, target } }
+    })
+}
+
+/// SOCKS5 method negotiation, optional username/password authentication, and CONNECT request and reply,
+/// or None if more bytes are needed
+fn parse_socks5(client: &[u8], server: &[u8]) -> Option<ProxyHandshake> {
+    let kind = "socks5";
+    let mut request = ByteReader::new(client);
+    let mut reply = ByteReader::new(server);
+    request.u8()?;
+    let method_count = request.u8()? as usize;
+    let methods = request.take(method_count)?;
+    if method_count == 0 {
+        return Some(ProxyHandshake::NotProxy);
+    }
+    let (version, method) = (reply.u8()?, reply.u8()?);
+    if version != 5 || (method != 0xff && !methods.contains(&method)) {
+        return Some(ProxyHandshake::NotProxy);
+    }
+    if method == 0xff {
+        return Some(ProxyHandshake::Refused { kind, target: "unknown".to_string() });
+    }
+    if method == 2 {
+        // Username/password sub-negotiation
+        request.u8()?;
+        let user_len = request.u8()? as usize;
+        request.take(user_len)?;
+        let password_len = request.u8()? as usize;
+        request.take(password_len)?;
+        let (_, auth_status) = (reply.u8()?, reply.u8()?);
+        if auth_status != 0 {
+            return Some(ProxyHandshake::Refused { kind, target: "unknown".to_string() });
+        }
+    }
+    let (version, command, _) = (request.u8()?, request.u8()?, request.u8()?);
+    if version != 5 || command != 1 {
+        return Some(ProxyHandshake::NotProxy);
+    }
+    let target = parse_socks5_address(&mut request)?;
+    let (version, status, _) = (reply.u8()?, reply.u8()?, reply.u8()?);
+    if version != 5 {
+        return Some(ProxyHandshake::NotProxy);
+    }
+    parse_socks5_address(&mut reply)?;
+    Some(match status {
+        0 => { ProxyHandshake::Tunnel { kind, target, client_len: request.pos, server_len: reply.pos } }
+        _ => { ProxyHandshake::Refused { kind, target } }
+    })
+}
+
+/// SOCKS5 address type, address and port, as "host:port"
+fn parse_socks5_address(reader: &mut ByteReader) -> Option<String> {
+    let host = match reader.u8()? {
+        1 => { Ipv4Addr::from(<[u8; 4]>::try_from(reader.take(4)?).ok()?).to_string() }
+        3 => {
+            let name_len = reader.u8()? as usize;
+            String::from_utf8_lossy(reader.take(name_len)?).into_owned()
+        }
+        4 => { format!("[{}]", Ipv6Addr::from(<[u8; 16]>::try_from(reader.take(16)?).ok()?)) }
+        _ => { "unknown".to_string() }
+    };
+    Some(format!("{}:{}", host, reader.u16()?))
+}
+
+/// Max bytes of a flow to collect while looking for a complete client/server handshake
+const HANDSHAKE_MAX_BYTES: usize = 4096;
+
+/// Parses the handshake of a protocol, given the bytes of the flow that may be the client and of the other flow.
+/// Returns None if more bytes are needed, or whether the handshake matched, after recording what it found.
+type HandshakeParser = fn(client: &[u8], server: &[u8], app: &mut AppInfo) -> Option<bool>;
+
+/// Identifies a protocol by its opening handshake, where the client is whichever flow the parser accepts as one
+#[derive(Clone)]
+struct HandshakeAnalyzer {
+    /// Bytes collected so far per flow, indexed by [flow_index]
+    buffers: [Vec<u8>; 2],
+    parse: HandshakeParser,
+}
+
+impl HandshakeAnalyzer {
+    fn new(parse: HandshakeParser) -> Self {
+        Self { buffers: [Vec::new(), Vec::new()], parse }
+    }
+}
+
+impl Analyzer for HandshakeAnalyzer {
+    fn feed(&mut self, dir: &PacketDir, data: &[u8], app: &mut AppInfo) -> bool {
+        let index = flow_index(dir);
+        let room = HANDSHAKE_MAX_BYTES.saturating_sub(self.buffers[index].len());
+        self.buffers[index].extend_from_slice(&data[..data.len().min(room)]);
+        let [first, second] = &self.buffers;
+        match ((self.parse)(first, second, app), (self.parse)(second, first, app)) {
+            (Some(true), _) | (_, Some(true)) => { false }
+            (None, _) | (_, None) => { first.len() < HANDSHAKE_MAX_BYTES && second.len() < HANDSHAKE_MAX_BYTES }
+            _ => { false }
+        }
+    }
+
+    fn clone_box(&self) -> Box<dyn Analyzer> {
+        Box::new(self.clone())
+    }
+}
+
+/// PostgreSQL SSL and GSS encryption request codes, sent instead of a protocol version
+const POSTGRESQL_SSL_REQUEST: u32 = 80877103;
+const POSTGRESQL_GSS_REQUEST: u32 = 80877104;
+/// PostgreSQL protocol version 3.0
+const POSTGRESQL_PROTOCOL_3: u32 = 196608;
+
+/// PostgreSQL startup message with its user and database, optionally preceded by an SSL or GSS encryption request
+fn parse_postgresql_startup(client: &[u8], server: &[u8], app: &mut AppInfo) -> Option<bool> {
+    let mut request = ByteReader::new(client);
+    let (mut len, mut code) = (request.u32()?, request.u32()?);
+    if len == 8 && (code == POSTGRESQL_SSL_REQUEST || code == POSTGRESQL_GSS_REQUEST) {
+        let encryption = if code == POSTGRESQL_SSL_REQUEST { "ssl" } else { "gss" };
+        match server.first()? {
+            b'S' | b'G' => {
+                app.content_label = Some("postgresql");
+                app.add_metadata("postgresql_encryption", encryption.to_string());
+                return Some(true);
+            }
+            // Refused, so the startup message follows in the clear
+            b'N' => { (len, code) = (request.u32()?, request.u32()?) }
+            _ => { return Some(false); }
+        }
+    }
+    if code != POSTGRESQL_PROTOCOL_3 || !(9..=HANDSHAKE_MAX_BYTES as u32).contains(&len) {
+        return Some(false);
+    }
+    let mut parameters = ByteReader::new(request.take(len as usize - 8)?);
+    let mut user = None;
+    let mut database = None;
+    let mut application = None;
+    loop {
+        let key = parameters.take_until_zero()?;
+        if key.is_empty() {
+            break;
+        }
+        let value = String::from_utf8_lossy(parameters.take_until_zero()?).into_owned();
+        match key {
+            b"user" => { user = Some(value) }
+            b"database" => { database = Some(value) }
+            b"application_name" => { application = Some(value) }
+            _ => {}
+        }
+    }
+    app.content_label = Some("postgresql");
+    // The database defaults to the user name
+    if let Some(database) = database.or_else(|| user.clone()) {
+        app.add_metadata("postgresql_database", database);
+    }
+    if let Some(user) = user {
+        app.add_metadata("postgresql_user", user);
+    }
+    if let Some(application) = application {
+        app.add_metadata("postgresql_application", application);
+    }
+    Some(true)
+}
+
+/// MySQL capability flags of the client handshake response
+const MYSQL_CLIENT_CONNECT_WITH_DB: u32 = 0x8;
+const MYSQL_CLIENT_PROTOCOL_41: u32 = 0x200;
+const MYSQL_CLIENT_SSL: u32 = 0x800;
+const MYSQL_CLIENT_SECURE_CONNECTION: u32 = 0x8000;
+const MYSQL_CLIENT_PLUGIN_AUTH_LENENC_DATA: u32 = 0x200000;
+
+/// MySQL server greeting (protocol 10) and the client handshake response with its user and database,
+/// or its SSL request
+fn parse_mysql_handshake(client: &[u8], server: &[u8], app: &mut AppInfo) -> Option<bool> {
+    let greeting = match mysql_packet(server, 0) {
+        Some(Some(greeting)) => { greeting }
+        Some(None) => { return Some(false); }
+        None => { return None; }
+    };
+    let mut greeting = ByteReader::new(greeting);
+    if greeting.u8() != Some(10) {
+        return Some(false);
+    }
+    let version = match greeting.take_until_zero() {
+        Some(version) if version.iter().all(|b| b.is_ascii_graphic()) => { String::from_utf8_lossy(version).into_owned() }
+        _ => { return Some(false); }
+    };
+    let response = match mysql_packet(client, 1)? {
+        Some(response) => { response }
+        None => { return Some(false); }
+    };
+    let mut response = ByteReader::new(response);
+    let capabilities = read_u32_le(response.take(4)?, 0)?;
+    app.content_label = Some("mysql");
+    app.add_metadata("mysql_version", version);
+    if capabilities & MYSQL_CLIENT_SSL != 0 && response.data.len() == 32 {
+        app.add_metadata("mysql_encryption", "ssl".to_string());
+        return Some(true);
+    }
+    if capabilities & MYSQL_CLIENT_PROTOCOL_41 == 0 {
+        return Some(true);
+    }
+    // Max packet size, character set and filler
+    response.take(4 + 1 + 23)?;
+    let user = String::from_utf8_lossy(response.take_until_zero()?).into_owned();
+    app.add_metadata("mysql_user", user);
+    if capabilities & MYSQL_CLIENT_PLUGIN_AUTH_LENENC_DATA != 0 {
+        let auth_len = response.u8()?;
+        if auth_len >= 0xfb {
+            return Some(true);
+        }
+        response.take(auth_len as usize)?;
+    } else if capabilities & MYSQL_CLIENT_SECURE_CONNECTION != 0 {
+        let auth_len = response.u8()? as usize;
+        response.take(auth_len)?;
+    } else {
+        response.take_until_zero()?;
+    }
+    if capabilities & MYSQL_CLIENT_CONNECT_WITH_DB != 0 {
+        if let Some(database) = response.take_until_zero() {
+            app.add_metadata("mysql_database", String::from_utf8_lossy(database).into_owned());
+        }
+    }
+    Some(true)
+}
+
+/// Payload of the MySQL packet at the start of a flow, if it has the expected sequence id.
+/// Return None if the packet is not complete yet, or Some(None) if it does not look like the expected packet.
+fn mysql_packet(flow: &[u8], sequence_id: u8) -> Option<Option<&[u8]>> {
+    let header = flow.get(..4)?;
+    let len = header[0] as usize | (header[1] as usize) << 8 | (header[2] as usize) << 16;
+    if header[3] != sequence_id || len == 0 || len > HANDSHAKE_MAX_BYTES {
+        return Some(None);
+    }
+    Some(Some(flow.get(4..4 + len)?))
+}
+
+/// RDP X.224 connection request and confirm, with the security protocol that was negotiated (if any)
+fn parse_rdp_negotiation(client: &[u8], server: &[u8], app: &mut AppInfo) -> Option<bool> {
+    let request = match x224_message(client, 0xe0)? {
+        Some(request) => { request }
+        None => { return Some(false); }
+    };
+    let confirm = match x224_message(server, 0xd0)? {
+        Some(confirm) => { confirm }
+        None => { return Some(false); }
+    };
+    app.content_label = Some("rdp");
+    // The request starts with an optional cookie line, with a user name hint
+    if let Some(cookie) = request.strip_prefix(b"Cookie: mstshash=") {
+        if let Some(end) = cookie.windows(2).position(|window| window == b"\r\n") {
+            app.add_metadata("rdp_user", String::from_utf8_lossy(&cookie[..end]).into_owned());
+        }
+    }
+    // Without a negotiation response, the server uses standard RDP security
+    let security = match (confirm.first(), read_u32_le(confirm, 4)) {
+        (Some(2), Some(0)) | (None, _) => { "rdp" }
+        (Some(2), Some(1)) => { "tls" }
+        (Some(2), Some(2)) => { "credssp" }
+        (Some(2), Some(4)) => { "rdstls" }
+        (Some(2), Some(8)) => { "credssp-early-auth" }
+        (Some(2), Some(16)) => { "aad" }
+        (Some(3), _) => { "negotiation-failure" }
+        _ => { "unknown" }
+    };
+    app.add_metadata("rdp_security", security.to_string());
+    Some(true)
+}
+
+/// Variable part of an X.224 TPDU with the given code, carried in a TPKT at the start of a flow.
+/// Return None if the message is not complete yet, or Some(None) if it is not such a message.
+fn x224_message(flow: &[u8], code: u8) -> Option<Option<&[u8]>> {
+    let mut reader = ByteReader::new(flow);
+    let (version, _, len) = (reader.u8()?, reader.u8()?, reader.u16()? as usize);
+    if version != 3 || !(11..=HANDSHAKE_MAX_BYTES).contains(&len) {
+        return Some(None);
+    }
+    let mut tpdu = ByteReader::new(reader.take(len - 4)?);
+    let (header_len, tpdu_code) = (tpdu.u8()?, tpdu.u8()?);
+    if tpdu_code & 0xf0 != code || header_len < 6 {
+        return Some(None);
+    }
+    // Destination and source references and class
+    tpdu.take(5);
+    Some(Some(&tpdu.data[tpdu.pos.min(tpdu.data.len())..]))
+}
+
+/// VNC (RFB) version exchange and the security type chosen by the client
+fn parse_vnc_handshake(client: &[u8], server: &[u8], app: &mut AppInfo) -> Option<bool> {
+    let server_version = match rfb_version(server)? {
+        Some(version) => { version }
+        None => { return Some(false); }
+    };
+    let client_version = match rfb_version(client)? {
+        Some(version) => { version }
+        None => { return Some(false); }
+    };
+    app.content_label = Some("vnc");
+    app.add_metadata("vnc_version", client_version.min(server_version).to_string());
+    let mut security = ByteReader::new(&server[12..]);
+    let security_type = if client_version < "003.007" {
+        // The server decides alone
+        security.u32()?
+    } else {
+        let type_count = security.u8()?;
+        if type_count == 0 {
+            app.add_metadata("vnc_security", "connection-failed".to_string());
+            return Some(true);
+        }
+        *client.get(12)? as u32
+    };
+    let security_name = match security_type {
+        0 => { "connection-failed" }
+        1 => { "none" }
+        2 => { "vnc-auth" }
+        5 => { "ra2" }
+        16 => { "tight" }
+        18 => { "tls" }
+        19 => { "vencrypt" }
+        30 => { "apple-ard" }
+        _ => { "unknown" }
+    };
+    app.add_metadata("vnc_security", security_name.to_string());
+    Some(true)
+}
+
+/// Protocol version of an RFB version message ("RFB 003.008\n") at the start of a flow, such as "003.008".
+/// Return None if the message is not complete yet, or Some(None) if it is not such a message.
+fn rfb_version(flow: &[u8]) -> Option<Option<&str>> {
+    const PREFIX: &[u8] = b"RFB ";
+    if !flow.starts_with(&PREFIX[..flow.len().min(PREFIX.len())]) {
+        return Some(None);
+    }
+    let message = flow.get(..12)?;
+    if message[11] != b'\n' || !message[4..11].iter().all(|b| b.is_ascii_digit() || *b == b'.') {
+        return Some(None);
+    }
+    Some(std::str::from_utf8(&message[4..11]).ok())
+}
+
+/// Max length of a protocol line (command line or RESP header) of the cache protocols
+const CACHE_LINE_MAX_BYTES: usize = 4096;
+
+/// Text commands of memcached, that is identified by its first command
+const MEMCACHED_COMMANDS: &[&str] = &[
+    "get", "gets", "gat", "gats", "set", "add", "replace", "append", "prepend", "cas", "delete", "incr", "decr",
+    "touch", "flush_all", "stats", "version", "verbosity", "quit", "mg", "ms", "md", "ma", "mn", "me",
+];
+
+/// Counts the commands of Redis (RESP) and memcached (text protocol) clients by verb, for the whole buffered stream.
+/// The client is the flow that sends first.
+#[derive(Clone)]
+struct CacheAnalyzer {
+    /// Flow index of the client, once it sent something
+    client: Option<usize>,
+    protocol: Option<CacheProtocol>,
+    /// Partial line collected so far
+    line: Vec<u8>,
+    /// Payload bytes to skip before the next line, such as stored values
+    skip: usize,
+    /// RESP elements left in the current command array
+    elements_left: usize,
+    /// Whether the next RESP bulk string of the array is the verb
+    verb_next: bool,
+    /// Length of the RESP bulk string that holds the verb, when it is the next line
+    verb_len: Option<usize>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum CacheProtocol {
+    Redis,
+    Memcached,
+}
+
+impl CacheAnalyzer {
+    fn new() -> Self {
+        Self { client: None, protocol: None, line: Vec::new(), skip: 0, elements_left: 0, verb_next: false, verb_len: None }
+    }
+
+    /// Handle a complete line of the client, without its CRLF. Return false if it is not the expected protocol.
+    fn handle_line(&mut self, line: &[u8], app: &mut AppInfo) -> bool {
+        if self.protocol.is_none() {
+            let first_word = line.split(|b| *b == b' ').next().unwrap_or_default();
+            self.protocol = if line.first() == Some(&b'*') {
+                Some(CacheProtocol::Redis)
+            } else if MEMCACHED_COMMANDS.iter().any(|command| command.as_bytes() == first_word) {
+                Some(CacheProtocol::Memcached)
+            } else {
+                return false;
+            };
+        }
+        match self.protocol {
+            Some(CacheProtocol::Redis) => { self.handle_resp_line(line, app) }
+            Some(CacheProtocol::Memcached) => { self.handle_memcached_line(line, app) }
+            None => { false }
+        }
+    }
+
+    fn handle_resp_line(&mut self, line: &[u8], app: &mut AppInfo) -> bool {
+        let number = || std::str::from_utf8(line.get(1..)?).ok()?.parse::<usize>().ok();
+        if let Some(verb_len) = self.verb_len.take() {
+            if line.len() != verb_len || !line.iter().all(|b| b.is_ascii_alphabetic()) {
+                return false;
+            }
+            app.content_label = Some("redis");
+            app.count_command(&String::from_utf8_lossy(line).to_ascii_uppercase());
+            self.elements_left -= 1;
+            return true;
+        }
+        match (self.elements_left, line.first()) {
+            (0, Some(b'*')) => {
+                match number() {
+                    Some(count) if count > 0 => { self.elements_left = count; self.verb_next = true }
+                    _ => { return false; }
+                }
+            }
+            (0, _) => {
+                // Inline command, as typed in a terminal
+                let verb = line.split(|b| *b == b' ').next().unwrap_or_default();
+                if verb.is_empty() || !verb.iter().all(|b| b.is_ascii_alphabetic()) {
+                    return false;
+                }
+                app.count_command(&String::from_utf8_lossy(verb).to_ascii_uppercase());
+            }
+            (_, Some(b'$')) => {
+                match number() {
+                    None => { return false; }
+                    // The first element is the verb, read as a line
+                    Some(len) if self.verb_next => { self.verb_next = false; self.verb_len = Some(len) }
+                    Some(len) => { self.skip = len + 2; self.elements_left -= 1 }
+                }
+            }
+            _ => { return false; }
+        }
+        true
+    }
+
+    fn handle_memcached_line(&mut self, line: &[u8], app: &mut AppInfo) -> bool {
+        let line = String::from_utf8_lossy(line);
+        let words: Vec<&str> = line.split(' ').collect();
+        let verb = words[0];
+        if !MEMCACHED_COMMANDS.contains(&verb) {
+            return false;
+        }
+        app.content_label = Some("memcached");
+        app.count_command(&verb.to_ascii_uppercase());
+        // Storage commands are followed by a data block and CRLF, given as the 4th word, or the 2nd of meta set
+        let data_len = match verb {
+            "set" | "add" | "replace" | "append" | "prepend" | "cas" => { words.get(4) }
+            "ms" => { words.get(2) }
+            _ => { None }
+        };
+        if let Some(data_len) = data_len {
+            match data_len.parse::<usize>() {
+                Ok(data_len) => { self.skip = data_len + 2 }
+                Err(_) => { return false; }
+            }
+        }
+        true
+    }
+}
+
+impl Analyzer for CacheAnalyzer {
+    fn feed(&mut self, dir: &PacketDir, mut data: &[u8], app: &mut AppInfo) -> bool {
+        let index = flow_index(dir);
+        if *self.client.get_or_insert(index) != index {
+            return true;
+        }
+        while !data.is_empty() {
+            let skipped = self.skip.min(data.len());
+            self.skip -= skipped;
+            data = &data[skipped..];
+            let line_end = match data.iter().position(|b| *b == b'\n') {
+                None => {
+                    self.line.extend_from_slice(data);
+                    return self.line.len() <= CACHE_LINE_MAX_BYTES;
+                }
+                Some(pos) => { pos }
+            };
+            self.line.extend_from_slice(&data[..line_end]);
+            data = &data[line_end + 1..];
+            let mut line = std::mem::take(&mut self.line);
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+            if line.len() > CACHE_LINE_MAX_BYTES || !self.handle_line(&line, app) {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn max_bytes(&self) -> usize {
+        usize::MAX
+    }
+
+    fn clone_box(&self) -> Box<dyn Analyzer> {
+        Box::new(self.clone())
+    }
+}
+
+/// Max length of the start line and headers of an HTTP message, beyond which the flow is not HTTP
+const HTTP_HEAD_MAX_BYTES: usize = 16384;
+/// Max length of an HTTP method, to tell early that a flow is not HTTP
+const HTTP_METHOD_MAX_LEN: usize = 16;
+
+/// Which part of an HTTP message the next bytes of a flow are
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum HttpPart {
+    /// Start line and headers
+    Head,
+    /// Body with the given number of bytes left
+    Body(u64),
+    /// Line with the size of the next chunk of a chunked body
+    ChunkSize,
+    /// Chunk with the given number of bytes left, including its CRLF
+    ChunkData(u64),
+    /// Trailer lines after the last chunk, up to an empty line
+    Trailers,
+}
+
+/// Parsing state of one flow of an HTTP connection
+#[derive(Clone)]
+struct HttpFlow {
+    part: HttpPart,
+    /// Partial line collected so far
+    line: Vec<u8>,
+    /// Lines of the current head, without their line ends
+    head: Vec<String>,
+    /// Bytes of the current head so far
+    head_len: usize,
+}
+
+impl HttpFlow {
+    fn new() -> Self {
+        Self { part: HttpPart::Head, line: Vec::new(), head: Vec::new(), head_len: 0 }
+    }
+}
+
+/// Records the transactions of HTTP/1.x, for the whole buffered stream: the method, target and host of every request,
+/// the status of its response, and the Content-Length of both. Bodies are skipped by their length or chunks, and
+/// responses are matched to the requests in order, as with pipelining. The client is the flow that sends first.
+/// CONNECT requests are left to the proxy analyzer, that follows the tunnel.
+#[derive(Clone)]
+struct HttpAnalyzer {
+    /// Flow index of the client, once it sent something
+    client: Option<usize>,
+    /// State of each flow, indexed by [flow_index]
+    flows: [HttpFlow; 2],
+    /// Indexes of the requests that wait for their response, in the HTTP transactions of the connection, oldest first
+    pending: VecDeque<usize>,
+}
+
+impl HttpAnalyzer {
+    fn new() -> Self {
+        Self { client: None, flows: [HttpFlow::new(), HttpFlow::new()], pending: VecDeque::new() }
+    }
+
+    /// Handle a complete line of a flow, without its line end. Return false if the flow is not HTTP, or when there is
+    /// nothing more to parse.
+    fn handle_line(&mut self, index: usize, line: &[u8], app: &mut AppInfo) -> bool {
+        let is_client = self.client == Some(index);
+        let flow = &mut self.flows[index];
+        match flow.part {
+            HttpPart::ChunkSize => {
+                let size = String::from_utf8_lossy(line);
+                match u64::from_str_radix(size.split(';').next().unwrap_or_default().trim(), 16) {
+                    Err(_) => { return false; }
+                    Ok(0) => { flow.part = HttpPart::Trailers }
+                    Ok(size) => {
+                        // The chunk is followed by its CRLF, and a size that overflows with it is not HTTP
+                        match size.checked_add(2) {
+                            None => { return false; }
+                            Some(left) => { flow.part = HttpPart::ChunkData(left) }
+                        }
+                    }
+                }
+                true
+            }
+            HttpPart::Trailers => {
+                if line.is_empty() {
+                    flow.part = HttpPart::Head;
+                }
+                true
+            }
+            _ if line.is_empty() => {
+                // Empty lines between messages are tolerated
+                if flow.head.is_empty() {
+                    return true;
+                }
+                let head = std::mem::take(&mut flow.head);
+                flow.head_len = 0;
+                if is_client { self.handle_request(index, &head, app) } else { self.handle_response(index, &head, app) }
+            }
+            _ => {
+                if flow.head.is_empty() && !is_http_start_line(line, is_client) {
+                    return false;
+                }
+                flow.head_len += line.len() + 2;
+                flow.head.push(String::from_utf8_lossy(line).into_owned());
+                flow.head_len <= HTTP_HEAD_MAX_BYTES
+            }
+        }
+    }
+
+    fn handle_request(&mut self, index: usize, head: &[String], app: &mut AppInfo) -> bool {
+        let mut words = head[0].split(' ');
+        let (method, path) = (words.next().unwrap_or_default(), words.next().unwrap_or_default());
+        let length = match http_content_length(head) {
+            Err(_) => { return false; }
+            Ok(length) => { length }
+        };
+        if method == "CONNECT" || app.http_transactions.len() >= MAX_HTTP_TRANSACTIONS {
+            return false;
+        }
+        app.content_label = Some("http");
+        app.http_transactions.push(HttpTransaction {
+            method: method.to_string(),
+            path: path.to_string(),
+            host: http_header(head, "host").map(String::from),
+            status: None,
+            request_length: length,
+            response_length: None,
+        });
+        self.pending.push_back(app.http_transactions.len() - 1);
+        self.flows[index].part = match (http_is_chunked(head), length) {
+            (true, _) => { HttpPart::ChunkSize }
+            (false, Some(length)) if length > 0 => { HttpPart::Body(length) }
+            _ => { HttpPart::Head }
+        };
+        true
+    }
+
+    fn handle_response(&mut self, index: usize, head: &[String], app: &mut AppInfo) -> bool {
+        let status = match head[0].get(9..12).and_then(|status| status.parse::<u16>().ok()) {
+            None => { return false; }
+            Some(status) => { status }
+        };
+        if (100..200).contains(&status) && status != 101 {
+            return true;
+        }
+        let length = match http_content_length(head) {
+            Err(_) => { return false; }
+            Ok(length) => { length }
+        };
+        // A response without a request means that the order was lost, so the rest cannot be matched
+        let transaction = match self.pending.pop_front().and_then(|pending| app.http_transactions.get_mut(pending)) {
+            None => { return false; }
+            Some(transaction) => { transaction }
+        };
+        transaction.status = Some(status);
+        transaction.response_length = length;
+        // The connection switched to another protocol, such as WebSocket
+        if status == 101 {
+            return false;
+        }
+        let has_body = transaction.method != "HEAD" && status != 204 && status != 304;
+        self.flows[index].part = match (has_body, http_is_chunked(head), length) {
+            (false, _, _) => { HttpPart::Head }
+            (true, true, _) => { HttpPart::ChunkSize }
+            (true, false, Some(0)) => { HttpPart::Head }
+            (true, false, Some(length)) => { HttpPart::Body(length) }
+            // The body lasts until the connection is closed, so no more responses follow
+            (true, false, None) => { return false; }
+        };
+        true
+    }
+}
+
+impl Analyzer for HttpAnalyzer {
+    fn feed(&mut self, dir: &PacketDir, mut data: &[u8], app: &mut AppInfo) -> bool {
+        let index = flow_index(dir);
+        let is_client = *self.client.get_or_insert(index) == index;
+        while !data.is_empty() {
+            let flow = &mut self.flows[index];
+            match flow.part {
+                HttpPart::Body(left) | HttpPart::ChunkData(left) => {
+                    let skipped = left.min(data.len() as u64);
+                    data = &data[skipped as usize..];
+                    flow.part = match flow.part {
+                        HttpPart::Body(_) if skipped == left => { HttpPart::Head }
+                        HttpPart::Body(_) => { HttpPart::Body(left - skipped) }
+                        _ if skipped == left => { HttpPart::ChunkSize }
+                        _ => { HttpPart::ChunkData(left - skipped) }
+                    };
+                }
+                _ => {
+                    let line_end = match data.iter().position(|b| *b == b'\n') {
+                        None => {
+                            flow.line.extend_from_slice(data);
+                            let start_ok = !flow.head.is_empty() || flow.part != HttpPart::Head
+                                || is_http_start_prefix(&flow.line, is_client);
+                            return start_ok && flow.head_len + flow.line.len() <= HTTP_HEAD_MAX_BYTES;
+                        }
+                        Some(pos) => { pos }
+                    };
+                    flow.line.extend_from_slice(&data[..line_end]);
+                    data = &data[line_end + 1..];
+                    let mut line = std::mem::take(&mut flow.line);
+                    if line.last() == Some(&b'\r') {
+                        line.pop();
+                    }
+                    if !self.handle_line(index, &line, app) {
+                        return false;
+                    }
+                }
+            }
+        }
+        true
+    }
+
+    fn max_bytes(&self) -> usize {
+        usize::MAX
+    }
+
+    fn clone_box(&self) -> Box<dyn Analyzer> {
+        Box::new(self.clone())
+    }
+}
+
+/// Whether the line may be the start of a request ("GET / HTTP/1.1") or of a response ("HTTP/1.1 200 OK"), so far
+fn is_http_start_prefix(line: &[u8], is_client: bool) -> bool {
+    if !is_client {
+        return b"HTTP/1.".starts_with(&line[..line.len().min(7)]);
+    }
+    let method_len = line.iter().position(|b| *b == b' ').unwrap_or(line.len());
+    method_len <= HTTP_METHOD_MAX_LEN && line[..method_len].iter().all(|b| b.is_ascii_uppercase())
+}
+
+/// Whether the line is the start line of a request or of a response of HTTP/1.x
+fn is_http_start_line(line: &[u8], is_client: bool) -> bool {
+    let line = String::from_utf8_lossy(line);
+    let words: Vec<&str> = line.split(' ').collect();
+    if is_client {
+        return matches!(words.as_slice(), [method, path, "HTTP/1.0" | "HTTP/1.1"]
+            if !method.is_empty() && !path.is_empty() && is_http_start_prefix(method.as_bytes(), true));
+    }
+    match words.as_slice() {
+        ["HTTP/1.0" | "HTTP/1.1", status, ..] => { status.len() == 3 && status.bytes().all(|b| b.is_ascii_digit()) }
+        _ => { false }
+    }
+}
+
+/// Value of the first header with the given lower-case name, after the start line of the head
+fn http_header<'a>(head: &'a [String], name: &str) -> Option<&'a str> {
+    head[1..].iter().filter_map(|line| line.split_once(':'))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim())
+}
+
+fn http_content_length(head: &[String]) -> Result<Option<u64>, std::num::ParseIntError> {
+    http_header(head, "content-length").map(|length| length.parse::<u64>()).transpose()
+}
+
+/// Whether the body is chunked, which takes precedence over the Content-Length
+fn http_is_chunked(head: &[String]) -> bool {
+    http_header(head, "transfer-encoding").is_some_and(|encoding| encoding.to_ascii_lowercase().ends_with("chunked"))
+}
+
+/// Length of the DNS message header
+const DNS_HEADER_LEN: usize = 12;
+/// Compression pointers to follow in a DNS name, against pointer loops
+const DNS_MAX_POINTERS: usize = 16;
+
+/// Follows DNS over TCP (RFC 7766), where every message has a 2 byte length prefix, and records the question of every
+/// query with the response code and the number of answers of its response, matched by the transaction ID since the
+/// responses may come out of order. A response whose query was not seen is recorded from its own question.
+#[derive(Clone)]
+struct DnsAnalyzer {
+    /// Partial message collected so far per flow, with its length prefix, indexed by [flow_index]
+    buffers: [Vec<u8>; 2],
+    /// Transaction IDs of the queries that wait for their response, with their indexes in the DNS queries of the connection
+    pending: Vec<(u16, usize)>,
+    /// Whether a DNS message was seen, after which messages that fail to parse are ignored
+    identified: bool,
+}
+
+impl DnsAnalyzer {
+    fn new() -> Self {
+        Self { buffers: [Vec::new(), Vec::new()], pending: Vec::new(), identified: false }
+    }
+
+    /// Record a complete message, without its length prefix. Return false if the flow is not DNS, or when there are
+    /// too many queries.
+    fn handle_message(&mut self, message: &[u8], app: &mut AppInfo) -> bool {
+        let message = match parse_dns_message(message) {
+            None => { return self.identified; }
+            Some(message) => { message }
+        };
+        self.identified = true;
+        app.content_label = Some("dns");
+        if message.response {
+            if let Some(pos) = self.pending.iter().position(|(id, _)| *id == message.id) {
+                let (_, index) = self.pending.remove(pos);
+                if let Some(query) = app.dns_queries.get_mut(index) {
+                    query.rcode = Some(message.rcode);
+                    query.answers = Some(message.answers);
+                }
+                return true;
+            }
+        }
+        let (name, qtype) = match message.question {
+            // Such as the later messages of a zone transfer
+            None => { return true; }
+            Some(question) => { question }
+        };
+        if app.dns_queries.len() >= MAX_DNS_QUERIES {
+            return false;
+        }
+        let (rcode, answers) = if message.response { (Some(message.rcode), Some(message.answers)) } else { (None, None) };
+        app.dns_queries.push(DnsQuery { id: message.id, name, qtype, rcode, answers });
+        if !message.response {
+            self.pending.push((message.id, app.dns_queries.len() - 1));
+        }
+        true
+    }
+}
+
+impl Analyzer for DnsAnalyzer {
+    fn feed(&mut self, dir: &PacketDir, mut data: &[u8], app: &mut AppInfo) -> bool {
+        let index = flow_index(dir);
+        while !data.is_empty() {
+            let buffer = &mut self.buffers[index];
+            // Complete the length prefix and the header, that tells early whether the flow is DNS at all
+            let header_missing = (2 + DNS_HEADER_LEN).saturating_sub(buffer.len());
+            let header_taken = header_missing.min(data.len());
+            buffer.extend_from_slice(&data[..header_taken]);
+            data = &data[header_taken..];
+            if buffer.len() < 2 + DNS_HEADER_LEN {
+                return true;
+            }
+            let message_len = u16::from_be_bytes([buffer[0], buffer[1]]) as usize;
+            if message_len < DNS_HEADER_LEN || (!self.identified && !is_dns_header(&buffer[2..])) {
+                return false;
+            }
+            let message_missing = 2 + message_len - buffer.len();
+            let message_taken = message_missing.min(data.len());
+            buffer.extend_from_slice(&data[..message_taken]);
+            data = &data[message_taken..];
+            if buffer.len() < 2 + message_len {
+                return true;
+            }
+            let message = std::mem::take(buffer);
+            if !self.handle_message(&message[2..], app) {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn max_bytes(&self) -> usize {
+        usize::MAX
+    }
+
+    fn clone_box(&self) -> Box<dyn Analyzer> {
+        Box::new(self.clone())
+    }
+}
+
+/// What the DNS analyzer takes from a message
+struct DnsMessage {
+    id: u16,
+    response: bool,
+    rcode: u8,
+    answers: u16,
+    /// Name and type of the first question, if any
+    question: Option<(String, u16)>,
+}
+
+/// Whether a message header looks like DNS: a known opcode, the reserved bit clear and a single question, as in the
+/// first message of every connection
+fn is_dns_header(header: &[u8]) -> bool {
+    let opcode = (header[2] >> 3) & 0xf;
+    let reserved = header[3] & 0x40;
+    let questions = u16::from_be_bytes([header[4], header[5]]);
+    matches!(opcode, 0 | 1 | 2 | 4 | 5 | 6) && reserved == 0 && questions == 1
+}
+
+fn parse_dns_message(message: &[u8]) -> Option<DnsMessage> {
+    let mut reader = ByteReader::new(message);
+    let id = reader.u16()?;
+    let flags = reader.u16()?;
+    let questions = reader.u16()?;
+    let answers = reader.u16()?;
+    let question = if questions == 0 {
+        None
+    } else {
+        reader.take(4)?;
+        let (name, name_end) = dns_name(message, reader.pos)?;
+        reader.pos = name_end;
+        let qtype = reader.u16()?;
+        // The class, which is IN in practice
+        reader.u16()?;
+        Some((name, qtype))
+    };
+    Some(DnsMessage { id, response: flags & 0x8000 != 0, rcode: (flags & 0xf) as u8, answers, question })
+}
+
+/// The question name of a successful DNS response, and the addresses of its A and AAAA answers, that may be of
+/// aliases (CNAME records) of the name, answered along
+pub(crate) fn dns_answer_addresses(message: &[u8]) -> Option<(String, Vec<IpAddr>)> {
+    let mut reader = ByteReader::new(message);
+    reader.u16()?;
+    let flags = reader.u16()?;
+    let (questions, answers) = (reader.u16()?, reader.u16()?);
+    if flags & 0x8000 == 0 || flags & 0xf != 0 || questions != 1 {
+        return None;
+    }
+    reader.take(4)?;
+    let (name, name_end) = dns_name(message, reader.pos)?;
+    reader.pos = name_end;
+    reader.take(4)?;
+    let mut addresses = Vec::new();
+    for _ in 0..answers {
+        let (_, owner_end) = dns_name(message, reader.pos)?;
+        reader.pos = owner_end;
+        let record_type = reader.u16()?;
+        // The class and the TTL
+        reader.take(2 + 4)?;
+        let data_len = reader.u16()? as usize;
+        let data = reader.take(data_len)?;
+        match (record_type, data_len) {
+            (1, 4) => { addresses.push(IpAddr::from(<[u8; 4]>::try_from(data).ok()?)) }
+            (28, 16) => { addresses.push(IpAddr::from(<[u8; 16]>::try_from(data).ok()?)) }
+            _ => {}
+        }
+    }
+    Some((name, addresses))
+}
+
+/// The name at the given position of a message, in dotted form, with the position right after it.
+/// Compressed names (RFC 1035 section 4.1.4) point back into the message.
+fn dns_name(message: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    let mut name = String::new();
+    let mut end = None;
+    let mut pointers = 0;
+    loop {
+        let label_len = *message.get(pos)? as usize;
+        match label_len & 0xc0 {
+            0 if label_len == 0 => {
+                if name.is_empty() {
+                    name.push('.');
+                }
+                return Some((name, end.unwrap_or(pos + 1)));
+            }
+            0 => {
+                let label = message.get(pos + 1..pos + 1 + label_len)?;
+                if !name.is_empty() {
+                    name.push('.');
+                }
+                name.push_str(&String::from_utf8_lossy(label));
+                if name.len() > MAX_SERVER_NAME_LEN {
+                    return None;
+                }
+                pos += 1 + label_len;
+            }
+            0xc0 => {
+                pointers += 1;
+                if pointers > DNS_MAX_POINTERS {
+                    return None;
+                }
+                end.get_or_insert(pos + 2);
+                pos = (label_len & 0x3f) << 8 | *message.get(pos + 1)? as usize;
+            }
+            _ => { return None; }
+        }
+    }
+}
+
+fn dns_type_name(qtype: u16) -> Option<&'static str> {
+    match qtype {
+        1 => { Some("A") }
+        2 => { Some("NS") }
+        5 => { Some("CNAME") }
+        6 => { Some("SOA") }
+        12 => { Some("PTR") }
+        15 => { Some("MX") }
+        16 => { Some("TXT") }
+        28 => { Some("AAAA") }
+        33 => { Some("SRV") }
+        35 => { Some("NAPTR") }
+        43 => { Some("DS") }
+        46 => { Some("RRSIG") }
+        48 => { Some("DNSKEY") }
+        64 => { Some("SVCB") }
+        65 => { Some("HTTPS") }
+        251 => { Some("IXFR") }
+        252 => { Some("AXFR") }
+        255 => { Some("ANY") }
+        _ => { None }
+    }
+}
+
+fn dns_rcode_name(rcode: u8) -> Option<&'static str> {
+    match rcode {
+        0 => { Some("NOERROR") }
+        1 => { Some("FORMERR") }
+        2 => { Some("SERVFAIL") }
+        3 => { Some("NXDOMAIN") }
+        4 => { Some("NOTIMP") }
+        5 => { Some("REFUSED") }
+        _ => { None }
+    }
+}
+
+/// Messages larger than this are skipped by the SMB analyzer without buffering, since they carry file data
+const SMB_MESSAGE_MAX_BYTES: usize = 16384;
+/// SMB2 header length, followed by the command body
+const SMB2_HEADER_LEN: usize = 64;
+
+/// Extracts session metadata from SMB2/3 over direct TCP (port 445): the negotiated dialect, the NTLM user,
+/// the tree connect share paths and the names of opened files. Encrypted (SMB 3 transform) messages are skipped.
+#[derive(Clone)]
+struct SmbAnalyzer {
+    /// Partial message collected so far per flow, indexed by [flow_index]
+    buffers: [Vec<u8>; 2],
+    /// Bytes left to skip per flow, of a message that is too large to buffer
+    skip: [usize; 2],
+    /// Whether an SMB message was seen, after which messages that fail to parse are ignored
+    identified: bool,
+}
+
+impl SmbAnalyzer {
+    fn new() -> Self {
+        Self { buffers: [Vec::new(), Vec::new()], skip: [0, 0], identified: false }
+    }
+}
+
+impl Analyzer for SmbAnalyzer {
+    fn feed(&mut self, dir: &PacketDir, mut data: &[u8], app: &mut AppInfo) -> bool {
+        let index = flow_index(dir);
+        loop {
+            let skipped = self.skip[index].min(data.len());
+            self.skip[index] -= skipped;
+            data = &data[skipped..];
+            let buffer = &mut self.buffers[index];
+            // Complete the NetBIOS session header (zero type and 24 bit length), and then the message
+            let header_missing = 4usize.saturating_sub(buffer.len());
+            let header_taken = header_missing.min(data.len());
+            buffer.extend_from_slice(&data[..header_taken]);
+            data = &data[header_taken..];
+            if buffer.len() < 4 {
+                return true;
+            }
+            let message_len = (buffer[1] as usize) << 16 | (buffer[2] as usize) << 8 | buffer[3] as usize;
+            if buffer[0] != 0 {
+                return false;
+            }
+            if message_len > SMB_MESSAGE_MAX_BYTES {
+                self.skip[index] = message_len;
+                buffer.clear();
+                continue;
+            }
+            let message_missing = 4 + message_len - buffer.len();
+            let message_taken = message_missing.min(data.len());
+            buffer.extend_from_slice(&data[..message_taken]);
+            data = &data[message_taken..];
+            if buffer.len() < 4 + message_len {
+                return true;
+            }
+            let message = std::mem::take(buffer);
+            match parse_smb_message(&message[4..], app) {
+                None if !self.identified => { return false; }
+                None => {}
+                Some(_) => {
+                    self.identified = true;
+                    app.content_label = Some("smb");
+                }
+            }
+            if data.is_empty() {
+                return true;
+            }
+        }
+    }
+
+    fn clone_box(&self) -> Box<dyn Analyzer> {
+        Box::new(self.clone())
+    }
+}
+
+/// Record the metadata of an SMB message, that may hold several compounded SMB2 commands.
+/// Return None if it is not SMB at all.
+fn parse_smb_message(message: &[u8], app: &mut AppInfo) -> Option<()> {
+    match message.get(..4)? {
+        // SMB 1, such as the first negotiate of a client that also offers SMB2
+        [0xff, b'S', b'M', b'B'] => { return Some(()); }
+        // Encrypted SMB 3
+        [0xfd, b'S', b'M', b'B'] => { return Some(()); }
+        [0xfe, b'S', b'M', b'B'] => {}
+        _ => { return None; }
+    }
+    let mut offset = 0;
+    loop {
+        let command = message.get(offset..)?;
+        if command.len() < SMB2_HEADER_LEN || command[..4] != [0xfe, b'S', b'M', b'B'] {
+            return Some(());
+        }
+        let command_code = read_u16_le(command, 12)?;
+        let response = read_u32_le(command, 16)? & 1 != 0;
+        let body = &command[SMB2_HEADER_LEN..];
+        match (command_code, response) {
+            (0, true) => {
+                if let Some(dialect) = read_u16_le(body, 4).and_then(smb_dialect_name) {
+                    app.add_metadata("smb_dialect", dialect.to_string());
+                }
+            }
+            (1, false) => {
+                let blob = read_u16_le(body, 12).zip(read_u16_le(body, 14))
+                    .and_then(|(blob_offset, blob_len)| command.get(blob_offset as usize..blob_offset as usize + blob_len as usize));
+                if let Some(user) = blob.and_then(ntlm_user) {
+                    app.add_metadata("smb_user", user);
+                }
+            }
+            (3, false) => {
+                if let Some(path) = read_u16_le(body, 4).zip(read_u16_le(body, 6))
+                    .and_then(|(path_offset, path_len)| command.get(path_offset as usize..path_offset as usize + path_len as usize)) {
+                    app.add_metadata("smb_share", utf16le_to_string(path));
+                }
+            }
+            (5, false) => {
+                if let Some(name) = read_u16_le(body, 44).zip(read_u16_le(body, 46))
+                    .and_then(|(name_offset, name_len)| command.get(name_offset as usize..name_offset as usize + name_len as usize)) {
+                    if !name.is_empty() {
+                        app.add_metadata("smb_file", utf16le_to_string(name));
+                    }
+                }
+            }
+            _ => {}
+        }
+        let next_command = read_u32_le(command, 20)? as usize;
+        if next_command == 0 {
+            return Some(());
+        }
+        offset += next_command;
+    }
+}
+
+fn smb_dialect_name(dialect: u16) -> Option<&'static str> {
+    match dialect {
+        0x0202 => { Some("2.0.2") }
+        0x0210 => { Some("2.1") }
+        0x0300 => { Some("3.0") }
+        0x0302 => { Some("3.0.2") }
+        0x0311 => { Some("3.1.1") }
+        _ => { None }
+    }
+}
+
+/// "DOMAIN\user" of the NTLMSSP authenticate message in a security blob, if any (Kerberos blobs have no plain user)
+fn ntlm_user(blob: &[u8]) -> Option<String> {
+    const SIGNATURE: &[u8] = b"NTLMSSP\0";
+    let start = blob.windows(SIGNATURE.len()).position(|window| window == SIGNATURE)?;
+    let ntlm = &blob[start..];
+    if read_u32_le(ntlm, 8)? != 3 {
+        return None;
+    }
+    let field = |pos: usize| -> Option<String> {
+        let (len, offset) = (read_u16_le(ntlm, pos)? as usize, read_u32_le(ntlm, pos + 4)? as usize);
+        Some(utf16le_to_string(ntlm.get(offset..offset + len)?))
+    };
+    let (domain, user) = (field(28)?, field(36)?);
+    Some(if domain.is_empty() { user } else { format!("{}\\{}", domain, user) })
+}
+
+fn read_u16_le(data: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(data.get(pos..pos + 2)?.try_into().ok()?))
+}
+
+fn read_u32_le(data: &[u8], pos: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(pos..pos + 4)?.try_into().ok()?))
+}
+
+fn utf16le_to_string(data: &[u8]) -> String {
+    let units: Vec<u16> = data.chunks_exact(2).map(|pair| u16::from_le_bytes([pair[0], pair[1]])).collect();
+    String::from_utf16_lossy(&units)
+}
+
+fn flow_index(dir: &PacketDir) -> usize {
+    match dir { PacketDir::SrcLowAddr => { 0 }, PacketDir::SrcHighAddr => { 1 } }
+}
+
+fn index_dir(index: usize) -> PacketDir {
+    if index == 0 { PacketDir::SrcLowAddr } else { PacketDir::SrcHighAddr }
+}
+
+/// Big-endian reader over a byte slice, where every read fails past the end
+struct ByteReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let result = self.data.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(result)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.take(4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        self.take(3).map(|b| (b[0] as usize) << 16 | (b[1] as usize) << 8 | b[2] as usize)
+    }
+
+    /// Bytes up to a zero byte, that is consumed but not returned
+    fn take_until_zero(&mut self) -> Option<&'a [u8]> {
+        let len = self.data.get(self.pos..)?.iter().position(|b| *b == 0)?;
+        let result = self.take(len)?;
+        self.pos += 1;
+        Some(result)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+}
+
+/// Look for a complete ClientHello or ServerHello at the start of a flow, that may span several TLS records
+fn parse_tls_hello(stream: &[u8]) -> TlsHello {
+    let mut records = ByteReader::new(stream);
+    let mut handshake = Vec::new();
+    loop {
+        let (content_type, major_version, len) = match (records.u8(), records.u8(), records.u8(), records.u16()) {
+            (Some(content_type), Some(major_version), Some(_), Some(len)) => { (content_type, major_version, len) }
+            _ => { return TlsHello::NeedMore; }
+        };
+        // Handshake records of TLS 1.0 and above (including 1.3, that keeps the 1.2 record version)
+        if content_type != 22 || major_version != 3 {
+            return TlsHello::NotTls;
+        }
+        match records.take(len as usize) {
+            None => { return TlsHello::NeedMore; }
+            Some(fragment) => { handshake.extend_from_slice(fragment) }
+        }
+        let mut message = ByteReader::new(&handshake);
+        if let (Some(message_type), Some(message_len)) = (message.u8(), message.u24()) {
+            if let Some(body) = message.take(message_len) {
+                return match message_type {
+                    1 => { parse_client_hello(body).unwrap_or(TlsHello::NotTls) }
+                    2 => { parse_server_hello(body).unwrap_or(TlsHello::NotTls) }
+                    _ => { TlsHello::NotTls }
+                };
+            }
+        }
+    }
+}
+
+fn parse_client_hello(body: &[u8]) -> Option<TlsHello> {
+    let mut reader = ByteReader::new(body);
+    reader.take(2)?;
+    let client_random = reader.take(CLIENT_RANDOM_LEN)?.try_into().ok()?;
+    let session_id_len = reader.u8()? as usize;
+    reader.take(session_id_len)?;
+    let cipher_suites_len = reader.u16()? as usize;
+    reader.take(cipher_suites_len)?;
+    let compression_len = reader.u8()? as usize;
+    reader.take(compression_len)?;
+    let mut server_name = None;
+    let mut alpn = Vec::new();
+    if reader.is_empty() {
+        return Some(TlsHello::Client { server_name, alpn, client_random });
+    }
+    let extensions_len = reader.u16()? as usize;
+    let mut extensions = ByteReader::new(reader.take(extensions_len)?);
+    while !extensions.is_empty() {
+        let extension_type = extensions.u16()?;
+        let extension_len = extensions.u16()? as usize;
+        let mut extension = ByteReader::new(extensions.take(extension_len)?);
+        match extension_type {
+            0 => {
+                let names_len = extension.u16()? as usize;
+                let mut names = ByteReader::new(extension.take(names_len)?);
+                while !names.is_empty() {
+                    let name_type = names.u8()?;
+                    let name_len = names.u16()? as usize;
+                    let name = names.take(name_len)?;
+                    if name_type == 0 && server_name.is_none() && name_len <= MAX_SERVER_NAME_LEN {
+                        server_name = Some(String::from_utf8_lossy(name).into_owned());
+                    }
+                }
+            }
+            16 => { alpn = parse_alpn_list(&mut extension)? }
+            _ => {}
+        }
+    }
+    Some(TlsHello::Client { server_name, alpn, client_random })
+}
+
+fn parse_server_hello(body: &[u8]) -> Option<TlsHello> {
+    let mut reader = ByteReader::new(body);
+    reader.take(2)?;
+    let server_random = reader.take(CLIENT_RANDOM_LEN)?.try_into().ok()?;
+    let session_id_len = reader.u8()? as usize;
+    reader.take(session_id_len)?;
+    let cipher_suite = reader.u16()?;
+    // Compression method
+    reader.take(1)?;
+    if reader.is_empty() {
+        return Some(TlsHello::Server { alpn: None, server_random, cipher_suite });
+    }
+    let extensions_len = reader.u16()? as usize;
+    let mut extensions = ByteReader::new(reader.take(extensions_len)?);
+    while !extensions.is_empty() {
+        let extension_type = extensions.u16()?;
+        let extension_len = extensions.u16()? as usize;
+        let mut extension = ByteReader::new(extensions.take(extension_len)?);
+        if extension_type == 16 {
+            return Some(TlsHello::Server { alpn: parse_alpn_list(&mut extension)?.into_iter().next(), server_random, cipher_suite });
+        }
+    }
+    Some(TlsHello::Server { alpn: None, server_random, cipher_suite })
+}
+
+/// Protocol names of an ALPN extension
+fn parse_alpn_list(extension: &mut ByteReader) -> Option<Vec<String>> {
+    let list_len = extension.u16()? as usize;
+    let mut list = ByteReader::new(extension.take(list_len)?);
+    let mut result = Vec::new();
+    while !list.is_empty() {
+        let name_len = list.u8()? as usize;
+        result.push(String::from_utf8_lossy(list.take(name_len)?).into_owned());
+    }
+    Some(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use crate::conn::PacketDir;
+    use crate::keylog::KeyLog;
+    use crate::tls_decrypt::RecordCipher;
+    use super::{dns_answer_addresses, dns_name, Analyzer, AnalyzerConfig, AppInfo, DnsAnalyzer, DnsQuery, HttpAnalyzer, TlsAnalyzer,
+                DNS_MAX_POINTERS};
+
+    /// Feed the client bytes, and then the server bytes, to an HTTP analyzer. Return whether it wants more of both.
+    fn http_exchange(request: &[u8], response: &[u8], app: &mut AppInfo) -> (bool, bool) {
+        let mut analyzer = HttpAnalyzer::new();
+        let client_more = analyzer.feed(&PacketDir::SrcLowAddr, request, app);
+        let server_more = analyzer.feed(&PacketDir::SrcHighAddr, response, app);
+        (client_more, server_more)
+    }
+
+    fn statuses(app: &AppInfo) -> Vec<(&str, Option<u16>)> {
+        app.http_transactions.iter().map(|transaction| (transaction.path.as_str(), transaction.status)).collect()
+    }
+
+    #[test]
+    fn chunk_size_that_overflows_is_not_http() {
+        let mut app = AppInfo::default();
+        let request = b"POST /a HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nffffffffffffffff\r\n";
+        let (client_more, _) = http_exchange(request, b"", &mut app);
+        assert!(!client_more);
+    }
+
+    #[test]
+    fn chunked_bodies_are_skipped() {
+        let mut app = AppInfo::default();
+        let request = b"POST /a HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+            5;ext=1\r\nGET /\r\n3\r\nabc\r\n0\r\nTrailer: x\r\n\r\nGET /b HTTP/1.1\r\n\r\n";
+        let response = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+            a\r\nHTTP/1.1 5\r\n0\r\n\r\nHTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n";
+        assert_eq!(http_exchange(request, response, &mut app), (true, true));
+        assert_eq!(statuses(&app), vec![("/a", Some(200)), ("/b", Some(404))]);
+    }
+
+    #[test]
+    fn content_length_bodies_are_skipped() {
+        let mut app = AppInfo::default();
+        let request = b"PUT /a HTTP/1.1\r\nHost: example.com\r\nContent-Length: 16\r\n\r\nGET /x HTTP/1.1\nGET /b HTTP/1.1\r\n\r\n";
+        let response = b"HTTP/1.1 201 Created\r\nContent-Length: 4\r\n\r\nbodyHTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n";
+        assert_eq!(http_exchange(request, response, &mut app), (true, true));
+        assert_eq!(statuses(&app), vec![("/a", Some(201)), ("/b", Some(200))]);
+        let first = &app.http_transactions[0];
+        assert_eq!((first.host.as_deref(), first.request_length, first.response_length), (Some("example.com"), Some(16), Some(4)));
+    }
+
+    #[test]
+    fn responses_without_a_body_are_followed_by_the_next() {
+        let mut app = AppInfo::default();
+        let request = b"HEAD /a HTTP/1.1\r\n\r\nDELETE /b HTTP/1.1\r\n\r\nGET /c HTTP/1.1\r\n\r\nGET /d HTTP/1.1\r\n\r\n";
+        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\n\
+            HTTP/1.1 204 No Content\r\n\r\n\
+            HTTP/1.1 304 Not Modified\r\nContent-Length: 100\r\n\r\n\
+            HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n";
+        assert_eq!(http_exchange(request, response, &mut app), (true, true));
+        assert_eq!(statuses(&app), vec![("/a", Some(200)), ("/b", Some(204)), ("/c", Some(304)), ("/d", Some(200))]);
+    }
+
+    #[test]
+    fn upgrade_stops_the_analyzer() {
+        let mut app = AppInfo::default();
+        let request = b"GET /chat HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n";
+        let response = b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\r\n\x81\x05hello";
+        assert_eq!(http_exchange(request, response, &mut app), (true, false));
+        assert_eq!(statuses(&app), vec![("/chat", Some(101))]);
+    }
+
+    /// A DNS message over TCP, with its length prefix and a single question of the given encoded name and type
+    fn dns_message(id: u16, flags: u16, answers: u16, name: &[u8], qtype: u16) -> Vec<u8> {
+        let mut message = Vec::new();
+        for field in [id, flags, 1, answers, 0, 0] {
+            message.extend_from_slice(&field.to_be_bytes());
+        }
+        message.extend_from_slice(name);
+        message.extend_from_slice(&qtype.to_be_bytes());
+        message.extend_from_slice(&1u16.to_be_bytes());
+        let mut framed = (message.len() as u16).to_be_bytes().to_vec();
+        framed.extend(message);
+        framed
+    }
+
+    fn query(id: u16, name: &str, qtype: u16, rcode: Option<u8>, answers: Option<u16>) -> DnsQuery {
+        DnsQuery { id, name: name.to_string(), qtype, rcode, answers }
+    }
+
+    #[test]
+    fn addresses_of_dns_answers() {
+        // A CNAME to cdn.example.net, then its A and AAAA records, all owned by compressed names
+        let mut message = dns_message(7, 0x8180, 3, b"\x03www\x07example\x03com\x00", 1)[2..].to_vec();
+        let answer = |message: &mut Vec<u8>, owner: u16, record_type: u16, data: &[u8]| {
+            for field in [owner, record_type, 1, 0, 300, data.len() as u16] {
+                message.extend_from_slice(&field.to_be_bytes());
+            }
+            message.extend_from_slice(data);
+        };
+        let alias_offset = message.len() as u16 + 12;
+        answer(&mut message, 0xc00c, 5, b"\x03cdn\x07example\x03net\x00");
+        answer(&mut message, 0xc000 | alias_offset, 1, &[192, 0, 2, 7]);
+        answer(&mut message, 0xc000 | alias_offset, 28, &[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 7]);
+        let (name, addresses) = dns_answer_addresses(&message).unwrap();
+        assert_eq!(name, "www.example.com");
+        assert_eq!(addresses, vec!["192.0.2.7".parse::<std::net::IpAddr>().unwrap(), "2001:db8::7".parse().unwrap()]);
+        // Queries, errors and cut answers have no addresses
+        assert_eq!(dns_answer_addresses(&dns_message(7, 0x0100, 0, b"\x00", 1)[2..]), None);
+        assert_eq!(dns_answer_addresses(&dns_message(7, 0x8183, 0, b"\x00", 1)[2..]), None);
+        assert_eq!(dns_answer_addresses(&message[..message.len() - 1]), None);
+    }
+
+    #[test]
+    fn dns_messages_are_framed_across_segments() {
+        let mut app = AppInfo::default();
+        let mut analyzer = DnsAnalyzer::new();
+        let first = dns_message(1, 0x0100, 0, b"\x07example\x03com\x00", 1);
+        let second = dns_message(2, 0x0100, 0, b"\x03www\x07example\x03com\x00", 28);
+        // The length prefix, the header and the question each split between segments
+        for (start, end) in [(0, 1), (1, 9), (9, 20)] {
+            assert!(analyzer.feed(&PacketDir::SrcLowAddr, &first[start..end], &mut app));
+            assert!(app.dns_queries.is_empty());
+        }
+        assert!(analyzer.feed(&PacketDir::SrcLowAddr, &first[20..], &mut app));
+        assert_eq!(app.dns_queries.len(), 1);
+        // The end of one message and the beginning of the next in a single segment
+        let mut both = second.clone();
+        both.extend(dns_message(3, 0x0100, 0, b"\x00", 2));
+        assert!(analyzer.feed(&PacketDir::SrcLowAddr, &both[..both.len() - 5], &mut app));
+        assert!(analyzer.feed(&PacketDir::SrcLowAddr, &both[both.len() - 5..], &mut app));
+        assert_eq!(app.dns_queries, vec![
+            query(1, "example.com", 1, None, None),
+            query(2, "www.example.com", 28, None, None),
+            query(3, ".", 2, None, None),
+        ]);
+        assert_eq!(app.content_label, Some("dns"));
+    }
+
+    #[test]
+    fn dns_flow_that_is_not_dns_is_dropped() {
+        let mut app = AppInfo::default();
+        let mut analyzer = DnsAnalyzer::new();
+        assert!(!analyzer.feed(&PacketDir::SrcLowAddr, b"GET / HTTP/1.1\r\n\r\n", &mut app));
+        assert!(app.dns_queries.is_empty());
+    }
+
+    #[test]
+    fn dns_responses_match_their_queries() {
+        let mut app = AppInfo::default();
+        let mut analyzer = DnsAnalyzer::new();
+        let mut queries = dns_message(7, 0x0100, 0, b"\x01a\x00", 1);
+        queries.extend(dns_message(8, 0x0100, 0, b"\x01b\x00", 1));
+        assert!(analyzer.feed(&PacketDir::SrcLowAddr, &queries, &mut app));
+        // Out of order, and one whose query was not seen
+        let mut responses = dns_message(8, 0x8183, 0, b"\x01b\x00", 1);
+        responses.extend(dns_message(9, 0x8180, 1, b"\x01c\x00", 28));
+        responses.extend(dns_message(7, 0x8180, 2, b"\x01a\x00", 1));
+        assert!(analyzer.feed(&PacketDir::SrcHighAddr, &responses, &mut app));
+        assert_eq!(app.dns_queries, vec![
+            query(7, "a", 1, Some(0), Some(2)),
+            query(8, "b", 1, Some(3), Some(0)),
+            query(9, "c", 28, Some(0), Some(1)),
+        ]);
+        assert_eq!(app.dns_queries[1].to_string(), "A b NXDOMAIN");
+    }
+
+    #[test]
+    fn dns_compressed_names() {
+        // The question ends with a pointer to a name after it, as a name may point anywhere in the message
+        let mut app = AppInfo::default();
+        let mut message = dns_message(1, 0x8180, 1, b"\x03www\xc0\x16", 1);
+        message.extend_from_slice(b"\x07example\x03com\x00");
+        let len = message.len() as u16 - 2;
+        message[..2].copy_from_slice(&len.to_be_bytes());
+        assert!(DnsAnalyzer::new().feed(&PacketDir::SrcHighAddr, &message, &mut app));
+        assert_eq!(app.dns_queries, vec![query(1, "www.example.com", 1, Some(0), Some(1))]);
+        // The position after a compressed name is right after its first pointer
+        let message = b"\x01a\x00\x01b\xc0\x00\xff";
+        assert_eq!(dns_name(message, 3), Some(("b.a".to_string(), 7)));
+        assert_eq!(dns_name(message, 5), Some(("a".to_string(), 7)));
+        // Labels of the reserved kinds, and names beyond the message
+        assert_eq!(dns_name(b"\x40a\x00", 0), None);
+        assert_eq!(dns_name(b"\x03ab", 0), None);
+        assert_eq!(dns_name(b"\xc0", 0), None);
+    }
+
+    #[test]
+    fn dns_pointer_loops_are_cut() {
+        assert_eq!(dns_name(b"\xc0\x00", 0), None);
+        assert_eq!(dns_name(b"\x01a\xc0\x00", 0), None);
+        // A chain of pointers, each to the one before, that ends at the root name at the start
+        let mut chain = vec![0u8];
+        for pointer in 0..=DNS_MAX_POINTERS {
+            let target = if pointer == 0 { 0 } else { 1 + 2 * (pointer - 1) };
+            chain.extend_from_slice(&[0xc0, target as u8]);
+        }
+        let last = chain.len() - 2;
+        assert_eq!(dns_name(&chain, last - 2), Some((".".to_string(), last)));
+        assert_eq!(dns_name(&chain, last), None);
+    }
+
+    /// Append a 2 byte length and then the bytes
+    fn put_u16_prefixed(out: &mut Vec<u8>, bytes: &[u8]) {
+        out.extend_from_slice(&(bytes.len() as u16).to_be_bytes());
+        out.extend_from_slice(bytes);
+    }
+
+    /// A handshake message of the given type, with its 3 byte length
+    fn handshake_message(message_type: u8, body: &[u8]) -> Vec<u8> {
+        let mut message = vec![message_type];
+        message.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        message.extend_from_slice(body);
+        message
+    }
+
+    /// A ClientHello with the server name and the ALPN extensions, and a random of the bytes 0 to 31
+    fn client_hello(server_name: &str, alpn: &[&str]) -> Vec<u8> {
+        let mut body = vec![3, 3];
+        body.extend(0..32u8);
+        body.push(0);
+        put_u16_prefixed(&mut body, &[0x13, 0x01, 0xc0, 0x2f]);
+        body.extend_from_slice(&[1, 0]);
+        let mut names = vec![0];
+        put_u16_prefixed(&mut names, server_name.as_bytes());
+        let mut sni = Vec::new();
+        put_u16_prefixed(&mut sni, &names);
+        let mut protocols = Vec::new();
+        for protocol in alpn {
+            protocols.push(protocol.len() as u8);
+            protocols.extend_from_slice(protocol.as_bytes());
+        }
+        let mut alpn_list = Vec::new();
+        put_u16_prefixed(&mut alpn_list, &protocols);
+        let mut extensions = Vec::new();
+        for (extension_type, extension) in [(0u16, sni), (16, alpn_list)] {
+            extensions.extend_from_slice(&extension_type.to_be_bytes());
+            put_u16_prefixed(&mut extensions, &extension);
+        }
+        put_u16_prefixed(&mut body, &extensions);
+        handshake_message(1, &body)
+    }
+
+    /// Handshake records that carry the message, in fragments of up to the given length
+    fn tls_records(message: &[u8], fragment_len: usize) -> Vec<u8> {
+        let mut records = Vec::new();
+        for fragment in message.chunks(fragment_len) {
+            records.extend_from_slice(&[22, 3, 1]);
+            put_u16_prefixed(&mut records, fragment);
+        }
+        records
+    }
+
+    #[test]
+    fn client_hello_across_records_and_segments() {
+        let mut app = AppInfo::default();
+        let mut analyzer = TlsAnalyzer::new(Arc::new(AnalyzerConfig::default()));
+        let records = tls_records(&client_hello("example.com", &["h2", "http/1.1"]), 20);
+        let (first, second) = records.split_at(50);
+        assert!(analyzer.feed(&PacketDir::SrcLowAddr, first, &mut app));
+        assert_eq!(app.server_name, None);
+        assert!(analyzer.feed(&PacketDir::SrcLowAddr, second, &mut app));
+        assert_eq!(app.server_name.as_deref(), Some("example.com"));
+        assert_eq!(app.alpn_offered, vec!["h2", "http/1.1"]);
+        assert_eq!(app.client_random.map(|random| random[31]), Some(31));
+        assert_eq!(app.content_label, Some("tls"));
+        // The ServerHello, with the selected protocol, ends the analysis
+        let mut body = vec![3, 3];
+        body.extend([0; 32]);
+        body.extend_from_slice(&[0, 0x13, 0x01, 0]);
+        let mut alpn = Vec::new();
+        put_u16_prefixed(&mut alpn, b"\x02h2");
+        let mut extensions = vec![0, 16];
+        put_u16_prefixed(&mut extensions, &alpn);
+        put_u16_prefixed(&mut body, &extensions);
+        let server_records = tls_records(&handshake_message(2, &body), 1000);
+        assert!(!analyzer.feed(&PacketDir::SrcHighAddr, &server_records, &mut app));
+        assert_eq!(app.alpn_selected.as_deref(), Some("h2"));
+    }
+
+    #[test]
+    fn flows_that_are_not_tls() {
+        let mut app = AppInfo::default();
+        let mut analyzer = TlsAnalyzer::new(Arc::new(AnalyzerConfig::default()));
+        assert!(analyzer.feed(&PacketDir::SrcLowAddr, b"GET / HTTP/1.1\r\n\r\n", &mut app));
+        assert!(!analyzer.feed(&PacketDir::SrcHighAddr, b"\x15\x03\x03\x00\x02\x02\x28", &mut app));
+        assert_eq!((app.server_name, app.content_label), (None, None));
+        // A handshake message other than a hello
+        let mut app = AppInfo::default();
+        let mut analyzer = TlsAnalyzer::new(Arc::new(AnalyzerConfig::default()));
+        assert!(analyzer.feed(&PacketDir::SrcLowAddr, &tls_records(&handshake_message(11, &[0; 8]), 100), &mut app));
+        assert!(!analyzer.feed(&PacketDir::SrcHighAddr, b"SSH-2.0-OpenSSH_9.6\r\n", &mut app));
+        assert_eq!(app.content_label, None);
+    }
+
+    #[test]
+    fn client_hello_with_truncated_extensions_is_not_tls() {
+        let hello = client_hello("example.com", &["h2"]);
+        // Cut the last extension short, while the lengths of the message and the records still match
+        let mut truncated = hello[..hello.len() - 3].to_vec();
+        let message_len = (truncated.len() - 4) as u32;
+        truncated[1..4].copy_from_slice(&message_len.to_be_bytes()[1..]);
+        let mut app = AppInfo::default();
+        let mut analyzer = TlsAnalyzer::new(Arc::new(AnalyzerConfig::default()));
+        assert!(analyzer.feed(&PacketDir::SrcLowAddr, &tls_records(&truncated, 1000), &mut app));
+        assert!(!analyzer.feed(&PacketDir::SrcHighAddr, b"HTTP/1.1 200 OK\r\n", &mut app));
+        assert_eq!((app.server_name, app.content_label), (None, None));
+        // A hello that is not complete yet waits for more
+        let mut app = AppInfo::default();
+        let mut analyzer = TlsAnalyzer::new(Arc::new(AnalyzerConfig::default()));
+        assert!(analyzer.feed(&PacketDir::SrcLowAddr, &tls_records(&hello, 1000)[..40], &mut app));
+        assert!(analyzer.feed(&PacketDir::SrcHighAddr, b"\x16\x03\x03", &mut app));
+    }
+
+    /// A ServerHello with a random of the bytes 32 to 63 and the given cipher suite
+    fn server_hello(cipher_suite: u16) -> Vec<u8> {
+        let mut body = vec![3, 3];
+        body.extend(32..64u8);
+        body.push(0);
+        body.extend_from_slice(&cipher_suite.to_be_bytes());
+        body.push(0);
+        tls_records(&handshake_message(2, &body), 1000)
+    }
+
+    /// A TLS analyzer with the master secret of the bytes 0 to 47 in the key log, for the random of [client_hello]
+    fn decrypting_analyzer() -> TlsAnalyzer {
+        let hex = |bytes: std::ops::Range<u8>| bytes.map(|b| format!("{:02x}", b)).collect::<String>();
+        let key_log = KeyLog::parse(&format!("# comment\nCLIENT_RANDOM {} {}\n", hex(0..32), hex(0..48))).unwrap();
+        TlsAnalyzer::new(Arc::new(AnalyzerConfig { key_log: Some(Arc::new(key_log)), ..AnalyzerConfig::default() }))
+    }
+
+    /// The ChangeCipherSpec, and encrypted records of the given types and plaintexts
+    fn encrypted_records(cipher: &mut RecordCipher, messages: &[(u8, &[u8])]) -> Vec<u8> {
+        let mut records = vec![20, 3, 3, 0, 1, 1];
+        for (index, (content_type, plaintext)) in messages.iter().enumerate() {
+            records.extend_from_slice(&[*content_type, 3, 3]);
+            put_u16_prefixed(&mut records, &cipher.seal(*content_type, 0x0303, index as u64, plaintext));
+        }
+        records
+    }
+
+    #[test]
+    fn tls12_session_is_decrypted_with_the_key_log() {
+        let master_secret: Vec<u8> = (0..48).collect();
+        let (mut client, mut server) = RecordCipher::from_master_secret(&master_secret, &core::array::from_fn(|i| i as u8),
+                                                                        &core::array::from_fn(|i| 32 + i as u8));
+        let finished = handshake_message(20, &[0; 12]);
+        let request = encrypted_records(&mut client, &[(22, &finished), (23, b"GET /secret HTTP/1.1\r\nHost: example.com\r\n\r\n")]);
+        let response = encrypted_records(&mut server, &[(22, &finished), (23, b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")]);
+        let mut app = AppInfo::default();
+        let mut analyzer = decrypting_analyzer();
+        assert!(analyzer.feed(&PacketDir::SrcLowAddr, &tls_records(&client_hello("example.com", &[]), 1000), &mut app));
+        assert!(analyzer.feed(&PacketDir::SrcHighAddr, &server_hello(0xc02f), &mut app));
+        // Records split across segments are decrypted once complete
+        let (first, second) = request.split_at(request.len() - 10);
+        assert!(analyzer.feed(&PacketDir::SrcLowAddr, first, &mut app));
+        assert!(app.http_transactions.is_empty());
+        assert!(analyzer.feed(&PacketDir::SrcLowAddr, second, &mut app));
+        assert_eq!(app.http_transactions[0].path, "/secret");
+        assert!(analyzer.feed(&PacketDir::SrcHighAddr, &response, &mut app));
+        assert_eq!(app.http_transactions[0].status, Some(200));
+        assert_eq!(app.metadata, vec![("tls_decryption", String::from("TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256"))]);
+        // A tampered record ends the decryption
+        let mut tampered = encrypted_records(&mut client, &[(23, b"GET / HTTP/1.1\r\n\r\n")])[6..].to_vec();
+        tampered[20] ^= 1;
+        assert!(!analyzer.feed(&PacketDir::SrcLowAddr, &tampered, &mut app));
+        assert_eq!(app.metadata[1], ("tls_decryption", String::from("bad record MAC")));
+    }
+
+    #[test]
+    fn tls_session_that_cannot_be_decrypted() {
+        // TLS 1.3 suites are not supported
+        let mut app = AppInfo::default();
+        let mut analyzer = decrypting_analyzer();
+        assert!(analyzer.feed(&PacketDir::SrcLowAddr, &tls_records(&client_hello("example.com", &[]), 1000), &mut app));
+        assert!(!analyzer.feed(&PacketDir::SrcHighAddr, &server_hello(0x1301), &mut app));
+        assert_eq!(app.metadata, vec![("tls_decryption", String::from("unsupported cipher suite 0x1301"))]);
+        // A session that is not in the key log is only identified
+        let mut app = AppInfo::default();
+        let mut analyzer = decrypting_analyzer();
+        let mut hello = client_hello("example.com", &[]);
+        hello[10] ^= 1;
+        assert!(analyzer.feed(&PacketDir::SrcLowAddr, &tls_records(&hello, 1000), &mut app));
+        assert!(!analyzer.feed(&PacketDir::SrcHighAddr, &server_hello(0xc02f), &mut app));
+        assert_eq!((app.server_name.as_deref(), app.metadata.len()), (Some("example.com"), 0));
+    }
+}
@@ -0,0 +1,113 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
+use etherparse::{LinkSlice, SlicedPacket, VlanSlice};
+
+/// Ether type of ARP
+pub(crate) const ETHER_TYPE_ARP: u16 = 0x0806;
+/// Length of an ARP packet for IPv4 over Ethernet
+const ARP_IPV4_LEN: usize = 28;
+/// Time after an ARP event in which a reset or a retransmission of a connection of the same IP is attributed to it
+pub const ARP_CORRELATION_WINDOW: Duration = Duration::from_secs(5);
+/// Max number of IPs whose MAC is remembered, so a scan of a large subnet does not grow the table without limit
+const MAX_ARP_ENTRIES: usize = 65536;
+/// Max number of recent events that connections are correlated with
+const MAX_RECENT_ARP_EVENTS: usize = 256;
+
+/// What an ARP packet revealed
+#[derive(Clone, Debug, PartialEq)]
+pub enum ArpEventKind {
+    /// The IP was announced by a different MAC than before, as in a failover or a spoofing
+    MacChanged { old_mac: [u8; 6] },
+    /// The host announced its own IP (the sender and target IPs are the same), unsolicited
+    Gratuitous,
+}
+
+/// An ARP packet that changed the IP to MAC mapping, or announced it, at a capture time
+#[derive(Clone, Debug, PartialEq)]
+pub struct ArpEvent {
+    pub ts: Duration,
+    pub ip: Ipv4Addr,
+    pub mac: [u8; 6],
+    pub kind: ArpEventKind,
+}
+
+impl fmt::Display for ArpEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            ArpEventKind::MacChanged { old_mac } => {
+                write!(f, "{} moved from MAC {} to {}", self.ip, MacAddr(old_mac), MacAddr(&self.mac))
+            }
+            ArpEventKind::Gratuitous => { write!(f, "gratuitous ARP for {} at MAC {}", self.ip, MacAddr(&self.mac)) }
+        }
+    }
+}
+
+struct MacAddr<'a>(&'a [u8; 6]);
+
+impl fmt::Display for MacAddr<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}", a, b, c, d, e, g)
+    }
+}
+
+/// The ARP payload of a parsed frame, if it is an ARP packet (possibly VLAN tagged)
+pub fn arp_payload<'a>(packet: &SlicedPacket<'a>) -> Option<&'a [u8]> {
+    let ether_type = match (&packet.vlan, &packet.link) {
+        (Some(VlanSlice::SingleVlan(single)), _) => { single.ether_type() }
+        (Some(VlanSlice::DoubleVlan(double)), _) => { double.inner().ether_type() }
+        (None, Some(LinkSlice::Ethernet2(ethernet))) => { ethernet.ether_type() }
+        (None, None) => { return None; }
+    };
+    if ether_type == ETHER_TYPE_ARP && packet.ip.is_none() { Some(packet.payload) } else { None }
+}
+
+/// IP to MAC mappings learned from ARP, and the recent changes, to correlate connection trouble with them
+#[derive(Clone, Default)]
+pub struct ArpTable {
+    entries: HashMap<Ipv4Addr, [u8; 6]>,
+    recent: VecDeque<ArpEvent>,
+}
+
+impl ArpTable {
+    /// Learn the sender of an ARP request or reply, and return what changed, if anything
+    pub fn process(&mut self, ts: Duration, payload: &[u8]) -> Option<ArpEvent> {
+        // Only IPv4 over Ethernet: hardware type 1, protocol type 0x0800, address lengths 6 and 4
+        if payload.len() < ARP_IPV4_LEN || payload[..6] != [0, 1, 8, 0, 6, 4] {
+            return None;
+        }
+        let mut mac = [0u8; 6];
+        mac.copy_from_slice(&payload[8..14]);
+        let ip = Ipv4Addr::new(payload[14], payload[15], payload[16], payload[17]);
+        let target_ip = Ipv4Addr::new(payload[24], payload[25], payload[26], payload[27]);
+        // A probe (RFC 5227) has no sender IP yet
+        if ip.is_unspecified() {
+            return None;
+        }
+        let previous = if self.entries.len() < MAX_ARP_ENTRIES || self.entries.contains_key(&ip) {
+            self.entries.insert(ip, mac)
+        } else {
+            self.entries.get(&ip).copied()
+        };
+        let kind = match previous {
+            Some(old_mac) if old_mac != mac => { ArpEventKind::MacChanged { old_mac } }
+            _ if ip == target_ip => { ArpEventKind::Gratuitous }
+            _ => { return None; }
+        };
+        let event = ArpEvent { ts, ip, mac, kind };
+        if self.recent.len() >= MAX_RECENT_ARP_EVENTS {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(event.clone());
+        Some(event)
+    }
+
+    /// The latest event of one of the given IPs within the correlation window before the given capture time, if any
+    pub fn recent_event(&self, ips: &[IpAddr], ts: Duration) -> Option<&ArpEvent> {
+        self.recent.iter().rev()
+            .take_while(|event| event.ts + ARP_CORRELATION_WINDOW >= ts)
+            .find(|event| event.ts <= ts && ips.contains(&IpAddr::V4(event.ip)))
+    }
+}
@@ -0,0 +1,157 @@
+use std::fmt;
+use pcap::{BpfProgram, Capture, Linktype};
+
+// Fields of a BPF instruction code, as in linux/filter.h
+const BPF_LD: u16 = 0x00;
+const BPF_LDX: u16 = 0x01;
+const BPF_ST: u16 = 0x02;
+const BPF_STX: u16 = 0x03;
+const BPF_ALU: u16 = 0x04;
+const BPF_JMP: u16 = 0x05;
+const BPF_RET: u16 = 0x06;
+const BPF_H: u16 = 0x08;
+const BPF_B: u16 = 0x10;
+const BPF_IMM: u16 = 0x00;
+const BPF_ABS: u16 = 0x20;
+const BPF_IND: u16 = 0x40;
+const BPF_MEM: u16 = 0x60;
+const BPF_LEN: u16 = 0x80;
+const BPF_MSH: u16 = 0xa0;
+const BPF_X: u16 = 0x08;
+const BPF_A: u16 = 0x10;
+const BPF_NEG: u16 = 0x80;
+const BPF_JA: u16 = 0x00;
+const BPF_TXA: u16 = 0x80;
+
+/// Compile a filter for the given data-link type, the same way the capture applies it, to inspect the program
+pub fn compile(datalink: Linktype, filter: &str, optimize: bool) -> Result<BpfProgram, pcap::Error> {
+    Capture::dead(datalink)?.compile(filter, optimize)
+}
+
+/// Extend a filter to also match frames with one or two VLAN tags (802.1Q or QinQ), as captured on trunk ports.
+/// Every "vlan" primitive moves the offsets of the rest of the filter past a tag, so the tagged alternatives are nested.
+pub fn with_vlan_tags(filter: &str) -> String {
+    format!("({0}) or (vlan and (({0}) or (vlan and ({0}))))", filter)
+}
+
+/// One instruction of a compiled filter, decoded from the "code jt jf k" text that pcap exposes
+struct Instruction {
+    code: u16,
+    jt: u8,
+    jf: u8,
+    k: u32,
+}
+
+impl Instruction {
+    fn parse(text: &str) -> Option<Self> {
+        let mut fields = text.split_whitespace();
+        Some(Instruction {
+            code: fields.next()?.parse().ok()?,
+            jt: fields.next()?.parse().ok()?,
+            jf: fields.next()?.parse().ok()?,
+            k: fields.next()?.parse().ok()?,
+        })
+    }
+
+    /// Mnemonic and operand, such as ("ldh", "[12]"), and the jump targets of conditional jumps
+    fn image(&self, pc: usize) -> (String, String, Option<(usize, usize)>) {
+        let k = self.k;
+        let size_suffix = match self.code & 0x18 { BPF_H => { "h" } BPF_B => { "b" } _ => { "" } };
+        let source = if self.code & BPF_X != 0 { String::from("x") } else { format!("#0x{:x}", k) };
+        let (mnemonic, operand) = match self.code & 0x07 {
+            BPF_LD => {
+                match self.code & 0xe0 {
+                    BPF_IMM => { (String::from("ld"), format!("#0x{:x}", k)) }
+                    BPF_ABS => { (format!("ld{}", size_suffix), format!("[{}]", k)) }
+                    BPF_IND => { (format!("ld{}", size_suffix), format!("[x + {}]", k)) }
+                    BPF_MEM => { (String::from("ld"), format!("M[{}]", k)) }
+                    BPF_LEN => { (String::from("ld"), String::from("#pktlen")) }
+                    _ => { (String::from("unimp"), format!("0x{:x}", self.code)) }
+                }
+            }
+            BPF_LDX => {
+                match self.code & 0xe0 {
+                    BPF_IMM => { (String::from("ldx"), format!("#0x{:x}", k)) }
+                    BPF_MEM => { (String::from("ldx"), format!("M[{}]", k)) }
+                    BPF_LEN => { (String::from("ldx"), String::from("#pktlen")) }
+                    BPF_MSH => { (String::from("ldxb"), format!("4*([{}]&0xf)", k)) }
+                    _ => { (String::from("unimp"), format!("0x{:x}", self.code)) }
+                }
+            }
+            BPF_ST => { (String::from("st"), format!("M[{}]", k)) }
+            BPF_STX => { (String::from("stx"), format!("M[{}]", k)) }
+            BPF_ALU => {
+                let mnemonic = ["add", "sub", "mul", "div", "or", "and", "lsh", "rsh", "neg", "mod", "xor"]
+                    .get(((self.code & 0xf0) >> 4) as usize).copied().unwrap_or("unimp");
+                let operand = if self.code & 0xf0 == BPF_NEG { String::new() } else { source };
+                (String::from(mnemonic), operand)
+            }
+            BPF_JMP => {
+                if self.code & 0xf0 == BPF_JA {
+                    return (String::from("ja"), format!("{}", pc + 1 + k as usize), None);
+                }
+                let mnemonic = ["ja", "jeq", "jgt", "jge", "jset"].get(((self.code & 0xf0) >> 4) as usize).copied().unwrap_or("unimp");
+                return (String::from(mnemonic), source, Some((pc + 1 + self.jt as usize, pc + 1 + self.jf as usize)));
+            }
+            BPF_RET => {
+                match self.code & 0x18 {
+                    BPF_A => { (String::from("ret"), String::from("a")) }
+                    BPF_X => { (String::from("ret"), String::from("x")) }
+                    _ => { (String::from("ret"), format!("#{}", k)) }
+                }
+            }
+            _ => {
+                if self.code & 0xf8 == BPF_TXA { (String::from("txa"), String::new()) } else { (String::from("tax"), String::new()) }
+            }
+        };
+        (mnemonic, operand, None)
+    }
+}
+
+/// The compiled filter as readable lines, in the format of "tcpdump -d"
+pub fn disassemble(program: &BpfProgram) -> Vec<String> {
+    program.get_instructions().iter().enumerate().map(|(pc, instruction)| {
+        let text = instruction.to_string();
+        match Instruction::parse(&text) {
+            None => { format!("({:03}) {}", pc, text) }
+            Some(instruction) => {
+                match instruction.image(pc) {
+                    (mnemonic, operand, Some((jt, jf))) => {
+                        format!("({:03}) {:<8} {:<16} jt {}\tjf {}", pc, mnemonic, operand, jt, jf)
+                    }
+                    (mnemonic, operand, None) => { format!("({:03}) {:<8} {}", pc, mnemonic, operand).trim_end().to_string() }
+                }
+            }
+        }
+    }).collect()
+}
+
+/// Counts how many of the packets seen before the filter is applied would be accepted by it
+pub struct FilterWarmup {
+    program: BpfProgram,
+    pub accepted: u64,
+    pub rejected: u64,
+}
+
+impl FilterWarmup {
+    pub fn new(program: BpfProgram) -> Self {
+        Self { program, accepted: 0, rejected: 0 }
+    }
+
+    /// Run the filter on a captured frame
+    pub fn check(&mut self, data: &[u8]) {
+        if self.program.filter(data) {
+            self.accepted += 1;
+        } else {
+            self.rejected += 1;
+        }
+    }
+}
+
+impl fmt::Display for FilterWarmup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let total = self.accepted + self.rejected;
+        let rejected_percent = if total == 0 { 0.0 } else { self.rejected as f64 * 100.0 / total as f64 };
+        write!(f, "{} of {} packets accepted, {:.1}% rejected", self.accepted, total, rejected_percent)
+    }
+}
@@ -0,0 +1,112 @@
+use std::fmt;
+use std::io::{BufRead, BufReader, Error, ErrorKind, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use log::warn;
+use crate::conn::Conn;
+use crate::features::{feature_values, format_values};
+
+/// Tag of the label that the classifier predicted for a connection
+pub const CLASS_TAG: &str = "class";
+
+/// A model that predicts the label of a connection from its feature vector, such as "video" or "bulk"
+pub trait Classifier: Send {
+    /// The label of a feature vector, in the order of the feature export from "server_port" on, with None for unknown values
+    fn classify(&mut self, features: &[Option<f64>]) -> Result<String, Error>;
+}
+
+/// A model served by a user command, so any runtime (scikit-learn, onnxruntime, a script) can be plugged in.
+/// The command gets a line per connection on its standard input, with the features as comma separated fields (empty
+/// where unknown), and answers with a line of the label on its standard output.
+/// ONNX models that the built-in evaluator does not support (see the "onnx" feature) are served this way.
+pub struct CommandClassifier {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+}
+
+impl CommandClassifier {
+    /// Start the command with the shell, to keep it running for all the connections
+    pub fn spawn(command: &str) -> Result<Self, Error> {
+        let mut child = Command::new("sh").arg("-c").arg(command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let stdin = child.stdin.take().ok_or_else(|| Error::new(ErrorKind::BrokenPipe, "No input to the classifier"))?;
+        let stdout = child.stdout.take().ok_or_else(|| Error::new(ErrorKind::BrokenPipe, "No output from the classifier"))?;
+        Ok(CommandClassifier { child, stdin, stdout: BufReader::new(stdout) })
+    }
+}
+
+impl Classifier for CommandClassifier {
+    fn classify(&mut self, features: &[Option<f64>]) -> Result<String, Error> {
+        writeln!(self.stdin, "{}", format_values(features))?;
+        self.stdin.flush()?;
+        let mut line = String::new();
+        if self.stdout.read_line(&mut line)? == 0 {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "The classifier exited"));
+        }
+        let label = line.trim();
+        if label.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidData, "The classifier answered an empty label"));
+        }
+        Ok(label.to_string())
+    }
+}
+
+/// Labels every connection once with a model, after its first packets or when it ends before them
+pub struct ConnClassifier {
+    model: Box<dyn Classifier>,
+    /// Number of packets of both directions after which a connection is classified
+    after_packets: u32,
+    classified_count: u64,
+    /// The model is not used anymore after its first failure, since a broken command would fail on every connection
+    failed: bool,
+}
+
+impl ConnClassifier {
+    pub fn new(model: Box<dyn Classifier>, after_packets: u32) -> Self {
+        ConnClassifier { model, after_packets, classified_count: 0, failed: false }
+    }
+
+    /// Label the connection if it just reached the number of packets, or if it ended without being labeled
+    pub(crate) fn check(&mut self, conn: &mut Conn, ended: bool) {
+        let packets = conn.flow_src_low.packet_count + conn.flow_src_high.packet_count;
+        if self.failed || conn.app.tags.get(CLASS_TAG).is_some() || !(ended || packets == self.after_packets) {
+            return;
+        }
+        let features = match feature_values(conn) {
+            None => { return; }
+            Some(features) => { features }
+        };
+        match self.model.classify(&features) {
+            Err(error) => {
+                warn!("Failed to classify connection {}, classification is disabled: {}", conn.conn_sequence, error);
+                self.failed = true;
+            }
+            Ok(label) => {
+                if conn.app.tags.set(CLASS_TAG, &label) {
+                    self.classified_count += 1;
+                } else {
+                    warn!("Invalid label of connection {}: {:.64}", conn.conn_sequence, label);
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for ConnClassifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} connections classified after {} packets", self.classified_count, self.after_packets)?;
+        if self.failed {
+            write!(f, ", disabled after a failure")?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for CommandClassifier {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
@@ -0,0 +1,235 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs;
+use std::io::{BufRead, BufReader, Error, ErrorKind, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use log::{debug, info, warn};
+use crate::conn::ConnSummary;
+use crate::json::JsonValue;
+
+/// Time to wait for the collector to accept a connection
+const EXPORT_CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+/// Time between attempts to connect to the collector, during which flow records are dropped
+const EXPORT_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+/// Max number of connections in the merged view, after which the oldest are evicted
+const MAX_MERGED_CONNS: usize = 100000;
+
+/// Send flow records to a collector over TCP, as JSON lines of the form {"probe": NAME, "flow": RECORD}, and the
+/// alarms of the capture watchdog as {"probe": NAME, "health": ALARM}.
+/// Records that cannot be sent, while the collector is down, are dropped and counted.
+pub struct FlowExporter {
+    addr: String,
+    probe: String,
+    stream: Option<TcpStream>,
+    /// When the last connection attempt failed, so the next one waits for the retry interval
+    last_failure: Option<Instant>,
+    /// Records dropped since the connection to the collector was lost
+    dropped: u64,
+}
+
+impl FlowExporter {
+    pub fn new(addr: &str, probe: &str) -> Self {
+        Self { addr: addr.to_string(), probe: probe.to_string(), stream: None, last_failure: None, dropped: 0 }
+    }
+
+    /// Send a flow record, connecting to the collector first if needed
+    pub fn export(&mut self, summary: &ConnSummary) {
+        self.send("flow", summary.to_json());
+    }
+
+    /// Send an alarm of the capture watchdog, connecting to the collector first if needed
+    pub fn export_health(&mut self, alarm: JsonValue) {
+        self.send("health", alarm);
+    }
+
+    fn send(&mut self, key: &str, record: JsonValue) {
+        let line = JsonValue::object(vec![("probe", self.probe.as_str().into()), (key, record)]).to_string() + "\n";
+        if self.stream.is_none() && self.last_failure.is_none_or(|failure| failure.elapsed() >= EXPORT_RETRY_INTERVAL) {
+            match self.connect() {
+                Err(error) => {
+                    warn!("Failed to connect to the collector at {}, flow records are dropped: {}", self.addr, error);
+                    self.last_failure = Some(Instant::now());
+                }
+                Ok(stream) => {
+                    match self.dropped {
+                        0 => { info!("Connected to the collector at {}", self.addr) }
+                        dropped => { info!("Connected to the collector at {}, after dropping {} flow records", self.addr, dropped) }
+                    }
+                    self.stream = Some(stream);
+                    self.last_failure = None;
+                    self.dropped = 0;
+                }
+            }
+        }
+        let result = match &mut self.stream {
+            None => { Err(Error::new(ErrorKind::NotConnected, "not connected")) }
+            Some(stream) => { stream.write_all(line.as_bytes()) }
+        };
+        if let Err(error) = result {
+            if self.stream.take().is_some() {
+                warn!("Lost the connection to the collector at {}: {}", self.addr, error);
+                self.last_failure = Some(Instant::now());
+            }
+            self.dropped += 1;
+        }
+    }
+
+    fn connect(&self) -> Result<TcpStream, Error> {
+        let addr = self.addr.to_socket_addrs()?.next()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("Cannot resolve {}", self.addr)))?;
+        TcpStream::connect_timeout(&addr, EXPORT_CONNECT_TIMEOUT)
+    }
+}
+
+/// The flow records that the probes sent about one connection, by probe name
+#[derive(Default)]
+struct MergedConn {
+    records: BTreeMap<String, JsonValue>,
+}
+
+impl MergedConn {
+    /// Union of the tags of the records. A key that the probes tagged differently keeps the value of the first probe by name.
+    fn tags(&self) -> JsonValue {
+        let mut tags: Vec<(&str, JsonValue)> = Vec::new();
+        for record in self.records.values() {
+            if let Some(JsonValue::Object(fields)) = record.get("tags") {
+                for (key, value) in fields {
+                    if !tags.iter().any(|(known, _)| known == key) {
+                        tags.push((key, value.clone()));
+                    }
+                }
+            }
+        }
+        JsonValue::object(tags)
+    }
+
+    /// Max of a counter over the probes, since every probe may have missed some packets
+    fn max_u64(&self, key: &str) -> u64 {
+        self.records.values().filter_map(|record| record.get(key)?.as_u64()).max().unwrap_or_default()
+    }
+}
+
+/// Connections reported by all the probes, merged by their addresses.
+/// A newer record of the same connection from the same probe, such as when the ports are reused, replaces the older one.
+#[derive(Default)]
+pub struct MergedView {
+    conns: HashMap<(SocketAddr, SocketAddr), MergedConn>,
+    /// Keys in the order they were added, for evicting the oldest
+    order: VecDeque<(SocketAddr, SocketAddr)>,
+    record_count: u64,
+}
+
+impl MergedView {
+    /// Merge a flow record line that was received from a probe
+    fn add_line(&mut self, line: &str) -> Result<(), Error> {
+        let json = JsonValue::parse(line)?;
+        let probe = json.field_str("probe")?.to_string();
+        if let Ok(health) = json.field("health") {
+            warn!("Probe {} capture health alarm: {}", probe, health);
+            return Ok(());
+        }
+        let flow = json.field("flow")?;
+        let parse_addr = |key: &str| -> Result<SocketAddr, Error> {
+            flow.field_str(key)?.parse()
+                .map_err(|_| Error::new(ErrorKind::InvalidData, format!("Invalid address in JSON field '{}'", key)))
+        };
+        let key = (parse_addr("low")?, parse_addr("high")?);
+        if !self.conns.contains_key(&key) {
+            if self.conns.len() >= MAX_MERGED_CONNS {
+                if let Some(oldest) = self.order.pop_front() {
+                    self.conns.remove(&oldest);
+                }
+            }
+            self.order.push_back(key);
+        }
+        self.conns.entry(key).or_default().records.insert(probe, flow.clone());
+        self.record_count += 1;
+        Ok(())
+    }
+
+    /// JSON representation of the merged connections, sorted by address, with the max counters over the probes
+    /// and the records of every probe
+    pub fn to_json(&self) -> JsonValue {
+        let mut keys: Vec<&(SocketAddr, SocketAddr)> = self.conns.keys().collect();
+        keys.sort();
+        let conns: Vec<JsonValue> = keys.into_iter().map(|key| {
+            let conn = &self.conns[key];
+            let app = conn.records.values().find_map(|record| record.get("app")?.as_str());
+            JsonValue::object(vec![
+                ("low", key.0.to_string().into()),
+                ("high", key.1.to_string().into()),
+                ("app", app.into()),
+                ("tags", conn.tags()),
+                ("probes", conn.records.keys().map(|probe| probe.as_str().into()).collect::<Vec<JsonValue>>().into()),
+                ("bytes_src_low", conn.max_u64("bytes_src_low").into()),
+                ("bytes_src_high", conn.max_u64("bytes_src_high").into()),
+                ("records", JsonValue::object(conn.records.iter().map(|(probe, record)| (probe.as_str(), record.clone())).collect())),
+            ])
+        }).collect();
+        JsonValue::object(vec![
+            ("record_count", self.record_count.into()),
+            ("connections", conns.into()),
+        ])
+    }
+}
+
+/// Receive flow records from probes on the given address, and keep a merged view of their connections.
+/// The view is written to the output file, if given, every interval. Runs until the process is stopped.
+pub fn collect(listen: &str, output: Option<PathBuf>, write_interval: Duration) -> Result<(), Error> {
+    let listener = TcpListener::bind(listen)?;
+    info!("Collecting flow records on {}", listen);
+    let view = Arc::new(Mutex::new(MergedView::default()));
+    let view_clone = view.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Err(error) => { warn!("Failed to accept a probe: {}", error) }
+                Ok(stream) => {
+                    let view = view_clone.clone();
+                    thread::spawn(move || receive_records(stream, &view));
+                }
+            }
+        }
+    });
+    loop {
+        thread::sleep(write_interval);
+        let view = view.lock().unwrap();
+        info!("Merged view: {} connections from {} flow records", view.conns.len(), view.record_count);
+        if let Some(path) = &output {
+            if let Err(error) = write_view(path, &view) {
+                warn!("Failed to write the merged view to {}: {}", path.display(), error);
+            }
+        }
+    }
+}
+
+/// Merge the flow records of one probe connection, until it is closed
+fn receive_records(stream: TcpStream, view: &Mutex<MergedView>) {
+    let peer = stream.peer_addr().map(|addr| addr.to_string()).unwrap_or_else(|_| String::from("unknown"));
+    info!("Probe connected from {}", peer);
+    for line in BufReader::new(stream).lines() {
+        let result = line.and_then(|line| view.lock().unwrap().add_line(&line));
+        if let Err(error) = result {
+            match error.kind() {
+                ErrorKind::InvalidData => { debug!("Invalid flow record from {}: {}", peer, error) }
+                _ => { warn!("Probe connection from {} failed: {}", peer, error); break; }
+            }
+        }
+    }
+    info!("Probe disconnected from {}", peer);
+}
+
+fn write_view(path: &Path, view: &MergedView) -> Result<(), Error> {
+    fs::write(path, view.to_json().to_pretty_string() + "\n")
+}
+
+/// Parse a collector address such as "10.0.0.1:7070", checking only that it has a port, since it may be a host name
+pub fn parse_collector_addr(arg: &str) -> Result<String, String> {
+    if arg.parse::<SocketAddr>().is_ok() || arg.rsplit_once(':').is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok()) {
+        return Ok(arg.to_string());
+    }
+    Err(String::from("expected HOST:PORT"))
+}
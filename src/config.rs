@@ -0,0 +1,385 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+
+/// A value of a config file setting
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConfigValue {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+    Array(Vec<ConfigValue>),
+}
+
+impl ConfigValue {
+    /// The value as command line values, one per item of an array
+    pub fn to_args(&self) -> Vec<String> {
+        match self {
+            ConfigValue::Array(items) => { items.iter().flat_map(|item| item.to_args()).collect() }
+            value => { vec![value.to_string()] }
+        }
+    }
+}
+
+/// Plain text, as given on the command line, with array items separated by commas
+impl fmt::Display for ConfigValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigValue::String(text) => { f.write_str(text) }
+            ConfigValue::Integer(value) => { write!(f, "{}", value) }
+            ConfigValue::Float(value) => { write!(f, "{}", value) }
+            ConfigValue::Boolean(value) => { write!(f, "{}", value) }
+            ConfigValue::Array(items) => {
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 { f.write_str(",")?; }
+                    write!(f, "{}", item)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// A key and its value, in the top level of the file or in a table
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConfigSetting {
+    /// Name of the table, or None at the top level
+    pub table: Option<String>,
+    pub key: String,
+    pub value: ConfigValue,
+    /// Line of the key in the file, starting from 1, for error messages
+    pub line: usize,
+}
+
+/// Read the settings of a config file, in the order of the file
+pub fn load(path: &Path) -> Result<Vec<ConfigSetting>, Error> {
+    fs::read_to_string(path)
+        .and_then(|text| parse(&text))
+        .map_err(|error| Error::new(error.kind(), format!("{}: {}", path.display(), error)))
+}
+
+/// Parse the settings of a config file in the subset of TOML that options need: tables, bare or quoted keys, strings,
+/// integers, floats, booleans and arrays of those. Dotted keys, arrays of tables, inline tables, multi-line strings
+/// and dates are refused.
+pub fn parse(text: &str) -> Result<Vec<ConfigSetting>, Error> {
+    let mut parser = ConfigParser { bytes: text.as_bytes(), pos: 0, line: 1 };
+    let mut settings = Vec::new();
+    let mut table: Option<String> = None;
+    let mut tables = HashSet::new();
+    let mut keys = HashSet::new();
+    loop {
+        parser.skip_blank();
+        match parser.bytes.get(parser.pos) {
+            None => { return Ok(settings); }
+            Some(b'\n') => {
+                parser.pos += 1;
+                parser.line += 1;
+                continue;
+            }
+            Some(b'[') => {
+                parser.pos += 1;
+                if parser.bytes.get(parser.pos) == Some(&b'[') {
+                    return Err(parser.error("Arrays of tables are not supported"));
+                }
+                parser.skip_blank();
+                let name = parser.parse_key()?;
+                parser.skip_blank();
+                parser.expect(b']')?;
+                if !tables.insert(name.clone()) {
+                    return Err(parser.error(&format!("Table [{}] is defined twice", name)));
+                }
+                table = Some(name);
+            }
+            Some(_) => {
+                let line = parser.line;
+                let key = parser.parse_key()?;
+                parser.skip_blank();
+                parser.expect(b'=')?;
+                parser.skip_blank();
+                let value = parser.parse_value()?;
+                if !keys.insert((table.clone(), key.clone())) {
+                    return Err(parser.error(&format!("Key \"{}\" is defined twice", key)));
+                }
+                settings.push(ConfigSetting { table: table.clone(), key, value, line });
+            }
+        }
+        parser.skip_blank();
+        match parser.bytes.get(parser.pos) {
+            None | Some(b'\n') => {}
+            Some(_) => { return Err(parser.error("Expected the end of the line")); }
+        }
+    }
+}
+
+/// Recursive descent parser over the file bytes, that counts lines for error messages
+struct ConfigParser<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    line: usize,
+}
+
+impl<'a> ConfigParser<'a> {
+    fn error(&self, message: &str) -> Error {
+        Error::new(ErrorKind::InvalidData, format!("Config parsing error at line {}: {}", self.line, message))
+    }
+
+    /// Skip spaces, tabs, carriage returns and comments, but not the end of the line
+    fn skip_blank(&mut self) {
+        while let Some(byte) = self.bytes.get(self.pos) {
+            match byte {
+                b' ' | b'\t' | b'\r' => { self.pos += 1; }
+                b'#' => {
+                    while self.bytes.get(self.pos).is_some_and(|byte| *byte != b'\n') {
+                        self.pos += 1;
+                    }
+                }
+                _ => { return; }
+            }
+        }
+    }
+
+    /// Skip blanks, comments and line ends, as between the items of an array
+    fn skip_blank_lines(&mut self) {
+        loop {
+            self.skip_blank();
+            if self.bytes.get(self.pos) != Some(&b'\n') {
+                return;
+            }
+            self.pos += 1;
+            self.line += 1;
+        }
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), Error> {
+        if self.bytes.get(self.pos) == Some(&byte) {
+            self.pos += 1;
+            return Ok(());
+        }
+        Err(self.error(&format!("Expected '{}'", byte as char)))
+    }
+
+    fn parse_key(&mut self) -> Result<String, Error> {
+        let key = match self.bytes.get(self.pos) {
+            Some(b'"') => { self.parse_basic_string()? }
+            Some(b'\'') => { self.parse_literal_string()? }
+            _ => {
+                let start = self.pos;
+                while self.bytes.get(self.pos).is_some_and(|byte| byte.is_ascii_alphanumeric() || matches!(byte, b'_' | b'-')) {
+                    self.pos += 1;
+                }
+                if self.pos == start {
+                    return Err(self.error("Expected a key"));
+                }
+                String::from_utf8_lossy(&self.bytes[start..self.pos]).into_owned()
+            }
+        };
+        if self.bytes.get(self.pos) == Some(&b'.') {
+            return Err(self.error("Dotted keys are not supported"));
+        }
+        Ok(key)
+    }
+
+    fn parse_value(&mut self) -> Result<ConfigValue, Error> {
+        match self.bytes.get(self.pos) {
+            None | Some(b'\n') => { Err(self.error("Expected a value")) }
+            Some(b'"') => { self.parse_basic_string().map(ConfigValue::String) }
+            Some(b'\'') => { self.parse_literal_string().map(ConfigValue::String) }
+            Some(b'[') => { self.parse_array() }
+            Some(b'{') => { Err(self.error("Inline tables are not supported")) }
+            Some(_) => { self.parse_bare_value() }
+        }
+    }
+
+    /// A boolean or a number
+    fn parse_bare_value(&mut self) -> Result<ConfigValue, Error> {
+        let start = self.pos;
+        while self.bytes.get(self.pos).is_some_and(|byte| byte.is_ascii_alphanumeric() || matches!(byte, b'+' | b'-' | b'.' | b'_' | b':')) {
+            self.pos += 1;
+        }
+        let text = String::from_utf8_lossy(&self.bytes[start..self.pos]);
+        let digits = text.replace('_', "");
+        match text.as_ref() {
+            "true" => { Ok(ConfigValue::Boolean(true)) }
+            "false" => { Ok(ConfigValue::Boolean(false)) }
+            _ if text.starts_with('_') || text.ends_with('_') || text.contains("__") => {
+                Err(self.error(&format!("Invalid value \"{}\"", text)))
+            }
+            _ => {
+                if let Ok(value) = digits.parse::<i64>() {
+                    return Ok(ConfigValue::Integer(value));
+                }
+                match digits.parse::<f64>() {
+                    Ok(value) if digits.contains(|c: char| c.is_ascii_digit()) => { Ok(ConfigValue::Float(value)) }
+                    _ => { Err(self.error(&format!("Invalid value \"{}\", strings are quoted", text))) }
+                }
+            }
+        }
+    }
+
+    fn parse_basic_string(&mut self) -> Result<String, Error> {
+        if self.bytes[self.pos..].starts_with(b"\"\"\"") {
+            return Err(self.error("Multi-line strings are not supported"));
+        }
+        self.expect(b'"')?;
+        let mut result = Vec::new();
+        loop {
+            match self.bytes.get(self.pos) {
+                None | Some(b'\n') => { return Err(self.error("Unterminated string")); }
+                Some(b'"') => {
+                    self.pos += 1;
+                    return String::from_utf8(result).map_err(|_| self.error("Invalid UTF-8"));
+                }
+                Some(b'\\') => {
+                    self.pos += 1;
+                    let escaped = self.bytes.get(self.pos).copied().ok_or_else(|| self.error("Unterminated escape"))?;
+                    self.pos += 1;
+                    let c = match escaped {
+                        b'"' => { '"' }
+                        b'\\' => { '\\' }
+                        b'b' => { '\u{8}' }
+                        b'f' => { '\u{c}' }
+                        b'n' => { '\n' }
+                        b'r' => { '\r' }
+                        b't' => { '\t' }
+                        b'u' => { self.parse_unicode(4)? }
+                        b'U' => { self.parse_unicode(8)? }
+                        _ => { return Err(self.error("Invalid escape")); }
+                    };
+                    result.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                }
+                Some(byte) => {
+                    result.push(*byte);
+                    self.pos += 1;
+                }
+            }
+        }
+    }
+
+    fn parse_unicode(&mut self, digits: usize) -> Result<char, Error> {
+        let hex = self.bytes.get(self.pos..self.pos + digits).and_then(|h| std::str::from_utf8(h).ok());
+        let code = hex.and_then(|h| u32::from_str_radix(h, 16).ok()).ok_or_else(|| self.error("Invalid unicode escape"))?;
+        self.pos += digits;
+        char::from_u32(code).ok_or_else(|| self.error("Invalid unicode character"))
+    }
+
+    /// A string in single quotes, without escapes
+    fn parse_literal_string(&mut self) -> Result<String, Error> {
+        if self.bytes[self.pos..].starts_with(b"'''") {
+            return Err(self.error("Multi-line strings are not supported"));
+        }
+        self.expect(b'\'')?;
+        let start = self.pos;
+        while self.bytes.get(self.pos).is_some_and(|byte| !matches!(byte, b'\'' | b'\n')) {
+            self.pos += 1;
+        }
+        let text = std::str::from_utf8(&self.bytes[start..self.pos]).map_err(|_| self.error("Invalid UTF-8"))?.to_string();
+        self.expect(b'\'')?;
+        Ok(text)
+    }
+
+    /// An array, that may span lines and have a trailing comma
+    fn parse_array(&mut self) -> Result<ConfigValue, Error> {
+        self.expect(b'[')?;
+        let mut items = Vec::new();
+        loop {
+            self.skip_blank_lines();
+            if self.bytes.get(self.pos) == Some(&b']') {
+                self.pos += 1;
+                return Ok(ConfigValue::Array(items));
+            }
+            items.push(self.parse_value()?);
+            self.skip_blank_lines();
+            match self.bytes.get(self.pos) {
+                Some(b',') => { self.pos += 1; }
+                Some(b']') => {
+                    self.pos += 1;
+                    return Ok(ConfigValue::Array(items));
+                }
+                _ => { return Err(self.error("Expected ',' or ']'")); }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ConfigSetting, ConfigValue, parse};
+
+    fn setting(table: Option<&str>, key: &str, value: ConfigValue, line: usize) -> ConfigSetting {
+        ConfigSetting { table: table.map(String::from), key: key.to_string(), value, line }
+    }
+
+    fn parse_error(text: &str) -> String {
+        parse(text).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn settings_of_tables_in_order() {
+        let text = "interface = \"eth0\"\n\n[replay]\nspeed = 2.5\n[ \"capture\" ]\nsnaplen = 1_500\npromisc = false\n";
+        assert_eq!(parse(text).unwrap(), vec![
+            setting(None, "interface", ConfigValue::String("eth0".to_string()), 1),
+            setting(Some("replay"), "speed", ConfigValue::Float(2.5), 4),
+            setting(Some("capture"), "snaplen", ConfigValue::Integer(1500), 6),
+            setting(Some("capture"), "promisc", ConfigValue::Boolean(false), 7),
+        ]);
+    }
+
+    #[test]
+    fn quoted_and_escaped_strings() {
+        let text = "\"quoted key\" = \"tab\\there \\\"quoted\\\" \\\\ \\u00e9\\U0001F600\"\nliteral = 'C:\\path\\no escapes'\n";
+        let settings = parse(text).unwrap();
+        assert_eq!(settings[0].key, "quoted key");
+        assert_eq!(settings[0].value, ConfigValue::String("tab\there \"quoted\" \\ \u{e9}\u{1F600}".to_string()));
+        assert_eq!(settings[1].value, ConfigValue::String("C:\\path\\no escapes".to_string()));
+    }
+
+    #[test]
+    fn arrays_across_lines_with_comments() {
+        let text = "# Rules\nignore = [\n  \"port 22\", # SSH\n  'port 53',\n]\nports = [80, 443]\nempty = []\n";
+        let settings = parse(text).unwrap();
+        assert_eq!(settings[0], setting(None, "ignore", ConfigValue::Array(vec![
+            ConfigValue::String("port 22".to_string()), ConfigValue::String("port 53".to_string())]), 2));
+        assert_eq!(settings[0].value.to_args(), vec!["port 22", "port 53"]);
+        assert_eq!(settings[1], setting(None, "ports", ConfigValue::Array(vec![ConfigValue::Integer(80), ConfigValue::Integer(443)]), 6));
+        assert_eq!(settings[1].value.to_string(), "80,443");
+        assert_eq!(settings[2].value, ConfigValue::Array(Vec::new()));
+    }
+
+    #[test]
+    fn comments_and_blank_lines_are_skipped() {
+        let text = "\r\n# Comment = 1\n   \t\nverbose = true # trailing comment\r\n";
+        assert_eq!(parse(text).unwrap(), vec![setting(None, "verbose", ConfigValue::Boolean(true), 4)]);
+    }
+
+    #[test]
+    fn unknown_keys_are_kept_for_the_caller() {
+        // The parser does not know the options, so any key is kept with its line, for the caller to check
+        let text = "[no_such_command]\nno_such_option = 1\n";
+        assert_eq!(parse(text).unwrap(), vec![setting(Some("no_such_command"), "no_such_option", ConfigValue::Integer(1), 2)]);
+    }
+
+    #[test]
+    fn errors_have_line_numbers() {
+        assert_eq!(parse_error("a = 1\n\nb = \"open\n"), "Config parsing error at line 3: Unterminated string");
+        assert_eq!(parse_error("a = 1\na = 2\n"), "Config parsing error at line 2: Key \"a\" is defined twice");
+        assert_eq!(parse_error("[t]\n[t]\n"), "Config parsing error at line 2: Table [t] is defined twice");
+        assert_eq!(parse_error("a = 1 2\n"), "Config parsing error at line 1: Expected the end of the line");
+        assert_eq!(parse_error("a = on\n"), "Config parsing error at line 1: Invalid value \"on\", strings are quoted");
+        assert_eq!(parse_error("a = 1__0\n"), "Config parsing error at line 1: Invalid value \"1__0\"");
+        assert_eq!(parse_error("a = \"\\q\"\n"), "Config parsing error at line 1: Invalid escape");
+        assert_eq!(parse_error("a = [\n1,\n2\n3]\n"), "Config parsing error at line 4: Expected ',' or ']'");
+        assert_eq!(parse_error("= 1\n"), "Config parsing error at line 1: Expected a key");
+        assert_eq!(parse_error("a =\n"), "Config parsing error at line 1: Expected a value");
+    }
+
+    #[test]
+    fn unsupported_syntax_is_refused() {
+        assert_eq!(parse_error("a.b = 1\n"), "Config parsing error at line 1: Dotted keys are not supported");
+        assert_eq!(parse_error("[[t]]\n"), "Config parsing error at line 1: Arrays of tables are not supported");
+        assert_eq!(parse_error("a = { b = 1 }\n"), "Config parsing error at line 1: Inline tables are not supported");
+        assert_eq!(parse_error("a = \"\"\"b\"\"\"\n"), "Config parsing error at line 1: Multi-line strings are not supported");
+    }
+}
@@ -1,11 +1,38 @@
 use std::fmt;
 use std::fmt::Debug;
-use std::net::Ipv4Addr;
-use std::time::Instant;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::time::{Duration, Instant};
 use etherparse::{TcpHeaderSlice, TcpOptionElement};
 use log::{Level, log, log_enabled};
-use crate::flow_buff::FlowBuff;
-use crate::utils::tcp_flags_to_string;
+use crate::alert::{Alert, AlertMetric, AlertRule, MAX_ALERT_RULES, RETRANSMIT_RATE_MIN_PACKETS};
+use crate::analyzer::{Analyzer, AppInfo, DnsQuery, HttpTransaction, MAX_SERVER_NAME_LEN, metadata_to_json, port_label};
+use crate::conn_pcap::ConnFrames;
+use crate::digest::PayloadExport;
+use crate::error;
+use crate::event::{Anomaly, ConnEvent};
+use crate::features::ConnFeatures;
+use crate::flow_buff::{AckPattern, FlowActivity, FlowBuff, SackLoss, SegmentCounts, WindowStalls};
+use crate::flow_key::{Encapsulation, OuterTunnel};
+use crate::history::{PacketHistory, PacketRecord, tcp_flags_list};
+use crate::json::JsonValue;
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
+use crate::tags::ConnTags;
+use crate::time_format::TimeFormatter;
+use crate::utils::{mix64, tcp_flags_to_string};
+
+/// Max window scale shift count (RFC 7323)
+const MAX_WINDOW_SHIFT: u8 = 14;
+/// Length of the IPv4 and TCP headers without options, that the MSS leaves out of the MTU
+const MSS_HEADERS_LEN: u16 = 40;
+/// Tag of the connections that were seen twice, before and after a NAT, set to the sequence of the other one
+pub(crate) const NAT_TWIN_TAG: &str = "nat_twin";
+/// Bit of the signature that marks an IPv6 connection, above the identifiers that a flow key may add
+const SIGN_IPV6_BIT: u128 = 1 << 127;
+
+/// The ISNs of the client and the server and their ports, that a NAT does not change, so the same connection on both
+/// sides of the NAT has the same key
+pub(crate) type NatTwinKey = (u32, u32, u16, u16);
 
 /// Hold a TCP connections, along with statistics
 /// The lower address is always considered "source" or xxx_1 in field names.
@@ -15,14 +42,62 @@ pub struct Conn {
     start_time: Instant,
     /// Connection state
     pub(crate) state: ConnState,
+    /// Capture time when the connection moved to the closed state, to be removed after a grace period
+    pub(crate) closed_ts: Option<Duration>,
+    /// Capture time of the last packet, to evict connections that went idle
+    pub(crate) last_packet_ts: Duration,
+    /// The direction that sent the first SYN, if seen
+    pub(crate) initiator: Option<PacketDir>,
+    /// Capture timestamp of the first SYN, for the handshake round trip time, and to detect handshakes that do not complete
+    pub(crate) syn_packet_ts: Option<Duration>,
+    /// Number of SYNs that were seen again with the same ISN
+    syn_retransmits: u32,
+    /// Number of SYN/ACKs that were seen again with the same ISN
+    syn_ack_retransmits: u32,
     /// Sequence of the connection (all time counter)
     pub(crate) conn_sequence: u32,
     /// Signature made of IPs and ports
     conn_sign: u128,
+    /// Lower and higher addresses of an IPv6 connection, since its signature holds them folded
+    v6_addresses: Option<(SocketAddrV6, SocketAddrV6)>,
+    /// VLAN and tunnel of the first packet, that the connection list may be keyed by along with the signature
+    pub(crate) encapsulation: Encapsulation,
+    /// Exported identifier, derived from the capture start time, the capture time of the first packet and the signature,
+    /// so exports of different runs and probes can be joined without clashes
+    pub(crate) conn_id: u64,
     /// Buffer and statistics for flow from low to high address
     pub(crate) flow_src_low: FlowBuff,
     /// Buffer and statistics for flow from high to low address
     pub(crate) flow_src_high: FlowBuff,
+    /// Last packets of the connection, if enabled
+    pub(crate) history: PacketHistory,
+    /// What is known about the application protocol
+    pub(crate) app: AppInfo,
+    /// Analyzers that still look at the bytes of the flows, set by the connection table for new connections
+    pub(crate) analyzers: Vec<Box<dyn Analyzer>>,
+    /// Time between the SYN and the SYN/ACK, if both were seen, which is the server side of the round trip
+    /// when the capture point is between the client and the server
+    pub(crate) handshake_rtt: Option<Duration>,
+    /// Capture timestamp of the last SYN/ACK, for the client side of the round trip
+    syn_ack_packet_ts: Option<Duration>,
+    /// Time between the SYN/ACK and the ACK that completes the handshake, which is the client side of the round trip
+    pub(crate) client_rtt: Option<Duration>,
+    /// Capture timestamp of the first payload sent by the initiator, for the time to first byte
+    first_request_ts: Option<Duration>,
+    /// Time between the first payload of the initiator and the first payload of the other side after it, if both were seen
+    pub(crate) ttfb: Option<Duration>,
+    /// Alert rules that already fired on this connection, as bits by the rule index
+    alerts_fired: u64,
+    /// Payloads are counted but not buffered, since the buffering budget was nearly exhausted
+    pub(crate) stats_only: bool,
+    /// Segments whose TCP checksum was wrong, and whose payloads were not buffered.
+    /// Not saved with the state, so a restored connection counts from zero.
+    pub(crate) checksum_errors: u32,
+    /// Statistics of the packets for the feature export, if enabled. Restored connections start without them.
+    pub(crate) features: Option<Box<ConnFeatures>>,
+    /// Frames that were not written to the pcap file of the connection yet, if per connection files are enabled.
+    /// Restored connections start without them.
+    pub(crate) frames: Option<ConnFrames>,
 }
 
 impl std::fmt::Debug for Conn {
@@ -33,20 +108,431 @@ impl std::fmt::Debug for Conn {
     }
 }
 
+/// Identity, state and counters of a connection at a point in time.
+/// Its display is a one line flow record.
 #[derive(Clone, Debug)]
-pub(crate) enum ConnState {
+pub struct ConnSummary {
+    /// Sequence of the connection (all time counter)
+    pub conn_sequence: u32,
+    /// Identifier that is unique across restarts and probes, in hex
+    pub conn_id: String,
+    pub low_addr: SocketAddr,
+    pub high_addr: SocketAddr,
+    /// VLAN and tunnel of the first packet
+    pub encapsulation: Encapsulation,
+    pub state: ConnState,
+    /// The direction that sent the first SYN, if seen
+    pub initiator: Option<PacketDir>,
+    /// Application protocol, if identified
+    pub app_label: Option<String>,
+    /// TLS server name (SNI) that the client asked for, if it sent a ClientHello
+    pub server_name: Option<String>,
+    /// Name that the client resolved to the server address over UDP DNS, if seen
+    pub resolved_name: Option<String>,
+    /// TLS version of the secrets found in the key log, or "missing", if looked up
+    pub tls_keys: Option<&'static str>,
+    /// Proxy tunnel kind and requested target, if the connection goes through a proxy
+    pub tunnel: Option<String>,
+    /// Protocol details found by the analyzers, such as the SMB dialect
+    pub metadata: Vec<(&'static str, String)>,
+    /// Client commands by verb, from the most common, for protocols where they are counted
+    pub commands: Vec<(String, u64)>,
+    /// Requests and responses of HTTP/1.x, in the order of the requests
+    pub http_transactions: Vec<HttpTransaction>,
+    /// Questions of DNS over TCP, with their response codes, in the order of the queries
+    pub dns_queries: Vec<DnsQuery>,
+    /// Labels attached by analyzers, rules and consumers
+    pub tags: ConnTags,
+    /// Round trip between the capture point and the server (SYN to SYN/ACK), if measured
+    pub server_rtt: Option<Duration>,
+    /// Round trip between the capture point and the client (SYN/ACK to ACK), if measured
+    pub client_rtt: Option<Duration>,
+    /// How many data segments every ACK of each side acknowledged
+    pub acks_src_low: AckPattern,
+    pub acks_src_high: AckPattern,
+    /// Number of Nagle and delayed ACK stalls of both sides, and the time they lost
+    pub nagle_stalls: u32,
+    pub nagle_time_lost: Duration,
+    /// Zero window and window-full periods in which each side, as the receiver, held the other side back
+    pub window_stalls_src_low: WindowStalls,
+    pub window_stalls_src_high: WindowStalls,
+    /// Whether both sides allowed SACK on their SYNs
+    pub sack_permitted: bool,
+    /// Data of each side that the SACK blocks of the other side reported missing
+    pub sack_loss_src_low: SackLoss,
+    pub sack_loss_src_high: SackLoss,
+    /// Data segments of each side by whether they were in order, retransmitted, out of order or overlapping
+    pub segments_src_low: SegmentCounts,
+    pub segments_src_high: SegmentCounts,
+    /// Number of times the SYN was sent, so more than one means it was retransmitted
+    pub handshake_attempts: u32,
+    pub syn_ack_retransmits: u32,
+    pub packets_src_low: u32,
+    pub packets_src_high: u32,
+    pub bytes_src_low: u64,
+    pub bytes_src_high: u64,
+    /// Most bytes of each side that were buffered and not consumed yet at any time
+    pub max_held_bytes_src_low: usize,
+    pub max_held_bytes_src_high: usize,
+    /// Payload bytes of each side that were not buffered since the flow buffer was at its limit
+    pub dropped_bytes_src_low: u64,
+    pub dropped_bytes_src_high: u64,
+    /// Capture times, IP octets and TCP flags of the packets of each side
+    pub activity_src_low: FlowActivity,
+    pub activity_src_high: FlowActivity,
+    /// Segments of both sides whose TCP checksum was wrong
+    pub checksum_errors: u32,
+    /// Time since the connection was first seen
+    pub age: Duration,
+}
+
+impl fmt::Display for ConnSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "conn {} (id {}) {} - {}, app: {}, state: {:?}, handshake attempts: {}, SYN/ACK retransmits: {}, \
+            packets: {}/{}, bytes: {}/{}, time: {}ms", self.conn_sequence, self.conn_id, self.low_addr, self.high_addr,
+               self.app_label.as_deref().unwrap_or("unknown"), self.state,
+               self.handshake_attempts, self.syn_ack_retransmits, self.packets_src_low, self.packets_src_high,
+               self.bytes_src_low, self.bytes_src_high, self.age.as_millis())?;
+        if let Some(server_name) = &self.server_name {
+            write!(f, ", server name: {}", server_name)?;
+        }
+        if let Some(resolved_name) = &self.resolved_name {
+            write!(f, ", resolved name: {}", resolved_name)?;
+        }
+        if !self.encapsulation.is_empty() {
+            write!(f, ", encapsulation: {}", self.encapsulation)?;
+        }
+        if let Some(server_rtt) = self.server_rtt {
+            write!(f, ", rtt server/client: {}us/", server_rtt.as_micros())?;
+            match self.client_rtt {
+                Some(client_rtt) => { write!(f, "{}us", client_rtt.as_micros())?; }
+                None => { write!(f, "unknown")?; }
+            }
+        }
+        if !self.acks_src_low.is_empty() || !self.acks_src_high.is_empty() {
+            write!(f, ", segments per ACK: {} / {}", self.acks_src_low, self.acks_src_high)?;
+        }
+        if self.dropped_bytes_src_low > 0 || self.dropped_bytes_src_high > 0 {
+            write!(f, ", bytes dropped over the buffer limit: {}/{}", self.dropped_bytes_src_low, self.dropped_bytes_src_high)?;
+        }
+        if self.nagle_stalls > 0 {
+            write!(f, ", Nagle/delayed ACK stalls: {} ({}ms lost)", self.nagle_stalls, self.nagle_time_lost.as_millis())?;
+        }
+        if !self.window_stalls_src_low.is_empty() || !self.window_stalls_src_high.is_empty() {
+            write!(f, ", receive window stalls: {} / {}", self.window_stalls_src_low, self.window_stalls_src_high)?;
+        }
+        if self.sack_loss_src_low.holes > 0 || self.sack_loss_src_high.holes > 0 {
+            let loss = |sack_loss: &SackLoss, bytes: u64| -> String {
+                format!("{:.2}% ({} holes, {} retransmitted)", sack_loss.loss_percent(bytes).unwrap_or_default(),
+                        sack_loss.holes, sack_loss.retransmitted_holes)
+            };
+            write!(f, ", SACK loss: {} / {}", loss(&self.sack_loss_src_low, self.bytes_src_low),
+                   loss(&self.sack_loss_src_high, self.bytes_src_high))?;
+        }
+        if self.segments_src_low.has_disorder() || self.segments_src_high.has_disorder() {
+            write!(f, ", segments: {} / {}", self.segments_src_low, self.segments_src_high)?;
+        }
+        if self.checksum_errors > 0 {
+            write!(f, ", checksum errors: {}", self.checksum_errors)?;
+        }
+        if let Some(tunnel) = &self.tunnel {
+            write!(f, ", tunnel: {}", tunnel)?;
+        }
+        if let Some(tls_keys) = self.tls_keys {
+            write!(f, ", TLS keys: {}", tls_keys)?;
+        }
+        for (key, value) in &self.metadata {
+            write!(f, ", {}: {}", key, value)?;
+        }
+        if !self.commands.is_empty() {
+            let commands: Vec<String> = self.commands.iter().map(|(verb, count)| format!("{} {}", verb, count)).collect();
+            write!(f, ", commands: {}", commands.join(" "))?;
+        }
+        if !self.http_transactions.is_empty() {
+            write!(f, ", HTTP transactions: {}", self.http_transactions.len())?;
+        }
+        if !self.dns_queries.is_empty() {
+            write!(f, ", DNS queries: {}", self.dns_queries.len())?;
+        }
+        if !self.tags.is_empty() {
+            write!(f, ", tags: {}", self.tags)?;
+        }
+        Ok(())
+    }
+}
+
+impl ConnSummary {
+    /// JSON representation of the flow record, for exporting it to a collector
+    pub fn to_json(&self) -> JsonValue {
+        JsonValue::object(vec![
+            ("sequence", self.conn_sequence.into()),
+            ("id", self.conn_id.as_str().into()),
+            ("low", self.low_addr.to_string().into()),
+            ("high", self.high_addr.to_string().into()),
+            ("vlan", self.encapsulation.vlan.into()),
+            ("tunnel_id", self.encapsulation.tunnel_id.into()),
+            ("outer_tunnel", self.encapsulation.outer_tunnel.map_or(JsonValue::Null, |tunnel| tunnel.to_json())),
+            ("state", self.state.to_json()),
+            ("initiator", self.initiator.as_ref().map(|dir| if *dir == PacketDir::SrcLowAddr { "low" } else { "high" }).into()),
+            ("app", self.app_label.as_deref().into()),
+            ("server_name", self.server_name.as_deref().into()),
+            ("resolved_name", self.resolved_name.as_deref().into()),
+            ("tls_keys", self.tls_keys.into()),
+            ("tunnel", self.tunnel.as_deref().into()),
+            ("metadata", metadata_to_json(&self.metadata)),
+            ("commands", JsonValue::object(self.commands.iter().map(|(verb, count)| (verb.as_str(), (*count).into())).collect())),
+            ("http_transactions", self.http_transactions.iter().map(|transaction| transaction.to_json()).collect::<Vec<JsonValue>>().into()),
+            ("dns_queries", self.dns_queries.iter().map(|query| query.to_json()).collect::<Vec<JsonValue>>().into()),
+            ("tags", self.tags.to_json()),
+            ("server_rtt_us", self.server_rtt.map(|rtt| rtt.as_micros() as u64).into()),
+            ("client_rtt_us", self.client_rtt.map(|rtt| rtt.as_micros() as u64).into()),
+            ("acks_src_low", self.acks_src_low.to_json()),
+            ("acks_src_high", self.acks_src_high.to_json()),
+            ("nagle_stalls", self.nagle_stalls.into()),
+            ("nagle_time_lost_ms", (self.nagle_time_lost.as_millis() as u64).into()),
+            ("window_stalls_src_low", self.window_stalls_src_low.to_json()),
+            ("window_stalls_src_high", self.window_stalls_src_high.to_json()),
+            ("sack_permitted", self.sack_permitted.into()),
+            ("sack_loss_src_low", self.sack_loss_src_low.to_json(self.bytes_src_low)),
+            ("sack_loss_src_high", self.sack_loss_src_high.to_json(self.bytes_src_high)),
+            ("segments_src_low", self.segments_src_low.to_json()),
+            ("segments_src_high", self.segments_src_high.to_json()),
+            ("handshake_attempts", self.handshake_attempts.into()),
+            ("syn_ack_retransmits", self.syn_ack_retransmits.into()),
+            ("packets_src_low", self.packets_src_low.into()),
+            ("packets_src_high", self.packets_src_high.into()),
+            ("bytes_src_low", self.bytes_src_low.into()),
+            ("bytes_src_high", self.bytes_src_high.into()),
+            ("max_held_bytes_src_low", self.max_held_bytes_src_low.into()),
+            ("max_held_bytes_src_high", self.max_held_bytes_src_high.into()),
+            ("dropped_bytes_src_low", self.dropped_bytes_src_low.into()),
+            ("dropped_bytes_src_high", self.dropped_bytes_src_high.into()),
+            ("checksum_errors", self.checksum_errors.into()),
+            ("age_ms", (self.age.as_millis() as u64).into()),
+        ])
+    }
+}
+
+/// Parameters of a connection that was just established, as a one line "connection opened" audit trail
+#[derive(Clone, Debug, PartialEq)]
+pub struct OpenedSummary {
+    /// The address that sent the SYN
+    pub client: SocketAddr,
+    pub server: SocketAddr,
+    /// Maximum segment size announced by each side, or 0 if none
+    pub client_mss: u16,
+    pub server_mss: u16,
+    /// Window scale multiplier announced by each side, or 1 if none
+    pub client_window_scale: u16,
+    pub server_window_scale: u16,
+    /// Time between the SYN and the SYN/ACK, as seen at the capture point
+    pub handshake_rtt: Option<Duration>,
+}
+
+impl fmt::Display for OpenedSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} -> {}, mss {}/{}, wscale {}/{}", self.client, self.server, self.client_mss, self.server_mss,
+               self.client_window_scale.trailing_zeros(), self.server_window_scale.trailing_zeros())?;
+        match self.handshake_rtt {
+            Some(rtt) => { write!(f, ", rtt {}us", rtt.as_micros()) }
+            None => { write!(f, ", rtt unknown") }
+        }
+    }
+}
+
+/// The state of a connection as seen by an observer of both directions, following RFC 793.
+/// Since the observer cannot tell when a side received a packet, the states that the two sides pass at about the same
+/// time (such as FIN-WAIT-2 and CLOSE-WAIT) are merged into one.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConnState {
     /// No SYN packets were detected yet
     Created,
     /// A SYN was detected, sent by the specified direction, carrying the specified TCP sequence
     SynSent(PacketDir, u32),
+    /// Simultaneous open, where the other side sent its own SYN before acknowledging the first one, that was sent by the
+    /// specified direction and is acknowledged by the specified sequence
+    SynReceived(PacketDir, u32),
     /// Who sent the first SYN
     Established(PacketDir),
+    /// Picked up after its handshake, such as one that was open before the capture started, with the initial sequence of
+    /// every direction inferred from its first segment
+    EstablishedMidstream,
     /// Who sent the first FIN, along with the expected ack sequence from the other direction
     FinWait1(PacketDir, u32),
-    /// Who sent the response FIN, along with the expected ack sequence from the other direction
-    FinWait2(PacketDir, u32),
-    /// Last one to send the RST or FIN
+    /// The first FIN, sent by the specified direction, was acknowledged, and the other side may still send data before
+    /// its own FIN (FIN-WAIT-2 on one side, CLOSE-WAIT on the other)
+    CloseWait(PacketDir),
+    /// Simultaneous close, where both sides sent a FIN before either was acknowledged.
+    /// Who sent the first FIN, along with the expected ack sequences of the first and of the second FIN.
+    Closing(PacketDir, u32, u32),
+    /// Who sent the last FIN, along with the expected ack sequence from the other direction, that already got its FIN
+    /// acknowledged
+    LastAck(PacketDir, u32),
+    /// Both FINs were acknowledged, by the specified direction last, that lingers before closing for good.
+    /// Late packets are still attributed to the connection until it is removed.
+    TimeWait(PacketDir),
+    /// Last one to send the RST
     Closed(PacketDir),
+    /// The handshake was not completed in time, by the specified direction that sent the SYN
+    Failed(PacketDir),
+}
+
+impl ConnState {
+    /// Whether the connection ended, gracefully or by a reset, so its buffered bytes can all be handed over
+    pub fn is_closed(&self) -> bool {
+        matches!(self, ConnState::TimeWait(_) | ConnState::Closed(_))
+    }
+
+    /// Whether one of the sides sent a FIN and the connection did not end yet
+    pub fn is_closing(&self) -> bool {
+        matches!(self, ConnState::FinWait1(..) | ConnState::CloseWait(_) | ConnState::Closing(..) | ConnState::LastAck(..))
+    }
+
+    /// The next state after a FIN, or after an ack while closing, if it changes
+    pub(crate) fn closing_transition(&self, packet_dir: &PacketDir, tcp: &TcpHeaderSlice, tcp_payload_len: u16)
+                                     -> Option<ConnState> {
+        let acked = |expected_ack: u32| tcp.ack() && tcp.acknowledgment_number() == expected_ack;
+        // The FIN takes the sequence number after the payload that it carries
+        let fin_ack = tcp.sequence_number().wrapping_add(tcp_payload_len as u32).wrapping_add(1);
+        match self {
+            // Normal - one side signals that it wants to close
+            ConnState::Established(_) | ConnState::EstablishedMidstream if tcp.fin() => { Some(ConnState::FinWait1(packet_dir.to_owned(), fin_ack)) }
+            ConnState::FinWait1(fin_dir, expected_ack) if fin_dir != packet_dir => {
+                match (tcp.fin(), acked(*expected_ack)) {
+                    // The other side acknowledges the FIN and sends its own right away
+                    (true, true) => { Some(ConnState::LastAck(packet_dir.to_owned(), fin_ack)) }
+                    // Both sides closed at the same time
+                    (true, false) => { Some(ConnState::Closing(fin_dir.to_owned(), *expected_ack, fin_ack)) }
+                    // Half-closed, the other side may still send data
+                    (false, true) => { Some(ConnState::CloseWait(fin_dir.to_owned())) }
+                    (false, false) => { None }
+                }
+            }
+            ConnState::CloseWait(fin_dir) if fin_dir != packet_dir && tcp.fin() => {
+                Some(ConnState::LastAck(packet_dir.to_owned(), fin_ack))
+            }
+            // Whichever FIN is acknowledged first, the other one is the last to be acknowledged
+            ConnState::Closing(fin_dir, expected_ack, other_expected_ack) => {
+                if fin_dir != packet_dir && acked(*expected_ack) {
+                    Some(ConnState::LastAck(packet_dir.to_owned(), *other_expected_ack))
+                } else if fin_dir == packet_dir && acked(*other_expected_ack) {
+                    Some(ConnState::LastAck(fin_dir.to_owned(), *expected_ack))
+                } else {
+                    None
+                }
+            }
+            ConnState::LastAck(fin_dir, expected_ack) if fin_dir != packet_dir && acked(*expected_ack) => {
+                Some(ConnState::TimeWait(packet_dir.to_owned()))
+            }
+            // A FIN in any other state can happen but normally should not
+            _ => { None }
+        }
+    }
+
+    /// Write the state as a tag, a direction and two sequences (zero when the state has none)
+    fn write_snapshot<W: Write>(&self, writer: &mut SnapshotWriter<W>) -> Result<(), Error> {
+        let (tag, dir, seq, other_seq) = match self {
+            ConnState::Created => { (0u8, &PacketDir::SrcLowAddr, 0u32, 0u32) }
+            ConnState::SynSent(dir, seq) => { (1, dir, *seq, 0) }
+            ConnState::Established(dir) => { (2, dir, 0, 0) }
+            ConnState::FinWait1(dir, seq) => { (3, dir, *seq, 0) }
+            ConnState::LastAck(dir, seq) => { (4, dir, *seq, 0) }
+            ConnState::Closed(dir) => { (5, dir, 0, 0) }
+            ConnState::Failed(dir) => { (6, dir, 0, 0) }
+            ConnState::SynReceived(dir, seq) => { (7, dir, *seq, 0) }
+            ConnState::CloseWait(dir) => { (8, dir, 0, 0) }
+            ConnState::Closing(dir, seq, other_seq) => { (9, dir, *seq, *other_seq) }
+            ConnState::TimeWait(dir) => { (10, dir, 0, 0) }
+            ConnState::EstablishedMidstream => { (11, &PacketDir::SrcLowAddr, 0, 0) }
+        };
+        writer.put_u8(tag)?;
+        writer.put_u8(match dir { PacketDir::SrcLowAddr => { 0 }, _ => { 1 } })?;
+        writer.put_u32(seq)?;
+        writer.put_u32(other_seq)
+    }
+
+    /// JSON representation with the state name, and the direction and sequence where relevant
+    pub(crate) fn to_json(&self) -> JsonValue {
+        let dir_json = |dir: &PacketDir| -> JsonValue {
+            match dir { PacketDir::SrcLowAddr => { "low".into() }, _ => { "high".into() } }
+        };
+        match self {
+            ConnState::Created => { JsonValue::object(vec![("name", "Created".into())]) }
+            ConnState::SynSent(dir, seq) => {
+                JsonValue::object(vec![("name", "SynSent".into()), ("dir", dir_json(dir)), ("seq", (*seq).into())])
+            }
+            ConnState::SynReceived(dir, seq) => {
+                JsonValue::object(vec![("name", "SynReceived".into()), ("dir", dir_json(dir)), ("seq", (*seq).into())])
+            }
+            ConnState::Established(dir) => { JsonValue::object(vec![("name", "Established".into()), ("dir", dir_json(dir))]) }
+            ConnState::EstablishedMidstream => { JsonValue::object(vec![("name", "EstablishedMidstream".into())]) }
+            ConnState::FinWait1(dir, seq) => {
+                JsonValue::object(vec![("name", "FinWait1".into()), ("dir", dir_json(dir)), ("seq", (*seq).into())])
+            }
+            ConnState::CloseWait(dir) => { JsonValue::object(vec![("name", "CloseWait".into()), ("dir", dir_json(dir))]) }
+            ConnState::Closing(dir, seq, other_seq) => {
+                JsonValue::object(vec![("name", "Closing".into()), ("dir", dir_json(dir)), ("seq", (*seq).into()),
+                                       ("other_seq", (*other_seq).into())])
+            }
+            ConnState::LastAck(dir, seq) => {
+                JsonValue::object(vec![("name", "LastAck".into()), ("dir", dir_json(dir)), ("seq", (*seq).into())])
+            }
+            ConnState::TimeWait(dir) => { JsonValue::object(vec![("name", "TimeWait".into()), ("dir", dir_json(dir))]) }
+            ConnState::Closed(dir) => { JsonValue::object(vec![("name", "Closed".into()), ("dir", dir_json(dir))]) }
+            ConnState::Failed(dir) => { JsonValue::object(vec![("name", "Failed".into()), ("dir", dir_json(dir))]) }
+        }
+    }
+
+    fn from_json(json: &JsonValue) -> Result<Self, Error> {
+        let name = json.field_str("name")?;
+        match name {
+            "Created" => { return Ok(ConnState::Created); }
+            "EstablishedMidstream" => { return Ok(ConnState::EstablishedMidstream); }
+            _ => {}
+        }
+        let dir = match json.field_str("dir")? {
+            "low" => { PacketDir::SrcLowAddr }
+            "high" => { PacketDir::SrcHighAddr }
+            other => { return Err(Error::new(ErrorKind::InvalidData, format!("Unknown direction '{}'", other))); }
+        };
+        match name {
+            "SynSent" => { Ok(ConnState::SynSent(dir, json.field_u64("seq")? as u32)) }
+            "SynReceived" => { Ok(ConnState::SynReceived(dir, json.field_u64("seq")? as u32)) }
+            "Established" => { Ok(ConnState::Established(dir)) }
+            "FinWait1" => { Ok(ConnState::FinWait1(dir, json.field_u64("seq")? as u32)) }
+            "CloseWait" => { Ok(ConnState::CloseWait(dir)) }
+            "Closing" => {
+                Ok(ConnState::Closing(dir, json.field_u64("seq")? as u32, json.field_u64("other_seq")? as u32))
+            }
+            "LastAck" => { Ok(ConnState::LastAck(dir, json.field_u64("seq")? as u32)) }
+            "TimeWait" => { Ok(ConnState::TimeWait(dir)) }
+            "Closed" => { Ok(ConnState::Closed(dir)) }
+            "Failed" => { Ok(ConnState::Failed(dir)) }
+            _ => { Err(Error::new(ErrorKind::InvalidData, format!("Unknown connection state '{}'", name))) }
+        }
+    }
+
+    fn read_snapshot<R: Read>(reader: &mut SnapshotReader<R>) -> Result<Self, Error> {
+        let tag = reader.get_u8()?;
+        let dir = match reader.get_u8()? { 0 => { PacketDir::SrcLowAddr }, _ => { PacketDir::SrcHighAddr } };
+        let seq = reader.get_u32()?;
+        let other_seq = reader.get_u32()?;
+        match tag {
+            0 => { Ok(ConnState::Created) }
+            1 => { Ok(ConnState::SynSent(dir, seq)) }
+            2 => { Ok(ConnState::Established(dir)) }
+            3 => { Ok(ConnState::FinWait1(dir, seq)) }
+            4 => { Ok(ConnState::LastAck(dir, seq)) }
+            5 => { Ok(ConnState::Closed(dir)) }
+            6 => { Ok(ConnState::Failed(dir)) }
+            7 => { Ok(ConnState::SynReceived(dir, seq)) }
+            8 => { Ok(ConnState::CloseWait(dir)) }
+            9 => { Ok(ConnState::Closing(dir, seq, other_seq)) }
+            10 => { Ok(ConnState::TimeWait(dir)) }
+            11 => { Ok(ConnState::EstablishedMidstream) }
+            _ => { Err(Error::new(ErrorKind::InvalidData, format!("Unknown connection state {} in snapshot", tag))) }
+        }
+    }
 }
 
 /// State direction is required because each connection handles both directions of traffic.
@@ -60,14 +546,239 @@ pub enum PacketDir {
 
 impl Conn {
     pub(crate) fn new(conn_sequence: u32, conn_sign: u128) -> Self {
+        // The server is not known yet, so prefer the lower well-known port
+        let (low_port, high_port) = (conn_sign as u16, (conn_sign >> 48) as u16);
+        let app = AppInfo {
+            port_label: port_label(low_port.min(high_port)).or(port_label(low_port.max(high_port))),
+            ..AppInfo::default()
+        };
         Self {
             state: ConnState::Created,
+            closed_ts: None,
+            last_packet_ts: Duration::ZERO,
+            syn_packet_ts: None,
+            initiator: None,
+            syn_retransmits: 0,
+            syn_ack_retransmits: 0,
             start_time: Instant::now(),
             conn_sequence,
             conn_sign,
+            v6_addresses: None,
+            encapsulation: Encapsulation::default(),
+            conn_id: 0,
             flow_src_low: FlowBuff::new(),
             flow_src_high: FlowBuff::new(),
+            history: PacketHistory::new(0),
+            app,
+            analyzers: Vec::new(),
+            handshake_rtt: None,
+            syn_ack_packet_ts: None,
+            client_rtt: None,
+            first_request_ts: None,
+            ttfb: None,
+            alerts_fired: 0,
+            stats_only: false,
+            checksum_errors: 0,
+            features: None,
+            frames: None,
+        }
+    }
+
+    /// Write the connection to a snapshot, including both flows.
+    /// The start time is saved as the connection age, since [Instant] has no absolute value.
+    pub(crate) fn write_snapshot<W: Write>(&self, writer: &mut SnapshotWriter<W>, with_payload: bool) -> Result<(), Error> {
+        writer.put_u128(self.conn_sign)?;
+        // The signature of an IPv6 connection is followed by its addresses
+        if let Some((low, high)) = self.v6_addresses {
+            for addr in [low, high] {
+                writer.put_u128(u128::from(*addr.ip()))?;
+                writer.put_u16(addr.port())?;
+            }
         }
+        self.encapsulation.write_snapshot(writer)?;
+        writer.put_u32(self.conn_sequence)?;
+        writer.put_u64(self.conn_id)?;
+        writer.put_u64(self.start_time.elapsed().as_millis() as u64)?;
+        self.state.write_snapshot(writer)?;
+        writer.put_u32(self.syn_retransmits)?;
+        writer.put_u32(self.syn_ack_retransmits)?;
+        self.app.tags.write_snapshot(writer)?;
+        writer.put_bytes(self.app.server_name.as_deref().unwrap_or_default().as_bytes())?;
+        self.flow_src_low.write_snapshot(writer, with_payload)?;
+        self.flow_src_high.write_snapshot(writer, with_payload)
+    }
+
+    /// Restore a connection that was written by [Conn::write_snapshot].
+    pub(crate) fn read_snapshot<R: Read>(reader: &mut SnapshotReader<R>) -> Result<Self, Error> {
+        let conn_sign = reader.get_u128()?;
+        let v6_addresses = if conn_sign & SIGN_IPV6_BIT == 0 { None } else {
+            let mut read_addr = || -> Result<SocketAddrV6, Error> {
+                let ip = Ipv6Addr::from(reader.get_u128()?);
+                Ok(SocketAddrV6::new(ip, reader.get_u16()?, 0, 0))
+            };
+            Some((read_addr()?, read_addr()?))
+        };
+        let encapsulation = Encapsulation::read_snapshot(reader)?;
+        let conn_sequence = reader.get_u32()?;
+        let conn_id = reader.get_u64()?;
+        let age = Duration::from_millis(reader.get_u64()?);
+        let mut conn = Conn::new(conn_sequence, conn_sign);
+        conn.v6_addresses = v6_addresses;
+        conn.encapsulation = encapsulation;
+        conn.conn_id = conn_id;
+        conn.start_time = Instant::now().checked_sub(age).unwrap_or(conn.start_time);
+        conn.state = ConnState::read_snapshot(reader)?;
+        conn.syn_retransmits = reader.get_u32()?;
+        conn.syn_ack_retransmits = reader.get_u32()?;
+        conn.app.tags = ConnTags::read_snapshot(reader)?;
+        let server_name = reader.get_bytes(MAX_SERVER_NAME_LEN)?;
+        if !server_name.is_empty() {
+            conn.app.server_name = Some(String::from_utf8_lossy(&server_name).into_owned());
+        }
+        conn.restore_initiator();
+        conn.flow_src_low = FlowBuff::read_snapshot(reader)?;
+        conn.flow_src_high = FlowBuff::read_snapshot(reader)?;
+        Ok(conn)
+    }
+
+    /// JSON representation of the connection and both its flows, for debug dumps
+    pub(crate) fn to_json(&self, payload: &PayloadExport, time_format: &TimeFormatter) -> JsonValue {
+        JsonValue::object(vec![
+            ("sequence", self.conn_sequence.into()),
+            ("id", self.id_as_str().into()),
+            ("family", self.address_family().into()),
+            ("low", self.addresses().0.to_string().into()),
+            ("high", self.addresses().1.to_string().into()),
+            ("vlan", self.encapsulation.vlan.into()),
+            ("tunnel_id", self.encapsulation.tunnel_id.into()),
+            ("outer_tunnel", self.encapsulation.outer_tunnel.map_or(JsonValue::Null, |tunnel| tunnel.to_json())),
+            ("age_ms", (self.start_time.elapsed().as_millis() as u64).into()),
+            ("state", self.state.to_json()),
+            ("app", self.app.label().into()),
+            ("server_name", self.app.server_name.as_deref().into()),
+            ("resolved_name", self.app.resolved_name.as_deref().into()),
+            ("tls_keys", self.app.tls_keys.into()),
+            ("tunnel", self.app.tunnel_description().into()),
+            ("metadata", self.app.metadata_json()),
+            ("commands", JsonValue::object(self.app.commands.iter().map(|(verb, count)| (verb.as_str(), (*count).into())).collect())),
+            ("http_transactions", self.app.http_transactions.iter().map(|transaction| transaction.to_json()).collect::<Vec<JsonValue>>().into()),
+            ("dns_queries", self.app.dns_queries.iter().map(|query| query.to_json()).collect::<Vec<JsonValue>>().into()),
+            ("tags", self.app.tags.to_json()),
+            ("server_rtt_us", self.handshake_rtt.map(|rtt| rtt.as_micros() as u64).into()),
+            ("client_rtt_us", self.client_rtt.map(|rtt| rtt.as_micros() as u64).into()),
+            ("syn_retransmits", self.syn_retransmits.into()),
+            ("syn_ack_retransmits", self.syn_ack_retransmits.into()),
+            ("flow_src_low", self.flow_src_low.to_json(payload, time_format)),
+            ("flow_src_high", self.flow_src_high.to_json(payload, time_format)),
+        ])
+    }
+
+    /// Restore a connection from its JSON representation, made by [Conn::to_json].
+    pub(crate) fn from_json(json: &JsonValue) -> Result<Self, Error> {
+        let parse_addr = |key: &str| -> Result<SocketAddr, Error> {
+            json.field_str(key)?.parse()
+                .map_err(|_| Error::new(ErrorKind::InvalidData, format!("Invalid address in JSON field '{}'", key)))
+        };
+        let (low, high) = (parse_addr("low")?, parse_addr("high")?);
+        let mut conn = Conn::new(json.field_u64("sequence")? as u32, Conn::sign_by_addresses(low, high));
+        conn.set_addresses(low, high);
+        conn.encapsulation = Encapsulation {
+            vlan: json.field("vlan")?.as_u64().map(|vlan| vlan as u32),
+            tunnel_id: json.field("tunnel_id")?.as_u64().map(|tunnel_id| tunnel_id as u32),
+            outer_tunnel: match json.field("outer_tunnel")? {
+                JsonValue::Null => { None }
+                tunnel => { Some(OuterTunnel::from_json(tunnel)?) }
+            },
+        };
+        let id = json.field_str("id")?;
+        conn.conn_id = u64::from_str_radix(id, 16)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, format!("Invalid connection id '{}'", id)))?;
+        let age = Duration::from_millis(json.field_u64("age_ms")?);
+        conn.start_time = Instant::now().checked_sub(age).unwrap_or(conn.start_time);
+        conn.state = ConnState::from_json(json.field("state")?)?;
+        conn.syn_retransmits = json.field_u64("syn_retransmits")? as u32;
+        conn.syn_ack_retransmits = json.field_u64("syn_ack_retransmits")? as u32;
+        conn.app.tags = ConnTags::from_json(json.field("tags")?)?;
+        conn.app.server_name = json.field("server_name")?.as_str().map(String::from);
+        conn.app.resolved_name = json.get("resolved_name").and_then(|name| name.as_str()).map(String::from);
+        let rtt = |key: &str| json.get(key).and_then(|rtt| rtt.as_u64()).map(Duration::from_micros);
+        conn.handshake_rtt = rtt("server_rtt_us");
+        conn.client_rtt = rtt("client_rtt_us");
+        conn.restore_initiator();
+        conn.flow_src_low = FlowBuff::from_json(json.field("flow_src_low")?)?;
+        conn.flow_src_high = FlowBuff::from_json(json.field("flow_src_high")?)?;
+        Ok(conn)
+    }
+
+    /// The initiator is not saved, but it is known from the state until the connection starts closing.
+    fn restore_initiator(&mut self) {
+        match &self.state {
+            ConnState::SynSent(dir, _) | ConnState::SynReceived(dir, _) | ConnState::Established(dir) |
+            ConnState::Failed(dir) => { self.initiator = Some(dir.to_owned()) }
+            _ => {}
+        }
+    }
+
+    /// The state times are not saved, so a restored connection gets a full grace period, handshake timeout or idle timeout
+    /// from the given capture time. A restored handshake is timed from it as well, including its round trip time.
+    pub(crate) fn restart_state_times(&mut self, now: Duration) {
+        self.last_packet_ts = now;
+        match &self.state {
+            ConnState::SynSent(..) | ConnState::SynReceived(..) => { self.syn_packet_ts = Some(now) }
+            ConnState::TimeWait(_) | ConnState::Closed(_) => { self.closed_ts = Some(now) }
+            _ => {}
+        }
+    }
+
+    /// Signature made of already sorted low and high addresses, as used by [Conn::sign_by_tuple].
+    /// IPv6 addresses are folded to 32 bits in the place of the IPv4 ones, so the ports stay where the flow keys expect them.
+    fn sign_by_addresses(low: SocketAddr, high: SocketAddr) -> u128 {
+        match (low.ip(), high.ip()) {
+            (IpAddr::V4(low_ip), IpAddr::V4(high_ip)) => {
+                (u32::from(low_ip) as u128) << 16 |
+                    (low.port() as u128) |
+                    (u32::from(high_ip) as u128) << 64 |
+                    (high.port() as u128) << 48
+            }
+            (low_ip, high_ip) => {
+                SIGN_IPV6_BIT |
+                    (fold_ipv6(low_ip) as u128) << 16 |
+                    (low.port() as u128) |
+                    (fold_ipv6(high_ip) as u128) << 64 |
+                    (high.port() as u128) << 48
+            }
+        }
+    }
+
+    /// Keep the full addresses of an IPv6 connection, given in the order of its signature
+    pub(crate) fn set_addresses(&mut self, low: SocketAddr, high: SocketAddr) {
+        self.v6_addresses = match (low, high) {
+            (SocketAddr::V4(_), SocketAddr::V4(_)) => { None }
+            (low, high) => { Some((to_v6(low), to_v6(high))) }
+        };
+    }
+
+    /// Signature made of IPs and ports, as used for the connection list
+    pub(crate) fn conn_sign(&self) -> u128 {
+        self.conn_sign
+    }
+
+    /// Derive the exported identifier from the capture start time, the capture time of the first packet of the connection
+    /// and its signature, instead of the sequence, that starts over with the process
+    pub(crate) fn set_id(&mut self, capture_start_ts: Duration, first_packet_ts: Duration) {
+        let flow_key = mix64(self.conn_sign as u64 ^ mix64((self.conn_sign >> 64) as u64));
+        self.conn_id = mix64(capture_start_ts.as_micros() as u64 ^ mix64(first_packet_ts.as_micros() as u64 ^ flow_key));
+    }
+
+    /// The exported identifier as 16 hex digits
+    pub fn id_as_str(&self) -> String {
+        format!("{:016x}", self.conn_id)
+    }
+
+    /// Set the max bytes that each flow holds in its buffer
+    pub(crate) fn set_buffer_limit(&mut self, limit: usize) {
+        self.flow_src_low.set_buffer_limit(limit);
+        self.flow_src_high.set_buffer_limit(limit);
     }
 
     /// Save the ISN per flow, to be used later for sequence tracing and buffering.
@@ -78,57 +789,293 @@ impl Conn {
         }
     }
 
-    /// Get the "IP:port" of the lower or higher address.
-    pub fn addresses_as_str(&self, low_address: bool) -> String {
+    /// Get the lower and higher addresses, decoded from the signature, or as kept for IPv6
+    pub fn addresses(&self) -> (SocketAddr, SocketAddr) {
+        if let Some((low, high)) = self.v6_addresses {
+            return (low.into(), high.into());
+        }
         // Each IP is 4*8=32 bits, and port is 16 bits
         // The higher IP:port gets the higher bits
-        if low_address {
-            return format!("{}.{}.{}.{}:{}", (self.conn_sign >> 40) as u8, (self.conn_sign >> 32) as u8,
-                           (self.conn_sign >> 24) as u8, (self.conn_sign >> 16) as u8, self.conn_sign as u16);
+        let low = SocketAddrV4::new(Ipv4Addr::from((self.conn_sign >> 16) as u32), self.conn_sign as u16);
+        let high = SocketAddrV4::new(Ipv4Addr::from((self.conn_sign >> 64) as u32), (self.conn_sign >> 48) as u16);
+        (low.into(), high.into())
+    }
+
+    /// "IPv4" or "IPv6"
+    pub fn address_family(&self) -> &'static str {
+        if self.v6_addresses.is_some() { "IPv6" } else { "IPv4" }
+    }
+
+    /// Whether the connection is between the given IPs, in any order
+    pub(crate) fn has_ips(&self, a: IpAddr, b: IpAddr) -> bool {
+        let (low, high) = self.addresses();
+        (low.ip() == a && high.ip() == b) || (low.ip() == b && high.ip() == a)
+    }
+
+    /// Get the lower or higher address with its family, as `IPv4 IP:port` or `IPv6 [IP]:port`
+    pub fn addresses_as_str(&self, low_address: bool) -> String {
+        let (low, high) = self.addresses();
+        format!("{} {}", self.address_family(), if low_address { low } else { high })
+    }
+
+    /// Copy of the identity, state and counters of the connection, that can be used without holding the list lock
+    pub fn summary(&self) -> ConnSummary {
+        let (low_addr, high_addr) = self.addresses();
+        ConnSummary {
+            conn_sequence: self.conn_sequence,
+            conn_id: self.id_as_str(),
+            low_addr,
+            high_addr,
+            encapsulation: self.encapsulation,
+            state: self.state.clone(),
+            initiator: self.initiator.clone(),
+            app_label: self.app.label(),
+            server_name: self.app.server_name.clone(),
+            resolved_name: self.app.resolved_name.clone(),
+            tls_keys: self.app.tls_keys,
+            tunnel: self.app.tunnel_description(),
+            metadata: self.app.metadata.clone(),
+            commands: self.app.top_commands(),
+            http_transactions: self.app.http_transactions.clone(),
+            dns_queries: self.app.dns_queries.clone(),
+            tags: self.app.tags.clone(),
+            server_rtt: self.handshake_rtt,
+            client_rtt: self.client_rtt,
+            acks_src_low: self.flow_src_low.acks.clone(),
+            acks_src_high: self.flow_src_high.acks.clone(),
+            nagle_stalls: self.flow_src_low.nagle_stalls.count + self.flow_src_high.nagle_stalls.count,
+            nagle_time_lost: self.flow_src_low.nagle_stalls.time_lost + self.flow_src_high.nagle_stalls.time_lost,
+            window_stalls_src_low: self.flow_src_low.window_stalls.clone(),
+            window_stalls_src_high: self.flow_src_high.window_stalls.clone(),
+            sack_permitted: self.flow_src_low.sack_permitted && self.flow_src_high.sack_permitted,
+            sack_loss_src_low: self.flow_src_low.sack_loss.clone(),
+            sack_loss_src_high: self.flow_src_high.sack_loss.clone(),
+            segments_src_low: self.flow_src_low.segments.clone(),
+            segments_src_high: self.flow_src_high.segments.clone(),
+            handshake_attempts: 1 + self.syn_retransmits,
+            syn_ack_retransmits: self.syn_ack_retransmits,
+            packets_src_low: self.flow_src_low.packet_count,
+            packets_src_high: self.flow_src_high.packet_count,
+            bytes_src_low: self.flow_src_low.byte_count,
+            bytes_src_high: self.flow_src_high.byte_count,
+            max_held_bytes_src_low: self.flow_src_low.max_held_bytes,
+            max_held_bytes_src_high: self.flow_src_high.max_held_bytes,
+            dropped_bytes_src_low: self.flow_src_low.dropped_bytes,
+            dropped_bytes_src_high: self.flow_src_high.dropped_bytes,
+            activity_src_low: self.flow_src_low.activity.clone(),
+            activity_src_high: self.flow_src_high.activity.clone(),
+            checksum_errors: self.checksum_errors,
+            age: self.start_time.elapsed(),
         }
-        return format!("{}.{}.{}.{}:{}", (self.conn_sign >> 88) as u8, (self.conn_sign >> 80) as u8,
-                       (self.conn_sign >> 72) as u8, (self.conn_sign >> 64) as u8, (self.conn_sign >> 56) as u16);
+    }
+
+    /// Signature made of IPs and ports, as made by [Conn::sign_by_tuple]
+    pub fn sign(&self) -> u128 {
+        self.conn_sign
     }
 
     /// Connection signature by 4-tuple, sorted by address, so both directions get the same deterministic signature
     /// Return the signature, along with the direction to be used later for statistics
-    pub fn sign_by_tuple(src_ip: Ipv4Addr, src_port: u16, dst_ip: Ipv4Addr, dst_port: u16) -> (u128, PacketDir) {
-        if src_ip < dst_ip || src_port < dst_port {
-            let sign = (u32::from_be_bytes(src_ip.octets()) as u128) << 16 |
-                (src_port as u128) |
-                (u32::from_be_bytes(dst_ip.octets()) as u128) << 64 |
-                (dst_port as u128) << 48;
-            return (sign, PacketDir::SrcLowAddr);
-        }
-        let sign = (u32::from_be_bytes(dst_ip.octets()) as u128) << 16 |
-            (dst_port as u128) |
-            (u32::from_be_bytes(src_ip.octets()) as u128) << 64 |
-            (src_port as u128) << 48;
-        return (sign, PacketDir::SrcHighAddr);
-    }
-
-    pub fn add_bytes(&mut self, tcp_seq: u32, byte_count: usize, packet_dir: &PacketDir, data: &[u8]) {
+    pub fn sign_by_tuple(src_ip: IpAddr, src_port: u16, dst_ip: IpAddr, dst_port: u16) -> (u128, PacketDir) {
+        let (src, dst) = (SocketAddr::new(src_ip, src_port), SocketAddr::new(dst_ip, dst_port));
+        if (src_ip, src_port) < (dst_ip, dst_port) {
+            return (Conn::sign_by_addresses(src, dst), PacketDir::SrcLowAddr);
+        }
+        (Conn::sign_by_addresses(dst, src), PacketDir::SrcHighAddr)
+    }
+
+    /// Summary of the handshake, given the direction that sent the SYN and the capture timestamp of the SYN/ACK
+    pub(crate) fn opened_summary(&self, syn_dir: &PacketDir, syn_ack_ts: Duration) -> OpenedSummary {
+        let (low, high) = self.addresses();
+        let (client, server, client_flow, server_flow) = match syn_dir {
+            PacketDir::SrcLowAddr => { (low, high, &self.flow_src_low, &self.flow_src_high) }
+            PacketDir::SrcHighAddr => { (high, low, &self.flow_src_high, &self.flow_src_low) }
+        };
+        OpenedSummary {
+            client,
+            server,
+            client_mss: client_flow.mss,
+            server_mss: server_flow.mss,
+            client_window_scale: client_flow.window_scale,
+            server_window_scale: server_flow.window_scale,
+            handshake_rtt: self.syn_packet_ts.and_then(|syn_ts| syn_ack_ts.checked_sub(syn_ts)),
+        }
+    }
+
+    /// The address that accepted the connection, if the initiator is known
+    pub(crate) fn server(&self) -> Option<SocketAddr> {
+        let (low, high) = self.addresses();
+        match self.initiator.as_ref()? {
+            PacketDir::SrcLowAddr => { Some(high) }
+            PacketDir::SrcHighAddr => { Some(low) }
+        }
+    }
+
+    /// Key to find the same connection on the other side of a NAT, once both SYNs were seen
+    pub(crate) fn nat_twin_key(&self) -> Option<NatTwinKey> {
+        let (low, high) = self.addresses();
+        let (client, server, client_flow, server_flow) = match self.initiator.as_ref()? {
+            PacketDir::SrcLowAddr => { (low, high, &self.flow_src_low, &self.flow_src_high) }
+            PacketDir::SrcHighAddr => { (high, low, &self.flow_src_high, &self.flow_src_low) }
+        };
+        Some((client_flow.initial_sequence_number(), server_flow.initial_sequence_number(), client.port(), server.port()))
+    }
+
+    /// Whether this connection is the later of two NAT twins, so it should not be counted again in the aggregates
+    pub(crate) fn is_nat_duplicate(&self) -> bool {
+        self.app.tags.get(NAT_TWIN_TAG).and_then(|twin| twin.parse::<u32>().ok()).is_some_and(|twin| twin < self.conn_sequence)
+    }
+
+    /// Track the last SYN/ACK and the ACK from the initiator that follows it, for the client side of the handshake round trip,
+    /// given the capture timestamp of a packet
+    pub(crate) fn track_client_rtt(&mut self, ts: Duration, packet_dir: &PacketDir, tcp: &TcpHeaderSlice) {
+        if self.client_rtt.is_some() || !tcp.ack() || tcp.rst() {
+            return;
+        }
+        let initiator = match &self.initiator {
+            None => { return; }
+            Some(initiator) => { initiator }
+        };
+        if tcp.syn() && initiator != packet_dir {
+            self.syn_ack_packet_ts = Some(ts);
+        } else if !tcp.syn() && initiator == packet_dir {
+            self.client_rtt = self.syn_ack_packet_ts.map(|syn_ack_ts| ts.saturating_sub(syn_ack_ts));
+        }
+    }
+
+    /// Track the first payload of each side, given the capture timestamp of a packet.
+    /// Return the time to first byte when this packet is the first response to the first request.
+    pub(crate) fn track_ttfb(&mut self, ts: Duration, packet_dir: &PacketDir, tcp_payload_len: u16) -> Option<Duration> {
+        if tcp_payload_len == 0 || self.ttfb.is_some() {
+            return None;
+        }
+        let initiator = self.initiator.as_ref()?;
+        match self.first_request_ts {
+            None if initiator == packet_dir => { self.first_request_ts = Some(ts) }
+            Some(request_ts) if initiator != packet_dir => { self.ttfb = Some(ts.saturating_sub(request_ts)) }
+            _ => {}
+        }
+        self.ttfb
+    }
+
+    /// Keep the packet in the history, if enabled
+    pub(crate) fn record_packet(&mut self, ts: Duration, packet_dir: &PacketDir, tcp: &TcpHeaderSlice, tcp_payload_len: u16) {
+        if !self.history.is_enabled() {
+            return;
+        }
+        let record = PacketRecord {
+            ts,
+            dir: packet_dir.to_owned(),
+            flags: tcp_flags_list(tcp),
+            seq: self.relative_seq(packet_dir, tcp.sequence_number()),
+            ack: if tcp.ack() { self.relative_ack(packet_dir, tcp.acknowledgment_number()) } else { 0 },
+            len: tcp_payload_len,
+            // The window on SYN packets is never scaled
+            window: if tcp.syn() { tcp.window_size() as u32 } else { self.scaled_window(packet_dir, tcp.window_size()) },
+        };
+        self.history.push(record);
+    }
+
+    /// Render the kept packets as a textual ladder diagram
+    pub fn ladder(&self) -> String {
+        let (low, high) = self.addresses();
+        self.history.ladder(self.conn_sequence, &low, &high)
+    }
+
+    /// Buffer and statistics of the flow sent by the given direction
+    pub fn flow(&self, packet_dir: &PacketDir) -> &FlowBuff {
         match packet_dir {
-            PacketDir::SrcLowAddr => {
-                self.flow_src_low.add_bytes(tcp_seq, byte_count, data);
+            PacketDir::SrcLowAddr => { &self.flow_src_low }
+            PacketDir::SrcHighAddr => { &self.flow_src_high }
+        }
+    }
+
+    pub(crate) fn flow_mut(&mut self, packet_dir: &PacketDir) -> &mut FlowBuff {
+        match packet_dir {
+            PacketDir::SrcLowAddr => { &mut self.flow_src_low }
+            PacketDir::SrcHighAddr => { &mut self.flow_src_high }
+        }
+    }
+
+    /// Count a SYN or SYN/ACK that repeats the ISN already seen in the handshake, which means the other side
+    /// did not get it or did not respond in time
+    pub(crate) fn count_handshake_retransmit(&mut self, packet_dir: &PacketDir, tcp: &TcpHeaderSlice) {
+        if !tcp.syn() {
+            return;
+        }
+        match &self.state {
+            ConnState::SynSent(syn_dir, expected_tcp_ack)
+            if !tcp.ack() && syn_dir == packet_dir && tcp.sequence_number().wrapping_add(1) == *expected_tcp_ack => {
+                self.syn_retransmits += 1;
             }
-            PacketDir::SrcHighAddr => {
-                self.flow_src_high.add_bytes(tcp_seq, byte_count, data);
+            ConnState::Established(syn_dir)
+            if tcp.ack() && syn_dir != packet_dir && tcp.sequence_number() == self.flow(packet_dir).initial_sequence_number() => {
+                self.syn_ack_retransmits += 1;
+            }
+            _ => {}
+        }
+    }
+
+    /// Count a packet and buffer its payload in the flow of the given direction, adding the related events.
+    /// The data is the TCP payload alone, or empty if it is not buffered, see [FlowBuff::add_bytes].
+    /// When the contiguous bytes of the flow first reach the given number, a buffer ready event is added too.
+    pub fn add_bytes(&mut self, tcp_seq: u32, byte_count: usize, packet_dir: &PacketDir, data: &[u8], ready_bytes: usize,
+                     events: &mut Vec<ConnEvent>) -> Result<(), error::Error> {
+        let flow = match packet_dir {
+            PacketDir::SrcLowAddr => { &mut self.flow_src_low }
+            PacketDir::SrcHighAddr => { &mut self.flow_src_high }
+        };
+        let anomaly = flow.add_bytes(tcp_seq, byte_count, data)?;
+        let became_ready = flow.signal_ready(ready_bytes);
+        if !self.analyzers.is_empty() {
+            let limit = self.analyzers.iter().map(|analyzer| analyzer.max_bytes()).max().unwrap_or_default();
+            if let Some(bytes) = flow.next_unanalyzed(limit) {
+                let app = &mut self.app;
+                self.analyzers.retain_mut(|analyzer| analyzer.feed(packet_dir, bytes, app));
             }
         }
+        if let Some(anomaly) = anomaly {
+            events.push(ConnEvent::Anomaly { conn_sequence: self.conn_sequence, dir: packet_dir.to_owned(), anomaly });
+        }
+        if byte_count > 0 {
+            events.push(ConnEvent::DataBuffered { conn_sequence: self.conn_sequence, dir: packet_dir.to_owned(), bytes: byte_count });
+        }
+        if became_ready {
+            events.push(ConnEvent::BufferReady { conn_sequence: self.conn_sequence, dir: packet_dir.to_owned() });
+        }
+        Ok(())
+    }
+
+    /// Answer if one of the directions has a significant buffer ready to process, or if the connection is closed
+    /// and has something to process
+    pub(crate) fn has_ready_buffer(&self, closed_connection: bool, min_ready_bytes: usize) -> bool {
+        self.flow_src_low.has_ready_buffer(closed_connection, min_ready_bytes) ||
+            self.flow_src_high.has_ready_buffer(closed_connection, min_ready_bytes)
     }
 
-    /// Check if this connection has bytes ready to process in one of the directions.
-    /// This means that at least the number of requested bytes are present in a buffer from the current position.
-    pub(crate) fn has_ready_bytes(&self, min_ready_bytes: usize) -> bool {
-        return self.flow_src_low.has_ready_bytes(min_ready_bytes) || self.flow_src_high.has_ready_bytes(min_ready_bytes);
+    /// Number of bytes of both directions that were buffered and not consumed yet
+    pub(crate) fn held_bytes(&self) -> usize {
+        self.flow_src_low.held_bytes() + self.flow_src_high.held_bytes()
     }
 
-    /// Get a direction that has a significant buffer ready to process, or if the connection is closed and has something to process.
-    pub(crate) fn pop_ready_buffer(&self, closed_connection: bool, min_ready_bytes: usize) -> Option<&FlowBuff> {
-        if self.flow_src_low.has_ready_buffer(closed_connection, min_ready_bytes) { return Some(&self.flow_src_low); }
-        if self.flow_src_high.has_ready_buffer(closed_connection, min_ready_bytes) { return Some(&self.flow_src_high); }
-        return None;
+    /// Directions that have any contiguous bytes that were not consumed yet
+    pub(crate) fn pending_dirs(&self) -> Vec<PacketDir> {
+        let mut result = Vec::new();
+        if self.flow_src_low.ready_len() > 0 { result.push(PacketDir::SrcLowAddr); }
+        if self.flow_src_high.ready_len() > 0 { result.push(PacketDir::SrcHighAddr); }
+        result
+    }
+
+    /// Take the ready bytes of a direction that has a significant buffer ready to process,
+    /// or if the connection is closed and has something to process. The taken bytes are marked as consumed.
+    pub(crate) fn take_ready_buffer(&mut self, closed_connection: bool, min_ready_bytes: usize) -> Option<(PacketDir, Vec<u8>)> {
+        if self.flow_src_low.has_ready_buffer(closed_connection, min_ready_bytes) {
+            return Some((PacketDir::SrcLowAddr, self.flow_src_low.take_ready_bytes()));
+        }
+        if self.flow_src_high.has_ready_buffer(closed_connection, min_ready_bytes) {
+            return Some((PacketDir::SrcHighAddr, self.flow_src_high.take_ready_bytes()));
+        }
+        None
     }
 
     fn relative_seq(&self, packet_dir: &PacketDir, seq: u32) -> u64 {
@@ -149,15 +1096,169 @@ impl Conn {
         flow.relative_seq(ack)
     }
 
+    /// The window is advertised by the sender, so it is scaled by the factor that the sender announced on its SYN
     fn scaled_window(&self, packet_dir: &PacketDir, window: u16) -> u32 {
-        match packet_dir {
-            PacketDir::SrcLowAddr => { self.flow_src_high.scaled_window(window) }
-            _ => { self.flow_src_low.scaled_window(window) }
+        self.flow(packet_dir).scaled_window(window)
+    }
+
+    /// Check that the sender of an ACK does not move its advertised window edge backwards, adding an anomaly event if it does.
+    /// The window on SYN packets is never scaled, so these are skipped.
+    pub(crate) fn check_window(&mut self, packet_dir: &PacketDir, tcp: &TcpHeaderSlice, events: &mut Vec<ConnEvent>) {
+        if !tcp.ack() || tcp.syn() || tcp.rst() {
+            return;
+        }
+        let relative_ack = self.relative_ack(packet_dir, tcp.acknowledgment_number());
+        let window = self.scaled_window(packet_dir, tcp.window_size());
+        let flow = match packet_dir {
+            PacketDir::SrcLowAddr => { &mut self.flow_src_low }
+            PacketDir::SrcHighAddr => { &mut self.flow_src_high }
+        };
+        if window == 0 {
+            flow.zero_window_count += 1;
+        }
+        if let Some(anomaly) = flow.track_window_edge(relative_ack, window) {
+            events.push(ConnEvent::Anomaly { conn_sequence: self.conn_sequence, dir: packet_dir.to_owned(), anomaly });
+        }
+    }
+
+    /// Count the ACK of a packet for its sender, and its data segment for the other side, for the ACK patterns
+    pub(crate) fn track_acks(&mut self, packet_dir: &PacketDir, tcp: &TcpHeaderSlice, tcp_payload_len: u16) {
+        if tcp.ack() && !tcp.syn() && !tcp.rst() {
+            let relative_ack = self.relative_ack(packet_dir, tcp.acknowledgment_number());
+            self.flow_mut(packet_dir).acks.add_ack(relative_ack);
+        }
+        if tcp_payload_len > 0 {
+            let other_dir = match packet_dir { PacketDir::SrcLowAddr => { PacketDir::SrcHighAddr }, _ => { PacketDir::SrcLowAddr } };
+            self.flow_mut(&other_dir).acks.add_segment();
+        }
+    }
+
+    /// Match a data segment with the holes that the other side reported by SACK, and count the holes that the SACK
+    /// blocks of the packet report in the data of the other side
+    pub(crate) fn track_sack(&mut self, packet_dir: &PacketDir, tcp: &TcpHeaderSlice, tcp_payload_len: u16) {
+        if tcp.syn() || tcp.rst() {
+            return;
+        }
+        if tcp_payload_len > 0 {
+            let start = self.relative_seq(packet_dir, tcp.sequence_number());
+            self.flow_mut(packet_dir).sack_loss.add_segment(start..start + tcp_payload_len as u64);
+        }
+        if !tcp.ack() {
+            return;
+        }
+        for option in tcp.options_iterator() {
+            if let Ok(TcpOptionElement::SelectiveAcknowledgement(first, rest)) = option {
+                let blocks = std::iter::once(first).chain(rest.into_iter().flatten())
+                    .map(|(left, right)| self.relative_ack(packet_dir, left)..self.relative_ack(packet_dir, right))
+                    .collect();
+                let relative_ack = self.relative_ack(packet_dir, tcp.acknowledgment_number());
+                let other_dir = match packet_dir { PacketDir::SrcLowAddr => { PacketDir::SrcHighAddr }, _ => { PacketDir::SrcLowAddr } };
+                self.flow_mut(&other_dir).sack_loss.add_sack(relative_ack, blocks);
+            }
+        }
+    }
+
+    /// Track the small writes of the sender of a packet, and the ACKs of the other side, for Nagle and delayed ACK stalls
+    pub(crate) fn track_nagle_stalls(&mut self, ts: Duration, packet_dir: &PacketDir, tcp: &TcpHeaderSlice, tcp_payload_len: u16) {
+        if tcp.syn() || tcp.rst() {
+            return;
+        }
+        let other_dir = match packet_dir { PacketDir::SrcLowAddr => { PacketDir::SrcHighAddr }, _ => { PacketDir::SrcLowAddr } };
+        if tcp.ack() {
+            let relative_ack = self.relative_ack(packet_dir, tcp.acknowledgment_number());
+            self.flow_mut(&other_dir).nagle_stalls.add_peer_packet(ts, relative_ack, tcp_payload_len > 0);
+        }
+        if tcp_payload_len > 0 {
+            let end_seq = self.relative_seq(packet_dir, tcp.sequence_number()) + tcp_payload_len as u64;
+            let peer_mss = self.flow(&other_dir).mss;
+            self.flow_mut(packet_dir).nagle_stalls.add_segment(ts, end_seq, tcp_payload_len, peer_mss);
+        }
+    }
+
+    /// Track the windows that the sender of a packet advertises, and whether its data fills the window of the other side
+    pub(crate) fn track_window_stalls(&mut self, ts: Duration, packet_dir: &PacketDir, tcp: &TcpHeaderSlice, tcp_payload_len: u16) {
+        if tcp.syn() || tcp.rst() {
+            return;
+        }
+        if tcp.ack() {
+            let relative_ack = self.relative_ack(packet_dir, tcp.acknowledgment_number());
+            let window = self.scaled_window(packet_dir, tcp.window_size());
+            self.flow_mut(packet_dir).window_stalls.add_window(ts, relative_ack, window);
+        }
+        if tcp_payload_len > 0 {
+            let other_dir = match packet_dir { PacketDir::SrcLowAddr => { PacketDir::SrcHighAddr }, _ => { PacketDir::SrcLowAddr } };
+            let end_seq = self.relative_seq(packet_dir, tcp.sequence_number()) + tcp_payload_len as u64;
+            self.flow_mut(&other_dir).window_stalls.add_peer_segment(ts, end_seq);
+        }
+    }
+
+    /// Current value of a metric that alert rules check, or None if it is not known (yet)
+    pub(crate) fn alert_metric(&self, metric: &AlertMetric) -> Option<f64> {
+        let (low, high) = (&self.flow_src_low, &self.flow_src_high);
+        match metric {
+            AlertMetric::RetransmitPercent => {
+                let data_packets = low.data_packet_count + high.data_packet_count;
+                if data_packets < RETRANSMIT_RATE_MIN_PACKETS {
+                    return None;
+                }
+                Some((low.segments.retransmitted + high.segments.retransmitted) as f64 * 100.0 / data_packets as f64)
+            }
+            AlertMetric::HandshakeRttMs => { self.handshake_rtt.map(|rtt| rtt.as_secs_f64() * 1000.0) }
+            AlertMetric::ZeroWindows => { Some((low.zero_window_count + high.zero_window_count) as f64) }
+            AlertMetric::NagleStallMs => { Some((low.nagle_stalls.time_lost + high.nagle_stalls.time_lost).as_secs_f64() * 1000.0) }
+        }
+    }
+
+    /// Add an alert event for every rule whose metric crossed its threshold, once per rule
+    pub(crate) fn check_alerts(&mut self, rules: &[AlertRule], events: &mut Vec<ConnEvent>) {
+        for (index, rule) in rules.iter().enumerate().take(MAX_ALERT_RULES) {
+            if self.alerts_fired & (1 << index) != 0 {
+                continue;
+            }
+            if let Some(value) = self.alert_metric(&rule.metric).filter(|value| *value > rule.threshold) {
+                self.alerts_fired |= 1 << index;
+                // Every rule that fired is listed in one tag, so the record can be filtered by "alert"
+                let fired = match self.app.tags.get("alert") {
+                    None => { rule.to_string() }
+                    Some(previous) => { format!("{},{}", previous, rule) }
+                };
+                self.app.tags.set("alert", &fired);
+                events.push(ConnEvent::Alert { conn_sequence: self.conn_sequence, alert: Alert { rule: rule.clone(), value } });
+            }
+        }
+    }
+
+    /// Check the options of both SYNs once the handshake completed, adding an anomaly event for those that look rewritten
+    /// by a middlebox: an MSS that does not fit in the MTU of the capture interface (if known), window scaling that only
+    /// one side announced, which then is not in effect, and a window scale above the limit.
+    pub(crate) fn check_handshake_options(&mut self, mtu: Option<u16>, events: &mut Vec<ConnEvent>) {
+        let shifts = (self.flow_src_low.announced_window_shift, self.flow_src_high.announced_window_shift);
+        let mut anomalies = Vec::new();
+        for dir in [PacketDir::SrcLowAddr, PacketDir::SrcHighAddr] {
+            let flow = self.flow(&dir);
+            if let Some(mtu) = mtu.filter(|mtu| flow.mss > mtu.saturating_sub(MSS_HEADERS_LEN)) {
+                anomalies.push((dir.to_owned(), Anomaly::MssAboveMtu { mss: flow.mss, mtu }));
+            }
+            if let Some(shift) = flow.announced_window_shift {
+                if shift > MAX_WINDOW_SHIFT {
+                    anomalies.push((dir.to_owned(), Anomaly::WindowScaleAboveLimit { shift }));
+                }
+                if shifts.0.is_none() || shifts.1.is_none() {
+                    anomalies.push((dir.to_owned(), Anomaly::WindowScaleOneSided { shift }));
+                }
+            }
+        }
+        if shifts.0.is_none() || shifts.1.is_none() {
+            self.flow_src_low.window_scale = 1;
+            self.flow_src_high.window_scale = 1;
+        }
+        for (dir, anomaly) in anomalies {
+            events.push(ConnEvent::Anomaly { conn_sequence: self.conn_sequence, dir, anomaly });
         }
     }
 
     /// Process TCP options. To be called when detecting a proper SYN packet.
-    /// For now, it only looks for window scaling for later display.
+    /// Keeps the MSS, the window scaling and whether SACK is permitted, of the side that sent the SYN.
     pub(crate) fn process_tcp_options(&mut self, packet_dir: &PacketDir, tcp: &TcpHeaderSlice) {
         let flow = match packet_dir {
             PacketDir::SrcLowAddr => { &mut self.flow_src_low }
@@ -168,13 +1269,16 @@ impl Conn {
             match option {
                 Ok(element) => {
                     match element {
-                        TcpOptionElement::MaximumSegmentSize(_) => {
-                            //TODO save MSS and use it when opening connections
+                        TcpOptionElement::MaximumSegmentSize(mss) => {
+                            flow.mss = mss;
                         }
                         TcpOptionElement::WindowScale(window_scale) => {
-                            if window_scale >= 1 && window_scale <= 14 {
-                                flow.window_scale = 2u16.pow(window_scale as u32);
-                            }
+                            flow.announced_window_shift = Some(window_scale);
+                            // A shift above the limit is taken as the limit (RFC 7323)
+                            flow.window_scale = 2u16.pow(window_scale.min(MAX_WINDOW_SHIFT) as u32);
+                        }
+                        TcpOptionElement::SelectiveAcknowledgementPermitted => {
+                            flow.sack_permitted = true;
                         }
                         _ => {}
                     }
@@ -188,7 +1292,7 @@ impl Conn {
         let log_level: Option<Level>;
         // Determine log level by connection's state
         match self.state {
-            ConnState::Established(_) => {
+            ConnState::Established(_) | ConnState::EstablishedMidstream => {
                 // If it was just established now by one of the parties
                 if tcp.syn() {
                     log_level = Some(Level::Debug);
@@ -232,4 +1336,19 @@ impl Conn {
                                          self);
         }
     }
-}
\ No newline at end of file
+}
+/// An IPv6 address folded to 32 bits for the signature, with an IPv4 address taken as IPv4-mapped
+pub(crate) fn fold_ipv6(ip: IpAddr) -> u32 {
+    let ip = match ip {
+        IpAddr::V4(ip) => { u128::from(ip.to_ipv6_mapped()) }
+        IpAddr::V6(ip) => { u128::from(ip) }
+    };
+    mix64(ip as u64 ^ mix64((ip >> 64) as u64)) as u32
+}
+
+fn to_v6(addr: SocketAddr) -> SocketAddrV6 {
+    match addr {
+        SocketAddr::V4(addr) => { SocketAddrV6::new(addr.ip().to_ipv6_mapped(), addr.port(), 0, 0) }
+        SocketAddr::V6(addr) => { addr }
+    }
+}
@@ -0,0 +1,130 @@
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Error, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use log::error;
+use pcap::Packet;
+use crate::conn::Conn;
+use crate::flight_recorder::{write_pcap_frame, write_pcap_header, RingFrame, PCAP_HEADER_LEN};
+
+/// Bytes of frames that a connection keeps before they are appended to its file
+const CONN_PCAP_FLUSH_BYTES: usize = 256 * 1024;
+
+/// Frames of a connection that were not written to its pcap file yet, and how far its current file is
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ConnFrames {
+    frames: Vec<RingFrame>,
+    /// Bytes of captured data of the kept frames
+    bytes: usize,
+    /// Index of the current file of the connection, where the first one has no suffix
+    file_index: u32,
+    /// Bytes written to the current file, or 0 if it was not created yet
+    file_bytes: u64,
+}
+
+impl ConnFrames {
+    pub(crate) fn push(&mut self, packet: &Packet) {
+        let frame = RingFrame::new(packet);
+        self.bytes += frame.data_len();
+        self.frames.push(frame);
+    }
+
+    /// Whether enough frames are kept to be appended to the file
+    pub(crate) fn is_due(&self) -> bool {
+        self.bytes >= CONN_PCAP_FLUSH_BYTES
+    }
+}
+
+/// Writes the frames of every connection to its own pcap file in a directory, named by its sequence and addresses as
+/// "conn_SEQ_LOWIP_LOWPORT_HIGHIP_HIGHPORT.pcap", and rotated to files with a "-1", "-2", ... suffix when a file reaches
+/// the size limit. The frames are kept by their connection and appended in batches, and when the connection is removed,
+/// so the files are not held open. A failure to write is logged once and stops the extraction.
+pub struct ConnPcap {
+    dir: PathBuf,
+    max_file_bytes: u64,
+    file_count: u64,
+    frame_count: u64,
+    failed: bool,
+}
+
+impl ConnPcap {
+    /// Create the directory if it does not exist
+    pub fn create(dir: &Path, max_file_bytes: u64) -> Result<Self, Error> {
+        fs::create_dir_all(dir)?;
+        Ok(ConnPcap { dir: dir.to_path_buf(), max_file_bytes, file_count: 0, frame_count: 0, failed: false })
+    }
+
+    /// Append the frames that the connection kept to its files
+    pub(crate) fn write(&mut self, conn: &mut Conn) {
+        let addresses = conn.addresses();
+        let conn_sequence = conn.conn_sequence;
+        let frames = match &mut conn.frames {
+            None => { return; }
+            Some(frames) => { frames }
+        };
+        if self.failed {
+            conn.frames = None;
+            return;
+        }
+        if let Err(error) = self.append(conn_sequence, addresses, frames) {
+            error!("Failed to write the frames of connection {} to {}, per connection files are stopped: {}",
+                conn_sequence, self.dir.display(), error);
+            self.failed = true;
+            conn.frames = None;
+        }
+    }
+
+    fn append(&mut self, conn_sequence: u32, addresses: (SocketAddr, SocketAddr), frames: &mut ConnFrames) -> Result<(), Error> {
+        let mut writer: Option<BufWriter<File>> = None;
+        frames.bytes = 0;
+        for frame in std::mem::take(&mut frames.frames) {
+            // A file has at least one frame, even if it is larger than the limit
+            if frames.file_bytes > PCAP_HEADER_LEN && frames.file_bytes + frame.pcap_len() > self.max_file_bytes {
+                if let Some(mut writer) = writer.take() {
+                    writer.flush()?;
+                }
+                frames.file_index += 1;
+                frames.file_bytes = 0;
+            }
+            if writer.is_none() {
+                let path = self.dir.join(file_name(conn_sequence, addresses, frames.file_index));
+                if frames.file_bytes == 0 {
+                    let mut file = BufWriter::new(File::create(path)?);
+                    write_pcap_header(&mut file)?;
+                    frames.file_bytes = PCAP_HEADER_LEN;
+                    self.file_count += 1;
+                    writer = Some(file);
+                } else {
+                    writer = Some(BufWriter::new(OpenOptions::new().append(true).open(path)?));
+                }
+            }
+            if let Some(writer) = &mut writer {
+                write_pcap_frame(writer, &frame)?;
+            }
+            frames.file_bytes += frame.pcap_len();
+            self.frame_count += 1;
+        }
+        match writer {
+            None => { Ok(()) }
+            Some(mut writer) => { writer.flush() }
+        }
+    }
+}
+
+impl fmt::Display for ConnPcap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} frames in {} files in {}", self.frame_count, self.file_count, self.dir.display())?;
+        if self.failed {
+            write!(f, ", stopped after a failure")?;
+        }
+        Ok(())
+    }
+}
+
+/// Name of a file of a connection, with the colons of IPv6 addresses replaced by dashes
+fn file_name(conn_sequence: u32, (low, high): (SocketAddr, SocketAddr), file_index: u32) -> String {
+    let endpoint = |addr: SocketAddr| format!("{}_{}", addr.ip().to_string().replace(':', "-"), addr.port());
+    let suffix = if file_index == 0 { String::new() } else { format!("-{}", file_index) };
+    format!("conn_{}_{}_{}{}.pcap", conn_sequence, endpoint(low), endpoint(high), suffix)
+}
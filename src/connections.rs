@@ -1,11 +1,313 @@
 use std::collections::hash_map::Entry::{Occupied, Vacant};
-use std::collections::HashMap;
-use log::{warn};
-use etherparse::{InternetSlice, SlicedPacket, TransportSlice};
-use pcap::Packet;
-use crate::conn::Conn;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
+use log::{debug, info, warn};
+use etherparse::{InternetSlice, ip_number, SlicedPacket, TcpHeaderSlice, TransportSlice};
+use pcap::{Packet, PacketHeader};
+use crate::alert::{AlertRule, MAX_ALERT_RULES};
+use crate::analyzer::{AnalyzerConfig, dns_answer_addresses};
+use crate::arp::{arp_payload, ArpTable};
+use crate::classifier::{Classifier, ConnClassifier};
+use crate::conn::{Conn, ConnSummary, NAT_TWIN_TAG, NatTwinKey, OpenedSummary, PacketDir};
 use crate::conn::ConnState;
-use crate::flow_buff::FlowBuff;
+use crate::conn_pcap::{ConnFrames, ConnPcap};
+use crate::digest::PayloadExport;
+use crate::dns_cache::DnsCache;
+use crate::features::{csv_row, FEATURES_CSV_HEADER};
+use crate::error;
+use crate::event::{ConnEvent, IgnoreReason};
+use crate::flow_buff::{DEFAULT_FLOW_BUFFER_LIMIT, FlowOverflow};
+use crate::flow_key::{decapsulate, Encapsulation, FlowKeyer};
+use crate::flow_key;
+use crate::flow_log::{FlowLog, FlowLogEvent};
+use crate::heatmap::{LatencyHeatmap, LatencyMetric};
+use crate::hosts::HostTable;
+use crate::ignore::{IgnoredFlows, IgnoreRule, raw_conn_sign};
+use crate::ipv4::{IPV4_MIN_HEADER_LEN, Ipv4Datagram, Ipv4Defragmenter, Ipv4FragmentStats};
+use crate::ipv6::{IPV6_HEADER_LEN, ipv6_offset, Ipv6Stats, Ipv6Walker, raw_vlan_id, tunneled_ipv6, UpperLayer};
+use crate::history::{dir_label, PacketHistory, TIME_SEQUENCE_CSV_HEADER};
+use crate::json::JsonValue;
+use crate::keylog::{CLIENT_RANDOM_LEN, KeyLog};
+use crate::latency::LatencyTracker;
+use crate::leak::{ConnSample, TableSample};
+use crate::observer::{CloseReason, ConnectionObserver};
+use crate::rate_limit::ConnRateLimiter;
+use crate::shards::shard_of_ips;
+use crate::snapshot::{SNAPSHOT_MAGIC, SNAPSHOT_VERSION, SnapshotReader, SnapshotWriter};
+use crate::syn_flood::SynFloodDetector;
+use crate::tags::TagRule;
+use crate::time_format::TimeFormatter;
+use crate::utils::{ipv4_header_checksum_ok, mix64, printable_preview, tcp_checksum_ok};
+
+/// Tag of the connections that were reset or retransmitted shortly after an ARP event of one of their IPs, set to the IP
+const ARP_TAG: &str = "arp";
+/// Source port of the DNS responses to learn the resolved names from
+const DNS_PORT: u16 = 53;
+/// Keys to try for a connection whose flow key is held by a connection of other IPv6 addresses
+const MAX_KEY_PROBES: u64 = 8;
+/// Length of the UDP header, before the payload
+const UDP_HEADER_LEN: usize = 8;
+
+/// Header line of the retransmission timeline CSV export
+pub const RETRANSMITS_CSV_HEADER: &str = "conn,id,time,dir,seq,len";
+
+/// A closed connection that was removed from the list, with the bytes that were still buffered and not consumed yet
+pub struct ReapedConn {
+    pub summary: ConnSummary,
+    pub remaining: Vec<(PacketDir, Vec<u8>)>,
+    /// Feature vector as a CSV row, if features are tracked
+    pub features: Option<String>,
+    /// Time without packets, if the connection was evicted for going idle rather than closed
+    pub idle: Option<Duration>,
+}
+
+/// How long a connection is kept by its state before it is removed, where zero keeps it until it closes
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ConnTimeouts {
+    /// Time without packets for connections whose handshake was not seen, such as ones that were open before the capture
+    pub idle: Duration,
+    /// Time without packets for established connections
+    pub established: Duration,
+    /// Time without packets for connections that started closing and did not finish
+    pub closing: Duration,
+    /// Time to keep a closed connection, so late packets are still attributed to it
+    pub closed: Duration,
+    /// Time to wait for the answer to a SYN, for [Connections::expire_handshakes]
+    pub handshake: Duration,
+}
+
+impl ConnTimeouts {
+    /// The time without packets after which a connection in the given state is evicted, if any.
+    /// Handshakes have their own timeout, and closed connections are kept from the time they closed.
+    fn idle_timeout(&self, state: &ConnState) -> Option<Duration> {
+        let timeout = match state {
+            ConnState::Created => { self.idle }
+            ConnState::Established(_) | ConnState::EstablishedMidstream => { self.established }
+            ConnState::FinWait1(..) | ConnState::CloseWait(_) | ConnState::Closing(..) | ConnState::LastAck(..) => { self.closing }
+            ConnState::SynSent(..) | ConnState::SynReceived(..) | ConnState::TimeWait(_) | ConnState::Closed(_) |
+            ConnState::Failed(_) => { Duration::ZERO }
+        };
+        if timeout.is_zero() { None } else { Some(timeout) }
+    }
+}
+
+/// Bytes that were buffered and not consumed yet, now and at their peak, to tune the ready threshold, the number of
+/// consumers and the memory limits from real traffic
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BufferWatermarks {
+    /// Bytes held by all the connections in the list
+    pub held_bytes: usize,
+    /// Most bytes held by all the connections at any time
+    pub max_held_bytes: usize,
+    /// Most bytes held by a single flow at any time, including flows that were removed
+    pub max_flow_held_bytes: usize,
+    /// Payload bytes that were not buffered since their flow was at its buffer limit, including flows that were removed
+    pub dropped_bytes: u64,
+}
+
+impl BufferWatermarks {
+    /// Add the bytes of another shard of the connection table. The peaks of the shards are added as well, so the total
+    /// peak is an upper bound.
+    pub fn merge(&mut self, other: &BufferWatermarks) {
+        self.held_bytes += other.held_bytes;
+        self.max_held_bytes += other.max_held_bytes;
+        self.max_flow_held_bytes = self.max_flow_held_bytes.max(other.max_flow_held_bytes);
+        self.dropped_bytes += other.dropped_bytes;
+    }
+}
+
+impl fmt::Display for BufferWatermarks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} bytes held, max {} bytes in total, max {} bytes in one flow, {} bytes dropped over the flow limit",
+               self.held_bytes, self.max_held_bytes, self.max_flow_held_bytes, self.dropped_bytes)
+    }
+}
+
+/// Share of the buffering budget in percent, from which new connections are not buffered
+const BUDGET_ADMISSION_PERCENT: usize = 90;
+
+/// Cap on the bytes buffered by all the connections together. New connections are only counted (stats-only) when the
+/// budget is nearly exhausted, and connections that hold more than their share are demoted to stats-only when it is.
+#[derive(Clone, Copy, Debug)]
+pub struct BufferBudget {
+    /// Max bytes held by all the connections
+    pub limit: usize,
+    /// Number of new connections that started in stats-only mode
+    pub stats_only_conns: u64,
+    /// Number of connections that stopped buffering when the budget was exhausted
+    pub demoted_conns: u64,
+}
+
+impl BufferBudget {
+    /// Held bytes from which new connections are not buffered
+    fn admission_bytes(&self) -> usize {
+        self.limit / 100 * BUDGET_ADMISSION_PERCENT
+    }
+
+    /// Add the limit and the counters of the budget of another shard of the connection table
+    pub fn merge(&mut self, other: &BufferBudget) {
+        self.limit += other.limit;
+        self.stats_only_conns += other.stats_only_conns;
+        self.demoted_conns += other.demoted_conns;
+    }
+}
+
+impl fmt::Display for BufferBudget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "limit {} bytes, {} new connections not buffered, {} connections demoted", self.limit,
+               self.stats_only_conns, self.demoted_conns)
+    }
+}
+
+/// Payload bytes of connections by the side that sent them
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SideBytes {
+    /// Bytes sent by the initiators
+    pub client: u64,
+    /// Bytes sent by the other sides
+    pub server: u64,
+    /// Bytes sent by either side of connections whose initiator was not seen, such as ones open before the capture
+    pub unknown: u64,
+}
+
+impl SideBytes {
+    fn add_conn(&mut self, conn: &Conn) {
+        let (low, high) = (conn.flow_src_low.byte_count, conn.flow_src_high.byte_count);
+        match &conn.initiator {
+            None => { self.unknown += low + high }
+            Some(PacketDir::SrcLowAddr) => {
+                self.client += low;
+                self.server += high;
+            }
+            Some(PacketDir::SrcHighAddr) => {
+                self.client += high;
+                self.server += low;
+            }
+        }
+    }
+
+    fn merge(&mut self, other: &SideBytes) {
+        self.client += other.client;
+        self.server += other.server;
+        self.unknown += other.unknown;
+    }
+}
+
+/// Totals of a connection table since it started, for the final report
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TableReport {
+    pub conn_alltime_count: u32,
+    /// Connections that are still in the table
+    pub open_conns: usize,
+    pub evicted_count: u64,
+    pub packet_count: u64,
+    /// Payload bytes of all the connections, including the removed ones
+    pub bytes: SideBytes,
+    pub packet_len_error_count: u32,
+    pub packet_parsing_error_count: u32,
+    pub packet_not_tcp_count: u32,
+    pub ignored_packet_count: u64,
+    /// Packets whose IPv4 header or TCP checksum was wrong
+    pub checksum_error_count: u64,
+}
+
+impl TableReport {
+    /// Add the totals of another shard of the same table
+    pub fn merge(&mut self, other: &TableReport) {
+        self.conn_alltime_count += other.conn_alltime_count;
+        self.open_conns += other.open_conns;
+        self.evicted_count += other.evicted_count;
+        self.packet_count += other.packet_count;
+        self.bytes.merge(&other.bytes);
+        self.packet_len_error_count += other.packet_len_error_count;
+        self.packet_parsing_error_count += other.packet_parsing_error_count;
+        self.packet_not_tcp_count += other.packet_not_tcp_count;
+        self.ignored_packet_count += other.ignored_packet_count;
+        self.checksum_error_count += other.checksum_error_count;
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        JsonValue::object(vec![
+            ("conn_alltime_count", self.conn_alltime_count.into()),
+            ("open_conns", self.open_conns.into()),
+            ("evicted_count", self.evicted_count.into()),
+            ("packet_count", self.packet_count.into()),
+            ("bytes_from_clients", self.bytes.client.into()),
+            ("bytes_from_servers", self.bytes.server.into()),
+            ("bytes_from_unknown", self.bytes.unknown.into()),
+            ("packet_len_error_count", self.packet_len_error_count.into()),
+            ("packet_parsing_error_count", self.packet_parsing_error_count.into()),
+            ("packet_not_tcp_count", self.packet_not_tcp_count.into()),
+            ("ignored_packet_count", self.ignored_packet_count.into()),
+            ("checksum_error_count", self.checksum_error_count.into()),
+        ])
+    }
+}
+
+impl fmt::Display for TableReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} connections ({} still open, {} evicted), {} packets, payload bytes: {} from clients, {} from servers, \
+            {} from unknown sides, packets not tracked: {} truncated, {} with parsing errors, {} not TCP, {} ignored by rules, \
+            checksum errors: {}",
+               self.conn_alltime_count, self.open_conns, self.evicted_count, self.packet_count, self.bytes.client,
+               self.bytes.server, self.bytes.unknown, self.packet_len_error_count, self.packet_parsing_error_count,
+               self.packet_not_tcp_count, self.ignored_packet_count, self.checksum_error_count)
+    }
+}
+
+/// What the top connections are ranked by, in both directions together
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TopOrder {
+    /// Payload bytes
+    Bytes,
+    Packets,
+}
+
+impl TopOrder {
+    /// Parse an order name: "bytes" or "packets"
+    pub fn parse(arg: &str) -> Result<Self, String> {
+        match arg.trim() {
+            "bytes" => { Ok(TopOrder::Bytes) }
+            "packets" => { Ok(TopOrder::Packets) }
+            other => { Err(format!("unknown order \"{}\", expected bytes or packets", other)) }
+        }
+    }
+
+    fn conn_key(&self, conn: &Conn) -> u64 {
+        match self {
+            TopOrder::Bytes => { conn.flow_src_low.byte_count + conn.flow_src_high.byte_count }
+            TopOrder::Packets => { conn.flow_src_low.packet_count as u64 + conn.flow_src_high.packet_count as u64 }
+        }
+    }
+
+    pub(crate) fn summary_key(&self, summary: &ConnSummary) -> u64 {
+        match self {
+            TopOrder::Bytes => { summary.bytes_src_low + summary.bytes_src_high }
+            TopOrder::Packets => { summary.packets_src_low as u64 + summary.packets_src_high as u64 }
+        }
+    }
+}
+
+/// Capture time of a packet, since the epoch
+pub fn capture_ts(header: &PacketHeader) -> Duration {
+    Duration::new(header.ts.tv_sec as u64, header.ts.tv_usec as u32 * 1000)
+}
+
+/// The server that a connection in the given state waits to answer its SYN, given the low and high addresses
+fn half_open_server(state: &ConnState, (low, high): (SocketAddr, SocketAddr)) -> Option<SocketAddr> {
+    match state {
+        ConnState::SynSent(PacketDir::SrcLowAddr, _) => { Some(high) }
+        ConnState::SynSent(PacketDir::SrcHighAddr, _) => { Some(low) }
+        _ => { None }
+    }
+}
 
 /// Hold TCP connections, along with statistics per connection and timeouts
 #[derive(Clone)]
@@ -16,6 +318,10 @@ pub struct Connections {
     /// All time counter of connections added to list, including removed ones
     /// Each connection holds everything related to both directions
     conn_alltime_count: u32,
+    /// Sequence of the next new connection, and the step to the one after it, which is the number of shards when the
+    /// table is split, so every shard numbers its connections apart from the others
+    next_sequence: u32,
+    sequence_step: u32,
     /// All time packets count, including all other packet_xxx_count fields, such as errors, duplicates, etc.
     packet_count: u64,
     /// Number of times the packet was not processed because capture was too short
@@ -24,6 +330,95 @@ pub struct Connections {
     packet_parsing_error_count: u32,
     /// Number of times the packet was not a TCP/IP, which is normal and pretty high if capturing UDP, ICMP etc
     packet_not_tcp_count: u32,
+    /// Sampled time spent processing packets
+    latency: LatencyTracker,
+    /// Number of contiguous bytes that make a flow buffer ready to be consumed
+    ready_bytes: usize,
+    /// Signatures of connections that have a buffer ready, so they can be drained without scanning the whole list
+    ready_conns: HashSet<u128>,
+    /// Where to signal that a buffer became ready, if anyone waits for it
+    ready_notifier: Option<Sender<()>>,
+    /// Buffered bytes that were not consumed yet, and their peaks
+    watermarks: BufferWatermarks,
+    /// Cap on the buffered bytes of all the connections, if any
+    buffer_budget: Option<BufferBudget>,
+    /// Max bytes buffered by a single flow, and what happens to a flow that reaches it
+    flow_buffer_limit: usize,
+    flow_overflow: FlowOverflow,
+    /// Number of last packets to keep per connection, for diagnostics
+    packet_history_len: usize,
+    /// Track the statistics of the packets of new connections, for the feature export
+    track_features: bool,
+    /// Profiles of the hosts of the finalized connections
+    hosts: HostTable,
+    /// TLS session secrets to match the TLS connections with, if a key log was given
+    key_log: Option<Arc<KeyLog>>,
+    /// Which analyzers run on new connections
+    analyzer_config: Arc<AnalyzerConfig>,
+    /// Rules that are checked on every packet of a connection, if any
+    alert_rules: Option<Arc<Vec<AlertRule>>>,
+    /// Model that labels the connections by their feature vectors, if any
+    classifier: Option<Arc<Mutex<ConnClassifier>>>,
+    /// Names that clients resolved over UDP DNS, to label their connections with, if enabled.
+    /// It is shared by the shards, since the DNS answers and the connections may be in different ones.
+    dns_cache: Option<Arc<Mutex<DnsCache>>>,
+    /// Handshake RTT and time to first byte distributions per server, if enabled
+    heatmap: Option<LatencyHeatmap>,
+    /// Cap on the rate of new connections, against SYN floods
+    conn_rate_limiter: ConnRateLimiter,
+    /// Half-open connections and SYN rate per destination, if enabled
+    syn_flood: Option<SynFloodDetector>,
+    /// Traffic that is not tracked, if any
+    ignore_rules: Vec<IgnoreRule>,
+    /// Negative cache of the flows that matched the ignore rules, set along with the rules
+    ignored_flows: Option<IgnoredFlows>,
+    /// Number of packets that were not tracked since they matched an ignore rule
+    ignored_packet_count: u64,
+    /// User labels for new connections that match a rule
+    tag_rules: Vec<TagRule>,
+    /// Which packets belong to the same connection, that makes the keys of the connection list
+    flow_keyer: Arc<dyn FlowKeyer>,
+    /// Track only the headers, without copying the payloads or logging the packets, to measure the max packet rate
+    headers_only: bool,
+    /// Adopt connections whose handshake was not seen, see [Connections::set_midstream_pickup]
+    midstream_pickup: bool,
+    /// Validate the IPv4 header and TCP checksums, see [Connections::set_checksum_check]
+    checksum_check: bool,
+    /// Max nested tunnels to unwrap from a packet, see [Connections::set_decap_depth]
+    decap_depth: u8,
+    /// Number of packets whose IPv4 header or TCP checksum was wrong
+    checksum_error_count: u64,
+    /// Number of first payload bytes of every direction to preview in an event, or 0 for none
+    payload_preview_len: usize,
+    /// IP to MAC mappings from ARP packets, to correlate connection resets and retransmissions with their changes
+    arp: ArpTable,
+    /// Opened connections by their NAT twin key, with their sequences and list keys, to find the same connection on
+    /// the other side of a NAT
+    nat_twins: HashMap<NatTwinKey, (u32, u128)>,
+    /// Number of connections that were NAT twins of earlier ones, and were left out of the host profiles and latencies
+    nat_duplicate_count: u64,
+    /// Connections that were put under another key than their flow key, since it was held by other IPv6 addresses
+    key_collision_count: u64,
+    /// Number of connections that were removed for going idle before they closed
+    evicted_count: u64,
+    /// MTU of the capture interface, if known, to check the announced MSS against
+    interface_mtu: Option<u16>,
+    /// Fragment reassembly of IPv4 packets, with its counters
+    ipv4: Ipv4Defragmenter,
+    /// Extension header walking and fragment reassembly of IPv6 packets, with their counters
+    ipv6: Ipv6Walker,
+    /// Capture time of the first packet processed since the process started, for the connection identifiers
+    capture_start_ts: Option<Duration>,
+    /// Latest capture time seen, that the connections age by, or None before the first packet
+    clock: Option<Duration>,
+    /// Where every connection event is written as a JSON line, if anywhere
+    flow_log: Option<Arc<Mutex<FlowLog>>>,
+    /// Where the frames of every connection are written to its own pcap file, if anywhere
+    conn_pcap: Option<Arc<Mutex<ConnPcap>>>,
+    /// Payload bytes of the connections that were removed, for the final report
+    removed_bytes: SideBytes,
+    /// Where the lifecycle of the connections is dispatched, in the order they were added
+    observers: Vec<Arc<Mutex<Box<dyn ConnectionObserver>>>>,
 }
 
 impl Connections {
@@ -33,150 +428,1962 @@ impl Connections {
         Connections {
             conn_list: HashMap::new(),
             conn_alltime_count: 0,
+            next_sequence: 1,
+            sequence_step: 1,
             packet_count: 0,
             packet_len_error_count: 0,
             packet_parsing_error_count: 0,
             packet_not_tcp_count: 0,
+            latency: LatencyTracker::new(0, Duration::ZERO),
+            ready_bytes: 32000,
+            ready_conns: HashSet::new(),
+            ready_notifier: None,
+            watermarks: BufferWatermarks::default(),
+            buffer_budget: None,
+            flow_buffer_limit: DEFAULT_FLOW_BUFFER_LIMIT,
+            flow_overflow: FlowOverflow::Truncate,
+            packet_history_len: 0,
+            track_features: false,
+            hosts: HostTable::default(),
+            key_log: None,
+            analyzer_config: Arc::new(AnalyzerConfig::default()),
+            alert_rules: None,
+            classifier: None,
+            dns_cache: None,
+            heatmap: None,
+            conn_rate_limiter: ConnRateLimiter::new(0),
+            syn_flood: None,
+            ignore_rules: Vec::new(),
+            ignored_flows: None,
+            ignored_packet_count: 0,
+            tag_rules: Vec::new(),
+            flow_keyer: flow_key::default_keyer(),
+            arp: ArpTable::default(),
+            nat_twins: HashMap::new(),
+            nat_duplicate_count: 0,
+            key_collision_count: 0,
+            evicted_count: 0,
+            interface_mtu: None,
+            ipv4: Ipv4Defragmenter::default(),
+            ipv6: Ipv6Walker::default(),
+            headers_only: false,
+            midstream_pickup: false,
+            checksum_check: true,
+            decap_depth: 1,
+            checksum_error_count: 0,
+            payload_preview_len: 0,
+            capture_start_ts: None,
+            clock: None,
+            flow_log: None,
+            conn_pcap: None,
+            removed_bytes: SideBytes::default(),
+            observers: Vec::new(),
+        }
+    }
+
+    /// Set the number of contiguous bytes that make a flow buffer ready, and where to signal when a buffer becomes ready.
+    /// Connections that are already ready, such as restored ones, are signalled right away.
+    pub fn set_ready_notifier(&mut self, ready_bytes: usize, notifier: Sender<()>) {
+        self.ready_bytes = ready_bytes;
+        for (conn_sign, conn) in &self.conn_list {
+            if conn.has_ready_buffer(conn.state.is_closed(), ready_bytes) {
+                self.ready_conns.insert(*conn_sign);
+            }
+        }
+        if !self.ready_conns.is_empty() {
+            let _ = notifier.send(());
+        }
+        self.ready_notifier = Some(notifier);
+    }
+
+    /// Keep the given number of last packets per connection (none if 0), to render or export them later
+    pub fn set_packet_history(&mut self, max_packets: usize) {
+        self.packet_history_len = max_packets;
+        for conn in self.conn_list.values_mut() {
+            conn.history = PacketHistory::new(max_packets);
+        }
+    }
+
+    /// Track the packet size, inter-arrival time and burst statistics of new connections, to export them as feature vectors
+    pub fn set_feature_tracking(&mut self, enabled: bool) {
+        self.track_features = enabled;
+    }
+
+    /// Set which analyzers run on new connections, and their limits
+    pub fn set_analyzer_config(&mut self, mut config: AnalyzerConfig) {
+        config.key_log = self.key_log.clone();
+        self.analyzer_config = Arc::new(config);
+    }
+
+    /// Check these rules on every packet, and add an alert event when a connection crosses one.
+    /// Rules beyond the max number are ignored, with a warning.
+    pub fn set_alert_rules(&mut self, mut rules: Vec<AlertRule>) {
+        if rules.len() > MAX_ALERT_RULES {
+            warn!("Only the first {} of {} alert rules are checked", MAX_ALERT_RULES, rules.len());
+            rules.truncate(MAX_ALERT_RULES);
+        }
+        self.alert_rules = if rules.is_empty() { None } else { Some(Arc::new(rules)) };
+    }
+
+    /// Set the MTU of the capture interface, to flag handshakes whose MSS does not fit in it
+    pub fn set_interface_mtu(&mut self, mtu: Option<u16>) {
+        self.interface_mtu = mtu;
+    }
+
+    /// Label every new connection with the model, in its "class" tag, once it has the given number of packets or when
+    /// it is removed before. Turns on the feature tracking that the model feeds on.
+    pub fn set_classifier(&mut self, model: Box<dyn Classifier>, after_packets: u32) {
+        self.track_features = true;
+        self.classifier = Some(Arc::new(Mutex::new(ConnClassifier::new(model, after_packets))));
+    }
+
+    /// Label new connections with the name that the client resolved to the server address, from the DNS answers seen
+    /// on UDP before
+    pub fn set_dns_names(&mut self, enabled: bool) {
+        self.dns_cache = if enabled { Some(Arc::new(Mutex::new(DnsCache::default()))) } else { None };
+    }
+
+    /// Number of (client, address) pairs with a resolved name, if enabled
+    pub fn dns_name_count(&self) -> Option<usize> {
+        self.dns_cache.as_ref().map(|dns_cache| dns_cache.lock().unwrap().len())
+    }
+
+    /// The classifier with its counters, if any
+    pub fn classifier(&self) -> Option<String> {
+        self.classifier.as_ref().map(|classifier| classifier.lock().unwrap().to_string())
+    }
+
+    /// Add at most the given number of new connections per second of capture time (unlimited if 0).
+    /// Packets of the new flows beyond the rate are not tracked, and are counted as suppressed.
+    pub fn set_conn_rate_limit(&mut self, per_sec: u32) {
+        self.conn_rate_limiter = ConnRateLimiter::new(per_sec);
+    }
+
+    /// Cap on the rate of new connections, with the number of suppressed flows
+    pub fn conn_rate_limiter(&self) -> &ConnRateLimiter {
+        &self.conn_rate_limiter
+    }
+
+    /// Raise an alarm when a destination has the given number of half-open connections, or receives the given number
+    /// of SYNs per second of capture time (never if 0)
+    pub fn set_syn_flood_thresholds(&mut self, max_half_open: u32, max_syn_rate: u32) {
+        let detector = SynFloodDetector::new(max_half_open, max_syn_rate);
+        self.syn_flood = if detector.is_enabled() { Some(detector) } else { None };
+    }
+
+    /// The SYN flood detector with its counters, if enabled
+    pub fn syn_flood(&self) -> Option<&SynFloodDetector> {
+        self.syn_flood.as_ref()
+    }
+
+    /// Number of new flows suppressed by the rate limit since the last call
+    pub fn take_suppressed_conns(&mut self) -> u64 {
+        self.conn_rate_limiter.take_suppressed()
+    }
+
+    /// Do not track connections that match any of these rules.
+    /// Their later packets are dropped early, by a negative cache of their flows.
+    pub fn set_ignore_rules(&mut self, rules: Vec<IgnoreRule>) {
+        self.ignored_flows = if rules.is_empty() { None } else { Some(IgnoredFlows::new()) };
+        self.ignore_rules = rules;
+    }
+
+    /// Tag new connections that match any of these rules
+    pub fn set_tag_rules(&mut self, rules: Vec<TagRule>) {
+        self.tag_rules = rules;
+    }
+
+    /// Set which packets belong to the same connection. Connections that are already in the list, such as restored ones,
+    /// are keyed again, and of those that end up with the same key only the first one is kept.
+    pub fn set_flow_keyer(&mut self, flow_keyer: Arc<dyn FlowKeyer>) {
+        self.flow_keyer = flow_keyer;
+        let mut conns: Vec<Conn> = std::mem::take(&mut self.conn_list).into_values().collect();
+        conns.sort_by_key(|conn| conn.conn_sequence);
+        let conn_count = conns.len();
+        for conn in conns {
+            self.conn_list.entry(self.flow_keyer.key(conn.conn_sign(), &conn.encapsulation)).or_insert(conn);
+        }
+        if self.conn_list.len() < conn_count {
+            warn!("Dropped {} connections that have the same {} key as others", conn_count - self.conn_list.len(),
+                self.flow_keyer.name());
+        }
+        self.watermarks.held_bytes = self.conn_list.values().map(Conn::held_bytes).sum();
+        self.ready_conns = self.conn_list.iter()
+            .filter(|(_, conn)| conn.has_ready_buffer(conn.state.is_closed(), self.ready_bytes))
+            .map(|(conn_key, _)| *conn_key)
+            .collect();
+    }
+
+    /// Track only the headers of the packets, without copying their payloads or logging them, so the max packet rate
+    /// of the host can be measured. Nothing becomes ready to be consumed.
+    pub fn set_headers_only(&mut self, headers_only: bool) {
+        self.headers_only = headers_only;
+    }
+
+    /// Adopt the connections whose handshake was not seen, such as ones that were open before the capture started, as
+    /// [ConnState::EstablishedMidstream] on their first packet that is not a SYN, instead of leaving them created with
+    /// no sequence tracking. The initial sequence of every direction is inferred from its first segment, as if its SYN
+    /// came right before it, so the payload is buffered from there.
+    pub fn set_midstream_pickup(&mut self, midstream_pickup: bool) {
+        self.midstream_pickup = midstream_pickup;
+    }
+
+    /// Validate the checksums of the IPv4 headers and of the TCP segments (the default). A packet whose IPv4 header is
+    /// wrong is ignored, since its addresses cannot be trusted, and a segment whose TCP checksum is wrong is tracked
+    /// without buffering its payload, and counted by its connection. Both are counted by the table.
+    /// Disable it on hosts with checksum offload, where the captured outgoing packets do not have their checksums yet.
+    pub fn set_checksum_check(&mut self, checksum_check: bool) {
+        self.checksum_check = checksum_check;
+    }
+
+    /// Unwrap up to this number of nested tunnels (VXLAN, GRE with ERSPAN, and IPv4 in IP) from a packet, 1 by default,
+    /// and track the connection that the innermost one carries, with the outermost tunnel and its endpoints in its
+    /// encapsulation. With 0 the tunnels are not unwrapped, so their packets are counted as not TCP.
+    pub fn set_decap_depth(&mut self, decap_depth: u8) {
+        self.decap_depth = decap_depth;
+    }
+
+    /// Add an event with a printable preview of the given number of first payload bytes of every direction (none if 0),
+    /// which is often enough to tell the application
+    pub fn set_payload_preview(&mut self, len: usize) {
+        self.payload_preview_len = len;
+    }
+
+    /// Cap the bytes buffered by all the connections together (unlimited if 0). From 90% of the budget, new connections
+    /// are only counted, and when it is exhausted, connections that hold more than the average are demoted to counting.
+    pub fn set_buffer_budget(&mut self, limit: usize) {
+        self.buffer_budget = if limit == 0 { None } else {
+            Some(BufferBudget { limit, stats_only_conns: 0, demoted_conns: 0 })
+        };
+    }
+
+    /// Cap the bytes that a single flow buffers before consumption, and set what happens to a flow that reaches it.
+    /// Connections that are already in the list, such as restored ones, get the new limit as well.
+    pub fn set_flow_buffer_limit(&mut self, limit: usize, overflow: FlowOverflow) {
+        self.flow_buffer_limit = limit;
+        self.flow_overflow = overflow;
+        for conn in self.conn_list.values_mut() {
+            conn.set_buffer_limit(limit);
+        }
+    }
+
+    /// The buffering budget with its transition counters, if set
+    pub fn buffer_budget(&self) -> Option<BufferBudget> {
+        self.buffer_budget
+    }
+
+    /// Attach a tag to the connection between the two given endpoints, such as by a consumer that identified it.
+    /// Return false if there is no such connection, or it has too many tags.
+    pub fn tag_conn(&mut self, a: SocketAddr, b: SocketAddr, key: &str, value: &str) -> bool {
+        match self.find_key(a, b).and_then(|conn_key| self.conn_list.get_mut(&conn_key)) {
+            None => { false }
+            Some(conn) => { conn.app.tags.set(key, value) }
+        }
+    }
+
+    /// Number of connections that were the same as earlier ones, seen on the other side of a NAT
+    pub fn nat_duplicate_count(&self) -> u64 {
+        self.nat_duplicate_count
+    }
+
+    /// Number of connections that were removed for going idle before they closed
+    pub fn evicted_count(&self) -> u64 {
+        self.evicted_count
+    }
+
+    /// Link a connection that just opened with an open connection of the same NAT twin key, tagging both with the
+    /// sequence of the other. Return true if it is a twin, so it is not counted again.
+    fn link_nat_twin(&mut self, twin_key: NatTwinKey, conn_key: u128, conn_sequence: u32, events: &mut Vec<ConnEvent>) -> bool {
+        let (twin_sequence, twin_conn_key) = match self.nat_twins.get(&twin_key) {
+            Some(twin) if self.conn_list.contains_key(&twin.1) && twin.1 != conn_key => { *twin }
+            _ => {
+                self.nat_twins.insert(twin_key, (conn_sequence, conn_key));
+                return false;
+            }
+        };
+        if let Some(twin) = self.conn_list.get_mut(&twin_conn_key) {
+            twin.app.tags.set(NAT_TWIN_TAG, &conn_sequence.to_string());
+        }
+        if let Some(conn) = self.conn_list.get_mut(&conn_key) {
+            conn.app.tags.set(NAT_TWIN_TAG, &twin_sequence.to_string());
+        }
+        self.nat_duplicate_count += 1;
+        events.push(ConnEvent::NatTwin { conn_sequence, twin: twin_sequence });
+        true
+    }
+
+    /// Counters of the fragments of the IPv4 packets
+    pub fn ipv4_fragment_stats(&self) -> Ipv4FragmentStats {
+        self.ipv4.stats()
+    }
+
+    /// Counters of the extension headers and the fragments of the IPv6 packets
+    pub fn ipv6_stats(&self) -> Ipv6Stats {
+        self.ipv6.stats()
+    }
+
+    /// Number of packets that were not tracked since they matched an ignore rule
+    pub fn ignored_packet_count(&self) -> u64 {
+        self.ignored_packet_count
+    }
+
+    /// Totals since the table started, including the connections that were removed
+    pub fn report(&self) -> TableReport {
+        let mut bytes = self.removed_bytes;
+        for conn in self.conn_list.values() {
+            bytes.add_conn(conn);
+        }
+        TableReport {
+            conn_alltime_count: self.conn_alltime_count,
+            open_conns: self.conn_list.len(),
+            evicted_count: self.evicted_count,
+            packet_count: self.packet_count,
+            bytes,
+            packet_len_error_count: self.packet_len_error_count,
+            packet_parsing_error_count: self.packet_parsing_error_count,
+            packet_not_tcp_count: self.packet_not_tcp_count,
+            ignored_packet_count: self.ignored_packet_count,
+            checksum_error_count: self.checksum_error_count,
+        }
+    }
+
+    /// Aggregate the handshake RTT and time to first byte of new connections per server subnet and port,
+    /// over time buckets of the given number of seconds
+    pub fn set_latency_heatmap(&mut self, bucket_secs: u64, prefix_len: u8) {
+        self.heatmap = Some(LatencyHeatmap::new(bucket_secs, prefix_len));
+    }
+
+    /// Latency distributions per server, if enabled
+    pub fn latency_heatmap(&self) -> Option<&LatencyHeatmap> {
+        self.heatmap.as_ref()
+    }
+
+    /// Match new TLS connections with their session secrets in the given key log, and decrypt the TLS 1.2 ones
+    pub fn set_key_log(&mut self, key_log: KeyLog) {
+        self.key_log = Some(Arc::new(key_log));
+        let mut config = (*self.analyzer_config).clone();
+        config.key_log = self.key_log.clone();
+        self.analyzer_config = Arc::new(config);
+    }
+
+    /// Export the kept packets of all connections as time-sequence samples for plotting, as CSV or JSON
+    pub fn export_time_sequence(&self, json: bool, time_format: &TimeFormatter) -> String {
+        let mut conns: Vec<&Conn> = self.conn_list.values().collect();
+        conns.sort_by_key(|conn| conn.conn_sequence);
+        if json {
+            let samples: Vec<JsonValue> = conns.iter()
+                .flat_map(|conn| conn.history.time_sequence_json(conn.conn_sequence, time_format))
+                .collect();
+            return JsonValue::from(samples).to_pretty_string() + "\n";
+        }
+        let mut result = String::from(TIME_SEQUENCE_CSV_HEADER) + "\n";
+        for conn in conns {
+            conn.history.time_sequence_csv(conn.conn_sequence, time_format, &mut result);
+        }
+        result
+    }
+
+    /// Label the connections in the table that were not classified yet, such as short ones at the end of a capture
+    pub fn classify_remaining(&mut self) {
+        if let Some(classifier) = &self.classifier {
+            let mut classifier = classifier.lock().unwrap();
+            let mut conns: Vec<&mut Conn> = self.conn_list.values_mut().collect();
+            conns.sort_by_key(|conn| conn.conn_sequence);
+            for conn in conns {
+                classifier.check(conn, true);
+            }
+        }
+    }
+
+    /// Export the feature vectors of the connections that track them, as CSV rows (see [FEATURES_CSV_HEADER]),
+    /// with the header line if asked for
+    pub fn export_features(&self, with_header: bool) -> String {
+        let mut conns: Vec<&Conn> = self.conn_list.values().collect();
+        conns.sort_by_key(|conn| conn.conn_sequence);
+        let mut result = if with_header { String::from(FEATURES_CSV_HEADER) + "\n" } else { String::new() };
+        for row in conns.iter().filter_map(|conn| csv_row(conn)) {
+            result.push_str(&row);
+            result.push('\n');
+        }
+        result
+    }
+
+    /// Export the retransmission timelines of all the connections, as CSV (see [RETRANSMITS_CSV_HEADER]) or JSON,
+    /// with the capture time and the direction as in the time-sequence export
+    pub fn export_retransmits(&self, json: bool, time_format: &TimeFormatter) -> String {
+        let mut conns: Vec<&Conn> = self.conn_list.values().collect();
+        conns.sort_by_key(|conn| conn.conn_sequence);
+        let mut records = Vec::new();
+        for conn in conns {
+            for dir in [PacketDir::SrcLowAddr, PacketDir::SrcHighAddr] {
+                for record in &conn.flow(&dir).retransmits {
+                    records.push((conn, dir_label(&dir), record));
+                }
+            }
+        }
+        if json {
+            let records: Vec<JsonValue> = records.iter().map(|(conn, dir, record)| JsonValue::object(vec![
+                ("conn", conn.conn_sequence.into()),
+                ("id", conn.id_as_str().into()),
+                ("time", time_format.to_json(record.ts)),
+                ("dir", (*dir).into()),
+                ("seq", record.seq.into()),
+                ("len", record.len.into()),
+            ])).collect();
+            return JsonValue::from(records).to_pretty_string() + "\n";
+        }
+        let mut result = String::from(RETRANSMITS_CSV_HEADER) + "\n";
+        for (conn, dir, record) in records {
+            result.push_str(&format!("{},{},{},{},{},{}\n", conn.conn_sequence, conn.id_as_str(), time_format.format(record.ts),
+                                     dir, record.seq, record.len));
+        }
+        result
+    }
+
+    /// Remember that a connection has a buffer ready, and wake up whoever waits for it
+    fn mark_ready(&mut self, conn_sign: u128) {
+        self.ready_conns.insert(conn_sign);
+        if let Some(notifier) = &self.ready_notifier {
+            // The receiver may be gone during shutdown, which is fine
+            let _ = notifier.send(());
+        }
+    }
+
+    /// When the buffering budget is exhausted, demote the given connection that just buffered more bytes, if it holds
+    /// more than the average, and hand over what it has
+    fn check_buffer_budget(&mut self, conn_key: u128, conn_held_bytes: usize, events: &mut Vec<ConnEvent>) {
+        let held_bytes = self.watermarks.held_bytes;
+        let conn_count = self.conn_list.len();
+        let budget = match &mut self.buffer_budget {
+            Some(budget) if held_bytes >= budget.limit && conn_held_bytes * conn_count >= held_bytes => { budget }
+            _ => { return; }
+        };
+        if let Some(conn) = self.conn_list.get_mut(&conn_key).filter(|conn| !conn.stats_only) {
+            conn.stats_only = true;
+            budget.demoted_conns += 1;
+            events.push(ConnEvent::BufferingStopped { conn_sequence: conn.conn_sequence, held_bytes: conn_held_bytes });
+            self.mark_ready(conn_key);
+        }
+    }
+
+    /// Apply the overflow policy to a flow of the given connection that just dropped payload bytes over its buffer limit.
+    /// Only the first overflow of a flow is reported.
+    fn handle_flow_overflow(&mut self, conn_key: u128, dir: &PacketDir, first_overflow: bool, events: &mut Vec<ConnEvent>) {
+        let conn = match self.conn_list.get_mut(&conn_key) {
+            None => { return; }
+            Some(conn) => { conn }
+        };
+        let (conn_sequence, limit) = (conn.conn_sequence, self.flow_buffer_limit);
+        match self.flow_overflow {
+            FlowOverflow::Truncate => {
+                if first_overflow {
+                    events.push(ConnEvent::FlowBufferFull { conn_sequence, dir: dir.to_owned(), limit, stopped: false });
+                }
+                if conn.flow(dir).ready_len() == 0 {
+                    return;
+                }
+            }
+            FlowOverflow::StatsOnly => {
+                if conn.stats_only {
+                    return;
+                }
+                conn.stats_only = true;
+                events.push(ConnEvent::FlowBufferFull { conn_sequence, dir: dir.to_owned(), limit, stopped: true });
+            }
+        }
+        self.mark_ready(conn_key);
+    }
+
+    /// Measure the processing time of one of every N packets (none if 0),
+    /// and warn when the p99 of the measurements exceeds the given threshold.
+    pub fn set_latency_tracking(&mut self, sample_every: u64, p99_warn_threshold: Duration) {
+        self.latency = LatencyTracker::new(sample_every, p99_warn_threshold);
+    }
+
+    /// Sampled time spent processing packets
+    pub fn latency(&self) -> &LatencyTracker {
+        &self.latency
+    }
+
+    /// Save the whole connection table, with counters and states, to a snapshot file.
+    /// The payload buffers are saved only if requested, since they may be large.
+    pub fn save_snapshot(&self, path: &Path, with_payload: bool) -> Result<(), Error> {
+        let mut writer = SnapshotWriter::new(BufWriter::new(File::create(path)?));
+        for b in SNAPSHOT_MAGIC {
+            writer.put_u8(*b)?;
+        }
+        writer.put_u16(SNAPSHOT_VERSION)?;
+        writer.put_bytes(self.flow_keyer.name().as_bytes())?;
+        writer.put_u32(self.conn_alltime_count)?;
+        writer.put_u64(self.packet_count)?;
+        writer.put_u32(self.packet_len_error_count)?;
+        writer.put_u32(self.packet_parsing_error_count)?;
+        writer.put_u32(self.packet_not_tcp_count)?;
+        writer.put_u32(self.conn_list.len() as u32)?;
+        for conn in self.conn_list.values() {
+            conn.write_snapshot(&mut writer, with_payload)?;
+        }
+        writer.flush()
+    }
+
+    /// Load a connection table that was saved by [Connections::save_snapshot].
+    pub fn load_snapshot(path: &Path) -> Result<Connections, Error> {
+        let mut reader = SnapshotReader::new(BufReader::new(File::open(path)?));
+        for b in SNAPSHOT_MAGIC {
+            if reader.get_u8()? != *b {
+                return Err(Error::new(ErrorKind::InvalidData, "Not a connections snapshot file"));
+            }
+        }
+        let version = reader.get_u16()?;
+        if version != SNAPSHOT_VERSION {
+            return Err(Error::new(ErrorKind::InvalidData,
+                                  format!("Snapshot version {} is not supported (expected {})", version, SNAPSHOT_VERSION)));
+        }
+        let mut connections = Connections::new();
+        connections.flow_keyer = flow_key::from_saved_name(&reader.get_bytes(flow_key::MAX_NAME_LEN)?)?;
+        connections.conn_alltime_count = reader.get_u32()?;
+        connections.packet_count = reader.get_u64()?;
+        connections.packet_len_error_count = reader.get_u32()?;
+        connections.packet_parsing_error_count = reader.get_u32()?;
+        connections.packet_not_tcp_count = reader.get_u32()?;
+        let conn_count = reader.get_u32()?;
+        for _ in 0..conn_count {
+            let conn = Conn::read_snapshot(&mut reader)?;
+            connections.insert_restored(conn);
+        }
+        connections.count_held_bytes();
+        connections.continue_sequences();
+        Ok(connections)
+    }
+
+    /// JSON representation of the counters and all the connections, sorted by connection sequence
+    pub fn to_json(&self, payload: &PayloadExport, time_format: &TimeFormatter) -> JsonValue {
+        let mut conns: Vec<&Conn> = self.conn_list.values().collect();
+        conns.sort_by_key(|conn| conn.conn_sequence);
+        JsonValue::object(vec![
+            ("flow_key", self.flow_keyer.name().into()),
+            ("conn_alltime_count", self.conn_alltime_count.into()),
+            ("packet_count", self.packet_count.into()),
+            ("packet_len_error_count", self.packet_len_error_count.into()),
+            ("packet_parsing_error_count", self.packet_parsing_error_count.into()),
+            ("packet_not_tcp_count", self.packet_not_tcp_count.into()),
+            ("connections", conns.iter().map(|conn| conn.to_json(payload, time_format)).collect::<Vec<JsonValue>>().into()),
+        ])
+    }
+
+    /// Restore a connection table from its JSON representation, made by [Connections::to_json].
+    pub fn from_json(json: &JsonValue) -> Result<Connections, Error> {
+        let mut connections = Connections::new();
+        connections.flow_keyer = flow_key::from_saved_name(json.field_str("flow_key")?.as_bytes())?;
+        connections.conn_alltime_count = json.field_u64("conn_alltime_count")? as u32;
+        connections.packet_count = json.field_u64("packet_count")?;
+        connections.packet_len_error_count = json.field_u64("packet_len_error_count")? as u32;
+        connections.packet_parsing_error_count = json.field_u64("packet_parsing_error_count")? as u32;
+        connections.packet_not_tcp_count = json.field_u64("packet_not_tcp_count")? as u32;
+        for conn_json in json.field_array("connections")? {
+            let conn = Conn::from_json(conn_json)?;
+            connections.insert_restored(conn);
+        }
+        connections.count_held_bytes();
+        connections.continue_sequences();
+        Ok(connections)
+    }
+
+    /// Number new connections after the restored ones, which may be numbered past the count if the table was sharded
+    fn continue_sequences(&mut self) {
+        let max_sequence = self.conn_list.values().map(|conn| conn.conn_sequence).max().unwrap_or_default();
+        self.next_sequence = self.conn_alltime_count.max(max_sequence) + 1;
+    }
+
+    /// Count the bytes held by restored connections, that start the watermarks
+    fn count_held_bytes(&mut self) {
+        let held_bytes = self.conn_list.values().map(Conn::held_bytes).sum();
+        let max_flow_held_bytes = self.conn_list.values()
+            .map(|conn| conn.flow_src_low.max_held_bytes.max(conn.flow_src_high.max_held_bytes))
+            .max().unwrap_or_default();
+        let dropped_bytes = self.watermarks.dropped_bytes;
+        self.watermarks = BufferWatermarks { held_bytes, max_held_bytes: held_bytes, max_flow_held_bytes, dropped_bytes };
+    }
+
+    /// Write a JSON line for every connection that opens, is established, closes or is evicted to the given log.
+    /// Lines are flushed when connections are reaped.
+    pub fn set_flow_log(&mut self, flow_log: FlowLog) {
+        self.flow_log = Some(Arc::new(Mutex::new(flow_log)));
+    }
+
+    /// Number of lines written to the connection log, if set
+    pub fn flow_log_line_count(&self) -> Option<u64> {
+        self.flow_log.as_ref().map(|flow_log| flow_log.lock().unwrap().line_count())
+    }
+
+    /// Write the frames of every new connection to its own pcap file, see [ConnPcap]
+    pub fn set_conn_pcap(&mut self, conn_pcap: ConnPcap) {
+        self.conn_pcap = Some(Arc::new(Mutex::new(conn_pcap)));
+    }
+
+    /// Frame and file counts of the per connection pcap files, if set
+    pub fn conn_pcap(&self) -> Option<String> {
+        self.conn_pcap.as_ref().map(|conn_pcap| conn_pcap.lock().unwrap().to_string())
+    }
+
+    /// Write what the output sinks still buffer, before the process exits: the frames that the connections keep for
+    /// their pcap files and the lines of the connection log, if set
+    pub fn flush_outputs(&mut self) {
+        if let Some(flow_log) = &self.flow_log {
+            flow_log.lock().unwrap().flush();
+        }
+        if let Some(conn_pcap) = &self.conn_pcap {
+            let mut conn_pcap = conn_pcap.lock().unwrap();
+            let mut conns: Vec<&mut Conn> = self.conn_list.values_mut().collect();
+            conns.sort_by_key(|conn| conn.conn_sequence);
+            for conn in conns {
+                conn_pcap.write(conn);
+            }
+        }
+    }
+
+    /// Dispatch the lifecycle of the connections to an observer, after the ones that were added before.
+    /// The shards of a split table share their observers.
+    pub fn add_observer(&mut self, observer: Box<dyn ConnectionObserver>) {
+        self.observers.push(Arc::new(Mutex::new(observer)));
+    }
+
+    /// Hand the establishment of a connection and the bytes of a flow that became contiguous to the observers
+    fn notify_observers(&mut self, conn_key: u128, packet_dir: &PacketDir, established: bool) {
+        let conn = match self.conn_list.get_mut(&conn_key) {
+            None => { return; }
+            Some(conn) => { conn }
+        };
+        let data = conn.flow_mut(packet_dir).next_unobserved();
+        let conn = &*conn;
+        for observer in &self.observers {
+            let mut observer = observer.lock().unwrap();
+            if established {
+                observer.on_established(conn);
+            }
+            if let Some(range) = &data {
+                observer.on_data(conn, packet_dir, &conn.flow(packet_dir).contiguous_bytes(range.start)[..range.len()]);
+            }
+        }
+    }
+
+    /// Write the open and established events of a connection to the connection log, if set
+    fn log_flow_events(&self, conn_key: u128, events: &[ConnEvent]) {
+        let conn_events: Vec<FlowLogEvent> = events.iter().filter_map(|event| {
+            match event {
+                ConnEvent::NewConnection { .. } => { Some(FlowLogEvent::Open) }
+                ConnEvent::Opened { .. } => { Some(FlowLogEvent::Established) }
+                _ => { None }
+            }
+        }).collect();
+        if let (Some(flow_log), Some(conn), false) = (&self.flow_log, self.conn_list.get(&conn_key), conn_events.is_empty()) {
+            let summary = conn.summary();
+            let mut flow_log = flow_log.lock().unwrap();
+            for event in conn_events {
+                flow_log.write(event, &summary);
+            }
+        }
+    }
+
+    /// Set the capture time that the connection identifiers are relative to, before the first packet is processed,
+    /// so the shards of a table identify their connections the same way
+    pub fn set_capture_start(&mut self, ts: Duration) {
+        self.capture_start_ts.get_or_insert(ts);
+    }
+
+    /// Move the capture clock that the connections age by to the given capture time, unless it is already later.
+    /// Packets move it as they are processed, and a live capture moves it to the current time as well, so connections
+    /// age when no packets come. Restored connections start aging when the clock is first set.
+    pub fn advance_clock(&mut self, ts: Duration) {
+        match self.clock {
+            None => {
+                for conn in self.conn_list.values_mut() {
+                    conn.restart_state_times(ts);
+                }
+                self.clock = Some(ts);
+            }
+            Some(clock) if clock < ts => { self.clock = Some(ts) }
+            Some(_) => {}
+        }
+    }
+
+    /// Split the table into the given number of shards with the same settings, where every connection goes to the
+    /// shard of its addresses, as [crate::shards::shard_index] picks for its packets. The counters stay in the first shard, and the rate
+    /// limit and the buffering budget are shared between the shards.
+    pub fn split(mut self, count: usize) -> Vec<Connections> {
+        let count = count.max(1);
+        let conns: Vec<(u128, Conn)> = self.conn_list.drain().collect();
+        self.ready_conns.clear();
+        self.conn_rate_limiter = self.conn_rate_limiter.share(count);
+        if let Some(detector) = &mut self.syn_flood {
+            *detector = detector.share(count);
+        }
+        if let Some(budget) = &mut self.buffer_budget {
+            budget.limit /= count;
+        }
+        self.sequence_step = count as u32;
+        let mut empty = self.clone();
+        empty.clear_counters();
+        let mut shards = vec![self];
+        for index in 1..count {
+            let mut shard = empty.clone();
+            shard.next_sequence = shards[0].next_sequence + index as u32;
+            shards.push(shard);
+        }
+        for (conn_key, conn) in conns {
+            let (low, high) = conn.addresses();
+            shards[shard_of_ips(low.ip(), high.ip(), count)].conn_list.insert(conn_key, conn);
+        }
+        for shard in &mut shards {
+            shard.count_held_bytes();
+        }
+        shards
+    }
+
+    /// Reset the counters of a copy of the table, keeping its settings
+    fn clear_counters(&mut self) {
+        self.conn_alltime_count = 0;
+        self.packet_count = 0;
+        self.packet_len_error_count = 0;
+        self.packet_parsing_error_count = 0;
+        self.packet_not_tcp_count = 0;
+        self.latency.clear();
+        self.watermarks = BufferWatermarks::default();
+        if let Some(budget) = &mut self.buffer_budget {
+            budget.stats_only_conns = 0;
+            budget.demoted_conns = 0;
+        }
+        self.hosts = HostTable::default();
+        if let Some(heatmap) = &mut self.heatmap {
+            heatmap.clear();
+        }
+        self.ignored_packet_count = 0;
+        self.checksum_error_count = 0;
+        self.nat_twins.clear();
+        self.nat_duplicate_count = 0;
+        self.evicted_count = 0;
+        self.removed_bytes = SideBytes::default();
+        self.ipv4 = Ipv4Defragmenter::default();
+        self.ipv6 = Ipv6Walker::default();
+    }
+
+    /// Take the connections and add the counters of another shard of the same table, made by [Connections::split],
+    /// to save and report the whole table once the shards stopped processing packets
+    pub fn merge(&mut self, other: Connections) {
+        self.conn_alltime_count += other.conn_alltime_count;
+        self.next_sequence = self.next_sequence.max(other.next_sequence);
+        self.sequence_step = 1;
+        self.packet_count += other.packet_count;
+        self.packet_len_error_count += other.packet_len_error_count;
+        self.packet_parsing_error_count += other.packet_parsing_error_count;
+        self.packet_not_tcp_count += other.packet_not_tcp_count;
+        self.checksum_error_count += other.checksum_error_count;
+        self.latency.merge(&other.latency);
+        self.ready_conns.extend(other.ready_conns);
+        self.watermarks.merge(&other.watermarks);
+        if let (Some(budget), Some(other_budget)) = (&mut self.buffer_budget, &other.buffer_budget) {
+            budget.merge(other_budget);
+        }
+        self.hosts.merge(&other.hosts);
+        if let (Some(heatmap), Some(other_heatmap)) = (&mut self.heatmap, &other.heatmap) {
+            heatmap.merge(other_heatmap);
+        }
+        self.conn_rate_limiter.merge(&other.conn_rate_limiter);
+        if let (Some(detector), Some(other_detector)) = (&mut self.syn_flood, &other.syn_flood) {
+            detector.merge(other_detector);
         }
+        self.ignored_packet_count += other.ignored_packet_count;
+        self.nat_twins.extend(other.nat_twins);
+        self.nat_duplicate_count += other.nat_duplicate_count;
+        self.key_collision_count += other.key_collision_count;
+        self.evicted_count += other.evicted_count;
+        self.removed_bytes.merge(&other.removed_bytes);
+        self.ipv4.merge_stats(&other.ipv4);
+        self.ipv6.merge_stats(&other.ipv6);
+        self.clock = self.clock.max(other.clock);
+        self.conn_list.extend(other.conn_list);
+    }
+
+    /// Bytes that were buffered and not consumed yet, now and at their peak
+    pub fn buffer_watermarks(&self) -> BufferWatermarks {
+        self.watermarks
+    }
+
+    /// Counters of the table and of every connection, to be compared with a later sample for leaks
+    pub fn sample(&self) -> TableSample {
+        let conns = self.conn_list.values().map(|conn| {
+            let (low, high) = conn.addresses();
+            let (flow_low, flow_high) = (&conn.flow_src_low, &conn.flow_src_high);
+            (conn.conn_sequence, ConnSample {
+                label: format!("connection {} {} <-> {} {:?}", conn.conn_sequence, low, high, conn.state),
+                packets: flow_low.packet_count as u64 + flow_high.packet_count as u64,
+                held_bytes: conn.held_bytes(),
+                consumed_bytes: flow_low.consumed_len() + flow_high.consumed_len(),
+            })
+        }).collect();
+        TableSample {
+            taken: Instant::now(),
+            packet_count: self.packet_count,
+            conn_alltime_count: self.conn_alltime_count,
+            conns,
+        }
+    }
+
+    /// Number of connections currently held in the list
+    pub fn len(&self) -> usize {
+        self.conn_list.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.conn_list.is_empty()
+    }
+
+    /// Summaries of the connections that have the given IP, and the given port if any, on either side
+    pub fn find(&self, ip: IpAddr, port: Option<u16>) -> Vec<ConnSummary> {
+        let matches = |addr: &SocketAddr| addr.ip() == ip && port.is_none_or(|port| addr.port() == port);
+        self.find_by(|low, high| matches(low) || matches(high))
+    }
+
+    /// Summaries of all the connections, sorted by connection sequence
+    pub fn summaries(&self) -> Vec<ConnSummary> {
+        self.find_by(|_, _| true)
+    }
+
+    /// Summaries of the connections between the two given IPs, in any direction
+    pub fn find_pair(&self, a: IpAddr, b: IpAddr) -> Vec<ConnSummary> {
+        self.find_by(|low, high| (low.ip() == a && high.ip() == b) || (low.ip() == b && high.ip() == a))
+    }
+
+    /// The connection with the given sequence, if it is still in the list
+    pub fn find_by_sequence(&self, conn_sequence: u32) -> Option<&Conn> {
+        self.conn_list.values().find(|conn| conn.conn_sequence == conn_sequence)
+    }
+
+    /// The connection with the given identifier, as exported, if it is still in the list
+    pub fn find_by_id(&self, conn_id: u64) -> Option<&Conn> {
+        self.conn_list.values().find(|conn| conn.conn_id == conn_id)
+    }
+
+    /// Summaries of the given number of connections that rank first by the given order, first to last, where
+    /// connections that rank the same are sorted by connection sequence
+    pub fn top(&self, count: usize, order: TopOrder) -> Vec<ConnSummary> {
+        let mut conns: Vec<&Conn> = self.conn_list.values().collect();
+        conns.sort_by_key(|conn| (std::cmp::Reverse(order.conn_key(conn)), conn.conn_sequence));
+        conns.iter().take(count).map(|conn| conn.summary()).collect()
+    }
+
+    /// The connection between the two given endpoints, in any direction
+    pub fn find_conn(&self, a: SocketAddr, b: SocketAddr) -> Option<&Conn> {
+        self.find_key(a, b).and_then(|conn_key| self.conn_list.get(&conn_key))
+    }
+
+    /// Key of the connection between the two given endpoints in the list, if any. With the default keying the key is
+    /// the signature of the endpoints, and otherwise the list is scanned.
+    fn find_key(&self, a: SocketAddr, b: SocketAddr) -> Option<u128> {
+        let (conn_sign, _) = Conn::sign_by_tuple(a.ip(), a.port(), b.ip(), b.port());
+        // IPv6 addresses are folded into the signature, so it does not tell connections of such addresses apart
+        let matches = |conn: &Conn| conn.conn_sign() == conn_sign && conn.has_ips(a.ip(), b.ip());
+        if self.conn_list.get(&conn_sign).is_some_and(matches) {
+            return Some(conn_sign);
+        }
+        self.conn_list.iter().find(|(_, conn)| matches(conn)).map(|(conn_key, _)| *conn_key)
+    }
+
+    /// The key in the list of the connection between the given IPs, starting from its flow key, or the key for a new
+    /// one. IPv6 addresses are folded into the signature, so a key may be held by a connection of other addresses,
+    /// and then the next keys are probed. None if all of them are held by others.
+    fn resolve_key(&self, flow_key: u128, a: IpAddr, b: IpAddr) -> Option<u128> {
+        let mut free_key = None;
+        for probe in 0..MAX_KEY_PROBES {
+            // The probes change the folded lower address, so an IPv6 key stays one
+            let conn_key = if probe == 0 { flow_key } else { flow_key ^ (mix64(probe) as u32 as u128) << 16 };
+            match self.conn_list.get(&conn_key) {
+                Some(conn) if conn.has_ips(a, b) => { return Some(conn_key); }
+                Some(_) => {}
+                None => {
+                    free_key.get_or_insert(conn_key);
+                    // Without a collision so far, no connection was put after a free key
+                    if self.key_collision_count == 0 {
+                        break;
+                    }
+                }
+            }
+        }
+        free_key
+    }
+
+    /// Put a restored connection in the list, under a key of its own
+    fn insert_restored(&mut self, conn: Conn) {
+        let (low, high) = conn.addresses();
+        let flow_key = self.flow_keyer.key(conn.conn_sign(), &conn.encapsulation);
+        if let Some(conn_key) = self.resolve_key(flow_key, low.ip(), high.ip()) {
+            if conn_key != flow_key {
+                self.key_collision_count += 1;
+            }
+            self.conn_list.insert(conn_key, conn);
+        }
+    }
+
+    /// Summaries of the connections whose low and high addresses match, sorted by connection sequence
+    fn find_by<F: Fn(&SocketAddr, &SocketAddr) -> bool>(&self, matches: F) -> Vec<ConnSummary> {
+        let mut result: Vec<ConnSummary> = self.conn_list.values()
+            .filter(|conn| {
+                let (low, high) = conn.addresses();
+                matches(&low, &high)
+            })
+            .map(|conn| conn.summary())
+            .collect();
+        result.sort_by_key(|summary| summary.conn_sequence);
+        result
     }
 
     /// Get an existing connection by signature (TCP 4 tuple), or return a new connection
-    fn get_connection_or_add_new(&mut self, conn_sign: u128) -> &mut Conn {
-        match self.conn_list.entry(conn_sign) {
+    fn get_connection_or_add_new(&mut self, conn_key: u128, conn_sign: u128, (low, high): (SocketAddr, SocketAddr),
+                                 encapsulation: Encapsulation, packet_ts: Duration, events: &mut Vec<ConnEvent>) -> &mut Conn {
+        match self.conn_list.entry(conn_key) {
             Occupied(o) => { o.into_mut() }
             Vacant(v) => {
+                let conn_sequence = self.next_sequence;
+                self.next_sequence += self.sequence_step;
                 self.conn_alltime_count += 1;
-                v.insert(Conn::new(self.conn_alltime_count, conn_sign))
+                events.push(ConnEvent::NewConnection { conn_sequence });
+                let mut conn = Conn::new(conn_sequence, conn_sign);
+                conn.set_addresses(low, high);
+                conn.encapsulation = encapsulation;
+                conn.set_id(*self.capture_start_ts.get_or_insert(packet_ts), packet_ts);
+                conn.history = PacketHistory::new(self.packet_history_len);
+                conn.set_buffer_limit(self.flow_buffer_limit);
+                if self.track_features {
+                    conn.features = Some(Box::default());
+                }
+                if self.conn_pcap.is_some() {
+                    conn.frames = Some(ConnFrames::default());
+                }
+                conn.analyzers = AnalyzerConfig::create_analyzers(&self.analyzer_config);
+                if let Some(budget) = &mut self.buffer_budget {
+                    if self.watermarks.held_bytes >= budget.admission_bytes() {
+                        conn.stats_only = true;
+                        budget.stats_only_conns += 1;
+                    }
+                }
+                for rule in &self.tag_rules {
+                    rule.apply(&low, &high, &mut conn.app.tags);
+                }
+                if let Some(dns_cache) = &self.dns_cache {
+                    // Either side may be the client, that resolved the other one
+                    let dns_cache = dns_cache.lock().unwrap();
+                    let name = dns_cache.get(low.ip(), high.ip()).or_else(|| dns_cache.get(high.ip(), low.ip()));
+                    conn.app.resolved_name = name.map(String::from);
+                }
+                v.insert(conn)
             }
         }
     }
 
-    /// Get all the connections that are closed or have a significant buffer ready to process.
-    /// Result may be empty if no connections match.
-    pub fn get_connections_by_rules(&mut self, closed: bool, min_ready_bytes: usize) -> Vec<&Conn> {
-        let mut result: Vec<&Conn> = Vec::new();
+    /// Extract all the buffers that are ready to process, and mark their bytes as consumed, so each byte is handed out once.
+    /// A buffer is ready when it has at least the configured number of contiguous bytes, or when its connection is closed
+    /// and it has anything left, or when its connection stopped buffering. Every item comes with a summary of its connection, so it can be processed without the lock.
+    /// Only connections that were signalled as ready are checked.
+    pub fn drain_ready(&mut self) -> impl Iterator<Item=(ConnSummary, PacketDir, Vec<u8>)> {
+        let mut result = Vec::new();
+        for conn_sign in self.ready_conns.drain() {
+            if let Some(conn) = self.conn_list.get_mut(&conn_sign) {
+                let flush = conn.state.is_closed() || conn.stats_only;
+                while let Some((dir, data)) = conn.take_ready_buffer(flush, self.ready_bytes) {
+                    self.watermarks.held_bytes = self.watermarks.held_bytes.saturating_sub(data.len());
+                    result.push((conn.summary(), dir, data));
+                }
+            }
+        }
+        result.into_iter()
+    }
 
-        for (_, conn) in &self.conn_list {
-            if closed {
-                if matches!(conn.state, ConnState::Closed(_)) {
-                    result.push(conn);
-                    continue;
+    /// Hand the bytes of every ready buffer to the given consumer in place, without copying them out.
+    /// The consumer returns how many of the bytes it consumed, and the rest are kept for a later call, once more bytes
+    /// arrived, such as a partial message that a parser cannot handle yet. Buffers are ready as for [Connections::drain_ready].
+    pub fn consume_ready<F>(&mut self, mut consumer: F) where F: FnMut(&ConnSummary, &PacketDir, &[u8]) -> usize {
+        for conn_sign in self.ready_conns.drain() {
+            if let Some(conn) = self.conn_list.get_mut(&conn_sign) {
+                let flush = conn.state.is_closed() || conn.stats_only;
+                let summary = conn.summary();
+                for dir in [PacketDir::SrcLowAddr, PacketDir::SrcHighAddr] {
+                    let flow = conn.flow_mut(&dir);
+                    if flow.has_ready_buffer(flush, self.ready_bytes) {
+                        let consumed = consumer(&summary, &dir, flow.peek_ready());
+                        let consumed = flow.consume(consumed);
+                        self.watermarks.held_bytes = self.watermarks.held_bytes.saturating_sub(consumed);
+                    }
                 }
             }
+        }
+    }
 
-            if conn.has_ready_bytes(min_ready_bytes) {
-                result.push(conn);
+    /// Move the connections that are waiting for a SYN/ACK (or for the ack of a simultaneous open) longer than the given
+    /// timeout to the failed state.
+    /// Return a setup failure event for each of them.
+    pub fn expire_handshakes(&mut self, timeout: Duration) -> Vec<ConnEvent> {
+        let mut events = Vec::new();
+        // Handshakes age by the capture time, as in [Connections::reap_closed]
+        let now = self.clock.unwrap_or_default();
+        for conn in self.conn_list.values_mut() {
+            if let ConnState::SynSent(syn_dir, _) | ConnState::SynReceived(syn_dir, _) = &conn.state {
+                if matches!(conn.syn_packet_ts, Some(syn_ts) if now.saturating_sub(syn_ts) >= timeout) {
+                    if let (Some(detector), Some(server)) = (&mut self.syn_flood, half_open_server(&conn.state, conn.addresses())) {
+                        detector.remove_half_open(server);
+                    }
+                    let dir = syn_dir.to_owned();
+                    conn.state = ConnState::Failed(dir.to_owned());
+                    events.push(ConnEvent::SetupFailed { conn_sequence: conn.conn_sequence, dir });
+                }
             }
         }
+        events
+    }
 
-        return result;
+    /// Profiles of the hosts of the connections that were finalized so far
+    pub fn hosts(&self) -> &HostTable {
+        &self.hosts
     }
 
-    /// Get one connection that has a significant buffer ready to process, or is closed and has something to process.
-    pub fn pop_ready_buffer(&mut self, min_ready_bytes: usize) -> Option<&FlowBuff> {
-        for (_, conn) in &self.conn_list {
-            let result: Option<&FlowBuff> = conn.pop_ready_buffer(matches!(conn.state, ConnState::Closed(_)), min_ready_bytes);
-            if result.is_some() {
-                return result;
+    /// Remove the connections that were closed at least the closed timeout ago, like TCP TIME_WAIT,
+    /// so late packets are still attributed to them, and evict the connections that had no packets for the idle timeout
+    /// of their state. Failed connections are removed right away. Return the summary of each removed connection,
+    /// along with any bytes that were still buffered and not consumed yet.
+    pub fn reap_closed(&mut self, timeouts: &ConnTimeouts) -> Vec<ReapedConn> {
+        // Connections age by the capture time, so a recording that is read faster than it was captured ages the same
+        let now = self.clock.unwrap_or_default();
+        let expired: Vec<(u128, Option<Duration>)> = self.conn_list.iter()
+            .filter_map(|(conn_sign, conn)| {
+                if matches!(conn.state, ConnState::Failed(_)) ||
+                    matches!(conn.closed_ts, Some(closed_ts) if now.saturating_sub(closed_ts) >= timeouts.closed) {
+                    return Some((*conn_sign, None));
+                }
+                let idle = now.saturating_sub(conn.last_packet_ts);
+                timeouts.idle_timeout(&conn.state).filter(|timeout| idle >= *timeout).map(|_| (*conn_sign, Some(idle)))
+            })
+            .collect();
+        let mut result = Vec::with_capacity(expired.len());
+        for (conn_sign, idle) in expired {
+            self.ready_conns.remove(&conn_sign);
+            if let Some(mut conn) = self.conn_list.remove(&conn_sign) {
+                // Bytes after a hole are never handed over, so they are released along with the connection
+                self.watermarks.held_bytes = self.watermarks.held_bytes.saturating_sub(conn.held_bytes());
+                let mut remaining = Vec::new();
+                while let Some(buffer) = conn.take_ready_buffer(true, 0) {
+                    remaining.push(buffer);
+                }
+                if let Some(classifier) = &self.classifier {
+                    classifier.lock().unwrap().check(&mut conn, true);
+                }
+                if let Some(twin_key) = conn.nat_twin_key() {
+                    if self.nat_twins.get(&twin_key).is_some_and(|twin| twin.0 == conn.conn_sequence) {
+                        self.nat_twins.remove(&twin_key);
+                    }
+                }
+                let summary = conn.summary();
+                if !conn.is_nat_duplicate() {
+                    self.hosts.add_connection(&summary);
+                }
+                if idle.is_some() {
+                    self.evicted_count += 1;
+                }
+                self.removed_bytes.add_conn(&conn);
+                if let Some(conn_pcap) = &self.conn_pcap {
+                    conn_pcap.lock().unwrap().write(&mut conn);
+                }
+                for observer in &self.observers {
+                    observer.lock().unwrap().on_closed(&conn, CloseReason::new(&conn.state, idle.is_some()));
+                }
+                if let Some(flow_log) = &self.flow_log {
+                    let event = if idle.is_some() { FlowLogEvent::Evicted } else { FlowLogEvent::Closed };
+                    flow_log.lock().unwrap().write(event, &summary);
+                }
+                result.push(ReapedConn { summary, remaining, features: csv_row(&conn), idle });
             }
         }
-
-        return None;
+        if let Some(flow_log) = &self.flow_log {
+            flow_log.lock().unwrap().flush();
+        }
+        result
     }
 
     /// Process a pcap packet.
     /// It identifies the connection and handles everything related to statistics, state, etc.
-    pub fn process_packet(&mut self, packet: &Packet) {
+    /// Return what happened, in order, so the caller can build its own reporting.
+    /// An error means the packet was counted and tracked, but its payload could not be buffered.
+    pub fn process_packet(&mut self, packet: &Packet) -> Result<Vec<ConnEvent>, error::Error> {
+        let mut events = Vec::new();
+        match self.latency.start() {
+            None => { self.process_packet_untimed(packet, &mut events)?; }
+            Some(start) => {
+                let result = self.process_packet_untimed(packet, &mut events);
+                self.latency.stop(start);
+                result?;
+            }
+        }
+        Ok(events)
+    }
+
+    /// Look up the secrets of a TLS connection whose ClientHello was just seen, once
+    fn match_tls_keys(&mut self, conn_sign: u128, client_random: &[u8; CLIENT_RANDOM_LEN]) {
+        let version = self.key_log.as_ref().and_then(|key_log| key_log.version(client_random));
+        if let Some(conn) = self.conn_list.get_mut(&conn_sign) {
+            match version {
+                Some(version) => { info!("Connection {} has {} secrets in the key log", conn.conn_sequence, version) }
+                None => { debug!("Connection {} has no secrets in the key log", conn.conn_sequence) }
+            }
+            conn.app.tls_keys = Some(version.unwrap_or("missing"));
+        }
+    }
+
+    fn process_packet_untimed(&mut self, packet: &Packet, events: &mut Vec<ConnEvent>) -> Result<(), error::Error> {
         self.packet_count += 1;
+        let packet_ts = capture_ts(packet.header);
+        self.advance_clock(packet_ts);
         // Check if the captured packet is complete
         if (packet.len() as u32) < packet.header.len {
             self.packet_len_error_count += 1;
-            return;
+            events.push(ConnEvent::Ignored { reason: IgnoreReason::Truncated });
+            return Ok(());
+        }
+
+        // Flows that already matched an ignore rule are dropped before the parsing
+        if let Some(ignored_flows) = &self.ignored_flows {
+            if raw_conn_sign(packet).is_some_and(|conn_sign| ignored_flows.contains(conn_sign)) {
+                self.ignored_packet_count += 1;
+                events.push(ConnEvent::Ignored { reason: IgnoreReason::MatchedIgnoreRule });
+                return Ok(());
+            }
+        }
+
+        // IPv6 packets are walked up to their upper-layer header (reassembling fragments) before the parse
+        if let Some(ip_start) = ipv6_offset(packet) {
+            let upper_layer = self.ipv6.process(packet_ts, &packet[ip_start..]);
+            let encapsulation = Encapsulation { vlan: raw_vlan_id(packet), tunnel_id: None, outer_tunnel: None };
+            return self.process_ipv6(packet, upper_layer, encapsulation, events);
         }
 
         // Parse
-        match SlicedPacket::from_ethernet(packet) {
+        match SlicedPacket::from_ethernet(packet).map(|packet| decapsulate(packet, self.decap_depth)) {
             Err(value) => {
                 self.packet_parsing_error_count += 1;
                 warn!("*** Parsing error: {:?}", value);
-                return;
+                events.push(ConnEvent::Ignored { reason: IgnoreReason::ParseError });
+                Ok(())
             }
-            Ok(value) => {
+            Ok((value, encapsulation)) => {
+                // The addresses of an IPv4 header with a wrong checksum cannot be trusted, so the packet is not tracked
+                if let Some(InternetSlice::Ipv4(ip_header, _)) = &value.ip {
+                    if self.checksum_check && !ipv4_header_checksum_ok(ip_header.slice()) {
+                        self.checksum_error_count += 1;
+                        events.push(ConnEvent::Ignored { reason: IgnoreReason::BadChecksum });
+                        return Ok(());
+                    }
+                }
+                // IPv6 that was tunneled
+                if let Some(InternetSlice::Ipv6(ip_header, _)) = &value.ip {
+                    let ip_start = ip_header.slice().as_ptr() as usize - packet.as_ptr() as usize;
+                    let upper_layer = self.ipv6.process(packet_ts, &packet[ip_start..]);
+                    return self.process_ipv6(packet, upper_layer, encapsulation, events);
+                }
+                // IPv6 over IPv4 transition tunnels
+                if let Some((tunnel, inner)) = tunneled_ipv6(&value) {
+                    let upper_layer = self.ipv6.process_tunneled(packet_ts, tunnel, inner);
+                    return self.process_ipv6(packet, upper_layer, encapsulation, events);
+                }
+                // Fragments of IPv4 datagrams are reassembled before the TCP parse
+                if let Some(InternetSlice::Ipv4(ip_header, _)) = &value.ip {
+                    if ip_header.is_fragmenting_payload() {
+                        let ip_start = ip_header.slice().as_ptr() as usize - packet.as_ptr() as usize;
+                        let datagram = self.ipv4.process(packet_ts, &packet[ip_start..]);
+                        return self.process_ipv4_datagram(packet, datagram, encapsulation, events);
+                    }
+                }
                 // For TCP packets, there should be link, ip and transport values
                 if !value.ip.is_some() || !value.transport.is_some() {
+                    if let Some(event) = arp_payload(&value).and_then(|payload| self.arp.process(packet_ts, payload)) {
+                        events.push(ConnEvent::Arp { event });
+                    }
                     self.packet_not_tcp_count += 1;
-                    return;
+                    events.push(ConnEvent::Ignored { reason: IgnoreReason::NotTcp });
+                    return Ok(());
                 }
 
                 // IP addresses
-                match value.ip.unwrap() {
-                    InternetSlice::Ipv4(ip_header, _) => {
-                        match value.transport.unwrap() {
-                            TransportSlice::Tcp(tcp) => {
-                                // IP payload is already calculated, while TCP header is that 32-bit units (see RFC)
-                                let tcp_payload_len = ip_header.payload_len() - 4 * tcp.data_offset() as u16;
-                                let (conn_sign, packet_dir) = Conn::sign_by_tuple(ip_header.source_addr(),
-                                                                                  tcp.source_port(),
-                                                                                  ip_header.destination_addr(),
-                                                                                  tcp.destination_port());
-                                let conn = self.get_connection_or_add_new(conn_sign);
-                                // Check for RST or ACK to a second (the other party) FIN
-                                if tcp.rst() || matches!(&conn.state,ConnState::FinWait2(wait_dir, wait_ack)
-                                    if wait_dir != &packet_dir && tcp.ack() && tcp.sequence_number() == *wait_ack)
-                                {
-                                    // With RST we don't care who sent first and we no longer handle data
-                                    conn.state = ConnState::Closed(packet_dir.to_owned());
-                                } else if tcp.fin() {
-                                    match &conn.state {
-                                        // Normal - one side signals that it wants to close
-                                        ConnState::Established(_) => {
-                                            conn.state = ConnState::FinWait1(packet_dir.to_owned(), tcp.sequence_number() + 1)
-                                        }
-                                        // The other side might also sent a FIN
-                                        ConnState::FinWait1(wait_dir, _) => {
-                                            if wait_dir != &packet_dir {
-                                                conn.state = ConnState::FinWait2(packet_dir.to_owned(), tcp.sequence_number() + 1)
-                                            }
-                                        }
-                                        // This can happen but normally should not
-                                        _ => {}
-                                    }
-                                } else {
-                                    // Check if connection is new and we still look for SYN
-                                    match &conn.state {
-                                        ConnState::Created => {
-                                            // A SYN without ACK
-                                            if tcp.syn() && !tcp.ack() {
-                                                conn.state = ConnState::SynSent(packet_dir.to_owned(), tcp.sequence_number() + 1);
-                                                conn.set_initial_sequence_number(&packet_dir, tcp.sequence_number());
-                                                conn.process_tcp_options(&packet_dir, &tcp);
-                                            }
-                                        }
-                                        ConnState::SynSent(syn_dir, expected_tcp_ack) => {
-                                            if tcp.syn() && tcp.ack() && syn_dir != &packet_dir && tcp.acknowledgment_number() == *expected_tcp_ack {
-                                                conn.state = ConnState::Established(syn_dir.to_owned());
-                                                conn.set_initial_sequence_number(&packet_dir, tcp.sequence_number());
-                                                conn.process_tcp_options(&packet_dir, &tcp);
-                                            }
-                                        }
-                                        _ => {}
-                                    }
-                                }
-                                conn.add_bytes(tcp.sequence_number(), tcp_payload_len as usize, &packet_dir, packet);
-                                conn.log(&tcp, tcp_payload_len, &packet_dir);
-                            }
-                            _ => {
-                                self.packet_not_tcp_count += 1;
-                                return;
-                            }
-                        }
+                match (value.ip.unwrap(), value.transport.unwrap()) {
+                    (InternetSlice::Ipv4(ip_header, _), TransportSlice::Tcp(tcp)) => {
+                        let src = SocketAddr::new(ip_header.source_addr().into(), tcp.source_port());
+                        let dst = SocketAddr::new(ip_header.destination_addr().into(), tcp.destination_port());
+                        let tcp_start = tcp.slice().as_ptr() as usize - packet.as_ptr() as usize;
+                        // The parsed payload runs to the end of the frame, so it is cut at the total length of the
+                        // datagram, to leave out the Ethernet padding and trailers
+                        let ip_start = ip_header.slice().as_ptr() as usize - packet.as_ptr() as usize;
+                        let segment_end = (tcp_start + tcp.slice().len() + value.payload.len())
+                            .min(ip_start + ip_header.total_len() as usize)
+                            .max(tcp_start + tcp.slice().len());
+                        let segment = &packet[tcp_start..segment_end];
+                        self.process_tcp(packet, (src, dst), &tcp, segment, encapsulation, events)
+                    }
+                    (InternetSlice::Ipv4(ip_header, _), TransportSlice::Udp(udp)) => {
+                        self.learn_dns_names(ip_header.destination_addr().into(), udp.source_port(), value.payload);
+                        self.packet_not_tcp_count += 1;
+                        events.push(ConnEvent::Ignored { reason: IgnoreReason::NotTcp });
+                        Ok(())
                     }
                     _ => {
                         self.packet_not_tcp_count += 1;
-                        return;
+                        events.push(ConnEvent::Ignored { reason: IgnoreReason::NotTcp });
+                        Ok(())
+                    }
+                }
+            }
+        }
+    }
+
+    /// Remember the names of the addresses that a DNS response over UDP resolved for its client, if enabled
+    fn learn_dns_names(&self, client: IpAddr, source_port: u16, payload: &[u8]) {
+        let dns_cache = match &self.dns_cache {
+            Some(dns_cache) if source_port == DNS_PORT => { dns_cache }
+            _ => { return; }
+        };
+        if let Some((name, addresses)) = dns_answer_addresses(payload) {
+            let mut dns_cache = dns_cache.lock().unwrap();
+            for address in addresses {
+                dns_cache.insert(client, address, &name);
+            }
+        }
+    }
+
+    /// Learn the DNS names of a UDP datagram, given with its header, see [Connections::learn_dns_names]
+    fn learn_dns_names_of_datagram(&self, client: IpAddr, protocol: u8, datagram: &[u8]) {
+        if protocol == ip_number::UDP && datagram.len() >= UDP_HEADER_LEN {
+            self.learn_dns_names(client, u16::from_be_bytes([datagram[0], datagram[1]]), &datagram[UDP_HEADER_LEN..]);
+        }
+    }
+
+    /// Track the TCP segment of an IPv4 datagram that was reassembled by [Ipv4Defragmenter], where the packet is the
+    /// one that completed the datagram
+    fn process_ipv4_datagram(&mut self, packet: &Packet, datagram: Option<Ipv4Datagram>, encapsulation: Encapsulation,
+                             events: &mut Vec<ConnEvent>) -> Result<(), error::Error> {
+        if let Some(datagram) = &datagram {
+            self.learn_dns_names_of_datagram(datagram.dst.into(), datagram.protocol, &datagram.payload);
+        }
+        // Fragments that do not complete a datagram yet are counted along with the other protocols
+        let datagram = match datagram.filter(|datagram| datagram.protocol == ip_number::TCP) {
+            None => {
+                self.packet_not_tcp_count += 1;
+                events.push(ConnEvent::Ignored { reason: IgnoreReason::NotTcp });
+                return Ok(());
+            }
+            Some(datagram) => { datagram }
+        };
+        match TcpHeaderSlice::from_slice(&datagram.payload) {
+            Err(value) => {
+                self.packet_parsing_error_count += 1;
+                warn!("*** Parsing error: {:?}", value);
+                events.push(ConnEvent::Ignored { reason: IgnoreReason::ParseError });
+                Ok(())
+            }
+            Ok(tcp) => {
+                let src = SocketAddr::new(datagram.src.into(), tcp.source_port());
+                let dst = SocketAddr::new(datagram.dst.into(), tcp.destination_port());
+                self.process_tcp(packet, (src, dst), &tcp, &datagram.payload, encapsulation, events)
+            }
+        }
+    }
+
+    /// Track the TCP segment of an IPv6 packet or of a reassembled datagram, as walked by [Ipv6Walker], where the
+    /// packet is the one that completed the datagram
+    fn process_ipv6(&mut self, packet: &Packet, upper_layer: Option<UpperLayer>, encapsulation: Encapsulation,
+                    events: &mut Vec<ConnEvent>) -> Result<(), error::Error> {
+        if let Some(upper_layer) = &upper_layer {
+            self.learn_dns_names_of_datagram(upper_layer.dst.into(), upper_layer.protocol, &upper_layer.payload);
+        }
+        // Fragments that do not complete a datagram yet are counted along with the other protocols
+        let upper_layer = match upper_layer.filter(|upper_layer| upper_layer.protocol == ip_number::TCP) {
+            None => {
+                self.packet_not_tcp_count += 1;
+                events.push(ConnEvent::Ignored { reason: IgnoreReason::NotTcp });
+                return Ok(());
+            }
+            Some(upper_layer) => { upper_layer }
+        };
+        match TcpHeaderSlice::from_slice(&upper_layer.payload) {
+            Err(value) => {
+                self.packet_parsing_error_count += 1;
+                warn!("*** Parsing error: {:?}", value);
+                events.push(ConnEvent::Ignored { reason: IgnoreReason::ParseError });
+                Ok(())
+            }
+            Ok(tcp) => {
+                let src = SocketAddr::new(upper_layer.src.into(), tcp.source_port());
+                let dst = SocketAddr::new(upper_layer.dst.into(), tcp.destination_port());
+                self.process_tcp(packet, (src, dst), &tcp, &upper_layer.payload, encapsulation, events)
+            }
+        }
+    }
+
+    /// Track a TCP segment of either address family, given the captured packet, its source and destination and the
+    /// segment with its header
+    fn process_tcp(&mut self, packet: &Packet, (src, dst): (SocketAddr, SocketAddr), tcp: &TcpHeaderSlice, segment: &[u8],
+                   encapsulation: Encapsulation, events: &mut Vec<ConnEvent>) -> Result<(), error::Error> {
+        let packet_ts = capture_ts(packet.header);
+        let payload = &segment[tcp.slice().len()..];
+        let tcp_payload_len = payload.len() as u16;
+        let (conn_sign, packet_dir) = Conn::sign_by_tuple(src.ip(), src.port(), dst.ip(), dst.port());
+        if let Some(ignored_flows) = &mut self.ignored_flows {
+            if self.ignore_rules.iter().any(|rule| rule.matches(&src, &dst)) {
+                ignored_flows.insert(conn_sign);
+                self.ignored_packet_count += 1;
+                events.push(ConnEvent::Ignored { reason: IgnoreReason::MatchedIgnoreRule });
+                return Ok(());
+            }
+        }
+        let flow_key = self.flow_keyer.key(conn_sign, &encapsulation);
+        let conn_key = match self.resolve_key(flow_key, src.ip(), dst.ip()) {
+            None => {
+                events.push(ConnEvent::Ignored { reason: IgnoreReason::KeyCollision });
+                return Ok(());
+            }
+            Some(conn_key) => { conn_key }
+        };
+        if conn_key != flow_key && !self.conn_list.contains_key(&conn_key) {
+            debug!("Flow key {:032x} of {} and {} is held by another connection, probed to {:032x}", flow_key, src, dst, conn_key);
+            self.key_collision_count += 1;
+        }
+        if !self.conn_list.contains_key(&conn_key) && !self.conn_rate_limiter.admit(packet_ts) {
+            events.push(ConnEvent::Ignored { reason: IgnoreReason::RateLimited });
+            return Ok(());
+        }
+        // The payload of a segment with a wrong checksum is not buffered, and the retransmission fills its place
+        let checksum_ok = !self.checksum_check || tcp_checksum_ok(src.ip(), dst.ip(), tcp, payload);
+        if !checksum_ok {
+            self.checksum_error_count += 1;
+        }
+        let ready_bytes = self.ready_bytes;
+        let headers_only = self.headers_only;
+        let midstream_pickup = self.midstream_pickup;
+        let payload_preview_len = self.payload_preview_len;
+        let check_tls_keys = self.key_log.is_some();
+        let alert_rules = self.alert_rules.clone();
+        let interface_mtu = self.interface_mtu;
+        let classifier = self.classifier.clone();
+        let track_syn_flood = self.syn_flood.is_some();
+        let addresses = match packet_dir {
+            PacketDir::SrcLowAddr => { (src, dst) }
+            PacketDir::SrcHighAddr => { (dst, src) }
+        };
+        let conn = self.get_connection_or_add_new(conn_key, conn_sign, addresses, encapsulation, packet_ts, events);
+        let prev_state = conn.state.clone();
+        conn.last_packet_ts = packet_ts;
+        if !checksum_ok {
+            conn.checksum_errors += 1;
+        }
+        let mut nat_twin_key = None;
+        conn.count_handshake_retransmit(&packet_dir, tcp);
+        if midstream_pickup && !tcp.syn() && conn.flow(&packet_dir).packet_count == 0 {
+            conn.set_initial_sequence_number(&packet_dir, tcp.sequence_number().wrapping_sub(1));
+            if conn.state == ConnState::Created && !tcp.rst() {
+                conn.state = ConnState::EstablishedMidstream;
+            }
+        }
+        if tcp.rst() {
+            // With RST we don't care who sent first and we no longer handle data
+            conn.state = ConnState::Closed(packet_dir.to_owned());
+        } else if tcp.fin() || conn.state.is_closing() {
+            if let Some(state) = conn.state.closing_transition(&packet_dir, tcp, tcp_payload_len) {
+                conn.state = state;
+            }
+        } else {
+            // Check if connection is new and we still look for SYN
+            match &conn.state {
+                // A SYN without ACK
+                ConnState::Created if tcp.syn() && !tcp.ack() => {
+                    conn.state = ConnState::SynSent(packet_dir.to_owned(), tcp.sequence_number().wrapping_add(1));
+                    conn.syn_packet_ts = Some(packet_ts);
+                    conn.initiator = Some(packet_dir.to_owned());
+                    conn.set_initial_sequence_number(&packet_dir, tcp.sequence_number());
+                    conn.process_tcp_options(&packet_dir, tcp);
+                }
+                // Simultaneous open - the other side sent its own SYN before it got the first one
+                ConnState::SynSent(syn_dir, expected_tcp_ack) if tcp.syn() && !tcp.ack() && syn_dir != &packet_dir => {
+                    conn.state = ConnState::SynReceived(syn_dir.to_owned(), *expected_tcp_ack);
+                    conn.set_initial_sequence_number(&packet_dir, tcp.sequence_number());
+                    conn.process_tcp_options(&packet_dir, tcp);
+                }
+                ConnState::SynSent(syn_dir, expected_tcp_ack) | ConnState::SynReceived(syn_dir, expected_tcp_ack) => {
+                    // After a simultaneous open, the SYN of the other side was already seen, so its ack of the first SYN
+                    // completes the handshake, with or without a SYN flag
+                    let simultaneous = matches!(conn.state, ConnState::SynReceived(..));
+                    // Data on the SYN (TFO) may be acknowledged along with it, or only the SYN itself
+                    let syn_data_acked = tcp.acknowledgment_number().wrapping_sub(*expected_tcp_ack) as u64;
+                    if (tcp.syn() || simultaneous) && tcp.ack() && syn_dir != &packet_dir &&
+                        syn_data_acked <= conn.flow(syn_dir).byte_count {
+                        let syn_dir = syn_dir.to_owned();
+                        if !simultaneous {
+                            conn.set_initial_sequence_number(&packet_dir, tcp.sequence_number());
+                            conn.process_tcp_options(&packet_dir, tcp);
+                        }
+                        let summary = conn.opened_summary(&syn_dir, packet_ts);
+                        conn.handshake_rtt = summary.handshake_rtt;
+                        events.push(ConnEvent::Opened { conn_sequence: conn.conn_sequence, summary });
+                        conn.check_handshake_options(interface_mtu, events);
+                        nat_twin_key = conn.nat_twin_key();
+                        conn.state = ConnState::Established(syn_dir);
                     }
                 }
+                _ => {}
+            }
+        }
+        let just_closed = conn.state != prev_state && conn.state.is_closed() && !prev_state.is_closed();
+        // Whether the connection was and is half-open, by its server
+        let half_open_change = if track_syn_flood && conn.state != prev_state {
+            Some((half_open_server(&prev_state, addresses), half_open_server(&conn.state, addresses)))
+        } else {
+            None
+        };
+        if conn.state != prev_state {
+            if just_closed && conn.closed_ts.is_none() {
+                conn.closed_ts = Some(packet_ts);
+            }
+            events.push(ConnEvent::StateChange {
+                conn_sequence: conn.conn_sequence,
+                from: prev_state,
+                to: conn.state.clone(),
+            });
+        }
+        // The payload of a SYN or SYN/ACK (TFO) starts right after the SYN, that takes one sequence number
+        let payload_seq = if tcp.syn() { tcp.sequence_number().wrapping_add(1) } else { tcp.sequence_number() };
+        conn.track_client_rtt(packet_ts, &packet_dir, tcp);
+        conn.track_acks(&packet_dir, tcp, tcp_payload_len);
+        conn.track_sack(&packet_dir, tcp, tcp_payload_len);
+        conn.track_nagle_stalls(packet_ts, &packet_dir, tcp, tcp_payload_len);
+        conn.track_window_stalls(packet_ts, &packet_dir, tcp, tcp_payload_len);
+        conn.check_window(&packet_dir, tcp, events);
+        let events_before = events.len();
+        let retransmits_before = conn.flow(&packet_dir).segments.retransmitted;
+        let dropped_before = conn.flow(&packet_dir).dropped_bytes;
+        let held_before = conn.flow(&packet_dir).held_bytes();
+        let payload_data: &[u8] = if headers_only || conn.stats_only || !checksum_ok { &[] } else { payload };
+        let result = conn.add_bytes(payload_seq, tcp_payload_len as usize, &packet_dir, payload_data,
+                                    ready_bytes, events);
+        let flow = conn.flow(&packet_dir);
+        let conn_held_bytes = conn.held_bytes();
+        let added_held_bytes = flow.held_bytes().saturating_sub(held_before);
+        let flow_max_held_bytes = flow.max_held_bytes;
+        let retransmitted = conn.flow(&packet_dir).segments.retransmitted > retransmits_before;
+        let dropped_bytes = conn.flow(&packet_dir).dropped_bytes - dropped_before;
+        if payload_preview_len > 0 && !headers_only && tcp_payload_len > 0 && flow.data_packet_count == 1 {
+            let preview = printable_preview(payload, payload_preview_len);
+            events.push(ConnEvent::FirstData { conn_sequence: conn.conn_sequence, dir: packet_dir.to_owned(), preview });
+        }
+        if retransmitted {
+            conn.flow_mut(&packet_dir).record_retransmit(packet_ts, payload_seq, tcp_payload_len as usize);
+        }
+        // Trouble is attributed to an ARP event once per connection
+        let (low, high) = conn.addresses();
+        let check_arp = (tcp.rst() || retransmitted) && conn.app.tags.get(ARP_TAG).is_none();
+        let (conn_sequence, nat_duplicate) = (conn.conn_sequence, conn.is_nat_duplicate());
+        if !headers_only {
+            conn.log(tcp, tcp_payload_len, &packet_dir);
+        }
+        conn.record_packet(packet_ts, &packet_dir, tcp, tcp_payload_len);
+        // The flags are the 14th byte of the TCP header, without the NS bit
+        let ip_header_len = if src.is_ipv4() { IPV4_MIN_HEADER_LEN } else { IPV6_HEADER_LEN };
+        conn.flow_mut(&packet_dir).activity.add_packet(packet_ts, ip_header_len + segment.len(), tcp.slice()[13]);
+        if let Some(features) = &mut conn.features {
+            features.add_packet(packet_ts, &packet_dir, tcp_payload_len);
+        }
+        let frames_due = match &mut conn.frames {
+            None => { false }
+            Some(frames) => {
+                frames.push(packet);
+                frames.is_due()
+            }
+        };
+        if let Some(classifier) = &classifier {
+            classifier.lock().unwrap().check(conn, false);
+        }
+        let ttfb = conn.track_ttfb(packet_ts, &packet_dir, tcp_payload_len).zip(conn.server());
+        if let Some(alert_rules) = &alert_rules {
+            conn.check_alerts(alert_rules, events);
+        }
+        // A closed connection hands over whatever it has left
+        if just_closed {
+            for dir in conn.pending_dirs() {
+                events.push(ConnEvent::BufferReady { conn_sequence: conn.conn_sequence, dir });
             }
         }
+        let client_random = if check_tls_keys && conn.app.tls_keys.is_none() { conn.app.client_random } else { None };
+        if events[events_before..].iter().any(|event| matches!(event, ConnEvent::BufferReady { .. })) {
+            self.mark_ready(conn_key);
+        }
+        if let Some(client_random) = client_random {
+            self.match_tls_keys(conn_key, &client_random);
+        }
+        if let Some(arp) = self.arp.recent_event(&[low.ip(), high.ip()], packet_ts).filter(|_| check_arp) {
+            let arp = arp.clone();
+            if let Some(conn) = self.conn_list.get_mut(&conn_key) {
+                conn.app.tags.set(ARP_TAG, &arp.ip.to_string());
+                let delay = packet_ts.saturating_sub(arp.ts);
+                events.push(ConnEvent::ArpCorrelated { conn_sequence: conn.conn_sequence, arp, delay });
+            }
+        }
+        if let (Some(detector), Some((was_half_open, half_open))) = (&mut self.syn_flood, half_open_change) {
+            if let Some(server) = was_half_open {
+                detector.remove_half_open(server);
+            }
+            if let Some(alarm) = half_open.and_then(|server| detector.add_syn(server, packet_ts)) {
+                for observer in &self.observers {
+                    observer.lock().unwrap().on_syn_flood(&alarm);
+                }
+                events.push(ConnEvent::SynFlood { alarm });
+            }
+        }
+        let watermarks = &mut self.watermarks;
+        watermarks.held_bytes += added_held_bytes;
+        watermarks.max_held_bytes = watermarks.max_held_bytes.max(watermarks.held_bytes);
+        watermarks.max_flow_held_bytes = watermarks.max_flow_held_bytes.max(flow_max_held_bytes);
+        watermarks.dropped_bytes += dropped_bytes;
+        if dropped_bytes > 0 {
+            self.handle_flow_overflow(conn_key, &packet_dir, dropped_before == 0, events);
+        }
+        if added_held_bytes > 0 {
+            self.check_buffer_budget(conn_key, conn_held_bytes, events);
+        }
+        if self.flow_log.is_some() {
+            self.log_flow_events(conn_key, events);
+        }
+        if !self.observers.is_empty() {
+            let established = events.iter().any(|event| matches!(event, ConnEvent::Opened { .. }));
+            self.notify_observers(conn_key, &packet_dir, established);
+        }
+        if let (true, Some(conn_pcap), Some(conn)) = (frames_due, &self.conn_pcap, self.conn_list.get_mut(&conn_key)) {
+            conn_pcap.lock().unwrap().write(conn);
+        }
+        // The twin on the other side of a NAT already counted the same latencies
+        let nat_duplicate = match nat_twin_key {
+            Some(twin_key) => { self.link_nat_twin(twin_key, conn_key, conn_sequence, events) }
+            None => { nat_duplicate }
+        };
+        if let Some(heatmap) = self.heatmap.as_mut().filter(|_| !nat_duplicate) {
+            for event in events.iter() {
+                if let ConnEvent::Opened { summary: OpenedSummary { server, handshake_rtt: Some(rtt), .. }, .. } = event {
+                    heatmap.record(LatencyMetric::HandshakeRtt, packet_ts, server.ip(), server.port(), *rtt);
+                }
+            }
+            if let Some((ttfb, server)) = ttfb {
+                heatmap.record(LatencyMetric::Ttfb, packet_ts, server.ip(), server.port(), ttfb);
+            }
+        }
+        result
+    }
+}
+
+impl Default for Connections {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+    use etherparse::{PacketBuilder, TcpOptionElement};
+    use pcap::{Packet, PacketHeader};
+    use crate::conn::{ConnState, fold_ipv6, PacketDir};
+    use crate::digest::PayloadExport;
+    use crate::event::{ConnEvent, IgnoreReason};
+    use crate::json::JsonValue;
+    use crate::time_format::{TimeFormat, TimeFormatter, TimeZone};
+    use super::{Connections, ConnTimeouts};
+
+    /// An Ethernet frame of a TCP segment, with the flags given as letters: S for SYN, A for ACK, F for FIN and R for RST
+    fn frame(src: SocketAddr, dst: SocketAddr, seq: u32, ack: u32, flags: &str, payload: &[u8]) -> Vec<u8> {
+        frame_with_options(src, dst, seq, ack, flags, &[], payload)
+    }
+
+    /// An Ethernet frame of a TCP segment with the given TCP options, see [frame]
+    fn frame_with_options(src: SocketAddr, dst: SocketAddr, seq: u32, ack: u32, flags: &str, options: &[TcpOptionElement],
+                          payload: &[u8]) -> Vec<u8> {
+        let ethernet = PacketBuilder::ethernet2([2, 0, 0, 0, 0, 1], [2, 0, 0, 0, 0, 2]);
+        let ip = match (src.ip(), dst.ip()) {
+            (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => { ethernet.ipv4(src_ip.octets(), dst_ip.octets(), 64) }
+            (src_ip, dst_ip) => { ethernet.ipv6(to_v6(src_ip).octets(), to_v6(dst_ip).octets(), 64) }
+        };
+        let mut tcp = ip.tcp(src.port(), dst.port(), seq, 65535);
+        if flags.contains('S') {
+            tcp = tcp.syn();
+        }
+        if flags.contains('A') {
+            tcp = tcp.ack(ack);
+        }
+        if flags.contains('F') {
+            tcp = tcp.fin();
+        }
+        if flags.contains('R') {
+            tcp = tcp.rst();
+        }
+        if !options.is_empty() {
+            tcp = tcp.options(options).unwrap();
+        }
+        let mut data = Vec::new();
+        tcp.write(&mut data, payload).unwrap();
+        data
+    }
+
+    fn to_v6(ip: IpAddr) -> Ipv6Addr {
+        match ip {
+            IpAddr::V4(ip) => { ip.to_ipv6_mapped() }
+            IpAddr::V6(ip) => { ip }
+        }
+    }
+
+    /// Process a frame captured at the given millisecond of the capture
+    fn process(connections: &mut Connections, ts_ms: u32, data: &[u8]) -> Vec<ConnEvent> {
+        process_captured(connections, ts_ms, data, data.len())
+    }
+
+    /// Process the captured part of a frame of the given length
+    fn process_captured(connections: &mut Connections, ts_ms: u32, data: &[u8], len: usize) -> Vec<ConnEvent> {
+        let header = PacketHeader {
+            ts: libc::timeval { tv_sec: (ts_ms / 1000) as libc::time_t, tv_usec: (ts_ms % 1000 * 1000) as libc::suseconds_t },
+            caplen: data.len() as u32,
+            len: len as u32,
+        };
+        connections.process_packet(&Packet::new(&header, data)).unwrap()
+    }
+
+    /// Feed the frames of a pcap file of tests/fixtures, as written with microsecond timestamps in little endian, the
+    /// way --read-file feeds those of a saved capture
+    fn process_fixture(connections: &mut Connections, name: &str) -> Vec<ConnEvent> {
+        let path = format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name);
+        let file = std::fs::read(path).unwrap();
+        assert_eq!(file[..4], 0xa1b2c3d4u32.to_le_bytes());
+        let field = |pos: usize| u32::from_le_bytes(file[pos..pos + 4].try_into().unwrap());
+        let mut events = Vec::new();
+        let mut pos = 24;
+        while pos < file.len() {
+            let header = PacketHeader {
+                ts: libc::timeval { tv_sec: field(pos) as libc::time_t, tv_usec: field(pos + 4) as libc::suseconds_t },
+                caplen: field(pos + 8),
+                len: field(pos + 12),
+            };
+            let data = &file[pos + 16..pos + 16 + header.caplen as usize];
+            events.extend(connections.process_packet(&Packet::new(&header, data)).unwrap());
+            pos += 16 + header.caplen as usize;
+        }
+        events
+    }
+
+    #[test]
+    fn saved_capture_is_tracked() {
+        // An HTTP exchange over IPv4 that closes, a DNS query over UDP, and an IPv6 connection that is reset
+        let mut connections = Connections::new();
+        let events = process_fixture(&mut connections, "two_connections.pcap");
+        assert_eq!(events.iter().filter(|event| matches!(event, ConnEvent::NewConnection { .. })).count(), 2);
+        assert_eq!(events.iter().filter(|event| matches!(event, ConnEvent::Ignored { .. })).count(), 1);
+        assert_eq!(connections.len(), 2);
+        let http = connections.find_pair("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+        assert_eq!(http.len(), 1);
+        assert_eq!((http[0].packets_src_low, http[0].packets_src_high), (5, 3));
+        assert_eq!((http[0].bytes_src_low, http[0].bytes_src_high), (37, 43));
+        assert!(matches!(http[0].state, ConnState::TimeWait(PacketDir::SrcLowAddr)));
+        let reset = connections.find_pair("2001:db8::1".parse().unwrap(), "2001:db8::2".parse().unwrap());
+        assert_eq!(reset.len(), 1);
+        assert_eq!((reset[0].packets_src_low, reset[0].packets_src_high), (2, 2));
+        assert_eq!((reset[0].bytes_src_low, reset[0].bytes_src_high), (100, 0));
+        assert!(matches!(reset[0].state, ConnState::Closed(PacketDir::SrcHighAddr)));
+    }
+
+    #[test]
+    fn ipv6_connections_with_colliding_signatures_are_kept_apart() {
+        // Two client addresses that fold to the same 32 bits, both lower than the server, so the signatures are equal
+        let mut folded = HashMap::new();
+        let (ip_a, ip_b) = (1u128..1 << 24).find_map(|host| {
+            let ip = IpAddr::V6(Ipv6Addr::from(0x2001_0db8_0000_0000_0000_0000_0000_0000 | host));
+            folded.insert(fold_ipv6(ip), ip).map(|other| (other, ip))
+        }).unwrap();
+        let server: SocketAddr = "[2001:db9::1]:443".parse().unwrap();
+        let (a, b) = (SocketAddr::new(ip_a, 40000), SocketAddr::new(ip_b, 40000));
+
+        let mut connections = Connections::new();
+        process(&mut connections, 0, &frame(a, server, 100, 0, "S", b""));
+        process(&mut connections, 1, &frame(b, server, 500, 0, "S", b""));
+        process(&mut connections, 2, &frame(server, a, 1000, 101, "SA", b""));
+        process(&mut connections, 3, &frame(server, b, 2000, 501, "SA", b""));
+        process(&mut connections, 4, &frame(a, server, 101, 1001, "A", b"from a"));
+        process(&mut connections, 5, &frame(b, server, 501, 2001, "A", b"from b!"));
+
+        assert_eq!(connections.len(), 2);
+        for (client, byte_count) in [(a, 6), (b, 7)] {
+            let summaries = connections.find_pair(client.ip(), server.ip());
+            assert_eq!(summaries.len(), 1);
+            assert_eq!(summaries[0].low_addr, client);
+            assert_eq!(summaries[0].bytes_src_low, byte_count);
+            let conn = connections.find_conn(client, server).unwrap();
+            assert_eq!(conn.addresses(), (client, server));
+        }
+    }
+
+    /// A connection between a client and a server, where the SYN and the SYN/ACK carry data, followed by one more
+    /// segment each way. Returns the bytes that were handed out of the client and server flows.
+    fn data_on_handshake(connections: &mut Connections, client: SocketAddr, server: SocketAddr) -> (Vec<u8>, Vec<u8>) {
+        let (notifier, _ready) = channel();
+        connections.set_ready_notifier(1, notifier);
+        process(connections, 0, &frame(client, server, 100, 0, "S", b"hello"));
+        process(connections, 1, &frame(server, client, 1000, 106, "SA", b"hi"));
+        process(connections, 2, &frame(client, server, 106, 1003, "A", b" world"));
+        process(connections, 3, &frame(server, client, 1003, 112, "A", b" there"));
+        let (mut client_bytes, mut server_bytes) = (Vec::new(), Vec::new());
+        for (summary, dir, data) in connections.drain_ready() {
+            let from_client = (dir == PacketDir::SrcLowAddr) == (summary.low_addr == client);
+            if from_client { client_bytes.extend(data) } else { server_bytes.extend(data) }
+        }
+        (client_bytes, server_bytes)
+    }
+
+    #[test]
+    fn data_on_syn_starts_the_client_stream() {
+        let client: SocketAddr = "10.0.0.1:40000".parse().unwrap();
+        let server: SocketAddr = "10.0.0.2:80".parse().unwrap();
+        let mut connections = Connections::new();
+        let (client_bytes, _) = data_on_handshake(&mut connections, client, server);
+        assert_eq!(client_bytes, b"hello world");
+    }
+
+    #[test]
+    fn data_on_syn_ack_starts_the_server_stream() {
+        let client: SocketAddr = "10.0.0.1:40000".parse().unwrap();
+        let server: SocketAddr = "10.0.0.2:80".parse().unwrap();
+        let mut connections = Connections::new();
+        let (_, server_bytes) = data_on_handshake(&mut connections, client, server);
+        assert_eq!(server_bytes, b"hi there");
+    }
+
+    #[test]
+    fn data_on_handshake_is_counted() {
+        // The server is the lower address, so the SYN/ACK data is counted on the low side
+        let client: SocketAddr = "10.0.0.2:40000".parse().unwrap();
+        let server: SocketAddr = "10.0.0.1:80".parse().unwrap();
+        let mut connections = Connections::new();
+        let (client_bytes, server_bytes) = data_on_handshake(&mut connections, client, server);
+        assert_eq!((client_bytes.len(), server_bytes.len()), (11, 8));
+
+        let conn = connections.find_conn(client, server).unwrap();
+        assert_eq!(conn.flow(&PacketDir::SrcHighAddr).byte_count, 11);
+        assert_eq!(conn.flow(&PacketDir::SrcLowAddr).byte_count, 8);
+        let summary = &connections.find_pair(client.ip(), server.ip())[0];
+        assert_eq!((summary.bytes_src_low, summary.bytes_src_high), (8, 11));
+    }
+
+    #[test]
+    fn connections_age_by_the_capture_time() {
+        let timeouts = ConnTimeouts {
+            established: Duration::from_secs(60),
+            closed: Duration::from_secs(5),
+            ..ConnTimeouts::default()
+        };
+        let server: SocketAddr = "10.0.0.2:80".parse().unwrap();
+        let (idle, closed, late) = ("10.0.0.1:40000".parse().unwrap(), "10.0.0.1:40001".parse().unwrap(),
+                                    "10.0.0.1:40002".parse().unwrap());
+        let mut connections = Connections::new();
+        for (index, client) in [idle, closed].into_iter().enumerate() {
+            let ts = index as u32 * 10;
+            process(&mut connections, ts, &frame(client, server, 100, 0, "S", b""));
+            process(&mut connections, ts + 1, &frame(server, client, 1000, 101, "SA", b""));
+            process(&mut connections, ts + 2, &frame(client, server, 101, 1001, "A", b""));
+        }
+        process(&mut connections, 1000, &frame(closed, server, 101, 1001, "R", b""));
+        // Nothing is old enough by the capture time, however long the packets took to process
+        assert!(connections.reap_closed(&timeouts).is_empty());
+
+        process(&mut connections, 30_000, &frame(late, server, 100, 0, "S", b""));
+        let reaped = connections.reap_closed(&timeouts);
+        assert_eq!(reaped.iter().map(|conn| (conn.summary.low_addr, conn.idle)).collect::<Vec<_>>(), vec![(closed, None)]);
+
+        process(&mut connections, 60_002, &frame(late, server, 100, 0, "S", b""));
+        let reaped = connections.reap_closed(&timeouts);
+        assert_eq!(reaped.iter().map(|conn| (conn.summary.low_addr, conn.idle)).collect::<Vec<_>>(),
+                   vec![(idle, Some(Duration::from_secs(60)))]);
+    }
+
+    #[test]
+    fn unanswered_syn_fails_by_the_capture_time() {
+        // The client is the higher address, so the failure is reported for the high side
+        let client: SocketAddr = "10.0.0.9:40000".parse().unwrap();
+        let server: SocketAddr = "10.0.0.2:80".parse().unwrap();
+        let other: SocketAddr = "10.0.0.3:40000".parse().unwrap();
+        let timeout = Duration::from_secs(3);
+        let mut connections = Connections::new();
+        process(&mut connections, 0, &frame(client, server, 100, 0, "S", b""));
+        process(&mut connections, 1000, &frame(client, server, 100, 0, "S", b""));
+        process(&mut connections, 2999, &frame(other, server, 100, 0, "S", b""));
+        assert!(connections.expire_handshakes(timeout).is_empty());
+
+        process(&mut connections, 3000, &frame(other, server, 100, 0, "S", b""));
+        let conn_sequence = connections.find_conn(client, server).unwrap().conn_sequence;
+        assert_eq!(connections.expire_handshakes(timeout),
+                   vec![ConnEvent::SetupFailed { conn_sequence, dir: PacketDir::SrcHighAddr }]);
+        let summaries = connections.find_pair(client.ip(), server.ip());
+        assert_eq!(summaries[0].state, ConnState::Failed(PacketDir::SrcHighAddr));
+    }
+
+    #[test]
+    fn handshake_events() {
+        let client: SocketAddr = "10.0.0.1:40000".parse().unwrap();
+        let server: SocketAddr = "10.0.0.2:80".parse().unwrap();
+        let mut connections = Connections::new();
+        let syn_sent = ConnState::SynSent(PacketDir::SrcLowAddr, 101);
+        assert_eq!(process(&mut connections, 0, &frame(client, server, 100, 0, "S", b"")), vec![
+            ConnEvent::NewConnection { conn_sequence: 1 },
+            ConnEvent::StateChange { conn_sequence: 1, from: ConnState::Created, to: syn_sent.clone() },
+        ]);
+
+        let events = process(&mut connections, 1, &frame(server, client, 1000, 101, "SA", b""));
+        assert_eq!(events.len(), 2);
+        match &events[0] {
+            ConnEvent::Opened { conn_sequence: 1, summary } => {
+                assert_eq!((summary.client, summary.server), (client, server));
+                assert_eq!(summary.handshake_rtt, Some(Duration::from_millis(1)));
+            }
+            event => { panic!("expected the connection to open, got {:?}", event) }
+        }
+        assert_eq!(events[1], ConnEvent::StateChange { conn_sequence: 1, from: syn_sent,
+            to: ConnState::Established(PacketDir::SrcLowAddr) });
+
+        assert_eq!(process(&mut connections, 2, &frame(client, server, 101, 1001, "A", b"")), vec![]);
+        assert_eq!(process(&mut connections, 3, &frame(client, server, 101, 1001, "A", b"GET")), vec![
+            ConnEvent::DataBuffered { conn_sequence: 1, dir: PacketDir::SrcLowAddr, bytes: 3 },
+        ]);
+    }
+
+    #[test]
+    fn truncated_frame_is_ignored() {
+        let data = frame("10.0.0.1:40000".parse().unwrap(), "10.0.0.2:80".parse().unwrap(), 100, 0, "S", b"");
+        let mut connections = Connections::new();
+        assert_eq!(process_captured(&mut connections, 0, &data[..40], data.len()),
+                   vec![ConnEvent::Ignored { reason: IgnoreReason::Truncated }]);
+        assert!(connections.is_empty());
+    }
+
+    #[test]
+    fn non_tcp_frame_is_ignored() {
+        let mut data = Vec::new();
+        PacketBuilder::ethernet2([2, 0, 0, 0, 0, 1], [2, 0, 0, 0, 0, 2]).ipv4([10, 0, 0, 1], [10, 0, 0, 2], 64)
+            .udp(5353, 53).write(&mut data, b"query").unwrap();
+        let mut connections = Connections::new();
+        assert_eq!(process(&mut connections, 0, &data), vec![ConnEvent::Ignored { reason: IgnoreReason::NotTcp }]);
+        assert!(connections.is_empty());
+    }
+
+    #[test]
+    fn connections_are_labeled_with_resolved_names() {
+        // A response to 10.0.0.1 with a question for example.com and an A record of 192.0.2.80
+        let mut message = vec![0, 7, 0x81, 0x80, 0, 1, 0, 1, 0, 0, 0, 0];
+        message.extend_from_slice(b"\x07example\x03com\x00\x00\x01\x00\x01");
+        message.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 1, 44, 0, 4, 192, 0, 2, 80]);
+        let mut data = Vec::new();
+        PacketBuilder::ethernet2([2, 0, 0, 0, 0, 2], [2, 0, 0, 0, 0, 1]).ipv4([10, 0, 0, 53], [10, 0, 0, 1], 64)
+            .udp(53, 5353).write(&mut data, &message).unwrap();
+        let mut connections = Connections::new();
+        connections.set_dns_names(true);
+        assert_eq!(process(&mut connections, 0, &data), vec![ConnEvent::Ignored { reason: IgnoreReason::NotTcp }]);
+        assert_eq!(connections.dns_name_count(), Some(1));
+        let client = "10.0.0.1:40000".parse().unwrap();
+        process(&mut connections, 1, &frame(client, "192.0.2.80:443".parse().unwrap(), 100, 0, "S", b""));
+        process(&mut connections, 2, &frame(client, "192.0.2.81:443".parse().unwrap(), 100, 0, "S", b""));
+        let mut names: Vec<Option<String>> = connections.summaries().into_iter().map(|summary| summary.resolved_name).collect();
+        names.sort();
+        assert_eq!(names, vec![None, Some(String::from("example.com"))]);
+    }
+
+    #[test]
+    fn bad_ipv4_checksum_is_ignored() {
+        let mut data = frame("10.0.0.1:40000".parse().unwrap(), "10.0.0.2:80".parse().unwrap(), 100, 0, "S", b"");
+        // The IPv4 header checksum follows the Ethernet header and 10 bytes of the IPv4 header
+        data[14 + 10] ^= 0xff;
+        let mut connections = Connections::new();
+        assert_eq!(process(&mut connections, 0, &data), vec![ConnEvent::Ignored { reason: IgnoreReason::BadChecksum }]);
+        assert!(connections.is_empty());
+    }
+
+    /// The value without the connection ages, that depend on when it is exported
+    fn without_ages(value: JsonValue) -> JsonValue {
+        match value {
+            JsonValue::Object(fields) => {
+                JsonValue::Object(fields.into_iter().filter(|(key, _)| key != "age_ms")
+                    .map(|(key, value)| (key, without_ages(value))).collect())
+            }
+            JsonValue::Array(items) => { JsonValue::Array(items.into_iter().map(without_ages).collect()) }
+            value => { value }
+        }
+    }
+
+    #[test]
+    fn json_export_round_trip() {
+        let server: SocketAddr = "10.0.0.2:80".parse().unwrap();
+        let server_v6: SocketAddr = "[2001:db8::2]:443".parse().unwrap();
+        let mut connections = Connections::new();
+        data_on_handshake(&mut connections, "10.0.0.1:40000".parse().unwrap(), server);
+        data_on_handshake(&mut connections, "[2001:db8::1]:40000".parse().unwrap(), server_v6);
+        process(&mut connections, 10, &frame("10.0.0.3:40000".parse().unwrap(), server, 100, 0, "S", b""));
+        process(&mut connections, 11, &frame(server, "10.0.0.4:40000".parse().unwrap(), 5000, 1, "A", b"midstream \"data\"\n"));
+
+        let time_format = TimeFormatter::new(TimeFormat::Epoch, TimeZone::Utc);
+        let exported = connections.to_json(&PayloadExport::Hex, &time_format);
+        let restored = Connections::from_json(&JsonValue::parse(&exported.to_pretty_string()).unwrap()).unwrap();
+        assert_eq!(restored.len(), connections.len());
+        assert_eq!(without_ages(restored.to_json(&PayloadExport::Hex, &time_format)), without_ages(exported));
+    }
+
+    #[test]
+    fn syn_with_the_last_sequence_number_wraps() {
+        let client: SocketAddr = "10.0.0.1:40000".parse().unwrap();
+        let server: SocketAddr = "10.0.0.2:80".parse().unwrap();
+        let mut connections = Connections::new();
+        process(&mut connections, 0, &frame(client, server, u32::MAX, 0, "S", b""));
+        process(&mut connections, 1, &frame(server, client, 1000, 0, "SA", b""));
+        process(&mut connections, 2, &frame(client, server, 0, 1001, "A", b"GET"));
+
+        let summaries = connections.find_pair(client.ip(), server.ip());
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].bytes_src_low, 3);
+    }
+
+    #[test]
+    fn sack_blocks_across_the_sequence_wrap() {
+        let client: SocketAddr = "10.0.0.1:40000".parse().unwrap();
+        let server: SocketAddr = "10.0.0.2:80".parse().unwrap();
+        let isn = u32::MAX - 1000;
+        let payload = [b'x'; 600];
+        // Relative sequence 0 is isn + 1, and the wrap around is 1000 bytes after it
+        let seq = |relative: u32| isn.wrapping_add(1).wrapping_add(relative);
+        let mut connections = Connections::new();
+        let sack_permitted = [TcpOptionElement::SelectiveAcknowledgementPermitted];
+        process(&mut connections, 0, &frame_with_options(client, server, isn, 0, "S", &sack_permitted, b""));
+        process(&mut connections, 1, &frame_with_options(server, client, 5000, seq(0), "SA", &sack_permitted, b""));
+        // The segments at 0 and 1200 are lost, and the one at 600 crosses the wrap around
+        for (ts, relative) in [(2, 600), (3, 1800)] {
+            process(&mut connections, ts, &frame(client, server, seq(relative), 5001, "A", &payload));
+        }
+        // The ACK is before the wrap around, and the first block starts before it and ends after it
+        let blocks = [TcpOptionElement::SelectiveAcknowledgement((seq(600), seq(1200)), [Some((seq(1800), seq(2400))), None, None])];
+        process(&mut connections, 4, &frame_with_options(server, client, 5001, seq(0), "A", &blocks, b""));
+        // The same holes again are not counted twice
+        process(&mut connections, 5, &frame_with_options(server, client, 5001, seq(0), "A", &blocks, b""));
+        for (ts, relative) in [(6, 0), (7, 1200)] {
+            process(&mut connections, ts, &frame(client, server, seq(relative), 5001, "A", &payload));
+        }
+        process(&mut connections, 8, &frame(server, client, 5001, seq(2400), "A", b""));
+
+        let summaries = connections.find_pair(client.ip(), server.ip());
+        let sack_loss = &summaries[0].sack_loss_src_low;
+        assert_eq!((sack_loss.sack_acks, sack_loss.holes, sack_loss.hole_bytes), (2, 2, 1200));
+        assert_eq!(sack_loss.retransmitted_holes, 2);
+        assert_eq!(summaries[0].sack_loss_src_high.holes, 0);
     }
 }
@@ -0,0 +1,37 @@
+use std::fmt;
+use std::time::Duration;
+use crate::latency::LatencyHistogram;
+
+/// Statistics of a thread that consumes ready buffers, so the consuming side is observable like the capture side
+#[derive(Clone)]
+pub struct ConsumerStats {
+    /// Consumer name, for reports
+    name: String,
+    /// Number of buffers that were delivered to the consumer
+    buffer_count: u64,
+    /// Total bytes of the delivered buffers
+    byte_count: u64,
+    /// Processing time of every buffer (in nanoseconds)
+    latency: LatencyHistogram,
+}
+
+impl ConsumerStats {
+    pub fn new(name: &str) -> Self {
+        Self { name: name.to_string(), buffer_count: 0, byte_count: 0, latency: LatencyHistogram::new() }
+    }
+
+    /// Count a buffer that was processed by the consumer, in the given time
+    pub fn record(&mut self, bytes: usize, elapsed: Duration) {
+        self.buffer_count += 1;
+        self.byte_count += bytes as u64;
+        self.latency.record(elapsed.as_nanos() as u64);
+    }
+}
+
+impl fmt::Display for ConsumerStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {} buffers, {} bytes, processing p50 {}us, p99 {}us, max {}us", self.name, self.buffer_count,
+               self.byte_count, self.latency.percentile(0.5) / 1000, self.latency.percentile(0.99) / 1000,
+               self.latency.max() / 1000)
+    }
+}
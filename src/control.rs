@@ -0,0 +1,274 @@
+use std::io::{BufRead, BufReader, Error, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use crate::conn::{Conn, ConnSummary};
+use crate::connections::TopOrder;
+use crate::digest::PayloadExport;
+use crate::json::JsonValue;
+use crate::leak::LeakReport;
+use crate::shards::ConnShards;
+use crate::time_format::TimeFormatter;
+
+/// Longest interval of a leak check
+const MAX_LEAK_CHECK_MINUTES: u64 = 24 * 60;
+/// Connections that list returns when no count is given, so a busy table does not make a huge response
+const DEFAULT_LIST_COUNT: usize = 1000;
+/// Connections that top returns when no count is given
+const DEFAULT_TOP_COUNT: usize = 10;
+
+const HELP: &str = "Commands:
+  find IP [PORT]   connections with this IP, and port if given, on either side
+  find-pair IP IP  connections between the two IPs
+  ranges IP:PORT IP:PORT
+                   captured byte ranges and holes of both flows of a connection, in offsets from the stream start
+  ladder IP:PORT IP:PORT
+                   ladder diagram of the last packets of a connection (requires --packet-history)
+  tag IP:PORT IP:PORT KEY[=VALUE]
+                   attach a tag to a connection, carried to its flow records and exports
+  stats            totals of the connections, bytes and untracked packets of every shard and of all of them
+  leak-check MINUTES
+                   sample the table now and after the given minutes, and report the connections that had no traffic,
+                   the buffers that only grew and the table counters that did not advance
+
+JSON commands, that respond with one line of JSON, or {\"error\": ...}:
+  list [COUNT]     summaries of the connections by sequence, up to COUNT (default 1000), and the number of connections
+  conn ID|SEQUENCE the connection with this identifier, as exported in 16 hex digits, or this sequence number
+  top [COUNT] [bytes|packets]
+                   summaries of the COUNT (default 10) connections with the most payload bytes or packets
+  stats json       the stats totals of every shard and of all of them
+
+  help             this text";
+
+/// Listen on a Unix socket for troubleshooting commands on the live connection table, one command per line.
+/// Every response is a list of lines that ends with an empty line. The socket file is replaced if it exists.
+/// Every client is served by its own thread, since some commands take long. The times of connections are formatted
+/// as in the exports.
+#[cfg(unix)]
+pub fn serve(path: &Path, connections: ConnShards, time_format: TimeFormatter) -> Result<(), Error> {
+    use std::os::unix::net::UnixListener;
+    use log::warn;
+
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let connections = connections.clone();
+            let time_format = time_format.clone();
+            thread::spawn(move || {
+                let result = stream.and_then(|stream| {
+                    let mut writer = stream.try_clone()?;
+                    for line in BufReader::new(stream).lines() {
+                        let response = run_command(&line?, &connections, &time_format);
+                        writer.write_all(response.as_bytes())?;
+                        writer.write_all(b"\n\n")?;
+                    }
+                    Ok(())
+                });
+                if let Err(error) = result {
+                    warn!("Control socket client failed: {}", error);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn serve(_path: &Path, _connections: ConnShards, _time_format: TimeFormatter) -> Result<(), Error> {
+    Err(Error::new(std::io::ErrorKind::Unsupported, "The control socket is only supported on Unix"))
+}
+
+/// Run one command line and return the response text
+fn run_command(line: &str, connections: &ConnShards, time_format: &TimeFormatter) -> String {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    if let Some(result) = run_json_command(&words, connections, time_format) {
+        return match result {
+            Err(message) => { JsonValue::object(vec![("error", message.into())]).to_string() }
+            Ok(json) => { json.to_string() }
+        };
+    }
+    let result = match words.as_slice() {
+        ["find", ip] => { parse_ip(ip).map(|ip| connections.find(ip, None)) }
+        ["find", ip, port] => {
+            parse_ip(ip).and_then(|ip| {
+                let port = port.parse::<u16>().map_err(|_| format!("invalid port '{}'", port))?;
+                Ok(connections.find(ip, Some(port)))
+            })
+        }
+        ["find-pair", a, b] => {
+            parse_ip(a).and_then(|a| Ok(connections.find_pair(a, parse_ip(b)?)))
+        }
+        ["ranges", a, b] => { return with_conn(a, b, connections, format_ranges); }
+        ["ladder", a, b] => { return with_conn(a, b, connections, Conn::ladder); }
+        ["tag", a, b, tag] => { return tag_conn(a, b, tag, connections); }
+        ["stats"] => { return format_reports(connections); }
+        ["leak-check", minutes] => { return leak_check(minutes, connections); }
+        ["help"] => { return HELP.to_string(); }
+        _ => { Err(String::from("unknown command, try help")) }
+    };
+    match result {
+        Err(message) => { format!("error: {}", message) }
+        Ok(summaries) => { format_summaries(&summaries) }
+    }
+}
+
+/// Run a command that responds with JSON, or return None if it is not one
+fn run_json_command(words: &[&str], connections: &ConnShards, time_format: &TimeFormatter)
+                    -> Option<Result<JsonValue, String>> {
+    let result = match words {
+        ["list"] => { Ok(list_json(connections, DEFAULT_LIST_COUNT)) }
+        ["list", count] => { parse_count(count).map(|count| list_json(connections, count)) }
+        ["conn", id] => { conn_json(id, connections, time_format) }
+        ["top"] => { Ok(top_json(connections, DEFAULT_TOP_COUNT, TopOrder::Bytes)) }
+        ["top", arg] => {
+            match parse_count(arg) {
+                Ok(count) => { Ok(top_json(connections, count, TopOrder::Bytes)) }
+                Err(_) => { TopOrder::parse(arg).map(|order| top_json(connections, DEFAULT_TOP_COUNT, order)) }
+            }
+        }
+        ["top", count, order] => {
+            parse_count(count).and_then(|count| Ok(top_json(connections, count, TopOrder::parse(order)?)))
+        }
+        ["stats", "json"] => { Ok(reports_json(connections)) }
+        _ => { return None; }
+    };
+    Some(result)
+}
+
+fn parse_count(text: &str) -> Result<usize, String> {
+    text.parse().map_err(|_| format!("invalid count '{}'", text))
+}
+
+/// The summaries of the first connections by sequence, and the number of all of them
+fn list_json(connections: &ConnShards, count: usize) -> JsonValue {
+    let summaries = connections.summaries();
+    JsonValue::object(vec![
+        ("count", summaries.len().into()),
+        ("connections", summaries.iter().take(count).map(|summary| summary.to_json()).collect::<Vec<JsonValue>>().into()),
+    ])
+}
+
+fn top_json(connections: &ConnShards, count: usize, order: TopOrder) -> JsonValue {
+    let summaries = connections.top(count, order);
+    JsonValue::object(vec![
+        ("connections", summaries.iter().map(|summary| summary.to_json()).collect::<Vec<JsonValue>>().into()),
+    ])
+}
+
+/// The full record of a connection, as exported without payloads, by identifier or sequence
+fn conn_json(id: &str, connections: &ConnShards, time_format: &TimeFormatter) -> Result<JsonValue, String> {
+    let conn_id = if id.len() == 16 { u64::from_str_radix(id, 16).ok() } else { None };
+    let conn_sequence = id.parse::<u32>().ok();
+    if conn_id.is_none() && conn_sequence.is_none() {
+        return Err(format!("invalid connection '{}', expected 16 hex digits or a sequence number", id));
+    }
+    for table in connections.tables() {
+        let table = table.lock().unwrap();
+        let conn = match conn_id {
+            Some(conn_id) => { table.find_by_id(conn_id) }
+            None => { conn_sequence.and_then(|conn_sequence| table.find_by_sequence(conn_sequence)) }
+        };
+        if let Some(conn) = conn {
+            return Ok(conn.to_json(&PayloadExport::Omit, time_format));
+        }
+    }
+    Err(String::from("no such connection"))
+}
+
+/// The totals of every shard and of all of them
+fn reports_json(connections: &ConnShards) -> JsonValue {
+    let reports = connections.reports();
+    let mut total = reports[0];
+    for report in &reports[1..] {
+        total.merge(report);
+    }
+    JsonValue::object(vec![
+        ("shards", reports.iter().map(|report| report.to_json()).collect::<Vec<JsonValue>>().into()),
+        ("total", total.to_json()),
+    ])
+}
+
+/// Format the connection between the two given "IP:PORT" endpoints, or return an error text
+fn with_conn<F: Fn(&Conn) -> String>(a: &str, b: &str, connections: &ConnShards, format: F) -> String {
+    match (a.parse::<SocketAddr>(), b.parse::<SocketAddr>()) {
+        (Ok(a), Ok(b)) => {
+            let shard = connections.conn_shard(a, b).map(|shard| shard.lock().unwrap());
+            match shard.as_ref().and_then(|shard| shard.find_conn(a, b)) {
+                None => { String::from("error: no such connection") }
+                Some(conn) => { format(conn) }
+            }
+        }
+        _ => { String::from("error: expected two IP:PORT addresses") }
+    }
+}
+
+/// Attach a "KEY" or "KEY=VALUE" tag to the connection between the two given "IP:PORT" endpoints
+fn tag_conn(a: &str, b: &str, tag: &str, connections: &ConnShards) -> String {
+    let (key, value) = tag.split_once('=').unwrap_or((tag, ""));
+    match (a.parse::<SocketAddr>(), b.parse::<SocketAddr>()) {
+        (Ok(a), Ok(b)) => {
+            if connections.tag_conn(a, b, key, value) {
+                String::from("tagged")
+            } else {
+                String::from("error: no such connection, too many tags or invalid tag")
+            }
+        }
+        _ => { String::from("error: expected two IP:PORT addresses") }
+    }
+}
+
+/// Sample the table, wait the given number of minutes without holding the locks, sample it again and compare
+fn leak_check(minutes: &str, connections: &ConnShards) -> String {
+    let minutes = match minutes.parse::<u64>() {
+        Ok(minutes) if (1..=MAX_LEAK_CHECK_MINUTES).contains(&minutes) => { minutes }
+        _ => { return format!("error: expected 1 to {} minutes", MAX_LEAK_CHECK_MINUTES); }
+    };
+    let old = connections.sample();
+    thread::sleep(Duration::from_secs(minutes * 60));
+    let new = connections.sample();
+    LeakReport::new(&old, &new).to_string()
+}
+
+/// A line per shard and one of the totals of all the shards
+fn format_reports(connections: &ConnShards) -> String {
+    let reports = connections.reports();
+    let mut lines = Vec::new();
+    let mut total = reports[0];
+    for (index, report) in reports.iter().enumerate() {
+        if index > 0 {
+            total.merge(report);
+        }
+        if reports.len() > 1 {
+            lines.push(format!("shard {}: {} open connections, {} packets", index, report.open_conns, report.packet_count));
+        }
+    }
+    lines.push(format!("total: {}", total));
+    lines.join("\n")
+}
+
+fn parse_ip(text: &str) -> Result<IpAddr, String> {
+    text.parse().map_err(|_| format!("invalid IP '{}'", text))
+}
+
+fn format_summaries(summaries: &[ConnSummary]) -> String {
+    let mut lines: Vec<String> = summaries.iter().map(|summary| summary.to_string()).collect();
+    lines.push(format!("{} connections", summaries.len()));
+    lines.join("\n")
+}
+
+fn format_ranges(conn: &Conn) -> String {
+    let (low, high) = conn.addresses();
+    let format_list = |ranges: Vec<std::ops::Range<usize>>| -> String {
+        ranges.iter().map(|range| format!("{}-{}", range.start, range.end)).collect::<Vec<String>>().join(" ")
+    };
+    let mut lines = Vec::new();
+    for (src, dst, flow) in [(low, high, &conn.flow_src_low), (high, low, &conn.flow_src_high)] {
+        lines.push(format!("{} -> {}: filled [{}], holes [{}]", src, dst, format_list(flow.filled_map()),
+                           format_list(flow.holes())));
+    }
+    lines.join("\n")
+}
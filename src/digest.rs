@@ -0,0 +1,197 @@
+use std::fmt;
+use std::ops::Range;
+use crate::json::JsonValue;
+use crate::utils::bytes_to_hex;
+
+/// How to export the payload of flows in JSON dumps
+#[derive(Clone, Debug)]
+pub enum PayloadExport {
+    /// No payload, only counters and states
+    Omit,
+    /// The raw bytes, hex encoded
+    Hex,
+    /// SHA-256 digests of chunks of the captured bytes, so equal content can be found without exposing it
+    Digests(ChunkMode),
+}
+
+/// How to cut captured bytes into chunks before hashing
+#[derive(Clone, Debug, PartialEq)]
+pub enum ChunkMode {
+    /// Chunks of this size, except the last one of every captured range
+    Fixed(usize),
+    /// Content-defined chunks of about this average size, so an insertion only changes the digests around it
+    ContentDefined(usize),
+}
+
+impl ChunkMode {
+    /// Parse "fixed:SIZE" or "cdc:AVERAGE_SIZE", where the average size of content-defined chunks is a power of 2
+    pub fn parse(arg: &str) -> Result<ChunkMode, String> {
+        let (kind, size) = arg.split_once(':').ok_or("expected fixed:SIZE or cdc:AVERAGE_SIZE")?;
+        let size: usize = size.trim().parse().map_err(|_| format!("invalid chunk size '{}'", size))?;
+        match kind.trim() {
+            "fixed" if size > 0 => { Ok(ChunkMode::Fixed(size)) }
+            "cdc" if size >= 64 && size.is_power_of_two() => { Ok(ChunkMode::ContentDefined(size)) }
+            "cdc" => { Err(String::from("the average CDC chunk size must be a power of 2, at least 64")) }
+            _ => { Err(format!("invalid chunk mode '{}'", arg)) }
+        }
+    }
+
+    /// Cut the bytes into chunk lengths
+    fn chunk_lengths(&self, data: &[u8]) -> Vec<usize> {
+        match self {
+            ChunkMode::Fixed(size) => { data.chunks(*size).map(|chunk| chunk.len()).collect() }
+            ChunkMode::ContentDefined(average) => { cdc_lengths(data, *average) }
+        }
+    }
+}
+
+impl fmt::Display for ChunkMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChunkMode::Fixed(size) => { write!(f, "fixed:{}", size) }
+            ChunkMode::ContentDefined(average) => { write!(f, "cdc:{}", average) }
+        }
+    }
+}
+
+/// Digest every chunk of the given captured ranges (inclusive ends, in stream offsets) of a buffer that starts at the
+/// given stream offset, as JSON objects with the chunk's offset in the stream, its length and its hex SHA-256.
+/// Chunks never span a hole.
+pub fn chunk_digests_json(data: &[u8], base_offset: usize, ranges: &[Range<usize>], mode: &ChunkMode) -> JsonValue {
+    let mut result = Vec::new();
+    for range in ranges {
+        let bytes = &data[range.start - base_offset..=range.end - base_offset];
+        let mut offset = 0;
+        for len in mode.chunk_lengths(bytes) {
+            let chunk = &bytes[offset..offset + len];
+            result.push(JsonValue::object(vec![
+                ("offset", (range.start + offset).into()),
+                ("len", len.into()),
+                ("sha256", bytes_to_hex(&sha256(chunk)).into()),
+            ]));
+            offset += len;
+        }
+    }
+    result.into()
+}
+
+/// Pseudo random values per byte for the gear rolling hash, from a fixed seed so chunks are stable across runs
+const GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state = 0x2545f4914f6cdd1du64;
+    let mut i = 0;
+    while i < 256 {
+        // splitmix64
+        state = state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// Content-defined chunk lengths with a gear rolling hash: a chunk ends where the top bits of the hash are zero,
+/// but never before a quarter of the average size or after four times the average size
+fn cdc_lengths(data: &[u8], average: usize) -> Vec<usize> {
+    let mask = !(u64::MAX >> average.trailing_zeros());
+    let (min_len, max_len) = (average / 4, average * 4);
+    let mut result = Vec::new();
+    let mut start = 0;
+    while start < data.len() {
+        let end = (start + max_len).min(data.len());
+        let mut cut = end;
+        let mut hash = 0u64;
+        for (i, b) in data[start..end].iter().enumerate() {
+            hash = (hash << 1).wrapping_add(GEAR[*b as usize]);
+            if i + 1 >= min_len && hash & mask == 0 {
+                cut = start + i + 1;
+                break;
+            }
+        }
+        result.push(cut - start);
+        start = cut;
+    }
+    result
+}
+
+const SHA256_K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// SHA-256 (FIPS 180-4) of the given bytes
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut h: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+    ];
+    // Padding: a single 1 bit, zeros, and the length in bits, up to a multiple of 64 bytes
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for block in message.chunks(64) {
+        let mut w = [0u32; 64];
+        for i in 0..16 {
+            w[i] = u32::from_be_bytes([block[4 * i], block[4 * i + 1], block[4 * i + 2], block[4 * i + 3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut hh] = h;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = hh.wrapping_add(s1).wrapping_add(ch).wrapping_add(SHA256_K[i]).wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            hh = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+        for (state, value) in h.iter_mut().zip([a, b, c, d, e, f, g, hh]) {
+            *state = state.wrapping_add(value);
+        }
+    }
+
+    let mut result = [0u8; 32];
+    for (i, value) in h.iter().enumerate() {
+        result[4 * i..4 * i + 4].copy_from_slice(&value.to_be_bytes());
+    }
+    result
+}
+
+/// HMAC-SHA-256 (RFC 2104) of the given bytes with the given key
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut block_key = [0u8; 64];
+    if key.len() > block_key.len() {
+        block_key[..32].copy_from_slice(&sha256(key));
+    } else {
+        block_key[..key.len()].copy_from_slice(key);
+    }
+    let mut inner: Vec<u8> = block_key.iter().map(|b| b ^ 0x36).collect();
+    inner.extend_from_slice(data);
+    let mut outer: Vec<u8> = block_key.iter().map(|b| b ^ 0x5c).collect();
+    outer.extend_from_slice(&sha256(&inner));
+    sha256(&outer)
+}
@@ -0,0 +1,68 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+
+/// Max addresses to keep names of, after which the oldest answers are evicted
+pub(crate) const DNS_CACHE_MAX_ENTRIES: usize = 65536;
+
+/// Names that clients resolved, by the client and the resolved address, from the DNS answers seen on UDP.
+/// Answers are kept regardless of their TTL, since clients keep using an address after it expires, until a newer
+/// answer replaces them or they are evicted.
+#[derive(Default)]
+pub(crate) struct DnsCache {
+    /// Name and insertion number by (client, address)
+    names: HashMap<(IpAddr, IpAddr), (String, u64)>,
+    /// Keys in insertion order, with their insertion numbers, where a replaced entry leaves a stale key behind
+    order: VecDeque<((IpAddr, IpAddr), u64)>,
+    insert_count: u64,
+}
+
+impl DnsCache {
+    /// Remember the name that the client resolved to the address
+    pub(crate) fn insert(&mut self, client: IpAddr, address: IpAddr, name: &str) {
+        self.insert_count += 1;
+        self.names.insert((client, address), (name.to_string(), self.insert_count));
+        self.order.push_back(((client, address), self.insert_count));
+        while self.order.len() > DNS_CACHE_MAX_ENTRIES {
+            if let Some((key, count)) = self.order.pop_front() {
+                if self.names.get(&key).is_some_and(|(_, inserted)| *inserted == count) {
+                    self.names.remove(&key);
+                }
+            }
+        }
+    }
+
+    /// The name that the client last resolved to the address, if seen
+    pub(crate) fn get(&self, client: IpAddr, address: IpAddr) -> Option<&str> {
+        self.names.get(&(client, address)).map(|(name, _)| name.as_str())
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.names.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use super::{DnsCache, DNS_CACHE_MAX_ENTRIES};
+
+    #[test]
+    fn names_per_client_and_eviction() {
+        let (client, other_client): (IpAddr, IpAddr) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+        let address: IpAddr = "192.0.2.1".parse().unwrap();
+        let mut cache = DnsCache::default();
+        cache.insert(client, address, "a.example.com");
+        cache.insert(other_client, address, "b.example.com");
+        assert_eq!(cache.get(client, address), Some("a.example.com"));
+        assert_eq!(cache.get(other_client, address), Some("b.example.com"));
+        assert_eq!(cache.get(address, client), None);
+        // A newer answer replaces the name, and is not evicted by its stale key
+        cache.insert(client, address, "c.example.com");
+        for host in 0..DNS_CACHE_MAX_ENTRIES as u32 - 1 {
+            cache.insert(client, IpAddr::from((0x0a01_0000 + host).to_be_bytes()), "filler.example.com");
+        }
+        assert_eq!(cache.get(client, address), Some("c.example.com"));
+        assert_eq!(cache.get(other_client, address), None);
+        assert_eq!(cache.len(), DNS_CACHE_MAX_ENTRIES);
+    }
+}
@@ -0,0 +1,54 @@
+use std::fmt;
+
+/// Errors of the capture and connection tracking engine, returned instead of panicking,
+/// so the engine can be embedded in processes that must not crash.
+#[derive(Debug)]
+pub enum Error {
+    /// The capture library failed, while doing what the context describes
+    Capture { context: String, source: pcap::Error },
+    /// Input could not be parsed or is inconsistent
+    Parse(String),
+    /// A resource limit was reached, so the request was refused
+    ResourceLimit { resource: &'static str, requested: usize, limit: usize },
+    /// File or network I/O failed
+    Io(std::io::Error),
+    /// The capture watchdog raised an alarm and was asked to stop the capture on it
+    Unhealthy(String),
+}
+
+impl Error {
+    /// Wrap a capture library error with a description of what was being done
+    pub fn capture(context: impl Into<String>, source: pcap::Error) -> Error {
+        Error::Capture { context: context.into(), source }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Capture { context, source } => { write!(f, "{}: {}", context, source) }
+            Error::Parse(message) => { write!(f, "Parsing error: {}", message) }
+            Error::ResourceLimit { resource, requested, limit } => {
+                write!(f, "Resource limit of {} reached: asked for {} while max allowed is {}", resource, requested, limit)
+            }
+            Error::Io(error) => { write!(f, "I/O error: {}", error) }
+            Error::Unhealthy(alarm) => { write!(f, "Capture stopped by the watchdog: {}", alarm) }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Capture { source, .. } => { Some(source) }
+            Error::Io(error) => { Some(error) }
+            _ => { None }
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Self {
+        Error::Io(error)
+    }
+}
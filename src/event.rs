@@ -0,0 +1,104 @@
+use std::fmt;
+use std::time::Duration;
+use crate::alert::Alert;
+use crate::arp::ArpEvent;
+use crate::conn::{ConnState, OpenedSummary, PacketDir};
+use crate::syn_flood::SynFloodAlarm;
+
+/// Something that happened while processing a packet, returned to the caller so it can build its own reporting.
+/// Connections are identified by their sequence (all time counter), as in the logs.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConnEvent {
+    /// A connection was seen for the first time and added to the list
+    NewConnection { conn_sequence: u32 },
+    /// A connection moved from one state to another
+    StateChange { conn_sequence: u32, from: ConnState, to: ConnState },
+    /// The handshake was completed, with its parameters
+    Opened { conn_sequence: u32, summary: OpenedSummary },
+    /// The handshake that was initiated by the given direction did not complete in time
+    SetupFailed { conn_sequence: u32, dir: PacketDir },
+    /// Payload bytes of one direction were copied to the flow buffer
+    DataBuffered { conn_sequence: u32, dir: PacketDir, bytes: usize },
+    /// The first payload of one direction, as a short printable preview, if previews are enabled
+    FirstData { conn_sequence: u32, dir: PacketDir, preview: String },
+    /// A flow has enough contiguous bytes to be consumed, or its connection was closed with bytes that were not consumed
+    BufferReady { conn_sequence: u32, dir: PacketDir },
+    /// A connection that held a large share of the buffering budget when it ran out stopped buffering its payloads,
+    /// and what it held is handed over
+    BufferingStopped { conn_sequence: u32, held_bytes: usize },
+    /// A flow reached its buffer limit for the first time, so payload bytes over it are dropped,
+    /// or the connection stopped buffering if that is the overflow policy
+    FlowBufferFull { conn_sequence: u32, dir: PacketDir, limit: usize, stopped: bool },
+    /// Unexpected traffic in one direction of a connection
+    Anomaly { conn_sequence: u32, dir: PacketDir, anomaly: Anomaly },
+    /// A metric of the connection crossed the threshold of an alert rule
+    Alert { conn_sequence: u32, alert: Alert },
+    /// An ARP packet moved an IP to another MAC, or announced it
+    Arp { event: ArpEvent },
+    /// A connection was reset or retransmitted shortly after an ARP event of one of its IPs
+    ArpCorrelated { conn_sequence: u32, arp: ArpEvent, delay: Duration },
+    /// The connection is the same one as an earlier connection, seen again on the other side of a NAT (a hairpin),
+    /// with the same ports and sequence numbers but different IPs
+    NatTwin { conn_sequence: u32, twin: u32 },
+    /// A destination has too many half-open connections, or receives SYNs too fast, as in a SYN flood
+    SynFlood { alarm: SynFloodAlarm },
+    /// The packet was not processed
+    Ignored { reason: IgnoreReason },
+}
+
+/// Unexpected traffic that was detected in a flow
+#[derive(Clone, Debug, PartialEq)]
+pub enum Anomaly {
+    /// The sequence number is too far ahead of the max sequence seen so far, so it was not tracked
+    SequenceJump { max_seq: u64, last_seq: u64 },
+    /// The receiver moved the right edge of its advertised window backwards, in relative sequence of the other flow
+    WindowShrink { before: u64, after: u64 },
+    /// The MSS announced on the SYN does not fit in the MTU of the capture interface, after the IPv4 and TCP headers
+    MssAboveMtu { mss: u16, mtu: u16 },
+    /// This side announced window scaling but the other did not, so neither side scales its window (RFC 7323),
+    /// as when a middlebox strips the option from one SYN
+    WindowScaleOneSided { shift: u8 },
+    /// The window scale shift is above 14, so the window could exceed the 1GB limit (RFC 7323). A shift of 14 is used.
+    WindowScaleAboveLimit { shift: u8 },
+}
+
+impl Anomaly {
+    /// Whether the anomaly is in the handshake options, which hosts rarely get wrong, so it hints at a middlebox that
+    /// rewrites them
+    pub fn is_option_tampering(&self) -> bool {
+        matches!(self, Anomaly::MssAboveMtu { .. } | Anomaly::WindowScaleOneSided { .. } | Anomaly::WindowScaleAboveLimit { .. })
+    }
+}
+
+impl fmt::Display for Anomaly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Anomaly::SequenceJump { max_seq, last_seq } => { write!(f, "sequence jump from {} to {}", max_seq, last_seq) }
+            Anomaly::WindowShrink { before, after } => { write!(f, "window edge moved back from {} to {}", before, after) }
+            Anomaly::MssAboveMtu { mss, mtu } => { write!(f, "MSS {} does not fit in the interface MTU {}", mss, mtu) }
+            Anomaly::WindowScaleOneSided { shift } => {
+                write!(f, "window scale {} announced without the other side, so not in effect", shift)
+            }
+            Anomaly::WindowScaleAboveLimit { shift } => { write!(f, "window scale {} is above the limit of 14", shift) }
+        }
+    }
+}
+
+/// Reason for not processing a packet
+#[derive(Clone, Debug, PartialEq)]
+pub enum IgnoreReason {
+    /// The captured part is shorter than the packet
+    Truncated,
+    /// The packet could not be parsed
+    ParseError,
+    /// The packet is not TCP over IPv4
+    NotTcp,
+    /// The connection of the packet matched an ignore rule
+    MatchedIgnoreRule,
+    /// The packet would open a new connection, but new connections are coming in faster than the rate limit
+    RateLimited,
+    /// The checksum of the IPv4 header is wrong, so its addresses cannot be trusted
+    BadChecksum,
+    /// The packet would open a new IPv6 connection, but the keys it may take are held by connections of other addresses
+    KeyCollision,
+}
@@ -0,0 +1,164 @@
+use std::time::Duration;
+use crate::conn::{Conn, PacketDir};
+
+/// Header line of the connection feature CSV export, one row per connection, for training traffic classifiers.
+///
+/// The forward direction is from the initiator (the sender of the first SYN), or from the lower address when the
+/// handshake was not seen. Sizes are TCP payload bytes of every packet, including empty ones. Inter-arrival times (IAT)
+/// are between packets of the same direction, or of any direction for the columns without a direction. A burst is a run
+/// of packets in the same direction. Ratios are backward over forward. Values that are unknown are left empty.
+/// Times are in milliseconds.
+pub const FEATURES_CSV_HEADER: &str = "conn,id,server_port,duration_ms,\
+fwd_packets,bwd_packets,fwd_bytes,bwd_bytes,\
+fwd_size_min,fwd_size_max,fwd_size_mean,fwd_size_std,bwd_size_min,bwd_size_max,bwd_size_mean,bwd_size_std,\
+fwd_iat_mean_ms,fwd_iat_std_ms,fwd_iat_max_ms,bwd_iat_mean_ms,bwd_iat_std_ms,bwd_iat_max_ms,iat_mean_ms,iat_std_ms,iat_max_ms,\
+packet_ratio,byte_ratio,bursts,burst_max_packets,handshake_rtt_ms,client_rtt_ms,ttfb_ms";
+
+/// Count, min, max, mean and variance of a series, updated one value at a time (Welford)
+#[derive(Clone, Debug, Default)]
+struct RunningStats {
+    count: u64,
+    min: f64,
+    max: f64,
+    mean: f64,
+    /// Sum of the squared differences from the mean
+    m2: f64,
+}
+
+impl RunningStats {
+    fn add(&mut self, value: f64) {
+        if self.count == 0 {
+            (self.min, self.max) = (value, value);
+        }
+        self.count += 1;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        let delta = value - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (value - self.mean);
+    }
+
+    /// Population standard deviation
+    fn std(&self) -> f64 {
+        if self.count == 0 { 0.0 } else { (self.m2 / self.count as f64).sqrt() }
+    }
+
+    /// Min, max, mean and standard deviation, or mean, standard deviation and max without the min, unknown if there are no values
+    fn values(&self, with_min: bool) -> Vec<Option<f64>> {
+        let values = if with_min { vec![self.min, self.max, self.mean, self.std()] } else { vec![self.mean, self.std(), self.max] };
+        values.into_iter().map(|value| if self.count == 0 { None } else { Some(value) }).collect()
+    }
+}
+
+/// Packet sizes and inter-arrival times of one direction
+#[derive(Clone, Debug, Default)]
+struct DirFeatures {
+    sizes: RunningStats,
+    /// Inter-arrival times in milliseconds
+    iats: RunningStats,
+    bytes: u64,
+    last_ts: Option<Duration>,
+}
+
+impl DirFeatures {
+    fn add_packet(&mut self, ts: Duration, payload_len: u16) {
+        self.sizes.add(payload_len as f64);
+        self.bytes += payload_len as u64;
+        if let Some(last_ts) = self.last_ts {
+            self.iats.add(ts.saturating_sub(last_ts).as_secs_f64() * 1000.0);
+        }
+        self.last_ts = Some(ts);
+    }
+}
+
+/// Statistics of the packets of a connection, that make its feature vector
+#[derive(Clone, Debug, Default)]
+pub struct ConnFeatures {
+    src_low: DirFeatures,
+    src_high: DirFeatures,
+    /// Inter-arrival times in milliseconds, of any direction
+    iats: RunningStats,
+    first_ts: Option<Duration>,
+    last_ts: Option<Duration>,
+    /// Direction of the current burst
+    burst_dir: Option<PacketDir>,
+    burst_len: u32,
+    bursts: u32,
+    max_burst_len: u32,
+}
+
+impl ConnFeatures {
+    /// Account for a packet of the connection, given its capture time, its direction and its TCP payload length
+    pub(crate) fn add_packet(&mut self, ts: Duration, packet_dir: &PacketDir, payload_len: u16) {
+        match packet_dir {
+            PacketDir::SrcLowAddr => { self.src_low.add_packet(ts, payload_len) }
+            PacketDir::SrcHighAddr => { self.src_high.add_packet(ts, payload_len) }
+        }
+        if let Some(last_ts) = self.last_ts {
+            self.iats.add(ts.saturating_sub(last_ts).as_secs_f64() * 1000.0);
+        }
+        self.first_ts.get_or_insert(ts);
+        self.last_ts = Some(ts);
+        if self.burst_dir.as_ref() == Some(packet_dir) {
+            self.burst_len += 1;
+        } else {
+            self.burst_dir = Some(packet_dir.to_owned());
+            self.burst_len = 1;
+            self.bursts += 1;
+        }
+        self.max_burst_len = self.max_burst_len.max(self.burst_len);
+    }
+}
+
+/// The numeric feature vector of a connection, in the order of [FEATURES_CSV_HEADER] from "server_port" on, if its
+/// features are tracked. Unknown values are None.
+pub fn feature_values(conn: &Conn) -> Option<Vec<Option<f64>>> {
+    let features = conn.features.as_ref()?;
+    let (fwd, bwd) = match conn.initiator {
+        Some(PacketDir::SrcHighAddr) => { (&features.src_high, &features.src_low) }
+        _ => { (&features.src_low, &features.src_high) }
+    };
+    let duration = features.last_ts.zip(features.first_ts).map(|(last, first)| last.saturating_sub(first)).unwrap_or_default();
+    let ratio = |bwd: f64, fwd: f64| if fwd == 0.0 { None } else { Some(bwd / fwd) };
+    let millis = |time: Option<Duration>| time.map(|time| time.as_secs_f64() * 1000.0);
+    let mut values = vec![
+        conn.server().map(|server| server.port() as f64),
+        Some(duration.as_secs_f64() * 1000.0),
+        Some(fwd.sizes.count as f64),
+        Some(bwd.sizes.count as f64),
+        Some(fwd.bytes as f64),
+        Some(bwd.bytes as f64),
+    ];
+    values.extend(fwd.sizes.values(true));
+    values.extend(bwd.sizes.values(true));
+    values.extend(fwd.iats.values(false));
+    values.extend(bwd.iats.values(false));
+    values.extend(features.iats.values(false));
+    values.extend([
+        ratio(bwd.sizes.count as f64, fwd.sizes.count as f64),
+        ratio(bwd.bytes as f64, fwd.bytes as f64),
+        Some(features.bursts as f64),
+        Some(features.max_burst_len as f64),
+        millis(conn.handshake_rtt),
+        millis(conn.client_rtt),
+        millis(conn.ttfb),
+    ]);
+    Some(values)
+}
+
+/// The feature values as comma separated fields, empty where unknown
+pub fn format_values(values: &[Option<f64>]) -> String {
+    values.iter().map(|value| value.map(format_number).unwrap_or_default()).collect::<Vec<String>>().join(",")
+}
+
+/// The feature vector of a connection as a CSV row, in the order of [FEATURES_CSV_HEADER], if its features are tracked
+pub fn csv_row(conn: &Conn) -> Option<String> {
+    let values = feature_values(conn)?;
+    Some(format!("{},{},{}", conn.conn_sequence, conn.id_as_str(), format_values(&values)))
+}
+
+/// A number with up to 3 decimals, without trailing zeros, to keep the export compact
+fn format_number(value: f64) -> String {
+    let text = format!("{:.3}", value);
+    text.trim_end_matches('0').trim_end_matches('.').to_string()
+}
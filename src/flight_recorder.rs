@@ -0,0 +1,194 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufWriter, Error, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::thread::JoinHandle;
+use etherparse::{InternetSlice, SlicedPacket, TransportSlice};
+use log::{error, info};
+use pcap::Packet;
+use crate::conn::Conn;
+
+/// Min capture time between two dumps, so a burst of alerts does not write a burst of files
+const MIN_DUMP_INTERVAL_MICROS: u64 = 10_000_000;
+/// Snap length written in the pcap file header, matching the capture snaplen
+const PCAP_SNAPLEN: u32 = 65535;
+/// Link type of the frames in the pcap files (Ethernet)
+const PCAP_LINKTYPE_ETHERNET: u32 = 1;
+
+/// Length of the pcap file header
+pub(crate) const PCAP_HEADER_LEN: u64 = 24;
+/// Length of the header of every frame in a pcap file
+pub(crate) const PCAP_RECORD_HEADER_LEN: u64 = 16;
+
+/// Raw frame kept in the ring, or by a connection for its own pcap file
+#[derive(Clone, Debug)]
+pub(crate) struct RingFrame {
+    ts_sec: u64,
+    ts_usec: u32,
+    /// Original length of the frame on the wire
+    len: u32,
+    data: Vec<u8>,
+}
+
+impl RingFrame {
+    pub(crate) fn new(packet: &Packet) -> Self {
+        RingFrame {
+            ts_sec: packet.header.ts.tv_sec as u64,
+            ts_usec: packet.header.ts.tv_usec as u32,
+            len: packet.header.len,
+            data: packet.data.to_vec(),
+        }
+    }
+
+    /// Bytes of captured data
+    pub(crate) fn data_len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Bytes that the frame takes in a pcap file, with its header
+    pub(crate) fn pcap_len(&self) -> u64 {
+        PCAP_RECORD_HEADER_LEN + self.data.len() as u64
+    }
+}
+
+/// Keeps the last captured frames, up to a number of bytes, and dumps them to pcap files when an alert fires,
+/// to see what happened just before
+pub struct FlightRecorder {
+    frames: VecDeque<RingFrame>,
+    /// Bytes of frame data currently in the ring
+    bytes: usize,
+    max_bytes: usize,
+    /// Where the pcap files are written
+    dir: PathBuf,
+    /// Capture time (microseconds) of the last dump
+    last_dump_micros: Option<u64>,
+    /// Number of alerts that were not dumped since they came too soon after the previous dump
+    skipped_dumps: u64,
+    /// Threads that are still writing dumps
+    writers: Vec<JoinHandle<()>>,
+}
+
+impl FlightRecorder {
+    pub fn new(dir: &Path, max_bytes: usize) -> Self {
+        Self {
+            frames: VecDeque::new(),
+            bytes: 0,
+            max_bytes,
+            dir: dir.to_path_buf(),
+            last_dump_micros: None,
+            skipped_dumps: 0,
+            writers: Vec::new(),
+        }
+    }
+
+    /// Keep a copy of the frame, dropping the oldest ones if the ring is full
+    pub fn push(&mut self, packet: &Packet) {
+        self.frames.push_back(RingFrame::new(packet));
+        self.bytes += packet.data.len();
+        while self.bytes > self.max_bytes {
+            match self.frames.pop_front() {
+                None => { break; }
+                Some(frame) => { self.bytes -= frame.data.len() }
+            }
+        }
+    }
+
+    /// Dump the ring to a pcap file, and the frames of the given connection that are in the ring to a second one.
+    /// The files are written in the background. Dumps that come too soon after the previous one are skipped.
+    pub fn dump(&mut self, conn_sequence: u32, conn_sign: u128) {
+        let now_micros = match self.frames.back() {
+            None => { return; }
+            Some(frame) => { frame.ts_sec * 1_000_000 + frame.ts_usec as u64 }
+        };
+        if self.last_dump_micros.is_some_and(|last| now_micros.saturating_sub(last) < MIN_DUMP_INTERVAL_MICROS) {
+            self.skipped_dumps += 1;
+            return;
+        }
+        self.last_dump_micros = Some(now_micros);
+        self.writers.retain(|writer| !writer.is_finished());
+
+        let window: Vec<RingFrame> = self.frames.iter().cloned().collect();
+        let base = format!("alert-conn{}-{}", conn_sequence, now_micros / 1_000_000);
+        let window_path = self.dir.join(format!("{}.pcap", base));
+        let conn_path = self.dir.join(format!("{}-conn.pcap", base));
+        self.writers.push(thread::spawn(move || {
+            let conn_frames: Vec<&RingFrame> = window.iter().filter(|frame| frame_conn_sign(&frame.data) == Some(conn_sign)).collect();
+            let result = write_pcap(&window_path, window.iter())
+                .and_then(|_| write_pcap(&conn_path, conn_frames.iter().copied()));
+            match result {
+                Err(error) => { error!("Failed to dump the flight recorder to {}: {}", window_path.display(), error) }
+                Ok(_) => {
+                    info!("Flight recorder dumped {} frames to {}, {} of connection {} to {}", window.len(),
+                        window_path.display(), conn_frames.len(), conn_sequence, conn_path.display())
+                }
+            }
+        }));
+    }
+
+    /// Wait for the dumps that are still being written
+    pub fn finish(self) {
+        if self.skipped_dumps > 0 {
+            info!("Flight recorder skipped {} dumps that came too soon after the previous one", self.skipped_dumps);
+        }
+        for writer in self.writers {
+            let _ = writer.join();
+        }
+    }
+}
+
+/// Signature of the TCP connection of a frame, as in the connection table, or None if it is not TCP
+fn frame_conn_sign(data: &[u8]) -> Option<u128> {
+    let sliced = SlicedPacket::from_ethernet(data).ok()?;
+    match (sliced.ip?, sliced.transport?) {
+        (InternetSlice::Ipv4(ip, _), TransportSlice::Tcp(tcp)) => {
+            Some(Conn::sign_by_tuple(ip.source_addr().into(), tcp.source_port(), ip.destination_addr().into(),
+                                     tcp.destination_port()).0)
+        }
+        (InternetSlice::Ipv6(ip, _), TransportSlice::Tcp(tcp)) => {
+            Some(Conn::sign_by_tuple(ip.source_addr().into(), tcp.source_port(), ip.destination_addr().into(),
+                                     tcp.destination_port()).0)
+        }
+        _ => { None }
+    }
+}
+
+/// Write frames to a file in the classic pcap format, readable by tcpdump and Wireshark
+fn write_pcap<'a>(path: &Path, frames: impl Iterator<Item=&'a RingFrame>) -> Result<(), Error> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    write_pcap_header(&mut writer)?;
+    for frame in frames {
+        write_pcap_frame(&mut writer, frame)?;
+    }
+    writer.flush()
+}
+
+/// Write the header of a pcap file of Ethernet frames, of [PCAP_HEADER_LEN] bytes
+pub(crate) fn write_pcap_header<W: Write>(writer: &mut W) -> Result<(), Error> {
+    write_pcap_file_header(writer, PCAP_SNAPLEN, PCAP_LINKTYPE_ETHERNET)
+}
+
+/// Write the header of a pcap file with the given snap length and link type, of [PCAP_HEADER_LEN] bytes
+pub(crate) fn write_pcap_file_header<W: Write>(writer: &mut W, snaplen: u32, linktype: u32) -> Result<(), Error> {
+    writer.write_all(&0xa1b2c3d4u32.to_le_bytes())?;
+    writer.write_all(&2u16.to_le_bytes())?;
+    writer.write_all(&4u16.to_le_bytes())?;
+    // Time zone offset and timestamp accuracy, always zero
+    writer.write_all(&[0; 8])?;
+    writer.write_all(&snaplen.to_le_bytes())?;
+    writer.write_all(&linktype.to_le_bytes())
+}
+
+/// Write a frame to a pcap file, after its header
+pub(crate) fn write_pcap_frame<W: Write>(writer: &mut W, frame: &RingFrame) -> Result<(), Error> {
+    write_pcap_record(writer, frame.ts_sec, frame.ts_usec, frame.len, &frame.data)
+}
+
+/// Write a frame, given its capture time, its length on the wire and its captured data, to a pcap file after its header
+pub(crate) fn write_pcap_record<W: Write>(writer: &mut W, ts_sec: u64, ts_usec: u32, len: u32, data: &[u8]) -> Result<(), Error> {
+    writer.write_all(&(ts_sec as u32).to_le_bytes())?;
+    writer.write_all(&ts_usec.to_le_bytes())?;
+    writer.write_all(&(data.len() as u32).to_le_bytes())?;
+    writer.write_all(&len.to_le_bytes())?;
+    writer.write_all(data)
+}
@@ -1,19 +1,539 @@
-use std::io::{Error, ErrorKind, Write};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{Error, ErrorKind, Read, Write};
 use std::ops::Range;
+use std::time::Duration;
 use log::warn;
+use crate::digest::{chunk_digests_json, PayloadExport};
+use crate::error;
+use crate::event::Anomaly;
+use crate::json::JsonValue;
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
+use crate::time_format::TimeFormatter;
+use crate::utils::{bytes_to_hex, hex_to_bytes};
 
 /// How far a future sequence number is allowed
 const MAX_FORWARD_SEQ_JUMP: u64 = 100000;
-/// The maximum buffer size allowed before a panic is called
-const MAX_BUFFER_SIZE: usize = 1000000;
+/// Number of TCP sequence numbers, after which they wrap around
+const SEQ_SPACE: u64 = 1 << 32;
+/// Default max bytes of a flow buffer, not including the consumed bytes that were released
+pub const DEFAULT_FLOW_BUFFER_LIMIT: usize = 1000000;
+/// Highest flow buffer limit that can be set, which also bounds the buffers of restored flows
+pub const MAX_FLOW_BUFFER_LIMIT: usize = 1 << 30;
+/// Max number of retransmissions kept in the timeline of a flow. Later ones are only counted.
+const MAX_RETRANSMIT_RECORDS: usize = 256;
+/// Max number of holes reported by SACK that are kept per flow to match with retransmissions. Older ones are dropped.
+const MAX_SACK_HOLES: usize = 64;
+/// Max number of unseen sequence ranges tracked per flow. Segments that fill older ones count as retransmissions.
+const MAX_SEQUENCE_HOLES: usize = 64;
+
+/// A packet whose payload carried no new bytes
+#[derive(Clone, Debug)]
+pub struct RetransmitRecord {
+    /// Capture timestamp
+    pub ts: Duration,
+    /// Sequence of the first payload byte, relative to the ISN
+    pub seq: u64,
+    /// TCP payload length
+    pub len: u32,
+}
+
+/// What happens to a flow whose buffer reached its limit, since its consumer does not keep up or a hole is never filled
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FlowOverflow {
+    /// Buffer the bytes that fit, drop the rest, and hand over what is ready so the consumer makes room
+    Truncate,
+    /// Stop buffering the connection and hand over what it holds, as when the buffering budget runs out
+    StatsOnly,
+}
+
+impl FlowOverflow {
+    /// Parse "truncate" or "stats-only"
+    pub fn parse(arg: &str) -> Result<FlowOverflow, String> {
+        match arg {
+            "truncate" => { Ok(FlowOverflow::Truncate) }
+            "stats-only" => { Ok(FlowOverflow::StatsOnly) }
+            _ => { Err(format!("invalid overflow policy '{}', expected truncate or stats-only", arg)) }
+        }
+    }
+}
+
+/// How a data segment relates to the bytes of the flow that were seen before it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SegmentKind {
+    /// Starts at the next expected sequence, or after it when segments before it were lost or are late
+    InOrder,
+    /// Carries only bytes that were seen before
+    Retransmission,
+    /// Carries only bytes that were not seen yet, below the next expected sequence
+    OutOfOrder,
+    /// Carries both bytes that were seen before and new ones
+    Overlapping,
+}
+
+/// Counters of the data segments of a flow by [SegmentKind], with the unseen ranges that tell them apart
+#[derive(Clone, Debug, Default)]
+pub struct SegmentCounts {
+    pub in_order: u32,
+    pub retransmitted: u32,
+    pub out_of_order: u32,
+    pub overlapping: u32,
+    /// Sequence ranges below the next expected sequence that were not seen yet, the oldest first
+    holes: Vec<Range<u64>>,
+}
+
+impl SegmentCounts {
+    /// Classify and count a segment, given its sequence range and the next expected sequence before it
+    pub(crate) fn add(&mut self, segment: Range<u64>, next_seq: u64) -> SegmentKind {
+        let kind = if segment.start >= next_seq {
+            if segment.start > next_seq {
+                self.holes.push(next_seq..segment.start);
+            }
+            SegmentKind::InOrder
+        } else {
+            // Bytes above the next expected sequence are new, and so are the ones that fill holes
+            let mut new_bytes = segment.end.saturating_sub(next_seq);
+            let mut holes = Vec::with_capacity(self.holes.len() + 1);
+            for hole in self.holes.drain(..) {
+                let (start, end) = (hole.start.max(segment.start), hole.end.min(segment.end));
+                if start >= end {
+                    holes.push(hole);
+                    continue;
+                }
+                new_bytes += end - start;
+                if hole.start < start {
+                    holes.push(hole.start..start);
+                }
+                if end < hole.end {
+                    holes.push(end..hole.end);
+                }
+            }
+            self.holes = holes;
+            if new_bytes == 0 {
+                SegmentKind::Retransmission
+            } else if new_bytes == segment.end - segment.start {
+                SegmentKind::OutOfOrder
+            } else {
+                SegmentKind::Overlapping
+            }
+        };
+        if self.holes.len() > MAX_SEQUENCE_HOLES {
+            self.holes.drain(..self.holes.len() - MAX_SEQUENCE_HOLES);
+        }
+        match kind {
+            SegmentKind::InOrder => { self.in_order += 1 }
+            SegmentKind::Retransmission => { self.retransmitted += 1 }
+            SegmentKind::OutOfOrder => { self.out_of_order += 1 }
+            SegmentKind::Overlapping => { self.overlapping += 1 }
+        }
+        kind
+    }
+
+    /// Whether any segment was not in order
+    pub(crate) fn has_disorder(&self) -> bool {
+        self.retransmitted + self.out_of_order + self.overlapping > 0
+    }
+
+    fn write_snapshot<W: Write>(&self, writer: &mut SnapshotWriter<W>) -> Result<(), Error> {
+        writer.put_u32(self.in_order)?;
+        writer.put_u32(self.retransmitted)?;
+        writer.put_u32(self.out_of_order)?;
+        writer.put_u32(self.overlapping)
+    }
+
+    fn read_snapshot<R: Read>(reader: &mut SnapshotReader<R>) -> Result<Self, Error> {
+        Ok(SegmentCounts {
+            in_order: reader.get_u32()?,
+            retransmitted: reader.get_u32()?,
+            out_of_order: reader.get_u32()?,
+            overlapping: reader.get_u32()?,
+            holes: Vec::new(),
+        })
+    }
+
+    pub(crate) fn to_json(&self) -> JsonValue {
+        JsonValue::object(vec![
+            ("in_order", self.in_order.into()),
+            ("retransmitted", self.retransmitted.into()),
+            ("out_of_order", self.out_of_order.into()),
+            ("overlapping", self.overlapping.into()),
+        ])
+    }
+
+    fn from_json(json: &JsonValue) -> Result<Self, Error> {
+        Ok(SegmentCounts {
+            in_order: json.field_u64("in_order")? as u32,
+            retransmitted: json.field_u64("retransmitted")? as u32,
+            out_of_order: json.field_u64("out_of_order")? as u32,
+            overlapping: json.field_u64("overlapping")? as u32,
+            holes: Vec::new(),
+        })
+    }
+}
+
+impl fmt::Display for SegmentCounts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "retransmitted:{} out-of-order:{} overlapping:{}", self.retransmitted, self.out_of_order, self.overlapping)
+    }
+}
+
+/// How many data segments of the other side this side acknowledged at a time, to spot delayed ACK pathologies
+#[derive(Clone, Debug, Default)]
+pub struct AckPattern {
+    /// ACKs that acknowledged a single segment
+    pub every_segment: u32,
+    /// ACKs that acknowledged two segments, as with the usual delayed ACK
+    pub every_other: u32,
+    /// ACKs that acknowledged more than two segments (stretch ACKs)
+    pub stretch: u32,
+    /// Highest relative ack sent so far
+    last_ack: u64,
+    /// Data segments received since the ack last moved forward
+    pending_segments: u32,
+}
+
+impl AckPattern {
+    /// Count a data segment received from the other side
+    pub(crate) fn add_segment(&mut self) {
+        self.pending_segments += 1;
+    }
+
+    /// Count an ACK sent by this side, given its relative ack. Only ACKs that move forward after data are counted.
+    pub(crate) fn add_ack(&mut self, relative_ack: u64) {
+        if relative_ack <= self.last_ack {
+            return;
+        }
+        self.last_ack = relative_ack;
+        match std::mem::take(&mut self.pending_segments) {
+            0 => {}
+            1 => { self.every_segment += 1 }
+            2 => { self.every_other += 1 }
+            _ => { self.stretch += 1 }
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.every_segment + self.every_other + self.stretch == 0
+    }
+
+    pub(crate) fn to_json(&self) -> JsonValue {
+        JsonValue::object(vec![
+            ("every_segment", self.every_segment.into()),
+            ("every_other", self.every_other.into()),
+            ("stretch", self.stretch.into()),
+        ])
+    }
+
+    /// Restore the counts, made by [AckPattern::to_json]. The ACKs after the restore are counted from the next one.
+    fn from_json(json: &JsonValue) -> Result<Self, Error> {
+        Ok(AckPattern {
+            every_segment: json.field_u64("every_segment")? as u32,
+            every_other: json.field_u64("every_other")? as u32,
+            stretch: json.field_u64("stretch")? as u32,
+            ..AckPattern::default()
+        })
+    }
+}
+
+impl fmt::Display for AckPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "1:{} 2:{} stretch:{}", self.every_segment, self.every_other, self.stretch)
+    }
+}
+
+/// Loss of the data of this side as reported by the SACK blocks of the other side: every hole between the ACK and the
+/// first block, or between two blocks, is data that did not arrive (yet). Every hole is counted once.
+#[derive(Clone, Debug, Default)]
+pub struct SackLoss {
+    /// ACKs of the other side that carried SACK blocks above the ACK
+    pub sack_acks: u32,
+    pub holes: u32,
+    /// Bytes in the holes, as an estimate of the bytes that were lost
+    pub hole_bytes: u64,
+    /// Holes that this side sent again afterwards
+    pub retransmitted_holes: u32,
+    /// Relative sequence up to which holes were already counted
+    counted_up_to: u64,
+    /// Holes that were not sent again yet, the oldest first
+    pending: Vec<Range<u64>>,
+}
+
+impl SackLoss {
+    /// Count the holes of an ACK of the other side, given its relative ack and SACK blocks.
+    /// Blocks below the ACK report duplicates (D-SACK), so they are ignored.
+    pub(crate) fn add_sack(&mut self, relative_ack: u64, mut blocks: Vec<Range<u64>>) {
+        blocks.retain(|block| block.start > relative_ack && block.start < block.end);
+        if blocks.is_empty() {
+            return;
+        }
+        self.sack_acks += 1;
+        blocks.sort_by_key(|block| block.start);
+        let mut hole_start = relative_ack;
+        for block in blocks {
+            let start = hole_start.max(self.counted_up_to);
+            if start < block.start {
+                self.holes += 1;
+                self.hole_bytes += block.start - start;
+                if self.pending.len() >= MAX_SACK_HOLES {
+                    self.pending.remove(0);
+                }
+                self.pending.push(start..block.start);
+            }
+            hole_start = hole_start.max(block.end);
+            self.counted_up_to = self.counted_up_to.max(block.end);
+        }
+    }
+
+    /// Match a data segment of this side, given its relative sequence range, with the holes it fills
+    pub(crate) fn add_segment(&mut self, segment: Range<u64>) {
+        let before = self.pending.len();
+        self.pending.retain(|hole| hole.end <= segment.start || segment.end <= hole.start);
+        self.retransmitted_holes += (before - self.pending.len()) as u32;
+    }
+
+    /// Bytes in the holes as a share of the given bytes sent, in percent, or None if nothing was sent
+    pub fn loss_percent(&self, bytes_sent: u64) -> Option<f64> {
+        if bytes_sent == 0 { None } else { Some(self.hole_bytes as f64 * 100.0 / bytes_sent as f64) }
+    }
+
+    pub(crate) fn to_json(&self, bytes_sent: u64) -> JsonValue {
+        JsonValue::object(vec![
+            ("sack_acks", self.sack_acks.into()),
+            ("holes", self.holes.into()),
+            ("hole_bytes", self.hole_bytes.into()),
+            ("retransmitted_holes", self.retransmitted_holes.into()),
+            ("loss_percent", self.loss_percent(bytes_sent).into()),
+        ])
+    }
+}
+
+/// When the packets of a flow were captured, their IP octets and the union of their TCP flags, for flow records in the
+/// NetFlow sense. Not saved with the state, so the times of a restored flow start from its next packet.
+#[derive(Clone, Debug, Default)]
+pub struct FlowActivity {
+    /// Capture timestamp of the first packet, if any
+    pub first_ts: Option<Duration>,
+    /// Capture timestamp of the last packet
+    pub last_ts: Duration,
+    /// IP octets of the packets, counting the IP header without options or extension headers
+    pub octets: u64,
+    /// All the TCP flags that were set on any of the packets, as in the TCP header
+    pub tcp_flags: u8,
+}
+
+impl FlowActivity {
+    /// Count a packet, given its capture timestamp, the length of its IP datagram and its TCP flags
+    pub(crate) fn add_packet(&mut self, ts: Duration, ip_len: usize, tcp_flags: u8) {
+        self.first_ts.get_or_insert(ts);
+        self.last_ts = self.last_ts.max(ts);
+        self.octets += ip_len as u64;
+        self.tcp_flags |= tcp_flags;
+    }
+}
+
+/// Shortest and longest ACK delay that look like a delayed ACK timer, which is typically 40ms on Linux and up to 200ms elsewhere
+const DELAYED_ACK_MIN: Duration = Duration::from_millis(35);
+const DELAYED_ACK_MAX: Duration = Duration::from_millis(250);
+/// Max time between the delayed ACK and the next segment, for the segment to count as released by the ACK (Nagle)
+const NAGLE_RELEASE_MAX: Duration = Duration::from_millis(10);
+/// Segment size below which a segment counts as a small write, when the MSS was not announced
+const DEFAULT_SMALL_SEGMENT: u16 = 536;
+
+/// Stalls in which this side held a small write (Nagle) until the other side sent a delayed ACK for the previous one
+#[derive(Clone, Debug, Default)]
+pub struct NagleStalls {
+    pub count: u32,
+    /// Sum of the ACK delays of the stalls
+    pub time_lost: Duration,
+    /// Capture time and relative end sequence of the last small segment of this side, while it is not acknowledged
+    small_unacked: Option<(Duration, u64)>,
+    /// Capture time and delay of a delayed ACK of the other side, that may release a held segment
+    delayed_ack: Option<(Duration, Duration)>,
+}
+
+impl NagleStalls {
+    /// Track a data segment of this side, given its capture time, relative end sequence and length,
+    /// and the MSS of the other side (0 if unknown)
+    pub(crate) fn add_segment(&mut self, ts: Duration, end_seq: u64, len: u16, peer_mss: u16) {
+        if let Some((ack_ts, delay)) = self.delayed_ack.take() {
+            if ts.saturating_sub(ack_ts) <= NAGLE_RELEASE_MAX {
+                self.count += 1;
+                self.time_lost += delay;
+            }
+        }
+        let small_segment = if peer_mss > 0 { peer_mss } else { DEFAULT_SMALL_SEGMENT };
+        self.small_unacked = if len < small_segment { Some((ts, end_seq)) } else { None };
+    }
+
+    /// Track a packet of the other side, given its capture time, relative ack and whether it carried data.
+    /// A pure ACK of the small segment that came after a delayed ACK timer may release a held segment.
+    pub(crate) fn add_peer_packet(&mut self, ts: Duration, relative_ack: u64, has_data: bool) {
+        self.delayed_ack = None;
+        if let Some((segment_ts, end_seq)) = self.small_unacked {
+            if relative_ack < end_seq {
+                return;
+            }
+            self.small_unacked = None;
+            let delay = ts.saturating_sub(segment_ts);
+            if !has_data && (DELAYED_ACK_MIN..=DELAYED_ACK_MAX).contains(&delay) {
+                self.delayed_ack = Some((ts, delay));
+            }
+        }
+    }
+
+    pub(crate) fn to_json(&self) -> JsonValue {
+        JsonValue::object(vec![
+            ("count", self.count.into()),
+            ("time_lost_ms", (self.time_lost.as_millis() as u64).into()),
+        ])
+    }
+}
+
+/// Periods in which this side, as the receiver, held the other side back, which tells a slow receiver from a slow
+/// network: its advertised window was zero, or the other side sent up to the edge of a window that was open
+/// (window-full). Only the periods that ended are timed.
+#[derive(Clone, Debug, Default)]
+pub struct WindowStalls {
+    pub zero_windows: u32,
+    pub zero_window_time: Duration,
+    pub window_full: u32,
+    pub window_full_time: Duration,
+    /// Relative ack and right edge of the last window that this side advertised, if any
+    advertised: Option<(u64, u64)>,
+    /// Capture time when the advertised window became zero, while it is
+    zero_since: Option<Duration>,
+    /// Capture time when the other side filled the window, and the edge that it filled, while it is full
+    full_since: Option<(Duration, u64)>,
+}
+
+impl WindowStalls {
+    /// Track a window advertised by this side, given its capture time, relative ack and scaled window.
+    /// ACKs older than the last one are reordered, so they are ignored.
+    pub(crate) fn add_window(&mut self, ts: Duration, relative_ack: u64, window: u32) {
+        if self.advertised.is_some_and(|(ack, _)| relative_ack < ack) {
+            return;
+        }
+        let edge = relative_ack + window as u64;
+        self.advertised = Some((relative_ack, edge));
+        // A full window that is acknowledged with a zero window goes on as a zero window period
+        if let Some((since, full_edge)) = self.full_since {
+            if window == 0 || edge > full_edge {
+                self.window_full_time += ts.saturating_sub(since);
+                self.full_since = None;
+            }
+        }
+        if window == 0 {
+            if self.zero_since.is_none() {
+                self.zero_windows += 1;
+                self.zero_since = Some(ts);
+            }
+        } else if let Some(since) = self.zero_since.take() {
+            self.zero_window_time += ts.saturating_sub(since);
+        }
+    }
+
+    /// Track a data segment of the other side, given its capture time and relative end sequence.
+    /// A segment that reaches the edge of an open window fills it, while segments into a zero window are probes.
+    pub(crate) fn add_peer_segment(&mut self, ts: Duration, end_seq: u64) {
+        if let Some((ack, edge)) = self.advertised {
+            if edge > ack && end_seq >= edge && self.full_since.is_none() {
+                self.window_full += 1;
+                self.full_since = Some((ts, edge));
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.zero_windows == 0 && self.window_full == 0
+    }
+
+    pub(crate) fn to_json(&self) -> JsonValue {
+        JsonValue::object(vec![
+            ("zero_windows", self.zero_windows.into()),
+            ("zero_window_ms", (self.zero_window_time.as_millis() as u64).into()),
+            ("window_full", self.window_full.into()),
+            ("window_full_ms", (self.window_full_time.as_millis() as u64).into()),
+        ])
+    }
+}
+
+impl fmt::Display for WindowStalls {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} zero ({}ms), {} full ({}ms)", self.zero_windows, self.zero_window_time.as_millis(),
+               self.window_full, self.window_full_time.as_millis())
+    }
+}
+
+/// Set of filled byte ranges of a stream, kept sorted and coalesced, so ranges that overlap or touch are one range,
+/// whatever the order they were added in. Ends are inclusive.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FilledRanges {
+    /// End of every range by its start
+    ranges: BTreeMap<usize, usize>,
+}
+
+impl FilledRanges {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a range, and merge it with the ranges that it overlaps or touches
+    pub fn insert(&mut self, start: usize, end_inclusive: usize) {
+        let (mut start, mut end) = (start, end_inclusive);
+        if let Some((&prev_start, &prev_end)) = self.ranges.range(..=start).next_back() {
+            if prev_end >= end {
+                return;
+            }
+            if prev_end.saturating_add(1) >= start {
+                start = prev_start;
+            }
+        }
+        while let Some((&next_start, &next_end)) = self.ranges.range(start..=end.saturating_add(1)).next() {
+            self.ranges.remove(&next_start);
+            end = end.max(next_end);
+        }
+        self.ranges.insert(start, end);
+    }
+
+    /// Number of contiguous filled bytes from the given position
+    pub fn contiguous_len(&self, pos: usize) -> usize {
+        match self.ranges.range(..=pos).next_back() {
+            Some((_, &end)) if end >= pos => { end + 1 - pos }
+            _ => { 0 }
+        }
+    }
+
+    /// The ranges by their start
+    pub fn iter(&self) -> impl Iterator<Item=Range<usize>> + '_ {
+        self.ranges.iter().map(|(start, end)| *start..*end)
+    }
+
+    /// Number of ranges, which is the number of holes between them, plus one
+    pub fn len(&self) -> usize {
+        self.ranges.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+}
 
 #[derive(Clone)]
 pub struct FlowBuff {
     /// The buffer itself where the payloads are copied to
     data: Vec<u8>,
-    /// Collection of filled payloads in buffer.
-    //TODO actually do something with it
-    data_filled_ranges: Vec<Range<usize>>,
+    /// Stream offsets of the bytes that were written to the buffer
+    data_filled_ranges: FilledRanges,
+    /// Position of the next byte to be consumed. All bytes before it were already handed out.
+    read_pos: usize,
+    /// Stream offset of the first byte in the buffer. Consumed bytes before it were released to save memory.
+    base_offset: usize,
+    /// Whether the flow was signalled as ready since it was last consumed, so it is signalled once
+    ready_signalled: bool,
+    /// Position of the next byte to be fed to the analyzers
+    analyzed_pos: usize,
+    /// Position of the next byte to be handed to the connection observers
+    observed_pos: usize,
     /// TCP initial sequence number (ISN) which is the one before the first payload byte
     initial_sequence_number: u32,
     /// Max sequence seen so far, for total unique payload calculation.
@@ -29,13 +549,55 @@ pub struct FlowBuff {
     /// TCP window scale multiplier (from 1 to 2^14) to multiply the transmitted window size (up to 64KB).
     /// By using the window scale option, the receive window size may be increased up to a maximum value of 1,073,725,440.
     pub(crate) window_scale: u16,
+    /// Window scale shift count as announced on the SYN, or None without the option.
+    /// Only needed during the handshake, so it is not saved with the state.
+    pub(crate) announced_window_shift: Option<u8>,
+    /// Maximum segment size announced by this side on its SYN, or 0 if none
+    pub(crate) mss: u16,
+    /// Whether this side allowed SACK on its SYN. Only needed during the handshake, so it is not saved with the state.
+    pub(crate) sack_permitted: bool,
+    /// Data of this side that the SACK blocks of the other side reported missing
+    pub(crate) sack_loss: SackLoss,
+    /// Capture times, octets and flags of the packets
+    pub(crate) activity: FlowActivity,
+    /// Highest relative sequence of the other flow that this side advertised it can receive (ack plus window)
+    window_edge: u64,
+    /// Relative ack of the packet that advertised the current window edge
+    window_edge_ack: u64,
+    /// Number of times this side moved its advertised window edge backwards
+    pub(crate) window_shrink_count: u32,
+    /// Number of packets that carried payload
+    pub(crate) data_packet_count: u32,
+    /// Data segments by how they relate to the bytes seen before them: in order, retransmitted, out of order or overlapping
+    pub(crate) segments: SegmentCounts,
+    /// Number of packets in which this side advertised a zero receive window
+    pub(crate) zero_window_count: u32,
+    /// The first retransmissions, for correlating loss episodes with other events
+    pub(crate) retransmits: Vec<RetransmitRecord>,
+    /// How many segments of the other flow every ACK of this side acknowledged
+    pub(crate) acks: AckPattern,
+    /// Small writes of this side that waited for a delayed ACK
+    pub(crate) nagle_stalls: NagleStalls,
+    /// Zero window and window-full periods in which this side held the other side back
+    pub(crate) window_stalls: WindowStalls,
+    /// Most bytes that were buffered and not consumed yet at any time, to tune the ready threshold and memory limits
+    pub(crate) max_held_bytes: usize,
+    /// Max bytes held in the buffer, above which payload bytes are dropped. A setting, so it is not saved with the state.
+    buffer_limit: usize,
+    /// Payload bytes that were not buffered since the buffer was at its limit
+    pub(crate) dropped_bytes: u64,
 }
 
 impl FlowBuff {
     pub(crate) fn new() -> Self {
         Self {
             data: vec![],
-            data_filled_ranges: vec![],
+            data_filled_ranges: FilledRanges::new(),
+            read_pos: 0,
+            base_offset: 0,
+            ready_signalled: false,
+            analyzed_pos: 0,
+            observed_pos: 0,
             // The ISN will be set later when SYN is detected
             initial_sequence_number: 0,
             byte_count: 0,
@@ -43,74 +605,200 @@ impl FlowBuff {
             wrap_around: 0,
             max_seq: 0,
             window_scale: 1,
+            announced_window_shift: None,
+            mss: 0,
+            sack_permitted: false,
+            sack_loss: SackLoss::default(),
+            activity: FlowActivity::default(),
+            window_edge: 0,
+            window_edge_ack: 0,
+            window_shrink_count: 0,
+            data_packet_count: 0,
+            segments: SegmentCounts::default(),
+            zero_window_count: 0,
+            retransmits: Vec::new(),
+            acks: AckPattern::default(),
+            nagle_stalls: NagleStalls::default(),
+            window_stalls: WindowStalls::default(),
+            max_held_bytes: 0,
+            buffer_limit: DEFAULT_FLOW_BUFFER_LIMIT,
+            dropped_bytes: 0,
+        }
+    }
+
+    /// Set the max bytes held in the buffer, see [FlowBuff::add_bytes]
+    pub(crate) fn set_buffer_limit(&mut self, limit: usize) {
+        self.buffer_limit = limit;
+    }
+
+    /// Number of contiguous bytes that are ready to be consumed from the current read position
+    pub fn ready_len(&self) -> usize {
+        self.contiguous_len(self.read_pos)
+    }
+
+    /// Number of contiguous filled bytes from the given position
+    fn contiguous_len(&self, pos: usize) -> usize {
+        self.data_filled_ranges.contiguous_len(pos)
+    }
+
+    /// The contiguous filled bytes from the given stream offset, whether they were consumed or not.
+    /// Nothing is returned from before [FlowBuff::released_len], since those bytes are gone.
+    pub fn contiguous_bytes(&self, pos: usize) -> &[u8] {
+        if pos < self.base_offset {
+            return &[];
+        }
+        &self.data[pos - self.base_offset..pos - self.base_offset + self.contiguous_len(pos)]
+    }
+
+    /// The contiguous bytes that were not analyzed yet, up to the given stream offset, and mark them as analyzed.
+    /// Analysis is independent of consumption, so it sees the bytes even if they were already consumed, unless they
+    /// were released.
+    pub(crate) fn next_unanalyzed(&mut self, limit: usize) -> Option<&[u8]> {
+        let start = self.analyzed_pos.max(self.base_offset);
+        let end = (start + self.contiguous_len(start)).min(limit);
+        if end <= start {
+            return None;
+        }
+        self.analyzed_pos = end;
+        Some(&self.data[start - self.base_offset..end - self.base_offset])
+    }
+
+    /// Stream offsets of the contiguous bytes that were not handed to the observers yet, and mark them as observed.
+    /// As with the analysis, bytes that were released before the observers got them are skipped.
+    pub(crate) fn next_unobserved(&mut self) -> Option<Range<usize>> {
+        let start = self.observed_pos.max(self.base_offset);
+        let end = start + self.contiguous_len(start);
+        if end <= start {
+            return None;
         }
+        self.observed_pos = end;
+        Some(start..end)
+    }
+
+    /// Number of filled bytes from the read position on, that were buffered and not consumed yet (holes not included)
+    pub fn held_bytes(&self) -> usize {
+        self.filled_map().iter().map(|range| (range.end + 1).saturating_sub(range.start.max(self.read_pos))).sum()
+    }
+
+    /// Number of bytes that were handed out to be consumed
+    pub fn consumed_len(&self) -> usize {
+        self.read_pos
     }
 
-    /// Check if this connection has bytes ready to process in one of the directions.
-    /// This means that at least the number of requested bytes are present in a buffer from the current position.
-    pub(crate) fn has_ready_bytes(&self, min_ready_bytes: usize) -> bool {
-        let first_buffer = self.data_filled_ranges.get(0);
-        return first_buffer.is_some() && first_buffer.unwrap().len() >= min_ready_bytes;
+    /// Number of consumed bytes whose memory was released, from the start of the stream
+    pub fn released_len(&self) -> usize {
+        self.base_offset
+    }
+
+    /// The contiguous bytes that are ready to be consumed from the current read position, without consuming them
+    pub fn peek_ready(&self) -> &[u8] {
+        self.contiguous_bytes(self.read_pos)
+    }
+
+    /// Mark up to the given number of ready bytes as consumed, and return how many were.
+    /// The flow can be signalled as ready again once more bytes arrive, even if it did not consume anything.
+    /// The memory of the consumed bytes is released when they are at least half of the buffer, so a long stream that
+    /// is consumed as it goes never reaches the buffer limit.
+    pub fn consume(&mut self, len: usize) -> usize {
+        let len = len.min(self.ready_len());
+        self.read_pos += len;
+        self.ready_signalled = false;
+        let releasable = self.read_pos - self.base_offset;
+        if releasable > 0 && releasable >= self.data.len() / 2 {
+            self.data.drain(..releasable);
+            self.data.shrink_to_fit();
+            self.base_offset = self.read_pos;
+        }
+        len
+    }
+
+    /// Answer if the ready bytes reached the given number since the flow was last consumed, for the first time,
+    /// so it is signalled once until it is consumed
+    pub(crate) fn signal_ready(&mut self, min_ready_bytes: usize) -> bool {
+        let ready_len = self.ready_len();
+        if self.ready_signalled || ready_len == 0 || ready_len < min_ready_bytes {
+            return false;
+        }
+        self.ready_signalled = true;
+        true
     }
 
     /// Answer if it has a significant number of bytes ready, or if the connection is closed and it has something to process.
     pub(crate) fn has_ready_buffer(&self, closed_connection: bool, min_ready_bytes: usize) -> bool {
-        let first_buffer = self.data_filled_ranges.get(0);
-        return first_buffer.is_some() && (closed_connection || first_buffer.unwrap().len() >= min_ready_bytes);
+        let ready_len = self.ready_len();
+        ready_len > 0 && (closed_connection || ready_len >= min_ready_bytes)
+    }
+
+    /// Copy out all the contiguous bytes that are ready from the current read position, and mark them as consumed.
+    pub(crate) fn take_ready_bytes(&mut self) -> Vec<u8> {
+        let result = self.peek_ready().to_vec();
+        self.consume(result.len());
+        result
     }
 
-    /// Return the buffer size
+    /// Return the buffer size, as the stream offset after its last byte
     pub fn len(&self) -> usize {
-        self.data.len()
+        self.base_offset + self.data.len()
     }
 
-    /// Append a byte array to the buffer.
-    /// The buffer is automatically extended if needed
-    pub fn write_bytes(&mut self, bytes: &[u8], wpos: usize) {
-        let size = bytes.len() + wpos;
+    /// Whether no byte was written at any stream offset yet
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Append a byte array to the buffer, at the given stream offset.
+    /// The buffer is automatically extended if needed, up to the maximum buffer size.
+    /// Bytes before the released ones were already consumed, so they are skipped.
+    pub fn write_bytes(&mut self, bytes: &[u8], wpos: usize) -> Result<(), error::Error> {
+        let skip = self.base_offset.saturating_sub(wpos).min(bytes.len());
+        let (bytes, wpos) = (&bytes[skip..], wpos + skip);
+        if bytes.is_empty() {
+            return Ok(());
+        }
+        let size = bytes.len() + wpos - self.base_offset;
 
         if size > self.data.len() {
-            if size > MAX_BUFFER_SIZE {
-                panic!("Test code does not allow large buffers. Asked for {} while max allowed is {}", size, MAX_BUFFER_SIZE)
+            if size > self.buffer_limit {
+                return Err(error::Error::ResourceLimit { resource: "flow buffer", requested: size, limit: self.buffer_limit });
             }
             self.resize(size);
         }
 
-        let mut pos = wpos;
+        let mut pos = wpos - self.base_offset;
         for v in bytes {
             self.data[pos] = *v;
             pos += 1;
         }
 
-        self.add_data_filled_range(wpos, wpos + bytes.len() - 1);
+        self.data_filled_ranges.insert(wpos, wpos + bytes.len() - 1);
+        Ok(())
     }
 
-    /// Add a range to the list of filled ranges
-    /// _note_: Does not fill a gap between two others, or partial overlaps, if that happens
-    fn add_data_filled_range(&mut self, start: usize, end_inclusive: usize) {
-        for i in 0..self.data_filled_ranges.len() {
-            let range = &mut self.data_filled_ranges[i];
-            // If the new range is the 99% case that is an adjacent range with no gaps
-            if range.end + 1 == start {
-                range.end = end_inclusive;
-                return;
-            }
-            // If the new range is a retransmission
-            if range.start == start {
-                if end_inclusive > range.end {
-                    range.end = end_inclusive;
-                }
-                return;
-            }
-            // If the new range is the one that was missing right before
-            if range.start == end_inclusive + 1 {
-                range.start = start;
-                return;
+    /// Filled ranges sorted and merged where they overlap or touch, in relative offsets (0 is the first payload byte).
+    /// Ends are inclusive, as held internally.
+    pub fn filled_map(&self) -> Vec<Range<usize>> {
+        self.data_filled_ranges.iter().collect()
+    }
+
+    /// Filled ranges that are still in the buffer, from the released bytes on, in stream offsets with inclusive ends
+    fn kept_map(&self) -> Vec<Range<usize>> {
+        self.filled_map().into_iter()
+            .filter(|range| range.end >= self.base_offset)
+            .map(|range| range.start.max(self.base_offset)..range.end)
+            .collect()
+    }
+
+    /// Byte ranges that were not captured between the filled ones, in relative offsets with inclusive ends
+    pub fn holes(&self) -> Vec<Range<usize>> {
+        let mut result = Vec::new();
+        let mut next = 0usize;
+        for range in self.filled_map() {
+            if range.start > next {
+                result.push(next..range.start - 1);
             }
+            next = range.end + 1;
         }
-        // Did not find an overlapping range, so add a range
-        // Happens with the first range, and normally should not happen often after that
-        self.data_filled_ranges.push(start..end_inclusive);
+        result
     }
 
     /// Change the buffer size to size.
@@ -123,17 +811,25 @@ impl FlowBuff {
         }
     }
 
-    /// Read a defined amount of raw bytes, or return an IO error if not enough bytes are available.
+    /// Read a defined amount of raw bytes from the given stream offset, or return an IO error if not enough bytes are
+    /// available.
     pub fn read_bytes(&mut self, size: usize, rpos: usize) -> Result<Vec<u8>, Error> {
-        if rpos + size > self.data.len() {
+        if rpos < self.base_offset {
+            return Err(Error::new(ErrorKind::InvalidInput, "Cannot read bytes that were released"));
+        }
+        if rpos + size > self.len() {
             return Err(Error::new(ErrorKind::UnexpectedEof, "Cannot read enough bytes from buffer"));
         }
-        let range = rpos..(rpos + size);
+        let range = rpos - self.base_offset..(rpos - self.base_offset + size);
         let mut res = Vec::<u8>::new();
         res.write_all(&self.data[range])?;
         Ok(res)
     }
 
+    pub(crate) fn initial_sequence_number(&self) -> u32 {
+        self.initial_sequence_number
+    }
+
     pub fn set_initial_sequence_number(&mut self, initial_sequence_number: u32) {
         self.initial_sequence_number = initial_sequence_number;
         self.max_seq = initial_sequence_number as u64;
@@ -141,9 +837,17 @@ impl FlowBuff {
 
     /// Get the relative 0-based sequence number of the given TCP sequence.
     /// Handles a wrap around of TCP sequence numbers, that are only 32-bits.
-    /// For example, the first payload byte is 0, the second is 1, etc.
+    /// For example, the first payload byte is 0, the second is 1, etc. The SYN itself and anything before it are 0 as well.
     pub fn relative_seq(&self, seq: u32) -> u64 {
-        (seq as u64) + (self.wrap_around as u64) * (u32::MAX as u64) - self.initial_sequence_number as u64 - 1u64
+        self.absolute_seq(seq).saturating_sub(self.initial_sequence_number as u64 + 1u64)
+    }
+
+    /// Sequence of the given TCP sequence counting the wrap arounds, taking the wrap around nearest to the max sequence,
+    /// so a sequence from before the last wrap around (a retransmission, or an ACK of older data) is not taken as one
+    /// after the next wrap around
+    fn absolute_seq(&self, seq: u32) -> u64 {
+        let seq = seq as u64 + self.wrap_around as u64 * SEQ_SPACE;
+        if seq >= SEQ_SPACE && seq > self.max_seq + SEQ_SPACE / 2 { seq - SEQ_SPACE } else { seq }
     }
 
     /// Calculate actual window size, given the published window size (up to 64KB) and the recorded window scaling (from SYN).
@@ -151,32 +855,301 @@ impl FlowBuff {
         (window as u32) * (self.window_scale as u32)
     }
 
-    pub fn add_bytes(&mut self, tcp_seq: u32, byte_count: usize, data: &[u8]) {
+    /// Track the right edge of the receive window advertised by this side, given the relative ack and the scaled window.
+    /// Return an anomaly if the edge moved backwards, which is forbidden since the other side may have already sent up to it.
+    /// Packets with an older ack are reordered, so they are ignored.
+    pub(crate) fn track_window_edge(&mut self, relative_ack: u64, window: u32) -> Option<Anomaly> {
+        if relative_ack < self.window_edge_ack {
+            return None;
+        }
+        let edge = relative_ack + window as u64;
+        let before = self.window_edge;
+        self.window_edge = edge;
+        self.window_edge_ack = relative_ack;
+        if edge < before {
+            self.window_shrink_count += 1;
+            return Some(Anomaly::WindowShrink { before, after: edge });
+        }
+        None
+    }
+
+    /// Count a packet and copy its payload, if any, to the buffer.
+    /// The given sequence is the one of the first payload byte, so for a SYN that carries data (TFO) it is one after
+    /// the SYN's own sequence. The data is the TCP payload, without the headers of the packet or the link layer padding.
+    /// Payload that starts before the first byte of the stream is counted but not buffered,
+    /// and so is all the payload if the given data is empty (only the headers are tracked).
+    /// Bytes beyond the buffer limit are dropped and counted, and the bytes before them are still buffered.
+    /// Return an anomaly if the sequence number could not be tracked,
+    /// or an error if the payload could not be buffered (the packet is still counted).
+    pub fn add_bytes(&mut self, tcp_seq: u32, byte_count: usize, data: &[u8]) -> Result<Option<Anomaly>, error::Error> {
+        let mut anomaly = None;
         self.packet_count += 1;
         // Calculate the sequence number of the last byte
         if byte_count > 0 {
             self.byte_count += byte_count as u64;
-            let last_seq: u64 = (tcp_seq as u64) + byte_count as u64 + (self.wrap_around as u64 * u32::MAX as u64);
+            self.data_packet_count += 1;
+            let last_seq: u64 = self.absolute_seq(tcp_seq) + byte_count as u64;
             // Check if this sequence number creates a wrap around that makes sense
-            if last_seq < self.max_seq && (last_seq + u32::MAX as u64) > self.max_seq && (last_seq + u32::MAX as u64 - MAX_FORWARD_SEQ_JUMP) <= self.max_seq {
+            // Before the first payload byte, the max sequence is the one of the SYN
+            let next_seq = self.max_seq.max(self.initial_sequence_number as u64 + 1);
+            if last_seq < self.max_seq && (last_seq + SEQ_SPACE) > self.max_seq && (last_seq + SEQ_SPACE - MAX_FORWARD_SEQ_JUMP) <= self.max_seq {
                 self.wrap_around += 1;
-                self.max_seq = last_seq + u32::MAX as u64;
-            } else if last_seq - MAX_FORWARD_SEQ_JUMP < self.max_seq {
-                self.max_seq = last_seq;
+                self.max_seq = last_seq + SEQ_SPACE;
+                self.segments.add(self.max_seq - byte_count as u64..self.max_seq, next_seq);
+            } else if last_seq.saturating_sub(MAX_FORWARD_SEQ_JUMP) < self.max_seq {
+                self.segments.add(last_seq - byte_count as u64..last_seq, next_seq);
+                self.max_seq = self.max_seq.max(last_seq);
             } else {
                 warn!("Conn seq error: ISN {}, max {}, packet seq {} len {}, calc last {}",
                     self.initial_sequence_number, self.max_seq, tcp_seq, byte_count, last_seq);
+                anomaly = Some(Anomaly::SequenceJump { max_seq: self.max_seq, last_seq });
             }
             // Save to buffer
-            // Typically all 3 length are identical- packet, packet header, packet data. TCP payload is 66 bytes less.
-            let offset = data.len() - byte_count;
-            if offset > 0 {
-                let buf = &data[offset..data.len()];
-                //TODO handle a future buffer-shift management
+            let first_seq = self.absolute_seq(tcp_seq);
+            if !data.is_empty() && first_seq > self.initial_sequence_number as u64 {
+                let buf = &data[..byte_count.min(data.len())];
                 let buffer_offset = self.relative_seq(tcp_seq) as usize;
+                let kept = buf.len().min((self.base_offset + self.buffer_limit).saturating_sub(buffer_offset));
+                self.dropped_bytes += (buf.len() - kept) as u64;
                 // Write the bytes and update the ranges control
-                self.write_bytes(buf, buffer_offset);
+                self.write_bytes(&buf[..kept], buffer_offset)?;
+                self.max_held_bytes = self.max_held_bytes.max(self.held_bytes());
             }
         }
+        Ok(anomaly)
     }
-}
\ No newline at end of file
+
+    /// Keep a retransmission in the timeline, unless it is full, given the capture timestamp and the packet's sequence
+    pub(crate) fn record_retransmit(&mut self, ts: Duration, tcp_seq: u32, byte_count: usize) {
+        if self.retransmits.len() < MAX_RETRANSMIT_RECORDS {
+            let seq = self.relative_seq(tcp_seq);
+            self.retransmits.push(RetransmitRecord { ts, seq, len: byte_count as u32 });
+        }
+    }
+
+    /// The retransmission timeline as JSON
+    pub(crate) fn retransmits_json(&self, time_format: &TimeFormatter) -> JsonValue {
+        self.retransmits.iter().map(|record| JsonValue::object(vec![
+            ("time", time_format.to_json(record.ts)),
+            ("seq", record.seq.into()),
+            ("len", record.len.into()),
+        ])).collect::<Vec<JsonValue>>().into()
+    }
+
+    /// Write the flow state to a snapshot.
+    /// Without payload, only the counters and sequence tracking are saved, and the buffer starts empty on restore.
+    pub(crate) fn write_snapshot<W: Write>(&self, writer: &mut SnapshotWriter<W>, with_payload: bool) -> Result<(), Error> {
+        writer.put_u32(self.initial_sequence_number)?;
+        writer.put_u64(self.max_seq)?;
+        writer.put_u64(self.wrap_around as u64)?;
+        writer.put_u64(self.byte_count)?;
+        writer.put_u32(self.packet_count)?;
+        writer.put_u16(self.window_scale)?;
+        writer.put_u32(self.window_shrink_count)?;
+        writer.put_u32(self.data_packet_count)?;
+        self.segments.write_snapshot(writer)?;
+        writer.put_u32(self.zero_window_count)?;
+        writer.put_u64(self.dropped_bytes)?;
+        if !with_payload {
+            writer.put_u8(0)?;
+            return Ok(());
+        }
+        writer.put_u8(1)?;
+        writer.put_bytes(&self.data)?;
+        writer.put_u64(self.base_offset as u64)?;
+        writer.put_u64(self.read_pos as u64)?;
+        writer.put_u32(self.data_filled_ranges.len() as u32)?;
+        for range in self.data_filled_ranges.iter() {
+            writer.put_u64(range.start as u64)?;
+            writer.put_u64(range.end as u64)?;
+        }
+        Ok(())
+    }
+
+    /// Restore a flow that was written by [FlowBuff::write_snapshot].
+    pub(crate) fn read_snapshot<R: Read>(reader: &mut SnapshotReader<R>) -> Result<Self, Error> {
+        let mut flow = FlowBuff::new();
+        flow.initial_sequence_number = reader.get_u32()?;
+        flow.max_seq = reader.get_u64()?;
+        flow.wrap_around = reader.get_u64()? as usize;
+        flow.byte_count = reader.get_u64()?;
+        flow.packet_count = reader.get_u32()?;
+        flow.window_scale = reader.get_u16()?;
+        flow.window_shrink_count = reader.get_u32()?;
+        flow.data_packet_count = reader.get_u32()?;
+        flow.segments = SegmentCounts::read_snapshot(reader)?;
+        flow.zero_window_count = reader.get_u32()?;
+        flow.dropped_bytes = reader.get_u64()?;
+        if reader.get_u8()? == 0 {
+            return Ok(flow);
+        }
+        flow.data = reader.get_bytes(MAX_FLOW_BUFFER_LIMIT)?;
+        flow.base_offset = reader.get_u64()? as usize;
+        flow.read_pos = reader.get_u64()? as usize;
+        if flow.read_pos < flow.base_offset || flow.read_pos > flow.len() {
+            return Err(Error::new(ErrorKind::InvalidData, "Snapshot read position is outside the buffer"));
+        }
+        let range_count = reader.get_u32()?;
+        for _ in 0..range_count {
+            let start = reader.get_u64()? as usize;
+            let end = reader.get_u64()? as usize;
+            if start > end || end >= flow.len() {
+                return Err(Error::new(ErrorKind::InvalidData, "Snapshot filled range is outside the buffer"));
+            }
+            flow.data_filled_ranges.insert(start, end);
+        }
+        Ok(flow)
+    }
+
+    /// JSON representation of the flow, for debug dumps.
+    /// Filled ranges and holes are inclusive on both ends, as held internally. Holes, retransmissions and stalls are informative and are not read back.
+    pub(crate) fn to_json(&self, payload: &PayloadExport, time_format: &TimeFormatter) -> JsonValue {
+        let ranges_json = |ranges: &[Range<usize>]| -> JsonValue {
+            ranges.iter().map(|r| JsonValue::Array(vec![r.start.into(), r.end.into()])).collect::<Vec<JsonValue>>().into()
+        };
+        let mut fields = vec![
+            ("initial_sequence_number", self.initial_sequence_number.into()),
+            ("max_seq", self.max_seq.into()),
+            ("wrap_around", self.wrap_around.into()),
+            ("byte_count", self.byte_count.into()),
+            ("packet_count", self.packet_count.into()),
+            ("window_scale", self.window_scale.into()),
+            ("window_shrink_count", self.window_shrink_count.into()),
+            ("data_packet_count", self.data_packet_count.into()),
+            ("segments", self.segments.to_json()),
+            ("zero_window_count", self.zero_window_count.into()),
+            ("dropped_bytes", self.dropped_bytes.into()),
+            ("retransmits", self.retransmits_json(time_format)),
+            ("acks", self.acks.to_json()),
+            ("nagle_stalls", self.nagle_stalls.to_json()),
+            ("window_stalls", self.window_stalls.to_json()),
+            ("sack_loss", self.sack_loss.to_json(self.byte_count)),
+            ("buffer_len", self.data.len().into()),
+            ("base_offset", self.base_offset.into()),
+            ("read_pos", self.read_pos.into()),
+            ("max_held_bytes", self.max_held_bytes.into()),
+            ("filled_ranges", ranges_json(&self.filled_map())),
+            ("holes", ranges_json(&self.holes())),
+        ];
+        match payload {
+            PayloadExport::Omit => {}
+            PayloadExport::Hex => { fields.push(("data", bytes_to_hex(&self.data).into())) }
+            PayloadExport::Digests(mode) => {
+                fields.push(("chunk_mode", mode.to_string().into()));
+                fields.push(("chunk_digests", chunk_digests_json(&self.data, self.base_offset, &self.kept_map(), mode)));
+            }
+        }
+        JsonValue::object(fields)
+    }
+
+    /// Restore a flow from its JSON representation, made by [FlowBuff::to_json].
+    /// Without the payload, the filled ranges are dropped since they would point at missing bytes.
+    pub(crate) fn from_json(json: &JsonValue) -> Result<Self, Error> {
+        let mut flow = FlowBuff::new();
+        flow.initial_sequence_number = json.field_u64("initial_sequence_number")? as u32;
+        flow.max_seq = json.field_u64("max_seq")?;
+        flow.wrap_around = json.field_u64("wrap_around")? as usize;
+        flow.byte_count = json.field_u64("byte_count")?;
+        flow.packet_count = json.field_u64("packet_count")? as u32;
+        flow.window_scale = json.field_u64("window_scale")? as u16;
+        flow.window_shrink_count = json.field_u64("window_shrink_count")? as u32;
+        flow.data_packet_count = json.field_u64("data_packet_count")? as u32;
+        flow.segments = SegmentCounts::from_json(json.get("segments").unwrap_or(&JsonValue::Null))?;
+        flow.zero_window_count = json.field_u64("zero_window_count")? as u32;
+        flow.dropped_bytes = json.field_u64("dropped_bytes")?;
+        if let Some(acks) = json.get("acks") {
+            flow.acks = AckPattern::from_json(acks)?;
+        }
+        flow.max_held_bytes = json.get("max_held_bytes").and_then(|bytes| bytes.as_u64()).unwrap_or_default() as usize;
+        let data = match json.get("data").and_then(|d| d.as_str()) {
+            None => { return Ok(flow); }
+            Some(hex) => { hex_to_bytes(hex).ok_or_else(|| Error::new(ErrorKind::InvalidData, "Invalid hex payload"))? }
+        };
+        if data.len() > MAX_FLOW_BUFFER_LIMIT {
+            return Err(Error::new(ErrorKind::InvalidData, "Payload exceeds the maximum buffer size"));
+        }
+        flow.data = data;
+        flow.base_offset = json.field_u64("base_offset")? as usize;
+        flow.read_pos = json.field_u64("read_pos")? as usize;
+        if flow.read_pos < flow.base_offset || flow.read_pos > flow.len() {
+            return Err(Error::new(ErrorKind::InvalidData, "Read position is outside the buffer"));
+        }
+        for range in json.field_array("filled_ranges")? {
+            let bounds: Vec<u64> = range.as_array().map(|a| a.iter().filter_map(|v| v.as_u64()).collect()).unwrap_or_default();
+            if bounds.len() != 2 || bounds[0] > bounds[1] || bounds[1] as usize >= flow.len() {
+                return Err(Error::new(ErrorKind::InvalidData, "Filled range is invalid or outside the buffer"));
+            }
+            flow.data_filled_ranges.insert(bounds[0] as usize, bounds[1] as usize);
+        }
+        Ok(flow)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FilledRanges, FlowBuff, SackLoss};
+
+    /// The ranges after adding the given ones in order, as inclusive start and end pairs
+    fn filled(added: &[(usize, usize)]) -> Vec<(usize, usize)> {
+        let mut ranges = FilledRanges::new();
+        for (start, end) in added {
+            ranges.insert(*start, *end);
+        }
+        ranges.iter().map(|range| (range.start, range.end)).collect()
+    }
+
+    #[test]
+    fn partial_overlap_extends_the_range() {
+        assert_eq!(filled(&[(0, 9), (5, 14)]), vec![(0, 14)]);
+        assert_eq!(filled(&[(5, 14), (0, 9)]), vec![(0, 14)]);
+    }
+
+    #[test]
+    fn gap_fill_joins_both_neighbours() {
+        assert_eq!(filled(&[(0, 9), (20, 29), (10, 19)]), vec![(0, 29)]);
+    }
+
+    #[test]
+    fn out_of_order_inserts_stay_sorted() {
+        assert_eq!(filled(&[(40, 49), (0, 9), (20, 29)]), vec![(0, 9), (20, 29), (40, 49)]);
+    }
+
+    #[test]
+    fn exact_duplicate_is_ignored() {
+        assert_eq!(filled(&[(0, 9), (20, 29), (20, 29)]), vec![(0, 9), (20, 29)]);
+    }
+
+    #[test]
+    fn range_inside_an_existing_one_is_ignored() {
+        assert_eq!(filled(&[(0, 29), (10, 19)]), vec![(0, 29)]);
+        assert_eq!(filled(&[(0, 29), (0, 5)]), vec![(0, 29)]);
+    }
+
+    #[test]
+    fn sack_holes_are_counted_once() {
+        let mut sack_loss = SackLoss::default();
+        // D-SACK blocks below the ACK report no holes
+        sack_loss.add_sack(1000, vec![500..600, 800..900]);
+        assert_eq!(sack_loss.sack_acks, 0);
+        sack_loss.add_sack(1000, vec![3000..4000, 2000..2500]);
+        sack_loss.add_sack(1000, vec![2000..4000, 5000..6000]);
+        assert_eq!((sack_loss.sack_acks, sack_loss.holes, sack_loss.hole_bytes), (2, 3, 2500));
+        sack_loss.add_segment(1000..2000);
+        sack_loss.add_segment(2500..2600);
+        assert_eq!(sack_loss.retransmitted_holes, 2);
+        assert_eq!(sack_loss.loss_percent(10000), Some(25.0));
+    }
+
+    #[test]
+    fn sequences_before_the_wrap_around() {
+        let mut flow = FlowBuff::new();
+        flow.set_initial_sequence_number(u32::MAX - 99);
+        flow.add_bytes(u32::MAX - 98, 100, &[]).unwrap();
+        // The segment that wraps around, after which the relative sequences go on from the ones before it
+        assert_eq!(flow.add_bytes(1, 100, &[]).unwrap(), None);
+        assert_eq!(flow.relative_seq(1), 100);
+        // A retransmission and an ACK from before the wrap around
+        assert_eq!(flow.add_bytes(u32::MAX - 98, 100, &[]).unwrap(), None);
+        assert_eq!(flow.relative_seq(u32::MAX - 48), 50);
+    }
+}
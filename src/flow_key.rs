@@ -0,0 +1,546 @@
+use std::fmt;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+use etherparse::{InternetSlice, SlicedPacket, TransportSlice, VlanSlice};
+use crate::json::JsonValue;
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
+
+/// UDP port of VXLAN (RFC 7348)
+const VXLAN_PORT: u16 = 4789;
+const VXLAN_HEADER_LEN: usize = 8;
+/// Flag of a VXLAN header that has a valid network identifier
+const VXLAN_FLAG_VNI: u8 = 0x08;
+/// IP protocols of IPv4 in IP (RFC 2003) and of GRE (RFC 2784)
+const IP_PROTOCOL_IPIP: u8 = 4;
+const IP_PROTOCOL_GRE: u8 = 47;
+/// Flags of a GRE header, for the optional fields, and the source routing of RFC 1701
+const GRE_FLAG_CHECKSUM: u8 = 0x80;
+const GRE_FLAG_ROUTING: u8 = 0x40;
+const GRE_FLAG_KEY: u8 = 0x20;
+const GRE_FLAG_SEQUENCE: u8 = 0x10;
+/// Protocol types of the GRE payloads that are unwrapped: IP, bridged Ethernet (NVGRE and mirroring), and Ethernet
+/// mirrored by ERSPAN
+const GRE_PROTOCOL_IPV4: u16 = 0x0800;
+const GRE_PROTOCOL_IPV6: u16 = 0x86dd;
+const GRE_PROTOCOL_ETHERNET: u16 = 0x6558;
+const GRE_PROTOCOL_ERSPAN_II: u16 = 0x88be;
+const GRE_PROTOCOL_ERSPAN_III: u16 = 0x22eb;
+const ERSPAN_II_HEADER_LEN: usize = 8;
+const ERSPAN_III_HEADER_LEN: usize = 12;
+/// Length of the platform specific subheader of ERSPAN type III, that follows its header if its flag is set
+const ERSPAN_III_SUBHEADER_LEN: usize = 8;
+
+/// Max length of a strategy name in a snapshot
+pub(crate) const MAX_NAME_LEN: usize = 32;
+/// Strategies by name, as given on the command line
+const FLOW_KEYERS: &[(&str, FlowKeyerFactory)] = &[
+    ("5-tuple", || Arc::new(FiveTuple)),
+    ("5-tuple+vlan", || Arc::new(FiveTupleVlan)),
+    ("5-tuple+tunnel", || Arc::new(FiveTupleTunnel)),
+    ("3-tuple", || Arc::new(ThreeTuple)),
+];
+
+type FlowKeyerFactory = fn() -> Arc<dyn FlowKeyer>;
+
+/// VLAN and tunnel a packet came through, that tell apart connections with the same addresses at some capture points
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Encapsulation {
+    /// VLAN identifier, or the outer and inner identifiers of a double tag (outer in the high bits), if tagged
+    pub vlan: Option<u32>,
+    /// VXLAN network identifier, if the packet was tunneled
+    pub tunnel_id: Option<u32>,
+    /// The outermost tunnel, if the packet was tunneled
+    pub outer_tunnel: Option<OuterTunnel>,
+}
+
+impl Encapsulation {
+    pub fn is_empty(&self) -> bool {
+        self.vlan.is_none() && self.tunnel_id.is_none() && self.outer_tunnel.is_none()
+    }
+
+    pub(crate) fn write_snapshot<W: Write>(&self, writer: &mut SnapshotWriter<W>) -> Result<(), Error> {
+        for id in [self.vlan, self.tunnel_id] {
+            writer.put_u8(id.is_some() as u8)?;
+            writer.put_u32(id.unwrap_or_default())?;
+        }
+        match &self.outer_tunnel {
+            None => { writer.put_u8(0) }
+            Some(tunnel) => {
+                writer.put_u8(tunnel.kind.tag())?;
+                writer.put_u8(tunnel.depth)?;
+                put_ip(writer, tunnel.src)?;
+                put_ip(writer, tunnel.dst)
+            }
+        }
+    }
+
+    /// Restore the identifiers that were written by [Encapsulation::write_snapshot]
+    pub(crate) fn read_snapshot<R: Read>(reader: &mut SnapshotReader<R>) -> Result<Self, Error> {
+        let mut read_id = || -> Result<Option<u32>, Error> {
+            let present = reader.get_u8()? != 0;
+            let id = reader.get_u32()?;
+            Ok(if present { Some(id) } else { None })
+        };
+        let (vlan, tunnel_id) = (read_id()?, read_id()?);
+        let outer_tunnel = match reader.get_u8()? {
+            0 => { None }
+            tag => {
+                let kind = TunnelKind::from_tag(tag)?;
+                let depth = reader.get_u8()?;
+                Some(OuterTunnel { kind, depth, src: get_ip(reader)?, dst: get_ip(reader)? })
+            }
+        };
+        Ok(Encapsulation { vlan, tunnel_id, outer_tunnel })
+    }
+}
+
+impl fmt::Display for Encapsulation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.vlan, self.tunnel_id) {
+            (Some(vlan), Some(tunnel_id)) => { write!(f, "vlan {} vni {}", vlan, tunnel_id)?; }
+            (Some(vlan), None) => { write!(f, "vlan {}", vlan)?; }
+            (None, Some(tunnel_id)) => { write!(f, "vni {}", tunnel_id)?; }
+            (None, None) if self.outer_tunnel.is_none() => { write!(f, "none")?; }
+            (None, None) => {}
+        }
+        if let Some(tunnel) = &self.outer_tunnel {
+            if self.vlan.is_some() || self.tunnel_id.is_some() {
+                write!(f, " ")?;
+            }
+            write!(f, "{}", tunnel)?;
+        }
+        Ok(())
+    }
+}
+
+/// Kind of a tunnel that is unwrapped
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TunnelKind {
+    Vxlan,
+    /// GRE, including ERSPAN mirroring
+    Gre,
+    /// IPv4 in IP
+    IpIp,
+}
+
+impl TunnelKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            TunnelKind::Vxlan => { "vxlan" }
+            TunnelKind::Gre => { "gre" }
+            TunnelKind::IpIp => { "ipip" }
+        }
+    }
+
+    fn tag(&self) -> u8 {
+        match self {
+            TunnelKind::Vxlan => { 1 }
+            TunnelKind::Gre => { 2 }
+            TunnelKind::IpIp => { 3 }
+        }
+    }
+
+    fn from_tag(tag: u8) -> Result<Self, Error> {
+        match tag {
+            1 => { Ok(TunnelKind::Vxlan) }
+            2 => { Ok(TunnelKind::Gre) }
+            3 => { Ok(TunnelKind::IpIp) }
+            _ => { Err(Error::new(ErrorKind::InvalidData, format!("Unknown tunnel kind tag {}", tag))) }
+        }
+    }
+
+    fn from_name(name: &str) -> Result<Self, Error> {
+        [TunnelKind::Vxlan, TunnelKind::Gre, TunnelKind::IpIp].into_iter().find(|kind| kind.name() == name)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("Unknown tunnel kind '{}'", name)))
+    }
+}
+
+/// The outermost tunnel that a packet came through, with its endpoints, such as the mirroring switch and the collector
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OuterTunnel {
+    pub kind: TunnelKind,
+    pub src: IpAddr,
+    pub dst: IpAddr,
+    /// Number of nested tunnels that were unwrapped, from 1 for this one only
+    pub depth: u8,
+}
+
+impl OuterTunnel {
+    pub fn to_json(&self) -> JsonValue {
+        JsonValue::object(vec![
+            ("kind", self.kind.name().into()),
+            ("src", self.src.to_string().into()),
+            ("dst", self.dst.to_string().into()),
+            ("depth", self.depth.into()),
+        ])
+    }
+
+    /// Restore a tunnel from its JSON representation, made by [OuterTunnel::to_json]
+    pub(crate) fn from_json(json: &JsonValue) -> Result<Self, Error> {
+        let parse_ip = |key: &str| -> Result<IpAddr, Error> {
+            json.field_str(key)?.parse()
+                .map_err(|_| Error::new(ErrorKind::InvalidData, format!("Invalid address in JSON field '{}'", key)))
+        };
+        Ok(OuterTunnel {
+            kind: TunnelKind::from_name(json.field_str("kind")?)?,
+            src: parse_ip("src")?,
+            dst: parse_ip("dst")?,
+            depth: json.field_u64("depth")? as u8,
+        })
+    }
+}
+
+impl fmt::Display for OuterTunnel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} > {}", self.kind.name(), self.src, self.dst)?;
+        if self.depth > 1 {
+            write!(f, " ({} nested)", self.depth)?;
+        }
+        Ok(())
+    }
+}
+
+/// An address as its family and its bits
+fn put_ip<W: Write>(writer: &mut SnapshotWriter<W>, ip: IpAddr) -> Result<(), Error> {
+    match ip {
+        IpAddr::V4(ip) => {
+            writer.put_u8(4)?;
+            writer.put_u128(u32::from(ip) as u128)
+        }
+        IpAddr::V6(ip) => {
+            writer.put_u8(6)?;
+            writer.put_u128(u128::from(ip))
+        }
+    }
+}
+
+fn get_ip<R: Read>(reader: &mut SnapshotReader<R>) -> Result<IpAddr, Error> {
+    let family = reader.get_u8()?;
+    let bits = reader.get_u128()?;
+    match family {
+        4 => { Ok(IpAddr::V4(Ipv4Addr::from(bits as u32))) }
+        6 => { Ok(IpAddr::V6(Ipv6Addr::from(bits))) }
+        _ => { Err(Error::new(ErrorKind::InvalidData, format!("Unknown address family {}", family))) }
+    }
+}
+
+/// Unwrap the tunnels of a packet, VXLAN, GRE (including ERSPAN mirroring) and IPv4 in IP, up to the given number of
+/// nested ones, so the tunneled connections are tracked rather than the tunnels.
+/// Return the innermost packet that could be parsed, with the VLAN and the tunnels it came through.
+pub fn decapsulate(outer: SlicedPacket, max_depth: u8) -> (SlicedPacket, Encapsulation) {
+    let mut encapsulation = Encapsulation { vlan: vlan_id(&outer.vlan), tunnel_id: None, outer_tunnel: None };
+    let mut packet = outer;
+    for depth in 1..=max_depth {
+        let (kind, inner, vni) = match unwrap_tunnel(&packet) {
+            None => { break; }
+            Some(unwrapped) => { unwrapped }
+        };
+        match &mut encapsulation.outer_tunnel {
+            Some(tunnel) => { tunnel.depth = depth }
+            None => {
+                if let Some((src, dst)) = ip_endpoints(&packet.ip) {
+                    encapsulation.outer_tunnel = Some(OuterTunnel { kind, src, dst, depth });
+                }
+            }
+        }
+        encapsulation.vlan = vlan_id(&inner.vlan).or(encapsulation.vlan);
+        encapsulation.tunnel_id = vni.or(encapsulation.tunnel_id);
+        packet = inner;
+    }
+    (packet, encapsulation)
+}
+
+/// The packet that a tunnel packet carries, with the kind of the tunnel and its VXLAN network identifier, if it is a
+/// tunnel and the packet it carries could be parsed
+fn unwrap_tunnel<'a>(packet: &SlicedPacket<'a>) -> Option<(TunnelKind, SlicedPacket<'a>, Option<u32>)> {
+    let payload = packet.payload;
+    match (&packet.ip, &packet.transport) {
+        (Some(_), Some(TransportSlice::Udp(udp))) if udp.destination_port() == VXLAN_PORT => {
+            if payload.len() <= VXLAN_HEADER_LEN || payload[0] & VXLAN_FLAG_VNI == 0 {
+                return None;
+            }
+            let vni = u32::from_be_bytes([0, payload[4], payload[5], payload[6]]);
+            let inner = SlicedPacket::from_ethernet(&payload[VXLAN_HEADER_LEN..]).ok()?;
+            Some((TunnelKind::Vxlan, inner, Some(vni)))
+        }
+        // Only the first fragment has the inner header, so fragmented tunnel packets are left as they are
+        (Some(InternetSlice::Ipv4(ip_header, _)), _) if !ip_header.is_fragmenting_payload() => {
+            // The parsed payload runs to the end of the frame, so it is cut at the total length of the datagram
+            let payload_len = (ip_header.total_len() as usize).saturating_sub(ip_header.slice().len());
+            let payload = &payload[..payload.len().min(payload_len)];
+            match ip_header.protocol() {
+                IP_PROTOCOL_IPIP => { Some((TunnelKind::IpIp, SlicedPacket::from_ip(payload).ok()?, None)) }
+                IP_PROTOCOL_GRE => { Some((TunnelKind::Gre, unwrap_gre(payload)?, None)) }
+                _ => { None }
+            }
+        }
+        _ => { None }
+    }
+}
+
+/// The packet that a GRE payload carries: IP, Ethernet, or Ethernet mirrored by ERSPAN
+fn unwrap_gre(gre: &[u8]) -> Option<SlicedPacket<'_>> {
+    let flags = *gre.first()?;
+    // Version 0 only, since version 1 is the PPP of PPTP, and without the obsolete source routing
+    if gre.get(1)? & 0x07 != 0 || flags & GRE_FLAG_ROUTING != 0 {
+        return None;
+    }
+    let header_len = 4 + [GRE_FLAG_CHECKSUM, GRE_FLAG_KEY, GRE_FLAG_SEQUENCE].iter()
+        .filter(|flag| flags & **flag != 0).count() * 4;
+    let protocol = u16::from_be_bytes([*gre.get(2)?, *gre.get(3)?]);
+    let inner = gre.get(header_len..)?;
+    match protocol {
+        GRE_PROTOCOL_IPV4 | GRE_PROTOCOL_IPV6 => { SlicedPacket::from_ip(inner).ok() }
+        GRE_PROTOCOL_ETHERNET => { SlicedPacket::from_ethernet(inner).ok() }
+        GRE_PROTOCOL_ERSPAN_II => {
+            // Type I has no header of its own, and is told apart from type II by the missing sequence number
+            let erspan_len = if flags & GRE_FLAG_SEQUENCE != 0 { ERSPAN_II_HEADER_LEN } else { 0 };
+            SlicedPacket::from_ethernet(inner.get(erspan_len..)?).ok()
+        }
+        GRE_PROTOCOL_ERSPAN_III => {
+            let subheader = inner.get(ERSPAN_III_HEADER_LEN - 1)? & 0x01 != 0;
+            let erspan_len = ERSPAN_III_HEADER_LEN + if subheader { ERSPAN_III_SUBHEADER_LEN } else { 0 };
+            SlicedPacket::from_ethernet(inner.get(erspan_len..)?).ok()
+        }
+        _ => { None }
+    }
+}
+
+fn ip_endpoints(ip: &Option<InternetSlice>) -> Option<(IpAddr, IpAddr)> {
+    match ip {
+        Some(InternetSlice::Ipv4(ip_header, _)) => { Some((ip_header.source_addr().into(), ip_header.destination_addr().into())) }
+        Some(InternetSlice::Ipv6(ip_header, _)) => { Some((ip_header.source_addr().into(), ip_header.destination_addr().into())) }
+        None => { None }
+    }
+}
+
+fn vlan_id(vlan: &Option<VlanSlice>) -> Option<u32> {
+    match vlan {
+        None => { None }
+        Some(VlanSlice::SingleVlan(single)) => { Some(single.vlan_identifier() as u32) }
+        Some(VlanSlice::DoubleVlan(double)) => {
+            Some((double.outer().vlan_identifier() as u32) << 12 | double.inner().vlan_identifier() as u32)
+        }
+    }
+}
+
+/// Decides which packets belong to the same connection, since capture points differ in what makes a connection unique
+pub trait FlowKeyer: Send + Sync {
+    /// Key of the connection list for a packet, given the signature of its addresses (as made by
+    /// [crate::conn::Conn::sign_by_tuple]) and its encapsulation. Both directions must get the same key.
+    fn key(&self, conn_sign: u128, encapsulation: &Encapsulation) -> u128;
+
+    /// Name of the strategy, as given on the command line
+    fn name(&self) -> &'static str;
+}
+
+/// Parse a strategy name: "5-tuple" (the default), "5-tuple+vlan", "5-tuple+tunnel" or "3-tuple"
+pub fn parse(arg: &str) -> Result<Arc<dyn FlowKeyer>, String> {
+    FLOW_KEYERS.iter().find(|(name, _)| *name == arg.trim()).map(|(_, create)| create())
+        .ok_or_else(|| format!("unknown flow key \"{}\", expected {}", arg.trim(),
+                               FLOW_KEYERS.iter().map(|(name, _)| *name).collect::<Vec<&str>>().join(", ")))
+}
+
+/// The strategy that a saved connection table was keyed by, by the name that was saved along with it
+pub(crate) fn from_saved_name(name: &[u8]) -> Result<Arc<dyn FlowKeyer>, Error> {
+    std::str::from_utf8(name).ok().and_then(|name| parse(name).ok())
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Unknown flow key in saved state"))
+}
+
+/// The default strategy
+pub fn default_keyer() -> Arc<dyn FlowKeyer> {
+    Arc::new(FiveTuple)
+}
+
+/// Identifier in the free high bits of the signature, above the addresses, where 0 stands for none
+fn with_id(conn_sign: u128, id: Option<u32>) -> u128 {
+    conn_sign | (id.map_or(0, |id| id as u128 + 1)) << 96
+}
+
+/// Addresses and ports only (the protocol is always TCP), so the same connection seen on several VLANs is tracked once
+struct FiveTuple;
+
+impl FlowKeyer for FiveTuple {
+    fn key(&self, conn_sign: u128, _encapsulation: &Encapsulation) -> u128 {
+        conn_sign
+    }
+
+    fn name(&self) -> &'static str {
+        "5-tuple"
+    }
+}
+
+/// Addresses, ports and VLAN, for capture points where overlapping address spaces are told apart by VLAN
+struct FiveTupleVlan;
+
+impl FlowKeyer for FiveTupleVlan {
+    fn key(&self, conn_sign: u128, encapsulation: &Encapsulation) -> u128 {
+        with_id(conn_sign, encapsulation.vlan)
+    }
+
+    fn name(&self) -> &'static str {
+        "5-tuple+vlan"
+    }
+}
+
+/// Addresses, ports and VXLAN network identifier, for overlay networks of tenants with overlapping address spaces
+struct FiveTupleTunnel;
+
+impl FlowKeyer for FiveTupleTunnel {
+    fn key(&self, conn_sign: u128, encapsulation: &Encapsulation) -> u128 {
+        with_id(conn_sign, encapsulation.tunnel_id)
+    }
+
+    fn name(&self) -> &'static str {
+        "5-tuple+tunnel"
+    }
+}
+
+/// Addresses and the service port, without the client port (the higher of the two), for captures behind a NAT that
+/// spreads a client over many ports. The connection keeps the ports of its first packet.
+struct ThreeTuple;
+
+impl FlowKeyer for ThreeTuple {
+    fn key(&self, conn_sign: u128, _encapsulation: &Encapsulation) -> u128 {
+        // The port of the lower address is in bits 0-15, and the port of the higher address in bits 48-63
+        let (low_port, high_port) = (conn_sign as u16, (conn_sign >> 48) as u16);
+        if low_port >= high_port { conn_sign & !0xffff } else { conn_sign & !(0xffff << 48) }
+    }
+
+    fn name(&self) -> &'static str {
+        "3-tuple"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use etherparse::{PacketBuilder, SlicedPacket, TransportSlice};
+    use super::{decapsulate, Encapsulation, OuterTunnel, TunnelKind, IP_PROTOCOL_GRE, IP_PROTOCOL_IPIP, VXLAN_PORT};
+
+    const MAC: [u8; 6] = [2, 0, 0, 0, 0, 1];
+
+    /// The tunneled TCP segment from 10.0.0.1:1000 to 10.0.0.2:80, as an IPv4 packet
+    fn inner_packet() -> Vec<u8> {
+        let mut packet = Vec::new();
+        PacketBuilder::ipv4([10, 0, 0, 1], [10, 0, 0, 2], 64).tcp(1000, 80, 1, 1000).write(&mut packet, b"hi").unwrap();
+        packet
+    }
+
+    /// The tunneled TCP segment in an Ethernet frame tagged with VLAN 20
+    fn inner_frame() -> Vec<u8> {
+        let mut frame = Vec::new();
+        PacketBuilder::ethernet2(MAC, MAC).single_vlan(20).ipv4([10, 0, 0, 1], [10, 0, 0, 2], 64)
+            .tcp(1000, 80, 1, 1000).write(&mut frame, b"hi").unwrap();
+        frame
+    }
+
+    /// An Ethernet frame with an IPv4 packet from 192.0.2.1 to 192.0.2.2 of the given protocol
+    fn outer_frame(protocol: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::new();
+        PacketBuilder::ethernet2(MAC, MAC).ipv4([192, 0, 2, 1], [192, 0, 2, 2], 64).write(&mut frame, protocol, payload)
+            .unwrap();
+        frame
+    }
+
+    /// A GRE header with the given flags and protocol type, and zeros for its optional fields
+    fn gre(flags: u8, protocol: u16, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![flags, 0];
+        packet.extend_from_slice(&protocol.to_be_bytes());
+        packet.resize(4 + 4 * (flags & 0xb0).count_ones() as usize, 0);
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    fn outer_tunnel(kind: TunnelKind, depth: u8) -> Option<OuterTunnel> {
+        Some(OuterTunnel { kind, src: IpAddr::from([192, 0, 2, 1]), dst: IpAddr::from([192, 0, 2, 2]), depth })
+    }
+
+    /// Decapsulate a frame, and return the payload and the destination port of its innermost TCP segment, if any
+    fn unwrap(frame: &[u8], max_depth: u8) -> (Option<(Vec<u8>, u16)>, Encapsulation) {
+        let (packet, encapsulation) = decapsulate(SlicedPacket::from_ethernet(frame).unwrap(), max_depth);
+        let tcp = match &packet.transport {
+            Some(TransportSlice::Tcp(tcp)) => { Some((packet.payload.to_vec(), tcp.destination_port())) }
+            _ => { None }
+        };
+        (tcp, encapsulation)
+    }
+
+    #[test]
+    fn gre_payloads() {
+        let segment = Some((b"hi".to_vec(), 80));
+        let (tcp, encapsulation) = unwrap(&outer_frame(IP_PROTOCOL_GRE, &gre(0, 0x0800, &inner_packet())), 4);
+        assert_eq!(tcp, segment);
+        assert_eq!(encapsulation, Encapsulation { vlan: None, tunnel_id: None, outer_tunnel: outer_tunnel(TunnelKind::Gre, 1) });
+        // With the checksum, key and sequence number fields, carrying bridged Ethernet
+        let (tcp, encapsulation) = unwrap(&outer_frame(IP_PROTOCOL_GRE, &gre(0xb0, 0x6558, &inner_frame())), 4);
+        assert_eq!((tcp, encapsulation.vlan), (segment.clone(), Some(20)));
+        // ERSPAN type I, without a header of its own, type II, and type III with and without its subheader
+        let mut erspan = vec![0; 8];
+        erspan.extend(inner_frame());
+        assert_eq!(unwrap(&outer_frame(IP_PROTOCOL_GRE, &gre(0, 0x88be, &inner_frame())), 4).0, segment);
+        assert_eq!(unwrap(&outer_frame(IP_PROTOCOL_GRE, &gre(0x10, 0x88be, &erspan)), 4).0, segment);
+        let mut erspan = vec![0; 12];
+        erspan.extend(inner_frame());
+        assert_eq!(unwrap(&outer_frame(IP_PROTOCOL_GRE, &gre(0, 0x22eb, &erspan)), 4).0, segment);
+        let mut erspan = vec![0; 20];
+        erspan[11] = 0x01;
+        erspan.extend(inner_frame());
+        assert_eq!(unwrap(&outer_frame(IP_PROTOCOL_GRE, &gre(0, 0x22eb, &erspan)), 4).0, segment);
+    }
+
+    #[test]
+    fn gre_that_is_not_unwrapped() {
+        let mut pptp = gre(0, 0x0800, &inner_packet());
+        pptp[1] = 0x01;
+        let mut routing = gre(0x40, 0x0800, &inner_packet());
+        routing.splice(4..4, [0; 4]);
+        for packet in [pptp, routing, gre(0, 0x0806, &inner_packet()), gre(0x20, 0x0800, &[])] {
+            assert_eq!(unwrap(&outer_frame(IP_PROTOCOL_GRE, &packet), 4), (None, Encapsulation::default()));
+        }
+        // A fragment of a tunnel packet
+        let mut fragment = outer_frame(IP_PROTOCOL_GRE, &gre(0, 0x0800, &inner_packet()));
+        fragment[14 + 6] = 0x20;
+        assert_eq!(unwrap(&fragment, 4), (None, Encapsulation::default()));
+    }
+
+    #[test]
+    fn nested_tunnels() {
+        let ipip = outer_frame(IP_PROTOCOL_IPIP, &inner_packet());
+        let (tcp, encapsulation) = unwrap(&ipip, 4);
+        assert_eq!((tcp, encapsulation.outer_tunnel), (Some((b"hi".to_vec(), 80)), outer_tunnel(TunnelKind::IpIp, 1)));
+        // IPv4 in IP in GRE, where the outer tunnel is the first one and counts both
+        let mut ipip_packet = Vec::new();
+        PacketBuilder::ipv4([172, 16, 0, 1], [172, 16, 0, 2], 64).write(&mut ipip_packet, IP_PROTOCOL_IPIP, &inner_packet())
+            .unwrap();
+        let frame = outer_frame(IP_PROTOCOL_GRE, &gre(0, 0x0800, &ipip_packet));
+        let (tcp, encapsulation) = unwrap(&frame, 4);
+        assert_eq!((tcp, encapsulation.outer_tunnel), (Some((b"hi".to_vec(), 80)), outer_tunnel(TunnelKind::Gre, 2)));
+        // Only as deep as allowed
+        let (tcp, encapsulation) = unwrap(&frame, 1);
+        assert_eq!((tcp, encapsulation.outer_tunnel), (None, outer_tunnel(TunnelKind::Gre, 1)));
+        assert_eq!(unwrap(&frame, 0), (None, Encapsulation::default()));
+    }
+
+    #[test]
+    fn tunnel_payload_ends_at_the_datagram() {
+        // Ethernet padding after a short tunnel packet is not part of the tunneled packet
+        let mut frame = outer_frame(IP_PROTOCOL_IPIP, &inner_packet());
+        frame.extend_from_slice(&[0xee; 6]);
+        assert_eq!(unwrap(&frame, 4).0, Some((b"hi".to_vec(), 80)));
+    }
+
+    #[test]
+    fn vxlan() {
+        let mut payload = vec![0x08, 0, 0, 0, 0, 0x12, 0x34, 0];
+        payload.extend(inner_frame());
+        let mut frame = Vec::new();
+        PacketBuilder::ethernet2(MAC, MAC).ipv4([192, 0, 2, 1], [192, 0, 2, 2], 64).udp(5000, VXLAN_PORT)
+            .write(&mut frame, &payload).unwrap();
+        let (tcp, encapsulation) = unwrap(&frame, 4);
+        assert_eq!(tcp, Some((b"hi".to_vec(), 80)));
+        assert_eq!(encapsulation, Encapsulation { vlan: Some(20), tunnel_id: Some(0x1234),
+            outer_tunnel: outer_tunnel(TunnelKind::Vxlan, 1) });
+    }
+}
@@ -0,0 +1,107 @@
+use std::fs::File;
+use std::io::{BufWriter, Error, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use log::error;
+use crate::conn::{ConnState, ConnSummary, PacketDir};
+use crate::json::JsonValue;
+use crate::observer::CloseReason;
+use crate::time_format::TimeFormatter;
+
+/// What happened to a connection, as a line of the connection log
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FlowLogEvent {
+    /// The connection was seen for the first time
+    Open,
+    /// The handshake was completed
+    Established,
+    /// The connection was removed after it closed or failed
+    Closed,
+    /// The connection was removed for going idle before it closed
+    Evicted,
+}
+
+impl FlowLogEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            FlowLogEvent::Open => { "open" }
+            FlowLogEvent::Established => { "established" }
+            FlowLogEvent::Closed => { "closed" }
+            FlowLogEvent::Evicted => { "evicted" }
+        }
+    }
+
+    /// Why the connection ended, for the events that end it: "fin", "reset", "setup_failed" or "idle"
+    fn close_reason(&self, state: &ConnState) -> Option<&'static str> {
+        match self {
+            FlowLogEvent::Open | FlowLogEvent::Established => { None }
+            FlowLogEvent::Evicted => { Some(CloseReason::Idle.name()) }
+            FlowLogEvent::Closed => { Some(CloseReason::new(state, false).name()) }
+        }
+    }
+}
+
+/// Connection log of one JSON object per line for every connection event, with the endpoints, the counters of both
+/// directions, the duration and the close reason, so log shippers can feed it to ELK as a lightweight flow log.
+/// A failure to write is logged once and stops the log.
+pub struct FlowLog {
+    writer: Option<BufWriter<File>>,
+    time_format: TimeFormatter,
+    line_count: u64,
+}
+
+impl FlowLog {
+    /// Create the log file, or truncate it if it exists
+    pub fn create(path: &Path, time_format: TimeFormatter) -> Result<Self, Error> {
+        Ok(FlowLog { writer: Some(BufWriter::new(File::create(path)?)), time_format, line_count: 0 })
+    }
+
+    /// Write the line of an event of the connection with the given summary, buffered until the next flush
+    pub fn write(&mut self, event: FlowLogEvent, summary: &ConnSummary) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let initiator = summary.initiator.as_ref().map(|dir| if *dir == PacketDir::SrcLowAddr { "low" } else { "high" });
+        let line = JsonValue::object(vec![
+            ("event", event.name().into()),
+            ("time", self.time_format.to_json(now)),
+            ("sequence", summary.conn_sequence.into()),
+            ("id", summary.conn_id.as_str().into()),
+            ("low", summary.low_addr.to_string().into()),
+            ("high", summary.high_addr.to_string().into()),
+            ("vlan", summary.encapsulation.vlan.into()),
+            ("outer_tunnel", summary.encapsulation.outer_tunnel.map_or(JsonValue::Null, |tunnel| tunnel.to_json())),
+            ("initiator", initiator.into()),
+            ("server_name", summary.server_name.as_deref().into()),
+            ("resolved_name", summary.resolved_name.as_deref().into()),
+            ("state", summary.state.to_json()),
+            ("packets_src_low", summary.packets_src_low.into()),
+            ("packets_src_high", summary.packets_src_high.into()),
+            ("bytes_src_low", summary.bytes_src_low.into()),
+            ("bytes_src_high", summary.bytes_src_high.into()),
+            ("duration_ms", (summary.age.as_millis() as u64).into()),
+            ("close_reason", event.close_reason(&summary.state).into()),
+        ]).to_string();
+        if let Some(writer) = &mut self.writer {
+            match writeln!(writer, "{}", line) {
+                Err(error) => { self.fail(error) }
+                Ok(_) => { self.line_count += 1 }
+            }
+        }
+    }
+
+    /// Write the buffered lines to the file, so a log shipper that follows it sees them
+    pub fn flush(&mut self) {
+        if let Some(Err(error)) = self.writer.as_mut().map(|writer| writer.flush()) {
+            self.fail(error);
+        }
+    }
+
+    /// Number of lines written
+    pub fn line_count(&self) -> u64 {
+        self.line_count
+    }
+
+    fn fail(&mut self, error: Error) {
+        error!("Failed to write the connection log, it is stopped after {} lines: {}", self.line_count, error);
+        self.writer = None;
+    }
+}
@@ -0,0 +1,149 @@
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::time::Duration;
+use log::warn;
+use crate::ignore::network;
+use crate::json::JsonValue;
+use crate::latency::LatencyHistogram;
+use crate::time_format::TimeFormatter;
+
+/// Header line of the latency heatmap CSV export
+pub const HEATMAP_CSV_HEADER: &str = "time,subnet,port,metric,count,p50_us,p90_us,p99_us,max_us";
+/// Max number of (time bucket, subnet, port) cells, since every cell holds two histograms
+const MAX_HEATMAP_CELLS: usize = 10000;
+/// IPv6 server addresses are aggregated by this prefix length, the common subnet size
+const IPV6_PREFIX_LEN: u8 = 64;
+
+/// Latency that is aggregated in the heatmap
+#[derive(Clone, Copy)]
+pub enum LatencyMetric {
+    /// Time between the SYN and the SYN/ACK
+    HandshakeRtt,
+    /// Time between the first payload of the client and the first payload of the server after it
+    Ttfb,
+}
+
+/// Aggregation key, ordered by time first so the export reads as a time line
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct CellKey {
+    /// Start of the time bucket, in seconds of capture time
+    time: u64,
+    subnet: IpAddr,
+    port: u16,
+}
+
+/// Latency distributions of the connections of one server subnet and port, in one time bucket, in microseconds
+#[derive(Clone, Default)]
+struct HeatmapCell {
+    handshake_rtt: LatencyHistogram,
+    ttfb: LatencyHistogram,
+}
+
+/// Handshake RTT and time to first byte distributions, per server subnet and port, over capture time buckets.
+/// Systemic latency changes, such as after a deploy, show up as a change between buckets.
+#[derive(Clone)]
+pub struct LatencyHeatmap {
+    /// Width of a time bucket in seconds
+    bucket_secs: u64,
+    /// IPv4 server addresses are aggregated by this prefix length
+    prefix_len: u8,
+    cells: BTreeMap<CellKey, HeatmapCell>,
+    /// Samples that were not aggregated since the max number of cells was reached
+    dropped_samples: u64,
+}
+
+impl LatencyHeatmap {
+    pub fn new(bucket_secs: u64, prefix_len: u8) -> Self {
+        Self { bucket_secs: bucket_secs.max(1), prefix_len: prefix_len.min(32), cells: BTreeMap::new(), dropped_samples: 0 }
+    }
+
+    /// Add a latency sample of a connection to the given server, measured at the given capture time
+    pub fn record(&mut self, metric: LatencyMetric, ts: Duration, server_ip: IpAddr, server_port: u16, latency: Duration) {
+        let key = CellKey {
+            time: ts.as_secs() - ts.as_secs() % self.bucket_secs,
+            subnet: network(server_ip, self.subnet_prefix_len(&server_ip)),
+            port: server_port,
+        };
+        if !self.cells.contains_key(&key) && self.cells.len() >= MAX_HEATMAP_CELLS {
+            if self.dropped_samples == 0 {
+                warn!("Latency heatmap reached {} cells, new cells are dropped", MAX_HEATMAP_CELLS);
+            }
+            self.dropped_samples += 1;
+            return;
+        }
+        let cell = self.cells.entry(key).or_default();
+        let histogram = match metric {
+            LatencyMetric::HandshakeRtt => { &mut cell.handshake_rtt }
+            LatencyMetric::Ttfb => { &mut cell.ttfb }
+        };
+        histogram.record(latency.as_micros() as u64);
+    }
+
+    /// Add the cells of another heatmap with the same buckets, such as of another shard of the connection table.
+    /// Cells beyond the max number are dropped with their samples.
+    pub fn merge(&mut self, other: &LatencyHeatmap) {
+        self.dropped_samples += other.dropped_samples;
+        for (key, other_cell) in &other.cells {
+            if !self.cells.contains_key(key) && self.cells.len() >= MAX_HEATMAP_CELLS {
+                self.dropped_samples += other_cell.handshake_rtt.count() + other_cell.ttfb.count();
+                continue;
+            }
+            let cell = self.cells.entry(*key).or_default();
+            cell.handshake_rtt.merge(&other_cell.handshake_rtt);
+            cell.ttfb.merge(&other_cell.ttfb);
+        }
+    }
+
+    /// Forget all the cells, keeping the buckets
+    pub fn clear(&mut self) {
+        self.cells.clear();
+        self.dropped_samples = 0;
+    }
+
+    /// Prefix length that the subnets of the address family of the given address are aggregated by
+    fn subnet_prefix_len(&self, ip: &IpAddr) -> u8 {
+        if ip.is_ipv4() { self.prefix_len } else { IPV6_PREFIX_LEN }
+    }
+
+    /// Non-empty histograms of all the cells, in time order, with the metric name
+    fn rows(&self) -> impl Iterator<Item=(&CellKey, &'static str, &LatencyHistogram)> {
+        self.cells.iter()
+            .flat_map(|(key, cell)| [(key, "handshake_rtt", &cell.handshake_rtt), (key, "ttfb", &cell.ttfb)])
+            .filter(|(_, _, histogram)| histogram.count() > 0)
+    }
+
+    /// Export as CSV lines (see [HEATMAP_CSV_HEADER]), with percentiles that are accurate within a factor of 2
+    pub fn to_csv(&self, time_format: &TimeFormatter) -> String {
+        let mut result = String::from(HEATMAP_CSV_HEADER) + "\n";
+        for (key, metric, histogram) in self.rows() {
+            result.push_str(&format!("{},{}/{},{},{},{},{},{},{},{}\n", time_format.format_secs(key.time), key.subnet, self.subnet_prefix_len(&key.subnet), key.port,
+                                     metric, histogram.count(), histogram.percentile(0.5), histogram.percentile(0.9),
+                                     histogram.percentile(0.99), histogram.max()));
+        }
+        result
+    }
+
+    /// Export as JSON, with the same fields as the CSV export and the full histogram of every row,
+    /// as counts by bucket upper bound
+    pub fn to_json(&self, time_format: &TimeFormatter) -> JsonValue {
+        let rows: Vec<JsonValue> = self.rows().map(|(key, metric, histogram)| JsonValue::object(vec![
+            ("time", time_format.secs_to_json(key.time)),
+            ("subnet", format!("{}/{}", key.subnet, self.subnet_prefix_len(&key.subnet)).into()),
+            ("port", key.port.into()),
+            ("metric", metric.into()),
+            ("count", histogram.count().into()),
+            ("p50_us", histogram.percentile(0.5).into()),
+            ("p90_us", histogram.percentile(0.9).into()),
+            ("p99_us", histogram.percentile(0.99).into()),
+            ("max_us", histogram.max().into()),
+            ("histogram", histogram.buckets()
+                .map(|(upper_bound, count)| JsonValue::object(vec![("le_us", upper_bound.into()), ("count", count.into())]))
+                .collect::<Vec<JsonValue>>().into()),
+        ])).collect();
+        JsonValue::object(vec![
+            ("bucket_secs", self.bucket_secs.into()),
+            ("dropped_samples", self.dropped_samples.into()),
+            ("rows", rows.into()),
+        ])
+    }
+}
@@ -0,0 +1,118 @@
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::time::Duration;
+use etherparse::TcpHeaderSlice;
+use crate::conn::PacketDir;
+use crate::json::JsonValue;
+use crate::time_format::TimeFormatter;
+
+/// Header line of the time-sequence CSV export
+pub const TIME_SEQUENCE_CSV_HEADER: &str = "conn,time,dir,seq,ack,len,window";
+
+/// Width of the arrow between the two sides of a ladder diagram, in characters
+const LADDER_WIDTH: usize = 64;
+
+/// One packet of a connection, as kept for diagnostics
+#[derive(Clone, Debug)]
+pub struct PacketRecord {
+    /// Capture timestamp
+    pub ts: Duration,
+    pub dir: PacketDir,
+    /// All the TCP flags, such as "SYN,ACK"
+    pub flags: String,
+    /// Sequence and ack, relative to the ISN of their flows
+    pub seq: u64,
+    pub ack: u64,
+    /// TCP payload length
+    pub len: u16,
+    /// Advertised window, after scaling except on SYN packets
+    pub window: u32,
+}
+
+/// The last packets of a connection, up to a max number, so it can be rendered or exported later
+#[derive(Clone)]
+pub struct PacketHistory {
+    records: VecDeque<PacketRecord>,
+    /// Max number of packets to keep, or 0 to keep none
+    max_len: usize,
+    /// Number of older packets that were dropped to respect the max
+    dropped: u64,
+}
+
+impl PacketHistory {
+    pub fn new(max_len: usize) -> Self {
+        Self { records: VecDeque::new(), max_len, dropped: 0 }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.max_len > 0
+    }
+
+    pub fn push(&mut self, record: PacketRecord) {
+        if self.max_len == 0 {
+            return;
+        }
+        if self.records.len() >= self.max_len {
+            self.records.pop_front();
+            self.dropped += 1;
+        }
+        self.records.push_back(record);
+    }
+
+    /// Append the packets as time-sequence CSV lines (see [TIME_SEQUENCE_CSV_HEADER]), with the capture time
+    /// and the direction as "A" for the low address and "B" for the high one, as in the ladder diagram
+    pub fn time_sequence_csv(&self, conn_sequence: u32, time_format: &TimeFormatter, out: &mut String) {
+        for record in &self.records {
+            out.push_str(&format!("{},{},{},{},{},{},{}\n", conn_sequence, time_format.format(record.ts), dir_label(&record.dir),
+                                  record.seq, record.ack, record.len, record.window));
+        }
+    }
+
+    /// The packets as time-sequence JSON samples, with the same fields as the CSV export
+    pub fn time_sequence_json(&self, conn_sequence: u32, time_format: &TimeFormatter) -> Vec<JsonValue> {
+        self.records.iter().map(|record| JsonValue::object(vec![
+            ("conn", conn_sequence.into()),
+            ("time", time_format.to_json(record.ts)),
+            ("dir", dir_label(&record.dir).into()),
+            ("seq", record.seq.into()),
+            ("ack", record.ack.into()),
+            ("len", record.len.into()),
+            ("window", record.window.into()),
+        ])).collect()
+    }
+
+    /// Render the packets as a textual ladder diagram between the low (A) and high (B) addresses,
+    /// with the time in milliseconds since the first kept packet
+    pub fn ladder(&self, conn_sequence: u32, low: &SocketAddr, high: &SocketAddr) -> String {
+        let mut lines = vec![format!("Connection {}: A = {}, B = {}", conn_sequence, low, high)];
+        if self.dropped > 0 {
+            lines.push(format!("({} earlier packets were not kept)", self.dropped));
+        }
+        lines.push(format!("{:>12}  A{}B", "time ms", " ".repeat(LADDER_WIDTH)));
+        let first_ts = self.records.front().map(|record| record.ts).unwrap_or_default();
+        for record in &self.records {
+            let label = format!(" {} seq {} ack {} len {} win {} ", record.flags, record.seq, record.ack, record.len,
+                                record.window);
+            let fill = "-".repeat(LADDER_WIDTH.saturating_sub(label.len() + 3));
+            let arrow = match record.dir {
+                PacketDir::SrcLowAddr => { format!("--{}{}>", label, fill) }
+                PacketDir::SrcHighAddr => { format!("<-{}{}-", label, fill) }
+            };
+            let ms = record.ts.saturating_sub(first_ts).as_micros() as f64 / 1000f64;
+            lines.push(format!("{:>12.3}  |{}|", ms, arrow));
+        }
+        lines.join("\n")
+    }
+}
+
+/// Direction as "A" for the low address and "B" for the high one, as in the ladder diagram
+pub fn dir_label(dir: &PacketDir) -> &'static str {
+    match dir { PacketDir::SrcLowAddr => { "A" }, PacketDir::SrcHighAddr => { "B" } }
+}
+
+/// All the flags that are set in a TCP header, comma separated, in the order they are usually written (SYN,ACK)
+pub fn tcp_flags_list(tcp: &TcpHeaderSlice) -> String {
+    let flags = [(tcp.syn(), "SYN"), (tcp.fin(), "FIN"), (tcp.rst(), "RST"), (tcp.psh(), "PSH"), (tcp.ack(), "ACK"),
+        (tcp.urg(), "URG"), (tcp.ece(), "ECE"), (tcp.cwr(), "CWR")];
+    flags.iter().filter(|(set, _)| *set).map(|(_, name)| *name).collect::<Vec<&str>>().join(",")
+}
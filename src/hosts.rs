@@ -0,0 +1,84 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::net::IpAddr;
+use crate::conn::{ConnSummary, PacketDir};
+
+/// Passive profile of a host, from the connections it took part in
+#[derive(Clone, Default)]
+pub struct HostStats {
+    /// Connections this host opened (sent the SYN)
+    pub initiated: u64,
+    /// Connections this host accepted
+    pub received: u64,
+    /// Payload bytes sent and received by this host
+    pub bytes_out: u64,
+    pub bytes_in: u64,
+    /// Other hosts this host talked to
+    peers: HashSet<IpAddr>,
+    /// Ports this host used, on its side of the connections
+    ports: HashSet<u16>,
+}
+
+impl fmt::Display for HostStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "initiated {}, received {}, bytes out {}, bytes in {}, {} peers, {} ports", self.initiated,
+               self.received, self.bytes_out, self.bytes_in, self.peers.len(), self.ports.len())
+    }
+}
+
+/// Host profiles by IP, updated when connections are finalized
+#[derive(Clone, Default)]
+pub struct HostTable {
+    hosts: HashMap<IpAddr, HostStats>,
+}
+
+impl HostTable {
+    /// Count a finalized connection in the profiles of both its hosts
+    pub fn add_connection(&mut self, summary: &ConnSummary) {
+        let sides = [
+            (summary.low_addr, summary.high_addr, PacketDir::SrcLowAddr, summary.bytes_src_low, summary.bytes_src_high),
+            (summary.high_addr, summary.low_addr, PacketDir::SrcHighAddr, summary.bytes_src_high, summary.bytes_src_low),
+        ];
+        for (addr, peer, dir, bytes_out, bytes_in) in sides {
+            let host = self.hosts.entry(addr.ip()).or_default();
+            match &summary.initiator {
+                Some(initiator) if *initiator == dir => { host.initiated += 1 }
+                Some(_) => { host.received += 1 }
+                None => {}
+            }
+            host.bytes_out += bytes_out;
+            host.bytes_in += bytes_in;
+            host.peers.insert(peer.ip());
+            host.ports.insert(addr.port());
+        }
+    }
+
+    /// Add the profiles of another table, such as of another shard of the connection table
+    pub fn merge(&mut self, other: &HostTable) {
+        for (ip, other_host) in &other.hosts {
+            let host = self.hosts.entry(*ip).or_default();
+            host.initiated += other_host.initiated;
+            host.received += other_host.received;
+            host.bytes_out += other_host.bytes_out;
+            host.bytes_in += other_host.bytes_in;
+            host.peers.extend(&other_host.peers);
+            host.ports.extend(&other_host.ports);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.hosts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hosts.is_empty()
+    }
+
+    /// The hosts that moved the most bytes, in both directions, up to the given number
+    pub fn top(&self, count: usize) -> Vec<(IpAddr, &HostStats)> {
+        let mut hosts: Vec<(IpAddr, &HostStats)> = self.hosts.iter().map(|(ip, host)| (*ip, host)).collect();
+        hosts.sort_by_key(|(ip, host)| (std::cmp::Reverse(host.bytes_out + host.bytes_in), *ip));
+        hosts.truncate(count);
+        hosts
+    }
+}
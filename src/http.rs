@@ -0,0 +1,151 @@
+use std::io::{BufRead, BufReader, Error, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use log::{info, warn};
+use crate::conn::{ConnState, PacketDir};
+use crate::connections::Connections;
+use crate::shards::ConnShards;
+
+/// How often a followed connection is checked for new bytes
+const FOLLOW_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Max length of the request line and of every header line, against clients that never end a line
+const MAX_LINE_LEN: usize = 8192;
+
+/// Listen on the given "IP:PORT" for HTTP requests on the live connection table. Every client is served by its own thread.
+///
+/// `GET /follow?a=IP:PORT&b=IP:PORT[&dir=low|high|both]` streams the reassembled payload of a connection, from its
+/// first byte and then while it is being captured, as a chunked response. It ends when the connection is closed and
+/// all its contiguous bytes were sent, or when the connection is removed.
+pub fn serve(addr: &str, connections: ConnShards) -> Result<(), Error> {
+    let listener = TcpListener::bind(addr)?;
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Err(error) => { warn!("Failed to accept an HTTP client: {}", error) }
+                Ok(stream) => {
+                    let connections = connections.clone();
+                    thread::spawn(move || {
+                        if let Err(error) = handle_client(stream, &connections) {
+                            warn!("HTTP client failed: {}", error);
+                        }
+                    });
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Serve a single request, since a followed stream takes the whole connection
+fn handle_client(stream: TcpStream, connections: &ConnShards) -> Result<(), Error> {
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream).take(MAX_LINE_LEN as u64);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Skip the headers, none of them matter
+    loop {
+        reader.set_limit(MAX_LINE_LEN as u64);
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim_end().is_empty() {
+            break;
+        }
+    }
+    let target = match request_line.split_whitespace().collect::<Vec<&str>>().as_slice() {
+        ["GET", target, _] => { *target }
+        _ => { return write_error(&mut writer, "405 Method Not Allowed", "only GET is supported"); }
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    if path != "/follow" {
+        return write_error(&mut writer, "404 Not Found", "unknown path, try /follow?a=IP:PORT&b=IP:PORT");
+    }
+    match parse_follow_query(query) {
+        Err(message) => { write_error(&mut writer, "400 Bad Request", &message) }
+        Ok((a, b, dirs)) => {
+            match connections.conn_shard(a, b) {
+                None => { write_error(&mut writer, "404 Not Found", "no such connection") }
+                Some(shard) => { follow(&mut writer, shard, a, b, &dirs) }
+            }
+        }
+    }
+}
+
+/// The endpoints and the directions to follow, from a "a=IP:PORT&b=IP:PORT&dir=low|high|both" query
+fn parse_follow_query(query: &str) -> Result<(SocketAddr, SocketAddr, Vec<PacketDir>), String> {
+    let (mut a, mut b, mut dirs) = (None, None, vec![PacketDir::SrcLowAddr, PacketDir::SrcHighAddr]);
+    for param in query.split('&').filter(|param| !param.is_empty()) {
+        let (key, value) = param.split_once('=').unwrap_or((param, ""));
+        match key {
+            "a" => { a = Some(value.parse::<SocketAddr>().map_err(|_| format!("invalid address '{}'", value))?) }
+            "b" => { b = Some(value.parse::<SocketAddr>().map_err(|_| format!("invalid address '{}'", value))?) }
+            "dir" => {
+                dirs = match value {
+                    "low" => { vec![PacketDir::SrcLowAddr] }
+                    "high" => { vec![PacketDir::SrcHighAddr] }
+                    "both" => { vec![PacketDir::SrcLowAddr, PacketDir::SrcHighAddr] }
+                    _ => { return Err(format!("invalid direction '{}', expected low, high or both", value)); }
+                }
+            }
+            _ => { return Err(format!("unknown parameter '{}'", key)); }
+        }
+    }
+    match (a, b) {
+        (Some(a), Some(b)) => { Ok((a, b, dirs)) }
+        _ => { Err(String::from("expected the two endpoints, as a=IP:PORT&b=IP:PORT")) }
+    }
+}
+
+/// Stream the contiguous bytes of the followed directions as they arrive, in chunks.
+/// Both directions are sent in the order they were polled, without framing, as in a "follow stream" of both sides.
+/// The connection stays in the shard it was found in, since its packets always go to the same shard.
+fn follow(writer: &mut TcpStream, connections: &Mutex<Connections>, a: SocketAddr, b: SocketAddr, dirs: &[PacketDir])
+          -> Result<(), Error> {
+    let conn_sequence = match connections.lock().unwrap().find_conn(a, b) {
+        None => { return write_error(writer, "404 Not Found", "no such connection"); }
+        Some(conn) => { conn.conn_sequence }
+    };
+    info!("HTTP client {} follows connection {}", writer.peer_addr()?, conn_sequence);
+    writer.write_all(b"HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\nTransfer-Encoding: chunked\r\n\
+                       Connection: close\r\n\r\n")?;
+    let mut positions = vec![0usize; dirs.len()];
+    loop {
+        let mut chunks = Vec::new();
+        let finished = {
+            let connections = connections.lock().unwrap();
+            match connections.find_conn(a, b).filter(|conn| conn.conn_sequence == conn_sequence) {
+                None => { true }
+                Some(conn) => {
+                    for (dir, pos) in dirs.iter().zip(positions.iter_mut()) {
+                        // Bytes that were consumed and released can no longer be followed
+                        *pos = (*pos).max(conn.flow(dir).released_len());
+                        let bytes = conn.flow(dir).contiguous_bytes(*pos);
+                        if !bytes.is_empty() {
+                            *pos += bytes.len();
+                            chunks.push(bytes.to_vec());
+                        }
+                    }
+                    conn.state.is_closed() || matches!(conn.state, ConnState::Failed(_))
+                }
+            }
+        };
+        for chunk in &chunks {
+            write!(writer, "{:x}\r\n", chunk.len())?;
+            writer.write_all(chunk)?;
+            writer.write_all(b"\r\n")?;
+        }
+        if finished {
+            break;
+        }
+        writer.flush()?;
+        thread::sleep(FOLLOW_POLL_INTERVAL);
+    }
+    writer.write_all(b"0\r\n\r\n")?;
+    writer.flush()
+}
+
+fn write_error(writer: &mut TcpStream, status: &str, message: &str) -> Result<(), Error> {
+    write!(writer, "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}\n", status,
+           message.len() + 1, message)?;
+    writer.flush()
+}
@@ -0,0 +1,143 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use crate::conn::Conn;
+use crate::ipv6::ETHER_TYPES_VLAN;
+use crate::utils::mix64;
+
+/// Number of bits in the negative cache of ignored flows (128KB)
+const IGNORED_FLOWS_FILTER_BITS: usize = 1 << 20;
+/// Number of flows after which the negative cache is cleared, which keeps its false positive rate below about 0.5%
+const IGNORED_FLOWS_FILTER_CAPACITY: u64 = 1 << 16;
+/// Number of bits set per flow
+const IGNORED_FLOWS_FILTER_HASHES: u64 = 3;
+
+/// Traffic that is not tracked at all, on either side of a connection
+#[derive(Clone, Debug, PartialEq)]
+pub enum IgnoreRule {
+    Port(u16),
+    Host(IpAddr),
+    /// Network address and prefix length
+    Net(IpAddr, u8),
+}
+
+impl IgnoreRule {
+    /// Parse a rule given as "port:N", "host:IP" or "net:IP/PREFIX"
+    pub fn parse(arg: &str) -> Result<Self, String> {
+        let (kind, value) = arg.split_once(':').ok_or("expected port:N, host:IP or net:IP/PREFIX")?;
+        let value = value.trim();
+        match kind.trim() {
+            "port" => { Ok(IgnoreRule::Port(value.parse().map_err(|_| format!("invalid port '{}'", value))?)) }
+            "host" => { Ok(IgnoreRule::Host(value.parse().map_err(|_| format!("invalid IP '{}'", value))?)) }
+            "net" => {
+                let (ip, prefix_len) = value.split_once('/').ok_or("expected net:IP/PREFIX")?;
+                let ip: IpAddr = ip.parse().map_err(|_| format!("invalid IP '{}'", ip))?;
+                let max_prefix_len = if ip.is_ipv4() { 32 } else { 128 };
+                let prefix_len: u8 = prefix_len.parse().ok().filter(|prefix_len| *prefix_len <= max_prefix_len)
+                    .ok_or_else(|| format!("invalid prefix length '{}'", prefix_len))?;
+                Ok(IgnoreRule::Net(network(ip, prefix_len), prefix_len))
+            }
+            other => { Err(format!("unknown rule kind \"{}\", expected port, host or net", other)) }
+        }
+    }
+
+    /// Answer if either side of a connection matches the rule
+    pub fn matches(&self, low: &SocketAddr, high: &SocketAddr) -> bool {
+        [low, high].iter().any(|addr| match self {
+            IgnoreRule::Port(port) => { addr.port() == *port }
+            IgnoreRule::Host(ip) => { addr.ip() == *ip }
+            IgnoreRule::Net(net, prefix_len) => { network(addr.ip(), *prefix_len) == *net }
+        })
+    }
+}
+
+impl fmt::Display for IgnoreRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IgnoreRule::Port(port) => { write!(f, "port:{}", port) }
+            IgnoreRule::Host(ip) => { write!(f, "host:{}", ip) }
+            IgnoreRule::Net(net, prefix_len) => { write!(f, "net:{}/{}", net, prefix_len) }
+        }
+    }
+}
+
+/// The network of an address by prefix length, of the same family as the address
+pub(crate) fn network(ip: IpAddr, prefix_len: u8) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let mask = u32::MAX.checked_shl(32u32.saturating_sub(prefix_len as u32)).unwrap_or(0);
+            Ipv4Addr::from(u32::from(ip) & mask).into()
+        }
+        IpAddr::V6(ip) => {
+            let mask = u128::MAX.checked_shl(128u32.saturating_sub(prefix_len as u32)).unwrap_or(0);
+            Ipv6Addr::from(u128::from(ip) & mask).into()
+        }
+    }
+}
+
+/// Bloom filter of the signatures of flows that matched an ignore rule, so their later packets are dropped
+/// before they are parsed and before the rules and the connection table are checked.
+/// A false positive drops a packet of a flow that should be tracked, so the filter is cleared when it gets too full.
+#[derive(Clone)]
+pub struct IgnoredFlows {
+    bits: Vec<u64>,
+    /// Flows added since the filter was cleared
+    flow_count: u64,
+}
+
+impl IgnoredFlows {
+    pub fn new() -> Self {
+        Self { bits: vec![0; IGNORED_FLOWS_FILTER_BITS / 64], flow_count: 0 }
+    }
+
+    pub fn insert(&mut self, conn_sign: u128) {
+        if self.flow_count >= IGNORED_FLOWS_FILTER_CAPACITY {
+            self.bits.fill(0);
+            self.flow_count = 0;
+        }
+        for bit in bit_indexes(conn_sign) {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+        self.flow_count += 1;
+    }
+
+    /// Answer if the flow was (probably) added
+    pub fn contains(&self, conn_sign: u128) -> bool {
+        self.flow_count > 0 && bit_indexes(conn_sign).all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+}
+
+impl Default for IgnoredFlows {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Bits of a flow in the filter, by double hashing of the signature
+fn bit_indexes(conn_sign: u128) -> impl Iterator<Item=usize> {
+    let h1 = mix64(conn_sign as u64 ^ mix64((conn_sign >> 64) as u64));
+    let h2 = mix64(h1) | 1;
+    (0..IGNORED_FLOWS_FILTER_HASHES).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % IGNORED_FLOWS_FILTER_BITS as u64) as usize)
+}
+
+/// Signature of the connection of a raw Ethernet frame carrying TCP over IPv4, possibly with one or two VLAN tags,
+/// without a full parse, or None for anything else (such as fragments), which goes through the full parse
+pub fn raw_conn_sign(frame: &[u8]) -> Option<u128> {
+    let mut offset = 12;
+    while offset < 20 && ETHER_TYPES_VLAN.contains(&u16::from_be_bytes([*frame.get(offset)?, *frame.get(offset + 1)?])) {
+        offset += 4;
+    }
+    let ip = frame.get(offset + 2..)?;
+    if frame[offset..offset + 2] != [0x08, 0x00] || ip.len() < 20 || ip[0] >> 4 != 4 || ip[9] != 6 {
+        return None;
+    }
+    // Only the first fragment has the TCP header, so fragments are left to the full parse
+    if u16::from_be_bytes([ip[6], ip[7]]) & 0x3fff != 0 {
+        return None;
+    }
+    let ports = ip.get((ip[0] & 0x0f) as usize * 4..)?.get(..4)?;
+    let src_ip = Ipv4Addr::new(ip[12], ip[13], ip[14], ip[15]);
+    let dst_ip = Ipv4Addr::new(ip[16], ip[17], ip[18], ip[19]);
+    let (conn_sign, _) = Conn::sign_by_tuple(src_ip.into(), u16::from_be_bytes([ports[0], ports[1]]),
+                                             dst_ip.into(), u16::from_be_bytes([ports[2], ports[3]]));
+    Some(conn_sign)
+}
@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+use crate::ipv6::{MAX_PENDING_DATAGRAMS, PendingDatagram};
+
+/// Length of the IPv4 header without options
+pub(crate) const IPV4_MIN_HEADER_LEN: usize = 20;
+/// Time to wait for the rest of the fragments of a datagram, from its first fragment, as Linux does by default
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(30);
+/// Flag of the fragment field that more fragments follow
+const MORE_FRAGMENTS: u16 = 0x2000;
+
+/// Counters of the IPv4 fragment reassembly
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Ipv4FragmentStats {
+    /// Fragments that were received
+    pub fragments: u64,
+    /// Datagrams that were reassembled from all their fragments
+    pub reassembled: u64,
+    /// Datagrams whose fragments overlapped, expired or exceeded the limits, and were dropped
+    pub dropped_datagrams: u64,
+    /// Fragments whose header or length did not make sense
+    pub malformed: u64,
+}
+
+impl Ipv4FragmentStats {
+    /// Add the counters of another reassembler, such as of another shard of the connection table
+    pub fn add(&mut self, other: &Ipv4FragmentStats) {
+        self.fragments += other.fragments;
+        self.reassembled += other.reassembled;
+        self.dropped_datagrams += other.dropped_datagrams;
+        self.malformed += other.malformed;
+    }
+}
+
+impl fmt::Display for Ipv4FragmentStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} fragments, {} datagrams reassembled, {} dropped, {} malformed", self.fragments, self.reassembled,
+               self.dropped_datagrams, self.malformed)
+    }
+}
+
+/// A datagram that was reassembled from its fragments
+pub struct Ipv4Datagram {
+    pub src: Ipv4Addr,
+    pub dst: Ipv4Addr,
+    pub protocol: u8,
+    pub payload: Vec<u8>,
+}
+
+/// Reassembles fragmented IPv4 datagrams, so their TCP segments are tracked as if they were not fragmented
+#[derive(Default)]
+pub struct Ipv4Defragmenter {
+    stats: Ipv4FragmentStats,
+    /// Datagrams being reassembled, by source, destination, identification and protocol (RFC 791)
+    pending: HashMap<([u8; 4], [u8; 4], u16, u8), PendingDatagram>,
+}
+
+impl Clone for Ipv4Defragmenter {
+    /// The counters only, since partial datagrams are not worth keeping in a copy of the table
+    fn clone(&self) -> Self {
+        Ipv4Defragmenter { stats: self.stats, pending: HashMap::new() }
+    }
+}
+
+impl Ipv4Defragmenter {
+    /// Keep a fragment, from its IPv4 header, captured at the given time. Return the datagram if it was the last
+    /// missing fragment. Datagrams with overlapping fragments are dropped, and so are those that are too long or expired.
+    pub fn process(&mut self, ts: Duration, packet: &[u8]) -> Option<Ipv4Datagram> {
+        self.stats.fragments += 1;
+        let header_len = packet.first().map_or(0, |b| (b & 0x0f) as usize * 4);
+        let total_len = match packet.get(..IPV4_MIN_HEADER_LEN) {
+            Some(header) => { u16::from_be_bytes([header[2], header[3]]) as usize }
+            None => { 0 }
+        };
+        let data = match packet.get(header_len..total_len) {
+            Some(data) if header_len >= IPV4_MIN_HEADER_LEN => { data }
+            _ => {
+                self.stats.malformed += 1;
+                return None;
+            }
+        };
+        let fragment = u16::from_be_bytes([packet[6], packet[7]]);
+        let offset = (fragment & 0x1fff) as usize * 8;
+        let more = fragment & MORE_FRAGMENTS != 0;
+        let mut src = [0u8; 4];
+        let mut dst = [0u8; 4];
+        src.copy_from_slice(&packet[12..16]);
+        dst.copy_from_slice(&packet[16..20]);
+        let key = (src, dst, u16::from_be_bytes([packet[4], packet[5]]), packet[9]);
+
+        if !self.pending.contains_key(&key) {
+            self.expire(ts);
+            if self.pending.len() >= MAX_PENDING_DATAGRAMS {
+                self.stats.dropped_datagrams += 1;
+                return None;
+            }
+        }
+        let datagram = self.pending.entry(key).or_insert_with(|| PendingDatagram::new(ts));
+        if !datagram.add(offset, more, data) {
+            self.pending.remove(&key);
+            self.stats.dropped_datagrams += 1;
+            return None;
+        }
+        let payload = datagram.reassemble()?;
+        self.pending.remove(&key);
+        self.stats.reassembled += 1;
+        Some(Ipv4Datagram { src: src.into(), dst: dst.into(), protocol: key.3, payload })
+    }
+
+    /// Counters of the reassembly
+    pub fn stats(&self) -> Ipv4FragmentStats {
+        self.stats
+    }
+
+    /// Add the counters of another reassembler, leaving its pending fragments
+    pub fn merge_stats(&mut self, other: &Ipv4Defragmenter) {
+        self.stats.add(&other.stats);
+    }
+
+    /// Drop the datagrams that did not complete in time
+    fn expire(&mut self, ts: Duration) {
+        let before = self.pending.len();
+        self.pending.retain(|_, datagram| ts.saturating_sub(datagram.first_ts) < REASSEMBLY_TIMEOUT);
+        self.stats.dropped_datagrams += (before - self.pending.len()) as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use crate::ipv6::MAX_PENDING_DATAGRAMS;
+    use super::{Ipv4Defragmenter, MORE_FRAGMENTS};
+
+    /// A fragment of a TCP datagram from 10.0.0.1 to 10.0.0.2, with the given identification, offset in bytes and data
+    fn fragment(id: u16, offset: usize, more: bool, data: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x45, 0];
+        packet.extend_from_slice(&(20 + data.len() as u16).to_be_bytes());
+        packet.extend_from_slice(&id.to_be_bytes());
+        let flags = if more { MORE_FRAGMENTS } else { 0 };
+        packet.extend_from_slice(&(flags | (offset / 8) as u16).to_be_bytes());
+        packet.extend_from_slice(&[64, 6, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2]);
+        packet.extend_from_slice(data);
+        packet
+    }
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn fragments_out_of_order() {
+        let mut defragmenter = Ipv4Defragmenter::default();
+        let data: Vec<u8> = (0..40).collect();
+        assert!(defragmenter.process(secs(1), &fragment(7, 32, false, &data[32..])).is_none());
+        assert!(defragmenter.process(secs(1), &fragment(7, 0, true, &data[..16])).is_none());
+        let datagram = defragmenter.process(secs(2), &fragment(7, 16, true, &data[16..32])).unwrap();
+        assert_eq!((datagram.src.to_string(), datagram.dst.to_string()), ("10.0.0.1".to_string(), "10.0.0.2".to_string()));
+        assert_eq!((datagram.protocol, datagram.payload), (6, data));
+        let stats = defragmenter.stats();
+        assert_eq!((stats.fragments, stats.reassembled, stats.dropped_datagrams), (3, 1, 0));
+    }
+
+    #[test]
+    fn overlapping_fragments_drop_the_datagram() {
+        let mut defragmenter = Ipv4Defragmenter::default();
+        assert!(defragmenter.process(secs(1), &fragment(7, 0, true, &[1; 16])).is_none());
+        assert!(defragmenter.process(secs(1), &fragment(7, 8, false, &[2; 16])).is_none());
+        assert_eq!(defragmenter.stats().dropped_datagrams, 1);
+        // The fragments that come after start a new datagram, that the first fragment again completes
+        assert!(defragmenter.process(secs(1), &fragment(7, 16, false, &[3; 4])).is_none());
+        let datagram = defragmenter.process(secs(1), &fragment(7, 0, true, &[1; 16])).unwrap();
+        assert_eq!(datagram.payload.len(), 20);
+        // A fragment that is not the last one, but does not carry a multiple of 8 bytes
+        assert!(defragmenter.process(secs(1), &fragment(8, 0, true, &[1; 12])).is_none());
+        assert_eq!(defragmenter.stats().dropped_datagrams, 2);
+    }
+
+    #[test]
+    fn datagrams_expire() {
+        let mut defragmenter = Ipv4Defragmenter::default();
+        assert!(defragmenter.process(secs(10), &fragment(1, 0, true, &[1; 8])).is_none());
+        assert!(defragmenter.process(secs(39), &fragment(2, 0, true, &[2; 8])).is_none());
+        assert_eq!(defragmenter.stats().dropped_datagrams, 0);
+        // Another datagram 30 seconds after the first fragment expires it
+        assert!(defragmenter.process(secs(40), &fragment(3, 0, true, &[3; 8])).is_none());
+        assert_eq!(defragmenter.stats().dropped_datagrams, 1);
+        assert!(defragmenter.process(secs(40), &fragment(1, 8, false, &[1; 8])).is_none());
+        assert!(defragmenter.process(secs(40), &fragment(2, 8, false, &[2; 8])).is_some());
+    }
+
+    #[test]
+    fn datagram_limits() {
+        let mut defragmenter = Ipv4Defragmenter::default();
+        // Beyond the longest IPv4 datagram
+        assert!(defragmenter.process(secs(1), &fragment(1, 65528, false, &[0; 16])).is_none());
+        assert_eq!(defragmenter.stats().dropped_datagrams, 1);
+        for id in 0..MAX_PENDING_DATAGRAMS as u16 {
+            assert!(defragmenter.process(secs(1), &fragment(id, 0, true, &[0; 8])).is_none());
+        }
+        assert_eq!(defragmenter.stats().dropped_datagrams, 1);
+        assert!(defragmenter.process(secs(1), &fragment(u16::MAX, 0, true, &[0; 8])).is_none());
+        assert_eq!(defragmenter.stats().dropped_datagrams, 2);
+        // Fragments of the pending datagrams are still kept
+        assert!(defragmenter.process(secs(1), &fragment(5, 8, false, &[0; 8])).is_some());
+    }
+
+    #[test]
+    fn malformed_fragments() {
+        let mut defragmenter = Ipv4Defragmenter::default();
+        let mut packet = fragment(1, 0, true, &[0; 8]);
+        packet[3] = 10;
+        assert!(defragmenter.process(secs(1), &packet).is_none());
+        assert!(defragmenter.process(secs(1), &packet[..12]).is_none());
+        let stats = defragmenter.stats();
+        assert_eq!((stats.fragments, stats.malformed, stats.dropped_datagrams), (2, 2, 0));
+    }
+}
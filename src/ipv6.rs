@@ -0,0 +1,519 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::Ipv6Addr;
+use std::time::Duration;
+use etherparse::{InternetSlice, ip_number, SlicedPacket, TcpHeaderSlice, TransportSlice};
+
+/// Ether types of IPv6 and of VLAN tags
+pub(crate) const ETHER_TYPE_IPV6: u16 = 0x86dd;
+pub(crate) const ETHER_TYPES_VLAN: [u16; 3] = [0x8100, 0x88a8, 0x9100];
+/// UDP port of Teredo (RFC 4380)
+const TEREDO_PORT: u16 = 3544;
+/// IP protocol of IPv6 encapsulated in IPv4, as in 6in4 and 6to4 (RFC 4213)
+const IP_PROTOCOL_IPV6: u8 = 41;
+/// Length of the fixed IPv6 header
+pub(crate) const IPV6_HEADER_LEN: usize = 40;
+// Next header values of the extension headers, as in RFC 8200
+const HOP_BY_HOP: u8 = 0;
+const ROUTING: u8 = 43;
+const FRAGMENT: u8 = 44;
+const AUTHENTICATION: u8 = 51;
+const DESTINATION_OPTIONS: u8 = 60;
+const FRAGMENT_HEADER_LEN: usize = 8;
+/// Time to wait for the rest of the fragments of a datagram, from its first fragment (RFC 8200)
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(60);
+/// Max number of datagrams being reassembled at once, so a flood of partial datagrams cannot grow the table without limit
+pub(crate) const MAX_PENDING_DATAGRAMS: usize = 1024;
+/// Max length of a reassembled payload
+const MAX_DATAGRAM_LEN: usize = 65535;
+
+/// Counters of the extension headers that were walked, and of the fragment reassembly
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Ipv6Stats {
+    pub packets: u64,
+    pub hop_by_hop: u64,
+    pub routing: u64,
+    pub fragment: u64,
+    pub destination_options: u64,
+    pub authentication: u64,
+    /// Datagrams that were reassembled from all their fragments
+    pub reassembled: u64,
+    /// Datagrams whose fragments overlapped, expired or exceeded the limits, and were dropped
+    pub dropped_datagrams: u64,
+    /// Packets whose extension headers could not be walked
+    pub malformed: u64,
+    /// Packets that came through a Teredo tunnel
+    pub teredo: u64,
+    /// Packets that came through a 6in4 tunnel
+    pub six_in_four: u64,
+    /// Complete TCP headers found after the extension headers, including in reassembled datagrams
+    pub tcp_segments: u64,
+}
+
+impl Ipv6Stats {
+    /// Add the counters of another walker, such as of another shard of the connection table
+    pub fn add(&mut self, other: &Ipv6Stats) {
+        self.packets += other.packets;
+        self.hop_by_hop += other.hop_by_hop;
+        self.routing += other.routing;
+        self.fragment += other.fragment;
+        self.destination_options += other.destination_options;
+        self.authentication += other.authentication;
+        self.reassembled += other.reassembled;
+        self.dropped_datagrams += other.dropped_datagrams;
+        self.malformed += other.malformed;
+        self.teredo += other.teredo;
+        self.six_in_four += other.six_in_four;
+        self.tcp_segments += other.tcp_segments;
+    }
+}
+
+impl fmt::Display for Ipv6Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} packets ({} Teredo, {} 6in4), {} TCP segments, extension headers: {} hop-by-hop, {} routing, \
+            {} fragment, {} destination options, {} authentication, {} datagrams reassembled, {} dropped, {} malformed",
+               self.packets, self.teredo, self.six_in_four, self.tcp_segments, self.hop_by_hop, self.routing, self.fragment, self.destination_options,
+               self.authentication, self.reassembled, self.dropped_datagrams, self.malformed)
+    }
+}
+
+/// Offset of the IPv6 header in an Ethernet frame, possibly VLAN tagged, if it carries IPv6.
+/// The packet parser gives up on fragments whose payload looks like extension headers, so IPv6 is found before it.
+pub fn ipv6_offset(frame: &[u8]) -> Option<usize> {
+    let mut offset = 12;
+    loop {
+        let ether_type = u16::from_be_bytes([*frame.get(offset)?, *frame.get(offset + 1)?]);
+        match ether_type {
+            ETHER_TYPE_IPV6 => { return Some(offset + 2); }
+            _ if ETHER_TYPES_VLAN.contains(&ether_type) && offset < 20 => { offset += 4; }
+            _ => { return None; }
+        }
+    }
+}
+
+/// VLAN identifier of an Ethernet frame, or the outer and inner identifiers of a double tag (outer in the high bits),
+/// as the packet parser gives for the frames it parses
+pub fn raw_vlan_id(frame: &[u8]) -> Option<u32> {
+    let mut vlan: Option<u32> = None;
+    let mut offset = 12;
+    while offset < 20 {
+        let ether_type = u16::from_be_bytes([*frame.get(offset)?, *frame.get(offset + 1)?]);
+        if !ETHER_TYPES_VLAN.contains(&ether_type) {
+            break;
+        }
+        let id = u16::from_be_bytes([*frame.get(offset + 2)?, *frame.get(offset + 3)?]) as u32 & 0xfff;
+        vlan = Some(vlan.map_or(id, |outer| outer << 12 | id));
+        offset += 4;
+    }
+    vlan
+}
+
+/// Transition mechanism that carries IPv6 over IPv4
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Ipv6Tunnel {
+    /// IPv6 in UDP, through NATs
+    Teredo,
+    /// IPv6 right in IPv4, as IP protocol 41
+    SixInFour,
+}
+
+/// The IPv6 packet that an IPv4 packet carries through a Teredo or a 6in4 tunnel, if any
+pub fn tunneled_ipv6<'a>(packet: &SlicedPacket<'a>) -> Option<(Ipv6Tunnel, &'a [u8])> {
+    let payload = packet.payload;
+    match (&packet.ip, &packet.transport) {
+        (Some(InternetSlice::Ipv4(ip_header, _)), _) if ip_header.protocol() == IP_PROTOCOL_IPV6 => {
+            Some((Ipv6Tunnel::SixInFour, payload))
+        }
+        (Some(InternetSlice::Ipv4(..)), Some(TransportSlice::Udp(udp)))
+        if udp.source_port() == TEREDO_PORT || udp.destination_port() == TEREDO_PORT => {
+            // Skip the authentication and origin indication headers, that may come before the IPv6 header
+            let mut offset = 0;
+            if payload.get(..2) == Some(&[0, 1]) {
+                let (id_len, auth_len) = (*payload.get(2)? as usize, *payload.get(3)? as usize);
+                // The identifier and the authentication value are followed by a nonce and a confirmation byte
+                offset += 4 + id_len + auth_len + 9;
+            }
+            if payload.get(offset..offset + 2) == Some(&[0, 0]) {
+                offset += 8;
+            }
+            let inner = payload.get(offset..)?;
+            if inner.first().is_some_and(|b| b >> 4 == 6) { Some((Ipv6Tunnel::Teredo, inner)) } else { None }
+        }
+        _ => { None }
+    }
+}
+
+/// The upper-layer protocol of a packet or of a reassembled datagram, with its payload and addresses
+pub struct UpperLayer {
+    pub src: Ipv6Addr,
+    pub dst: Ipv6Addr,
+    pub protocol: u8,
+    pub payload: Vec<u8>,
+}
+
+/// Where the extension headers of a packet end
+enum Walked {
+    /// At the upper-layer header of the given protocol, at this offset
+    UpperLayer { protocol: u8, offset: usize },
+    /// At the fragmentable part of a fragment, at this offset
+    Fragment { next_header: u8, offset: usize, fragment_offset: usize, more: bool, id: u32 },
+}
+
+/// Fragments of a datagram that were received so far, of IPv6 or IPv4
+pub(crate) struct PendingDatagram {
+    /// Capture time of the first fragment that was received
+    pub(crate) first_ts: Duration,
+    /// Next header of the fragmentable part, as in the fragment header of the first fragment
+    next_header: Option<u8>,
+    /// Length of the fragmentable part, once the last fragment was received
+    total_len: Option<usize>,
+    /// Fragments by their offset in the fragmentable part, that do not overlap
+    fragments: Vec<(usize, Vec<u8>)>,
+}
+
+impl PendingDatagram {
+    pub(crate) fn new(first_ts: Duration) -> Self {
+        PendingDatagram { first_ts, next_header: None, total_len: None, fragments: Vec::new() }
+    }
+
+    /// Keep a fragment at the given offset of the fragmentable part, given whether more fragments follow it.
+    /// Return false if it overlaps another fragment (RFC 5722), is misaligned or goes beyond the end of the datagram,
+    /// so the datagram should be dropped.
+    pub(crate) fn add(&mut self, offset: usize, more: bool, data: &[u8]) -> bool {
+        let end = offset + data.len();
+        let overlaps = self.fragments.iter().any(|(start, bytes)| offset < start + bytes.len() && *start < end);
+        // All fragments but the last one carry a multiple of 8 bytes
+        let misaligned = more && !data.len().is_multiple_of(8);
+        let beyond_end = self.total_len.is_some_and(|total_len| end > total_len) ||
+            (!more && self.fragments.iter().any(|(start, bytes)| start + bytes.len() > end));
+        if overlaps || misaligned || beyond_end || end > MAX_DATAGRAM_LEN {
+            return false;
+        }
+        if !more {
+            self.total_len = Some(end);
+        }
+        self.fragments.push((offset, data.to_vec()));
+        true
+    }
+
+    /// The fragmentable part, if all of it was received
+    pub(crate) fn reassemble(&mut self) -> Option<Vec<u8>> {
+        let total_len = self.total_len?;
+        self.fragments.sort_by_key(|(offset, _)| *offset);
+        let mut payload = Vec::with_capacity(total_len);
+        for (offset, data) in &self.fragments {
+            if *offset != payload.len() {
+                return None;
+            }
+            payload.extend_from_slice(data);
+        }
+        if payload.len() == total_len { Some(payload) } else { None }
+    }
+}
+
+/// Walks the extension headers of IPv6 packets to find their upper-layer header, and reassembles fragmented datagrams
+#[derive(Default)]
+pub struct Ipv6Walker {
+    stats: Ipv6Stats,
+    /// Datagrams being reassembled, by source, destination and identification
+    pending: HashMap<([u8; 16], [u8; 16], u32), PendingDatagram>,
+}
+
+impl Clone for Ipv6Walker {
+    /// The counters only, since partial datagrams are not worth keeping in a copy of the table
+    fn clone(&self) -> Self {
+        Ipv6Walker { stats: self.stats, pending: HashMap::new() }
+    }
+}
+
+impl Ipv6Walker {
+    /// Walk an IPv6 packet, from its fixed header, captured at the given time. Return its upper-layer protocol and payload,
+    /// or those of the datagram it completed, if it was the last missing fragment.
+    pub fn process(&mut self, ts: Duration, packet: &[u8]) -> Option<UpperLayer> {
+        self.stats.packets += 1;
+        if packet.len() < IPV6_HEADER_LEN || packet[0] >> 4 != 6 {
+            self.stats.malformed += 1;
+            return None;
+        }
+        let payload_len = u16::from_be_bytes([packet[4], packet[5]]) as usize;
+        let packet = &packet[..(IPV6_HEADER_LEN + payload_len).min(packet.len())];
+        let walked = match self.walk(packet[6], packet, IPV6_HEADER_LEN, true) {
+            None => {
+                self.stats.malformed += 1;
+                return None;
+            }
+            Some(walked) => { walked }
+        };
+        let mut src = [0u8; 16];
+        let mut dst = [0u8; 16];
+        src.copy_from_slice(&packet[8..24]);
+        dst.copy_from_slice(&packet[24..40]);
+        let (src_ip, dst_ip) = (Ipv6Addr::from(src), Ipv6Addr::from(dst));
+        let upper_layer = match walked {
+            Walked::UpperLayer { protocol, offset } => {
+                UpperLayer { src: src_ip, dst: dst_ip, protocol, payload: packet[offset..].to_vec() }
+            }
+            Walked::Fragment { next_header, offset, fragment_offset, more, id } => {
+                let (next_header, payload) = self.add_fragment(ts, (src, dst, id), next_header, fragment_offset, more,
+                                                                &packet[offset..])?;
+                // The fragmentable part may start with more extension headers
+                match self.walk(next_header, &payload, 0, false) {
+                    Some(Walked::UpperLayer { protocol, offset }) => {
+                        UpperLayer { src: src_ip, dst: dst_ip, protocol, payload: payload[offset..].to_vec() }
+                    }
+                    _ => {
+                        self.stats.malformed += 1;
+                        return None;
+                    }
+                }
+            }
+        };
+        if upper_layer.protocol == ip_number::TCP && TcpHeaderSlice::from_slice(&upper_layer.payload).is_ok() {
+            self.stats.tcp_segments += 1;
+        }
+        Some(upper_layer)
+    }
+
+    /// Walk an IPv6 packet that came through a tunnel, as [Ipv6Walker::process], and count it by the tunnel
+    pub fn process_tunneled(&mut self, ts: Duration, tunnel: Ipv6Tunnel, packet: &[u8]) -> Option<UpperLayer> {
+        match tunnel {
+            Ipv6Tunnel::Teredo => { self.stats.teredo += 1 }
+            Ipv6Tunnel::SixInFour => { self.stats.six_in_four += 1 }
+        }
+        self.process(ts, packet)
+    }
+
+    /// Counters of the walked packets
+    pub fn stats(&self) -> Ipv6Stats {
+        self.stats
+    }
+
+    /// Add the counters of another walker, leaving its pending fragments
+    pub fn merge_stats(&mut self, other: &Ipv6Walker) {
+        self.stats.add(&other.stats);
+    }
+
+    /// Walk the extension headers from the given offset, counting them, until the upper-layer header or a fragment.
+    /// A hop-by-hop header is only valid right after the fixed header. Return None if a header is cut or misplaced.
+    fn walk(&mut self, mut next_header: u8, packet: &[u8], mut offset: usize, at_start: bool) -> Option<Walked> {
+        let mut first = at_start;
+        loop {
+            let header = packet.get(offset..)?;
+            let header_len = match next_header {
+                HOP_BY_HOP if first => {
+                    self.stats.hop_by_hop += 1;
+                    (*header.get(1)? as usize + 1) * 8
+                }
+                HOP_BY_HOP => { return None; }
+                ROUTING => {
+                    self.stats.routing += 1;
+                    (*header.get(1)? as usize + 1) * 8
+                }
+                DESTINATION_OPTIONS => {
+                    self.stats.destination_options += 1;
+                    (*header.get(1)? as usize + 1) * 8
+                }
+                AUTHENTICATION => {
+                    self.stats.authentication += 1;
+                    (*header.get(1)? as usize + 2) * 4
+                }
+                FRAGMENT => {
+                    self.stats.fragment += 1;
+                    let fragment = header.get(..FRAGMENT_HEADER_LEN)?;
+                    let offset_and_flags = u16::from_be_bytes([fragment[2], fragment[3]]);
+                    let fragment_offset = (offset_and_flags >> 3) as usize * 8;
+                    let more = offset_and_flags & 1 != 0;
+                    // An atomic fragment (RFC 6946) is a whole datagram
+                    if fragment_offset != 0 || more {
+                        let id = u32::from_be_bytes([fragment[4], fragment[5], fragment[6], fragment[7]]);
+                        return Some(Walked::Fragment { next_header: fragment[0], offset: offset + FRAGMENT_HEADER_LEN,
+                            fragment_offset, more, id });
+                    }
+                    FRAGMENT_HEADER_LEN
+                }
+                protocol => { return Some(Walked::UpperLayer { protocol, offset }); }
+            };
+            if header.len() < header_len {
+                return None;
+            }
+            next_header = header[0];
+            offset += header_len;
+            first = false;
+        }
+    }
+
+    /// Keep a fragment of a datagram, and return the next header and the fragmentable part of the datagram if it is complete.
+    /// Datagrams with overlapping fragments (RFC 5722) are dropped, and so are those that are too long or expired.
+    fn add_fragment(&mut self, ts: Duration, key: ([u8; 16], [u8; 16], u32), next_header: u8, offset: usize, more: bool,
+                    data: &[u8]) -> Option<(u8, Vec<u8>)> {
+        if !self.pending.contains_key(&key) {
+            self.expire(ts);
+            if self.pending.len() >= MAX_PENDING_DATAGRAMS {
+                self.stats.dropped_datagrams += 1;
+                return None;
+            }
+        }
+        let datagram = self.pending.entry(key).or_insert_with(|| PendingDatagram::new(ts));
+        if !datagram.add(offset, more, data) {
+            self.pending.remove(&key);
+            self.stats.dropped_datagrams += 1;
+            return None;
+        }
+        if offset == 0 {
+            datagram.next_header = Some(next_header);
+        }
+        let payload = datagram.reassemble()?;
+        let next_header = datagram.next_header?;
+        self.pending.remove(&key);
+        self.stats.reassembled += 1;
+        Some((next_header, payload))
+    }
+
+    /// Drop the datagrams that did not complete in time
+    fn expire(&mut self, ts: Duration) {
+        let before = self.pending.len();
+        self.pending.retain(|_, datagram| ts.saturating_sub(datagram.first_ts) < REASSEMBLY_TIMEOUT);
+        self.stats.dropped_datagrams += (before - self.pending.len()) as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::{ipv6_offset, raw_vlan_id, Ipv6Walker, AUTHENTICATION, DESTINATION_OPTIONS, FRAGMENT, HOP_BY_HOP, ROUTING};
+
+    const TCP: u8 = 6;
+
+    /// A packet from 2001:db8::1 to 2001:db8::2, with the extension headers and the payload right after the fixed header
+    fn packet(next_header: u8, extensions: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x60, 0, 0, 0];
+        packet.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        packet.extend_from_slice(&[next_header, 64]);
+        for last in [1, 2] {
+            packet.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8]);
+            packet.extend_from_slice(&[0; 11]);
+            packet.push(last);
+        }
+        packet.extend_from_slice(extensions);
+        packet
+    }
+
+    /// An extension header of 8 bytes, whose length counts 8 byte units beyond the first
+    fn extension(next_header: u8) -> Vec<u8> {
+        vec![next_header, 0, 1, 4, 0, 0, 0, 0]
+    }
+
+    fn fragment_header(next_header: u8, offset: usize, more: bool, id: u32) -> Vec<u8> {
+        let mut header = vec![next_header, 0];
+        header.extend_from_slice(&((offset as u16) | more as u16).to_be_bytes());
+        header.extend_from_slice(&id.to_be_bytes());
+        header
+    }
+
+    /// A TCP header without options, from port 1000 to port 80
+    fn tcp_header() -> Vec<u8> {
+        let mut header = vec![0x03, 0xe8, 0, 80, 0, 0, 0, 1, 0, 0, 0, 0, 0x50, 0x02, 0xff, 0xff];
+        header.extend_from_slice(&[0; 4]);
+        header
+    }
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn extension_headers_are_walked() {
+        let mut walker = Ipv6Walker::default();
+        let mut extensions = extension(ROUTING);
+        extensions.extend(extension(DESTINATION_OPTIONS));
+        extensions.extend(extension(AUTHENTICATION));
+        // The authentication header counts 4 byte units beyond the first two
+        extensions.extend([FRAGMENT, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        extensions.extend(fragment_header(TCP, 0, false, 9));
+        extensions.extend(tcp_header());
+        extensions.extend(b"data");
+        let upper_layer = walker.process(secs(1), &packet(HOP_BY_HOP, &extensions)).unwrap();
+        assert_eq!((upper_layer.src.to_string(), upper_layer.dst.to_string()),
+                   ("2001:db8::1".to_string(), "2001:db8::2".to_string()));
+        assert_eq!(upper_layer.protocol, TCP);
+        assert_eq!(upper_layer.payload.len(), 24);
+        let stats = walker.stats();
+        assert_eq!((stats.hop_by_hop, stats.routing, stats.destination_options, stats.authentication, stats.fragment),
+                   (1, 1, 1, 1, 1));
+        assert_eq!((stats.packets, stats.tcp_segments, stats.malformed, stats.reassembled), (1, 1, 0, 0));
+    }
+
+    #[test]
+    fn misplaced_or_cut_extension_headers_are_malformed() {
+        let mut walker = Ipv6Walker::default();
+        let mut extensions = extension(HOP_BY_HOP);
+        extensions.extend(extension(TCP));
+        assert!(walker.process(secs(1), &packet(ROUTING, &extensions)).is_none());
+        assert!(walker.process(secs(1), &packet(ROUTING, &extension(TCP)[..6])).is_none());
+        let mut short = packet(TCP, &tcp_header());
+        short.truncate(30);
+        assert!(walker.process(secs(1), &short).is_none());
+        let mut not_ipv6 = packet(TCP, &tcp_header());
+        not_ipv6[0] = 0x45;
+        assert!(walker.process(secs(1), &not_ipv6).is_none());
+        assert_eq!(walker.stats().malformed, 4);
+        // The payload length cuts the bytes that follow the packet, such as the Ethernet padding
+        let mut padded = packet(TCP, b"ab");
+        padded.extend_from_slice(&[0; 6]);
+        assert_eq!(walker.process(secs(1), &padded).unwrap().payload, b"ab");
+    }
+
+    #[test]
+    fn fragments_are_reassembled() {
+        let mut walker = Ipv6Walker::default();
+        // The fragmentable part starts with a destination options header
+        let mut datagram = extension(TCP);
+        datagram.extend(tcp_header());
+        datagram.extend((0..20).collect::<Vec<u8>>());
+        let mut last = fragment_header(TCP, 32, false, 5);
+        last.extend_from_slice(&datagram[32..]);
+        assert!(walker.process(secs(1), &packet(FRAGMENT, &last)).is_none());
+        let mut first = fragment_header(DESTINATION_OPTIONS, 0, true, 5);
+        first.extend_from_slice(&datagram[..16]);
+        assert!(walker.process(secs(1), &packet(FRAGMENT, &first)).is_none());
+        let mut middle = fragment_header(TCP, 16, true, 5);
+        middle.extend_from_slice(&datagram[16..32]);
+        let upper_layer = walker.process(secs(2), &packet(FRAGMENT, &middle)).unwrap();
+        assert_eq!((upper_layer.protocol, upper_layer.payload), (TCP, datagram[8..].to_vec()));
+        let stats = walker.stats();
+        assert_eq!((stats.fragment, stats.destination_options, stats.reassembled, stats.tcp_segments), (3, 1, 1, 1));
+    }
+
+    #[test]
+    fn overlapping_and_expired_fragments_are_dropped() {
+        let mut walker = Ipv6Walker::default();
+        let mut first = fragment_header(TCP, 0, true, 1);
+        first.extend_from_slice(&[0; 16]);
+        let mut overlapping = fragment_header(TCP, 8, false, 1);
+        overlapping.extend_from_slice(&[0; 16]);
+        assert!(walker.process(secs(1), &packet(FRAGMENT, &first)).is_none());
+        assert!(walker.process(secs(1), &packet(FRAGMENT, &overlapping)).is_none());
+        assert_eq!(walker.stats().dropped_datagrams, 1);
+        // A fragment 60 seconds after the first fragment of another datagram expires it
+        assert!(walker.process(secs(10), &packet(FRAGMENT, &first)).is_none());
+        let mut other = fragment_header(TCP, 0, true, 2);
+        other.extend_from_slice(&[0; 8]);
+        assert!(walker.process(secs(70), &packet(FRAGMENT, &other)).is_none());
+        assert_eq!(walker.stats().dropped_datagrams, 2);
+        let mut rest = fragment_header(TCP, 16, false, 1);
+        rest.extend_from_slice(&[0; 4]);
+        assert!(walker.process(secs(70), &packet(FRAGMENT, &rest)).is_none());
+        assert_eq!(walker.stats().reassembled, 0);
+    }
+
+    #[test]
+    fn ipv6_in_vlan_tagged_frames() {
+        let mut frame = vec![0; 12];
+        frame.extend_from_slice(&[0x88, 0xa8, 0x00, 0x0a, 0x81, 0x00, 0x00, 0x14, 0x86, 0xdd]);
+        assert_eq!(ipv6_offset(&frame), Some(22));
+        assert_eq!(raw_vlan_id(&frame), Some(10 << 12 | 20));
+        frame[20] = 0x08;
+        frame[21] = 0x00;
+        assert_eq!(ipv6_offset(&frame), None);
+    }
+}
@@ -0,0 +1,458 @@
+use std::fmt;
+use std::fmt::Write as FmtWrite;
+use std::io::{Error, ErrorKind};
+
+/// Minimal JSON document model, used for debug dumps and exports.
+/// Numbers keep their literal text, so 64-bit counters survive a round trip without precision loss.
+/// Object fields keep their insertion order.
+#[derive(Clone, Debug, PartialEq)]
+pub enum JsonValue {
+    Null,
+    Bool(bool),
+    Number(String),
+    String(String),
+    Array(Vec<JsonValue>),
+    Object(Vec<(String, JsonValue)>),
+}
+
+impl JsonValue {
+    /// Build an object from a list of key-value pairs
+    pub fn object(fields: Vec<(&str, JsonValue)>) -> JsonValue {
+        JsonValue::Object(fields.into_iter().map(|(k, v)| (k.to_string(), v)).collect())
+    }
+
+    /// Get a field of an object, or None if this is not an object or the field is missing
+    pub fn get(&self, key: &str) -> Option<&JsonValue> {
+        match self {
+            JsonValue::Object(fields) => { fields.iter().find(|(k, _)| k == key).map(|(_, v)| v) }
+            _ => { None }
+        }
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            JsonValue::Number(text) => { text.parse().ok() }
+            _ => { None }
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            JsonValue::String(text) => { Some(text) }
+            _ => { None }
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&Vec<JsonValue>> {
+        match self {
+            JsonValue::Array(items) => { Some(items) }
+            _ => { None }
+        }
+    }
+
+    /// Get a mandatory numeric field of an object, or an error naming the field
+    pub(crate) fn field_u64(&self, key: &str) -> Result<u64, Error> {
+        self.get(key).and_then(|v| v.as_u64()).ok_or_else(|| missing_field(key))
+    }
+
+    /// Get a mandatory string field of an object, or an error naming the field
+    pub(crate) fn field_str(&self, key: &str) -> Result<&str, Error> {
+        self.get(key).and_then(|v| v.as_str()).ok_or_else(|| missing_field(key))
+    }
+
+    /// Get a mandatory array field of an object, or an error naming the field
+    pub(crate) fn field_array(&self, key: &str) -> Result<&Vec<JsonValue>, Error> {
+        self.get(key).and_then(|v| v.as_array()).ok_or_else(|| missing_field(key))
+    }
+
+    /// Get a mandatory field of any type, or an error naming the field
+    pub(crate) fn field(&self, key: &str) -> Result<&JsonValue, Error> {
+        self.get(key).ok_or_else(|| missing_field(key))
+    }
+
+    /// Serialize with newlines and two-space indentation, for files meant to be read by humans
+    pub fn to_pretty_string(&self) -> String {
+        let mut out = String::new();
+        self.write_pretty(&mut out, 0);
+        out
+    }
+
+    fn write_pretty(&self, out: &mut String, indent: usize) {
+        match self {
+            // Arrays of scalars, such as ranges, stay on one line
+            JsonValue::Array(items) if items.iter().any(|i| matches!(i, JsonValue::Array(_) | JsonValue::Object(_))) => {
+                out.push_str("[\n");
+                for (i, item) in items.iter().enumerate() {
+                    out.push_str(&"  ".repeat(indent + 1));
+                    item.write_pretty(out, indent + 1);
+                    out.push_str(if i + 1 < items.len() { ",\n" } else { "\n" });
+                }
+                out.push_str(&"  ".repeat(indent));
+                out.push(']');
+            }
+            JsonValue::Object(fields) if !fields.is_empty() => {
+                out.push_str("{\n");
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    out.push_str(&"  ".repeat(indent + 1));
+                    write_escaped(out, key);
+                    out.push_str(": ");
+                    value.write_pretty(out, indent + 1);
+                    out.push_str(if i + 1 < fields.len() { ",\n" } else { "\n" });
+                }
+                out.push_str(&"  ".repeat(indent));
+                out.push('}');
+            }
+            _ => { let _ = write!(out, "{}", self); }
+        }
+    }
+
+    /// Parse a complete JSON document
+    pub fn parse(text: &str) -> Result<JsonValue, Error> {
+        let mut parser = JsonParser { bytes: text.as_bytes(), pos: 0 };
+        let value = parser.parse_value()?;
+        parser.skip_whitespace();
+        if parser.pos < parser.bytes.len() {
+            return Err(parser.error("Unexpected trailing characters"));
+        }
+        Ok(value)
+    }
+}
+
+/// Compact serialization, with no whitespace
+impl fmt::Display for JsonValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JsonValue::Null => { f.write_str("null") }
+            JsonValue::Bool(value) => { write!(f, "{}", value) }
+            JsonValue::Number(text) => { f.write_str(text) }
+            JsonValue::String(text) => {
+                let mut out = String::new();
+                write_escaped(&mut out, text);
+                f.write_str(&out)
+            }
+            JsonValue::Array(items) => {
+                f.write_str("[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 { f.write_str(",")?; }
+                    write!(f, "{}", item)?;
+                }
+                f.write_str("]")
+            }
+            JsonValue::Object(fields) => {
+                f.write_str("{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 { f.write_str(",")?; }
+                    let mut out = String::new();
+                    write_escaped(&mut out, key);
+                    write!(f, "{}:{}", out, value)?;
+                }
+                f.write_str("}")
+            }
+        }
+    }
+}
+
+impl From<bool> for JsonValue {
+    fn from(value: bool) -> Self { JsonValue::Bool(value) }
+}
+
+impl From<u8> for JsonValue {
+    fn from(value: u8) -> Self { JsonValue::Number(value.to_string()) }
+}
+
+impl From<u16> for JsonValue {
+    fn from(value: u16) -> Self { JsonValue::Number(value.to_string()) }
+}
+
+impl From<u32> for JsonValue {
+    fn from(value: u32) -> Self { JsonValue::Number(value.to_string()) }
+}
+
+impl From<u64> for JsonValue {
+    fn from(value: u64) -> Self { JsonValue::Number(value.to_string()) }
+}
+
+impl From<usize> for JsonValue {
+    fn from(value: usize) -> Self { JsonValue::Number(value.to_string()) }
+}
+
+impl From<i64> for JsonValue {
+    fn from(value: i64) -> Self { JsonValue::Number(value.to_string()) }
+}
+
+/// Non-finite values have no JSON representation, so they become null
+impl From<f64> for JsonValue {
+    fn from(value: f64) -> Self {
+        if value.is_finite() { JsonValue::Number(value.to_string()) } else { JsonValue::Null }
+    }
+}
+
+impl From<&str> for JsonValue {
+    fn from(value: &str) -> Self { JsonValue::String(value.to_string()) }
+}
+
+impl From<String> for JsonValue {
+    fn from(value: String) -> Self { JsonValue::String(value) }
+}
+
+impl From<Vec<JsonValue>> for JsonValue {
+    fn from(value: Vec<JsonValue>) -> Self { JsonValue::Array(value) }
+}
+
+impl<T: Into<JsonValue>> From<Option<T>> for JsonValue {
+    fn from(value: Option<T>) -> Self {
+        match value {
+            Some(v) => { v.into() }
+            None => { JsonValue::Null }
+        }
+    }
+}
+
+fn missing_field(key: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("Missing or invalid JSON field '{}'", key))
+}
+
+/// Write a quoted string, escaping quotes, backslashes and control characters
+fn write_escaped(out: &mut String, text: &str) {
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => { out.push_str("\\\"") }
+            '\\' => { out.push_str("\\\\") }
+            '\n' => { out.push_str("\\n") }
+            '\r' => { out.push_str("\\r") }
+            '\t' => { out.push_str("\\t") }
+            c if (c as u32) < 0x20 => { let _ = write!(out, "\\u{:04x}", c as u32); }
+            c => { out.push(c) }
+        }
+    }
+    out.push('"');
+}
+
+/// Recursive descent parser over the document bytes
+struct JsonParser<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> JsonParser<'a> {
+    fn error(&self, message: &str) -> Error {
+        Error::new(ErrorKind::InvalidData, format!("JSON parsing error at offset {}: {}", self.pos, message))
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.pos < self.bytes.len() && matches!(self.bytes[self.pos], b' ' | b'\t' | b'\n' | b'\r') {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_whitespace();
+        self.bytes.get(self.pos).copied()
+    }
+
+    fn expect(&mut self, literal: &str) -> Result<(), Error> {
+        if self.bytes[self.pos..].starts_with(literal.as_bytes()) {
+            self.pos += literal.len();
+            return Ok(());
+        }
+        Err(self.error(&format!("Expected '{}'", literal)))
+    }
+
+    fn parse_value(&mut self) -> Result<JsonValue, Error> {
+        match self.peek() {
+            None => { Err(self.error("Unexpected end of document")) }
+            Some(b'n') => { self.expect("null").map(|_| JsonValue::Null) }
+            Some(b't') => { self.expect("true").map(|_| JsonValue::Bool(true)) }
+            Some(b'f') => { self.expect("false").map(|_| JsonValue::Bool(false)) }
+            Some(b'"') => { self.parse_string().map(JsonValue::String) }
+            Some(b'[') => { self.parse_array() }
+            Some(b'{') => { self.parse_object() }
+            Some(b'-') | Some(b'0'..=b'9') => { self.parse_number() }
+            Some(_) => { Err(self.error("Unexpected character")) }
+        }
+    }
+
+    fn parse_number(&mut self) -> Result<JsonValue, Error> {
+        let start = self.pos;
+        while self.pos < self.bytes.len() && matches!(self.bytes[self.pos], b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9') {
+            self.pos += 1;
+        }
+        let text = std::str::from_utf8(&self.bytes[start..self.pos]).unwrap_or("");
+        if text.parse::<f64>().is_err() {
+            return Err(self.error("Invalid number"));
+        }
+        Ok(JsonValue::Number(text.to_string()))
+    }
+
+    fn parse_hex4(&mut self) -> Result<u32, Error> {
+        let hex = self.bytes.get(self.pos..self.pos + 4).and_then(|h| std::str::from_utf8(h).ok());
+        let value = hex.and_then(|h| u32::from_str_radix(h, 16).ok()).ok_or_else(|| self.error("Invalid unicode escape"))?;
+        self.pos += 4;
+        Ok(value)
+    }
+
+    fn parse_string(&mut self) -> Result<String, Error> {
+        self.expect("\"")?;
+        let mut result = String::new();
+        loop {
+            let start = self.pos;
+            while self.pos < self.bytes.len() && self.bytes[self.pos] != b'"' && self.bytes[self.pos] != b'\\' {
+                self.pos += 1;
+            }
+            result.push_str(std::str::from_utf8(&self.bytes[start..self.pos]).map_err(|_| self.error("Invalid UTF-8"))?);
+            match self.bytes.get(self.pos) {
+                None => { return Err(self.error("Unterminated string")); }
+                Some(b'"') => {
+                    self.pos += 1;
+                    return Ok(result);
+                }
+                _ => {}
+            }
+            // Escape sequence
+            self.pos += 1;
+            let escaped = self.bytes.get(self.pos).copied().ok_or_else(|| self.error("Unterminated escape"))?;
+            self.pos += 1;
+            match escaped {
+                b'"' => { result.push('"') }
+                b'\\' => { result.push('\\') }
+                b'/' => { result.push('/') }
+                b'b' => { result.push('\u{8}') }
+                b'f' => { result.push('\u{c}') }
+                b'n' => { result.push('\n') }
+                b'r' => { result.push('\r') }
+                b't' => { result.push('\t') }
+                b'u' => {
+                    let mut code = self.parse_hex4()?;
+                    // Surrogate pair for characters outside the basic plane
+                    if (0xD800..0xDC00).contains(&code) {
+                        self.expect("\\u")?;
+                        let low = self.parse_hex4()?;
+                        if !(0xDC00..0xE000).contains(&low) {
+                            return Err(self.error("Invalid surrogate pair"));
+                        }
+                        code = 0x10000 + ((code - 0xD800) << 10) + (low - 0xDC00);
+                    }
+                    result.push(char::from_u32(code).ok_or_else(|| self.error("Invalid unicode character"))?);
+                }
+                _ => { return Err(self.error("Invalid escape")); }
+            }
+        }
+    }
+
+    fn parse_array(&mut self) -> Result<JsonValue, Error> {
+        self.expect("[")?;
+        let mut items = Vec::new();
+        if self.peek() == Some(b']') {
+            self.pos += 1;
+            return Ok(JsonValue::Array(items));
+        }
+        loop {
+            items.push(self.parse_value()?);
+            match self.peek() {
+                Some(b',') => { self.pos += 1; }
+                Some(b']') => {
+                    self.pos += 1;
+                    return Ok(JsonValue::Array(items));
+                }
+                _ => { return Err(self.error("Expected ',' or ']'")); }
+            }
+        }
+    }
+
+    fn parse_object(&mut self) -> Result<JsonValue, Error> {
+        self.expect("{")?;
+        let mut fields = Vec::new();
+        if self.peek() == Some(b'}') {
+            self.pos += 1;
+            return Ok(JsonValue::Object(fields));
+        }
+        loop {
+            if self.peek() != Some(b'"') {
+                return Err(self.error("Expected a field name"));
+            }
+            let key = self.parse_string()?;
+            if self.peek() != Some(b':') {
+                return Err(self.error("Expected ':'"));
+            }
+            self.pos += 1;
+            fields.push((key, self.parse_value()?));
+            match self.peek() {
+                Some(b',') => { self.pos += 1; }
+                Some(b'}') => {
+                    self.pos += 1;
+                    return Ok(JsonValue::Object(fields));
+                }
+                _ => { return Err(self.error("Expected ',' or '}'")); }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::JsonValue;
+
+    #[test]
+    fn control_characters_are_escaped() {
+        let value = JsonValue::from("quote \" backslash \\ lines \n\r tab \t bell \u{7} nul \u{0} unit \u{1f} é");
+        let text = value.to_string();
+        assert_eq!(text, "\"quote \\\" backslash \\\\ lines \\n\\r tab \\t bell \\u0007 nul \\u0000 unit \\u001f é\"");
+        assert_eq!(JsonValue::parse(&text).unwrap(), value);
+    }
+
+    #[test]
+    fn escapes_are_parsed() {
+        let value = JsonValue::parse(r#""\/\b\f\u00e9\u20AC""#).unwrap();
+        assert_eq!(value.as_str(), Some("/\u{8}\u{c}é€"));
+    }
+
+    #[test]
+    fn surrogate_pairs() {
+        assert_eq!(JsonValue::parse(r#""\ud83d\ude00!""#).unwrap().as_str(), Some("\u{1F600}!"));
+        // Characters outside the basic plane are written as they are, and read back
+        assert_eq!(JsonValue::from("\u{1F600}").to_string(), "\"\u{1F600}\"");
+        assert!(JsonValue::parse(r#""\ud83d""#).is_err());
+        assert!(JsonValue::parse(r#""\ud83d\u0041""#).is_err());
+        assert!(JsonValue::parse(r#""\ude00""#).is_err());
+    }
+
+    #[test]
+    fn numbers_keep_their_text() {
+        let value = JsonValue::parse("[18446744073709551615, -12, 0.5, 1e-3, 2E+10]").unwrap();
+        let items = value.as_array().unwrap();
+        assert_eq!(items[0].as_u64(), Some(u64::MAX));
+        assert_eq!(items[1].as_u64(), None);
+        assert_eq!(value.to_string(), "[18446744073709551615,-12,0.5,1e-3,2E+10]");
+        assert_eq!(JsonValue::from(f64::NAN), JsonValue::Null);
+        assert!(JsonValue::parse("-").is_err());
+        assert!(JsonValue::parse("1.2.3").is_err());
+    }
+
+    #[test]
+    fn nested_values_round_trip() {
+        let value = JsonValue::object(vec![
+            ("name", "conn".into()),
+            ("empty", JsonValue::object(Vec::new())),
+            ("ranges", vec![JsonValue::from(1u32), 2u32.into()].into()),
+            ("flows", vec![JsonValue::object(vec![("ok", true.into()), ("peer", JsonValue::Null)])].into()),
+        ]);
+        let compact = value.to_string();
+        assert_eq!(compact, r#"{"name":"conn","empty":{},"ranges":[1,2],"flows":[{"ok":true,"peer":null}]}"#);
+        assert_eq!(JsonValue::parse(&compact).unwrap(), value);
+        let pretty = value.to_pretty_string();
+        assert_eq!(pretty, "{\n  \"name\": \"conn\",\n  \"empty\": {},\n  \"ranges\": [1,2],\n  \"flows\": [\n    {\n      \"ok\": true,\n      \"peer\": null\n    }\n  ]\n}");
+        assert_eq!(JsonValue::parse(&pretty).unwrap(), value);
+        assert_eq!(value.get("flows").and_then(|flows| flows.as_array()).map(|flows| flows.len()), Some(1));
+    }
+
+    #[test]
+    fn malformed_documents_are_refused() {
+        assert_eq!(JsonValue::parse("{\"a\":1,}").unwrap_err().to_string(), "JSON parsing error at offset 7: Expected a field name");
+        assert_eq!(JsonValue::parse("[1 2]").unwrap_err().to_string(), "JSON parsing error at offset 3: Expected ',' or ']'");
+        assert_eq!(JsonValue::parse("{} x").unwrap_err().to_string(), "JSON parsing error at offset 3: Unexpected trailing characters");
+        assert!(JsonValue::parse("\"open").is_err());
+        assert!(JsonValue::parse("\"\\x\"").is_err());
+        assert!(JsonValue::parse("").is_err());
+    }
+}
@@ -0,0 +1,78 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+use crate::utils::hex_to_bytes;
+
+/// Length of the random of a TLS ClientHello, that identifies the session in a key log
+pub const CLIENT_RANDOM_LEN: usize = 32;
+
+/// TLS session secrets, as written by browsers and TLS libraries to the file named by SSLKEYLOGFILE.
+/// Each line is "LABEL CLIENT_RANDOM SECRET" in hex, where the label is CLIENT_RANDOM for the TLS 1.2 master secret,
+/// or one of the TLS 1.3 traffic secrets such as CLIENT_TRAFFIC_SECRET_0.
+/// It tells which connections have secrets, and the TLS 1.2 master secrets are used to decrypt AES-128-GCM sessions.
+#[derive(Default, PartialEq)]
+pub struct KeyLog {
+    /// Secrets by label, per client random
+    sessions: HashMap<[u8; CLIENT_RANDOM_LEN], HashMap<String, Vec<u8>>>,
+}
+
+impl KeyLog {
+    /// Read a key log file. Comments and empty lines are skipped, while a malformed line is an error.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        KeyLog::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Parse the lines of a key log
+    pub fn parse(text: &str) -> Result<Self, Error> {
+        let mut key_log = KeyLog::default();
+        for (line_index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || Error::new(ErrorKind::InvalidData, format!("Invalid key log line {}", line_index + 1));
+            let mut fields = line.split_whitespace();
+            let (label, client_random, secret) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(label), Some(client_random), Some(secret)) => { (label, client_random, secret) }
+                _ => { return Err(invalid()); }
+            };
+            let client_random: [u8; CLIENT_RANDOM_LEN] = hex_to_bytes(client_random)
+                .and_then(|bytes| bytes.try_into().ok())
+                .ok_or_else(invalid)?;
+            let secret = hex_to_bytes(secret).ok_or_else(invalid)?;
+            key_log.sessions.entry(client_random).or_default().insert(label.to_string(), secret);
+        }
+        Ok(key_log)
+    }
+
+    /// Number of TLS sessions with secrets
+    pub fn session_count(&self) -> usize {
+        self.sessions.len()
+    }
+
+    /// TLS version of the secrets logged for a session, or None if the session is not in the log
+    pub fn version(&self, client_random: &[u8; CLIENT_RANDOM_LEN]) -> Option<&'static str> {
+        let secrets = self.sessions.get(client_random)?;
+        if secrets.contains_key("CLIENT_TRAFFIC_SECRET_0") || secrets.contains_key("SERVER_TRAFFIC_SECRET_0") {
+            Some("tls1.3")
+        } else if secrets.contains_key("CLIENT_RANDOM") {
+            Some("tls1.2")
+        } else {
+            None
+        }
+    }
+
+    /// TLS 1.2 master secret of a session, or None if the log does not have it
+    pub fn master_secret(&self, client_random: &[u8; CLIENT_RANDOM_LEN]) -> Option<&[u8]> {
+        self.sessions.get(client_random)?.get("CLIENT_RANDOM").map(Vec::as_slice)
+    }
+}
+
+/// Only the number of sessions, to keep the secrets out of logs
+impl fmt::Debug for KeyLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "KeyLog({} sessions)", self.session_count())
+    }
+}
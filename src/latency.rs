@@ -0,0 +1,166 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+use log::warn;
+
+/// Number of histogram buckets. Bucket i counts values in [2^i, 2^(i+1)), so 64 buckets cover any u64.
+const BUCKET_COUNT: usize = 64;
+/// Number of samples in a window, after which the p99 is checked against the threshold
+const WINDOW_SAMPLES: u64 = 10000;
+
+/// Histogram with power-of-two buckets, cheap enough to update per packet.
+/// Percentiles are reported as the upper bound of the matching bucket, so they are accurate within a factor of 2.
+#[derive(Clone)]
+pub struct LatencyHistogram {
+    buckets: [u64; BUCKET_COUNT],
+    count: u64,
+    max: u64,
+}
+
+impl LatencyHistogram {
+    pub fn new() -> Self {
+        Self { buckets: [0; BUCKET_COUNT], count: 0, max: 0 }
+    }
+
+    pub fn record(&mut self, value: u64) {
+        let bucket = (u64::BITS - value.leading_zeros()).saturating_sub(1) as usize;
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.max = self.max.max(value);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    /// Upper bound of the value below which the given fraction (0 to 1) of the samples fall, or 0 if empty
+    pub fn percentile(&self, fraction: f64) -> u64 {
+        if self.count == 0 {
+            return 0;
+        }
+        let target = ((self.count as f64) * fraction).ceil().max(1f64) as u64;
+        let mut seen = 0u64;
+        for (i, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= target {
+                // Never report more than the actual max
+                return if i >= 63 { self.max } else { ((1u64 << (i + 1)) - 1).min(self.max) };
+            }
+        }
+        self.max
+    }
+
+    /// Upper bound and count of every non-empty bucket, from the lowest
+    pub fn buckets(&self) -> impl Iterator<Item=(u64, u64)> + '_ {
+        self.buckets.iter().enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(i, count)| (if i >= 63 { u64::MAX } else { (1u64 << (i + 1)) - 1 }, *count))
+    }
+
+    /// Add the samples of another histogram
+    pub fn merge(&mut self, other: &LatencyHistogram) {
+        for (bucket, count) in self.buckets.iter_mut().zip(other.buckets.iter()) {
+            *bucket += count;
+        }
+        self.count += other.count;
+        self.max = self.max.max(other.max);
+    }
+
+    pub fn clear(&mut self) {
+        *self = LatencyHistogram::new();
+    }
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Sampled tracking of the time spent processing packets.
+/// Every window of samples, the p99 is checked and a warning is logged when it exceeds the threshold,
+/// which is an early warning that the capture loop is about to start dropping packets.
+#[derive(Clone)]
+pub struct LatencyTracker {
+    /// Measure one of every N packets, or none if 0
+    sample_every: u64,
+    /// Warn when the p99 of a window exceeds this
+    p99_warn_threshold: Duration,
+    /// Packets seen, for sampling
+    packet_count: u64,
+    /// Samples (in nanoseconds) of the current window
+    window: LatencyHistogram,
+    /// All time samples (in nanoseconds)
+    total: LatencyHistogram,
+    /// Number of windows whose p99 exceeded the threshold
+    warn_count: u64,
+}
+
+impl LatencyTracker {
+    pub fn new(sample_every: u64, p99_warn_threshold: Duration) -> Self {
+        Self {
+            sample_every,
+            p99_warn_threshold,
+            packet_count: 0,
+            window: LatencyHistogram::new(),
+            total: LatencyHistogram::new(),
+            warn_count: 0,
+        }
+    }
+
+    /// Called when a packet starts processing. Return the start time if this packet is sampled.
+    pub fn start(&mut self) -> Option<Instant> {
+        if self.sample_every == 0 {
+            return None;
+        }
+        self.packet_count += 1;
+        if !self.packet_count.is_multiple_of(self.sample_every) {
+            return None;
+        }
+        Some(Instant::now())
+    }
+
+    /// Called when a sampled packet is done
+    pub fn stop(&mut self, start: Instant) {
+        let nanos = start.elapsed().as_nanos() as u64;
+        self.window.record(nanos);
+        self.total.record(nanos);
+        if self.window.count() < WINDOW_SAMPLES {
+            return;
+        }
+        let p99 = Duration::from_nanos(self.window.percentile(0.99));
+        if p99 > self.p99_warn_threshold {
+            self.warn_count += 1;
+            warn!("Packet processing p99 latency is {}us (threshold {}us, max {}us), the capture may start dropping",
+                p99.as_micros(), self.p99_warn_threshold.as_micros(), self.window.max() / 1000);
+        }
+        self.window.clear();
+    }
+
+    /// Add the samples and warnings of another tracker, such as of another shard of the connection table
+    pub fn merge(&mut self, other: &LatencyTracker) {
+        self.packet_count += other.packet_count;
+        self.window.merge(&other.window);
+        self.total.merge(&other.total);
+        self.warn_count += other.warn_count;
+    }
+
+    /// Forget all the samples and warnings, keeping the settings
+    pub fn clear(&mut self) {
+        self.packet_count = 0;
+        self.window.clear();
+        self.total.clear();
+        self.warn_count = 0;
+    }
+}
+
+impl fmt::Display for LatencyTracker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} samples, p50 {}us, p99 {}us, max {}us, {} p99 warnings", self.total.count(),
+               self.total.percentile(0.5) / 1000, self.total.percentile(0.99) / 1000, self.total.max() / 1000,
+               self.warn_count)
+    }
+}
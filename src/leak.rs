@@ -0,0 +1,89 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Counters of a connection at a point in time, to compare with a later sample
+#[derive(Clone, Debug)]
+pub struct ConnSample {
+    /// Sequence, addresses and state, as shown in the report
+    pub label: String,
+    /// Packets of both directions
+    pub packets: u64,
+    /// Bytes of both directions that were buffered and not consumed yet
+    pub held_bytes: usize,
+    /// Bytes of both directions that were handed over to the consumers
+    pub consumed_bytes: usize,
+}
+
+/// Counters of the whole connection table at a point in time, light enough to take on a live table
+#[derive(Clone, Debug)]
+pub struct TableSample {
+    pub taken: Instant,
+    pub packet_count: u64,
+    pub conn_alltime_count: u32,
+    /// Connections by their sequence
+    pub conns: HashMap<u32, ConnSample>,
+}
+
+impl TableSample {
+    /// Add the counters and connections of a sample of another shard of the same table, taken at about the same time
+    pub fn merge(&mut self, other: TableSample) {
+        self.packet_count += other.packet_count;
+        self.conn_alltime_count += other.conn_alltime_count;
+        self.conns.extend(other.conns);
+    }
+}
+
+/// What looks like leaked state between two samples of the same table
+pub struct LeakReport {
+    interval: Duration,
+    /// Number of connections that were in both samples
+    common_count: usize,
+    /// Connections that are still in the table, without any packet since the first sample
+    idle: Vec<ConnSample>,
+    /// Connections whose buffers grew while none of their bytes were consumed, with the held bytes of the first sample
+    growing: Vec<(usize, ConnSample)>,
+    /// Table counters that did not advance
+    stuck_counters: Vec<(&'static str, u64)>,
+}
+
+impl LeakReport {
+    /// Compare two samples of the same table, the older one first
+    pub fn new(old: &TableSample, new: &TableSample) -> Self {
+        let mut idle = Vec::new();
+        let mut growing = Vec::new();
+        let mut common: Vec<(&u32, &ConnSample, &ConnSample)> = new.conns.iter()
+            .filter_map(|(conn_sequence, new_conn)| old.conns.get(conn_sequence).map(|old_conn| (conn_sequence, old_conn, new_conn)))
+            .collect();
+        common.sort_by_key(|(conn_sequence, _, _)| **conn_sequence);
+        for (_, old_conn, new_conn) in &common {
+            if new_conn.packets == old_conn.packets {
+                idle.push((*new_conn).clone());
+            }
+            if new_conn.held_bytes > old_conn.held_bytes && new_conn.consumed_bytes == old_conn.consumed_bytes {
+                growing.push((old_conn.held_bytes, (*new_conn).clone()));
+            }
+        }
+        let stuck_counters = [
+            ("packet_count", old.packet_count, new.packet_count),
+            ("conn_alltime_count", old.conn_alltime_count as u64, new.conn_alltime_count as u64),
+        ].into_iter().filter(|(_, old, new)| new == old).map(|(name, _, new)| (name, new)).collect();
+        LeakReport { interval: new.taken.duration_since(old.taken), common_count: common.len(), idle, growing, stuck_counters }
+    }
+}
+
+impl fmt::Display for LeakReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for conn in &self.idle {
+            writeln!(f, "idle: {}, {} bytes held", conn.label, conn.held_bytes)?;
+        }
+        for (old_held_bytes, conn) in &self.growing {
+            writeln!(f, "growing: {}, {} -> {} bytes held, none consumed", conn.label, old_held_bytes, conn.held_bytes)?;
+        }
+        for (name, value) in &self.stuck_counters {
+            writeln!(f, "not advancing: {} at {}", name, value)?;
+        }
+        write!(f, "{} connections persisted over {}s: {} idle, {} with growing buffers", self.common_count,
+               self.interval.as_secs(), self.idle.len(), self.growing.len())
+    }
+}
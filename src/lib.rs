@@ -0,0 +1,68 @@
+//! Capture TCP traffic, reconstruct the connections, and follow their states.
+//!
+//! The connection tracking and reassembly engine can be embedded in other tools: feed captured frames to
+//! [Connections::process_packet], and take the reassembled bytes with [Connections::drain_ready].
+//! The types re-exported here are the stable API. The modules that the `pcap_test` binary is built on are public for it
+//! but hidden from the documentation, and may change. The rest are private.
+
+#[doc(hidden)] pub mod affinity;
+#[doc(hidden)] pub mod alert;
+#[doc(hidden)] pub mod analyzer;
+pub(crate) mod arp;
+#[doc(hidden)] pub mod bpf;
+#[doc(hidden)] pub mod classifier;
+#[doc(hidden)] pub mod collector;
+#[doc(hidden)] pub mod config;
+#[doc(hidden)] pub mod conn;
+#[doc(hidden)] pub mod conn_pcap;
+#[doc(hidden)] pub mod connections;
+#[doc(hidden)] pub mod consumer_stats;
+#[doc(hidden)] pub mod control;
+#[doc(hidden)] pub mod digest;
+pub(crate) mod dns_cache;
+#[doc(hidden)] pub mod error;
+#[doc(hidden)] pub mod event;
+#[doc(hidden)] pub mod features;
+#[doc(hidden)] pub mod flight_recorder;
+#[doc(hidden)] pub mod flow_buff;
+#[doc(hidden)] pub mod flow_key;
+#[doc(hidden)] pub mod flow_log;
+pub(crate) mod heatmap;
+pub(crate) mod history;
+#[doc(hidden)] pub mod http;
+#[doc(hidden)] pub mod hosts;
+#[doc(hidden)] pub mod ignore;
+pub(crate) mod ipv4;
+pub(crate) mod ipv6;
+#[doc(hidden)] pub mod json;
+#[doc(hidden)] pub mod keylog;
+pub(crate) mod latency;
+pub(crate) mod leak;
+#[doc(hidden)] pub mod mem_stats;
+#[doc(hidden)] pub mod netflow;
+pub(crate) mod observer;
+#[cfg(feature = "onnx")]
+#[doc(hidden)] pub mod onnx;
+pub(crate) mod pcapng;
+#[doc(hidden)] pub mod pipeline;
+pub(crate) mod rate_limit;
+#[doc(hidden)] pub mod replay;
+#[doc(hidden)] pub mod savefile;
+#[doc(hidden)] pub mod self_test;
+#[doc(hidden)] pub mod shards;
+#[doc(hidden)] pub mod shutdown;
+#[doc(hidden)] pub mod snapshot;
+#[doc(hidden)] pub mod stats_report;
+pub(crate) mod syn_flood;
+#[doc(hidden)] pub mod tags;
+#[doc(hidden)] pub mod time_format;
+pub(crate) mod tls_decrypt;
+pub(crate) mod utils;
+#[doc(hidden)] pub mod watchdog;
+
+pub use conn::{Conn, ConnState, ConnSummary, PacketDir};
+pub use connections::{Connections, ConnTimeouts, ReapedConn};
+pub use error::Error;
+pub use event::ConnEvent;
+pub use flow_buff::FlowBuff;
+pub use observer::{CloseReason, ConnectionObserver};
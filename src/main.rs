@@ -1,57 +1,1060 @@
-mod conn;
-mod connections;
-mod flow_buff;
-mod utils;
-
-use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::mpsc::channel;
 use std::thread;
 use std::time::Duration;
 use env_logger::Env;
-use log::{info, Level, log_enabled, trace};
-use pcap::{Active, Capture, Device, Direction};
-use clap::Parser;
-use crate::connections::{Connections};
+use log::{error, info, Level, log_enabled, trace, warn};
+use pcap::{Activated, Active, Capture, Device, Direction};
+use clap::{ArgAction, CommandFactory, FromArgMatches, Parser, Subcommand};
+use clap::error::ErrorKind;
+use pcap_test::{bpf, collector, config, control, flow_key, http, replay, self_test};
+use pcap_test::affinity::CpuSet;
+use pcap_test::alert::AlertRule;
+use pcap_test::analyzer::{AnalyzerConfig, AnalyzerRule};
+use pcap_test::bpf::FilterWarmup;
+use pcap_test::classifier::{Classifier, CommandClassifier};
+use pcap_test::collector::{FlowExporter, parse_collector_addr};
+use pcap_test::config::{ConfigSetting, ConfigValue};
+use pcap_test::conn_pcap::ConnPcap;
+use pcap_test::connections::{capture_ts, Connections, ConnTimeouts};
+use pcap_test::digest::{ChunkMode, PayloadExport};
+use pcap_test::error::Error;
+use pcap_test::features::FEATURES_CSV_HEADER;
+use pcap_test::flight_recorder::FlightRecorder;
+use pcap_test::flow_buff::FlowOverflow;
+use pcap_test::flow_key::FlowKeyer;
+use pcap_test::flow_log::FlowLog;
+use pcap_test::ignore::IgnoreRule;
+use pcap_test::json::JsonValue;
+use pcap_test::keylog::KeyLog;
+#[cfg(feature = "alloc-stats")]
+use pcap_test::mem_stats::CountingAllocator;
+use pcap_test::mem_stats::MemStats;
+use pcap_test::netflow::{NetflowExporter, NetflowVersion};
+#[cfg(feature = "onnx")]
+use pcap_test::onnx::OnnxClassifier;
+use pcap_test::replay::Recorder;
+use pcap_test::savefile::SavefileWriter;
+use pcap_test::pipeline::{Consumers, dispatch_ready_buffers, pin_thread, reap_closed_connections, run_line_rate_test, Workers};
+use pcap_test::shards::ConnShards;
+use pcap_test::shutdown;
+use pcap_test::shutdown::SHUTDOWN_READ_TIMEOUT_MS;
+use pcap_test::stats_report::{log_hosts, StatsReporter, write_latency_heatmap, write_retransmit_timeline};
+use pcap_test::tags::TagRule;
+use pcap_test::time_format::{TimeFormat, TimeFormatter, TimeZone};
+use pcap_test::watchdog::{Watchdog, WATCHDOG_READ_TIMEOUT_MS};
+
+/// Read timeout of the capture in a line-rate test, so it ends on time on an idle link
+const LINE_RATE_TEST_READ_TIMEOUT_MS: i32 = 100;
+/// Exit code when the capture was stopped by a watchdog alarm
+const WATCHDOG_EXIT_CODE: i32 = 3;
+/// Max packets to keep per connection when rendering ladder diagrams or exporting time-sequence samples of a replay
+const REPLAY_PACKET_HISTORY: usize = 100000;
+/// Largest snap length of a capture, that is also the largest one of the files that are read
+const MAX_SNAPLEN: u32 = 262144;
+
+/// Count the heap allocations for the memory reports
+#[cfg(feature = "alloc-stats")]
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
 
 #[derive(Parser)]
 #[clap(author, version, about)]
 struct Cli {
+    /// Optional offline command. Without a command, live traffic is captured.
+    #[clap(subcommand)]
+    command: Option<Command>,
     /// Filter in BPF (pcap) format.
     /// See http://biot.com/capstats/bpf.html for more information about this syntax.
     #[clap(short, long, value_parser, default_value = "tcp")]
     filter: String,
+    /// Apply the filter after the pcap optimizer, which usually makes the program shorter
+    #[clap(long, value_parser)]
+    optimize_filter: bool,
+    /// Capture on a trunk port: also apply the filter to frames with one or two VLAN tags (802.1Q or QinQ),
+    /// which a plain filter such as "tcp" does not match
+    #[clap(long, value_parser)]
+    vlan_trunk: bool,
+    /// Print the compiled filter program, in the format of "tcpdump -d", and exit
+    #[clap(long, value_parser)]
+    print_bpf: bool,
+    /// Measure the max packet rate the host sustains: for this number of seconds, track only the headers of the packets,
+    /// without copying payloads, logging or consuming, then report the rate and the drops, and exit.
+    /// Compare it with the rate of the full configuration to see how much headroom it has.
+    #[clap(long, value_parser)]
+    line_rate_test_secs: Option<u64>,
+    /// Raise a capture health alarm if no packets arrive for this number of seconds while the interface is up,
+    /// or never if 0. Alarms are logged, and sent to the collector if flow records are exported.
+    #[clap(long, value_parser, default_value_t = 0)]
+    watchdog_silence_secs: u64,
+    /// Raise a capture health alarm if the kernel drops more than this percent of the packets in a 5 second interval
+    #[clap(long, value_parser = parse_percent)]
+    watchdog_max_drop_percent: Option<f64>,
+    /// Stop the capture on a watchdog alarm, after the usual shutdown, with exit code 3, so a supervisor restarts it
+    #[clap(long, value_parser)]
+    watchdog_exit: bool,
+    /// Before applying the filter, check it on this number of unfiltered packets and report how many it rejects,
+    /// to tune filters on busy links. The warm-up packets are not tracked.
+    #[clap(long, value_parser, default_value_t = 0)]
+    filter_warmup_packets: u64,
     /// Device name to capture ("interface" in tcpdump terminology).
     /// Defaults to the main device
     #[clap(short, long, value_parser)]
     device: Option<String>,
+    /// Read the packets of this pcap file instead of capturing a device, through the same pipeline as a live capture,
+    /// and stop at its end. The watchdog is off, and the MTU is only known from --mtu.
+    #[clap(long, value_parser, conflicts_with = "device")]
+    read_file: Option<PathBuf>,
+    /// Max bytes to capture of every packet. Packets that are cut by it are counted as truncated and not tracked.
+    #[clap(long, value_parser = clap::value_parser!(i32).range(64..=MAX_SNAPLEN as i64), default_value_t = 65535)]
+    snaplen: i32,
+    /// Do not put the device in promiscuous mode, so only the traffic to and from this host is captured
+    #[clap(long, value_parser)]
+    no_promisc: bool,
+    /// Size of the kernel capture buffer in bytes. A larger buffer absorbs longer bursts before packets are dropped.
+    #[clap(long, value_parser = clap::value_parser!(i32).range(65536..), default_value_t = 10000000)]
+    buffer_size: i32,
+    /// Let the kernel batch packets until the read timeout or until its buffer fills, instead of delivering every packet
+    /// right away, for less CPU at high packet rates
+    #[clap(long, value_parser)]
+    no_immediate: bool,
+    /// Read timeout of the capture in milliseconds, that bounds the batching without immediate mode.
+    /// It is at most 1000, so a shutdown signal is noticed on an idle link, and the line-rate test lowers it further.
+    #[clap(long, value_parser = clap::value_parser!(i32).range(1..))]
+    timeout: Option<i32>,
+    /// Format of the timestamps in all the exports and the log, "epoch" (seconds) or "rfc3339" (dates).
+    /// By default, the exports are written in epoch seconds and the log in dates.
+    #[clap(long, global = true, value_parser = TimeFormat::parse)]
+    time_format: Option<TimeFormat>,
+    /// Time zone of the dates, "utc" or "local"
+    #[clap(long, global = true, value_parser = TimeZone::parse, default_value = "utc")]
+    timezone: TimeZone,
+    /// Snapshot file of the connection table.
+    /// If it exists, it is loaded on startup, and it is (re)written on shutdown.
+    #[clap(long, value_parser)]
+    state_file: Option<PathBuf>,
+    /// Do not save the payload buffers in the state snapshot, only counters and states
+    #[clap(long, value_parser, default_value_t = false)]
+    state_no_payload: bool,
+    /// Record every captured frame with its timestamp into this file, to be replayed later with the replay command.
+    /// A file name that ends with ".pcapng" is written in pcapng, to be opened by Wireshark as well.
+    #[clap(long, value_parser)]
+    record: Option<PathBuf>,
+    /// Write every captured frame that passed the filter to this pcap savefile, as tcpdump -w does, while the
+    /// connections are tracked
+    #[clap(short = 'w', long, value_parser)]
+    write_file: Option<PathBuf>,
+    /// Megabytes after which the savefile is rotated to a new one with a "-1", "-2", ... suffix, or never if 0
+    #[clap(long, value_parser, default_value_t = 0)]
+    write_file_rotate_mb: u64,
+    /// Seconds of capture time after which the savefile is rotated to a new one, or never if 0
+    #[clap(long, value_parser, default_value_t = 0)]
+    write_file_rotate_secs: u64,
+    /// Pin the capture thread to these CPUs, given as a list such as "2" or "0-3,8", or as "node:N" for a NUMA node
+    #[clap(long, value_parser = CpuSet::parse)]
+    capture_cpus: Option<CpuSet>,
+    /// Pin the threads that consume ready buffers to these CPUs, in the same format as --capture-cpus
+    #[clap(long, value_parser = CpuSet::parse)]
+    consumer_cpus: Option<CpuSet>,
+    /// Number of threads that consume ready buffers. The buffers of a connection always go to the same thread,
+    /// so every stream is consumed in order.
+    #[clap(long, value_parser = clap::value_parser!(u16).range(1..), default_value_t = 1)]
+    consumer_threads: u16,
+    /// Number of threads that process the captured packets, each into its own shard of the connection table by the IP
+    /// addresses, so all the packets of a connection go to the same thread. The capture thread only queues the packets.
+    #[clap(long, value_parser = clap::value_parser!(u16).range(1..), default_value_t = 1)]
+    worker_threads: u16,
+    /// Pin the threads that process the captured packets to these CPUs, in the same format as --capture-cpus
+    #[clap(long, value_parser = CpuSet::parse)]
+    worker_cpus: Option<CpuSet>,
+    /// Interval in seconds between memory reports (resident, malloc fragmentation, buffered bytes), consumer reports
+    /// and host reports,
+    /// or 0 to disable.
+    /// Allocation counters require building with the "alloc-stats" feature.
+    #[clap(long, value_parser, default_value_t = 60)]
+    memory_report_secs: u64,
+    /// Interval in seconds between traffic reports of the live capture (packets and bits per second, new, closed and
+    /// active connections, parse errors and the packets dropped by the kernel and the interface), or 0 to disable
+    #[clap(long, value_parser, default_value_t = 10)]
+    stats_secs: u64,
+    /// Number of contiguous bytes that a flow needs to have before its buffer is handed over to the consumer.
+    /// Closed connections hand over whatever they have.
+    #[clap(long, value_parser, default_value_t = 32000)]
+    ready_bytes: usize,
+    /// Seconds to keep a closed connection, so late packets are still attributed to it, before it is finalized and removed
+    #[clap(long, value_parser, default_value_t = 60)]
+    close_grace_secs: u64,
+    /// Seconds to wait for the SYN/ACK of a connection, before it is considered failed and removed
+    #[clap(long, value_parser, default_value_t = 30)]
+    handshake_timeout_secs: u64,
+    /// Seconds without packets before a connection whose handshake was not seen, such as one that was open before the
+    /// capture started, is evicted, or 0 to keep it until it closes
+    #[clap(long, value_parser, default_value_t = 300)]
+    idle_timeout_secs: u64,
+    /// Seconds without packets before an established connection is evicted, or 0 to keep it until it closes
+    #[clap(long, value_parser, default_value_t = 3600)]
+    established_timeout_secs: u64,
+    /// Seconds without packets before a connection that started closing and did not finish is evicted,
+    /// or 0 to keep it until it closes
+    #[clap(long, value_parser, default_value_t = 120)]
+    closing_timeout_secs: u64,
+    /// Adopt connections whose handshake was not seen, such as ones that were open before the capture started, with
+    /// their sequences inferred from the first segment of every direction, so their payloads are tracked too
+    #[clap(long, value_parser)]
+    midstream: bool,
+    /// Do not validate the IPv4 header and TCP checksums, on hosts with checksum offload, where the captured outgoing
+    /// packets do not have their checksums yet. Otherwise the payloads of the segments with wrong checksums are not
+    /// buffered, and the failures are counted per connection and for the table.
+    #[clap(long, value_parser)]
+    no_checksum_check: bool,
+    /// Max nested tunnels (VXLAN, GRE with ERSPAN, and IPv4 in IP) to unwrap from a packet, to track the connections
+    /// that traffic mirrored through tunnels carries, or 0 to count the tunnel packets as not TCP
+    #[clap(long, value_parser, default_value_t = 1)]
+    decap_depth: u8,
+    /// Keep the last N packets of every connection, so they can be rendered with the ladder control command
+    #[clap(long, value_parser, default_value_t = 0)]
+    packet_history: usize,
+    /// Unix socket for troubleshooting commands on the live connection table, such as finding the connections of a host.
+    /// Connect with a tool like "nc -U" and type "help".
+    #[clap(long, value_parser)]
+    control_socket: Option<PathBuf>,
+    /// Serve HTTP on this "IP:PORT", to follow the reassembled payload of a live connection remotely, such as with
+    /// "curl -N 'http://IP:PORT/follow?a=10.0.0.1:443&b=10.0.0.2:51000'". Add "&dir=low" or "&dir=high" for one side.
+    #[clap(long, value_parser)]
+    http_listen: Option<String>,
+    /// TLS key log file, in the format written to SSLKEYLOGFILE by browsers and TLS libraries.
+    /// TLS connections are matched with their session secrets, which are reported in the flow records.
+    /// TLS 1.2 sessions with an AES-128-GCM suite are decrypted, so the analyzers see their application data.
+    #[clap(long, value_parser)]
+    tls_keylog: Option<PathBuf>,
+    /// Label the connections with the names that their clients resolved to the server addresses, from the DNS
+    /// responses seen on UDP port 53 before the connections started
+    #[clap(long, value_parser)]
+    dns_names: bool,
+    /// Enable, disable or limit protocol analyzers, as "NAME:SETTING[,SETTING...]" where NAME is an analyzer or "all",
+    /// and a SETTING is "on", "off", "max-bytes=N" (stream bytes inspected per flow) or "max-records=N"
+    /// (metadata entries, commands, HTTP transactions and DNS queries recorded per connection). Repeat for more
+    /// analyzers; later options win.
+    /// For example "--analyzer all:off --analyzer tls:on" or "--analyzer cache:max-bytes=1000000".
+    #[clap(long = "analyzer", value_parser = AnalyzerRule::parse)]
+    analyzer_rules: Vec<AnalyzerRule>,
+    /// Alert when a connection crosses a threshold, given as "METRIC>THRESHOLD" where METRIC is retransmit-pct
+    /// (of the data packets), handshake-rtt-ms, zero-windows (packets advertising a zero window) or nagle-stall-ms
+    /// (time lost to small writes that waited for a delayed ACK).
+    /// Every rule fires at most once per connection. Repeat for more rules.
+    #[clap(long = "alert", value_parser = AlertRule::parse)]
+    alert_rules: Vec<AlertRule>,
+    /// Keep the last captured frames in memory, and when an alert fires, dump them to a pcap file in this directory,
+    /// along with a second file of only the frames of the alerting connection
+    #[clap(long, value_parser)]
+    flight_recorder_dir: Option<PathBuf>,
+    /// Megabytes of frames that the flight recorder keeps
+    #[clap(long, value_parser, default_value_t = 64)]
+    flight_recorder_mb: usize,
+    /// On shutdown, write the handshake RTT and time to first byte distributions per server subnet and port
+    /// and per time bucket to this file, to spot systemic latency changes.
+    /// The format is JSON (with full histograms) if the file name ends with ".json", or CSV otherwise.
+    #[clap(long, value_parser)]
+    latency_heatmap: Option<PathBuf>,
+    /// Width in seconds of the time buckets of the latency heatmap
+    #[clap(long, value_parser, default_value_t = 60)]
+    latency_heatmap_bucket_secs: u64,
+    /// Prefix length by which server addresses are aggregated in the latency heatmap, such as 32 for every server apart
+    #[clap(long, value_parser = clap::value_parser!(u8).range(0..=32), default_value_t = 24)]
+    latency_heatmap_prefix: u8,
+    /// Do not track connections that match this rule on either side, given as "port:N", "host:IP" or "net:IP/PREFIX".
+    /// Repeat for more rules. The later packets of ignored flows are dropped before they are parsed.
+    #[clap(long = "ignore", value_parser = IgnoreRule::parse)]
+    ignore_rules: Vec<IgnoreRule>,
+    /// Tag new connections that match a rule on either side, given as "MATCH=KEY" or "MATCH=KEY=VALUE",
+    /// such as "port:5432=db" or "net:10.1.0.0/16=team=payments". Repeat for more tags.
+    /// Tags are carried to the flow records, the JSON dumps and the exports to a collector.
+    #[clap(long = "tag", value_parser = TagRule::parse)]
+    tag_rules: Vec<TagRule>,
+    /// Which packets belong to the same connection: "5-tuple", "5-tuple+vlan", "5-tuple+tunnel" (the VXLAN network
+    /// identifier) or "3-tuple" (without the client port, for captures behind a NAT). VXLAN packets are always unwrapped.
+    #[clap(long, value_parser = flow_key::parse, default_value = "5-tuple")]
+    flow_key: Arc<dyn FlowKeyer>,
+    /// Add at most this number of new connections per second, or unlimited if 0, to protect the connection table
+    /// from SYN floods. Packets of the new flows beyond the rate are not tracked, and are counted as suppressed.
+    #[clap(long, value_parser, default_value_t = 0)]
+    max_new_conns_per_sec: u32,
+    /// Warn when a destination address and port has this number of half-open connections, that sent a SYN and got no
+    /// SYN/ACK yet, or never if 0
+    #[clap(long, value_parser, default_value_t = 0)]
+    syn_flood_half_open: u32,
+    /// Warn when a destination address and port receives this number of SYNs of new connections per second, or never
+    /// if 0
+    #[clap(long, value_parser, default_value_t = 0)]
+    syn_flood_rate: u32,
+    /// Megabytes that all the connections together may buffer before consumption, or unlimited if 0. From 90% of it,
+    /// new connections are only counted, and when it is exhausted, connections that hold more than the average stop
+    /// buffering and hand over what they have.
+    #[clap(long, value_parser, default_value_t = 0)]
+    buffer_budget_mb: usize,
+    /// Bytes that a single flow may buffer before consumption (up to 1GB). Payload over it is dropped and counted.
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1024..=1073741824), default_value_t = 1000000)]
+    flow_buffer_limit: u32,
+    /// What happens to a flow that reaches its buffer limit: "truncate" drops the payload over it and hands over what is
+    /// ready, so its consumer makes room, and "stats-only" stops buffering the connection and hands over what it has
+    #[clap(long, value_parser = FlowOverflow::parse, default_value = "truncate")]
+    flow_overflow: FlowOverflow,
+    /// Log a preview of this number (32 to 128) of first payload bytes of every direction of a connection, with the bytes
+    /// that are not printable replaced by dots, which is often enough to tell the application
+    #[clap(long, value_parser = clap::value_parser!(u16).range(32..=128))]
+    payload_preview: Option<u16>,
+    /// MTU of the capture interface, to flag handshakes whose MSS does not fit in it. Defaults to the MTU of the device.
+    #[clap(long, value_parser = clap::value_parser!(u16).range(68..))]
+    mtu: Option<u16>,
+    /// On shutdown, write the retransmissions of the connections in the table, as (time, relative seq, length)
+    /// per flow up to a cap, to this file. The format is JSON if the file name ends with ".json", or CSV otherwise.
+    #[clap(long, value_parser)]
+    retransmit_timeline: Option<PathBuf>,
+    /// Write a feature vector of every connection (packet size and inter-arrival time statistics, direction ratios,
+    /// bursts and handshake timings) to this CSV file, for training traffic classifiers. Finalized connections are
+    /// written as they are removed, and the rest on shutdown. The columns are documented in features.rs.
+    #[clap(long, value_parser)]
+    features_export: Option<PathBuf>,
+    /// Label every connection in its "class" tag with a model that this shell command serves. The command gets a line
+    /// of comma separated features per connection (the feature export columns from "server_port" on, empty where
+    /// unknown) on its input and answers a line of the label, so any model runtime can be plugged in.
+    #[clap(long, value_parser)]
+    classifier_cmd: Option<String>,
+    /// Label every connection in its "class" tag with this ONNX model instead, evaluated in the process. It gets the
+    /// same features as the command, with NaN where unknown. Only scikit-learn style linear and tree ensemble
+    /// classifiers are supported, and it requires building with the "onnx" feature.
+    #[clap(long, value_parser, conflicts_with = "classifier_cmd")]
+    classifier_model: Option<PathBuf>,
+    /// Classify a connection once it has this number of packets, or when it ends before
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..), default_value_t = 20)]
+    classify_after_packets: u32,
+    /// Send the flow records of the finalized connections to a collector at this "HOST:PORT", started with the collect command
+    #[clap(long, value_parser = parse_collector_addr)]
+    export_to: Option<String>,
+    /// Send the flow records of the finalized connections, one per direction, to a NetFlow v9 or IPFIX collector at
+    /// this "HOST:PORT" over UDP
+    #[clap(long, value_parser = parse_collector_addr)]
+    netflow_to: Option<String>,
+    /// Format of the flow records sent with --netflow-to: "v9" or "ipfix"
+    #[clap(long, value_parser = NetflowVersion::parse, default_value = "ipfix")]
+    netflow_version: NetflowVersion,
+    /// Observation domain (IPFIX) or source ID (NetFlow v9) of the flow records sent with --netflow-to
+    #[clap(long, value_parser, default_value_t = 0)]
+    netflow_domain_id: u32,
+    /// Write a JSON line to this file for every connection that opens, is established, closes or is evicted, with its
+    /// endpoints, packet and byte counts of both directions, duration and close reason, for log shippers such as Filebeat
+    #[clap(long, value_parser)]
+    json_out: Option<PathBuf>,
+    /// Write the frames of every connection to its own pcap file in this directory, named by the connection sequence
+    /// and addresses, such as "conn_12_10.0.0.1_443_10.0.0.2_51000.pcap", to pull single sessions out of a busy capture
+    #[clap(long, value_parser)]
+    conn_pcap_dir: Option<PathBuf>,
+    /// Megabytes after which the pcap file of a connection is rotated to a new one with a "-1", "-2", ... suffix
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..), default_value_t = 100)]
+    conn_pcap_rotate_mb: u64,
+    /// Name of this probe in the exported flow records. Defaults to the host name.
+    #[clap(long, value_parser)]
+    probe_name: Option<String>,
+    /// Measure the processing time of one of every N packets, or none if 0
+    #[clap(long, value_parser, default_value_t = 100)]
+    latency_sample_every: u64,
+    /// Warn when the p99 of the measured packet processing time exceeds this number of microseconds
+    #[clap(long, value_parser, default_value_t = 500)]
+    latency_warn_us: u64,
+    /// Log level, or filters in the format of RUST_LOG such as "info,pcap_test::connections=debug", instead of RUST_LOG
+    #[clap(long, value_parser)]
+    log_level: Option<String>,
+    /// Read the defaults of the options from this TOML file, where options are keys by their long names, such as
+    /// `buffer_size = 20000000` or `alert = ["retransmit-pct>5"]`, and those of a command are in its table, such as
+    /// `[replay]`. Options given on the command line override the file.
+    #[clap(long, value_parser)]
+    config: Option<PathBuf>,
+}
+
+// Parsed once, so the size of the replay options does not matter
+#[allow(clippy::large_enum_variant)]
+#[derive(Subcommand)]
+enum Command {
+    /// Write the connection table of a state snapshot file as JSON, for bug reports and debugging
+    DumpState {
+        /// Snapshot file, as saved with --state-file
+        state_file: PathBuf,
+        /// Output JSON file. Defaults to the standard output.
+        #[clap(short, long, value_parser)]
+        output: Option<PathBuf>,
+        /// Do not include the payload buffers (hex encoded) in the JSON
+        #[clap(long, value_parser, default_value_t = false)]
+        no_payload: bool,
+        /// Instead of the payload, include SHA-256 digests of its chunks, cut as "fixed:SIZE" or as content-defined
+        /// chunks of about a power of 2 size "cdc:AVERAGE_SIZE", so content can be compared without exposing it
+        #[clap(long, value_parser = ChunkMode::parse, conflicts_with = "no_payload")]
+        payload_digests: Option<ChunkMode>,
+    },
+    /// Read a JSON connection table, as written by dump-state, into a state snapshot file
+    LoadState {
+        /// JSON file, as written by dump-state
+        json_file: PathBuf,
+        /// Output snapshot file, to be loaded later with --state-file
+        #[clap(short, long, value_parser)]
+        output: PathBuf,
+    },
+    /// Feed a synthetic connection through the connection tracking and verify the counters, the state transitions and
+    /// the reassembled bytes, to validate a build or a deployment without crafting traffic
+    SelfTest,
+    /// Receive flow records from probes started with --export-to, and keep a merged view of their connections,
+    /// until stopped
+    Collect {
+        /// Address to listen on for probes, such as "0.0.0.0:7070"
+        listen: String,
+        /// Write the merged view as JSON to this file, every interval
+        #[clap(short, long, value_parser)]
+        output: Option<PathBuf>,
+        /// Seconds between writes of the merged view, which is also logged
+        #[clap(long, value_parser, default_value_t = 10)]
+        write_secs: u64,
+    },
+    /// Feed recordings, made with --record, to an empty connection table, frame by frame and in order.
+    /// Multiple recordings are merged by capture time.
+    Replay {
+        /// Recording files, as written with --record, or pcapng files such as those of Wireshark
+        #[clap(required = true)]
+        recordings: Vec<PathBuf>,
+        /// Clock offset in seconds to add to the times of a recording, given by its index as "index=seconds".
+        /// For example "1=-0.25" moves the second recording a quarter of a second earlier.
+        #[clap(long, value_parser = parse_time_offset)]
+        time_offset: Vec<(usize, f64)>,
+        /// Estimate the clock offset of every recording relative to the first one, from handshakes seen in both.
+        /// Manual offsets take precedence.
+        #[clap(long, value_parser, default_value_t = false)]
+        estimate_offsets: bool,
+        /// Write the resulting connection table as JSON to this file
+        #[clap(short, long, value_parser)]
+        output: Option<PathBuf>,
+        /// Print a ladder diagram of the packets of the connection with this sequence (as in the logs)
+        #[clap(long, value_parser)]
+        ladder: Vec<u32>,
+        /// Write the (time, seq, ack, window) samples of every packet to this file, for time-sequence graphs.
+        /// The format is JSON if the file name ends with ".json", or CSV otherwise.
+        #[clap(long, value_parser)]
+        time_sequence: Option<PathBuf>,
+        /// Write the retransmissions of every connection, as (time, relative seq, length) per flow up to a cap, to this file.
+        /// The format is JSON if the file name ends with ".json", or CSV otherwise.
+        #[clap(long, value_parser)]
+        retransmit_timeline: Option<PathBuf>,
+        /// Write a feature vector of every connection to this CSV file, as with live capture
+        #[clap(long, value_parser)]
+        features_export: Option<PathBuf>,
+        /// Label every connection with a model that this shell command serves, as with live capture
+        #[clap(long, value_parser)]
+        classifier_cmd: Option<String>,
+        /// Label every connection with this ONNX model instead, as with live capture
+        #[clap(long, value_parser, conflicts_with = "classifier_cmd")]
+        classifier_model: Option<PathBuf>,
+        /// Classify a connection once it has this number of packets, or when it ends before
+        #[clap(long, value_parser = clap::value_parser!(u32).range(1..), default_value_t = 20)]
+        classify_after_packets: u32,
+        /// TLS key log file to match the replayed TLS connections with, as with live capture
+        #[clap(long, value_parser)]
+        tls_keylog: Option<PathBuf>,
+        /// Label the connections with the names that their clients resolved over DNS, as with live capture
+        #[clap(long, value_parser)]
+        dns_names: bool,
+        /// Enable, disable or limit protocol analyzers, as with live capture
+        #[clap(long = "analyzer", value_parser = AnalyzerRule::parse)]
+        analyzer_rules: Vec<AnalyzerRule>,
+        /// Alert rules to check on the replayed connections, as with live capture
+        #[clap(long = "alert", value_parser = AlertRule::parse)]
+        alert_rules: Vec<AlertRule>,
+        /// Dump the last replayed frames to pcap files in this directory when an alert fires, as with live capture
+        #[clap(long, value_parser)]
+        flight_recorder_dir: Option<PathBuf>,
+        /// Megabytes of frames that the flight recorder keeps
+        #[clap(long, value_parser, default_value_t = 64)]
+        flight_recorder_mb: usize,
+        /// Write the frames of every connection to its own pcap file in this directory, as with live capture
+        #[clap(long, value_parser)]
+        conn_pcap_dir: Option<PathBuf>,
+        /// Megabytes after which the pcap file of a connection is rotated to a new one
+        #[clap(long, value_parser = clap::value_parser!(u64).range(1..), default_value_t = 100)]
+        conn_pcap_rotate_mb: u64,
+        /// Send the flow records of all the connections at the end of the replay to a collector at this "HOST:PORT"
+        #[clap(long, value_parser = parse_collector_addr)]
+        export_to: Option<String>,
+        /// Send the flow records of all the connections at the end of the replay to a NetFlow v9 or IPFIX collector at
+        /// this "HOST:PORT", as with live capture
+        #[clap(long, value_parser = parse_collector_addr)]
+        netflow_to: Option<String>,
+        /// Format of the flow records sent with --netflow-to: "v9" or "ipfix"
+        #[clap(long, value_parser = NetflowVersion::parse, default_value = "ipfix")]
+        netflow_version: NetflowVersion,
+        /// Observation domain (IPFIX) or source ID (NetFlow v9) of the flow records sent with --netflow-to
+        #[clap(long, value_parser, default_value_t = 0)]
+        netflow_domain_id: u32,
+        /// Name of this probe in the exported flow records. Defaults to the host name.
+        #[clap(long, value_parser)]
+        probe_name: Option<String>,
+        /// Do not track connections that match this rule on either side, as with live capture
+        #[clap(long = "ignore", value_parser = IgnoreRule::parse)]
+        ignore_rules: Vec<IgnoreRule>,
+        /// Tag new connections that match a rule on either side, as with live capture
+        #[clap(long = "tag", value_parser = TagRule::parse)]
+        tag_rules: Vec<TagRule>,
+        /// Which packets belong to the same connection, as with live capture
+        #[clap(long, value_parser = flow_key::parse, default_value = "5-tuple")]
+        flow_key: Arc<dyn FlowKeyer>,
+        /// Add at most this number of new connections per second of capture time, or unlimited if 0, as with live capture
+        #[clap(long, value_parser, default_value_t = 0)]
+        max_new_conns_per_sec: u32,
+        /// Warn when a destination has this number of half-open connections, or never if 0, as with live capture
+        #[clap(long, value_parser, default_value_t = 0)]
+        syn_flood_half_open: u32,
+        /// Warn when a destination receives this number of SYNs per second of capture time, or never if 0, as with live
+        /// capture
+        #[clap(long, value_parser, default_value_t = 0)]
+        syn_flood_rate: u32,
+        /// Adopt connections whose handshake was not seen, as with live capture
+        #[clap(long, value_parser)]
+        midstream: bool,
+        /// Do not validate the IPv4 header and TCP checksums, for recordings of a host with checksum offload
+        #[clap(long, value_parser)]
+        no_checksum_check: bool,
+        /// Max nested tunnels to unwrap from a packet, as with live capture
+        #[clap(long, value_parser, default_value_t = 1)]
+        decap_depth: u8,
+        /// Log a preview of this number (32 to 128) of first payload bytes of every direction, as with live capture
+        #[clap(long, value_parser = clap::value_parser!(u16).range(32..=128))]
+        payload_preview: Option<u16>,
+        /// MTU of the interface that the recordings were captured on, to flag handshakes whose MSS does not fit in it
+        #[clap(long, value_parser = clap::value_parser!(u16).range(68..))]
+        mtu: Option<u16>,
+        /// Write the latency distributions per server subnet and port and per time bucket to this file,
+        /// as JSON if the file name ends with ".json", or CSV otherwise
+        #[clap(long, value_parser)]
+        latency_heatmap: Option<PathBuf>,
+        /// Width in seconds of the time buckets of the latency heatmap
+        #[clap(long, value_parser, default_value_t = 60)]
+        latency_heatmap_bucket_secs: u64,
+        /// Prefix length by which server addresses are aggregated in the latency heatmap
+        #[clap(long, value_parser = clap::value_parser!(u8).range(0..=32), default_value_t = 24)]
+        latency_heatmap_prefix: u8,
+    },
+}
+
+impl Cli {
+    /// How timestamps are written in the exports, which are epoch seconds unless another format was chosen
+    fn export_time_format(&self) -> TimeFormatter {
+        TimeFormatter::new(self.time_format.unwrap_or(TimeFormat::Epoch), self.timezone)
+    }
 }
 
 fn main() {
-    let args = Cli::parse();
+    let args = parse_cli();
+
+    // Without --log-level, RUST_LOG applies, and if it is not set either, then default to INFO level
+    let log_time = TimeFormatter::new(args.time_format.unwrap_or(TimeFormat::Rfc3339), args.timezone);
+    let mut logger = match &args.log_level {
+        Some(filters) => {
+            let mut logger = env_logger::Builder::new();
+            logger.parse_filters(filters);
+            logger
+        }
+        None => { env_logger::Builder::from_env(Env::default().default_filter_or("info")) }
+    };
+    logger
+        .format(move |buf, record| {
+            writeln!(buf, "[{} {:<5} {}] {}", log_time.now(), buf.default_styled_level(record.level()), record.target(),
+                     record.args())
+        })
+        .init();
+
+    let result = match &args.command {
+        Some(command) => { run_command(command, &args.export_time_format()) }
+        None => { run_capture(&args) }
+    };
+    if let Err(error) = result {
+        error!("{}", error);
+        std::process::exit(if matches!(error, Error::Unhealthy(_)) { WATCHDOG_EXIT_CODE } else { 1 });
+    }
+}
+
+/// Parse the command line, with the settings of the --config file, if given, as the defaults of the options
+fn parse_cli() -> Cli {
+    let mut command = Cli::command();
+    if let Some(path) = config_path() {
+        let defaults = config::load(&path)
+            .map_err(|error| error.to_string())
+            .and_then(|settings| config_defaults(&command, &settings).map_err(|message| format!("{}: {}", path.display(), message)));
+        let defaults = match defaults {
+            Ok(defaults) => { defaults }
+            Err(error) => { command.error(ErrorKind::InvalidValue, error).exit() }
+        };
+        for (subcommand, arg_id, values) in defaults {
+            command = match subcommand {
+                None => { command.mut_arg(arg_id, |arg| arg.default_values(values)) }
+                Some(name) => { command.mut_subcommand(name, |sub| sub.mut_arg(arg_id, |arg| arg.default_values(values))) }
+            };
+        }
+    }
+    let matches = command.get_matches();
+    Cli::from_arg_matches(&matches).unwrap_or_else(|error| error.exit())
+}
+
+/// The --config file of the command line, found before it is parsed, since the file changes the parsing
+fn config_path() -> Option<PathBuf> {
+    let mut args = std::env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            break;
+        }
+        if arg == "--config" {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.to_str().and_then(|arg| arg.strip_prefix("--config=")) {
+            return Some(PathBuf::from(path));
+        }
+    }
+    None
+}
 
-    // If RUST_LOG is not set, then default to INFO level
-    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+/// Default values of an option, as the command (None for the top level), the option ID and the values
+type OptionDefault = (Option<String>, String, Vec<String>);
+
+/// The defaults of the options that the config settings give. The values are checked as those of the command line
+/// are, and so are the keys, with a dash or an underscore between words.
+fn config_defaults(command: &clap::Command, settings: &[ConfigSetting]) -> Result<Vec<OptionDefault>, String> {
+    let mut defaults = Vec::new();
+    for setting in settings {
+        let subcommand_name = setting.table.as_ref().map(|table| table.replace('_', "-"));
+        let target = match &subcommand_name {
+            None => { command }
+            Some(name) => {
+                command.find_subcommand(name).ok_or_else(|| format!("line {}: unknown command [{}]", setting.line, name))?
+            }
+        };
+        let long = setting.key.replace('_', "-");
+        let arg = match target.get_arguments().find(|arg| arg.get_long() == Some(long.as_str())) {
+            Some(arg) if long != "config" => { arg }
+            _ => { return Err(format!("line {}: unknown option \"{}\"", setting.line, setting.key)); }
+        };
+        let values = match (arg.get_action(), &setting.value) {
+            (ArgAction::SetTrue, ConfigValue::Boolean(_)) => { setting.value.to_args() }
+            (ArgAction::SetTrue, _) => { return Err(format!("line {}: \"{}\" is true or false", setting.line, setting.key)); }
+            (ArgAction::Set, ConfigValue::Array(_)) => {
+                return Err(format!("line {}: \"{}\" has a single value", setting.line, setting.key));
+            }
+            (ArgAction::Set, _) | (ArgAction::Append, _) => { setting.value.to_args() }
+            _ => { return Err(format!("line {}: \"{}\" cannot be set in a config file", setting.line, setting.key)); }
+        };
+        // With only the value parser of the option, since an invalid default is a panic of clap
+        let check = clap::Command::new("config").no_binary_name(true).arg(clap::Arg::new("value").long(long.clone())
+            .value_name(arg.get_id().as_str().to_uppercase()).value_parser(arg.get_value_parser().clone()));
+        for value in &values {
+            if let Err(error) = check.clone().try_get_matches_from([format!("--{}={}", long, value)]) {
+                let message = error.render().to_string();
+                let message = message.lines().next().unwrap_or_default().trim_start_matches("error: ");
+                return Err(format!("line {}: {}", setting.line, message));
+            }
+        }
+        defaults.push((subcommand_name, arg.get_id().to_string(), values));
+    }
+    Ok(defaults)
+}
+
+/// Capture live traffic from the specified or default device, or read a pcap file, until the capture ends
+fn run_capture(args: &Cli) -> Result<(), Error> {
     info!("Start pcap_test...");
+    shutdown::install()?;
 
-    // Get the default device name, to be used later when looking at the device list
-    let main_device_name = match args.device {
-        Some(arg_device) => { String::from(arg_device) }
+    // An idle link is a failure, but the end of a file is just the end
+    let watchdog_enabled = args.read_file.is_none() && (args.watchdog_silence_secs > 0 || args.watchdog_max_drop_percent.is_some());
+    let (mut cap, device_name) = match &args.read_file {
+        Some(path) => {
+            let cap = Capture::from_file(path)
+                .map_err(|error| Error::capture(format!("Failed to open pcap file {}", path.display()), error))?;
+            info!("Reading packets from {}", path.display());
+            (Capture::<dyn Activated>::from(cap), None)
+        }
         None => {
-            match Device::lookup() {
-                Err(error) => { panic!("Failed to get default pcap device: {}", error) }
-                Ok(device) => {
-                    device.name
+            let (cap, device_name) = open_device(args, watchdog_enabled)?;
+            (Capture::<dyn Activated>::from(cap), Some(device_name))
+        }
+    };
+    info!("Capture data-link: {{name: {:?},desc: {:?}}}",
+        cap.get_datalink().get_name().unwrap_or_default(),
+        cap.get_datalink().get_description().unwrap_or_default());
+
+    // Prepare filter (optional)
+    let filter = if args.vlan_trunk { bpf::with_vlan_tags(&args.filter) } else { args.filter.clone() };
+    let compile_error = |error| Error::capture("Failed to compile pcap filter", error);
+    let program = bpf::compile(cap.get_datalink(), &filter, args.optimize_filter).map_err(compile_error)?;
+    let other_program = bpf::compile(cap.get_datalink(), &filter, !args.optimize_filter).map_err(compile_error)?;
+    info!("Filter \"{}\" compiled to {} BPF instructions ({} {})", filter, program.get_instructions().len(),
+        other_program.get_instructions().len(), if args.optimize_filter { "without optimization" } else { "with --optimize-filter" });
+    if args.print_bpf {
+        for line in bpf::disassemble(&program) {
+            println!("{}", line);
+        }
+        return Ok(());
+    }
+    if args.filter_warmup_packets > 0 {
+        info!("Filter warm-up on {} packets, that are not tracked...", args.filter_warmup_packets);
+        let mut warmup = FilterWarmup::new(program);
+        while warmup.accepted + warmup.rejected < args.filter_warmup_packets {
+            match cap.next() {
+                Err(_) => { break; }
+                Ok(packet) => { warmup.check(packet.data) }
+            }
+        }
+        info!("Filter warm-up: {}", warmup);
+    }
+    cap.filter(&filter, args.optimize_filter).map_err(|error| Error::capture("Failed to apply pcap filter", error))?;
+
+    let mut connections = load_state(&args.state_file);
+    info!("Flow key: {}", args.flow_key.name());
+    connections.set_flow_keyer(args.flow_key.clone());
+    connections.set_latency_tracking(args.latency_sample_every, Duration::from_micros(args.latency_warn_us));
+    connections.set_packet_history(args.packet_history);
+    connections.set_conn_rate_limit(args.max_new_conns_per_sec);
+    connections.set_syn_flood_thresholds(args.syn_flood_half_open, args.syn_flood_rate);
+    connections.set_buffer_budget(args.buffer_budget_mb * 1024 * 1024);
+    connections.set_flow_buffer_limit(args.flow_buffer_limit as usize, args.flow_overflow);
+    connections.set_payload_preview(args.payload_preview.unwrap_or_default() as usize);
+    connections.set_midstream_pickup(args.midstream);
+    connections.set_checksum_check(!args.no_checksum_check);
+    connections.set_decap_depth(args.decap_depth);
+    let mtu = args.mtu.or_else(|| device_name.as_deref().and_then(interface_mtu));
+    info!("Interface MTU: {}", mtu.map(|mtu| mtu.to_string()).unwrap_or_else(|| String::from("unknown")));
+    connections.set_interface_mtu(mtu);
+    connections.set_ignore_rules(args.ignore_rules.clone());
+    connections.set_tag_rules(args.tag_rules.clone());
+    let analyzer_config = AnalyzerConfig::new(&args.analyzer_rules);
+    info!("Analyzers: {}", analyzer_config);
+    connections.set_analyzer_config(analyzer_config);
+    connections.set_alert_rules(args.alert_rules.clone());
+    if let Some(path) = &args.tls_keylog {
+        load_key_log(path, &mut connections)?;
+    }
+    connections.set_dns_names(args.dns_names);
+    if let Some(command) = &args.classifier_cmd {
+        connections.set_classifier(Box::new(CommandClassifier::spawn(command)?), args.classify_after_packets);
+    }
+    if let Some(path) = &args.classifier_model {
+        connections.set_classifier(load_classifier_model(path)?, args.classify_after_packets);
+    }
+    if args.latency_heatmap.is_some() {
+        connections.set_latency_heatmap(args.latency_heatmap_bucket_secs, args.latency_heatmap_prefix);
+    }
+    if let Some(secs) = args.line_rate_test_secs {
+        return run_line_rate_test(&mut cap, &mut connections, Duration::from_secs(secs));
+    }
+    let features_file = match &args.features_export {
+        None => { None }
+        Some(path) => {
+            let mut file = fs::File::create(path)?;
+            file.write_all(format!("{}\n", FEATURES_CSV_HEADER).as_bytes())?;
+            connections.set_feature_tracking(true);
+            info!("Writing connection features to {}", path.display());
+            Some(Arc::new(file))
+        }
+    };
+    if let Some(path) = &args.json_out {
+        connections.set_flow_log(FlowLog::create(path, args.export_time_format())?);
+        info!("Writing the connection log to {}", path.display());
+    }
+    if let Some(dir) = &args.conn_pcap_dir {
+        connections.set_conn_pcap(ConnPcap::create(dir, args.conn_pcap_rotate_mb * 1024 * 1024)?);
+        info!("Writing the frames of every connection to its own pcap file in {}", dir.display());
+    }
+    let connections = ConnShards::new(connections, args.worker_threads as usize);
+
+    let mut recorder = match &args.record {
+        None => { None }
+        Some(path) => {
+            let recorder = Recorder::create(path, device_name.as_deref(), args.snaplen as u32)?;
+            info!("Recording frames to {}", path.display());
+            Some(recorder)
+        }
+    };
+
+    let mut savefile = match &args.write_file {
+        None => { None }
+        Some(path) => {
+            // The snap length of a file that is read is not known, so it is the largest one
+            let snaplen = if args.read_file.is_some() { MAX_SNAPLEN } else { args.snaplen as u32 };
+            let rotate_interval = Some(Duration::from_secs(args.write_file_rotate_secs)).filter(|interval| !interval.is_zero());
+            let savefile = SavefileWriter::create(path, snaplen, cap.get_datalink().0 as u32,
+                args.write_file_rotate_mb * 1024 * 1024, rotate_interval)?;
+            info!("Writing the captured frames to {}", path.display());
+            Some(savefile)
+        }
+    };
+
+    let mut flight_recorder = args.flight_recorder_dir.as_ref().map(|dir| {
+        info!("Flight recorder keeps the last {}MB of frames, dumped to {} on alerts", args.flight_recorder_mb, dir.display());
+        FlightRecorder::new(dir, args.flight_recorder_mb * 1024 * 1024)
+    });
+
+    // Fire up the threads that consume ready buffers, and a thread to take the buffers and dispatch them to the consumers
+    let (consumers, all_consumer_stats) = Consumers::spawn(args.consumer_threads as usize, &args.consumer_cpus);
+    let connections_clone = connections.clone();
+    let consumers_clone = consumers.clone();
+    let (ready_sender, ready_receiver) = channel();
+    for table in connections.tables() {
+        table.lock().unwrap().set_ready_notifier(args.ready_bytes, ready_sender.clone());
+    }
+    drop(ready_sender);
+    thread::spawn(move || {
+        dispatch_ready_buffers(&connections_clone, &ready_receiver, &consumers_clone);
+    });
+
+    // Fire up a thread to fail handshakes that timed out, and to finalize and remove closed and idle connections
+    let connections_clone = connections.clone();
+    let timeouts = ConnTimeouts {
+        idle: Duration::from_secs(args.idle_timeout_secs),
+        established: Duration::from_secs(args.established_timeout_secs),
+        closing: Duration::from_secs(args.closing_timeout_secs),
+        closed: Duration::from_secs(args.close_grace_secs),
+        handshake: Duration::from_secs(args.handshake_timeout_secs),
+    };
+    let mut exporter = args.export_to.as_ref().map(|addr| {
+        let probe_name = args.probe_name.clone().unwrap_or_else(host_name);
+        info!("Exporting flow records to {} as probe {}", addr, probe_name);
+        FlowExporter::new(addr, &probe_name)
+    });
+    let mut netflow_exporter = match &args.netflow_to {
+        None => { None }
+        Some(addr) => {
+            info!("Exporting flow records to {} as {}", addr, args.netflow_version);
+            Some(NetflowExporter::new(addr, args.netflow_version, args.netflow_domain_id)?)
+        }
+    };
+    let features_file_clone = features_file.clone();
+    let live = args.read_file.is_none();
+    thread::spawn(move || {
+        reap_closed_connections(&connections_clone, &consumers, &timeouts, live, &mut exporter,
+                                &mut netflow_exporter, features_file_clone.as_deref());
+    });
+
+    if let Some(path) = &args.control_socket {
+        control::serve(path, connections.clone(), args.export_time_format())?;
+        info!("Listening for control commands on {}", path.display());
+    }
+
+    if let Some(addr) = &args.http_listen {
+        http::serve(addr, connections.clone())?;
+        info!("Serving HTTP on {}", addr);
+    }
+
+    if args.memory_report_secs > 0 {
+        let interval = Duration::from_secs(args.memory_report_secs);
+        let all_consumer_stats = all_consumer_stats.clone();
+        let connections_clone = connections.clone();
+        thread::spawn(move || {
+            loop {
+                thread::sleep(interval);
+                info!("Memory: {}", MemStats::collect());
+                for stats in &all_consumer_stats {
+                    info!("Consumer {}", stats.lock().unwrap());
+                }
+                info!("Buffered: {}", connections_clone.buffer_watermarks());
+                if let Some(budget) = connections_clone.buffer_budget() {
+                    info!("Buffer budget: {}", budget);
+                }
+                let suppressed = connections_clone.take_suppressed_conns();
+                if suppressed > 0 {
+                    warn!("Suppressed {} new flows in the last {}s over the connection rate limit", suppressed, interval.as_secs());
+                }
+                log_hosts(&connections_clone.hosts());
+            }
+        });
+    }
+
+    // Fire up the threads that process the captured packets, which report their alerts back for the flight recorder
+    let (alert_sender, alert_receiver) = channel();
+    let workers = Workers::spawn(&connections, &args.worker_cpus, flight_recorder.as_ref().map(|_| alert_sender));
+    info!("Processing packets in {} worker threads", connections.count());
+
+    pin_thread("capture", &args.capture_cpus);
+
+    let mut watchdog = if let (true, Some(device_name)) = (watchdog_enabled, &device_name) {
+        info!("Capture watchdog: silence of {}s, drops over {}%, {}", args.watchdog_silence_secs,
+            args.watchdog_max_drop_percent.map(|percent| percent.to_string()).unwrap_or_else(|| String::from("none")),
+            if args.watchdog_exit { "exit on alarm" } else { "log on alarm" });
+        Some(Watchdog::new(device_name, Duration::from_secs(args.watchdog_silence_secs), args.watchdog_max_drop_percent))
+    } else {
+        None
+    };
+    // The flow exporter belongs to the reaper thread, so alarms are sent over their own connection to the collector
+    let mut health_exporter = args.export_to.as_ref().filter(|_| watchdog_enabled)
+        .map(|addr| FlowExporter::new(addr, &args.probe_name.clone().unwrap_or_else(host_name)));
+    let mut watchdog_alarm = None;
+    let mut stats_reporter = Some(args.stats_secs).filter(|secs| *secs > 0)
+        .map(|secs| StatsReporter::new(Duration::from_secs(secs)));
+    let mut packet_count = 0u64;
+    let mut byte_count = 0u64;
+
+    loop {
+        if shutdown::is_requested() {
+            info!("Shutting down on a signal after {} packets, processing the queued packets", packet_count);
+            break;
+        }
+        if let Some(watchdog) = watchdog.as_mut().filter(|watchdog| watchdog.is_due()) {
+            for alarm in watchdog.check(packet_count, cap.stats().ok()) {
+                error!("Capture watchdog alarm: {}", alarm);
+                if let Some(exporter) = &mut health_exporter {
+                    exporter.export_health(alarm.to_json());
+                }
+                if args.watchdog_exit {
+                    watchdog_alarm = Some(alarm);
                 }
             }
+            if watchdog_alarm.is_some() {
+                break;
+            }
+        }
+        if let Some(stats_reporter) = stats_reporter.as_mut().filter(|reporter| reporter.is_due()) {
+            info!("Stats: {}", stats_reporter.report(packet_count, byte_count, connections.report(), cap.stats().ok()));
+        }
+        let packet = match cap.next() {
+            Ok(packet) => { packet }
+            // The read timeout is there to check the watchdog and the shutdown signal on an idle link
+            Err(pcap::Error::TimeoutExpired) => { continue; }
+            Err(_) => { break; }
+        };
+        if packet_count == 0 {
+            connections.set_capture_start(capture_ts(packet.header));
+        }
+        packet_count += 1;
+        byte_count += packet.header.len as u64;
+        if let Some(rec) = &mut recorder {
+            if let Err(error) = rec.record(&packet) {
+                error!("Failed to record frame, recording stopped after {} frames: {}", rec.frame_count(), error);
+                recorder = None;
+            }
+        }
+        if let Some(writer) = &mut savefile {
+            if let Err(error) = writer.write(&packet) {
+                error!("Failed to write frame to the savefile, writing stopped after {}: {}", writer, error);
+                savefile = None;
+            }
+        }
+        if let Some(flight_recorder) = &mut flight_recorder {
+            flight_recorder.push(&packet);
+            for (conn_sequence, conn_sign) in alert_receiver.try_iter() {
+                flight_recorder.dump(conn_sequence, conn_sign);
+            }
+        }
+        workers.dispatch(&packet);
+    }
+
+    workers.join();
+    if let Some(flight_recorder) = flight_recorder {
+        flight_recorder.finish();
+    }
+    if let Some(mut writer) = savefile {
+        match writer.flush() {
+            Err(error) => { error!("Failed to write the savefile: {}", error) }
+            Ok(_) => { info!("Savefile: {}", writer) }
+        }
+    }
+    let mut connections = connections.merge();
+    connections.flush_outputs();
+
+    if let Some(state_file) = &args.state_file {
+        match connections.save_snapshot(state_file, !args.state_no_payload) {
+            Err(error) => { error!("Failed to save state to {}: {}", state_file.display(), error) }
+            Ok(_) => { info!("Saved state to {}", state_file.display()) }
+        }
+    }
+
+    if let Some(path) = &args.latency_heatmap {
+        if let Err(error) = write_latency_heatmap(path, &connections, &args.export_time_format()) {
+            error!("Failed to write the latency heatmap to {}: {}", path.display(), error);
+        }
+    }
+    if let Some(path) = &args.retransmit_timeline {
+        if let Err(error) = write_retransmit_timeline(path, &connections, &args.export_time_format()) {
+            error!("Failed to write the retransmission timeline to {}: {}", path.display(), error);
+        }
+    }
+    connections.classify_remaining();
+    if let Some(features_file) = &features_file {
+        if let Err(error) = (&**features_file).write_all(connections.export_features(false).as_bytes()) {
+            error!("Failed to write the connection features: {}", error);
+        }
+    }
+
+    info!("Packet processing time: {}", connections.latency());
+    info!("Connection rate limit: {}", connections.conn_rate_limiter());
+    if let Some(detector) = connections.syn_flood() {
+        info!("SYN flood detection: {}", detector);
+    }
+    if !args.ignore_rules.is_empty() {
+        info!("Ignored {} packets by ignore rules", connections.ignored_packet_count());
+    }
+    if let Some(classifier) = connections.classifier() {
+        info!("Classifier: {}", classifier);
+    }
+    let nat_duplicate_count = connections.nat_duplicate_count();
+    if nat_duplicate_count > 0 {
+        info!("Connections seen twice through a NAT, counted once: {}", nat_duplicate_count);
+    }
+    let evicted_count = connections.evicted_count();
+    if evicted_count > 0 {
+        info!("Connections evicted for going idle: {}", evicted_count);
+    }
+    let ipv4_fragment_stats = connections.ipv4_fragment_stats();
+    if ipv4_fragment_stats.fragments > 0 {
+        info!("IPv4 fragments: {}", ipv4_fragment_stats);
+    }
+    let ipv6_stats = connections.ipv6_stats();
+    if ipv6_stats.packets > 0 {
+        info!("IPv6: {}", ipv6_stats);
+    }
+    if let Some(line_count) = connections.flow_log_line_count() {
+        info!("Connection log: {} lines", line_count);
+    }
+    if let Some(conn_pcap) = connections.conn_pcap() {
+        info!("Per connection pcap files: {}", conn_pcap);
+    }
+    info!("Memory: {}", MemStats::collect());
+    for stats in &all_consumer_stats {
+        info!("Consumer {}", stats.lock().unwrap());
+    }
+    info!("Buffered: {}", connections.buffer_watermarks());
+    if let Some(budget) = connections.buffer_budget() {
+        info!("Buffer budget: {}", budget);
+    }
+    log_hosts(connections.hosts());
+    info!("Final report: {}", connections.report());
+    info!("End pcap_test.");
+    match watchdog_alarm {
+        None => { Ok(()) }
+        Some(alarm) => { Err(Error::Unhealthy(alarm.to_string())) }
+    }
+}
+
+/// Open the specified or default device for a live capture, returning the capture with the name of the device
+fn open_device(args: &Cli, watchdog_enabled: bool) -> Result<(Capture<Active>, String), Error> {
+    // Get the default device name, to be used later when looking at the device list
+    let main_device_name = match &args.device {
+        Some(arg_device) => { arg_device.to_owned() }
+        None => {
+            Device::lookup().map_err(|error| Error::capture("Failed to get default pcap device", error))?.name
         }
     };
 
     let mut main_device: Option<Device> = None;
-    let device_list = Device::list().expect("Failed to get device list");
+    let device_list = Device::list().map_err(|error| Error::capture("Failed to get device list", error))?;
     info!("Device list has {} elements. Those with addresses displayed in TRACE log level.", device_list.len());
     for cur_device in device_list {
         if cur_device.name.eq(&main_device_name) { main_device = Some(cur_device.to_owned()); }
         if !log_enabled!(Level::Trace) { continue; }
-        if cur_device.addresses.len() <= 0 { continue; }
+        if cur_device.addresses.is_empty() { continue; }
         trace!("   Device '{}' = {} ({} addresses)", cur_device.name,
                  cur_device.desc.unwrap_or(String::from("unknown")),
                  cur_device.addresses.len());
@@ -60,55 +1063,283 @@ fn main() {
         }
     }
 
-    if main_device.is_none() {
-        panic!("Failed to find a (specified or default) device. \
-        Consider running with RUST_LOG=\"trace\" and watch the device list carefully.");
-    }
+    let main_device = main_device.ok_or_else(|| Error::Parse(format!("Failed to find a (specified or default) device '{}'. \
+        Consider running with RUST_LOG=\"trace\" and watch the device list carefully.", main_device_name)))?;
+
+    let open_error = |error| Error::capture(format!("Failed to open pcap device {}", main_device_name), error);
+    let mut cap = Capture::from_device(main_device).map_err(open_error)?
+        .promisc(!args.no_promisc)
+        .immediate_mode(!args.no_immediate)
+        .snaplen(args.snaplen)
+        .buffer_size(args.buffer_size);
+    let required_timeout = if args.line_rate_test_secs.is_some() {
+        // The test has to end on time even if the link is idle
+        LINE_RATE_TEST_READ_TIMEOUT_MS
+    } else if watchdog_enabled {
+        WATCHDOG_READ_TIMEOUT_MS
+    } else {
+        // A shutdown signal has to be noticed even if the link is idle
+        SHUTDOWN_READ_TIMEOUT_MS
+    };
+    let timeout = args.timeout.map_or(required_timeout, |timeout| timeout.min(required_timeout));
+    cap = cap.timeout(timeout);
+    info!("Capturing {} with snaplen {}, {} byte buffer, promiscuous {}, immediate {}, read timeout {}ms", main_device_name,
+          args.snaplen, args.buffer_size, !args.no_promisc, !args.no_immediate, timeout);
+    let cap: Capture<Active> = cap.open().map_err(open_error)?;
+    cap.direction(Direction::InOut).map_err(|error| Error::capture("Failed to set pcap direction", error))?;
+    Ok((cap, main_device_name))
+}
 
-    let mut cap: Capture<Active> =
-        {
-            match Capture::from_device(main_device.unwrap()).unwrap()
-                .promisc(true)
-                .immediate_mode(true)
-                .snaplen(65535)
-                .buffer_size(10000000)
-                .open() {
-                Err(error) => { panic!("Failed to open pcap device {}: {}", main_device_name, error) }
-                Ok(cap) => {
-                    info!("Capture data-link: {{name: {:?},desc: {:?}}}",
-                        cap.get_datalink().get_name().unwrap(),
-            cap.get_datalink().get_description().unwrap());
-                    cap
+/// Run an offline command that does not capture traffic
+fn run_command(command: &Command, time_format: &TimeFormatter) -> Result<(), Error> {
+    match command {
+        Command::DumpState { state_file, output, no_payload, payload_digests } => {
+            let connections = Connections::load_snapshot(state_file)?;
+            let payload = match (no_payload, payload_digests) {
+                (true, _) => { PayloadExport::Omit }
+                (false, Some(mode)) => { PayloadExport::Digests(mode.clone()) }
+                (false, None) => { PayloadExport::Hex }
+            };
+            let json = connections.to_json(&payload, time_format).to_pretty_string();
+            match output {
+                Some(path) => {
+                    fs::write(path, json + "\n")?;
+                    info!("Dumped {} connections to {}", connections.len(), path.display());
                 }
+                None => { println!("{}", json) }
             }
-        };
+        }
+        Command::LoadState { json_file, output } => {
+            let json = JsonValue::parse(&fs::read_to_string(json_file)?)?;
+            let connections = Connections::from_json(&json)?;
+            connections.save_snapshot(output, true)?;
+            info!("Loaded {} connections into {}", connections.len(), output.display());
+        }
+        Command::SelfTest => {
+            self_test::run()?;
+        }
+        Command::Collect { listen, output, write_secs } => {
+            collector::collect(listen, output.clone(), Duration::from_secs((*write_secs).max(1)))?;
+        }
+        Command::Replay { recordings, time_offset, estimate_offsets, output, ladder, time_sequence, retransmit_timeline,
+            features_export, classifier_cmd, classifier_model, classify_after_packets, tls_keylog,
+            dns_names, analyzer_rules, alert_rules, flight_recorder_dir, flight_recorder_mb, conn_pcap_dir, conn_pcap_rotate_mb, latency_heatmap,
+            latency_heatmap_bucket_secs, latency_heatmap_prefix, max_new_conns_per_sec, syn_flood_half_open, syn_flood_rate, midstream, no_checksum_check, decap_depth, payload_preview, mtu,
+            ignore_rules, tag_rules, flow_key, export_to, netflow_to, netflow_version, netflow_domain_id, probe_name } => {
+            let mut connections = Connections::new();
+            connections.set_flow_keyer(flow_key.clone());
+            connections.set_analyzer_config(AnalyzerConfig::new(analyzer_rules));
+            connections.set_alert_rules(alert_rules.clone());
+            if let Some(path) = tls_keylog {
+                load_key_log(path, &mut connections)?;
+            }
+            connections.set_dns_names(*dns_names);
+            connections.set_conn_rate_limit(*max_new_conns_per_sec);
+            connections.set_syn_flood_thresholds(*syn_flood_half_open, *syn_flood_rate);
+            connections.set_payload_preview(payload_preview.unwrap_or_default() as usize);
+            connections.set_midstream_pickup(*midstream);
+            connections.set_checksum_check(!*no_checksum_check);
+            connections.set_decap_depth(*decap_depth);
+            connections.set_interface_mtu(*mtu);
+            connections.set_ignore_rules(ignore_rules.clone());
+            connections.set_tag_rules(tag_rules.clone());
+            if latency_heatmap.is_some() {
+                connections.set_latency_heatmap(*latency_heatmap_bucket_secs, *latency_heatmap_prefix);
+            }
+            connections.set_feature_tracking(features_export.is_some());
+            if let Some(command) = classifier_cmd {
+                connections.set_classifier(Box::new(CommandClassifier::spawn(command)?), *classify_after_packets);
+            }
+            if let Some(path) = classifier_model {
+                connections.set_classifier(load_classifier_model(path)?, *classify_after_packets);
+            }
+            if !ladder.is_empty() || time_sequence.is_some() {
+                connections.set_packet_history(REPLAY_PACKET_HISTORY);
+            }
+            if let Some(dir) = conn_pcap_dir {
+                connections.set_conn_pcap(ConnPcap::create(dir, conn_pcap_rotate_mb * 1024 * 1024)?);
+            }
+            let offsets: HashMap<usize, f64> = time_offset.iter().cloned().collect();
+            let mut flight_recorder = flight_recorder_dir.as_ref()
+                .map(|dir| FlightRecorder::new(dir, flight_recorder_mb * 1024 * 1024));
+            let frame_count = replay::replay(recordings, &offsets, *estimate_offsets, &mut connections,
+                                             &mut flight_recorder)?;
+            if let Some(flight_recorder) = flight_recorder {
+                flight_recorder.finish();
+            }
+            connections.classify_remaining();
+            connections.flush_outputs();
+            info!("Replayed {} frames from {} recordings, resulting in {} connections", frame_count, recordings.len(),
+                connections.len());
+            if !ignore_rules.is_empty() {
+                info!("Ignored {} packets by ignore rules", connections.ignored_packet_count());
+            }
+            if connections.ipv4_fragment_stats().fragments > 0 {
+                info!("IPv4 fragments: {}", connections.ipv4_fragment_stats());
+            }
+            if connections.ipv6_stats().packets > 0 {
+                info!("IPv6: {}", connections.ipv6_stats());
+            }
+            if connections.conn_rate_limiter().suppressed() > 0 {
+                info!("Connection rate limit: {}", connections.conn_rate_limiter());
+            }
+            if let Some(detector) = connections.syn_flood() {
+                info!("SYN flood detection: {}", detector);
+            }
+            if let Some(classifier) = connections.classifier() {
+                info!("Classifier: {}", classifier);
+            }
+            if connections.nat_duplicate_count() > 0 {
+                info!("Connections seen twice through a NAT, counted once: {}", connections.nat_duplicate_count());
+            }
+            if let Some(conn_pcap) = connections.conn_pcap() {
+                info!("Per connection pcap files: {}", conn_pcap);
+            }
+            if let Some(path) = output {
+                fs::write(path, connections.to_json(&PayloadExport::Hex, time_format).to_pretty_string() + "\n")?;
+            }
+            if let Some(path) = time_sequence {
+                let json = path.extension().is_some_and(|extension| extension == "json");
+                fs::write(path, connections.export_time_sequence(json, time_format))?;
+            }
+            if let Some(path) = latency_heatmap {
+                write_latency_heatmap(path, &connections, time_format)?;
+            }
+            if let Some(path) = retransmit_timeline {
+                write_retransmit_timeline(path, &connections, time_format)?;
+            }
+            if let Some(path) = features_export {
+                fs::write(path, connections.export_features(true))?;
+                info!("Wrote the connection features to {}", path.display());
+            }
+            if let Some(addr) = export_to {
+                let mut exporter = FlowExporter::new(addr, &probe_name.clone().unwrap_or_else(host_name));
+                for summary in connections.summaries() {
+                    exporter.export(&summary);
+                }
+            }
+            if let Some(addr) = netflow_to {
+                let mut exporter = NetflowExporter::new(addr, *netflow_version, *netflow_domain_id)?;
+                for summary in connections.summaries() {
+                    exporter.export(&summary);
+                }
+                exporter.flush();
+                info!("Exported {}", exporter);
+            }
+            for conn_sequence in ladder {
+                match connections.find_by_sequence(*conn_sequence) {
+                    None => { warn!("Connection {} is not in the table", conn_sequence) }
+                    Some(conn) => { println!("{}\n", conn.ladder()) }
+                }
+            }
+        }
+    }
+    Ok(())
+}
 
-    // Prepare filter (optional)
-    cap.filter(&args.filter, false).expect("Failed to apply pcap filter");
-    cap.direction(Direction::InOut).expect("Failed to set pcap direction");
+/// Name of this host, for naming the probe in exported flow records
+fn host_name() -> String {
+    fs::read_to_string("/proc/sys/kernel/hostname").ok()
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| String::from("probe"))
+}
 
-    let connections: Arc<Mutex<Connections>> = Arc::new(Mutex::new(Connections::new()));
+/// MTU of a network interface, from sysfs, if it can be read
+fn interface_mtu(name: &str) -> Option<u16> {
+    fs::read_to_string(format!("/sys/class/net/{}/mtu", name)).ok()
+        .and_then(|mtu| mtu.trim().parse::<u32>().ok())
+        .map(|mtu| mtu.min(u16::MAX as u32) as u16)
+}
 
-    // Fire up a thread to consume ready buffers
-    let connections_clone = connections.clone();
-    thread::spawn(move || {
-        consume_ready_buffers(&connections_clone);
-    });
+/// Load a TLS key log file for matching the TLS connections with their secrets
+#[cfg(feature = "onnx")]
+fn load_classifier_model(path: &Path) -> Result<Box<dyn Classifier>, Error> {
+    let model = OnnxClassifier::load(path)?;
+    info!("Loaded the classification model {}", path.display());
+    Ok(Box::new(model))
+}
 
+#[cfg(not(feature = "onnx"))]
+fn load_classifier_model(path: &Path) -> Result<Box<dyn Classifier>, Error> {
+    let message = format!("Cannot load {}: ONNX models require building with the \"onnx\" feature", path.display());
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, message).into())
+}
+
+fn load_key_log(path: &Path, connections: &mut Connections) -> Result<(), Error> {
+    let key_log = KeyLog::load(path)?;
+    info!("Loaded secrets of {} TLS sessions from {}", key_log.session_count(), path.display());
+    connections.set_key_log(key_log);
+    Ok(())
+}
 
-    while let Ok(packet) = cap.next() {
-        connections.lock().unwrap().process_packet(&packet);
+/// Parse a per-source clock offset in the form "index=seconds"
+fn parse_time_offset(arg: &str) -> Result<(usize, f64), String> {
+    let (index, seconds) = arg.split_once('=').ok_or("expected index=seconds")?;
+    Ok((index.trim().parse().map_err(|_| "invalid source index")?,
+        seconds.trim().parse().map_err(|_| "invalid number of seconds")?))
+}
+
+/// Parse a percent from 0 to 100, such as "0.5"
+fn parse_percent(arg: &str) -> Result<f64, String> {
+    match arg.trim().parse::<f64>() {
+        Ok(percent) if (0.0..=100.0).contains(&percent) => { Ok(percent) }
+        _ => { Err(format!("invalid percent '{}', expected a number from 0 to 100", arg)) }
     }
+}
 
-    info!("End pcap_test.");
+/// Restore the connection table from the state file if one is given and exists, or start with an empty table.
+fn load_state(state_file: &Option<PathBuf>) -> Connections {
+    match state_file {
+        Some(path) if path.exists() => {
+            match Connections::load_snapshot(path) {
+                Err(error) => {
+                    error!("Failed to load state from {}, starting with an empty table: {}", path.display(), error);
+                    Connections::new()
+                }
+                Ok(connections) => {
+                    info!("Loaded {} connections from {}", connections.len(), path.display());
+                    connections
+                }
+            }
+        }
+        _ => { Connections::new() }
+    }
 }
 
-fn consume_ready_buffers(connections: &Arc<Mutex<Connections>>) {
-    loop {
-        let mut lock = connections.lock().unwrap();
-        let ready_buffers = lock.get_connections_by_rules(true, 32000);
-        std::mem::drop(lock);
-        //TODO actually consume the buffers
-        thread::sleep(Duration::from_millis(10));
+#[cfg(test)]
+mod tests {
+    use clap::CommandFactory;
+    use pcap_test::config;
+    use super::{Cli, config_defaults, OptionDefault};
+
+    fn defaults(text: &str) -> Result<Vec<OptionDefault>, String> {
+        config_defaults(&Cli::command(), &config::parse(text).unwrap())
+    }
+
+    #[test]
+    fn config_settings_become_option_defaults() {
+        let text = "snaplen = 1500\nno-promisc = true\n[replay]\nestimate_offsets = true\nladder = [1, 2]\n";
+        assert_eq!(defaults(text).unwrap(), vec![
+            (None, "snaplen".to_string(), vec!["1500".to_string()]),
+            (None, "no_promisc".to_string(), vec!["true".to_string()]),
+            (Some("replay".to_string()), "estimate_offsets".to_string(), vec!["true".to_string()]),
+            (Some("replay".to_string()), "ladder".to_string(), vec!["1".to_string(), "2".to_string()]),
+        ]);
+    }
+
+    #[test]
+    fn unknown_config_keys_are_refused_with_their_line() {
+        assert_eq!(defaults("snaplen = 1500\nno_such_option = 1\n").unwrap_err(), "line 2: unknown option \"no_such_option\"");
+        assert_eq!(defaults("[no_such_command]\nsnaplen = 1\n").unwrap_err(), "line 2: unknown command [no-such-command]");
+        assert_eq!(defaults("config = 'other.toml'\n").unwrap_err(), "line 1: unknown option \"config\"");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn config_values_are_checked_as_options() {
+        assert_eq!(defaults("no_promisc = 1\n").unwrap_err(), "line 1: \"no_promisc\" is true or false");
+        assert_eq!(defaults("snaplen = [1, 2]\n").unwrap_err(), "line 1: \"snaplen\" has a single value");
+        assert!(defaults("snaplen = 'many'\n").unwrap_err().starts_with("line 1: invalid value 'many' for '--snaplen"));
+    }
+}
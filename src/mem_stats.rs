@@ -0,0 +1,176 @@
+use std::fmt;
+
+#[cfg(feature = "alloc-stats")]
+use std::alloc::{GlobalAlloc, Layout, System};
+#[cfg(feature = "alloc-stats")]
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Bytes currently allocated on the heap
+#[cfg(feature = "alloc-stats")]
+static ALLOCATED: AtomicU64 = AtomicU64::new(0);
+/// High watermark of [ALLOCATED]
+#[cfg(feature = "alloc-stats")]
+static PEAK_ALLOCATED: AtomicU64 = AtomicU64::new(0);
+/// All time number of allocations
+#[cfg(feature = "alloc-stats")]
+static ALLOCATION_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// The system allocator, counting the allocated bytes, for the binary to install with `#[global_allocator]`.
+/// The counters are relaxed atomics, which is cheap enough to keep on in production.
+#[cfg(feature = "alloc-stats")]
+pub struct CountingAllocator;
+
+#[cfg(feature = "alloc-stats")]
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            ALLOCATION_COUNT.fetch_add(1, Ordering::Relaxed);
+            let allocated = ALLOCATED.fetch_add(layout.size() as u64, Ordering::Relaxed) + layout.size() as u64;
+            PEAK_ALLOCATED.fetch_max(allocated, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        ALLOCATED.fetch_sub(layout.size() as u64, Ordering::Relaxed);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = System.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            ALLOCATION_COUNT.fetch_add(1, Ordering::Relaxed);
+            if new_size > layout.size() {
+                let grow = (new_size - layout.size()) as u64;
+                let allocated = ALLOCATED.fetch_add(grow, Ordering::Relaxed) + grow;
+                PEAK_ALLOCATED.fetch_max(allocated, Ordering::Relaxed);
+            } else {
+                ALLOCATED.fetch_sub((layout.size() - new_size) as u64, Ordering::Relaxed);
+            }
+        }
+        new_ptr
+    }
+}
+
+/// Process memory statistics. The resident size is only available on Linux, the malloc statistics with glibc,
+/// and the allocation counters with the "alloc-stats" feature, once the binary installs the `CountingAllocator`.
+#[derive(Clone, Debug, Default)]
+pub struct MemStats {
+    /// Resident set size of the process
+    pub resident_bytes: Option<u64>,
+    /// Bytes currently allocated on the heap
+    pub allocated_bytes: Option<u64>,
+    /// High watermark of the allocated bytes
+    pub peak_allocated_bytes: Option<u64>,
+    /// All time number of allocations, including reallocations
+    pub allocation_count: Option<u64>,
+    /// Bytes of the chunks that malloc handed out, including its per-chunk overhead and mmapped chunks
+    pub malloc_in_use_bytes: Option<u64>,
+    /// Bytes of the free chunks that malloc keeps in its arenas, instead of returning them to the system
+    pub malloc_free_bytes: Option<u64>,
+}
+
+impl MemStats {
+    /// Take a snapshot of the current memory statistics
+    pub fn collect() -> MemStats {
+        let (allocated_bytes, peak_allocated_bytes, allocation_count) = allocation_counters();
+        let (malloc_in_use_bytes, malloc_free_bytes) = malloc_stats();
+        MemStats {
+            resident_bytes: resident_bytes(),
+            allocated_bytes,
+            peak_allocated_bytes,
+            allocation_count,
+            malloc_in_use_bytes,
+            malloc_free_bytes,
+        }
+    }
+
+    /// Share of the memory that malloc holds in free chunks (0 to 1), which it cannot return to the system
+    /// while used chunks sit above them
+    pub fn fragmentation(&self) -> Option<f64> {
+        match (self.malloc_in_use_bytes, self.malloc_free_bytes) {
+            (Some(in_use), Some(free)) if in_use + free > 0 => { Some(free as f64 / (in_use + free) as f64) }
+            _ => { None }
+        }
+    }
+}
+
+impl fmt::Display for MemStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mb = |bytes: Option<u64>| -> String {
+            match bytes {
+                Some(b) => { format!("{:.1}MB", b as f64 / 1_000_000f64) }
+                None => { String::from("n/a") }
+            }
+        };
+        write!(f, "resident {}, allocated {} (peak {}), allocations {}, malloc in use {} and free {}, fragmentation {}",
+               mb(self.resident_bytes), mb(self.allocated_bytes), mb(self.peak_allocated_bytes),
+               self.allocation_count.map_or(String::from("n/a"), |c| c.to_string()),
+               mb(self.malloc_in_use_bytes), mb(self.malloc_free_bytes),
+               self.fragmentation().map_or(String::from("n/a"), |r| format!("{:.1}%", r * 100f64)))
+    }
+}
+
+/// Allocated bytes, their peak and the number of allocations, unless the `CountingAllocator` was not installed
+/// (so nothing was counted)
+#[cfg(feature = "alloc-stats")]
+fn allocation_counters() -> (Option<u64>, Option<u64>, Option<u64>) {
+    match ALLOCATION_COUNT.load(Ordering::Relaxed) {
+        0 => { (None, None, None) }
+        count => { (Some(ALLOCATED.load(Ordering::Relaxed)), Some(PEAK_ALLOCATED.load(Ordering::Relaxed)), Some(count)) }
+    }
+}
+
+#[cfg(not(feature = "alloc-stats"))]
+fn allocation_counters() -> (Option<u64>, Option<u64>, Option<u64>) {
+    (None, None, None)
+}
+
+/// Resident set size, from the second field of /proc/self/statm (in pages)
+#[cfg(target_os = "linux")]
+fn resident_bytes() -> Option<u64> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    // sysconf has no side effects
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    if page_size <= 0 {
+        return None;
+    }
+    Some(pages * page_size as u64)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn resident_bytes() -> Option<u64> {
+    None
+}
+
+/// Bytes in use and free in the arenas of glibc malloc, which is the system allocator, summed over all arenas
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+fn malloc_stats() -> (Option<u64>, Option<u64>) {
+    // mallinfo2 only reads the arenas, under their locks
+    let info = unsafe { libc::mallinfo2() };
+    (Some((info.uordblks + info.hblkhd) as u64), Some(info.fordblks as u64))
+}
+
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+fn malloc_stats() -> (Option<u64>, Option<u64>) {
+    (None, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MemStats;
+
+    #[test]
+    fn fragmentation_is_the_free_share_of_malloc() {
+        let stats = MemStats { malloc_in_use_bytes: Some(300), malloc_free_bytes: Some(100), ..Default::default() };
+        assert_eq!(stats.fragmentation(), Some(0.25));
+        assert_eq!(MemStats { resident_bytes: Some(1000), ..Default::default() }.fragmentation(), None);
+        #[cfg(all(target_os = "linux", target_env = "gnu"))]
+        {
+            let buffer = vec![1u8; 1 << 20];
+            assert!(MemStats::collect().malloc_in_use_bytes.unwrap() >= buffer.len() as u64);
+        }
+    }
+}
@@ -0,0 +1,362 @@
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use log::{info, warn};
+use crate::conn::ConnSummary;
+use crate::flow_buff::FlowActivity;
+
+/// Max length of an export packet, so it is not fragmented on a typical path
+const MAX_MESSAGE_LEN: usize = 1400;
+/// The templates are sent again after this number of export packets, or this time, whichever comes first, so a
+/// collector that restarts learns them again (RFC 7011 section 10.3.6)
+const TEMPLATE_REFRESH_PACKETS: u32 = 20;
+const TEMPLATE_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+const IPV4_TEMPLATE_ID: u16 = 256;
+const IPV6_TEMPLATE_ID: u16 = 257;
+/// Fields of the templates, as IANA information element types and lengths, that NetFlow v9 shares with IPFIX:
+/// addresses, ports, protocol, TCP flags, octets, packets and the first and last packet times in epoch milliseconds
+const IPV4_FIELDS: [(u16, u16); 10] = [(8, 4), (12, 4), (7, 2), (11, 2), (4, 1), (6, 1), (1, 8), (2, 8), (152, 8), (153, 8)];
+const IPV6_FIELDS: [(u16, u16); 10] = [(27, 16), (28, 16), (7, 2), (11, 2), (4, 1), (6, 1), (1, 8), (2, 8), (152, 8), (153, 8)];
+const PROTOCOL_TCP: u8 = 6;
+
+/// Format of the export packets
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NetflowVersion {
+    /// NetFlow version 9 (RFC 3954)
+    V9,
+    /// IPFIX (RFC 7011)
+    Ipfix,
+}
+
+impl NetflowVersion {
+    /// Parse a format name: "v9" or "ipfix"
+    pub fn parse(arg: &str) -> Result<Self, String> {
+        match arg.trim() {
+            "v9" => { Ok(NetflowVersion::V9) }
+            "ipfix" => { Ok(NetflowVersion::Ipfix) }
+            other => { Err(format!("unknown format \"{}\", expected v9 or ipfix", other)) }
+        }
+    }
+
+    fn header_len(&self) -> usize {
+        match self {
+            NetflowVersion::V9 => { 20 }
+            NetflowVersion::Ipfix => { 16 }
+        }
+    }
+}
+
+impl fmt::Display for NetflowVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NetflowVersion::V9 => { write!(f, "NetFlow v9") }
+            NetflowVersion::Ipfix => { write!(f, "IPFIX") }
+        }
+    }
+}
+
+/// Send flow records to a NetFlow v9 or IPFIX collector over UDP, so existing collectors can ingest them. Every
+/// connection makes a record per direction that sent packets, with its addresses, ports, octets, packets, TCP flags
+/// and the times of its first and last packets. The records are kept until [NetflowExporter::flush], which packs them
+/// into as few packets as fit, with the templates sent along periodically.
+/// Packets that cannot be sent, while the collector is down, are dropped and counted.
+pub struct NetflowExporter {
+    socket: UdpSocket,
+    addr: SocketAddr,
+    version: NetflowVersion,
+    /// Observation domain (IPFIX) or source ID (NetFlow v9) of the records
+    domain_id: u32,
+    /// When the exporter started, for the system uptime of NetFlow v9
+    start_time: Instant,
+    /// Export packets sent (NetFlow v9), or data records sent (IPFIX)
+    sequence: u32,
+    /// Records that were not sent yet, by template
+    pending: Vec<(u16, Vec<u8>)>,
+    /// When the templates were last sent, and the number of packets since, if they were sent
+    templates_sent: Option<(Instant, u32)>,
+    record_count: u64,
+    packet_count: u64,
+    failed_packet_count: u64,
+    /// Whether the last send failed, so a failure is logged once until the collector is back
+    failing: bool,
+}
+
+impl NetflowExporter {
+    /// Resolve the collector address, as "HOST:PORT", and open a socket to it
+    pub fn new(addr: &str, version: NetflowVersion, domain_id: u32) -> Result<Self, Error> {
+        let addr = addr.to_socket_addrs()?.next()
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("No address for {}", addr)))?;
+        let bind_addr = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = UdpSocket::bind(bind_addr)?;
+        socket.connect(addr)?;
+        Ok(NetflowExporter { socket, addr, version, domain_id, start_time: Instant::now(), sequence: 0,
+            pending: Vec::new(), templates_sent: None, record_count: 0, packet_count: 0, failed_packet_count: 0,
+            failing: false })
+    }
+
+    /// Keep the records of a connection, one per direction that sent packets, to be sent on the next flush
+    pub fn export(&mut self, summary: &ConnSummary) {
+        let directions = [
+            (summary.low_addr, summary.high_addr, summary.packets_src_low, &summary.activity_src_low),
+            (summary.high_addr, summary.low_addr, summary.packets_src_high, &summary.activity_src_high),
+        ];
+        for (src, dst, packets, activity) in directions {
+            if packets > 0 {
+                self.pending.push(flow_record(src, dst, packets, activity));
+            }
+        }
+    }
+
+    /// Send the kept records, in as many packets as needed
+    pub fn flush(&mut self) {
+        let mut records = std::mem::take(&mut self.pending).into_iter().peekable();
+        while records.peek().is_some() {
+            let mut body = Vec::with_capacity(MAX_MESSAGE_LEN);
+            let mut record_count = 0;
+            if self.templates_due() {
+                record_count += self.write_templates(&mut body);
+            }
+            let mut data_count = 0;
+            // The current set, by its template and where it starts in the body
+            let mut set: Option<(u16, usize)> = None;
+            while let Some((template_id, record)) = records.peek() {
+                let same_set = set.is_some_and(|(set_template_id, _)| set_template_id == *template_id);
+                let added_len = record.len() + if same_set { 0 } else { 4 };
+                // A packet has at least one record, and room for the padding of its last set
+                if data_count > 0 && self.version.header_len() + body.len() + added_len + 3 > MAX_MESSAGE_LEN {
+                    break;
+                }
+                if !same_set {
+                    if let Some((_, start)) = set {
+                        close_set(&mut body, start);
+                    }
+                    set = Some((*template_id, body.len()));
+                    body.extend_from_slice(&template_id.to_be_bytes());
+                    body.extend_from_slice(&[0, 0]);
+                }
+                body.extend_from_slice(record);
+                data_count += 1;
+                records.next();
+            }
+            if let Some((_, start)) = set {
+                close_set(&mut body, start);
+            }
+            self.send(body, record_count + data_count, data_count);
+        }
+    }
+
+    fn templates_due(&self) -> bool {
+        match self.templates_sent {
+            None => { true }
+            Some((time, packets)) => { packets >= TEMPLATE_REFRESH_PACKETS || time.elapsed() >= TEMPLATE_REFRESH_INTERVAL }
+        }
+    }
+
+    /// Write a set of the templates, and return the number of templates
+    fn write_templates(&mut self, body: &mut Vec<u8>) -> u16 {
+        let start = body.len();
+        let set_id: u16 = match self.version {
+            NetflowVersion::V9 => { 0 }
+            NetflowVersion::Ipfix => { 2 }
+        };
+        body.extend_from_slice(&set_id.to_be_bytes());
+        body.extend_from_slice(&[0, 0]);
+        for (template_id, fields) in [(IPV4_TEMPLATE_ID, &IPV4_FIELDS), (IPV6_TEMPLATE_ID, &IPV6_FIELDS)] {
+            body.extend_from_slice(&template_id.to_be_bytes());
+            body.extend_from_slice(&(fields.len() as u16).to_be_bytes());
+            for (field_type, field_len) in fields {
+                body.extend_from_slice(&field_type.to_be_bytes());
+                body.extend_from_slice(&field_len.to_be_bytes());
+            }
+        }
+        close_set(body, start);
+        self.templates_sent = Some((Instant::now(), 0));
+        2
+    }
+
+    /// Send a packet with the given sets, which hold the given number of records, of which the given number are data
+    fn send(&mut self, body: Vec<u8>, record_count: u16, data_count: u16) {
+        let export_secs = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as u32;
+        let mut message = Vec::with_capacity(self.version.header_len() + body.len());
+        match self.version {
+            NetflowVersion::V9 => {
+                message.extend_from_slice(&9u16.to_be_bytes());
+                message.extend_from_slice(&record_count.to_be_bytes());
+                message.extend_from_slice(&(self.start_time.elapsed().as_millis() as u32).to_be_bytes());
+                message.extend_from_slice(&export_secs.to_be_bytes());
+                message.extend_from_slice(&self.sequence.to_be_bytes());
+                self.sequence = self.sequence.wrapping_add(1);
+            }
+            NetflowVersion::Ipfix => {
+                message.extend_from_slice(&10u16.to_be_bytes());
+                message.extend_from_slice(&((self.version.header_len() + body.len()) as u16).to_be_bytes());
+                message.extend_from_slice(&export_secs.to_be_bytes());
+                message.extend_from_slice(&self.sequence.to_be_bytes());
+                self.sequence = self.sequence.wrapping_add(data_count as u32);
+            }
+        }
+        message.extend_from_slice(&self.domain_id.to_be_bytes());
+        message.extend_from_slice(&body);
+        if let Some((_, packets)) = &mut self.templates_sent {
+            *packets += 1;
+        }
+        match self.socket.send(&message) {
+            Err(error) => {
+                if !self.failing {
+                    warn!("Failed to send flow records to the {} collector at {}, they are dropped: {}", self.version,
+                        self.addr, error);
+                    self.failing = true;
+                }
+                self.failed_packet_count += 1;
+            }
+            Ok(_) => {
+                if self.failing {
+                    info!("Sending flow records to the {} collector at {} again", self.version, self.addr);
+                    self.failing = false;
+                }
+                self.packet_count += 1;
+                self.record_count += data_count as u64;
+            }
+        }
+    }
+}
+
+impl fmt::Display for NetflowExporter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} records in {} packets to {} as {}, {} packets failed", self.record_count, self.packet_count,
+               self.addr, self.version, self.failed_packet_count)
+    }
+}
+
+/// A data record of one direction of a connection, by its template
+fn flow_record(src: SocketAddr, dst: SocketAddr, packets: u32, activity: &FlowActivity) -> (u16, Vec<u8>) {
+    let mut record = Vec::with_capacity(70);
+    let template_id = match (src, dst) {
+        (SocketAddr::V4(src), SocketAddr::V4(dst)) => {
+            record.extend_from_slice(&src.ip().octets());
+            record.extend_from_slice(&dst.ip().octets());
+            IPV4_TEMPLATE_ID
+        }
+        _ => {
+            record.extend_from_slice(&ipv6_octets(src));
+            record.extend_from_slice(&ipv6_octets(dst));
+            IPV6_TEMPLATE_ID
+        }
+    };
+    record.extend_from_slice(&src.port().to_be_bytes());
+    record.extend_from_slice(&dst.port().to_be_bytes());
+    record.push(PROTOCOL_TCP);
+    record.push(activity.tcp_flags);
+    record.extend_from_slice(&activity.octets.to_be_bytes());
+    record.extend_from_slice(&(packets as u64).to_be_bytes());
+    let first_ts = activity.first_ts.unwrap_or(activity.last_ts);
+    record.extend_from_slice(&(first_ts.as_millis() as u64).to_be_bytes());
+    record.extend_from_slice(&(activity.last_ts.as_millis() as u64).to_be_bytes());
+    (template_id, record)
+}
+
+/// The address as IPv6, with an IPv4 address taken as IPv4-mapped
+fn ipv6_octets(addr: SocketAddr) -> [u8; 16] {
+    match addr {
+        SocketAddr::V4(addr) => { addr.ip().to_ipv6_mapped().octets() }
+        SocketAddr::V6(addr) => { addr.ip().octets() }
+    }
+}
+
+/// Pad the set that starts at the given position to 4 bytes, and write its length
+fn close_set(body: &mut Vec<u8>, start: usize) {
+    while !(body.len() - start).is_multiple_of(4) {
+        body.push(0);
+    }
+    let len = ((body.len() - start) as u16).to_be_bytes();
+    body[start + 2..start + 4].copy_from_slice(&len);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+    use std::time::Duration;
+    use crate::flow_buff::FlowActivity;
+    use crate::utils::bytes_to_hex;
+    use super::{flow_record, NetflowExporter, NetflowVersion};
+
+    const IPV4_TEMPLATE: &str = "0100 000a 0008 0004 000c 0004 0007 0002 000b 0002 0004 0001 0006 0001 0001 0008 0002 0008 \
+        0098 0008 0099 0008";
+    const IPV6_TEMPLATE: &str = "0101 000a 001b 0010 001c 0010 0007 0002 000b 0002 0004 0001 0006 0001 0001 0008 0002 0008 \
+        0098 0008 0099 0008";
+    const IPV4_RECORD: &str = "0a000001 0a000002 9c40 01bb 06 1b 00000000000000b4 0000000000000003 00000000000005dc \
+        00000000000008ca";
+
+    /// Hex of golden bytes, written with spaces between the fields
+    fn golden(fields: &[&str]) -> String {
+        fields.concat().replace(' ', "")
+    }
+
+    fn activity() -> FlowActivity {
+        FlowActivity { first_ts: Some(Duration::from_millis(1500)), last_ts: Duration::from_millis(2250), octets: 180,
+            tcp_flags: 0x1b }
+    }
+
+    /// The record of 3 packets from 10.0.0.1:40000 to 10.0.0.2:443
+    fn ipv4_record() -> (u16, Vec<u8>) {
+        flow_record("10.0.0.1:40000".parse().unwrap(), "10.0.0.2:443".parse().unwrap(), 3, &activity())
+    }
+
+    /// An exporter to a socket on the loopback, and that socket
+    fn exporter(version: NetflowVersion) -> (NetflowExporter, UdpSocket) {
+        let collector = UdpSocket::bind("127.0.0.1:0").unwrap();
+        collector.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let addr = collector.local_addr().unwrap().to_string();
+        (NetflowExporter::new(&addr, version, 42).unwrap(), collector)
+    }
+
+    /// The next packet of the collector, in hex, with the given bytes of the header blanked since they are times
+    fn receive(collector: &UdpSocket, times: std::ops::Range<usize>) -> String {
+        let mut packet = [0u8; 2048];
+        let len = collector.recv(&mut packet).unwrap();
+        packet[times].fill(0);
+        bytes_to_hex(&packet[..len])
+    }
+
+    #[test]
+    fn data_records() {
+        let record = ipv4_record();
+        assert_eq!((record.0, bytes_to_hex(&record.1)), (256, golden(&[IPV4_RECORD])));
+        // An IPv4 address with an IPv6 one is IPv4-mapped, and a flow without a first packet starts at its last
+        let activity = FlowActivity { first_ts: None, ..activity() };
+        let record = flow_record("[2001:db8::1]:1".parse().unwrap(), "10.0.0.2:443".parse().unwrap(), 3, &activity);
+        let expected = golden(&["20010db8000000000000000000000001 00000000000000000000ffff0a000002 0001 01bb 06 1b \
+            00000000000000b4 0000000000000003 00000000000008ca 00000000000008ca"]);
+        assert_eq!((record.0, bytes_to_hex(&record.1)), (257, expected));
+    }
+
+    #[test]
+    fn netflow_v9_packet() {
+        let (mut exporter, collector) = exporter(NetflowVersion::V9);
+        exporter.pending.push(ipv4_record());
+        exporter.flush();
+        // Version, records, uptime, export time, sequence and source ID, a template set and a padded data set
+        assert_eq!(receive(&collector, 4..12), golden(&["0009 0003 00000000 00000000 00000000 0000002a",
+            "0000 005c ", IPV4_TEMPLATE, IPV6_TEMPLATE, "0100 0034 ", IPV4_RECORD, "0000"]));
+        exporter.pending.push(ipv4_record());
+        exporter.flush();
+        assert_eq!(receive(&collector, 4..12), golden(&["0009 0001 00000000 00000000 00000001 0000002a",
+            "0100 0034 ", IPV4_RECORD, "0000"]));
+    }
+
+    #[test]
+    fn ipfix_packet() {
+        let (mut exporter, collector) = exporter(NetflowVersion::Ipfix);
+        exporter.pending.push(ipv4_record());
+        exporter.flush();
+        // Version, length, export time, sequence and observation domain, a template set and a padded data set
+        assert_eq!(receive(&collector, 4..8), golden(&["000a 00a0 00000000 00000000 0000002a",
+            "0002 005c ", IPV4_TEMPLATE, IPV6_TEMPLATE, "0100 0034 ", IPV4_RECORD, "0000"]));
+        // The sequence counts the data records sent before
+        exporter.pending.push(ipv4_record());
+        exporter.flush();
+        assert_eq!(receive(&collector, 4..8), golden(&["000a 0044 00000000 00000001 0000002a",
+            "0100 0034 ", IPV4_RECORD, "0000"]));
+    }
+}
@@ -0,0 +1,56 @@
+use crate::conn::{Conn, ConnState, PacketDir};
+use crate::syn_flood::SynFloodAlarm;
+
+/// Why a connection was removed from the table
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CloseReason {
+    /// Both sides sent a FIN
+    Fin,
+    /// One of the sides sent a RST
+    Reset,
+    /// The handshake did not complete
+    SetupFailed,
+    /// No packets for the idle timeout of its state
+    Idle,
+}
+
+impl CloseReason {
+    /// Why a connection in the given state was removed, given whether it went idle before it closed
+    pub(crate) fn new(state: &ConnState, idle: bool) -> Self {
+        match (state, idle) {
+            (_, true) => { CloseReason::Idle }
+            (ConnState::Closed(_), _) => { CloseReason::Reset }
+            (ConnState::Failed(_), _) => { CloseReason::SetupFailed }
+            _ => { CloseReason::Fin }
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            CloseReason::Fin => { "fin" }
+            CloseReason::Reset => { "reset" }
+            CloseReason::SetupFailed => { "setup_failed" }
+            CloseReason::Idle => { "idle" }
+        }
+    }
+}
+
+/// Callbacks on the lifecycle of the connections, that the connection table dispatches to while it processes the
+/// packets, so the crate can be used as a stream processing framework. Every callback has an empty default, so an
+/// observer implements only the ones it needs. Callbacks run under the lock of the table (or its shard), so they
+/// should be quick.
+pub trait ConnectionObserver: Send {
+    /// The handshake of the connection completed
+    fn on_established(&mut self, _conn: &Conn) {}
+
+    /// The next contiguous bytes of the flow sent by the given direction, in stream order, once each. Connections that
+    /// only count their payloads, such as over the buffering budget, have none.
+    fn on_data(&mut self, _conn: &Conn, _dir: &PacketDir, _data: &[u8]) {}
+
+    /// The connection was removed from the table. Connections that are still in the table on exit are not closed.
+    fn on_closed(&mut self, _conn: &Conn, _reason: CloseReason) {}
+
+    /// A destination crossed a threshold of the SYN flood detector, if it is enabled. It is raised once, until the
+    /// destination calms down.
+    fn on_syn_flood(&mut self, _alarm: &SynFloodAlarm) {}
+}
@@ -0,0 +1,596 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+use crate::classifier::Classifier;
+
+/// Operators of the ai.onnx.ml domain that a model can be made of, as exported from scikit-learn classifiers
+const SUPPORTED_OPERATORS: &str = "Imputer, Scaler, LinearClassifier, TreeEnsembleClassifier, ZipMap, Cast and Identity";
+
+/// An ONNX classification model, evaluated without an external runtime.
+/// The graph must be a chain of operators from its input to a LinearClassifier or a TreeEnsembleClassifier, whose
+/// label output is the prediction. Unknown features are fed as NaN, for an Imputer or the trees' missing value tracks.
+pub struct OnnxClassifier {
+    /// Operators on the feature vector, in the order of the chain
+    transforms: Vec<Transform>,
+    model: Model,
+    /// Labels by class index
+    labels: Vec<String>,
+}
+
+/// An operator that changes the feature vector
+enum Transform {
+    /// Replace the NaN (or the given) values, with one value for all features or a value per feature
+    Imputer { values: Vec<f32>, replaced: f32 },
+    /// Subtract the offsets and multiply by the scales, with one value for all features or a value per feature
+    Scaler { offsets: Vec<f32>, scales: Vec<f32> },
+}
+
+/// The operator that predicts the label
+enum Model {
+    /// Scores per class as intercepts plus the weighted features, where a single score is of the second class
+    Linear { coefficients: Vec<f32>, intercepts: Vec<f32> },
+    Trees(TreeEnsemble),
+}
+
+/// Trees whose leaves add weights to the scores of classes
+struct TreeEnsemble {
+    nodes: Vec<TreeNode>,
+    /// Index of the root node of every tree
+    roots: Vec<usize>,
+    base_values: Vec<f32>,
+    /// Whether all the weights are of the first class of two, so its score alone tells the class
+    binary: bool,
+}
+
+struct TreeNode {
+    mode: NodeMode,
+    feature: usize,
+    value: f32,
+    true_index: usize,
+    false_index: usize,
+    /// Whether a missing (NaN) feature takes the true branch
+    missing_true: bool,
+    /// Weights that a leaf adds, by class index
+    weights: Vec<(usize, f32)>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum NodeMode {
+    Leq,
+    Lt,
+    Gte,
+    Gt,
+    Eq,
+    Neq,
+    Leaf,
+}
+
+impl OnnxClassifier {
+    /// Read a model file
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        OnnxClassifier::parse(&fs::read(path)?)
+    }
+
+    /// Parse a serialized ModelProto, and check that it is a supported chain of operators
+    pub fn parse(data: &[u8]) -> Result<Self, Error> {
+        let graph = match message_fields(data)?.into_iter().find(|(number, _)| *number == 7) {
+            Some((_, Field::Bytes(graph))) => { graph }
+            _ => { return Err(invalid("the model has no graph")); }
+        };
+        let mut input = None;
+        let mut nodes = Vec::new();
+        for (number, field) in message_fields(graph)? {
+            match (number, field) {
+                (1, Field::Bytes(node)) => { nodes.push(Node::parse(node)?) }
+                (11, Field::Bytes(value_info)) if input.is_none() => { input = Some(string_field(value_info, 1)?) }
+                _ => {}
+            }
+        }
+        // Follow the chain from the graph input to the classifier
+        let mut current = input.ok_or_else(|| invalid("the graph has no input"))?;
+        let mut transforms = Vec::new();
+        for node in &nodes {
+            let op_type = node.op_type.as_str();
+            if op_type == "ZipMap" {
+                continue;
+            }
+            if node.inputs.first() != Some(&current) || node.outputs.is_empty() {
+                return Err(invalid(&format!("the {} operator is not in a chain from the graph input", op_type)));
+            }
+            current = node.outputs[0].clone();
+            match op_type {
+                "Cast" | "Identity" => {}
+                "Imputer" => {
+                    let values = node.floats("imputed_value_floats");
+                    let replaced = node.floats("replaced_value_float").first().copied().unwrap_or(f32::NAN);
+                    transforms.push(Transform::Imputer { values, replaced });
+                }
+                "Scaler" => {
+                    transforms.push(Transform::Scaler { offsets: node.floats("offset"), scales: node.floats("scale") });
+                }
+                "LinearClassifier" | "TreeEnsembleClassifier" => {
+                    let labels = node.labels()?;
+                    let model = if op_type == "LinearClassifier" { node.linear(labels.len())? } else { node.trees(labels.len())? };
+                    return Ok(OnnxClassifier { transforms, model, labels });
+                }
+                _ => { return Err(invalid(&format!("unsupported operator {}, expected {}", op_type, SUPPORTED_OPERATORS))); }
+            }
+        }
+        Err(invalid("the graph has no LinearClassifier or TreeEnsembleClassifier"))
+    }
+
+    /// Index of the predicted class
+    fn predict(&self, features: &[f32]) -> Result<usize, Error> {
+        let mut features = features.to_vec();
+        for transform in &self.transforms {
+            transform.apply(&mut features);
+        }
+        match &self.model {
+            Model::Linear { coefficients, intercepts } => {
+                let feature_count = coefficients.len() / intercepts.len();
+                let scores: Vec<f32> = intercepts.iter().enumerate().map(|(class, intercept)| {
+                    let weights = &coefficients[class * feature_count..(class + 1) * feature_count];
+                    intercept + weights.iter().zip(&features).map(|(weight, value)| weight * value).sum::<f32>()
+                }).collect();
+                match scores.as_slice() {
+                    [score] => { Ok(usize::from(*score > 0.0)) }
+                    _ => { Ok(argmax(&scores)) }
+                }
+            }
+            Model::Trees(trees) => { trees.predict(&features, self.labels.len()) }
+        }
+    }
+}
+
+impl Classifier for OnnxClassifier {
+    fn classify(&mut self, features: &[Option<f64>]) -> Result<String, Error> {
+        let features: Vec<f32> = features.iter().map(|value| value.map_or(f32::NAN, |value| value as f32)).collect();
+        Ok(self.labels[self.predict(&features)?].clone())
+    }
+}
+
+impl Transform {
+    fn apply(&self, features: &mut [f32]) {
+        // A single value applies to all the features
+        let value_at = |values: &[f32], index: usize, default: f32| match values {
+            [] => { default }
+            [value] => { *value }
+            _ => { values.get(index).copied().unwrap_or(default) }
+        };
+        for (index, feature) in features.iter_mut().enumerate() {
+            match self {
+                Transform::Imputer { values, replaced } => {
+                    if (feature.is_nan() && replaced.is_nan()) || *feature == *replaced {
+                        *feature = value_at(values, index, *feature);
+                    }
+                }
+                Transform::Scaler { offsets, scales } => {
+                    *feature = (*feature - value_at(offsets, index, 0.0)) * value_at(scales, index, 1.0);
+                }
+            }
+        }
+    }
+}
+
+impl TreeEnsemble {
+    fn predict(&self, features: &[f32], class_count: usize) -> Result<usize, Error> {
+        let mut scores = vec![0.0f32; class_count];
+        for (score, base_value) in scores.iter_mut().zip(&self.base_values) {
+            *score = *base_value;
+        }
+        for root in &self.roots {
+            let mut node = &self.nodes[*root];
+            // A tree with a cycle would never reach a leaf
+            let mut steps = 0;
+            while node.mode != NodeMode::Leaf {
+                steps += 1;
+                if steps > self.nodes.len() {
+                    return Err(invalid("a tree of the model has a cycle"));
+                }
+                let value = features.get(node.feature).copied().unwrap_or(f32::NAN);
+                let branch = match node.mode {
+                    _ if value.is_nan() => { node.missing_true }
+                    NodeMode::Leq => { value <= node.value }
+                    NodeMode::Lt => { value < node.value }
+                    NodeMode::Gte => { value >= node.value }
+                    NodeMode::Gt => { value > node.value }
+                    NodeMode::Eq => { value == node.value }
+                    NodeMode::Neq => { value != node.value }
+                    NodeMode::Leaf => { unreachable!() }
+                };
+                node = &self.nodes[if branch { node.true_index } else { node.false_index }];
+            }
+            for (class, weight) in &node.weights {
+                scores[*class] += weight;
+            }
+        }
+        if self.binary {
+            // Probabilities of the second class are above 0.5, and raw scores (as of boosting) are above 0
+            let all_positive = self.nodes.iter().flat_map(|node| &node.weights).all(|(_, weight)| *weight >= 0.0);
+            return Ok(usize::from(scores[0] > if all_positive { 0.5 } else { 0.0 }));
+        }
+        Ok(argmax(&scores))
+    }
+}
+
+/// Index of the highest score, the first one on a tie
+fn argmax(scores: &[f32]) -> usize {
+    scores.iter().enumerate().fold(0, |best, (index, score)| if *score > scores[best] { index } else { best })
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("Invalid ONNX model: {}", message))
+}
+
+/// A NodeProto, with its attributes by name, where single and repeated values are kept alike
+struct Node {
+    op_type: String,
+    inputs: Vec<String>,
+    outputs: Vec<String>,
+    floats: HashMap<String, Vec<f32>>,
+    ints: HashMap<String, Vec<i64>>,
+    strings: HashMap<String, Vec<String>>,
+}
+
+impl Node {
+    fn parse(data: &[u8]) -> Result<Self, Error> {
+        let mut node = Node {
+            op_type: String::new(),
+            inputs: Vec::new(),
+            outputs: Vec::new(),
+            floats: HashMap::new(),
+            ints: HashMap::new(),
+            strings: HashMap::new(),
+        };
+        for (number, field) in message_fields(data)? {
+            match (number, field) {
+                (1, Field::Bytes(input)) => { node.inputs.push(utf8(input)?) }
+                (2, Field::Bytes(output)) => { node.outputs.push(utf8(output)?) }
+                (4, Field::Bytes(op_type)) => { node.op_type = utf8(op_type)? }
+                (5, Field::Bytes(attribute)) => { node.add_attribute(attribute)? }
+                _ => {}
+            }
+        }
+        Ok(node)
+    }
+
+    /// Add the values of an AttributeProto
+    fn add_attribute(&mut self, data: &[u8]) -> Result<(), Error> {
+        let mut name = String::new();
+        let mut floats = Vec::new();
+        let mut ints = Vec::new();
+        let mut strings = Vec::new();
+        for (number, field) in message_fields(data)? {
+            match (number, field) {
+                (1, Field::Bytes(value)) => { name = utf8(value)? }
+                (2 | 7, Field::Fixed32(value)) => { floats.push(f32::from_bits(value)) }
+                (7, Field::Bytes(packed)) => {
+                    floats.extend(packed.chunks_exact(4).map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap())));
+                }
+                (3 | 8, Field::Varint(value)) => { ints.push(value as i64) }
+                (8, Field::Bytes(mut packed)) => {
+                    while !packed.is_empty() {
+                        ints.push(read_varint(&mut packed)? as i64);
+                    }
+                }
+                (4 | 9, Field::Bytes(value)) => { strings.push(utf8(value)?) }
+                _ => {}
+            }
+        }
+        self.floats.entry(name.clone()).or_default().extend(floats);
+        self.ints.entry(name.clone()).or_default().extend(ints);
+        self.strings.entry(name).or_default().extend(strings);
+        Ok(())
+    }
+
+    fn floats(&self, name: &str) -> Vec<f32> {
+        self.floats.get(name).cloned().unwrap_or_default()
+    }
+
+    fn ints(&self, name: &str) -> Vec<i64> {
+        self.ints.get(name).cloned().unwrap_or_default()
+    }
+
+    fn strings(&self, name: &str) -> Vec<String> {
+        self.strings.get(name).cloned().unwrap_or_default()
+    }
+
+    /// Class labels, given as strings or as integers
+    fn labels(&self) -> Result<Vec<String>, Error> {
+        let mut labels = self.strings("classlabels_strings");
+        if labels.is_empty() {
+            labels = self.ints("classlabels_ints").iter().map(i64::to_string).collect();
+        }
+        if labels.is_empty() {
+            labels = self.ints("classlabels_int64s").iter().map(i64::to_string).collect();
+        }
+        match labels.len() {
+            0 => { Err(invalid(&format!("the {} has no class labels", self.op_type))) }
+            _ => { Ok(labels) }
+        }
+    }
+
+    fn linear(&self, class_count: usize) -> Result<Model, Error> {
+        let coefficients = self.floats("coefficients");
+        let intercepts = self.floats("intercepts");
+        let scores_match = intercepts.len() == class_count || (intercepts.len() == 1 && class_count == 2);
+        if !scores_match || coefficients.is_empty() || !coefficients.len().is_multiple_of(intercepts.len()) {
+            return Err(invalid("the coefficients and intercepts of the LinearClassifier do not match its classes"));
+        }
+        Ok(Model::Linear { coefficients, intercepts })
+    }
+
+    fn trees(&self, class_count: usize) -> Result<Model, Error> {
+        let tree_ids = self.ints("nodes_treeids");
+        let node_ids = self.ints("nodes_nodeids");
+        let features = self.ints("nodes_featureids");
+        let values = self.floats("nodes_values");
+        let modes = self.strings("nodes_modes");
+        let true_ids = self.ints("nodes_truenodeids");
+        let false_ids = self.ints("nodes_falsenodeids");
+        let missing_true = self.ints("nodes_missing_value_tracks_true");
+        let count = tree_ids.len();
+        if [node_ids.len(), features.len(), values.len(), modes.len(), true_ids.len(), false_ids.len()].iter().any(|len| *len != count) {
+            return Err(invalid("the node attributes of the TreeEnsembleClassifier differ in length"));
+        }
+        let index_of: HashMap<(i64, i64), usize> = tree_ids.iter().zip(&node_ids).enumerate()
+            .map(|(index, (tree_id, node_id))| ((*tree_id, *node_id), index)).collect();
+        let child = |tree_id: i64, node_id: i64| index_of.get(&(tree_id, node_id)).copied()
+            .ok_or_else(|| invalid(&format!("tree {} has no node {}", tree_id, node_id)));
+        let mut nodes = Vec::with_capacity(count);
+        let mut roots: Vec<(i64, i64, usize)> = Vec::new();
+        for index in 0..count {
+            let mode = match modes[index].as_str() {
+                "BRANCH_LEQ" => { NodeMode::Leq }
+                "BRANCH_LT" => { NodeMode::Lt }
+                "BRANCH_GTE" => { NodeMode::Gte }
+                "BRANCH_GT" => { NodeMode::Gt }
+                "BRANCH_EQ" => { NodeMode::Eq }
+                "BRANCH_NEQ" => { NodeMode::Neq }
+                "LEAF" => { NodeMode::Leaf }
+                other => { return Err(invalid(&format!("unknown tree node mode {}", other))); }
+            };
+            let (true_index, false_index) = match mode {
+                NodeMode::Leaf => { (index, index) }
+                _ => { (child(tree_ids[index], true_ids[index])?, child(tree_ids[index], false_ids[index])?) }
+            };
+            nodes.push(TreeNode {
+                mode,
+                feature: usize::try_from(features[index]).map_err(|_| invalid("negative feature index"))?,
+                value: values[index],
+                true_index,
+                false_index,
+                missing_true: missing_true.get(index).is_some_and(|value| *value != 0),
+                weights: Vec::new(),
+            });
+            // The root of a tree is its node with the lowest id
+            match roots.iter_mut().find(|(tree_id, _, _)| *tree_id == tree_ids[index]) {
+                Some(root) if node_ids[index] < root.1 => { *root = (tree_ids[index], node_ids[index], index) }
+                Some(_) => {}
+                None => { roots.push((tree_ids[index], node_ids[index], index)) }
+            }
+        }
+        let class_tree_ids = self.ints("class_treeids");
+        let class_node_ids = self.ints("class_nodeids");
+        let class_ids = self.ints("class_ids");
+        let class_weights = self.floats("class_weights");
+        if [class_node_ids.len(), class_ids.len(), class_weights.len()].iter().any(|len| *len != class_tree_ids.len()) {
+            return Err(invalid("the class attributes of the TreeEnsembleClassifier differ in length"));
+        }
+        for index in 0..class_tree_ids.len() {
+            let class = usize::try_from(class_ids[index]).ok().filter(|class| *class < class_count)
+                .ok_or_else(|| invalid(&format!("class id {} is not of a label", class_ids[index])))?;
+            nodes[child(class_tree_ids[index], class_node_ids[index])?].weights.push((class, class_weights[index]));
+        }
+        let binary = class_count == 2 && class_ids.iter().all(|class| *class == 0);
+        let roots = roots.into_iter().map(|(_, _, index)| index).collect();
+        Ok(Model::Trees(TreeEnsemble { nodes, roots, base_values: self.floats("base_values"), binary }))
+    }
+}
+
+/// A field of a protobuf message by wire type, where 64-bit fixed values are not used by the supported messages
+enum Field<'a> {
+    Varint(u64),
+    Fixed32(u32),
+    Fixed64,
+    Bytes(&'a [u8]),
+}
+
+/// Fields of a protobuf message, as (field number, value) in order
+fn message_fields(mut data: &[u8]) -> Result<Vec<(u64, Field<'_>)>, Error> {
+    let mut fields = Vec::new();
+    while !data.is_empty() {
+        let key = read_varint(&mut data)?;
+        let field = match key & 7 {
+            0 => { Field::Varint(read_varint(&mut data)?) }
+            1 => {
+                take(&mut data, 8)?;
+                Field::Fixed64
+            }
+            2 => {
+                let len = usize::try_from(read_varint(&mut data)?).map_err(|_| invalid("field too long"))?;
+                Field::Bytes(take(&mut data, len)?)
+            }
+            5 => { Field::Fixed32(u32::from_le_bytes(take(&mut data, 4)?.try_into().unwrap())) }
+            wire_type => { return Err(invalid(&format!("unsupported protobuf wire type {}", wire_type))); }
+        };
+        fields.push((key >> 3, field));
+    }
+    Ok(fields)
+}
+
+fn read_varint(data: &mut &[u8]) -> Result<u64, Error> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = take(data, 1)?[0];
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(invalid("varint too long"))
+}
+
+fn take<'a>(data: &mut &'a [u8], len: usize) -> Result<&'a [u8], Error> {
+    if data.len() < len {
+        return Err(invalid("truncated message"));
+    }
+    let (head, tail) = data.split_at(len);
+    *data = tail;
+    Ok(head)
+}
+
+/// The first string field with the given number of a message, or empty
+fn string_field(data: &[u8], number: u64) -> Result<String, Error> {
+    for (field_number, field) in message_fields(data)? {
+        match field {
+            Field::Bytes(value) if field_number == number => { return utf8(value); }
+            _ => {}
+        }
+    }
+    Ok(String::new())
+}
+
+fn utf8(bytes: &[u8]) -> Result<String, Error> {
+    String::from_utf8(bytes.to_vec()).map_err(|_| invalid("a name is not UTF-8"))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::classifier::Classifier;
+    use super::OnnxClassifier;
+
+    /// A protobuf length-delimited field
+    fn bytes_field(number: u64, bytes: &[u8]) -> Vec<u8> {
+        let mut field = varint(number << 3 | 2);
+        field.extend(varint(bytes.len() as u64));
+        field.extend_from_slice(bytes);
+        field
+    }
+
+    fn varint(mut value: u64) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        while value >= 0x80 {
+            bytes.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        bytes.push(value as u8);
+        bytes
+    }
+
+    /// Attribute values of a node
+    enum Value<'a> {
+        Floats(&'a [f32]),
+        Ints(&'a [i64]),
+        Strings(&'a [&'a str]),
+    }
+
+    /// A NodeProto, with packed repeated attributes
+    fn node(op_type: &str, input: &str, output: &str, attributes: &[(&str, Value)]) -> Vec<u8> {
+        let mut node = bytes_field(1, input.as_bytes());
+        node.extend(bytes_field(2, output.as_bytes()));
+        node.extend(bytes_field(4, op_type.as_bytes()));
+        for (name, value) in attributes {
+            let mut attribute = bytes_field(1, name.as_bytes());
+            match value {
+                Value::Floats(floats) => { attribute.extend(bytes_field(7, &floats.iter().flat_map(|f| f.to_le_bytes()).collect::<Vec<u8>>())) }
+                Value::Ints(ints) => { attribute.extend(bytes_field(8, &ints.iter().flat_map(|i| varint(*i as u64)).collect::<Vec<u8>>())) }
+                Value::Strings(strings) => {
+                    for string in *strings {
+                        attribute.extend(bytes_field(9, string.as_bytes()));
+                    }
+                }
+            }
+            node.extend(bytes_field(5, &attribute));
+        }
+        node
+    }
+
+    /// A ModelProto of the nodes, with the graph input "features"
+    fn model(nodes: &[Vec<u8>]) -> Vec<u8> {
+        let mut graph = Vec::new();
+        for node in nodes {
+            graph.extend(bytes_field(1, node));
+        }
+        graph.extend(bytes_field(11, &bytes_field(1, b"features")));
+        let mut model = vec![0x08, 7];
+        model.extend(bytes_field(7, &graph));
+        model
+    }
+
+    #[test]
+    fn linear_classifier_after_imputer_and_scaler() {
+        let data = model(&[
+            node("Imputer", "features", "imputed", &[("imputed_value_floats", Value::Floats(&[5.0]))]),
+            node("Scaler", "imputed", "scaled", &[("offset", Value::Floats(&[1.0, 0.0])), ("scale", Value::Floats(&[2.0]))]),
+            node("LinearClassifier", "scaled", "label", &[
+                ("classlabels_strings", Value::Strings(&["bulk", "video", "web"])),
+                ("coefficients", Value::Floats(&[1.0, 0.0, 0.0, 1.0, -1.0, -1.0])),
+                ("intercepts", Value::Floats(&[0.0, 0.0, 10.0])),
+            ]),
+            node("ZipMap", "probabilities", "output_probability", &[]),
+        ]);
+        let mut classifier = OnnxClassifier::parse(&data).unwrap();
+        assert_eq!(classifier.classify(&[Some(11.0), Some(2.0)]).unwrap(), "bulk");
+        assert_eq!(classifier.classify(&[Some(1.0), Some(12.0)]).unwrap(), "video");
+        assert_eq!(classifier.classify(&[Some(1.0), Some(1.0)]).unwrap(), "web");
+        // The unknown feature is imputed as 5, scaled to 10
+        assert_eq!(classifier.classify(&[Some(1.0), None]).unwrap(), "video");
+        // A single score is of the second class
+        let binary = model(&[node("LinearClassifier", "features", "label", &[
+            ("classlabels_ints", Value::Ints(&[0, 1])),
+            ("coefficients", Value::Floats(&[1.0, -1.0])),
+            ("intercepts", Value::Floats(&[0.5])),
+        ])]);
+        let mut classifier = OnnxClassifier::parse(&binary).unwrap();
+        assert_eq!(classifier.classify(&[Some(1.0), Some(1.0)]).unwrap(), "1");
+        assert_eq!(classifier.classify(&[Some(1.0), Some(2.0)]).unwrap(), "0");
+    }
+
+    #[test]
+    fn tree_ensemble_classifier() {
+        // Tree 0 splits on feature 0 (missing goes to the true branch), tree 1 is a single leaf
+        let trees = |class_ids: &[i64], class_weights: &[f32]| model(&[
+            node("Cast", "features", "cast", &[]),
+            node("TreeEnsembleClassifier", "cast", "label", &[
+                ("classlabels_strings", Value::Strings(&["chat", "stream"])),
+                ("nodes_treeids", Value::Ints(&[0, 0, 0, 1])),
+                ("nodes_nodeids", Value::Ints(&[0, 1, 2, 0])),
+                ("nodes_featureids", Value::Ints(&[0, 0, 0, 0])),
+                ("nodes_values", Value::Floats(&[100.0, 0.0, 0.0, 0.0])),
+                ("nodes_modes", Value::Strings(&["BRANCH_LEQ", "LEAF", "LEAF", "LEAF"])),
+                ("nodes_truenodeids", Value::Ints(&[1, 0, 0, 0])),
+                ("nodes_falsenodeids", Value::Ints(&[2, 0, 0, 0])),
+                ("nodes_missing_value_tracks_true", Value::Ints(&[1, 0, 0, 0])),
+                ("class_treeids", Value::Ints(&[0, 0, 1])),
+                ("class_nodeids", Value::Ints(&[1, 2, 0])),
+                ("class_ids", Value::Ints(class_ids)),
+                ("class_weights", Value::Floats(class_weights)),
+            ]),
+        ]);
+        let mut classifier = OnnxClassifier::parse(&trees(&[0, 1, 1], &[1.0, 1.0, 0.5])).unwrap();
+        assert_eq!(classifier.classify(&[Some(50.0)]).unwrap(), "chat");
+        assert_eq!(classifier.classify(&[Some(500.0)]).unwrap(), "stream");
+        assert_eq!(classifier.classify(&[None]).unwrap(), "chat");
+        // Boosted trees with a raw score of the first class alone, positive for the second label
+        let mut classifier = OnnxClassifier::parse(&trees(&[0, 0, 0], &[-2.0, 2.0, 0.5])).unwrap();
+        assert_eq!(classifier.classify(&[Some(50.0)]).unwrap(), "chat");
+        assert_eq!(classifier.classify(&[Some(500.0)]).unwrap(), "stream");
+    }
+
+    #[test]
+    fn unsupported_models() {
+        let error = |data: &[u8]| OnnxClassifier::parse(data).err().unwrap().to_string();
+        assert!(error(&model(&[node("SVMClassifier", "features", "label", &[])])).contains("unsupported operator SVMClassifier"));
+        assert!(error(&model(&[node("Scaler", "other", "scaled", &[])])).contains("not in a chain"));
+        assert!(error(&model(&[node("Scaler", "features", "scaled", &[])])).contains("no LinearClassifier"));
+        assert!(error(&model(&[node("LinearClassifier", "features", "label", &[
+            ("classlabels_ints", Value::Ints(&[0, 1, 2])),
+            ("coefficients", Value::Floats(&[1.0])),
+            ("intercepts", Value::Floats(&[0.0, 0.0])),
+        ])])).contains("do not match"));
+        assert!(error(&[0x3a, 0x10, 0x0a]).contains("truncated"));
+        assert!(error(&[0x08, 0x07]).contains("no graph"));
+    }
+}
@@ -0,0 +1,436 @@
+use std::fs;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::path::Path;
+
+/// Type of the section header block, which is also the magic at the beginning of every pcapng file
+const BLOCK_SECTION_HEADER: u32 = 0x0A0D0D0A;
+const BLOCK_INTERFACE_DESCRIPTION: u32 = 1;
+const BLOCK_SIMPLE_PACKET: u32 = 3;
+const BLOCK_ENHANCED_PACKET: u32 = 6;
+/// Written in the section header in the byte order of the section
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B3C4D;
+/// Options of the interface description block that are used
+const OPTION_END: u16 = 0;
+const OPTION_IF_NAME: u16 = 2;
+const OPTION_IF_TSRESOL: u16 = 9;
+const OPTION_IF_TSOFFSET: u16 = 14;
+/// Application name option of the section header block
+const OPTION_SHB_USERAPPL: u16 = 4;
+/// Link type of Ethernet frames, the only one the connection tracking reads
+pub const LINKTYPE_ETHERNET: u16 = 1;
+/// Timestamp units per second when the interface does not say, which is microseconds
+const DEFAULT_TICKS_PER_SEC: u64 = 1_000_000;
+
+/// An interface of a pcapng file, from its interface description block
+#[derive(Clone, Debug)]
+pub struct PcapngInterface {
+    pub link_type: u16,
+    pub snaplen: u32,
+    pub name: Option<String>,
+    /// Timestamp units per second
+    ticks_per_sec: u64,
+    /// Seconds to add to the timestamps
+    offset_secs: i64,
+}
+
+/// A packet of a pcapng file
+#[derive(Clone, Debug)]
+pub struct PcapngPacket {
+    /// Index of the interface in the file, counting the interfaces of all the sections
+    pub interface_id: u32,
+    /// Capture time in microseconds since the epoch
+    pub ts_micros: i64,
+    /// Original length of the frame on the wire
+    pub len: u32,
+    pub data: Vec<u8>,
+}
+
+/// The interfaces and packets of a pcapng file, as written by Wireshark, dumpcap or multi-interface taps.
+/// Blocks other than the section headers, the interface descriptions and the packets are skipped.
+pub struct PcapngFile {
+    pub interfaces: Vec<PcapngInterface>,
+    pub packets: Vec<PcapngPacket>,
+}
+
+/// Whether the file starts with the pcapng magic
+pub fn is_pcapng(path: &Path) -> Result<bool, Error> {
+    let mut magic = [0u8; 4];
+    match File::open(path)?.read_exact(&mut magic) {
+        Err(error) if error.kind() == ErrorKind::UnexpectedEof => { Ok(false) }
+        Err(error) => { Err(error) }
+        Ok(_) => { Ok(u32::from_le_bytes(magic) == BLOCK_SECTION_HEADER) }
+    }
+}
+
+impl PcapngFile {
+    /// Read all the interfaces and packets of a file, in either byte order, with any number of sections
+    pub fn read(path: &Path) -> Result<PcapngFile, Error> {
+        PcapngFile::parse(&fs::read(path)?)
+    }
+
+    /// Parse all the interfaces and packets of the contents of a file, as [PcapngFile::read]
+    pub fn parse(data: &[u8]) -> Result<PcapngFile, Error> {
+        let mut file = PcapngFile { interfaces: Vec::new(), packets: Vec::new() };
+        // Interface ids are per section, so they are offset by the interfaces of the sections before
+        let mut section_base = 0;
+        let mut big_endian = false;
+        let mut pos = 0;
+        while pos < data.len() {
+            let header = data.get(pos..pos + 12).ok_or_else(|| invalid("truncated block header"))?;
+            let block_type = read_u32(header, 0, big_endian);
+            if block_type == BLOCK_SECTION_HEADER {
+                big_endian = match read_u32(header, 8, false) {
+                    BYTE_ORDER_MAGIC => { false }
+                    magic if magic.swap_bytes() == BYTE_ORDER_MAGIC => { true }
+                    _ => { return Err(invalid("unknown byte order magic")); }
+                };
+                section_base = file.interfaces.len();
+            } else if pos == 0 {
+                return Err(invalid("not a pcapng file"));
+            }
+            let block_len = read_u32(header, 4, big_endian) as usize;
+            if block_len < 12 || !block_len.is_multiple_of(4) {
+                return Err(invalid("invalid block length"));
+            }
+            let block = data.get(pos..pos + block_len).ok_or_else(|| invalid("truncated block"))?;
+            let body = &block[8..block_len - 4];
+            match block_type {
+                BLOCK_INTERFACE_DESCRIPTION => { file.interfaces.push(read_interface(body, big_endian)?) }
+                BLOCK_ENHANCED_PACKET => {
+                    let fields = body.get(..20).ok_or_else(|| invalid("truncated enhanced packet block"))?;
+                    let local_id = read_u32(fields, 0, big_endian) as usize;
+                    let ticks = (read_u32(fields, 4, big_endian) as u64) << 32 | read_u32(fields, 8, big_endian) as u64;
+                    let caplen = read_u32(fields, 12, big_endian) as usize;
+                    let data = body.get(20..20 + caplen).ok_or_else(|| invalid("packet data beyond its block"))?;
+                    let interface = file.interfaces.get(section_base + local_id).ok_or_else(|| invalid("unknown interface id"))?;
+                    file.packets.push(PcapngPacket {
+                        interface_id: (section_base + local_id) as u32,
+                        ts_micros: interface.ts_micros(ticks),
+                        len: read_u32(fields, 16, big_endian),
+                        data: data.to_vec(),
+                    });
+                }
+                BLOCK_SIMPLE_PACKET => {
+                    // No timestamp, and the captured length is the original length cut by the snaplen of the first interface
+                    let len = read_u32(body.get(..4).ok_or_else(|| invalid("truncated simple packet block"))?, 0, big_endian);
+                    let interface = file.interfaces.get(section_base).ok_or_else(|| invalid("simple packet before any interface"))?;
+                    let caplen = if interface.snaplen == 0 { len } else { len.min(interface.snaplen) };
+                    let data = body.get(4..4 + caplen as usize).ok_or_else(|| invalid("packet data beyond its block"))?;
+                    file.packets.push(PcapngPacket { interface_id: section_base as u32, ts_micros: 0, len, data: data.to_vec() });
+                }
+                _ => {}
+            }
+            pos += block_len;
+        }
+        Ok(file)
+    }
+}
+
+impl PcapngInterface {
+    /// Capture time in microseconds of a timestamp in the units of the interface. Times beyond the range saturate,
+    /// since the timestamps and the offset come from the file.
+    fn ts_micros(&self, ticks: u64) -> i64 {
+        let micros = i64::try_from(ticks as u128 * 1_000_000 / self.ticks_per_sec as u128).unwrap_or(i64::MAX);
+        micros.saturating_add(self.offset_secs.saturating_mul(1_000_000))
+    }
+}
+
+/// Parse the body of an interface description block
+fn read_interface(body: &[u8], big_endian: bool) -> Result<PcapngInterface, Error> {
+    let fields = body.get(..8).ok_or_else(|| invalid("truncated interface description block"))?;
+    let mut interface = PcapngInterface {
+        link_type: read_u16(fields, 0, big_endian),
+        snaplen: read_u32(fields, 4, big_endian),
+        name: None,
+        ticks_per_sec: DEFAULT_TICKS_PER_SEC,
+        offset_secs: 0,
+    };
+    let mut options = &body[8..];
+    while options.len() >= 4 {
+        let code = read_u16(options, 0, big_endian);
+        let len = read_u16(options, 2, big_endian) as usize;
+        let value = options.get(4..4 + len).ok_or_else(|| invalid("option beyond its block"))?;
+        match (code, value) {
+            (OPTION_END, _) => { break; }
+            (OPTION_IF_NAME, _) => { interface.name = Some(String::from_utf8_lossy(value).trim_end_matches('\0').to_string()) }
+            (OPTION_IF_TSRESOL, [resolution]) => {
+                // The high bit tells a negative power of 2 from a negative power of 10
+                interface.ticks_per_sec = match resolution & 0x80 {
+                    0 => { 10u64.checked_pow(*resolution as u32) }
+                    _ => { 1u64.checked_shl((resolution & 0x7f) as u32) }
+                }.ok_or_else(|| invalid("unsupported timestamp resolution"))?;
+            }
+            (OPTION_IF_TSOFFSET, _) if len == 8 => {
+                let bytes: [u8; 8] = value.try_into().unwrap_or_default();
+                interface.offset_secs = if big_endian { i64::from_be_bytes(bytes) } else { i64::from_le_bytes(bytes) };
+            }
+            _ => {}
+        }
+        options = options.get(4 + padded(len)..).unwrap_or_default();
+    }
+    Ok(interface)
+}
+
+/// Write frames in the pcapng format, with an interface description per capture interface and microsecond timestamps,
+/// in little endian
+pub struct PcapngWriter<W: Write> {
+    writer: W,
+    interface_count: u32,
+}
+
+impl<W: Write> PcapngWriter<W> {
+    /// Start the file with a section header
+    pub fn new(mut writer: W) -> Result<Self, Error> {
+        let mut body = Vec::new();
+        body.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        body.extend_from_slice(&1u16.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        // Section length is not known
+        body.extend_from_slice(&(-1i64).to_le_bytes());
+        put_option(&mut body, OPTION_SHB_USERAPPL, b"pcap_test");
+        put_option(&mut body, OPTION_END, &[]);
+        write_block(&mut writer, BLOCK_SECTION_HEADER, &body)?;
+        Ok(PcapngWriter { writer, interface_count: 0 })
+    }
+
+    /// Describe the next interface, and return its id for the packets captured on it
+    pub fn add_interface(&mut self, name: Option<&str>, link_type: u16, snaplen: u32) -> Result<u32, Error> {
+        let mut body = Vec::new();
+        body.extend_from_slice(&link_type.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        body.extend_from_slice(&snaplen.to_le_bytes());
+        if let Some(name) = name {
+            put_option(&mut body, OPTION_IF_NAME, name.as_bytes());
+            put_option(&mut body, OPTION_END, &[]);
+        }
+        write_block(&mut self.writer, BLOCK_INTERFACE_DESCRIPTION, &body)?;
+        self.interface_count += 1;
+        Ok(self.interface_count - 1)
+    }
+
+    /// Write a frame captured on the given interface, with its capture time and original length
+    pub fn write_packet(&mut self, interface_id: u32, ts_micros: u64, len: u32, data: &[u8]) -> Result<(), Error> {
+        if interface_id >= self.interface_count {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("Interface {} was not described", interface_id)));
+        }
+        let mut body = Vec::with_capacity(20 + padded(data.len()));
+        body.extend_from_slice(&interface_id.to_le_bytes());
+        body.extend_from_slice(&((ts_micros >> 32) as u32).to_le_bytes());
+        body.extend_from_slice(&(ts_micros as u32).to_le_bytes());
+        body.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        body.extend_from_slice(&len.to_le_bytes());
+        body.extend_from_slice(data);
+        body.resize(20 + padded(data.len()), 0);
+        write_block(&mut self.writer, BLOCK_ENHANCED_PACKET, &body)
+    }
+
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.writer.flush()
+    }
+}
+
+/// Write a block around a body whose length is a multiple of 4
+fn write_block<W: Write>(writer: &mut W, block_type: u32, body: &[u8]) -> Result<(), Error> {
+    let total_len = (body.len() + 12) as u32;
+    writer.write_all(&block_type.to_le_bytes())?;
+    writer.write_all(&total_len.to_le_bytes())?;
+    writer.write_all(body)?;
+    writer.write_all(&total_len.to_le_bytes())
+}
+
+/// Append an option, with its value padded to 4 bytes
+fn put_option(body: &mut Vec<u8>, code: u16, value: &[u8]) {
+    body.extend_from_slice(&code.to_le_bytes());
+    body.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    body.extend_from_slice(value);
+    body.resize(body.len() + padded(value.len()) - value.len(), 0);
+}
+
+/// Length padded to a multiple of 4
+fn padded(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn read_u16(bytes: &[u8], offset: usize, big_endian: bool) -> u16 {
+    let value = [bytes[offset], bytes[offset + 1]];
+    if big_endian { u16::from_be_bytes(value) } else { u16::from_le_bytes(value) }
+}
+
+fn read_u32(bytes: &[u8], offset: usize, big_endian: bool) -> u32 {
+    let value = [bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]];
+    if big_endian { u32::from_be_bytes(value) } else { u32::from_le_bytes(value) }
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("Invalid pcapng file: {}", message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A block of the given type around a body, in the given byte order
+    fn block(block_type: u32, body: &[u8], big_endian: bool) -> Vec<u8> {
+        let total_len = (body.len() + 12) as u32;
+        let mut block = Vec::new();
+        block.extend_from_slice(&u32_bytes(block_type, big_endian));
+        block.extend_from_slice(&u32_bytes(total_len, big_endian));
+        block.extend_from_slice(body);
+        block.extend_from_slice(&u32_bytes(total_len, big_endian));
+        block
+    }
+
+    fn u32_bytes(value: u32, big_endian: bool) -> [u8; 4] {
+        if big_endian { value.to_be_bytes() } else { value.to_le_bytes() }
+    }
+
+    fn u16_bytes(value: u16, big_endian: bool) -> [u8; 2] {
+        if big_endian { value.to_be_bytes() } else { value.to_le_bytes() }
+    }
+
+    fn section_header(big_endian: bool) -> Vec<u8> {
+        let mut body = u32_bytes(BYTE_ORDER_MAGIC, big_endian).to_vec();
+        body.extend_from_slice(&u16_bytes(1, big_endian));
+        body.extend_from_slice(&u16_bytes(0, big_endian));
+        body.extend_from_slice(&(-1i64).to_le_bytes());
+        block(BLOCK_SECTION_HEADER, &body, big_endian)
+    }
+
+    /// An Ethernet interface description, with the given options as code and value
+    fn interface(options: &[(u16, &[u8])], big_endian: bool) -> Vec<u8> {
+        let mut body = u16_bytes(LINKTYPE_ETHERNET, big_endian).to_vec();
+        body.extend_from_slice(&[0, 0]);
+        body.extend_from_slice(&u32_bytes(65535, big_endian));
+        for (code, value) in options {
+            body.extend_from_slice(&u16_bytes(*code, big_endian));
+            body.extend_from_slice(&u16_bytes(value.len() as u16, big_endian));
+            body.extend_from_slice(value);
+            body.resize(body.len() + padded(value.len()) - value.len(), 0);
+        }
+        block(BLOCK_INTERFACE_DESCRIPTION, &body, big_endian)
+    }
+
+    fn packet(local_id: u32, ticks: u64, data: &[u8], big_endian: bool) -> Vec<u8> {
+        let mut body = u32_bytes(local_id, big_endian).to_vec();
+        body.extend_from_slice(&u32_bytes((ticks >> 32) as u32, big_endian));
+        body.extend_from_slice(&u32_bytes(ticks as u32, big_endian));
+        body.extend_from_slice(&u32_bytes(data.len() as u32, big_endian));
+        body.extend_from_slice(&u32_bytes(data.len() as u32 + 10, big_endian));
+        body.extend_from_slice(data);
+        body.resize(20 + padded(data.len()), 0);
+        block(BLOCK_ENHANCED_PACKET, &body, big_endian)
+    }
+
+    fn error_of(data: &[u8]) -> String {
+        match PcapngFile::parse(data) {
+            Ok(_) => { panic!("parsed an invalid file") }
+            Err(error) => { error.to_string() }
+        }
+    }
+
+    #[test]
+    fn written_file_reads_back() {
+        let mut writer = PcapngWriter::new(Vec::new()).unwrap();
+        assert_eq!(writer.add_interface(Some("eth0"), LINKTYPE_ETHERNET, 1500).unwrap(), 0);
+        assert_eq!(writer.add_interface(None, LINKTYPE_ETHERNET, 0).unwrap(), 1);
+        writer.write_packet(1, 1_700_000_000_123_456, 60, b"hello").unwrap();
+        writer.write_packet(0, 5_000_000_000_000, 4, b"abcd").unwrap();
+        assert!(writer.write_packet(2, 0, 0, &[]).is_err());
+        let file = PcapngFile::parse(&writer.writer).unwrap();
+        assert_eq!(file.interfaces.len(), 2);
+        assert_eq!(file.interfaces[0].name.as_deref(), Some("eth0"));
+        assert_eq!(file.interfaces[0].snaplen, 1500);
+        assert_eq!(file.interfaces[1].name, None);
+        assert_eq!(file.packets.len(), 2);
+        assert_eq!(file.packets[0].interface_id, 1);
+        assert_eq!(file.packets[0].ts_micros, 1_700_000_000_123_456);
+        assert_eq!(file.packets[0].len, 60);
+        assert_eq!(file.packets[0].data, b"hello");
+        assert_eq!(file.packets[1].interface_id, 0);
+        assert_eq!(file.packets[1].ts_micros, 5_000_000_000_000);
+        assert_eq!(file.packets[1].data, b"abcd");
+    }
+
+    #[test]
+    fn big_endian_section() {
+        let mut data = section_header(true);
+        data.extend(interface(&[(OPTION_IF_NAME, b"wlan0")], true));
+        data.extend(packet(0, 0x1_0000_0002, b"xyz", true));
+        let file = PcapngFile::parse(&data).unwrap();
+        assert_eq!(file.interfaces[0].link_type, LINKTYPE_ETHERNET);
+        assert_eq!(file.interfaces[0].snaplen, 65535);
+        assert_eq!(file.interfaces[0].name.as_deref(), Some("wlan0"));
+        assert_eq!(file.packets[0].ts_micros, 0x1_0000_0002);
+        assert_eq!(file.packets[0].len, 13);
+        assert_eq!(file.packets[0].data, b"xyz");
+    }
+
+    #[test]
+    fn interface_ids_count_the_sections_before() {
+        let mut data = section_header(false);
+        data.extend(interface(&[], false));
+        data.extend(interface(&[], false));
+        data.extend(packet(1, 1, b"a", false));
+        data.extend(section_header(true));
+        data.extend(interface(&[], true));
+        data.extend(packet(0, 2, b"b", true));
+        let file = PcapngFile::parse(&data).unwrap();
+        assert_eq!(file.interfaces.len(), 3);
+        let ids: Vec<u32> = file.packets.iter().map(|packet| packet.interface_id).collect();
+        assert_eq!(ids, vec![1, 2]);
+        // Local id 1 is not in the second section
+        data.extend(packet(1, 3, b"c", true));
+        assert!(error_of(&data).contains("unknown interface id"));
+    }
+
+    #[test]
+    fn timestamp_resolutions() {
+        let mut data = section_header(false);
+        data.extend(interface(&[(OPTION_IF_TSRESOL, &[9])], false));
+        data.extend(interface(&[(OPTION_IF_TSRESOL, &[0x80 | 10])], false));
+        data.extend(interface(&[(OPTION_IF_TSOFFSET, &100i64.to_le_bytes())], false));
+        data.extend(packet(0, 3_000_000_500, &[], false));
+        data.extend(packet(1, 2048 + 512, &[], false));
+        data.extend(packet(2, 7, &[], false));
+        let file = PcapngFile::parse(&data).unwrap();
+        let times: Vec<i64> = file.packets.iter().map(|packet| packet.ts_micros).collect();
+        assert_eq!(times, vec![3_000_000, 2_500_000, 100_000_007]);
+        let mut data = section_header(false);
+        data.extend(interface(&[(OPTION_IF_TSRESOL, &[20])], false));
+        assert!(error_of(&data).contains("unsupported timestamp resolution"));
+    }
+
+    #[test]
+    fn hostile_timestamps_saturate() {
+        let mut data = section_header(false);
+        data.extend(interface(&[(OPTION_IF_TSOFFSET, &i64::MAX.to_le_bytes())], false));
+        data.extend(interface(&[(OPTION_IF_TSOFFSET, &i64::MIN.to_le_bytes())], false));
+        data.extend(packet(0, u64::MAX, &[], false));
+        data.extend(packet(1, 0, &[], false));
+        let file = PcapngFile::parse(&data).unwrap();
+        assert_eq!(file.packets[0].ts_micros, i64::MAX);
+        assert_eq!(file.packets[1].ts_micros, i64::MIN);
+    }
+
+    #[test]
+    fn invalid_block_lengths() {
+        let mut data = section_header(false);
+        data.extend(interface(&[], false));
+        data.extend(packet(0, 0, b"payload", false));
+        assert!(PcapngFile::parse(&data).is_ok());
+        assert!(error_of(&data[..data.len() - 1]).contains("truncated block"));
+        assert!(error_of(&data[..data.len() - 30]).contains("truncated block"));
+        let last = data.len() - 40;
+        let mut odd = data.clone();
+        odd[last + 4..last + 8].copy_from_slice(&30u32.to_le_bytes());
+        assert!(error_of(&odd).contains("invalid block length"));
+        let mut short = data.clone();
+        short[last + 4..last + 8].copy_from_slice(&8u32.to_le_bytes());
+        assert!(error_of(&short).contains("invalid block length"));
+        let mut beyond = data;
+        beyond[last + 20..last + 24].copy_from_slice(&100u32.to_le_bytes());
+        assert!(error_of(&beyond).contains("packet data beyond its block"));
+        assert!(error_of(&packet(0, 0, &[], false)).contains("not a pcapng file"));
+    }
+}
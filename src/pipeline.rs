@@ -0,0 +1,304 @@
+use std::fs;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, Sender, sync_channel, SyncSender};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use log::{debug, error, info, trace, warn};
+use pcap::{Activated, Capture, Packet, PacketHeader};
+use crate::affinity::CpuSet;
+use crate::collector::FlowExporter;
+use crate::conn::{ConnSummary, PacketDir};
+use crate::connections::{Connections, ConnTimeouts};
+use crate::consumer_stats::ConsumerStats;
+use crate::error::Error;
+use crate::event::ConnEvent;
+use crate::netflow::NetflowExporter;
+use crate::shards::{ConnShards, shard_index};
+use crate::shutdown;
+
+/// Max frames queued to a worker thread, after which the capture thread waits for it
+const WORKER_QUEUE_FRAMES: usize = 10000;
+/// Max frames that a worker thread processes under one lock of its shard
+const WORKER_BATCH_FRAMES: usize = 64;
+
+/// A captured frame with its header, copied to be processed by a worker thread
+type CapturedFrame = (PacketHeader, Vec<u8>);
+
+/// Threads that process the captured frames, each into its own shard of the connection table, from its own bounded
+/// queue. All the frames of a connection go to the same thread, so they are processed in the order of the capture.
+pub struct Workers {
+    senders: Vec<SyncSender<CapturedFrame>>,
+    threads: Vec<thread::JoinHandle<()>>,
+}
+
+impl Workers {
+    /// Start a worker thread for every shard, pinned to the given CPUs. Alerts are sent to the given channel, if any,
+    /// with the sequence and signature of their connection.
+    pub fn spawn(shards: &ConnShards, cpus: &Option<CpuSet>, alert_sender: Option<Sender<(u32, u128)>>) -> Self {
+        let count = shards.count();
+        let mut senders = Vec::with_capacity(count);
+        let mut threads = Vec::with_capacity(count);
+        for index in 0..count {
+            let name = if count == 1 { String::from("worker") } else { format!("worker {}", index) };
+            let (sender, receiver) = sync_channel::<CapturedFrame>(WORKER_QUEUE_FRAMES);
+            let shards = shards.clone();
+            let cpus = cpus.clone();
+            let alert_sender = alert_sender.clone();
+            threads.push(thread::spawn(move || {
+                pin_thread(&name, &cpus);
+                process_frames(shards.table(index), &receiver, alert_sender.as_ref());
+            }));
+            senders.push(sender);
+        }
+        Workers { senders, threads }
+    }
+
+    /// Queue a copy of a frame to the worker of its shard, or to all the workers if every shard needs it.
+    /// Blocks while the queue is full, so a slow worker backs the capture up into the kernel buffer.
+    pub fn dispatch(&self, packet: &Packet) {
+        let frame = (*packet.header, packet.data.to_vec());
+        // A worker only stops if it panicked, and then its frames are lost anyway
+        match shard_index(packet.data, self.senders.len()) {
+            Some(index) => { let _ = self.senders[index].send(frame); }
+            None => {
+                for sender in &self.senders {
+                    let _ = sender.send(frame.clone());
+                }
+            }
+        }
+    }
+
+    /// Wait for the workers to process all the queued frames and stop
+    pub fn join(self) {
+        drop(self.senders);
+        for thread in self.threads {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Process the frames of a queue into a shard of the connection table until the queue is closed.
+/// Frames that are already queued are processed in a batch, under a single lock of the shard.
+fn process_frames(shard: &Mutex<Connections>, receiver: &Receiver<CapturedFrame>, alert_sender: Option<&Sender<(u32, u128)>>) {
+    while let Ok(frame) = receiver.recv() {
+        let mut connections = shard.lock().unwrap();
+        for (header, data) in std::iter::once(frame).chain(receiver.try_iter().take(WORKER_BATCH_FRAMES - 1)) {
+            match connections.process_packet(&Packet::new(&header, &data)) {
+                Err(error) => { warn!("{}", error) }
+                Ok(events) => { log_events(&events, &connections, alert_sender) }
+            }
+        }
+    }
+}
+
+/// Log the events of a packet, and send the alerts with the signatures of their connections
+fn log_events(events: &[ConnEvent], connections: &Connections, alert_sender: Option<&Sender<(u32, u128)>>) {
+    for event in events {
+        match event {
+            ConnEvent::Opened { conn_sequence, summary } => {
+                info!("Connection {} opened: {}", conn_sequence, summary);
+            }
+            ConnEvent::Arp { event } => { warn!("ARP: {}", event) }
+            ConnEvent::SynFlood { alarm } => { warn!("{}", alarm) }
+            ConnEvent::ArpCorrelated { conn_sequence, arp, delay } => {
+                warn!("Connection {} was reset or retransmitted {}ms after {}", conn_sequence, delay.as_millis(), arp);
+            }
+            ConnEvent::FirstData { conn_sequence, dir, preview } => {
+                info!("Connection {} first data from {:?}: \"{}\"", conn_sequence, dir, preview);
+            }
+            ConnEvent::Anomaly { conn_sequence, dir, anomaly } if anomaly.is_option_tampering() => {
+                warn!("Connection {} handshake anomaly from {:?}: {}", conn_sequence, dir, anomaly);
+            }
+            ConnEvent::NatTwin { conn_sequence, twin } => {
+                info!("Connection {} is connection {} seen again through a NAT", conn_sequence, twin);
+            }
+            ConnEvent::BufferingStopped { conn_sequence, held_bytes } => {
+                warn!("Connection {} stopped buffering over the budget, holding {} bytes", conn_sequence, held_bytes);
+            }
+            ConnEvent::FlowBufferFull { conn_sequence, dir, limit, stopped } => {
+                let action = if *stopped { "stopped buffering" } else { "drops the payload over it" };
+                warn!("Connection {} from {:?} reached the flow buffer limit of {} bytes and {}", conn_sequence, dir, limit, action);
+            }
+            ConnEvent::Alert { conn_sequence, alert } => {
+                warn!("Connection {} alert: {}", conn_sequence, alert);
+                if let (Some(alert_sender), Some(conn)) = (alert_sender, connections.find_by_sequence(*conn_sequence)) {
+                    let _ = alert_sender.send((*conn_sequence, conn.sign()));
+                }
+            }
+            _ => {}
+        }
+        trace!("Event: {:?}", event);
+    }
+}
+
+/// A buffer taken from one direction of a connection, to be consumed
+pub type ReadyBuffer = (ConnSummary, PacketDir, Vec<u8>);
+
+/// Threads that consume ready buffers, each from its own queue. All the buffers of a connection go to the same thread,
+/// so they are consumed in the order of the stream, while different connections are consumed in parallel.
+#[derive(Clone)]
+pub struct Consumers {
+    senders: Vec<Sender<ReadyBuffer>>,
+}
+
+impl Consumers {
+    /// Start the given number of consumer threads, pinned to the given CPUs, and return them with their stats
+    pub fn spawn(count: usize, cpus: &Option<CpuSet>) -> (Self, Vec<Arc<Mutex<ConsumerStats>>>) {
+        let mut senders = Vec::with_capacity(count);
+        let mut all_stats = Vec::with_capacity(count);
+        for index in 0..count {
+            let name = if count == 1 { String::from("consumer") } else { format!("consumer {}", index) };
+            let stats = Arc::new(Mutex::new(ConsumerStats::new(&name)));
+            let stats_clone = stats.clone();
+            let (sender, receiver) = channel::<ReadyBuffer>();
+            let cpus = cpus.clone();
+            thread::spawn(move || {
+                pin_thread(&name, &cpus);
+                for (summary, dir, data) in receiver {
+                    deliver_buffer(&stats_clone, &summary, &dir, &data);
+                }
+            });
+            senders.push(sender);
+            all_stats.push(stats);
+        }
+        (Consumers { senders }, all_stats)
+    }
+
+    /// Queue a buffer to the consumer of its connection
+    pub fn dispatch(&self, buffer: ReadyBuffer) {
+        let index = buffer.0.conn_sequence as usize % self.senders.len();
+        // A consumer only stops if it panicked, and then its buffers are lost anyway
+        let _ = self.senders[index].send(buffer);
+    }
+}
+
+/// Wait for buffers to become ready in any shard, take them, and dispatch them to the consumers.
+/// They are queued while holding the lock of their shard, so they cannot be overtaken by the last bytes of a reaped
+/// connection. Return when the connection table is gone and no more signals can arrive.
+pub fn dispatch_ready_buffers(connections: &ConnShards, ready_receiver: &Receiver<()>, consumers: &Consumers) {
+    while ready_receiver.recv().is_ok() {
+        // One drain handles all the signals that are already queued
+        while ready_receiver.try_recv().is_ok() {}
+        for table in connections.tables() {
+            table.lock().unwrap().drain_ready().for_each(|buffer| consumers.dispatch(buffer));
+        }
+    }
+}
+
+/// Periodically fail the handshakes that timed out, and remove the connections that were closed before the grace period
+/// or went idle, after dispatching their remaining bytes to the consumers and exporting their flow records, if enabled.
+/// Connections age by the capture time, which a live capture moves with the clock even when no packets come.
+pub fn reap_closed_connections(connections: &ConnShards, consumers: &Consumers, timeouts: &ConnTimeouts, live: bool,
+                               exporter: &mut Option<FlowExporter>, netflow_exporter: &mut Option<NetflowExporter>,
+                               mut features_file: Option<&fs::File>) {
+    loop {
+        thread::sleep(Duration::from_secs(1));
+        let mut failed = Vec::new();
+        let mut reaped = Vec::new();
+        for table in connections.tables() {
+            let mut lock = table.lock().unwrap();
+            if live {
+                lock.advance_clock(SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default());
+            }
+            failed.extend(lock.expire_handshakes(timeouts.handshake));
+            let mut shard_reaped = lock.reap_closed(timeouts);
+            // Queued before the lock is released, after the bytes that were already taken from the same connections
+            for reaped_conn in &mut shard_reaped {
+                for (dir, data) in reaped_conn.remaining.drain(..) {
+                    consumers.dispatch((reaped_conn.summary.clone(), dir, data));
+                }
+            }
+            reaped.extend(shard_reaped);
+        }
+        for event in failed {
+            if let ConnEvent::SetupFailed { conn_sequence, dir } = event {
+                info!("Connection {} setup failed, no SYN/ACK for the SYN sent from {:?}", conn_sequence, dir);
+            }
+        }
+        for reaped_conn in reaped {
+            if let Some(idle) = reaped_conn.idle {
+                info!("Connection {} evicted after {}s without packets", reaped_conn.summary.conn_sequence, idle.as_secs());
+            }
+            info!("Flow record: {}", reaped_conn.summary);
+            if let Some(exporter) = exporter {
+                exporter.export(&reaped_conn.summary);
+            }
+            if let Some(exporter) = netflow_exporter {
+                exporter.export(&reaped_conn.summary);
+            }
+            if let (Some(file), Some(row)) = (&mut features_file, &reaped_conn.features) {
+                if let Err(error) = file.write_all(format!("{}\n", row).as_bytes()) {
+                    error!("Failed to write the features of connection {}, features are not written anymore: {}",
+                        reaped_conn.summary.conn_sequence, error);
+                    features_file = None;
+                }
+            }
+        }
+        if let Some(exporter) = netflow_exporter {
+            exporter.flush();
+        }
+    }
+}
+
+/// Hand a buffer over to processing, and count it with its processing time in the stats of the consumer
+fn deliver_buffer(stats: &Mutex<ConsumerStats>, summary: &ConnSummary, dir: &PacketDir, data: &[u8]) {
+    let start = Instant::now();
+    consume_buffer(summary, dir, data);
+    stats.lock().unwrap().record(data.len(), start.elapsed());
+}
+
+/// Process the bytes that were taken from one direction of a connection
+fn consume_buffer(summary: &ConnSummary, dir: &PacketDir, data: &[u8]) {
+    debug!("Consumed {} bytes from {:?} of {}", data.len(), dir, summary);
+}
+
+/// Track only the headers of the captured packets for the given time, and report the packet rate and the drops
+pub fn run_line_rate_test(cap: &mut Capture<dyn Activated>, connections: &mut Connections, duration: Duration) -> Result<(), Error> {
+    info!("Line-rate test: tracking only the headers of the packets for {}s", duration.as_secs());
+    connections.set_headers_only(true);
+    let start = Instant::now();
+    let (mut packet_count, mut byte_count) = (0u64, 0u64);
+    // Packets of the current second since the start, and the most packets in a second so far
+    let (mut second, mut second_packets, mut peak_packets) = (0u64, 0u64, 0u64);
+    while start.elapsed() < duration && !shutdown::is_requested() {
+        match cap.next() {
+            Err(pcap::Error::TimeoutExpired) => { continue; }
+            Err(error) => { return Err(Error::capture("Line-rate test failed to capture", error)); }
+            Ok(packet) => {
+                packet_count += 1;
+                byte_count += packet.header.len as u64;
+                let _ = connections.process_packet(&packet);
+                let now = start.elapsed().as_secs();
+                if now != second {
+                    peak_packets = peak_packets.max(second_packets);
+                    (second, second_packets) = (now, 0);
+                }
+                second_packets += 1;
+            }
+        }
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+    info!("Line-rate test: {} packets in {:.1}s, average {:.0} packets/s ({:.1} Mbit/s), peak {} packets/s", packet_count,
+        elapsed, packet_count as f64 / elapsed, byte_count as f64 * 8.0 / elapsed / 1e6, peak_packets.max(second_packets));
+    match cap.stats() {
+        Err(error) => { warn!("Line-rate test failed to get the capture statistics: {}", error) }
+        Ok(stats) => {
+            let dropped_percent = stats.dropped as f64 * 100.0 / (stats.received as f64).max(1.0);
+            info!("Line-rate test: {} packets received by the filter, {} dropped by the kernel ({:.2}%), {} dropped by the interface",
+                stats.received, stats.dropped, dropped_percent, stats.if_dropped);
+        }
+    }
+    info!("Packet processing time: {}", connections.latency());
+    Ok(())
+}
+
+/// Pin the calling thread to the given CPUs, if any. A failure is logged but is not fatal.
+pub fn pin_thread(thread_name: &str, cpus: &Option<CpuSet>) {
+    if let Some(cpus) = cpus {
+        match cpus.pin_current_thread() {
+            Err(error) => { error!("Failed to pin the {} thread to CPUs {}: {}", thread_name, cpus, error) }
+            Ok(_) => { info!("Pinned the {} thread to CPUs {}", thread_name, cpus) }
+        }
+    }
+}
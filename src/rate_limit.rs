@@ -0,0 +1,79 @@
+use std::fmt;
+use std::time::Duration;
+use log::warn;
+
+/// Token bucket that caps the rate of new connections, by capture time, so a SYN flood cannot grow the connection table
+/// (and the memory) without bounds. It allows bursts of up to one second worth of connections.
+#[derive(Clone)]
+pub struct ConnRateLimiter {
+    /// New connections allowed per second, or unlimited if 0
+    per_sec: u32,
+    /// Connections that can be added right now, up to the rate per second
+    tokens: f64,
+    /// Capture time of the last refill
+    last_ts: Option<Duration>,
+    /// All time number of new flows that were not added to the table
+    suppressed: u64,
+    /// Flows suppressed since the last report, to warn once per burst instead of once per flow
+    suppressed_since_report: u64,
+}
+
+impl ConnRateLimiter {
+    pub fn new(per_sec: u32) -> Self {
+        Self { per_sec, tokens: per_sec as f64, last_ts: None, suppressed: 0, suppressed_since_report: 0 }
+    }
+
+    /// A limiter for one of the given number of shards of the connection table, that together allow about the same rate
+    pub fn share(&self, count: usize) -> Self {
+        ConnRateLimiter::new(self.per_sec.div_ceil(count.max(1) as u32))
+    }
+
+    /// Add the rate and the suppressed flows of another limiter, such as of another shard of the connection table
+    pub fn merge(&mut self, other: &ConnRateLimiter) {
+        self.per_sec += other.per_sec;
+        self.suppressed += other.suppressed;
+        self.suppressed_since_report += other.suppressed_since_report;
+    }
+
+    /// Answer if a new connection, first seen at the given capture time, may be added to the table.
+    /// A refused connection is counted as suppressed.
+    pub fn admit(&mut self, ts: Duration) -> bool {
+        if self.per_sec == 0 {
+            return true;
+        }
+        if let Some(last_ts) = self.last_ts {
+            let elapsed = ts.saturating_sub(last_ts).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * self.per_sec as f64).min(self.per_sec as f64);
+        }
+        self.last_ts = Some(ts);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return true;
+        }
+        if self.suppressed_since_report == 0 {
+            warn!("New connections exceed {} per second, new flows are suppressed", self.per_sec);
+        }
+        self.suppressed += 1;
+        self.suppressed_since_report += 1;
+        false
+    }
+
+    /// Number of flows suppressed since the last call, to be reported. The next suppressed flow warns again.
+    pub fn take_suppressed(&mut self) -> u64 {
+        std::mem::take(&mut self.suppressed_since_report)
+    }
+
+    /// All time number of suppressed flows
+    pub fn suppressed(&self) -> u64 {
+        self.suppressed
+    }
+}
+
+impl fmt::Display for ConnRateLimiter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.per_sec == 0 {
+            return write!(f, "unlimited");
+        }
+        write!(f, "{} new connections per second, {} suppressed", self.per_sec, self.suppressed)
+    }
+}
@@ -0,0 +1,269 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Error, ErrorKind};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use etherparse::{InternetSlice, SlicedPacket, TransportSlice};
+use log::{info, warn};
+use pcap::{Packet, PacketHeader};
+use crate::connections::Connections;
+use crate::event::ConnEvent;
+use crate::flight_recorder::FlightRecorder;
+use crate::pcapng::{is_pcapng, LINKTYPE_ETHERNET, PcapngFile, PcapngWriter};
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
+
+/// Magic bytes at the beginning of every recording file
+const RECORDING_MAGIC: &[u8; 4] = b"PCTR";
+/// Recording layout version. Files with a different version are rejected.
+const RECORDING_VERSION: u16 = 1;
+/// Largest frame accepted when reading a recording, matching the capture snaplen
+const MAX_FRAME_SIZE: usize = 65535;
+
+/// Format of a recording file
+enum RecordingWriter {
+    /// The compact log of this tool
+    Native(SnapshotWriter<BufWriter<File>>),
+    /// pcapng, readable by Wireshark, with the capture interface as its only interface
+    Pcapng(PcapngWriter<BufWriter<File>>),
+}
+
+/// Record every raw frame fed to the connection tracking, along with its pcap header, into a compact log.
+/// Each frame is flushed right away, so the frame that triggers a crash is already in the log.
+pub struct Recorder {
+    writer: RecordingWriter,
+    /// Number of frames recorded so far
+    frame_count: u64,
+}
+
+impl Recorder {
+    /// Create a recording of the frames captured on the given device, if known, with the given snaplen.
+    /// It is written in pcapng if the file name ends with ".pcapng", or in the compact log otherwise.
+    pub fn create(path: &Path, device: Option<&str>, snaplen: u32) -> Result<Recorder, Error> {
+        let file = BufWriter::new(File::create(path)?);
+        let writer = if path.extension().is_some_and(|extension| extension == "pcapng") {
+            let mut writer = PcapngWriter::new(file)?;
+            writer.add_interface(device, LINKTYPE_ETHERNET, snaplen)?;
+            writer.flush()?;
+            RecordingWriter::Pcapng(writer)
+        } else {
+            let mut writer = SnapshotWriter::new(file);
+            for b in RECORDING_MAGIC {
+                writer.put_u8(*b)?;
+            }
+            writer.put_u16(RECORDING_VERSION)?;
+            writer.flush()?;
+            RecordingWriter::Native(writer)
+        };
+        Ok(Recorder { writer, frame_count: 0 })
+    }
+
+    /// Append a frame with its timestamp and lengths
+    pub fn record(&mut self, packet: &Packet) -> Result<(), Error> {
+        match &mut self.writer {
+            RecordingWriter::Native(writer) => {
+                writer.put_u64(packet.header.ts.tv_sec as u64)?;
+                writer.put_u32(packet.header.ts.tv_usec as u32)?;
+                writer.put_u32(packet.header.len)?;
+                writer.put_bytes(packet.data)?;
+                writer.flush()?;
+            }
+            RecordingWriter::Pcapng(writer) => {
+                let ts_micros = packet.header.ts.tv_sec as u64 * 1_000_000 + packet.header.ts.tv_usec as u64;
+                writer.write_packet(0, ts_micros, packet.header.len, packet.data)?;
+                writer.flush()?;
+            }
+        }
+        self.frame_count += 1;
+        Ok(())
+    }
+
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+}
+
+/// One frame read back from a recording
+struct RecordedFrame {
+    /// Capture time in microseconds since the epoch, after the source's time offset is applied
+    ts_micros: i64,
+    /// Original length of the frame on the wire
+    len: u32,
+    data: Vec<u8>,
+}
+
+/// Read all the frames of a recording, or of a pcapng file, into memory
+fn read_recording(path: &Path) -> Result<Vec<RecordedFrame>, Error> {
+    if is_pcapng(path)? {
+        return read_pcapng(path);
+    }
+    let mut reader = SnapshotReader::new(BufReader::new(File::open(path)?));
+    for b in RECORDING_MAGIC {
+        if reader.get_u8()? != *b {
+            return Err(Error::new(ErrorKind::InvalidData, format!("{} is not a recording file", path.display())));
+        }
+    }
+    let version = reader.get_u16()?;
+    if version != RECORDING_VERSION {
+        return Err(Error::new(ErrorKind::InvalidData,
+                              format!("Recording version {} is not supported (expected {})", version, RECORDING_VERSION)));
+    }
+
+    let mut frames = Vec::new();
+    loop {
+        // A clean end of file is only allowed between frames
+        let tv_sec = match reader.get_u64() {
+            Err(error) if error.kind() == ErrorKind::UnexpectedEof => { return Ok(frames); }
+            other => { other? as i64 }
+        };
+        let tv_usec = reader.get_u32()? as i64;
+        let len = reader.get_u32()?;
+        let data = reader.get_bytes(MAX_FRAME_SIZE)?;
+        frames.push(RecordedFrame { ts_micros: tv_sec * 1_000_000 + tv_usec, len, data });
+    }
+}
+
+/// Read the frames of the Ethernet interfaces of a pcapng file, such as one from Wireshark or a multi-interface tap.
+/// The frames of all the interfaces are merged, and those of interfaces with other link types are skipped.
+fn read_pcapng(path: &Path) -> Result<Vec<RecordedFrame>, Error> {
+    let file = PcapngFile::read(path)?;
+    let mut frame_counts = vec![0u64; file.interfaces.len()];
+    let mut frames = Vec::with_capacity(file.packets.len());
+    for packet in file.packets {
+        frame_counts[packet.interface_id as usize] += 1;
+        if file.interfaces[packet.interface_id as usize].link_type == LINKTYPE_ETHERNET {
+            frames.push(RecordedFrame { ts_micros: packet.ts_micros, len: packet.len, data: packet.data });
+        }
+    }
+    for (id, (interface, frame_count)) in file.interfaces.iter().zip(frame_counts).enumerate() {
+        let name = interface.name.as_deref().unwrap_or("unnamed");
+        if interface.link_type == LINKTYPE_ETHERNET {
+            info!("{} interface {} ({}): {} frames", path.display(), id, name, frame_count);
+        } else {
+            warn!("{} interface {} ({}): {} frames skipped, since link type {} is not Ethernet", path.display(), id, name,
+                frame_count, interface.link_type);
+        }
+    }
+    Ok(frames)
+}
+
+/// Capture time of the first SYN (without ACK) of every connection, keyed by the 4-tuple and the ISN,
+/// which identify the same handshake in captures taken at different points.
+fn syn_times(frames: &[RecordedFrame]) -> HashMap<(IpAddr, u16, IpAddr, u16, u32), i64> {
+    let mut result = HashMap::new();
+    for frame in frames {
+        if let Ok(SlicedPacket { ip: Some(ip), transport: Some(TransportSlice::Tcp(tcp)), .. })
+            = SlicedPacket::from_ethernet(&frame.data) {
+            let (src, dst): (IpAddr, IpAddr) = match ip {
+                InternetSlice::Ipv4(ip, _) => { (ip.source_addr().into(), ip.destination_addr().into()) }
+                InternetSlice::Ipv6(ip, _) => { (ip.source_addr().into(), ip.destination_addr().into()) }
+            };
+            if tcp.syn() && !tcp.ack() {
+                let key = (src, tcp.source_port(), dst, tcp.destination_port(), tcp.sequence_number());
+                result.entry(key).or_insert(frame.ts_micros);
+            }
+        }
+    }
+    result
+}
+
+/// Estimate the clock offset of a capture relative to the reference capture, in microseconds.
+/// It is the median difference between the times of the handshakes seen in both, or None if none is shared.
+fn estimate_offset(reference: &[RecordedFrame], other: &[RecordedFrame]) -> Option<i64> {
+    let reference_syns = syn_times(reference);
+    let mut diffs: Vec<i64> = syn_times(other).iter()
+        .filter_map(|(key, ts)| reference_syns.get(key).map(|ref_ts| ref_ts - ts))
+        .collect();
+    if diffs.is_empty() {
+        return None;
+    }
+    diffs.sort_unstable();
+    Some(diffs[diffs.len() / 2])
+}
+
+/// Feed the frames of one or more recordings to the given connections, merged by capture time.
+/// Source clocks may be aligned by manual offsets (seconds, per source index) that are added to the source's times,
+/// or else by offsets estimated relative to the first source.
+/// Frames are also kept by the flight recorder, if any, which dumps them when an alert fires.
+/// Return the number of frames replayed.
+pub fn replay(paths: &[PathBuf], manual_offsets: &HashMap<usize, f64>, estimate_offsets: bool,
+              connections: &mut Connections, flight_recorder: &mut Option<FlightRecorder>) -> Result<u64, Error> {
+    let mut sources = Vec::new();
+    for path in paths {
+        sources.push(read_recording(path)?);
+    }
+
+    for i in 0..sources.len() {
+        let offset = match manual_offsets.get(&i) {
+            Some(seconds) => { (seconds * 1_000_000f64) as i64 }
+            None if estimate_offsets && i > 0 => {
+                match estimate_offset(&sources[0], &sources[i]) {
+                    Some(offset) => { offset }
+                    None => {
+                        warn!("No handshake of {} is shared with {}, so its clock offset cannot be estimated",
+                            paths[i].display(), paths[0].display());
+                        0
+                    }
+                }
+            }
+            None => { 0 }
+        };
+        if offset != 0 {
+            info!("Clock offset of {} is {:.6} seconds", paths[i].display(), offset as f64 / 1_000_000f64);
+            for frame in sources[i].iter_mut() {
+                frame.ts_micros += offset;
+            }
+        }
+    }
+
+    // Stable sort, so frames with identical times keep the order of their sources
+    let mut frames: Vec<RecordedFrame> = sources.into_iter().flatten().collect();
+    frames.sort_by_key(|frame| frame.ts_micros);
+
+    let mut frame_count = 0u64;
+    for frame in &frames {
+        let header = PacketHeader {
+            ts: libc::timeval {
+                tv_sec: frame.ts_micros.div_euclid(1_000_000) as libc::time_t,
+                tv_usec: frame.ts_micros.rem_euclid(1_000_000) as libc::suseconds_t,
+            },
+            caplen: frame.data.len() as u32,
+            len: frame.len,
+        };
+        let packet = Packet::new(&header, &frame.data);
+        if let Some(flight_recorder) = flight_recorder {
+            flight_recorder.push(&packet);
+        }
+        match connections.process_packet(&packet) {
+            Err(error) => { warn!("Replayed frame {}: {}", frame_count, error) }
+            Ok(events) => {
+                for event in events {
+                    match event {
+                        ConnEvent::FirstData { conn_sequence, dir, preview } => {
+                            info!("Connection {} first data from {:?}: \"{}\"", conn_sequence, dir, preview);
+                        }
+                        ConnEvent::Anomaly { conn_sequence, dir, anomaly } if anomaly.is_option_tampering() => {
+                            warn!("Connection {} handshake anomaly from {:?}: {}", conn_sequence, dir, anomaly);
+                        }
+                        ConnEvent::NatTwin { conn_sequence, twin } => {
+                            info!("Connection {} is connection {} seen again through a NAT", conn_sequence, twin);
+                        }
+                        ConnEvent::Arp { event } => { warn!("ARP: {}", event) }
+                        ConnEvent::SynFlood { alarm } => { warn!("{}", alarm) }
+                        ConnEvent::ArpCorrelated { conn_sequence, arp, delay } => {
+                            warn!("Connection {} was reset or retransmitted {}ms after {}", conn_sequence, delay.as_millis(), arp);
+                        }
+                        ConnEvent::Alert { conn_sequence, alert } => {
+                            warn!("Connection {} alert: {}", conn_sequence, alert);
+                            if let (Some(flight_recorder), Some(conn)) = (flight_recorder.as_mut(), connections.find_by_sequence(conn_sequence)) {
+                                flight_recorder.dump(conn_sequence, conn.sign());
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+        frame_count += 1;
+    }
+    Ok(frame_count)
+}
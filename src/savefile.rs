@@ -0,0 +1,99 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{BufWriter, Error, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use pcap::Packet;
+use crate::connections::capture_ts;
+use crate::flight_recorder::{write_pcap_file_header, write_pcap_record, PCAP_HEADER_LEN, PCAP_RECORD_HEADER_LEN};
+
+/// Writes every captured frame to a pcap savefile, as tcpdump does with -w, while the connections are tracked.
+/// The file is rotated to a new one with a "-1", "-2", ... suffix before its extension when it reaches the size limit,
+/// or when the rotation interval of capture time passed since its first frame, whichever comes first.
+pub struct SavefileWriter {
+    path: PathBuf,
+    snaplen: u32,
+    linktype: u32,
+    /// Bytes after which the file is rotated, or never if 0
+    max_file_bytes: u64,
+    /// Capture time after which the file is rotated, if any
+    rotate_interval: Option<Duration>,
+    writer: BufWriter<File>,
+    /// Index of the current file, where the first one has no suffix
+    file_index: u32,
+    /// Bytes written to the current file, and the capture time of its first frame, if any
+    file_bytes: u64,
+    file_start: Option<Duration>,
+    frame_count: u64,
+    byte_count: u64,
+}
+
+impl SavefileWriter {
+    /// Create the first file, for frames of the given link type captured with the given snap length
+    pub fn create(path: &Path, snaplen: u32, linktype: u32, max_file_bytes: u64, rotate_interval: Option<Duration>) -> Result<Self, Error> {
+        let writer = create_file(path, snaplen, linktype)?;
+        Ok(SavefileWriter { path: path.to_path_buf(), snaplen, linktype, max_file_bytes, rotate_interval, writer,
+            file_index: 0, file_bytes: PCAP_HEADER_LEN, file_start: None, frame_count: 0, byte_count: PCAP_HEADER_LEN })
+    }
+
+    /// Append a frame, to a new file if the current one is due for rotation
+    pub fn write(&mut self, packet: &Packet) -> Result<(), Error> {
+        let ts = capture_ts(packet.header);
+        let frame_len = PCAP_RECORD_HEADER_LEN + packet.data.len() as u64;
+        // A file has at least one frame, even if it is larger than the limit
+        let full = self.max_file_bytes > 0 && self.file_bytes + frame_len > self.max_file_bytes;
+        let expired = matches!((self.file_start, self.rotate_interval),
+            (Some(start), Some(interval)) if ts.saturating_sub(start) >= interval);
+        if self.file_start.is_some() && (full || expired) {
+            self.rotate()?;
+        }
+        write_pcap_record(&mut self.writer, packet.header.ts.tv_sec as u64, packet.header.ts.tv_usec as u32,
+                          packet.header.len, packet.data)?;
+        self.file_start.get_or_insert(ts);
+        self.file_bytes += frame_len;
+        self.byte_count += frame_len;
+        self.frame_count += 1;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> Result<(), Error> {
+        self.writer.flush()?;
+        self.file_index += 1;
+        self.writer = create_file(&self.file_path(), self.snaplen, self.linktype)?;
+        self.file_bytes = PCAP_HEADER_LEN;
+        self.file_start = None;
+        self.byte_count += PCAP_HEADER_LEN;
+        Ok(())
+    }
+
+    /// Path of the current file
+    fn file_path(&self) -> PathBuf {
+        if self.file_index == 0 {
+            return self.path.clone();
+        }
+        let stem = self.path.file_stem().unwrap_or_default().to_string_lossy();
+        let name = match self.path.extension() {
+            None => { format!("{}-{}", stem, self.file_index) }
+            Some(extension) => { format!("{}-{}.{}", stem, self.file_index, extension.to_string_lossy()) }
+        };
+        self.path.with_file_name(name)
+    }
+
+    /// Write the frames that are still buffered to the current file
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.writer.flush()
+    }
+}
+
+impl fmt::Display for SavefileWriter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} frames, {} bytes in {} files, the last is {}", self.frame_count, self.byte_count,
+               self.file_index + 1, self.file_path().display())
+    }
+}
+
+fn create_file(path: &Path, snaplen: u32, linktype: u32) -> Result<BufWriter<File>, Error> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    write_pcap_file_header(&mut writer, snaplen, linktype)?;
+    Ok(writer)
+}
@@ -0,0 +1,211 @@
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use etherparse::PacketBuilder;
+use log::{error, info};
+use pcap::{Packet, PacketHeader};
+use crate::conn::{ConnState, PacketDir};
+use crate::connections::Connections;
+use crate::error::Error;
+use crate::event::ConnEvent;
+use crate::flow_buff::FilledRanges;
+
+const CLIENT: ([u8; 4], u16) = ([10, 0, 0, 1], 40000);
+const SERVER: ([u8; 4], u16) = ([10, 0, 0, 2], 80);
+const CLIENT_ISN: u32 = 1000;
+const SERVER_ISN: u32 = 5000;
+/// Size of the server segments, so the response takes a few of them
+const SEGMENT_SIZE: usize = 1000;
+
+/// Start and inclusive end of a filled range
+type FilledRange = (usize, usize);
+/// Filled ranges of a stream, as added by segments that touch, overlap, arrive out of order or are duplicated, and
+/// the ranges they merge into, with inclusive ends
+const FILLED_RANGE_CASES: [(&str, &[FilledRange], &[FilledRange]); 6] = [
+    ("adjacent", &[(0, 9), (10, 19)], &[(0, 19)]),
+    ("gap fill", &[(0, 9), (20, 29), (10, 19)], &[(0, 29)]),
+    ("out of order", &[(40, 49), (20, 29), (0, 9)], &[(0, 9), (20, 29), (40, 49)]),
+    ("partial overlaps", &[(10, 19), (5, 12), (17, 25), (30, 35), (24, 31)], &[(5, 35)]),
+    ("duplicates", &[(0, 9), (0, 9), (3, 5), (0, 4)], &[(0, 9)]),
+    ("spanning", &[(10, 12), (20, 22), (30, 32), (0, 40)], &[(0, 40)]),
+];
+
+/// Builds the frames of one side of the synthetic connection, with increasing capture times
+struct FrameBuilder {
+    ts_micros: i64,
+    frames: Vec<(i64, Vec<u8>)>,
+}
+
+impl FrameBuilder {
+    /// Add a frame from the client (or else from the server) with the given flags, 1ms after the previous one
+    fn push(&mut self, from_client: bool, seq: u32, ack: Option<u32>, syn: bool, fin: bool, payload: &[u8]) {
+        let ((src_ip, src_port), (dst_ip, dst_port)) = if from_client { (CLIENT, SERVER) } else { (SERVER, CLIENT) };
+        let mut builder = PacketBuilder::ethernet2([2, 0, 0, 0, 0, 1], [2, 0, 0, 0, 0, 2])
+            .ipv4(src_ip, dst_ip, 64)
+            .tcp(src_port, dst_port, seq, 65535);
+        if syn {
+            builder = builder.syn();
+        }
+        if fin {
+            builder = builder.fin();
+        }
+        if let Some(ack) = ack {
+            builder = builder.ack(ack);
+        }
+        if !payload.is_empty() {
+            builder = builder.psh();
+        }
+        let mut frame = Vec::with_capacity(builder.size(payload.len()));
+        // Writing to a vector does not fail
+        let _ = builder.write(&mut frame, payload);
+        self.ts_micros += 1000;
+        self.frames.push((self.ts_micros, frame));
+    }
+}
+
+/// Payload of a given length that is easy to tell apart when reassembled in the wrong order
+fn pattern(len: usize, seed: u8) -> Vec<u8> {
+    (0..len).map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed)).collect()
+}
+
+/// Feed a synthetic connection through the raw-frame API: a handshake, a request, a response in segments that arrive
+/// out of order with one retransmission, and a teardown. Verify the counters, the state transitions and the reassembled
+/// bytes, and the merging of filled ranges on its own, and log every check. Return an error if any of them failed.
+pub fn run() -> Result<(), Error> {
+    let request = pattern(300, 1);
+    let response = pattern(3 * SEGMENT_SIZE, 7);
+    let client_data_seq = CLIENT_ISN + 1;
+    let server_data_seq = SERVER_ISN + 1;
+    let client_fin_seq = client_data_seq + request.len() as u32;
+    let server_fin_seq = server_data_seq + response.len() as u32;
+
+    let mut builder = FrameBuilder { ts_micros: 1_700_000_000_000_000, frames: Vec::new() };
+    builder.push(true, CLIENT_ISN, None, true, false, &[]);
+    builder.push(false, SERVER_ISN, Some(client_data_seq), true, false, &[]);
+    builder.push(true, client_data_seq, Some(server_data_seq), false, false, &[]);
+    builder.push(true, client_data_seq, Some(server_data_seq), false, false, &request);
+    // The second segment of the response arrives before the first, and the first is sent twice
+    let segment_seq = |index: usize| server_data_seq + (index * SEGMENT_SIZE) as u32;
+    let segment = |index: usize| &response[index * SEGMENT_SIZE..(index + 1) * SEGMENT_SIZE];
+    builder.push(false, segment_seq(1), Some(client_fin_seq), false, false, segment(1));
+    builder.push(false, segment_seq(0), Some(client_fin_seq), false, false, segment(0));
+    builder.push(false, segment_seq(0), Some(client_fin_seq), false, false, segment(0));
+    builder.push(false, segment_seq(2), Some(client_fin_seq), false, false, segment(2));
+    builder.push(true, client_fin_seq, Some(server_fin_seq), false, false, &[]);
+    builder.push(true, client_fin_seq, Some(server_fin_seq), false, true, &[]);
+    builder.push(false, server_fin_seq, Some(client_fin_seq + 1), false, true, &[]);
+    builder.push(true, client_fin_seq + 1, Some(server_fin_seq + 1), false, false, &[]);
+
+    let mut connections = Connections::new();
+    let mut events = Vec::new();
+    for (ts_micros, frame) in &builder.frames {
+        let header = PacketHeader {
+            ts: libc::timeval {
+                tv_sec: (ts_micros / 1_000_000) as libc::time_t,
+                tv_usec: (ts_micros % 1_000_000) as libc::suseconds_t,
+            },
+            caplen: frame.len() as u32,
+            len: frame.len() as u32,
+        };
+        events.extend(connections.process_packet(&Packet::new(&header, frame))?);
+    }
+
+    let mut failures = 0;
+    let mut check = |name: &str, passed: bool, detail: String| {
+        if passed {
+            info!("Self-test {}: ok", name);
+        } else {
+            error!("Self-test {}: FAILED, {}", name, detail);
+            failures += 1;
+        }
+    };
+
+    let new_conns = events.iter().filter(|event| matches!(event, ConnEvent::NewConnection { .. })).count();
+    check("new connection", new_conns == 1, format!("{} connections were added", new_conns));
+    let opened = events.iter().any(|event| matches!(event, ConnEvent::Opened { .. }));
+    check("handshake", opened, String::from("the connection was not opened"));
+
+    let states: Vec<String> = events.iter().filter_map(|event| match event {
+        ConnEvent::StateChange { to, .. } => { Some(state_name(to).to_string()) }
+        _ => { None }
+    }).collect();
+    let expected_states = ["SynSent", "Established", "FinWait1", "LastAck", "TimeWait"];
+    check("state transitions", states == expected_states, format!("expected {}, got {}", expected_states.join(" > "),
+                                                                     states.join(" > ")));
+
+    let summaries = connections.summaries();
+    match summaries.first() {
+        None => { check("counters", false, String::from("the connection is not in the table")) }
+        Some(summary) => {
+            // The client has the lower address
+            let packets = (summary.packets_src_low, summary.packets_src_high);
+            check("packet counters", packets == (6, 6), format!("expected 6/6 packets, got {}/{}", packets.0, packets.1));
+            // Bytes are counted as seen on the wire, including the retransmitted segment
+            let expected_bytes = (request.len() as u64, (response.len() + SEGMENT_SIZE) as u64);
+            let bytes = (summary.bytes_src_low, summary.bytes_src_high);
+            check("byte counters", bytes == expected_bytes,
+                  format!("expected {}/{} bytes, got {}/{}", expected_bytes.0, expected_bytes.1, bytes.0, bytes.1));
+        }
+    }
+    let segments = connections.find_conn(socket_addr(CLIENT), socket_addr(SERVER))
+        .map(|conn| conn.flow(&PacketDir::SrcHighAddr).segments.clone()).unwrap_or_default();
+    // The late first segment fills the hole that the second one left, and its copy carries no new bytes
+    let kinds = (segments.in_order, segments.out_of_order, segments.retransmitted, segments.overlapping);
+    check("segment order", kinds == (2, 1, 1, 0),
+          format!("expected 2 in order, 1 out of order, 1 retransmitted, got {} in order, {}", segments.in_order, segments));
+
+    let (mut client_bytes, mut server_bytes) = (Vec::new(), Vec::new());
+    for (_, dir, data) in connections.drain_ready() {
+        match dir {
+            PacketDir::SrcLowAddr => { client_bytes.extend(data) }
+            PacketDir::SrcHighAddr => { server_bytes.extend(data) }
+        }
+    }
+    check("reassembled request", client_bytes == request,
+          format!("expected {} bytes, got {} bytes that differ", request.len(), client_bytes.len()));
+    check("reassembled response", server_bytes == response,
+          format!("expected {} bytes, got {} bytes that differ", response.len(), server_bytes.len()));
+
+    for (name, added, expected) in FILLED_RANGE_CASES {
+        let mut ranges = FilledRanges::new();
+        for (start, end) in added {
+            ranges.insert(*start, *end);
+        }
+        let merged: Vec<FilledRange> = ranges.iter().map(|range| (range.start, range.end)).collect();
+        check(&format!("filled ranges, {}", name), merged == expected, format!("expected {:?}, got {:?}", expected, merged));
+    }
+    // Bytes after a hole become ready once it is filled
+    let mut ranges = FilledRanges::new();
+    ranges.insert(0, 9);
+    ranges.insert(20, 29);
+    let before_fill = ranges.contiguous_len(0);
+    ranges.insert(10, 19);
+    let after_fill = ranges.contiguous_len(0);
+    check("filled ranges, ready bytes", (before_fill, after_fill) == (10, 30),
+          format!("expected 10 then 30 bytes, got {} then {}", before_fill, after_fill));
+
+    if failures > 0 {
+        return Err(Error::Parse(format!("{} self-test checks failed", failures)));
+    }
+    info!("Self-test passed");
+    Ok(())
+}
+
+fn socket_addr((ip, port): ([u8; 4], u16)) -> SocketAddr {
+    SocketAddrV4::new(Ipv4Addr::from(ip), port).into()
+}
+
+fn state_name(state: &ConnState) -> &'static str {
+    match state {
+        ConnState::Created => { "Created" }
+        ConnState::SynSent(..) => { "SynSent" }
+        ConnState::Established(_) => { "Established" }
+        ConnState::EstablishedMidstream => { "EstablishedMidstream" }
+        ConnState::SynReceived(..) => { "SynReceived" }
+        ConnState::FinWait1(..) => { "FinWait1" }
+        ConnState::CloseWait(_) => { "CloseWait" }
+        ConnState::Closing(..) => { "Closing" }
+        ConnState::LastAck(..) => { "LastAck" }
+        ConnState::TimeWait(_) => { "TimeWait" }
+        ConnState::Closed(_) => { "Closed" }
+        ConnState::Failed(_) => { "Failed" }
+    }
+}
@@ -0,0 +1,211 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use crate::arp::ETHER_TYPE_ARP;
+use crate::conn::ConnSummary;
+use crate::connections::{BufferBudget, BufferWatermarks, Connections, TableReport, TopOrder};
+use crate::hosts::HostTable;
+use crate::ipv6::{ETHER_TYPE_IPV6, ETHER_TYPES_VLAN};
+use crate::leak::TableSample;
+use crate::utils::mix64;
+
+/// Ether type of IPv4
+const ETHER_TYPE_IPV4: u16 = 0x0800;
+
+/// A connection table that is split into shards by the addresses of the packets, so every shard is processed by its
+/// own worker thread, without one lock for all the packets. The other threads query all the shards.
+#[derive(Clone)]
+pub struct ConnShards {
+    tables: Arc<Vec<Mutex<Connections>>>,
+}
+
+impl ConnShards {
+    /// Split the table into the given number of shards, see [Connections::split]
+    pub fn new(connections: Connections, count: usize) -> Self {
+        ConnShards { tables: Arc::new(connections.split(count).into_iter().map(Mutex::new).collect()) }
+    }
+
+    /// Number of shards
+    pub fn count(&self) -> usize {
+        self.tables.len()
+    }
+
+    /// The shard of the given index, as picked by [shard_index]
+    pub fn table(&self, index: usize) -> &Mutex<Connections> {
+        &self.tables[index]
+    }
+
+    pub fn tables(&self) -> impl Iterator<Item=&Mutex<Connections>> {
+        self.tables.iter()
+    }
+
+    /// Set the capture time that the connection identifiers are relative to in all the shards, from the first packet
+    pub fn set_capture_start(&self, ts: Duration) {
+        for table in self.tables() {
+            table.lock().unwrap().set_capture_start(ts);
+        }
+    }
+
+    /// The shard that holds the connection between the two given endpoints, if any
+    pub fn conn_shard(&self, a: SocketAddr, b: SocketAddr) -> Option<&Mutex<Connections>> {
+        self.tables().find(|table| table.lock().unwrap().find_conn(a, b).is_some())
+    }
+
+    /// Summaries of the connections that have the given IP, and the given port if any, on either side
+    pub fn find(&self, ip: IpAddr, port: Option<u16>) -> Vec<ConnSummary> {
+        self.collect(|connections| connections.find(ip, port))
+    }
+
+    /// Summaries of the connections between the two given IPs
+    pub fn find_pair(&self, a: IpAddr, b: IpAddr) -> Vec<ConnSummary> {
+        self.collect(|connections| connections.find_pair(a, b))
+    }
+
+    /// Summaries of all the connections, sorted by connection sequence
+    pub fn summaries(&self) -> Vec<ConnSummary> {
+        self.collect(|connections| connections.summaries())
+    }
+
+    /// Summaries of the given number of connections of all the shards that rank first, see [Connections::top]
+    pub fn top(&self, count: usize, order: TopOrder) -> Vec<ConnSummary> {
+        let mut summaries: Vec<ConnSummary> = self.tables().flat_map(|table| table.lock().unwrap().top(count, order)).collect();
+        summaries.sort_by_key(|summary| (std::cmp::Reverse(order.summary_key(summary)), summary.conn_sequence));
+        summaries.truncate(count);
+        summaries
+    }
+
+    /// Summaries from all the shards, sorted by connection sequence
+    fn collect<F: Fn(&Connections) -> Vec<ConnSummary>>(&self, find: F) -> Vec<ConnSummary> {
+        let mut summaries: Vec<ConnSummary> = self.tables().flat_map(|table| find(&table.lock().unwrap())).collect();
+        summaries.sort_by_key(|summary| summary.conn_sequence);
+        summaries
+    }
+
+    /// Attach a tag to the connection between the two given endpoints, see [Connections::tag_conn]
+    pub fn tag_conn(&self, a: SocketAddr, b: SocketAddr, key: &str, value: &str) -> bool {
+        self.tables().any(|table| table.lock().unwrap().tag_conn(a, b, key, value))
+    }
+
+    /// Counters of all the shards and of every connection, each shard sampled under its own lock
+    pub fn sample(&self) -> TableSample {
+        let mut samples = self.tables().map(|table| table.lock().unwrap().sample());
+        let mut sample = samples.next().expect("At least one shard");
+        samples.for_each(|other| sample.merge(other));
+        sample
+    }
+
+    /// Profiles of the hosts of the finalized connections of all the shards
+    pub fn hosts(&self) -> HostTable {
+        let mut hosts = HostTable::default();
+        for table in self.tables() {
+            hosts.merge(table.lock().unwrap().hosts());
+        }
+        hosts
+    }
+
+    /// Bytes that were buffered and not consumed yet in all the shards, see [BufferWatermarks::merge]
+    pub fn buffer_watermarks(&self) -> BufferWatermarks {
+        let mut watermarks = BufferWatermarks::default();
+        for table in self.tables() {
+            watermarks.merge(&table.lock().unwrap().buffer_watermarks());
+        }
+        watermarks
+    }
+
+    /// The buffering budget of all the shards together, if set
+    pub fn buffer_budget(&self) -> Option<BufferBudget> {
+        let mut budgets = self.tables().filter_map(|table| table.lock().unwrap().buffer_budget());
+        let mut budget = budgets.next()?;
+        budgets.for_each(|other| budget.merge(&other));
+        Some(budget)
+    }
+
+    /// Totals of every shard, each taken under its own lock, to be merged with [TableReport::merge] or compared to
+    /// see how evenly the traffic is spread
+    pub fn reports(&self) -> Vec<TableReport> {
+        self.tables().map(|table| table.lock().unwrap().report()).collect()
+    }
+
+    /// Totals of all the shards together
+    pub fn report(&self) -> TableReport {
+        let mut total = TableReport::default();
+        for report in self.reports() {
+            total.merge(&report);
+        }
+        total
+    }
+
+    /// Number of flows that all the shards suppressed over the connection rate limit since the last call
+    pub fn take_suppressed_conns(&self) -> u64 {
+        self.tables().map(|table| table.lock().unwrap().take_suppressed_conns()).sum()
+    }
+
+    /// Take all the shards out as one table, leaving them empty, once they stopped processing packets
+    pub fn merge(&self) -> Connections {
+        let mut tables = self.tables().map(|table| std::mem::take(&mut *table.lock().unwrap()));
+        let mut connections = tables.next().expect("At least one shard");
+        tables.for_each(|other| connections.merge(other));
+        connections
+    }
+}
+
+/// Index of the shard of a captured Ethernet frame, by the hash of its IP addresses, which is the same for both
+/// directions of a connection, or None for an ARP packet, that all the shards correlate their connections with.
+/// Frames that are not IP, or too short, go to the first shard, to be counted there.
+pub fn shard_index(frame: &[u8], count: usize) -> Option<usize> {
+    if count <= 1 {
+        return Some(0);
+    }
+    let (ether_type, ip) = match ether_type(frame) {
+        None => { return Some(0); }
+        Some(ether_type) => { ether_type }
+    };
+    let (addresses, address_len) = match ether_type {
+        ETHER_TYPE_ARP => { return None; }
+        ETHER_TYPE_IPV4 => { (ip + 12, 4) }
+        ETHER_TYPE_IPV6 => { (ip + 8, 16) }
+        _ => { return Some(0); }
+    };
+    match frame.get(addresses..addresses + 2 * address_len) {
+        None => { Some(0) }
+        Some(addresses) => {
+            let (src, dst) = addresses.split_at(address_len);
+            Some(shard_of_addresses(src, dst, count))
+        }
+    }
+}
+
+/// Index of the shard of a connection between the two given IPs, the same as [shard_index] picks for its frames
+pub(crate) fn shard_of_ips(a: IpAddr, b: IpAddr, count: usize) -> usize {
+    let octets = |ip: IpAddr| -> Vec<u8> {
+        match ip {
+            IpAddr::V4(ip) => { ip.octets().to_vec() }
+            IpAddr::V6(ip) => { ip.octets().to_vec() }
+        }
+    };
+    shard_of_addresses(&octets(a), &octets(b), count)
+}
+
+/// The ether type of a frame after up to two VLAN tags, and the offset of its payload
+fn ether_type(frame: &[u8]) -> Option<(u16, usize)> {
+    let mut offset = 12;
+    loop {
+        let ether_type = u16::from_be_bytes([*frame.get(offset)?, *frame.get(offset + 1)?]);
+        if !ETHER_TYPES_VLAN.contains(&ether_type) || offset >= 20 {
+            return Some((ether_type, offset + 2));
+        }
+        offset += 4;
+    }
+}
+
+/// Shard of two addresses, whose hashes are added so the order of the addresses does not matter
+fn shard_of_addresses(a: &[u8], b: &[u8], count: usize) -> usize {
+    let hash = |address: &[u8]| -> u64 {
+        address.chunks(8).fold(0, |hash, chunk| {
+            let mut word = [0u8; 8];
+            word[..chunk.len()].copy_from_slice(chunk);
+            mix64(hash ^ u64::from_be_bytes(word))
+        })
+    };
+    (mix64(hash(a).wrapping_add(hash(b))) % count as u64) as usize
+}
@@ -0,0 +1,36 @@
+use std::io::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Read timeout of a live capture, so a shutdown request is noticed on an idle link
+pub const SHUTDOWN_READ_TIMEOUT_MS: i32 = 1000;
+
+/// Set by the signal handler, and polled by the capture loop
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Only stores the flag, which is all that is safe to do in a signal handler
+extern "C" fn on_signal(_signal: libc::c_int) {
+    SHUTDOWN_REQUESTED.store(true, Ordering::Relaxed);
+}
+
+/// Request a graceful shutdown on SIGINT and SIGTERM, instead of exiting right away. The handler is reset after the
+/// first signal, so a second one exits right away, in case the shutdown hangs.
+pub fn install() -> Result<(), Error> {
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        // A plain sigaction without SA_RESTART, so a blocking read is interrupted
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESETHAND;
+            libc::sigemptyset(&mut action.sa_mask);
+            if libc::sigaction(signal, &action, std::ptr::null_mut()) != 0 {
+                return Err(Error::last_os_error());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether SIGINT or SIGTERM was received since [install]
+pub fn is_requested() -> bool {
+    SHUTDOWN_REQUESTED.load(Ordering::Relaxed)
+}
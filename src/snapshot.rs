@@ -0,0 +1,100 @@
+use std::io::{Error, ErrorKind, Read, Write};
+
+/// Magic bytes at the beginning of every snapshot file
+pub(crate) const SNAPSHOT_MAGIC: &[u8; 4] = b"PCTS";
+/// Snapshot layout version. Files with a different version are rejected.
+pub(crate) const SNAPSHOT_VERSION: u16 = 15;
+
+/// Write primitive values to a snapshot stream, in little-endian byte order.
+pub(crate) struct SnapshotWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> SnapshotWriter<W> {
+    pub(crate) fn new(inner: W) -> Self {
+        Self { inner }
+    }
+
+    pub(crate) fn put_u8(&mut self, value: u8) -> Result<(), Error> {
+        self.inner.write_all(&[value])
+    }
+
+    pub(crate) fn put_u16(&mut self, value: u16) -> Result<(), Error> {
+        self.inner.write_all(&value.to_le_bytes())
+    }
+
+    pub(crate) fn put_u32(&mut self, value: u32) -> Result<(), Error> {
+        self.inner.write_all(&value.to_le_bytes())
+    }
+
+    pub(crate) fn put_u64(&mut self, value: u64) -> Result<(), Error> {
+        self.inner.write_all(&value.to_le_bytes())
+    }
+
+    pub(crate) fn put_u128(&mut self, value: u128) -> Result<(), Error> {
+        self.inner.write_all(&value.to_le_bytes())
+    }
+
+    /// Write a length-prefixed byte array
+    pub(crate) fn put_bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        self.put_u64(bytes.len() as u64)?;
+        self.inner.write_all(bytes)
+    }
+
+    pub(crate) fn flush(&mut self) -> Result<(), Error> {
+        self.inner.flush()
+    }
+}
+
+/// Read primitive values from a snapshot stream, written by [SnapshotWriter].
+pub(crate) struct SnapshotReader<R: Read> {
+    inner: R,
+}
+
+impl<R: Read> SnapshotReader<R> {
+    pub(crate) fn new(inner: R) -> Self {
+        Self { inner }
+    }
+
+    pub(crate) fn get_u8(&mut self) -> Result<u8, Error> {
+        let mut buf = [0u8; 1];
+        self.inner.read_exact(&mut buf)?;
+        Ok(buf[0])
+    }
+
+    pub(crate) fn get_u16(&mut self) -> Result<u16, Error> {
+        let mut buf = [0u8; 2];
+        self.inner.read_exact(&mut buf)?;
+        Ok(u16::from_le_bytes(buf))
+    }
+
+    pub(crate) fn get_u32(&mut self) -> Result<u32, Error> {
+        let mut buf = [0u8; 4];
+        self.inner.read_exact(&mut buf)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    pub(crate) fn get_u64(&mut self) -> Result<u64, Error> {
+        let mut buf = [0u8; 8];
+        self.inner.read_exact(&mut buf)?;
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    pub(crate) fn get_u128(&mut self) -> Result<u128, Error> {
+        let mut buf = [0u8; 16];
+        self.inner.read_exact(&mut buf)?;
+        Ok(u128::from_le_bytes(buf))
+    }
+
+    /// Read a length-prefixed byte array, refusing lengths above the given limit
+    pub(crate) fn get_bytes(&mut self, max_len: usize) -> Result<Vec<u8>, Error> {
+        let len = self.get_u64()? as usize;
+        if len > max_len {
+            return Err(Error::new(ErrorKind::InvalidData,
+                                  format!("Snapshot byte array of {} bytes exceeds the limit of {}", len, max_len)));
+        }
+        let mut res = vec![0u8; len];
+        self.inner.read_exact(&mut res)?;
+        Ok(res)
+    }
+}
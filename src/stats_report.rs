@@ -0,0 +1,141 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use log::info;
+use pcap::Stat;
+use crate::connections::{Connections, TableReport};
+use crate::error::Error;
+use crate::hosts::HostTable;
+use crate::time_format::TimeFormatter;
+
+/// Number of hosts to list in periodic reports
+const REPORT_TOP_HOSTS: usize = 10;
+
+/// Traffic of a live capture over one report interval
+#[derive(Clone, Copy, Debug)]
+pub struct StatsInterval {
+    pub duration: Duration,
+    /// Packets captured, and their length on the wire
+    pub packets: u64,
+    pub bytes: u64,
+    /// Connections that were added to the table, and that were removed from it
+    pub new_conns: u64,
+    pub closed_conns: u64,
+    /// Connections in the table at the end of the interval
+    pub active_conns: usize,
+    /// Packets that were truncated or could not be parsed
+    pub parse_errors: u64,
+    /// Packets dropped by the kernel and by the interface, if the capture counters are known
+    pub dropped: Option<(u32, u32)>,
+}
+
+impl StatsInterval {
+    pub fn packets_per_sec(&self) -> f64 {
+        self.packets as f64 / self.duration.as_secs_f64().max(0.001)
+    }
+
+    pub fn bytes_per_sec(&self) -> f64 {
+        self.bytes as f64 / self.duration.as_secs_f64().max(0.001)
+    }
+}
+
+impl fmt::Display for StatsInterval {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:.0} packets/s, {:.2} Mbit/s, {} new and {} closed connections, {} active, {} parse errors",
+               self.packets_per_sec(), self.bytes_per_sec() * 8.0 / 1e6, self.new_conns, self.closed_conns,
+               self.active_conns, self.parse_errors)?;
+        match self.dropped {
+            None => { write!(f, ", drops unknown")?; }
+            Some((dropped, if_dropped)) => { write!(f, ", {} dropped by the kernel, {} by the interface", dropped, if_dropped)?; }
+        }
+        write!(f, " in the last {}s", self.duration.as_secs())
+    }
+}
+
+/// Reports the traffic of a live capture every interval, as rates and counts since the previous report, so the state
+/// of a capture is known without reading the logs of every packet. It is polled by the capture loop, which has the
+/// capture counters.
+pub struct StatsReporter {
+    interval: Duration,
+    last_report: Instant,
+    /// Packets and bytes captured at the last report
+    packet_count: u64,
+    byte_count: u64,
+    /// Totals of the connection table at the last report
+    table: TableReport,
+    /// Capture counters at the last report, if known
+    capture: Option<Stat>,
+}
+
+impl StatsReporter {
+    pub fn new(interval: Duration) -> Self {
+        StatsReporter {
+            interval,
+            last_report: Instant::now(),
+            packet_count: 0,
+            byte_count: 0,
+            table: TableReport::default(),
+            capture: None,
+        }
+    }
+
+    /// Whether it is time for the next report
+    pub fn is_due(&self) -> bool {
+        self.last_report.elapsed() >= self.interval
+    }
+
+    /// The traffic since the last report, given the packets and bytes captured so far, the totals of the connection
+    /// table and the capture counters, if known
+    pub fn report(&mut self, packet_count: u64, byte_count: u64, table: TableReport, capture: Option<Stat>) -> StatsInterval {
+        let new_conns = table.conn_alltime_count.saturating_sub(self.table.conn_alltime_count) as u64;
+        let parse_errors = |report: &TableReport| report.packet_len_error_count as u64 + report.packet_parsing_error_count as u64;
+        // The counters of the kernel start with the capture, so the first interval counts the drops since then
+        let dropped = capture.map(|stats| {
+            let last = self.capture.unwrap_or(Stat { received: 0, dropped: 0, if_dropped: 0 });
+            (stats.dropped.wrapping_sub(last.dropped), stats.if_dropped.wrapping_sub(last.if_dropped))
+        });
+        let interval = StatsInterval {
+            duration: self.last_report.elapsed(),
+            packets: packet_count - self.packet_count,
+            bytes: byte_count - self.byte_count,
+            new_conns,
+            closed_conns: (self.table.open_conns as u64 + new_conns).saturating_sub(table.open_conns as u64),
+            active_conns: table.open_conns,
+            parse_errors: parse_errors(&table).saturating_sub(parse_errors(&self.table)),
+            dropped,
+        };
+        self.last_report = Instant::now();
+        self.packet_count = packet_count;
+        self.byte_count = byte_count;
+        self.table = table;
+        self.capture = capture;
+        interval
+    }
+}
+
+/// Write the latency heatmap of the connection table, as JSON if the file name ends with ".json", or CSV otherwise
+pub fn write_latency_heatmap(path: &Path, connections: &Connections, time_format: &TimeFormatter) -> Result<(), Error> {
+    if let Some(heatmap) = connections.latency_heatmap() {
+        let json = path.extension().is_some_and(|extension| extension == "json");
+        fs::write(path, if json { heatmap.to_json(time_format).to_pretty_string() + "\n" } else { heatmap.to_csv(time_format) })?;
+        info!("Wrote the latency heatmap to {}", path.display());
+    }
+    Ok(())
+}
+
+/// Write the retransmission timelines of the connection table, as JSON if the file name ends with ".json", or CSV otherwise
+pub fn write_retransmit_timeline(path: &Path, connections: &Connections, time_format: &TimeFormatter) -> Result<(), Error> {
+    let json = path.extension().is_some_and(|extension| extension == "json");
+    fs::write(path, connections.export_retransmits(json, time_format))?;
+    info!("Wrote the retransmission timeline to {}", path.display());
+    Ok(())
+}
+
+/// Log the profiles of the hosts that moved the most bytes
+pub fn log_hosts(hosts: &HostTable) {
+    info!("Hosts: {} seen in finalized connections, top {} by bytes:", hosts.len(), REPORT_TOP_HOSTS);
+    for (ip, host) in hosts.top(REPORT_TOP_HOSTS) {
+        info!("   {}: {}", ip, host);
+    }
+}
@@ -0,0 +1,154 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::Duration;
+use log::info;
+use crate::json::JsonValue;
+
+/// Max destinations tracked. When it is reached, the ones without half-open connections are dropped, and if none is,
+/// the SYNs to new destinations are not counted.
+const MAX_SYN_DESTINATIONS: usize = 65536;
+/// Capture time over which the SYNs to a destination are counted for their rate
+const SYN_RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// A destination that receives SYNs faster than the threshold, or that has more half-open connections than it
+#[derive(Clone, Debug, PartialEq)]
+pub struct SynFloodAlarm {
+    /// Address and port that the SYNs are sent to
+    pub dst: SocketAddr,
+    /// Connections to the destination that sent a SYN and got no SYN/ACK yet
+    pub half_open: u32,
+    /// SYNs of new connections to the destination in the current second of capture time
+    pub syn_rate: u32,
+}
+
+impl SynFloodAlarm {
+    pub fn to_json(&self) -> JsonValue {
+        JsonValue::object(vec![
+            ("alarm", "syn_flood".into()),
+            ("dst", self.dst.to_string().into()),
+            ("half_open", self.half_open.into()),
+            ("syn_rate", self.syn_rate.into()),
+        ])
+    }
+}
+
+impl fmt::Display for SynFloodAlarm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SYN flood to {}: {} half-open connections, {} SYNs in the last second", self.dst, self.half_open,
+               self.syn_rate)
+    }
+}
+
+/// SYNs and half-open connections of one destination
+#[derive(Clone, Copy, Debug, Default)]
+struct DestinationSyns {
+    half_open: u32,
+    /// Capture time of the first SYN of the current rate window, and the SYNs since
+    window_start: Duration,
+    window_syns: u32,
+    /// Whether an alarm was raised, until the destination calms down
+    alarmed: bool,
+}
+
+/// Watches the connections that wait for the answer to their SYN (in the SynSent state), by their destination address
+/// and port, and raises an alarm when a destination has too many of them or receives SYNs too fast, as in a SYN flood
+/// or a port scan. Every destination alarms once, until its half-open connections and SYN rate are down to half the
+/// thresholds.
+#[derive(Clone)]
+pub struct SynFloodDetector {
+    /// Half-open connections per destination that raise an alarm, or never if 0
+    max_half_open: u32,
+    /// SYNs per second to a destination that raise an alarm, or never if 0
+    max_syn_rate: u32,
+    destinations: HashMap<SocketAddr, DestinationSyns>,
+    alarm_count: u64,
+    /// Most half-open connections of a single destination at any time
+    peak_half_open: u32,
+}
+
+impl SynFloodDetector {
+    pub fn new(max_half_open: u32, max_syn_rate: u32) -> Self {
+        Self { max_half_open, max_syn_rate, destinations: HashMap::new(), alarm_count: 0, peak_half_open: 0 }
+    }
+
+    /// Whether any threshold is set
+    pub fn is_enabled(&self) -> bool {
+        self.max_half_open > 0 || self.max_syn_rate > 0
+    }
+
+    /// A detector for one of the given number of shards of the connection table, where the connections of a destination
+    /// are spread by their source, so the thresholds are split between the shards
+    pub fn share(&self, count: usize) -> Self {
+        let count = count.max(1) as u32;
+        SynFloodDetector::new(self.max_half_open.div_ceil(count), self.max_syn_rate.div_ceil(count))
+    }
+
+    /// Add the thresholds and counters of another detector, such as of another shard of the connection table
+    pub fn merge(&mut self, other: &SynFloodDetector) {
+        self.max_half_open += other.max_half_open;
+        self.max_syn_rate += other.max_syn_rate;
+        self.alarm_count += other.alarm_count;
+        self.peak_half_open = self.peak_half_open.max(other.peak_half_open);
+    }
+
+    /// Count a new connection to the given destination that sent a SYN at the given capture time, and is half-open
+    /// until [SynFloodDetector::remove_half_open]. Return an alarm if the destination crossed a threshold.
+    pub fn add_syn(&mut self, dst: SocketAddr, ts: Duration) -> Option<SynFloodAlarm> {
+        if !self.destinations.contains_key(&dst) && self.destinations.len() >= MAX_SYN_DESTINATIONS {
+            self.destinations.retain(|_, syns| syns.half_open > 0);
+            if self.destinations.len() >= MAX_SYN_DESTINATIONS {
+                return None;
+            }
+        }
+        let (max_half_open, max_syn_rate) = (self.max_half_open, self.max_syn_rate);
+        let syns = self.destinations.entry(dst).or_default();
+        if ts.saturating_sub(syns.window_start) >= SYN_RATE_WINDOW {
+            syns.window_start = ts;
+            syns.window_syns = 0;
+            syns.check_calm(dst, max_half_open, max_syn_rate);
+        }
+        syns.half_open += 1;
+        syns.window_syns += 1;
+        self.peak_half_open = self.peak_half_open.max(syns.half_open);
+        let flooded = (max_half_open > 0 && syns.half_open >= max_half_open) ||
+            (max_syn_rate > 0 && syns.window_syns >= max_syn_rate);
+        if !flooded || syns.alarmed {
+            return None;
+        }
+        syns.alarmed = true;
+        self.alarm_count += 1;
+        Some(SynFloodAlarm { dst, half_open: syns.half_open, syn_rate: syns.window_syns })
+    }
+
+    /// A connection to the given destination is no longer half-open, since it was answered, reset or failed
+    pub fn remove_half_open(&mut self, dst: SocketAddr) {
+        if let Some(syns) = self.destinations.get_mut(&dst) {
+            syns.half_open = syns.half_open.saturating_sub(1);
+            syns.check_calm(dst, self.max_half_open, self.max_syn_rate);
+        }
+    }
+
+    /// Number of alarms raised so far
+    pub fn alarm_count(&self) -> u64 {
+        self.alarm_count
+    }
+}
+
+impl DestinationSyns {
+    /// Clear the alarm once the half-open connections and the SYN rate are down to half the thresholds
+    fn check_calm(&mut self, dst: SocketAddr, max_half_open: u32, max_syn_rate: u32) {
+        if self.alarmed && self.half_open <= max_half_open / 2 && self.window_syns <= max_syn_rate / 2 {
+            self.alarmed = false;
+            info!("SYN flood to {} is over, {} half-open connections", dst, self.half_open);
+        }
+    }
+}
+
+impl fmt::Display for SynFloodDetector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let threshold = |value: u32| if value == 0 { String::from("unlimited") } else { value.to_string() };
+        write!(f, "{} half-open connections or {} SYNs per second per destination, {} alarms, peak of {} half-open",
+               threshold(self.max_half_open), threshold(self.max_syn_rate), self.alarm_count, self.peak_half_open)
+    }
+}
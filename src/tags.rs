@@ -0,0 +1,145 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::SocketAddr;
+use crate::ignore::IgnoreRule;
+use crate::json::JsonValue;
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
+
+/// Max tags per connection, so a chatty analyzer or rule cannot grow a connection without limit
+const MAX_TAGS: usize = 32;
+/// Max length of a tag key or value
+pub(crate) const MAX_TAG_LEN: usize = 256;
+
+/// Free-form key-value labels of a connection, such as "sni=example.com" or "rule=db", that consumers, analyzers
+/// and rules attach, and that are carried to all the flow exports. A plain tag has an empty value.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ConnTags {
+    tags: BTreeMap<String, String>,
+}
+
+impl ConnTags {
+    /// Set the value of a tag, replacing the previous value of the key.
+    /// Return false if there are too many tags or the key or value is too long.
+    pub fn set(&mut self, key: &str, value: &str) -> bool {
+        if key.is_empty() || key.len() > MAX_TAG_LEN || value.len() > MAX_TAG_LEN {
+            return false;
+        }
+        if self.tags.len() >= MAX_TAGS && !self.tags.contains_key(key) {
+            return false;
+        }
+        self.tags.insert(key.to_string(), value.to_string());
+        true
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.tags.get(key).map(String::as_str)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=(&str, &str)> {
+        self.tags.iter().map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tags.is_empty()
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        JsonValue::object(self.iter().map(|(key, value)| (key, value.into())).collect())
+    }
+
+    pub(crate) fn write_snapshot<W: Write>(&self, writer: &mut SnapshotWriter<W>) -> Result<(), Error> {
+        writer.put_u8(self.tags.len() as u8)?;
+        for (key, value) in self.iter() {
+            writer.put_bytes(key.as_bytes())?;
+            writer.put_bytes(value.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Restore tags that were written by [ConnTags::write_snapshot]
+    pub(crate) fn read_snapshot<R: Read>(reader: &mut SnapshotReader<R>) -> Result<Self, Error> {
+        let count = reader.get_u8()?;
+        let mut read_string = || -> Result<String, Error> {
+            String::from_utf8(reader.get_bytes(MAX_TAG_LEN)?)
+                .map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid connection tag in snapshot"))
+        };
+        let mut tags = ConnTags::default();
+        for _ in 0..count {
+            let key = read_string()?;
+            let value = read_string()?;
+            tags.set(&key, &value);
+        }
+        Ok(tags)
+    }
+
+    /// Restore tags from their JSON representation, made by [ConnTags::to_json]
+    pub fn from_json(json: &JsonValue) -> Result<Self, Error> {
+        let invalid = || Error::new(ErrorKind::InvalidData, "Invalid connection tags in JSON");
+        let mut tags = ConnTags::default();
+        match json {
+            JsonValue::Object(fields) => {
+                for (key, value) in fields {
+                    if !tags.set(key, value.as_str().ok_or_else(invalid)?) {
+                        return Err(invalid());
+                    }
+                }
+            }
+            _ => { return Err(invalid()); }
+        }
+        Ok(tags)
+    }
+}
+
+impl fmt::Display for ConnTags {
+    /// Space separated "key=value" pairs, or just the key for a plain tag
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, (key, value)) in self.iter().enumerate() {
+            if index > 0 {
+                write!(f, " ")?;
+            }
+            if value.is_empty() { write!(f, "{}", key)?; } else { write!(f, "{}={}", key, value)?; }
+        }
+        Ok(())
+    }
+}
+
+/// User label, attached to new connections that match a rule
+#[derive(Clone, Debug, PartialEq)]
+pub struct TagRule {
+    pub rule: IgnoreRule,
+    pub key: String,
+    pub value: String,
+}
+
+impl TagRule {
+    /// Parse a rule given as "MATCH=KEY" or "MATCH=KEY=VALUE", where the match is "port:N", "host:IP" or "net:IP/PREFIX",
+    /// such as "port:5432=db" or "net:10.1.0.0/16=team=payments"
+    pub fn parse(arg: &str) -> Result<Self, String> {
+        let (rule, tag) = arg.split_once('=').ok_or("expected MATCH=KEY[=VALUE]")?;
+        let rule = IgnoreRule::parse(rule)?;
+        let (key, value) = tag.split_once('=').unwrap_or((tag, ""));
+        let key = key.trim();
+        if key.is_empty() || key.len() > MAX_TAG_LEN || value.len() > MAX_TAG_LEN {
+            return Err(format!("invalid tag '{}'", tag));
+        }
+        Ok(TagRule { rule, key: key.to_string(), value: value.trim().to_string() })
+    }
+
+    /// Tag the connection if either side matches the rule
+    pub fn apply(&self, low: &SocketAddr, high: &SocketAddr, tags: &mut ConnTags) {
+        if self.rule.matches(low, high) {
+            tags.set(&self.key, &self.value);
+        }
+    }
+}
+
+impl fmt::Display for TagRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.value.is_empty() {
+            write!(f, "{}={}", self.rule, self.key)
+        } else {
+            write!(f, "{}={}={}", self.rule, self.key, self.value)
+        }
+    }
+}
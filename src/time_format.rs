@@ -0,0 +1,207 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::json::JsonValue;
+
+/// How timestamps are written in the exports and the log
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TimeFormat {
+    /// Seconds since the epoch, with a fraction
+    Epoch,
+    /// Date and time, such as "2024-01-31T12:00:00.123456Z"
+    Rfc3339,
+}
+
+impl TimeFormat {
+    /// Parse "epoch" or "rfc3339"
+    pub fn parse(arg: &str) -> Result<Self, String> {
+        match arg.trim() {
+            "epoch" => { Ok(TimeFormat::Epoch) }
+            "rfc3339" => { Ok(TimeFormat::Rfc3339) }
+            other => { Err(format!("unknown time format \"{}\", expected epoch or rfc3339", other)) }
+        }
+    }
+}
+
+/// Time zone of the dates, when they are written as RFC 3339
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TimeZone {
+    Utc,
+    /// The local time zone of the host, with its offset from UTC at every timestamp (so daylight saving is followed)
+    Local,
+}
+
+impl TimeZone {
+    /// Parse "utc" or "local"
+    pub fn parse(arg: &str) -> Result<Self, String> {
+        match arg.trim() {
+            "utc" => { Ok(TimeZone::Utc) }
+            "local" => { Ok(TimeZone::Local) }
+            other => { Err(format!("unknown time zone \"{}\", expected utc or local", other)) }
+        }
+    }
+}
+
+/// Writes all the timestamps of the exports and the log, so they can be correlated with each other.
+/// Timestamps are capture times, or wall-clock times for the log, as durations since the epoch.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TimeFormatter {
+    format: TimeFormat,
+    zone: TimeZone,
+}
+
+impl TimeFormatter {
+    pub fn new(format: TimeFormat, zone: TimeZone) -> Self {
+        Self { format, zone }
+    }
+
+    /// Format a timestamp with microseconds
+    pub fn format(&self, ts: Duration) -> String {
+        match self.format {
+            TimeFormat::Epoch => { format!("{:.6}", ts.as_secs_f64()) }
+            TimeFormat::Rfc3339 => { self.rfc3339(ts.as_secs(), Some(ts.subsec_micros())) }
+        }
+    }
+
+    /// Format a timestamp in whole seconds, such as a time bucket
+    pub fn format_secs(&self, secs: u64) -> String {
+        match self.format {
+            TimeFormat::Epoch => { secs.to_string() }
+            TimeFormat::Rfc3339 => { self.rfc3339(secs, None) }
+        }
+    }
+
+    /// A timestamp with microseconds for JSON exports, as a number for epoch seconds or as a string for dates
+    pub fn to_json(&self, ts: Duration) -> JsonValue {
+        match self.format {
+            TimeFormat::Epoch => { ts.as_secs_f64().into() }
+            TimeFormat::Rfc3339 => { self.format(ts).into() }
+        }
+    }
+
+    /// A timestamp in whole seconds for JSON exports, as a number for epoch seconds or as a string for dates
+    pub fn secs_to_json(&self, secs: u64) -> JsonValue {
+        match self.format {
+            TimeFormat::Epoch => { secs.into() }
+            TimeFormat::Rfc3339 => { self.format_secs(secs).into() }
+        }
+    }
+
+    /// The current wall-clock time in whole seconds, for log lines
+    pub fn now(&self) -> String {
+        self.format_secs(SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs())
+    }
+
+    fn rfc3339(&self, secs: u64, micros: Option<u32>) -> String {
+        let offset_secs = match self.zone {
+            TimeZone::Utc => { None }
+            TimeZone::Local => { Some(local_offset_secs(secs)) }
+        };
+        rfc3339(secs, micros, offset_secs)
+    }
+}
+
+/// Format seconds since the epoch as an RFC 3339 date, with the given offset from UTC in seconds, or in UTC ("Z")
+fn rfc3339(secs: u64, micros: Option<u32>, offset_secs: Option<i64>) -> String {
+    let local_secs = secs as i64 + offset_secs.unwrap_or_default();
+    let (year, month, day) = civil_from_days(local_secs.div_euclid(86400));
+    let day_secs = local_secs.rem_euclid(86400);
+    let mut result = format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}", year, month, day, day_secs / 3600, day_secs / 60 % 60,
+                             day_secs % 60);
+    if let Some(micros) = micros {
+        result.push_str(&format!(".{:06}", micros));
+    }
+    match offset_secs {
+        Some(offset_secs) => {
+            let sign = if offset_secs < 0 { '-' } else { '+' };
+            let offset_mins = offset_secs.abs() / 60;
+            result.push_str(&format!("{}{:02}:{:02}", sign, offset_mins / 60, offset_mins % 60));
+        }
+        None => { result.push('Z'); }
+    }
+    result
+}
+
+/// Year, month and day of a number of days since the epoch, in the proleptic Gregorian calendar
+/// (after Howard Hinnant's civil_from_days)
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    // Months start in March, so the leap day is the last day of the year
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Offset of the local time zone from UTC at the given time, in seconds
+#[cfg(unix)]
+fn local_offset_secs(secs: u64) -> i64 {
+    // localtime_r fills a stack-allocated struct, and is thread safe unlike localtime
+    unsafe {
+        let time = secs as libc::time_t;
+        let mut tm: libc::tm = std::mem::zeroed();
+        if libc::localtime_r(&time, &mut tm).is_null() {
+            return 0;
+        }
+        tm.tm_gmtoff as i64
+    }
+}
+
+/// Offset of the local time zone from UTC at the given time, in seconds
+#[cfg(not(unix))]
+fn local_offset_secs(_secs: u64) -> i64 {
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use crate::json::JsonValue;
+    use super::{rfc3339, TimeFormat, TimeFormatter, TimeZone};
+
+    #[test]
+    fn utc_dates() {
+        let formatter = TimeFormatter::new(TimeFormat::Rfc3339, TimeZone::Utc);
+        assert_eq!(formatter.format_secs(0), "1970-01-01T00:00:00Z");
+        assert_eq!(formatter.format(Duration::from_micros(1_500_000)), "1970-01-01T00:00:01.500000Z");
+        // The leap day of 2024, and the day after it
+        assert_eq!(formatter.format_secs(1709164800), "2024-02-29T00:00:00Z");
+        assert_eq!(formatter.format_secs(1709251199), "2024-02-29T23:59:59Z");
+        assert_eq!(formatter.format_secs(1709251200), "2024-03-01T00:00:00Z");
+        // 2000 is a leap year, although divisible by 100
+        assert_eq!(formatter.format_secs(951782400), "2000-02-29T00:00:00Z");
+        // The last second of a signed 32-bit time_t, and the one after it
+        assert_eq!(formatter.format_secs(2147483647), "2038-01-19T03:14:07Z");
+        assert_eq!(formatter.format_secs(2147483648), "2038-01-19T03:14:08Z");
+        assert_eq!(formatter.format(Duration::new(4102444800, 999_999_000)), "2100-01-01T00:00:00.999999Z");
+    }
+
+    #[test]
+    fn offsets_from_utc() {
+        // A negative offset moves the epoch back to the previous day and year
+        assert_eq!(rfc3339(0, None, Some(-5 * 3600)), "1969-12-31T19:00:00-05:00");
+        assert_eq!(rfc3339(1709251200, Some(0), Some(-(3 * 3600 + 1800))), "2024-02-29T20:30:00.000000-03:30");
+        assert_eq!(rfc3339(1709164800, None, Some(5 * 3600 + 2700)), "2024-02-29T05:45:00+05:45");
+        // A local zone at UTC still writes its offset
+        assert_eq!(rfc3339(2147483647, None, Some(0)), "2038-01-19T03:14:07+00:00");
+    }
+
+    #[test]
+    fn epoch_seconds() {
+        let formatter = TimeFormatter::new(TimeFormat::Epoch, TimeZone::Local);
+        assert_eq!(formatter.format(Duration::from_micros(1_700_000_000_123_456)), "1700000000.123456");
+        assert_eq!(formatter.format_secs(0), "0");
+        assert_eq!(formatter.secs_to_json(2147483648), JsonValue::from(2147483648u64));
+    }
+
+    #[test]
+    fn formats_and_zones_are_parsed() {
+        assert_eq!(TimeFormat::parse(" rfc3339"), Ok(TimeFormat::Rfc3339));
+        assert_eq!(TimeZone::parse("local"), Ok(TimeZone::Local));
+        assert!(TimeFormat::parse("iso").is_err());
+        assert!(TimeZone::parse("cet").is_err());
+    }
+}
@@ -0,0 +1,304 @@
+use crate::digest::hmac_sha256;
+use crate::keylog::CLIENT_RANDOM_LEN;
+
+/// TLS 1.2 cipher suites whose records can be decrypted: AES-128-GCM with the SHA-256 PRF (RFC 5288, RFC 5289)
+const AES_128_GCM_SUITES: &[(u16, &str)] = &[
+    (0x009c, "TLS_RSA_WITH_AES_128_GCM_SHA256"),
+    (0x009e, "TLS_DHE_RSA_WITH_AES_128_GCM_SHA256"),
+    (0xc02b, "TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256"),
+    (0xc02f, "TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256"),
+];
+/// Length of the master secret of TLS 1.2, as logged in CLIENT_RANDOM lines
+pub(crate) const MASTER_SECRET_LEN: usize = 48;
+/// Length of the nonce part sent before the ciphertext of every record
+const EXPLICIT_NONCE_LEN: usize = 8;
+/// Length of the GCM authentication tag at the end of every record
+const TAG_LEN: usize = 16;
+
+/// Name of a cipher suite whose records can be decrypted, or None if it is not supported
+pub(crate) fn cipher_suite_name(suite: u16) -> Option<&'static str> {
+    AES_128_GCM_SUITES.iter().find(|(id, _)| *id == suite).map(|(_, name)| *name)
+}
+
+/// Decrypts the records of one flow of a TLS 1.2 session, after its ChangeCipherSpec
+#[derive(Clone)]
+pub(crate) struct RecordCipher {
+    aes: Aes128,
+    /// Implicit part of the nonce, from the key block
+    salt: [u8; 4],
+    /// Sequence number of the next record, that is authenticated but not sent
+    seq: u64,
+}
+
+impl RecordCipher {
+    /// Ciphers of the client and server flows, with the keys expanded from the master secret and the hello randoms
+    pub(crate) fn from_master_secret(master_secret: &[u8], client_random: &[u8; CLIENT_RANDOM_LEN],
+                                     server_random: &[u8; CLIENT_RANDOM_LEN]) -> (Self, Self) {
+        let mut seed = server_random.to_vec();
+        seed.extend_from_slice(client_random);
+        // Client and server write keys, then client and server implicit nonces (no MAC keys with AEAD)
+        let key_block = prf_sha256(master_secret, b"key expansion", &seed, 40);
+        let cipher = |key: &[u8], salt: &[u8]| RecordCipher {
+            aes: Aes128::new(key.try_into().unwrap()),
+            salt: salt.try_into().unwrap(),
+            seq: 0,
+        };
+        (cipher(&key_block[..16], &key_block[32..36]), cipher(&key_block[16..32], &key_block[36..40]))
+    }
+
+    /// Plaintext of the next record, given its type, version and fragment, or None if it fails authentication
+    pub(crate) fn open(&mut self, content_type: u8, version: u16, fragment: &[u8]) -> Option<Vec<u8>> {
+        if fragment.len() < EXPLICIT_NONCE_LEN + TAG_LEN {
+            return None;
+        }
+        let (explicit_nonce, sealed) = fragment.split_at(EXPLICIT_NONCE_LEN);
+        let (ciphertext, tag) = sealed.split_at(sealed.len() - TAG_LEN);
+        let nonce = self.nonce(explicit_nonce);
+        let aad = self.aad(content_type, version, ciphertext.len());
+        if gcm_tag(&self.aes, &nonce, &aad, ciphertext).iter().zip(tag).fold(0, |diff, (a, b)| diff | (a ^ b)) != 0 {
+            return None;
+        }
+        self.seq += 1;
+        Some(gcm_ctr(&self.aes, &nonce, ciphertext))
+    }
+
+    /// Fragment of the next record with the given plaintext, as a peer would send it
+    #[cfg(test)]
+    pub(crate) fn seal(&mut self, content_type: u8, version: u16, explicit_nonce: u64, plaintext: &[u8]) -> Vec<u8> {
+        let nonce = self.nonce(&explicit_nonce.to_be_bytes());
+        let aad = self.aad(content_type, version, plaintext.len());
+        let mut fragment = explicit_nonce.to_be_bytes().to_vec();
+        fragment.extend(gcm_ctr(&self.aes, &nonce, plaintext));
+        let tag = gcm_tag(&self.aes, &nonce, &aad, &fragment[EXPLICIT_NONCE_LEN..]);
+        fragment.extend_from_slice(&tag);
+        self.seq += 1;
+        fragment
+    }
+
+    fn nonce(&self, explicit_nonce: &[u8]) -> [u8; 12] {
+        let mut nonce = [0u8; 12];
+        nonce[..4].copy_from_slice(&self.salt);
+        nonce[4..].copy_from_slice(explicit_nonce);
+        nonce
+    }
+
+    /// Additional authenticated data: the sequence number and the record header with the plaintext length
+    fn aad(&self, content_type: u8, version: u16, len: usize) -> Vec<u8> {
+        let mut aad = self.seq.to_be_bytes().to_vec();
+        aad.push(content_type);
+        aad.extend_from_slice(&version.to_be_bytes());
+        aad.extend_from_slice(&(len as u16).to_be_bytes());
+        aad
+    }
+}
+
+/// TLS 1.2 PRF with HMAC-SHA-256 (RFC 5246 section 5)
+fn prf_sha256(secret: &[u8], label: &[u8], seed: &[u8], len: usize) -> Vec<u8> {
+    let mut label_seed = label.to_vec();
+    label_seed.extend_from_slice(seed);
+    let mut a = hmac_sha256(secret, &label_seed);
+    let mut result = Vec::with_capacity(len + 32);
+    while result.len() < len {
+        let mut input = a.to_vec();
+        input.extend_from_slice(&label_seed);
+        result.extend_from_slice(&hmac_sha256(secret, &input));
+        a = hmac_sha256(secret, &a);
+    }
+    result.truncate(len);
+    result
+}
+
+/// AES S-box, computed from the inverse in GF(2^8) and the affine transform (FIPS 197 section 5.1.1)
+const SBOX: [u8; 256] = sbox_table();
+
+const fn gf256_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            product ^= a;
+        }
+        a = (a << 1) ^ if a & 0x80 != 0 { 0x1b } else { 0 };
+        b >>= 1;
+    }
+    product
+}
+
+const fn sbox_table() -> [u8; 256] {
+    let mut table = [0u8; 256];
+    let mut x = 0;
+    while x < 256 {
+        // The inverse is x^254, the product of x^2, x^4, ... x^128 (and 0 for 0)
+        let mut inverse = 1u8;
+        let mut power = x as u8;
+        let mut i = 0;
+        while i < 7 {
+            power = gf256_mul(power, power);
+            inverse = gf256_mul(inverse, power);
+            i += 1;
+        }
+        table[x] = inverse ^ inverse.rotate_left(1) ^ inverse.rotate_left(2) ^ inverse.rotate_left(3)
+            ^ inverse.rotate_left(4) ^ 0x63;
+        x += 1;
+    }
+    table
+}
+
+/// AES-128 block encryption (FIPS 197), which is all that GCM needs
+#[derive(Clone)]
+struct Aes128 {
+    round_keys: [[u8; 16]; 11],
+}
+
+impl Aes128 {
+    fn new(key: &[u8; 16]) -> Self {
+        let mut words = [[0u8; 4]; 44];
+        for (i, word) in key.chunks(4).enumerate() {
+            words[i].copy_from_slice(word);
+        }
+        let mut rcon = 1u8;
+        for i in 4..44 {
+            let mut word = words[i - 1];
+            if i % 4 == 0 {
+                word = [SBOX[word[1] as usize] ^ rcon, SBOX[word[2] as usize], SBOX[word[3] as usize], SBOX[word[0] as usize]];
+                rcon = gf256_mul(rcon, 2);
+            }
+            for j in 0..4 {
+                words[i][j] = words[i - 4][j] ^ word[j];
+            }
+        }
+        let mut round_keys = [[0u8; 16]; 11];
+        for (round, round_key) in round_keys.iter_mut().enumerate() {
+            for j in 0..4 {
+                round_key[4 * j..4 * j + 4].copy_from_slice(&words[4 * round + j]);
+            }
+        }
+        Self { round_keys }
+    }
+
+    fn encrypt_block(&self, block: &[u8; 16]) -> [u8; 16] {
+        // Column-major state, as the bytes of the block
+        let mut state = *block;
+        xor_into(&mut state, &self.round_keys[0]);
+        for round in 1..11 {
+            let mut shifted = [0u8; 16];
+            for column in 0..4 {
+                for row in 0..4 {
+                    shifted[4 * column + row] = SBOX[state[4 * ((column + row) % 4) + row] as usize];
+                }
+            }
+            state = shifted;
+            if round < 10 {
+                for column in state.chunks_mut(4) {
+                    let [a0, a1, a2, a3] = [column[0], column[1], column[2], column[3]];
+                    let double = |a: u8| gf256_mul(a, 2);
+                    column[0] = double(a0) ^ double(a1) ^ a1 ^ a2 ^ a3;
+                    column[1] = a0 ^ double(a1) ^ double(a2) ^ a2 ^ a3;
+                    column[2] = a0 ^ a1 ^ double(a2) ^ double(a3) ^ a3;
+                    column[3] = double(a0) ^ a0 ^ a1 ^ a2 ^ double(a3);
+                }
+            }
+            xor_into(&mut state, &self.round_keys[round]);
+        }
+        state
+    }
+}
+
+fn xor_into(target: &mut [u8], other: &[u8]) {
+    for (t, o) in target.iter_mut().zip(other) {
+        *t ^= o;
+    }
+}
+
+/// Counter block of GCM with a 96-bit nonce
+fn gcm_counter(nonce: &[u8; 12], counter: u32) -> [u8; 16] {
+    let mut block = [0u8; 16];
+    block[..12].copy_from_slice(nonce);
+    block[12..].copy_from_slice(&counter.to_be_bytes());
+    block
+}
+
+/// Encrypt or decrypt with the GCM counter mode, that starts at counter 2 (NIST SP 800-38D)
+fn gcm_ctr(aes: &Aes128, nonce: &[u8; 12], data: &[u8]) -> Vec<u8> {
+    let mut result = data.to_vec();
+    for (i, chunk) in result.chunks_mut(16).enumerate() {
+        xor_into(chunk, &aes.encrypt_block(&gcm_counter(nonce, (i as u32).wrapping_add(2))));
+    }
+    result
+}
+
+/// Authentication tag of GCM over the additional data and the ciphertext
+fn gcm_tag(aes: &Aes128, nonce: &[u8; 12], aad: &[u8], ciphertext: &[u8]) -> [u8; 16] {
+    let h = u128::from_be_bytes(aes.encrypt_block(&[0; 16]));
+    let mut ghash = 0u128;
+    for data in [aad, ciphertext] {
+        for chunk in data.chunks(16) {
+            let mut block = [0u8; 16];
+            block[..chunk.len()].copy_from_slice(chunk);
+            ghash = ghash_mul(ghash ^ u128::from_be_bytes(block), h);
+        }
+    }
+    let lengths = ((aad.len() as u128 * 8) << 64) | (ciphertext.len() as u128 * 8);
+    ghash = ghash_mul(ghash ^ lengths, h);
+    (ghash ^ u128::from_be_bytes(aes.encrypt_block(&gcm_counter(nonce, 1)))).to_be_bytes()
+}
+
+/// Multiplication in GF(2^128) with the bit order of GHASH, where the first bit is the most significant
+fn ghash_mul(x: u128, y: u128) -> u128 {
+    let mut product = 0;
+    let mut v = y;
+    for i in 0..128 {
+        if x & (1 << (127 - i)) != 0 {
+            product ^= v;
+        }
+        v = (v >> 1) ^ if v & 1 != 0 { 0xe1 << 120 } else { 0 };
+    }
+    product
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::digest::hmac_sha256;
+    use crate::utils::{bytes_to_hex, hex_to_bytes};
+    use super::{gcm_ctr, gcm_tag, prf_sha256, Aes128, RecordCipher};
+
+    #[test]
+    fn aes_and_gcm_test_vectors() {
+        let aes = Aes128::new(&core::array::from_fn(|i| i as u8));
+        let block = hex_to_bytes("00112233445566778899aabbccddeeff").unwrap();
+        assert_eq!(bytes_to_hex(&aes.encrypt_block(&block.try_into().unwrap())), "69c4e0d86a7b0430d8cdb78070b4c55a");
+        // GCM test cases 1 and 2, with a zero key and nonce
+        let aes = Aes128::new(&[0; 16]);
+        assert_eq!(bytes_to_hex(&gcm_tag(&aes, &[0; 12], &[], &[])), "58e2fccefa7e3061367f1d57a4e7455a");
+        let ciphertext = gcm_ctr(&aes, &[0; 12], &[0; 16]);
+        assert_eq!(bytes_to_hex(&ciphertext), "0388dace60b6a392f328c2b971b2fe78");
+        assert_eq!(bytes_to_hex(&gcm_tag(&aes, &[0; 12], &[], &ciphertext)), "ab6e47d42cec13bdf53a67b21257bddf");
+    }
+
+    #[test]
+    fn hmac_and_prf_test_vectors() {
+        assert_eq!(bytes_to_hex(&hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+                   "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+        let secret = hex_to_bytes("9bbe436ba940f017b17652849a71db35").unwrap();
+        let seed = hex_to_bytes("a0ba9f936cda311827a6f796ffd5198c").unwrap();
+        assert_eq!(bytes_to_hex(&prf_sha256(&secret, b"test label", &seed, 32)),
+                   "e3f229ba727be17b8d122620557cd453c2aab21d07c3d495329b52d4e61edb5a");
+    }
+
+    #[test]
+    fn client_record_is_decrypted_in_sequence() {
+        let master_secret: Vec<u8> = (0..48).collect();
+        let (mut client, _) = RecordCipher::from_master_secret(&master_secret, &core::array::from_fn(|i| i as u8),
+                                                               &core::array::from_fn(|i| 32 + i as u8));
+        // Sealed by another implementation, as the second record after the ChangeCipherSpec
+        let fragment = hex_to_bytes("0000000000000001073ce484a0739f73f0c344b559442dfff76271dcc72adcaff56da533ae61758e").unwrap();
+        assert_eq!(client.clone().open(23, 0x0303, &fragment), None);
+        client.seq = 1;
+        let mut tampered = fragment.clone();
+        tampered[10] ^= 1;
+        assert_eq!(client.clone().open(23, 0x0303, &tampered), None);
+        assert_eq!(client.clone().open(22, 0x0303, &fragment), None);
+        assert_eq!(client.open(23, 0x0303, &fragment).as_deref(), Some(&b"GET / HTTP/1.1\r\n"[..]));
+        assert_eq!(client.seq, 2);
+        assert_eq!(client.open(23, 0x0303, &fragment[..20]), None);
+    }
+}
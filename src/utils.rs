@@ -1,3 +1,4 @@
+use std::net::IpAddr;
 use etherparse::TcpHeaderSlice;
 
 /// Return the most meaningful flag(s) in a TCP packet
@@ -10,4 +11,56 @@ pub fn tcp_flags_to_string<'a>(tcp: &'a TcpHeaderSlice) -> &'a str {
         return "SYN";
     }
     return "";
-}
\ No newline at end of file
+}
+
+/// Whether the checksum of an IPv4 header is right, as the ones' complement sum of the header, including its checksum,
+/// is all ones (RFC 1071)
+pub fn ipv4_header_checksum_ok(header: &[u8]) -> bool {
+    let mut sum: u32 = header.chunks(2).map(|word| u16::from_be_bytes([word[0], *word.get(1).unwrap_or(&0)]) as u32).sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum == 0xffff
+}
+
+/// Whether the checksum of a TCP segment is right, given the addresses of its pseudo header, its header and its payload
+pub fn tcp_checksum_ok(src: IpAddr, dst: IpAddr, tcp: &TcpHeaderSlice, payload: &[u8]) -> bool {
+    let checksum = match (src, dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => { tcp.calc_checksum_ipv4_raw(src.octets(), dst.octets(), payload) }
+        (IpAddr::V6(src), IpAddr::V6(dst)) => { tcp.calc_checksum_ipv6_raw(src.octets(), dst.octets(), payload) }
+        _ => { return false; }
+    };
+    checksum.is_ok_and(|checksum| checksum == tcp.checksum())
+}
+
+/// Encode bytes as a lowercase hex string
+pub fn bytes_to_hex(bytes: &[u8]) -> String {
+    let mut result = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        result.push_str(&format!("{:02x}", b));
+    }
+    result
+}
+
+/// Decode a hex string into bytes, or None if it has an odd length or a non-hex character
+pub fn hex_to_bytes(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) { return None; }
+    (0..hex.len()).step_by(2)
+        .map(|i| hex.get(i..i + 2).and_then(|h| u8::from_str_radix(h, 16).ok()))
+        .collect()
+}
+
+/// Up to the given number of bytes as text, with the bytes that are not printable ASCII replaced by dots, so they are
+/// safe to log
+pub fn printable_preview(bytes: &[u8], max_len: usize) -> String {
+    bytes.iter().take(max_len).map(|b| if b.is_ascii_graphic() || *b == b' ' { *b as char } else { '.' }).collect()
+}
+
+/// Finalizer of SplitMix64, that spreads every input bit over the whole output, as a stable hash of integers
+pub fn mix64(mut x: u64) -> u64 {
+    x ^= x >> 30;
+    x = x.wrapping_mul(0xbf58476d1ce4e5b9);
+    x ^= x >> 27;
+    x = x.wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
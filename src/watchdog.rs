@@ -0,0 +1,142 @@
+use std::fmt;
+use std::fs;
+use std::time::{Duration, Instant};
+use log::{info, warn};
+use pcap::Stat;
+use crate::json::JsonValue;
+
+/// How often the capture is checked
+pub const WATCHDOG_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// Read timeout of the capture when the watchdog is on, so it is checked even when no packet arrives
+pub const WATCHDOG_READ_TIMEOUT_MS: i32 = 1000;
+/// Min number of packets (received and dropped) in a check interval for its drop rate to count, against noise
+const MIN_DROP_RATE_PACKETS: u32 = 100;
+
+/// Something wrong with the capture that the watchdog found
+#[derive(Clone, Debug, PartialEq)]
+pub enum CaptureAlarm {
+    /// No packets for this long while the interface is up
+    Silent { secs: u64 },
+    /// The kernel dropped this share of the packets in the last check interval
+    Drops { dropped: u32, received: u32, percent: f64 },
+}
+
+impl CaptureAlarm {
+    pub fn to_json(&self) -> JsonValue {
+        match self {
+            CaptureAlarm::Silent { secs } => {
+                JsonValue::object(vec![("alarm", "silent".into()), ("secs", (*secs).into())])
+            }
+            CaptureAlarm::Drops { dropped, received, percent } => {
+                JsonValue::object(vec![("alarm", "drops".into()), ("dropped", (*dropped).into()),
+                                       ("received", (*received).into()), ("percent", (*percent).into())])
+            }
+        }
+    }
+}
+
+impl fmt::Display for CaptureAlarm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CaptureAlarm::Silent { secs } => { write!(f, "no packets for {}s while the interface is up", secs) }
+            CaptureAlarm::Drops { dropped, received, percent } => {
+                write!(f, "{} of {} packets dropped by the kernel ({:.2}%) in the last {}s", dropped, dropped.saturating_add(*received), percent,
+                       WATCHDOG_CHECK_INTERVAL.as_secs())
+            }
+        }
+    }
+}
+
+/// Watches a live capture for silence and drops, so a capture that failed silently does not go unnoticed.
+/// Every alarm is raised once, until the capture recovers from it.
+pub struct Watchdog {
+    device: String,
+    /// No packets for this long is an alarm, or never if zero
+    max_silence: Duration,
+    /// A higher drop rate is an alarm, or never if None
+    max_drop_percent: Option<f64>,
+    last_check: Instant,
+    /// Packet count at the last check, and when it last changed
+    packet_count: u64,
+    last_packet_time: Instant,
+    /// Capture counters at the last check
+    last_stats: Option<Stat>,
+    silent_alarm: bool,
+    drops_alarm: bool,
+    /// Whether the interface was found down while silent, so it is logged once
+    interface_down: bool,
+}
+
+impl Watchdog {
+    pub fn new(device: &str, max_silence: Duration, max_drop_percent: Option<f64>) -> Self {
+        Watchdog {
+            device: device.to_string(),
+            max_silence,
+            max_drop_percent,
+            last_check: Instant::now(),
+            packet_count: 0,
+            last_packet_time: Instant::now(),
+            last_stats: None,
+            silent_alarm: false,
+            drops_alarm: false,
+            interface_down: false,
+        }
+    }
+
+    /// Whether it is time for the next check
+    pub fn is_due(&self) -> bool {
+        self.last_check.elapsed() >= WATCHDOG_CHECK_INTERVAL
+    }
+
+    /// Check the capture given the number of packets so far and its counters, and return the new alarms.
+    /// Recoveries and a silence on an interface that is down are logged here.
+    pub fn check(&mut self, packet_count: u64, stats: Option<Stat>) -> Vec<CaptureAlarm> {
+        self.last_check = Instant::now();
+        let mut alarms = Vec::new();
+        if packet_count != self.packet_count {
+            if self.silent_alarm {
+                info!("Capture watchdog: packets are coming in again after {}s", self.last_packet_time.elapsed().as_secs());
+            }
+            self.packet_count = packet_count;
+            self.last_packet_time = Instant::now();
+            self.silent_alarm = false;
+            self.interface_down = false;
+        } else if !self.max_silence.is_zero() && !self.silent_alarm && self.last_packet_time.elapsed() >= self.max_silence {
+            if self.is_interface_up() {
+                self.silent_alarm = true;
+                alarms.push(CaptureAlarm::Silent { secs: self.last_packet_time.elapsed().as_secs() });
+            } else if !self.interface_down {
+                self.interface_down = true;
+                warn!("Capture watchdog: no packets, since the interface {} is down", self.device);
+            }
+        }
+        if let (Some(max_drop_percent), Some(stats)) = (self.max_drop_percent, stats) {
+            if let Some(last_stats) = self.last_stats {
+                let received = stats.received.wrapping_sub(last_stats.received);
+                let dropped = stats.dropped.wrapping_sub(last_stats.dropped);
+                let total = received.saturating_add(dropped);
+                let percent = if total == 0 { 0.0 } else { dropped as f64 * 100.0 / total as f64 };
+                if total >= MIN_DROP_RATE_PACKETS && percent > max_drop_percent {
+                    if !self.drops_alarm {
+                        self.drops_alarm = true;
+                        alarms.push(CaptureAlarm::Drops { dropped, received, percent });
+                    }
+                } else if self.drops_alarm {
+                    self.drops_alarm = false;
+                    info!("Capture watchdog: the drop rate is back to {:.2}%", percent);
+                }
+            }
+            self.last_stats = Some(stats);
+        }
+        alarms
+    }
+
+    /// Whether the interface is up as its operational state in sysfs says. Interfaces without a known state, such as
+    /// loopback and tunnels, and ones that cannot be read are taken as up.
+    fn is_interface_up(&self) -> bool {
+        match fs::read_to_string(format!("/sys/class/net/{}/operstate", self.device)) {
+            Err(_) => { true }
+            Ok(state) => { !matches!(state.trim(), "down" | "lowerlayerdown" | "notpresent") }
+        }
+    }
+}